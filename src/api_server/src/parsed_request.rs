@@ -19,7 +19,7 @@ use crate::request::machine_configuration::{
 };
 use crate::request::metrics::parse_put_metrics;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::net::{parse_get_net, parse_patch_net, parse_put_net};
 use crate::request::snapshot::{parse_patch_vm_state, parse_put_snapshot};
 use crate::request::version::parse_get_version;
 use crate::request::vsock::parse_put_vsock;
@@ -100,6 +100,7 @@ impl ParsedRequest {
             }
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "network-interfaces", None) => parse_get_net(path_tokens.get(1)),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
@@ -167,6 +168,7 @@ impl ParsedRequest {
                     Self::success_response_with_data(vm_config)
                 }
                 VmmData::MmdsValue(value) => Self::success_response_with_mmds_value(value),
+                VmmData::NetworkInterface(config) => Self::success_response_with_data(config),
                 VmmData::BalloonConfig(balloon_config) => {
                     Self::success_response_with_data(balloon_config)
                 }
@@ -226,6 +228,10 @@ fn log_received_api_request(api_description: String) {
 fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
     match (path, body) {
         ("/mmds", Some(_)) | (_, None) => format!("{:?} request on {:?}", method, path),
+        // The encryption keys of the drives must never end up in the logs.
+        (_, Some(value)) if has_encryption_key(value) => {
+            format!("{:?} request on {:?} with a redacted body", method, path)
+        }
         (_, Some(value)) => format!(
             "{:?} request on {:?} with body {:?}",
             method,
@@ -237,6 +243,12 @@ fn describe(method: Method, path: &str, body: Option<&Body>) -> String {
     }
 }
 
+/// Checks whether the body of an API request may hold an encryption key.
+fn has_encryption_key(body: &Body) -> bool {
+    const FIELD: &[u8] = b"encryption\"";
+    body.body.windows(FIELD.len()).any(|window| window == FIELD)
+}
+
 /// Generates a `GenericError` for each request method.
 pub(crate) fn method_to_error(method: Method) -> Result<ParsedRequest, Error> {
     match method {
@@ -539,6 +551,22 @@ pub(crate) mod tests {
             describe(Method::Put, "path", Some(&Body::new("body"))),
             "Put request on \"path\" with body \"body\""
         );
+        assert_eq!(
+            describe(
+                Method::Put,
+                "/drives/rootfs",
+                Some(&Body::new(r#"{"encryption": {"key": "secret"}}"#))
+            ),
+            "Put request on \"/drives/rootfs\" with a redacted body"
+        );
+        assert_eq!(
+            describe(
+                Method::Put,
+                "/snapshot/load",
+                Some(&Body::new(r#"{"drive_encryption": []}"#))
+            ),
+            "Put request on \"/snapshot/load\" with a redacted body"
+        );
     }
 
     #[test]
@@ -563,6 +591,9 @@ pub(crate) mod tests {
                 VmmData::MmdsValue(value) => {
                     http_response(&serde_json::to_string(value).unwrap(), 200)
                 }
+                VmmData::NetworkInterface(cfg) => {
+                    http_response(&serde_json::to_string(cfg).unwrap(), 200)
+                }
                 VmmData::InstanceInformation(info) => {
                     http_response(&serde_json::to_string(info).unwrap(), 200)
                 }
@@ -586,6 +617,9 @@ pub(crate) mod tests {
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(VmConfig::default()));
         verify_ok_response_with(VmmData::MmdsValue(serde_json::from_str("{}").unwrap()));
+        verify_ok_response_with(VmmData::NetworkInterface(
+            serde_json::from_str(r#"{"iface_id": "net0", "host_dev_name": "tap0"}"#).unwrap(),
+        ));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
         verify_ok_response_with(VmmData::VmmVersion(String::default()));
 
@@ -612,6 +646,18 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_net() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/network-interfaces/net0", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
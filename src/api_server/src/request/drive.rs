@@ -67,14 +67,16 @@ pub(crate) fn parse_patch_drive(
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
     // - rate_limiter
+    // - trace
     if block_device_update_cfg.path_on_host.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
+        && block_device_update_cfg.trace.is_none()
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, rate_limiter, trace.",
             ),
         ));
     }
@@ -199,6 +201,43 @@ mod tests {
         // Validate that updating both path and rate limiter succeds.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
 
+        let body = r#"{
+            "drive_id": "foo",
+            "trace": {
+                "enabled": true,
+                "path_on_host": "/trace"
+            }
+        }"#;
+        // Validate that starting a trace works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert!(cfg.path_on_host.is_none());
+                let trace = cfg.trace.unwrap();
+                assert!(trace.enabled);
+                assert_eq!(trace.path_on_host.unwrap(), "/trace".to_string());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
+        let body = r#"{
+            "drive_id": "foo",
+            "trace": {
+                "enabled": false
+            }
+        }"#;
+        // Validate that stopping a trace works.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
+
+        let body = r#"{
+            "drive_id": "foo",
+            "trace": {
+                "path_on_host": "/trace"
+            }
+        }"#;
+        // Must fail since the trace doesn't say whether it is enabled.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
         let body = r#"{
             "drive_id": "foo",
             "path_on_host": "/there",
@@ -245,6 +284,8 @@ mod tests {
                 "is_read_only": true,
                 "cache_type": "Unsafe",
                 "io_engine": "Sync",
+                "format": "Qcow2",
+                "num_queues": 2,
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -259,5 +300,83 @@ mod tests {
                 }
             }"#;
         assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with a vhost-user backend instead of a backing file.
+        let body = r#"{
+                "drive_id": "1000",
+                "socket": "/tmp/vhost-user-blk.sock",
+                "is_root_device": false,
+                "is_read_only": false
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with tuning options for the Async engine.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Async",
+                "io_engine_config": {
+                    "registered_buffers": true,
+                    "sqpoll_idle_ms": 100
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // Unknown tuning options are rejected.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "io_engine": "Async",
+                "io_engine_config": {
+                    "sq_thread_cpu": 1
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
+
+        // PUT with a block topology.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false,
+                "topology": {
+                    "logical_block_size": 4096,
+                    "physical_block_size": 4096,
+                    "min_io_size": 4096,
+                    "opt_io_size": 65536
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with integrity verification.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": true,
+                "verity": {
+                    "root_hash": "ad7facb2586fc6e966c004d7d1d16b024f5805ff7cb47c7a85dabd8b48892ca7",
+                    "salt": "1234",
+                    "hash_path": "dummy.hash",
+                    "hash_offset": 4096
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // The root hash is mandatory.
+        let body = r#"{
+                "drive_id": "1000",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": true,
+                "verity": {
+                    "hash_path": "dummy.hash"
+                }
+            }"#;
+        assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_err());
     }
 }
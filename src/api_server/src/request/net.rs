@@ -8,6 +8,18 @@ use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
 use crate::request::{Body, StatusCode};
 
+pub(crate) fn parse_get_net(id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
+    METRICS.get_api_requests.network_count.inc();
+    let id = match id_from_path {
+        Some(id) => checked_id(id)?,
+        None => return Err(Error::EmptyID),
+    };
+
+    Ok(ParsedRequest::new_sync(VmmAction::GetNetworkInterface(
+        id.to_string(),
+    )))
+}
+
 pub(crate) fn parse_put_net(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -78,6 +90,16 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_net_request() {
+        match vmm_action_from_request(parse_get_net(Some(&"foo")).unwrap()) {
+            VmmAction::GetNetworkInterface(id) => assert_eq!(id, "foo"),
+            _ => panic!("Test failed."),
+        }
+        assert!(parse_get_net(None).is_err());
+        assert!(parse_get_net(Some(&"foo-bar")).is_err());
+    }
+
     #[test]
     fn test_parse_put_net_request() {
         let body = r#"{
@@ -157,5 +179,51 @@ mod tests {
             }
         }"#;
         assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 5. Starting a capture.
+        let body = r#"{
+            "iface_id": "foo",
+            "capture": {
+                "enabled": true,
+                "path_on_host": "/capture.pcap",
+                "max_size_bytes": 1048576,
+                "snap_len": 128
+            }
+        }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => {
+                assert!(netif.rx_rate_limiter.is_none());
+                let capture = netif.capture.unwrap();
+                assert!(capture.enabled);
+                assert_eq!(capture.path_on_host.unwrap(), "/capture.pcap");
+                assert_eq!(capture.max_size_bytes, Some(1048576));
+                assert_eq!(capture.snap_len, Some(128));
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 6. Serde error for a capture which doesn't say whether it is enabled.
+        let body = r#"{
+            "iface_id": "foo",
+            "capture": {
+                "path_on_host": "/capture.pcap"
+            }
+        }"#;
+        assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 7. Bringing the link down.
+        let body = r#"{
+            "iface_id": "foo",
+            "link_up": false
+        }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => {
+                assert!(netif.capture.is_none());
+                assert_eq!(netif.link_up, Some(false));
+            }
+            _ => panic!("Test failed."),
+        }
     }
 }
@@ -94,6 +94,7 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
         mem_backend,
         enable_diff_snapshots: snapshot_config.enable_diff_snapshots,
         resume_vm: snapshot_config.resume_vm,
+        drive_encryption: snapshot_config.drive_encryption,
     };
 
     // Construct the `ParsedRequest` object.
@@ -109,7 +110,8 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use vmm::vmm_config::drive::EncryptionConfig;
+    use vmm::vmm_config::snapshot::{DriveEncryptionConfig, MemBackendConfig, MemBackendType};
 
     use super::*;
     use crate::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
@@ -183,6 +185,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            drive_encryption: Vec::new(),
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -213,6 +216,7 @@ mod tests {
             },
             enable_diff_snapshots: true,
             resume_vm: false,
+            drive_encryption: Vec::new(),
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -242,6 +246,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            drive_encryption: Vec::new(),
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -254,6 +259,44 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "File"
+                },
+                "drive_encryption": [
+                    {
+                        "drive_id": "rootfs",
+                        "encryption": {
+                            "key": "1111111111111111111111111111111122222222222222222222222222222222"
+                        }
+                    }
+                ]
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            drive_encryption: vec![DriveEncryptionConfig {
+                drive_id: "rootfs".to_string(),
+                encryption: EncryptionConfig {
+                    key: ("11".repeat(16) + &"22".repeat(16)).into(),
+                },
+            }],
+        };
+
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
         body = r#"{
                 "snapshot_path": "foo",
                 "mem_file_path": "bar",
@@ -268,6 +311,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            drive_encryption: Vec::new(),
         };
 
         let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -0,0 +1,482 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![warn(clippy::ptr_as_ptr)]
+#![warn(clippy::undocumented_unsafe_blocks)]
+#![warn(clippy::cast_lossless)]
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+use devices::virtio::block::trace::{self, TraceReader, TraceRecord};
+use devices::virtio::block::{
+    SECTOR_SHIFT, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+};
+use utils::arg_parser::{ArgParser, Argument, Arguments};
+
+const BLOCK_REPLAY_VERSION: &str = env!("FIRECRACKER_VERSION");
+const EXIT_CODE_SUCCESS: i32 = 0;
+const TRACE_FILE: &str = "trace-file";
+const IMAGE_FILE: &str = "image-file";
+const NO_DELAY: &str = "no-delay";
+
+#[derive(Debug)]
+enum Error {
+    InvalidTraceFile(std::io::Error),
+    InvalidImageFile(std::io::Error),
+    Trace(trace::Error),
+    Read(std::io::Error),
+    Write(std::io::Error),
+    Flush(std::io::Error),
+    Fallocate(std::io::Error),
+}
+
+fn build_arg_parser<'a>() -> ArgParser<'a> {
+    let arg_parser = ArgParser::new()
+        .arg(
+            Argument::new(TRACE_FILE)
+                .required(true)
+                .takes_value(true)
+                .help("File path of the block device trace."),
+        )
+        .arg(
+            Argument::new(IMAGE_FILE)
+                .required(true)
+                .takes_value(true)
+                .help(
+                    "File path of the disk image to replay the trace against. The writes of the \
+                     trace overwrite the image with zeroes, so it should be a scratch copy.",
+                ),
+        )
+        .arg(Argument::new(NO_DELAY).takes_value(false).help(
+            "Replay the requests back to back, instead of waiting for their original submission \
+             times.",
+        ));
+
+    arg_parser
+}
+
+fn extract_args<'a>(arg_parser: &'a mut ArgParser<'a>) -> &'a Arguments<'a> {
+    arg_parser.parse_from_cmdline().unwrap_or_else(|err| {
+        panic!(
+            "Arguments parsing error: {} \n\nFor more information try --help.",
+            err
+        );
+    });
+
+    if arg_parser.arguments().flag_present("help") {
+        println!("Block_replay v{}", BLOCK_REPLAY_VERSION);
+        println!("Tool that replays the I/O trace of a block device against a disk image\n");
+        println!("{}", arg_parser.formatted_help());
+        process::exit(EXIT_CODE_SUCCESS);
+    }
+    if arg_parser.arguments().flag_present("version") {
+        println!("Block_replay v{}\n", BLOCK_REPLAY_VERSION);
+        process::exit(EXIT_CODE_SUCCESS);
+    }
+
+    arg_parser.arguments()
+}
+
+fn parse_args(args: &Arguments) -> Result<(File, File, bool), Error> {
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arg_parser.parse_from_cmdline()`
+    let trace_file_path = args.single_value(TRACE_FILE).unwrap();
+    let trace_file = File::open(trace_file_path).map_err(Error::InvalidTraceFile)?;
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arg_parser.parse_from_cmdline()`
+    let image_file_path = args.single_value(IMAGE_FILE).unwrap();
+    let image_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image_file_path)
+        .map_err(Error::InvalidImageFile)?;
+
+    Ok((trace_file, image_file, !args.flag_present(NO_DELAY)))
+}
+
+/// Reads the records of a trace, in submission order.
+fn read_trace(trace_file: File) -> Result<Vec<TraceRecord>, Error> {
+    let reader = TraceReader::new(trace_file).map_err(Error::Trace)?;
+    let mut records = Vec::new();
+    for record in reader {
+        match record {
+            Ok(record) => records.push(record),
+            // The trace of a device which was not stopped properly may end with a partial record.
+            Err(trace::Error::TruncatedRecord) => {
+                eprintln!("The trace ends with a truncated record, which is ignored.");
+                break;
+            }
+            Err(err) => return Err(Error::Trace(err)),
+        }
+    }
+    // The records are written in completion order.
+    records.sort_by_key(|record| record.submit_time_us);
+    Ok(records)
+}
+
+/// Latencies of the requests of a given type, in microseconds.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct OpStats {
+    count: u64,
+    bytes: u64,
+    traced_total_us: u64,
+    traced_max_us: u64,
+    replayed_total_us: u64,
+    replayed_max_us: u64,
+}
+
+impl OpStats {
+    fn add(&mut self, record: &TraceRecord, replayed_us: u64) {
+        self.count += 1;
+        self.bytes += u64::from(record.len);
+        self.traced_total_us += record.latency_us();
+        self.traced_max_us = self.traced_max_us.max(record.latency_us());
+        self.replayed_total_us += replayed_us;
+        self.replayed_max_us = self.replayed_max_us.max(replayed_us);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplayStats {
+    // The statistics of the replayed requests, by request type.
+    ops: BTreeMap<u32, OpStats>,
+    // The number of requests which failed when traced, or which don't access the disk.
+    skipped: u64,
+}
+
+fn fallocate(image_file: &File, mode: i32, offset: u64, len: u64) -> Result<(), Error> {
+    // SAFETY: Safe because the file descriptor is valid, and the return value is checked.
+    let ret = unsafe {
+        libc::fallocate(
+            image_file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::Fallocate(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Executes a single request against the image.
+fn execute(image_file: &File, record: &TraceRecord, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let offset = record.sector << SECTOR_SHIFT;
+    let len = record.len as usize;
+    match record.request_type {
+        VIRTIO_BLK_T_IN => {
+            buf.resize(len, 0);
+            image_file
+                .read_exact_at(&mut buf[..len], offset)
+                .map_err(Error::Read)
+        }
+        VIRTIO_BLK_T_OUT => {
+            // The data of the requests is not traced.
+            buf.clear();
+            buf.resize(len, 0);
+            image_file.write_all_at(buf, offset).map_err(Error::Write)
+        }
+        VIRTIO_BLK_T_FLUSH => image_file.sync_data().map_err(Error::Flush),
+        VIRTIO_BLK_T_DISCARD => fallocate(
+            image_file,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            u64::from(record.len),
+        ),
+        VIRTIO_BLK_T_WRITE_ZEROES => fallocate(
+            image_file,
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            u64::from(record.len),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn replay(
+    image_file: &File,
+    records: &[TraceRecord],
+    keep_timing: bool,
+) -> Result<ReplayStats, Error> {
+    let mut stats = ReplayStats::default();
+    let mut buf = Vec::new();
+    let first_submit_time_us = records.first().map_or(0, |record| record.submit_time_us);
+    let start = Instant::now();
+
+    for record in records {
+        let replayed = matches!(
+            record.request_type,
+            VIRTIO_BLK_T_IN
+                | VIRTIO_BLK_T_OUT
+                | VIRTIO_BLK_T_FLUSH
+                | VIRTIO_BLK_T_DISCARD
+                | VIRTIO_BLK_T_WRITE_ZEROES
+        );
+        if !replayed || u32::from(record.status) != VIRTIO_BLK_S_OK {
+            stats.skipped += 1;
+            continue;
+        }
+
+        if keep_timing {
+            let submit_time =
+                Duration::from_micros(record.submit_time_us.saturating_sub(first_submit_time_us));
+            if let Some(delay) = submit_time.checked_sub(start.elapsed()) {
+                thread::sleep(delay);
+            }
+        }
+
+        let submitted = Instant::now();
+        execute(image_file, record, &mut buf)?;
+        let replayed_us = u64::try_from(submitted.elapsed().as_micros()).unwrap_or(u64::MAX);
+
+        stats
+            .ops
+            .entry(record.request_type)
+            .or_default()
+            .add(record, replayed_us);
+    }
+
+    Ok(stats)
+}
+
+fn request_type_name(request_type: u32) -> &'static str {
+    match request_type {
+        VIRTIO_BLK_T_IN => "read",
+        VIRTIO_BLK_T_OUT => "write",
+        VIRTIO_BLK_T_FLUSH => "flush",
+        VIRTIO_BLK_T_DISCARD => "discard",
+        VIRTIO_BLK_T_WRITE_ZEROES => "write zeroes",
+        _ => "unknown",
+    }
+}
+
+fn print_stats(stats: &ReplayStats) {
+    println!(
+        "{:<14}{:>10}{:>14}{:>16}{:>16}{:>16}{:>16}",
+        "request",
+        "count",
+        "bytes",
+        "traced avg us",
+        "traced max us",
+        "replay avg us",
+        "replay max us"
+    );
+    for (request_type, op) in stats.ops.iter() {
+        println!(
+            "{:<14}{:>10}{:>14}{:>16}{:>16}{:>16}{:>16}",
+            request_type_name(*request_type),
+            op.count,
+            op.bytes,
+            op.traced_total_us / op.count,
+            op.traced_max_us,
+            op.replayed_total_us / op.count,
+            op.replayed_max_us
+        );
+    }
+    println!("{} requests skipped", stats.skipped);
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+    let args = extract_args(&mut arg_parser);
+    let (trace_file, image_file, keep_timing) =
+        parse_args(args).unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
+
+    let records =
+        read_trace(trace_file).unwrap_or_else(|err| panic!("Error reading the trace: {:?}", err));
+    let stats = replay(&image_file, &records, keep_timing)
+        .unwrap_or_else(|err| panic!("Error replaying the trace: {:?}", err));
+    print_stats(&stats);
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::virtio::block::trace::TraceWriter;
+    use devices::virtio::block::{VIRTIO_BLK_S_IOERR, VIRTIO_BLK_T_GET_ID};
+    use utils::tempfile;
+
+    use super::*;
+
+    macro_rules! assert_err {
+        ($expression:expr, $($pattern:tt)+) => {
+            match $expression {
+                Err($($pattern)+) => (),
+                ref err =>  {
+                    println!("expected `{}` but got `{:?}`", stringify!($($pattern)+), err);
+                    assert!(false)
+                }
+            }
+        }
+    }
+
+    fn record(request_type: u32, sector: u64, len: u32, submit_time_us: u64) -> TraceRecord {
+        TraceRecord {
+            request_type,
+            sector,
+            len,
+            submit_time_us,
+            complete_time_us: submit_time_us + 100,
+            status: VIRTIO_BLK_S_OK as u8,
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let trace_file = tempfile::TempFile::new().unwrap();
+        let trace_file_path = trace_file.as_path().to_str().unwrap().to_string();
+        let image_file = tempfile::TempFile::new().unwrap();
+        let image_file_path = image_file.as_path().to_str().unwrap().to_string();
+
+        let arg_parser = build_arg_parser();
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "block_replay",
+                    "--trace-file",
+                    "wrong_file",
+                    "--image-file",
+                    &image_file_path,
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(parse_args(arguments), Error::InvalidTraceFile(_));
+
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "block_replay",
+                    "--trace-file",
+                    &trace_file_path,
+                    "--image-file",
+                    "wrong_file",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(parse_args(arguments), Error::InvalidImageFile(_));
+
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "block_replay",
+                    "--trace-file",
+                    &trace_file_path,
+                    "--image-file",
+                    &image_file_path,
+                    "--no-delay",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        let (_, _, keep_timing) = parse_args(arguments).unwrap();
+        assert!(!keep_timing);
+    }
+
+    #[test]
+    fn test_read_trace() {
+        let trace_file = tempfile::TempFile::new().unwrap();
+        let trace_file_path = trace_file.as_path().to_str().unwrap();
+
+        assert_err!(
+            read_trace(File::open(trace_file_path).unwrap()),
+            Error::Trace(trace::Error::InvalidHeader)
+        );
+
+        // The records are sorted by submission time.
+        let mut writer = TraceWriter::new(trace_file_path).unwrap();
+        writer
+            .record(&record(VIRTIO_BLK_T_IN, 8, 512, 200))
+            .unwrap();
+        writer
+            .record(&record(VIRTIO_BLK_T_OUT, 0, 512, 100))
+            .unwrap();
+        writer.flush().unwrap();
+        let records = read_trace(File::open(trace_file_path).unwrap()).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                record(VIRTIO_BLK_T_OUT, 0, 512, 100),
+                record(VIRTIO_BLK_T_IN, 8, 512, 200)
+            ]
+        );
+
+        // A truncated record ends the trace.
+        trace_file
+            .as_file()
+            .set_len(trace_file.as_file().metadata().unwrap().len() - 1)
+            .unwrap();
+        let records = read_trace(File::open(trace_file_path).unwrap()).unwrap();
+        assert_eq!(records, vec![record(VIRTIO_BLK_T_IN, 8, 512, 200)]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let image_file = tempfile::TempFile::new().unwrap().into_file();
+        image_file.write_all_at(&[0xab; 0x4000], 0).unwrap();
+
+        let mut failed = record(VIRTIO_BLK_T_OUT, 16, 0x1000, 350);
+        failed.status = VIRTIO_BLK_S_IOERR as u8;
+        let records = vec![
+            record(VIRTIO_BLK_T_IN, 0, 0x1000, 0),
+            record(VIRTIO_BLK_T_OUT, 8, 0x1000, 100),
+            record(VIRTIO_BLK_T_FLUSH, 0, 0, 150),
+            record(VIRTIO_BLK_T_IN, 8, 0x1000, 200),
+            record(VIRTIO_BLK_T_GET_ID, 0, 20, 300),
+            failed,
+            record(VIRTIO_BLK_T_DISCARD, 24, 0x1000, 400),
+        ];
+        let start = Instant::now();
+        let stats = replay(&image_file, &records, true).unwrap();
+        // The last request is replayed at its original submission time.
+        assert!(start.elapsed() >= Duration::from_micros(400));
+
+        assert_eq!(stats.skipped, 2);
+        assert_eq!(stats.ops.len(), 4);
+        let reads = &stats.ops[&VIRTIO_BLK_T_IN];
+        assert_eq!(reads.count, 2);
+        assert_eq!(reads.bytes, 0x2000);
+        assert_eq!(reads.traced_total_us, 200);
+        assert_eq!(reads.traced_max_us, 100);
+        assert_eq!(stats.ops[&VIRTIO_BLK_T_OUT].count, 1);
+        assert_eq!(stats.ops[&VIRTIO_BLK_T_FLUSH].count, 1);
+        assert_eq!(stats.ops[&VIRTIO_BLK_T_DISCARD].bytes, 0x1000);
+
+        // The writes overwrite the image with zeroes, and the failed write is skipped.
+        let mut data = vec![0u8; 0x4000];
+        image_file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data[..0x1000], [0xab; 0x1000]);
+        assert_eq!(data[0x1000..0x2000], [0; 0x1000]);
+        assert_eq!(data[0x2000..0x3000], [0xab; 0x1000]);
+        assert_eq!(data[0x3000..], [0; 0x1000]);
+
+        // Requests past the end of the image fail.
+        assert_err!(
+            replay(
+                &image_file,
+                &[record(VIRTIO_BLK_T_IN, 32, 0x1000, 0)],
+                false
+            ),
+            Error::Read(_)
+        );
+    }
+}
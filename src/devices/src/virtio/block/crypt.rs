@@ -0,0 +1,374 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transparent encryption of the disk image with AES-XTS.
+//!
+//! Every 512-byte sector is encrypted on its own, with the little-endian sector number as the
+//! tweak, which matches the `aes-xts-plain64` cipher of dm-crypt. The key is split in two halves:
+//! the first one encrypts the data, while the second one encrypts the tweak.
+//!
+//! The data written by the guest is encrypted into a host buffer before being handed to the
+//! engine, so that the guest memory is never modified. The data read from the disk is decrypted
+//! in place once the read completes.
+
+use std::ops::Deref;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::{fmt, ptr};
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256, Block};
+use serde::{Deserialize, Serialize};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::verity::parse_hex;
+use super::{SECTOR_SHIFT, SECTOR_SIZE};
+
+// The size of an AES block.
+const BLOCK_SIZE: usize = 16;
+// Reduction polynomial of GF(2^128), used when the tweak is multiplied by the primitive element.
+const GF_128_FEEDBACK: u8 = 0x87;
+/// Size of the buffer the data written by the guest is encrypted through, a whole number of
+/// sectors. Larger writes are encrypted and written one chunk at a time.
+pub const WRITE_CHUNK_SIZE: usize = 64 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The key is not a hex encoded 256 or 512 bit key, made of two distinct halves.
+    InvalidKey,
+    /// Failed to access the data in guest memory.
+    Transfer(GuestMemoryError),
+}
+
+/// Configuration of the encryption of a drive.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// The hex encoded AES-XTS key, 256 bits long for AES-128 or 512 bits long for AES-256. The
+    /// key is never serialized.
+    #[serde(skip_serializing)]
+    pub key: EncryptionKey,
+}
+
+// The key must never end up in the logs.
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Clone)]
+enum Aes {
+    Aes128(Aes128),
+    Aes256(Aes256),
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> Self {
+        match key.len() {
+            16 => Aes::Aes128(Aes128::new(key.into())),
+            _ => Aes::Aes256(Aes256::new(key.into())),
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = Block::from_mut_slice(block);
+        match self {
+            Aes::Aes128(cipher) => cipher.encrypt_block(block),
+            Aes::Aes256(cipher) => cipher.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = Block::from_mut_slice(block);
+        match self {
+            Aes::Aes128(cipher) => cipher.decrypt_block(block),
+            Aes::Aes256(cipher) => cipher.decrypt_block(block),
+        }
+    }
+}
+
+/// A hex encoded key, which is wiped from memory once dropped.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct EncryptionKey(String);
+
+impl From<String> for EncryptionKey {
+    fn from(key: String) -> Self {
+        EncryptionKey(key)
+    }
+}
+
+impl Deref for EncryptionKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        // SAFETY: Safe because zeroes are valid UTF-8.
+        zeroize(unsafe { self.0.as_bytes_mut() });
+    }
+}
+
+// Overwrites `bytes` with zeroes, in a way which the compiler can't elide even though the memory
+// is about to be freed.
+fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: Safe because the pointer comes from a mutable reference.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// An AES-XTS cipher working on whole sectors.
+#[derive(Clone)]
+pub struct XtsCipher {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl XtsCipher {
+    pub fn new(config: &EncryptionConfig) -> Result<Self, Error> {
+        let mut key = parse_hex(&config.key).ok_or(Error::InvalidKey)?;
+        let cipher = Self::from_key(&key);
+        zeroize(&mut key);
+        cipher
+    }
+
+    fn from_key(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(Error::InvalidKey);
+        }
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        // Equal halves make XTS degrade to a mode which leaks information about the plaintext.
+        if data_key == tweak_key {
+            return Err(Error::InvalidKey);
+        }
+
+        Ok(XtsCipher {
+            data: Aes::new(data_key),
+            tweak: Aes::new(tweak_key),
+        })
+    }
+
+    /// Encrypts `data` in place, `data` being made of whole sectors starting at `sector`.
+    pub fn encrypt(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, true)
+    }
+
+    /// Decrypts `data` in place, `data` being made of whole sectors starting at `sector`.
+    pub fn decrypt(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, false)
+    }
+
+    /// Fills `buf` with the data found in guest memory at `addr` and encrypts it, `offset` being
+    /// the position of the data on the disk.
+    pub fn encrypt_from_mem(
+        &self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        mem.read_slice(buf, addr).map_err(Error::Transfer)?;
+        self.encrypt(offset >> SECTOR_SHIFT, buf);
+        Ok(())
+    }
+
+    /// Decrypts in place the `len` bytes found in guest memory at `addr`, `offset` being the
+    /// position of the data on the disk.
+    pub fn decrypt_mem(
+        &self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        offset: u64,
+        len: u32,
+    ) -> Result<(), Error> {
+        let mut data = [0u8; SECTOR_SIZE as usize];
+        let first_sector = offset >> SECTOR_SHIFT;
+        for i in 0..u64::from(len) >> SECTOR_SHIFT {
+            let sector_addr = addr.unchecked_add(i << SECTOR_SHIFT);
+            mem.read_slice(&mut data, sector_addr)
+                .map_err(Error::Transfer)?;
+            self.decrypt(first_sector + i, &mut data);
+            mem.write_slice(&data, sector_addr)
+                .map_err(Error::Transfer)?;
+        }
+        Ok(())
+    }
+
+    fn process(&self, first_sector: u64, data: &mut [u8], encrypt: bool) {
+        for (sector, sector_data) in
+            (first_sector..).zip(data.chunks_exact_mut(SECTOR_SIZE as usize))
+        {
+            let mut tweak = [0u8; BLOCK_SIZE];
+            tweak[..8].copy_from_slice(&sector.to_le_bytes());
+            self.tweak.encrypt(&mut tweak);
+
+            for block in sector_data.chunks_exact_mut(BLOCK_SIZE) {
+                xor(block, &tweak);
+                match encrypt {
+                    true => self.data.encrypt(block),
+                    false => self.data.decrypt(block),
+                }
+                xor(block, &tweak);
+                mul_alpha(&mut tweak);
+            }
+        }
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8; BLOCK_SIZE]) {
+    block
+        .iter_mut()
+        .zip(tweak.iter())
+        .for_each(|(byte, tweak_byte)| *byte ^= tweak_byte);
+}
+
+// Multiplies the tweak by the primitive element of GF(2^128), the tweak being little-endian.
+fn mul_alpha(tweak: &mut [u8; BLOCK_SIZE]) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= GF_128_FEEDBACK;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    fn cipher(key: &str) -> XtsCipher {
+        XtsCipher::new(&EncryptionConfig {
+            key: key.to_string().into(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        for key in [
+            String::new(),
+            "0011".to_string(),
+            // Not hex encoded.
+            "zz".repeat(32),
+            // Neither 256 nor 512 bits long.
+            "01".repeat(48),
+            // Equal halves.
+            "01".repeat(32),
+        ] {
+            assert!(matches!(
+                XtsCipher::new(&EncryptionConfig { key: key.into() }),
+                Err(Error::InvalidKey)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_key_redacted() {
+        let config = EncryptionConfig {
+            key: ("11".repeat(16) + &"22".repeat(16)).into(),
+        };
+        assert_eq!(
+            format!("{:?}", config),
+            "EncryptionConfig { key: \"<redacted>\" }"
+        );
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut key = "11".repeat(16).into_bytes();
+        zeroize(&mut key);
+        assert!(key.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_xts_aes_128() {
+        // IEEE 1619 test vector 2, with the data unit extended to a whole sector. XTS doesn't
+        // chain the blocks, so the first 32 bytes of the sector match the vector.
+        let cipher = cipher(&("11".repeat(16) + &"22".repeat(16)));
+        let mut data = [0x44u8; SECTOR_SIZE as usize];
+        cipher.encrypt(0x33_3333_3333, &mut data);
+        assert_eq!(
+            data[..32],
+            parse_hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0").unwrap()
+        );
+
+        cipher.decrypt(0x33_3333_3333, &mut data);
+        assert_eq!(data, [0x44u8; SECTOR_SIZE as usize]);
+    }
+
+    #[test]
+    fn test_xts_aes_256() {
+        let key: String = (0u8..64).map(|b| format!("{:02x}", b)).collect();
+        let cipher = cipher(&key);
+        let plaintext: Vec<u8> = (0..SECTOR_SIZE).map(|b| b as u8).collect();
+
+        let mut data = plaintext.clone();
+        cipher.encrypt(1, &mut data);
+        assert_eq!(
+            data[..32],
+            parse_hex("0976f139b289f2dd570e3b8caa596f98f86a162f8768ffbd7ad06c74d403f32a").unwrap()
+        );
+        // The last block checks the multiplications of the tweak.
+        assert_eq!(
+            data[SECTOR_SIZE as usize - 16..],
+            parse_hex("f2046c4c06a0faf268eea4040c56ac27").unwrap()
+        );
+
+        // The same data encrypts differently in another sector.
+        let mut other = plaintext.clone();
+        cipher.encrypt(2, &mut other);
+        assert_ne!(data, other);
+
+        cipher.decrypt(1, &mut data);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_guest_memory() {
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false).unwrap();
+        let cipher = cipher(&("11".repeat(16) + &"22".repeat(16)));
+        let plaintext: Vec<u8> = (0..0x400).map(|b| b as u8).collect();
+        mem.write_slice(&plaintext, GuestAddress(0x200)).unwrap();
+
+        // Encrypting from guest memory leaves it untouched.
+        let mut encrypted = vec![0u8; 0x400];
+        cipher
+            .encrypt_from_mem(&mem, GuestAddress(0x200), 0x800, &mut encrypted)
+            .unwrap();
+        let mut expected = plaintext.clone();
+        cipher.encrypt(4, &mut expected);
+        assert_eq!(encrypted, expected);
+        let mut data = vec![0u8; 0x400];
+        mem.read_slice(&mut data, GuestAddress(0x200)).unwrap();
+        assert_eq!(data, plaintext);
+
+        mem.write_slice(&encrypted, GuestAddress(0x200)).unwrap();
+        cipher
+            .decrypt_mem(&mem, GuestAddress(0x200), 0x800, 0x400)
+            .unwrap();
+        mem.read_slice(&mut data, GuestAddress(0x200)).unwrap();
+        assert_eq!(data, plaintext);
+
+        assert!(matches!(
+            cipher.decrypt_mem(&mem, GuestAddress(0xe00), 0, 0x400),
+            Err(Error::Transfer(_))
+        ));
+        assert!(matches!(
+            cipher.encrypt_from_mem(&mem, GuestAddress(0xe00), 0, &mut encrypted),
+            Err(Error::Transfer(_))
+        ));
+    }
+}
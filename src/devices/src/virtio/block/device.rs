@@ -9,27 +9,39 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::{cmp, result};
 
-use block_io::FileEngine;
-use logger::{error, warn, IncMetric, METRICS};
+use block_io::{DirectIo, FileEngine, FileEngineOk, Qcow2Image, UserDataError};
+use logger::{error, info, warn, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+use utils::time::{get_time_us, ClockType};
 use virtio_gen::virtio_blk::{
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_F_VERSION_1,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{ByteValued, GuestAddress, GuestMemoryMmap};
 
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK};
-use super::io::async_io;
+use super::crypt::{EncryptionConfig, XtsCipher, WRITE_CHUNK_SIZE};
+use super::io::{async_io, NbdConfig, NbdEngine};
 use super::request::*;
-use super::{io as block_io, Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE};
+use super::trace::{self, TraceConfig, TraceRecord, TraceWriter};
+use super::verity::{HashTree, VerityConfig};
+use super::{
+    io as block_io, Error, BLK_SIZE_CONFIG_SPACE_SIZE, CONFIG_SPACE_SIZE,
+    DISCARD_CONFIG_SPACE_SIZE, MAX_DISCARD_SECTORS, MAX_LOGICAL_BLOCK_SIZE, MAX_NUM_QUEUES,
+    MQ_CONFIG_SPACE_SIZE, QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE, TOPOLOGY_CONFIG_SPACE_SIZE,
+};
 use crate::virtio::{IrqTrigger, IrqType};
 
 /// Configuration options for disk caching.
@@ -42,6 +54,10 @@ pub enum CacheType {
     /// flush requests coming from the guest will be performed using
     /// `fsync`.
     Writeback,
+    /// Same as `Writeback`, but the backing file is opened with `O_DIRECT`, bypassing the host
+    /// page cache. The logical block size of the backing storage is advertised to the guest
+    /// driver, and requests which are not aligned to it go through bounce buffers.
+    Direct,
 }
 
 impl Default for CacheType {
@@ -50,12 +66,15 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
     /// Use a Sync engine, based on blocking system calls.
     Sync,
+    /// Use an Async engine if the host kernel supports all the io_uring operations it needs,
+    /// and a Sync engine otherwise.
+    Auto,
 }
 
 impl Default for FileEngineType {
@@ -71,15 +90,154 @@ impl FileEngineType {
             _ => Ok(true),
         }
     }
+
+    /// Selects the engine used by the `Auto` type, probing the operations supported by the host
+    /// kernel. The other types are returned as is.
+    pub fn select(
+        self,
+        config: IoEngineConfig,
+    ) -> result::Result<Self, utils::kernel_version::Error> {
+        match self {
+            Self::Auto if Self::Async.is_supported()? && async_io::is_supported(config) => {
+                Ok(Self::Async)
+            }
+            Self::Auto => Ok(Self::Sync),
+            _ => Ok(self),
+        }
+    }
+}
+
+/// Tuning options for the `Async` IO engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IoEngineConfig {
+    /// Register the guest memory with io_uring once the device is activated, so that the
+    /// guest pages don't have to be pinned on every request. The guest memory stays pinned
+    /// for the lifetime of the device.
+    #[serde(default)]
+    pub registered_buffers: bool,
+    /// Poll the submission queue from a kernel thread, which goes to sleep after being idle
+    /// for this many milliseconds. Requests are then submitted without a system call while
+    /// the thread is awake.
+    pub sqpoll_idle_ms: Option<u32>,
+}
+
+/// Block sizes and IO size hints advertised to the guest driver, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockTopology {
+    /// The smallest unit the disk can be accessed with. Requests which are not aligned to it are
+    /// rejected. Defaults to the sector size.
+    pub logical_block_size: Option<u32>,
+    /// The unit the backing storage writes without a read-modify-write cycle. Defaults to the
+    /// logical block size.
+    pub physical_block_size: Option<u32>,
+    /// The smallest request size which doesn't incur a penalty.
+    pub min_io_size: Option<u32>,
+    /// The request size giving the best throughput, e.g. the stripe size of a RAID array.
+    pub opt_io_size: Option<u32>,
+}
+
+impl BlockTopology {
+    /// Specifies if any of the hints exposed through `VIRTIO_BLK_F_TOPOLOGY` is set.
+    pub fn has_io_hints(&self) -> bool {
+        self.physical_block_size.is_some()
+            || self.min_io_size.is_some()
+            || self.opt_io_size.is_some()
+    }
+
+    /// Checks that the sizes are consistent and can be expressed in the config space, where
+    /// they are counted in logical blocks.
+    fn is_valid(&self) -> bool {
+        let logical_block_size = self.logical_block_size.unwrap_or(SECTOR_SIZE as u32);
+        let physical_block_size = self.physical_block_size.unwrap_or(logical_block_size);
+        let min_io_size = self.min_io_size.unwrap_or(0);
+        let opt_io_size = self.opt_io_size.unwrap_or(0);
+
+        logical_block_size.is_power_of_two()
+            && (SECTOR_SIZE as u32..=MAX_LOGICAL_BLOCK_SIZE).contains(&logical_block_size)
+            && physical_block_size.is_power_of_two()
+            && physical_block_size >= logical_block_size
+            && min_io_size % logical_block_size == 0
+            && min_io_size / logical_block_size <= u32::from(u16::MAX)
+            && opt_io_size % logical_block_size == 0
+    }
+}
+
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ImageFormat {
+    /// The image is a raw copy of the disk contents.
+    Raw,
+    /// The image is in the qcow2 format. Clusters which are not allocated are read from the
+    /// backing file, and get allocated on write.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+/// The layout of the virtio block configuration space (`struct virtio_blk_config`).
+///
+/// Only the fields for which the device offers the corresponding feature are exposed to the
+/// driver.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ConfigSpace {
+    pub capacity: u64,
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+    pub blk_size: u32,
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+    pub writeback: u8,
+    pub unused0: u8,
+    pub num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    pub unused1: [u8; 3],
+    // The spec defines a packed structure of 60 bytes. Pad it explicitly up to its alignment,
+    // so that it doesn't contain uninitialized bytes.
+    padding: [u8; 4],
 }
 
+// SAFETY: Safe because ConfigSpace only contains plain data and has no implicit padding.
+unsafe impl ByteValued for ConfigSpace {}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
     file_path: String,
-    file_engine: FileEngine<PendingRequest>,
+    // One engine per virtio queue, each one with its own handle of the backing file.
+    file_engines: Vec<FileEngine<PendingRequest>>,
+    io_engine_config: IoEngineConfig,
+    // Whether the type of the engines was selected automatically, in which case it is selected
+    // again on restore.
+    auto_engine: bool,
+    qcow2_image: Option<Qcow2Image>,
+    direct_io: Option<DirectIo>,
+    topology: BlockTopology,
+    hash_tree: Option<HashTree>,
+    cipher: Option<XtsCipher>,
+    // The buffer the data written to an encrypted disk goes through, empty otherwise.
+    crypt_buf: Vec<u8>,
+    // The NBD export backing the disk, instead of a file.
+    nbd: Option<NbdConfig>,
     nsectors: u64,
     image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
+    is_discard_enabled: bool,
 }
 
 impl DiskProperties {
@@ -88,16 +246,186 @@ impl DiskProperties {
         is_disk_read_only: bool,
         cache_type: CacheType,
         file_engine_type: FileEngineType,
+        io_engine_config: IoEngineConfig,
+        image_format: ImageFormat,
+        is_discard_enabled: bool,
+        num_queues: usize,
+        topology: BlockTopology,
+        verity: Option<VerityConfig>,
+        cipher: Option<XtsCipher>,
+        nbd: Option<NbdConfig>,
     ) -> result::Result<Self, Error> {
-        let mut disk_image = OpenOptions::new()
-            .read(true)
-            .write(!is_disk_read_only)
+        if let Some(config) = nbd {
+            return Self::new_nbd(
+                config,
+                is_disk_read_only,
+                cache_type,
+                io_engine_config,
+                is_discard_enabled,
+                num_queues,
+                topology,
+                cipher,
+            );
+        }
+
+        let auto_engine = file_engine_type == FileEngineType::Auto;
+        let file_engine_type = file_engine_type
+            .select(io_engine_config)
+            .map_err(|err| Error::FileEngine(block_io::Error::GetKernelVersion(err)))?;
+        // The tuning options only apply to the Async engine.
+        let io_engine_config = match file_engine_type {
+            FileEngineType::Async => io_engine_config,
+            _ => IoEngineConfig::default(),
+        };
+        if auto_engine {
+            info!(
+                "Selected the {:?} io_engine for {}.",
+                file_engine_type, disk_image_path
+            );
+            match file_engine_type {
+                FileEngineType::Async => METRICS.block.io_engine_auto_async_count.inc(),
+                _ => METRICS.block.io_engine_auto_sync_count.inc(),
+            }
+        }
+
+        let mut open_options = OpenOptions::new();
+        open_options.read(true).write(!is_disk_read_only);
+        if cache_type == CacheType::Direct {
+            open_options.custom_flags(libc::O_DIRECT);
+        }
+        let mut disk_image = open_options
             .open(PathBuf::from(&disk_image_path))
             .map_err(Error::BackingFile)?;
-        let disk_size = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(Error::BackingFile)?;
 
+        let (disk_size, qcow2_image) = match image_format {
+            ImageFormat::Raw => {
+                let disk_size = disk_image
+                    .seek(SeekFrom::End(0))
+                    .map_err(Error::BackingFile)?;
+                (disk_size, None)
+            }
+            ImageFormat::Qcow2 => {
+                // The image keeps its own handle of the file for accessing the metadata.
+                let image = Qcow2Image::open(
+                    disk_image.try_clone().map_err(Error::BackingFile)?,
+                    Path::new(&disk_image_path),
+                    !is_disk_read_only,
+                )
+                .map_err(Error::Qcow2)?;
+                (image.virtual_size(), Some(image))
+            }
+        };
+
+        let hash_tree = verity
+            .map(|config| HashTree::new(config, &disk_image_path, disk_size))
+            .transpose()
+            .map_err(Error::Verity)?;
+        // Only the data covered by the hash tree is exposed to the guest, in whole data blocks.
+        let disk_size = match hash_tree {
+            Some(ref hash_tree) => {
+                if topology
+                    .logical_block_size
+                    .map_or(false, |size| u64::from(size) != hash_tree.data_block_size())
+                {
+                    return Err(Error::InvalidTopology(topology));
+                }
+                hash_tree.data_size()
+            }
+            None => disk_size,
+        };
+
+        Self::check_disk_size(disk_size);
+
+        let direct_io = match cache_type {
+            CacheType::Direct => Some(
+                DirectIo::new(disk_image.try_clone().map_err(Error::BackingFile)?)
+                    .map_err(Error::DirectIo)?,
+            ),
+            CacheType::Unsafe | CacheType::Writeback => None,
+        };
+
+        let file_engines = (0..num_queues)
+            .map(|_| {
+                let file = disk_image.try_clone().map_err(Error::BackingFile)?;
+                FileEngine::from_file(file, file_engine_type, io_engine_config)
+                    .map_err(Error::FileEngine)
+            })
+            .collect::<result::Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            cache_type,
+            nsectors: disk_size >> SECTOR_SHIFT,
+            image_id: Self::build_disk_image_id(&disk_image),
+            file_path: disk_image_path,
+            file_engines,
+            io_engine_config,
+            auto_engine,
+            qcow2_image,
+            direct_io,
+            topology,
+            hash_tree,
+            crypt_buf: Self::crypt_buf(cipher.as_ref()),
+            cipher,
+            nbd: None,
+            is_discard_enabled,
+        })
+    }
+
+    // Connects to the NBD export backing the disk, once per queue.
+    #[allow(clippy::too_many_arguments)]
+    fn new_nbd(
+        config: NbdConfig,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+        io_engine_config: IoEngineConfig,
+        is_discard_enabled: bool,
+        num_queues: usize,
+        topology: BlockTopology,
+        cipher: Option<XtsCipher>,
+    ) -> result::Result<Self, Error> {
+        let engines = (0..num_queues)
+            .map(|_| NbdEngine::connect(&config, is_disk_read_only, num_queues > 1))
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(|err| Error::FileEngine(block_io::Error::Nbd(err)))?;
+
+        // All the connections access the same export.
+        let disk_size = engines[0].size();
+        Self::check_disk_size(disk_size);
+        if is_discard_enabled && !engines[0].can_discard() {
+            warn!(
+                "The NBD server doesn't support discard and write zeroes requests; discard will \
+                 be disabled."
+            );
+        }
+        let is_discard_enabled = is_discard_enabled && engines[0].can_discard();
+
+        Ok(Self {
+            cache_type,
+            nsectors: disk_size >> SECTOR_SHIFT,
+            image_id: Self::build_nbd_image_id(&config),
+            file_path: config.uri(),
+            file_engines: engines.into_iter().map(FileEngine::Nbd).collect(),
+            io_engine_config,
+            auto_engine: false,
+            qcow2_image: None,
+            direct_io: None,
+            topology,
+            hash_tree: None,
+            crypt_buf: Self::crypt_buf(cipher.as_ref()),
+            cipher,
+            nbd: Some(config),
+            is_discard_enabled,
+        })
+    }
+
+    fn crypt_buf(cipher: Option<&XtsCipher>) -> Vec<u8> {
+        match cipher {
+            Some(_) => vec![0u8; WRITE_CHUNK_SIZE],
+            None => Vec::new(),
+        }
+    }
+
+    fn check_disk_size(disk_size: u64) {
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
         if disk_size % SECTOR_SIZE != 0 {
@@ -107,28 +435,134 @@ impl DiskProperties {
                 disk_size, SECTOR_SIZE
             );
         }
+    }
 
-        Ok(Self {
-            cache_type,
-            nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
-            file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(Error::FileEngine)?,
-        })
+    pub fn file_engine(&self, queue_index: usize) -> &FileEngine<PendingRequest> {
+        &self.file_engines[queue_index]
+    }
+
+    pub fn file_engine_mut(&mut self, queue_index: usize) -> &mut FileEngine<PendingRequest> {
+        &mut self.file_engines[queue_index]
     }
 
-    pub fn file_engine(&self) -> &FileEngine<PendingRequest> {
-        &self.file_engine
+    pub fn file_engines(&self) -> &[FileEngine<PendingRequest>] {
+        &self.file_engines
     }
 
-    pub fn file_engine_mut(&mut self) -> &mut FileEngine<PendingRequest> {
-        &mut self.file_engine
+    pub fn file_engines_mut(&mut self) -> &mut [FileEngine<PendingRequest>] {
+        &mut self.file_engines
+    }
+
+    /// Reads from the disk, translating the offset through the image format or bouncing the
+    /// data through an aligned buffer if needed.
+    pub fn read(
+        &mut self,
+        queue_index: usize,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: PendingRequest,
+    ) -> result::Result<FileEngineOk<PendingRequest>, UserDataError<PendingRequest, block_io::Error>>
+    {
+        let file_engine = &mut self.file_engines[queue_index];
+        match (self.qcow2_image.as_mut(), self.direct_io.as_ref()) {
+            (Some(image), _) => image.read(file_engine, offset, mem, addr, count, user_data),
+            (None, Some(direct_io)) => {
+                direct_io.read(file_engine, offset, mem, addr, count, user_data)
+            }
+            (None, None) => file_engine.read(offset, mem, addr, count, user_data),
+        }
+    }
+
+    /// Writes to the disk, translating the offset through the image format, encrypting the data
+    /// or bouncing it through an aligned buffer if needed.
+    pub fn write(
+        &mut self,
+        queue_index: usize,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: PendingRequest,
+    ) -> result::Result<FileEngineOk<PendingRequest>, UserDataError<PendingRequest, block_io::Error>>
+    {
+        let file_engine = &mut self.file_engines[queue_index];
+        if let Some(cipher) = self.cipher.as_ref() {
+            return file_engine.write_encrypted(
+                offset,
+                mem,
+                addr,
+                count,
+                cipher,
+                &mut self.crypt_buf,
+                user_data,
+            );
+        }
+        match (self.qcow2_image.as_mut(), self.direct_io.as_ref()) {
+            (Some(image), _) => image.write(file_engine, offset, mem, addr, count, user_data),
+            (None, Some(direct_io)) => {
+                direct_io.write(file_engine, offset, mem, addr, count, user_data)
+            }
+            (None, None) => file_engine.write(offset, mem, addr, count, user_data),
+        }
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match self.qcow2_image {
+            Some(_) => ImageFormat::Qcow2,
+            None => ImageFormat::Raw,
+        }
     }
 
     #[cfg(test)]
-    pub fn file(&self) -> &File {
-        self.file_engine.file()
+    pub fn file(&self) -> Option<&File> {
+        self.file_engines[0].file()
+    }
+
+    /// The logical block size advertised to the guest driver, if any. Unless configured, this
+    /// is the data block size of the hash tree, or the block size of the backing storage when it
+    /// is accessed with `O_DIRECT`.
+    pub fn logical_block_size(&self) -> Option<u64> {
+        self.topology
+            .logical_block_size
+            .map(u64::from)
+            .or_else(|| self.hash_tree.as_ref().map(HashTree::data_block_size))
+            .or_else(|| self.direct_io.as_ref().map(DirectIo::block_size))
+    }
+
+    /// The alignment of the requests accepted from the guest driver, in bytes. Only a configured
+    /// logical block size or the data block size of the hash tree are enforced, as the unaligned
+    /// requests to a disk accessed with `O_DIRECT` are bounced.
+    pub fn request_alignment(&self) -> u64 {
+        self.topology
+            .logical_block_size
+            .map(u64::from)
+            .or_else(|| self.hash_tree.as_ref().map(HashTree::data_block_size))
+            .unwrap_or(SECTOR_SIZE)
+    }
+
+    pub fn topology(&self) -> BlockTopology {
+        self.topology
+    }
+
+    pub fn verity(&self) -> Option<VerityConfig> {
+        self.hash_tree
+            .as_ref()
+            .map(|hash_tree| hash_tree.config().clone())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn nbd(&self) -> Option<&NbdConfig> {
+        self.nbd.as_ref()
+    }
+
+    /// The hash tree and the cipher which the data read from the disk goes through.
+    pub fn hash_tree_and_cipher(&mut self) -> (Option<&mut HashTree>, Option<&XtsCipher>) {
+        (self.hash_tree.as_mut(), self.cipher.as_ref())
     }
 
     pub fn nsectors(&self) -> u64 {
@@ -168,6 +602,15 @@ impl DiskProperties {
         default_id
     }
 
+    // The NBD exports don't have a stable identity on the host, so the ID is derived from their
+    // location.
+    fn build_nbd_image_id(config: &NbdConfig) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+        let mut id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        let digest = format!("{:x}", Sha256::digest(config.uri().as_bytes()));
+        id.copy_from_slice(&digest.as_bytes()[..VIRTIO_BLK_ID_BYTES as usize]);
+        id
+    }
+
     /// Backing file path.
     pub fn file_path(&self) -> &String {
         &self.file_path
@@ -177,17 +620,85 @@ impl DiskProperties {
     /// buffer. The config space is populated with the disk size based
     /// on the backing file size.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
-        // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
+        // The config space is little endian. Firecracker only runs on little endian platforms,
+        // so the in-memory representation can be used as is.
+        let mut config = ConfigSpace {
+            capacity: self.nsectors,
+            ..Default::default()
+        };
+        let mut config_len = CONFIG_SPACE_SIZE;
+
+        let logical_block_size = self.logical_block_size();
+        if let Some(block_size) = logical_block_size {
+            config.blk_size = block_size as u32;
+            config_len = BLK_SIZE_CONFIG_SPACE_SIZE;
+        }
+
+        if self.topology.has_io_hints() {
+            // The hints are counted in logical blocks.
+            let block_size = logical_block_size.unwrap_or(SECTOR_SIZE) as u32;
+            let physical_block_size = self
+                .topology
+                .physical_block_size
+                .map_or(block_size, |size| cmp::max(size, block_size));
+            config.physical_block_exp = (physical_block_size / block_size).trailing_zeros() as u8;
+            config.min_io_size = (self.topology.min_io_size.unwrap_or(0) / block_size) as u16;
+            config.opt_io_size = self.topology.opt_io_size.unwrap_or(0) / block_size;
+            config_len = TOPOLOGY_CONFIG_SPACE_SIZE;
+        }
+
+        if self.num_queues() > 1 {
+            config.num_queues = self.num_queues() as u16;
+            config_len = MQ_CONFIG_SPACE_SIZE;
+        }
+
+        if self.is_discard_enabled {
+            config.max_discard_sectors = MAX_DISCARD_SECTORS;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = (self.request_alignment() >> SECTOR_SHIFT) as u32;
+            config.max_write_zeroes_sectors = MAX_DISCARD_SECTORS;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+            config_len = DISCARD_CONFIG_SPACE_SIZE;
         }
-        config
+
+        config.as_slice()[..config_len].to_vec()
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn io_engine_config(&self) -> IoEngineConfig {
+        self.io_engine_config
+    }
+
+    /// Specifies if the type of the engines was selected automatically.
+    pub fn is_auto_engine(&self) -> bool {
+        self.auto_engine
+    }
+
+    /// Specifies if discard and write zeroes requests are served by this disk.
+    pub fn is_discard_enabled(&self) -> bool {
+        self.is_discard_enabled
+    }
+
+    /// Number of queues the disk is accessed from, each one with its own IO engine.
+    pub fn num_queues(&self) -> usize {
+        self.file_engines.len()
+    }
+
+    /// Specifies if contiguous read and write requests can be merged into a single vectored
+    /// transfer, which requires the guest memory to be accessed in place, at the offsets of the
+    /// requests.
+    pub fn can_merge_requests(&self) -> bool {
+        self.qcow2_image.is_none()
+            && self.direct_io.is_none()
+            && self.cipher.is_none()
+            && self.nbd.is_none()
+            // The vectored operations don't use the registered buffers.
+            && !self.io_engine_config.registered_buffers
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -203,7 +714,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -212,25 +723,15 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
-    is_io_engine_throttled: bool,
-}
-
-macro_rules! unwrap_async_file_engine_or_return {
-    ($file_engine: expr) => {
-        match $file_engine {
-            FileEngine::Async(engine) => engine,
-            FileEngine::Sync(_) => {
-                error!("The block device doesn't use an async IO engine");
-                return;
-            }
-        }
-    };
+    is_io_engine_throttled: Vec<bool>,
+    tracer: Option<TraceWriter>,
 }
 
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable. If an NBD export is given, the device
+    /// operates on the export instead, and the path is ignored.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
@@ -241,27 +742,113 @@ impl Block {
         is_disk_root: bool,
         rate_limiter: RateLimiter,
         file_engine_type: FileEngineType,
+        io_engine_config: IoEngineConfig,
+        image_format: ImageFormat,
+        is_discard_enabled: bool,
+        num_queues: usize,
+        topology: BlockTopology,
+        verity: Option<VerityConfig>,
+        encryption: Option<EncryptionConfig>,
+        nbd: Option<NbdConfig>,
     ) -> result::Result<Block, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        if !topology.is_valid() {
+            return Err(Error::InvalidTopology(topology));
+        }
+
+        // Deallocating or zeroing blocks of a read-only disk is not allowed.
+        if is_discard_enabled && is_disk_read_only {
+            warn!("Discard is not supported for read-only block devices; it will be disabled.");
+        }
+        // Deallocating qcow2 clusters is not implemented.
+        if is_discard_enabled && image_format != ImageFormat::Raw {
+            warn!("Discard is only supported for raw disk images; it will be disabled.");
+        }
+        let is_discard_enabled =
+            is_discard_enabled && !is_disk_read_only && image_format == ImageFormat::Raw;
+        // The export is accessed as a raw disk, through connections which the NBD engines own.
+        if nbd.is_some()
+            && (image_format != ImageFormat::Raw
+                || cache_type == CacheType::Direct
+                || file_engine_type == FileEngineType::Async
+                || verity.is_some())
+        {
+            return Err(Error::UnsupportedNbd);
+        }
+        // The qcow2 metadata is accessed at offsets which are not aligned to the block size.
+        if cache_type == CacheType::Direct && image_format != ImageFormat::Raw {
+            return Err(Error::UnsupportedDirectIo(image_format));
+        }
+        // The verified data must not change, and the hash tree covers the contents of the file.
+        if verity.is_some() && (!is_disk_read_only || image_format != ImageFormat::Raw) {
+            return Err(Error::UnsupportedVerity);
+        }
+        // Only the raw sectors are encrypted, and the data is written through host buffers.
+        // Discarded sectors would read back as garbage once decrypted.
+        if encryption.is_some()
+            && (image_format != ImageFormat::Raw
+                || cache_type == CacheType::Direct
+                || is_discard_enabled
+                || verity.is_some())
+        {
+            return Err(Error::UnsupportedEncryption);
+        }
+        let cipher = encryption
+            .as_ref()
+            .map(XtsCipher::new)
+            .transpose()
+            .map_err(Error::Crypt)?;
+
         let disk_properties = DiskProperties::new(
             disk_image_path,
             is_disk_read_only,
             cache_type,
             file_engine_type,
+            io_engine_config,
+            image_format,
+            is_discard_enabled,
+            num_queues,
+            topology,
+            verity,
+            cipher,
+            nbd,
         )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
 
-        if cache_type == CacheType::Writeback {
+        if cache_type != CacheType::Unsafe {
             avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
         }
 
+        if disk_properties.logical_block_size().is_some() {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+        }
+
+        if topology.has_io_hints() {
+            avail_features |= 1u64 << VIRTIO_BLK_F_TOPOLOGY;
+        }
+
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?];
+        // The NBD server may not support discard.
+        if disk_properties.is_discard_enabled() {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        }
+
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<_>, _>>()?;
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
             id,
@@ -277,35 +864,38 @@ impl Block {
             device_state: DeviceState::Inactive,
             irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
-            is_io_engine_throttled: false,
+            is_io_engine_throttled: vec![false; num_queues],
+            tracer: None,
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(err) = self.queue_evts[0].read() {
+        if let Err(err) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", err);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
-        } else if self.is_io_engine_throttled {
+        } else if self.is_io_engine_throttled[queue_index] {
             METRICS.block.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         METRICS.block.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            self.process_virtio_queues();
         }
     }
 
@@ -327,16 +917,65 @@ impl Block {
         }
     }
 
+    // Appends the record of a request to the trace, if the device is traced. Tracing stops on
+    // the first failure to write the trace.
+    fn trace(tracer: &mut Option<TraceWriter>, record: Option<TraceRecord>) {
+        if let (Some(writer), Some(record)) = (tracer.as_mut(), record) {
+            if let Err(err) = writer.record(&record) {
+                error!("Failed to write the block trace, tracing stops: {:?}", err);
+                METRICS.block.trace_fails.inc();
+                *tracer = None;
+            }
+        }
+    }
+
+    // Returns the executed requests to the guest. Returns `false` if the requests were throttled
+    // by the IO engine, in which case they must be processed again later.
+    fn complete_processing(
+        queue: &mut Queue,
+        tracer: &mut Option<TraceWriter>,
+        mem: &GuestMemoryMmap,
+        irq_trigger: &IrqTrigger,
+        processing_result: ProcessingResult,
+    ) -> bool {
+        let finished = match processing_result {
+            ProcessingResult::Submitted => vec![],
+            ProcessingResult::Throttled => return false,
+            ProcessingResult::Executed(finished) => vec![finished],
+            ProcessingResult::ExecutedMerged(finished) => finished,
+        };
+        for finished in finished {
+            Self::trace(tracer, finished.trace);
+            Self::add_used_descriptor(
+                queue,
+                finished.desc_idx,
+                finished.num_bytes_to_mem,
+                mem,
+                irq_trigger,
+            );
+        }
+        true
+    }
+
     pub fn process_queue(&mut self, queue_index: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
         let queue = &mut self.queues[queue_index];
         let mut used_any = false;
+        // Contiguous read or write requests, transferred together once a request which doesn't
+        // follow them is found.
+        let mut merged: Option<MergedRequest> = None;
 
         while let Some(head) = queue.pop_or_enable_notification(mem) {
-            let processing_result = match Request::parse(&head, mem, self.disk.nsectors()) {
+            let request = match Request::parse(
+                &head,
+                mem,
+                self.disk.nsectors(),
+                self.disk.request_alignment(),
+            ) {
                 Ok(request) => {
+                    // The requests are rate limited one by one, even when they are merged.
                     if request.rate_limit(&mut self.rate_limiter) {
                         // Stop processing the queue and return this descriptor chain to the
                         // avail ring, for later processing.
@@ -346,7 +985,63 @@ impl Block {
                     }
 
                     used_any = true;
-                    request.process(&mut self.disk, head.index, mem)
+                    let submit_time_us = self
+                        .tracer
+                        .as_ref()
+                        .map(|_| get_time_us(ClockType::Monotonic));
+                    let request = match merged.as_mut() {
+                        Some(merged) => {
+                            match merged.merge(request, head.index, submit_time_us, mem) {
+                                Ok(()) => continue,
+                                Err(request) => request,
+                            }
+                        }
+                        None => request,
+                    };
+                    Ok((request, submit_time_us))
+                }
+                Err(err) => Err(err),
+            };
+
+            // The request doesn't follow the merged ones, which are processed first to keep the
+            // order of the requests.
+            if let Some(merged) = merged.take() {
+                let len = merged.len();
+                let processing_result = merged.process(&mut self.disk, queue_index, mem);
+                if !Self::complete_processing(
+                    queue,
+                    &mut self.tracer,
+                    mem,
+                    &self.irq_trigger,
+                    processing_result,
+                ) {
+                    // Return the merged requests along with this descriptor chain.
+                    for _ in 0..=len {
+                        queue.undo_pop();
+                    }
+                    self.is_io_engine_throttled[queue_index] = true;
+                    break;
+                }
+            }
+
+            let processing_result = match request {
+                Ok((request, submit_time_us)) if self.disk.can_merge_requests() => {
+                    match MergedRequest::new(request, head.index, submit_time_us, mem) {
+                        Ok(request) => {
+                            merged = Some(request);
+                            continue;
+                        }
+                        Err(request) => request.process(
+                            &mut self.disk,
+                            queue_index,
+                            head.index,
+                            mem,
+                            submit_time_us,
+                        ),
+                    }
+                }
+                Ok((request, submit_time_us)) => {
+                    request.process(&mut self.disk, queue_index, head.index, mem, submit_time_us)
                 }
                 Err(err) => {
                     error!("Failed to parse available descriptor chain: {:?}", err);
@@ -354,33 +1049,49 @@ impl Block {
                     ProcessingResult::Executed(FinishedRequest {
                         num_bytes_to_mem: 0,
                         desc_idx: head.index,
+                        trace: None,
                     })
                 }
             };
 
-            match processing_result {
-                ProcessingResult::Submitted => {}
-                ProcessingResult::Throttled => {
+            if !Self::complete_processing(
+                queue,
+                &mut self.tracer,
+                mem,
+                &self.irq_trigger,
+                processing_result,
+            ) {
+                queue.undo_pop();
+                self.is_io_engine_throttled[queue_index] = true;
+                break;
+            }
+        }
+
+        if let Some(merged) = merged {
+            let len = merged.len();
+            let processing_result = merged.process(&mut self.disk, queue_index, mem);
+            if !Self::complete_processing(
+                queue,
+                &mut self.tracer,
+                mem,
+                &self.irq_trigger,
+                processing_result,
+            ) {
+                for _ in 0..len {
                     queue.undo_pop();
-                    self.is_io_engine_throttled = true;
-                    break;
-                }
-                ProcessingResult::Executed(finished) => {
-                    Self::add_used_descriptor(
-                        queue,
-                        head.index,
-                        finished.num_bytes_to_mem,
-                        mem,
-                        &self.irq_trigger,
-                    );
                 }
+                self.is_io_engine_throttled[queue_index] = true;
             }
         }
 
-        if let FileEngine::Async(engine) = self.disk.file_engine_mut() {
-            if let Err(err) = engine.kick_submission_queue() {
-                error!("Error submitting pending block requests: {:?}", err);
+        match self.disk.file_engine_mut(queue_index) {
+            FileEngine::Async(engine) => {
+                if let Err(err) = engine.kick_submission_queue() {
+                    error!("Error submitting pending block requests: {:?}", err);
+                }
             }
+            FileEngine::Nbd(engine) => engine.kick(),
+            FileEngine::Sync(_) => {}
         }
 
         if !used_any {
@@ -388,72 +1099,123 @@ impl Block {
         }
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
-
+    fn process_async_completion_queue(&mut self, queue_index: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
+        let queue = &mut self.queues[queue_index];
 
         loop {
-            match engine.pop(mem) {
-                Err(error) => {
-                    error!("Failed to read completed io_uring entry: {:?}", error);
-                    break;
-                }
-                Ok(None) => break,
-                Ok(Some(cqe)) => {
-                    let res = cqe.result();
-                    let user_data = cqe.user_data();
-
-                    let (pending, res) = match res {
-                        Ok(count) => (user_data, Ok(count)),
-                        Err(error) => (
-                            user_data,
-                            Err(IoErr::FileEngine(block_io::Error::Async(
-                                async_io::Error::IO(error),
-                            ))),
-                        ),
-                    };
-                    let finished = pending.finish(mem, res);
-
-                    Self::add_used_descriptor(
-                        queue,
-                        finished.desc_idx,
-                        finished.num_bytes_to_mem,
-                        mem,
-                        &self.irq_trigger,
-                    );
+            // The engine is borrowed from the disk directly, as the hash tree is needed alongside it.
+            let (pending, res) = match &mut self.disk.file_engines[queue_index] {
+                FileEngine::Async(engine) => match engine.pop(mem) {
+                    Err(error) => {
+                        error!("Failed to read completed io_uring entry: {:?}", error);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Ok(Some(cqe)) => {
+                        let res = cqe.result();
+                        let user_data = cqe.user_data();
+
+                        match res {
+                            Ok(count) => (user_data, Ok(count)),
+                            Err(error) => (
+                                user_data,
+                                Err(IoErr::FileEngine(block_io::Error::Async(
+                                    async_io::Error::IO(error),
+                                ))),
+                            ),
+                        }
+                    }
+                },
+                FileEngine::Nbd(engine) => match engine.pop(mem) {
+                    None => break,
+                    Some(Ok(ok)) => (ok.user_data, Ok(ok.count)),
+                    Some(Err(err)) => (
+                        err.user_data,
+                        Err(IoErr::FileEngine(block_io::Error::Nbd(err.error))),
+                    ),
+                },
+                FileEngine::Sync(_) => {
+                    error!("The block device doesn't use an async IO engine");
+                    return;
                 }
+            };
+            // Merged requests complete one by one.
+            for (pending, res) in pending.split(res) {
+                let finished = pending.finish(
+                    mem,
+                    res,
+                    self.disk.hash_tree.as_mut(),
+                    self.disk.cipher.as_ref(),
+                );
+
+                Self::trace(&mut self.tracer, finished.trace);
+                Self::add_used_descriptor(
+                    queue,
+                    finished.desc_idx,
+                    finished.num_bytes_to_mem,
+                    mem,
+                    &self.irq_trigger,
+                );
             }
         }
     }
 
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
+        match self.disk.file_engine_mut(queue_index) {
+            FileEngine::Async(engine) => {
+                if let Err(err) = engine.completion_evt().read() {
+                    error!("Failed to get async completion event: {:?}", err);
+                    return;
+                }
+            }
+            FileEngine::Nbd(engine) => {
+                if let Err(err) = engine.process_events() {
+                    error!("Failed to process the NBD engine events: {:?}", err);
+                    return;
+                }
+            }
+            FileEngine::Sync(_) => {
+                error!("The block device doesn't use an async IO engine");
+                return;
+            }
+        }
 
-        if let Err(err) = engine.completion_evt().read() {
-            error!("Failed to get async completion event: {:?}", err);
-        } else {
-            self.process_async_completion_queue();
+        self.process_async_completion_queue(queue_index);
 
-            if self.is_io_engine_throttled {
-                self.is_io_engine_throttled = false;
-                self.process_queue(0);
-            }
+        if self.is_io_engine_throttled[queue_index] {
+            self.is_io_engine_throttled[queue_index] = false;
+            self.process_queue(queue_index);
         }
     }
 
     /// Update the backing file and the config space of the block device.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> result::Result<(), Error> {
+        if self.disk.nbd().is_some() {
+            return Err(Error::UnsupportedNbd);
+        }
         let disk_properties = DiskProperties::new(
             disk_image_path,
             self.is_read_only(),
             self.cache_type(),
-            self.file_engine_type(),
+            if self.is_auto_engine() {
+                FileEngineType::Auto
+            } else {
+                self.file_engine_type()
+            },
+            self.io_engine_config(),
+            self.image_format(),
+            self.is_discard_enabled(),
+            self.num_queues(),
+            self.topology(),
+            self.verity(),
+            self.disk.cipher.clone(),
+            None,
         )?;
         self.disk = disk_properties;
         self.config_space = self.disk.virtio_block_config_space();
+        self.register_guest_memory();
 
         // Kick the driver to pick up the changes.
         self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
@@ -467,6 +1229,27 @@ impl Block {
         self.rate_limiter.update_buckets(bytes, ops);
     }
 
+    /// Starts or stops tracing the requests processed by the device. Starting a trace while
+    /// another one is running stops the running one first.
+    pub fn update_trace(&mut self, config: &TraceConfig) -> result::Result<(), Error> {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.flush().map_err(Error::Trace)?;
+        }
+        if config.enabled {
+            let path = config
+                .path_on_host
+                .as_ref()
+                .ok_or(Error::Trace(trace::Error::MissingPath))?;
+            self.tracer = Some(TraceWriter::new(path).map_err(Error::Trace)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the requests processed by the device are traced.
+    pub fn is_traced(&self) -> bool {
+        self.tracer.is_some()
+    }
+
     /// Provides the ID of this block device.
     pub fn id(&self) -> &String {
         &self.id
@@ -497,21 +1280,90 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Specifies if discard and write zeroes requests are supported by this block device.
+    pub fn is_discard_enabled(&self) -> bool {
+        self.disk.is_discard_enabled()
+    }
+
+    /// Specifies the format of the disk image.
+    pub fn image_format(&self) -> ImageFormat {
+        self.disk.image_format()
+    }
+
+    /// Provides the number of request queues of this block device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
     }
 
     pub fn file_engine_type(&self) -> FileEngineType {
-        match self.disk.file_engine() {
-            FileEngine::Sync(_) => FileEngineType::Sync,
+        // All the queues use the same type of engine.
+        match self.disk.file_engine(0) {
+            // The NBD engines don't depend on the engine type, which keeps its default value.
+            FileEngine::Sync(_) | FileEngine::Nbd(_) => FileEngineType::Sync,
             FileEngine::Async(_) => FileEngineType::Async,
         }
     }
 
+    /// Provides the tuning options of the IO engine.
+    pub fn io_engine_config(&self) -> IoEngineConfig {
+        self.disk.io_engine_config()
+    }
+
+    /// Specifies if the IO engine was configured as `Auto`, in which case `file_engine_type`
+    /// provides the selected engine.
+    pub fn is_auto_engine(&self) -> bool {
+        self.disk.is_auto_engine()
+    }
+
+    /// Provides the block sizes and IO size hints advertised to the guest driver.
+    pub fn topology(&self) -> BlockTopology {
+        self.disk.topology()
+    }
+
+    /// Provides the configuration of the integrity verification of the disk, if enabled.
+    pub fn verity(&self) -> Option<VerityConfig> {
+        self.disk.verity()
+    }
+
+    /// Whether the disk image is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.disk.is_encrypted()
+    }
+
+    /// Provides the NBD export backing this block device, if any.
+    pub fn nbd(&self) -> Option<&NbdConfig> {
+        self.disk.nbd()
+    }
+
+    // Registers the guest memory with the IO engines, if they are configured to use registered
+    // buffers. This is done on the VMM thread once the device is activated, since registering
+    // memory with io_uring is not allowed on the vCPU threads.
+    pub(crate) fn register_guest_memory(&mut self) {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            DeviceState::Inactive => return,
+        };
+        for file_engine in self.disk.file_engines.iter_mut() {
+            if let Err(err) = file_engine.register_memory(mem) {
+                // The requests are still served, without using registered buffers.
+                warn!(
+                    "Failed to register the guest memory with the IO engine: {:?}",
+                    err
+                );
+            }
+        }
+    }
+
     fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(err) = self.disk.file_engine_mut().drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", err);
+        for file_engine in self.disk.file_engines_mut() {
+            if let Err(err) = file_engine.drain_and_flush(discard) {
+                error!("Failed to drain ops and flush block data: {:?}", err);
+            }
         }
     }
 
@@ -521,8 +1373,17 @@ impl Block {
         }
 
         self.drain_and_flush(false);
-        if let FileEngine::Async(_engine) = self.disk.file_engine_mut() {
-            self.process_async_completion_queue();
+        if !matches!(self.disk.file_engine(0), FileEngine::Sync(_)) {
+            for queue_index in 0..self.queues.len() {
+                self.process_async_completion_queue(queue_index);
+            }
+        }
+        // The trace is not saved in the snapshot, but it is complete up to this point.
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(err) = tracer.flush() {
+                error!("Failed to flush the block trace: {:?}", err);
+                METRICS.block.trace_fails.inc();
+            }
         }
     }
 }
@@ -616,11 +1477,13 @@ impl Drop for Block {
     fn drop(&mut self) {
         match self.disk.cache_type {
             CacheType::Unsafe => {
-                if let Err(err) = self.disk.file_engine_mut().drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", err);
+                for file_engine in self.disk.file_engines_mut() {
+                    if let Err(err) = file_engine.drain(true) {
+                        error!("Failed to drain ops on drop: {:?}", err);
+                    }
                 }
             }
-            CacheType::Writeback => {
+            CacheType::Writeback | CacheType::Direct => {
                 self.drain_and_flush(true);
             }
         };
@@ -638,15 +1501,18 @@ pub(crate) mod tests {
     use rate_limiter::TokenType;
     use utils::skip_if_io_uring_unsupported;
     use utils::tempfile::TempFile;
-    use vm_memory::{Address, Bytes, GuestAddress};
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemory};
 
     use super::*;
     use crate::check_metric_after_block;
+    use crate::virtio::block::crypt;
+    use crate::virtio::block::io::nbd::test_utils::{TestServer, TestServerConfig};
     use crate::virtio::block::test_utils::{
         default_block, default_engine_type_for_kv, set_queue, set_rate_limiter,
         simulate_async_completion_event, simulate_queue_and_async_completion_events,
         simulate_queue_event,
     };
+    use crate::virtio::block::verity::tests::build_hash_tree;
     use crate::virtio::queue::tests::*;
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
     use crate::virtio::IO_URING_NUM_ENTRIES;
@@ -663,6 +1529,13 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
         )
         .unwrap();
 
@@ -681,6 +1554,13 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
         )
         .is_err());
     }
@@ -989,8 +1869,8 @@ pub(crate) mod tests {
 
             // Check that the data wasn't written to the file
             let mut buf = [0u8; 512];
-            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
-            block.disk.file().read_exact(&mut buf).unwrap();
+            block.disk.file().unwrap().seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file().unwrap().read_exact(&mut buf).unwrap();
             assert_eq!(buf, empty_data.as_slice());
         }
 
@@ -1123,8 +2003,8 @@ pub(crate) mod tests {
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
             mem.write_slice(empty_data.as_slice(), data_addr).unwrap();
 
-            let size = block.disk.file().seek(SeekFrom::End(0)).unwrap();
-            block.disk.file().set_len(size / 2).unwrap();
+            let size = block.disk.file().unwrap().seek(SeekFrom::End(0)).unwrap();
+            block.disk.file().unwrap().set_len(size / 2).unwrap();
             mem.write_obj(10, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
@@ -1152,8 +2032,8 @@ pub(crate) mod tests {
                 .flags
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
-            let size = block.disk.file().seek(SeekFrom::End(0)).unwrap();
-            block.disk.file().set_len(size / 2).unwrap();
+            let size = block.disk.file().unwrap().seek(SeekFrom::End(0)).unwrap();
+            block.disk.file().unwrap().set_len(size / 2).unwrap();
             // Update sector number: stored at `request_type_addr.0 + 8`
             mem.write_obj(5, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
@@ -1191,8 +2071,9 @@ pub(crate) mod tests {
             mem.write_obj(1, GuestAddress(request_type_addr.0 + 8))
                 .unwrap();
 
-            block.disk.file().seek(SeekFrom::Start(512)).unwrap();
-            block.disk.file().write_all(&rand_data[512..]).unwrap();
+            let mut file = block.disk.file().unwrap();
+            file.seek(SeekFrom::Start(512)).unwrap();
+            file.write_all(&rand_data[512..]).unwrap();
 
             simulate_queue_and_async_completion_events(&mut block, true);
 
@@ -1297,9 +2178,558 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn test_get_device_id() {
-        let mut block = default_block(default_engine_type_for_kv());
-        let mem = default_mem();
+    fn test_discard() {
+        use std::os::unix::fs::FileExt;
+
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all_at(&[0xaa; 0x1000], 0).unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            default_engine_type_for_kv(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            true,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(block.is_discard_enabled());
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_DISCARD)));
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_WRITE_ZEROES)));
+
+        // The discard and write zeroes limits are exposed in the config space.
+        let mut config_space = [0u8; DISCARD_CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
+        let mut config = ConfigSpace::default();
+        config.as_mut_slice()[..DISCARD_CONFIG_SPACE_SIZE].copy_from_slice(&config_space);
+        assert_eq!(config.capacity, 8);
+        assert_eq!(config.max_discard_sectors, MAX_DISCARD_SECTORS);
+        assert_eq!(config.max_discard_seg, 1);
+        assert_eq!(config.max_write_zeroes_seg, 1);
+        assert_eq!(config.write_zeroes_may_unmap, 1);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+        // Discard the first 4 sectors.
+        {
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 4, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.discard_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // Zero out the next 2 sectors.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(4, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        }
+
+        // The backing file keeps its size and only the requested ranges are zeroed.
+        let zeroed_len = 6 * SECTOR_SIZE as usize;
+        let mut buf = [0u8; 0x1000];
+        f.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(f.as_file().metadata().unwrap().len(), 0x1000);
+        assert!(buf[..zeroed_len].iter().all(|&b| b == 0));
+        assert!(buf[zeroed_len..].iter().all(|&b| b == 0xaa));
+
+        // A block device without discard support rejects the requests as unsupported.
+        let mut block = default_block(default_engine_type_for_kv());
+        assert!(!block.is_discard_enabled());
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+            .unwrap();
+
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 1);
+        assert_eq!(
+            mem.read_obj::<u32>(status_addr).unwrap(),
+            VIRTIO_BLK_S_UNSUPP
+        );
+    }
+
+    #[test]
+    fn test_qcow2_image() {
+        use std::os::unix::fs::FileExt;
+
+        use crate::virtio::block::io::qcow2::tests::create_qcow2_image;
+
+        let f = TempFile::new().unwrap();
+        let disk_size = 0x10_0000;
+        create_qcow2_image(f.as_file(), disk_size, None);
+        let image_len = f.as_file().metadata().unwrap().len();
+
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            IoEngineConfig::default(),
+            ImageFormat::Qcow2,
+            true,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(block.image_format(), ImageFormat::Qcow2);
+        // The capacity is the virtual size of the image, and discard is not supported.
+        assert_eq!(block.disk.nsectors(), disk_size >> SECTOR_SHIFT);
+        assert!(!block.is_discard_enabled());
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_DISCARD)));
+
+        // Invalid images are rejected.
+        let raw = TempFile::new().unwrap();
+        raw.as_file().set_len(disk_size).unwrap();
+        assert!(matches!(
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                raw.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Sync,
+                IoEngineConfig::default(),
+                ImageFormat::Qcow2,
+                false,
+                1,
+                BlockTopology::default(),
+                None,
+                None,
+                None,
+            ),
+            Err(Error::Qcow2(_))
+        ));
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let data = vec![0xaa; vq.dtable[1].len.get() as usize];
+
+        // Writing to the disk allocates clusters at the end of the image.
+        {
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+                .unwrap();
+            mem.write_slice(&data, data_addr).unwrap();
+
+            simulate_queue_event(&mut block, Some(true));
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            assert!(f.as_file().metadata().unwrap().len() > image_len);
+
+            // The header is left untouched.
+            let mut magic = [0u8; 4];
+            f.as_file().read_exact_at(&mut magic, 0).unwrap();
+            assert_eq!(magic, [b'Q', b'F', b'I', 0xfb]);
+        }
+
+        // Reading back goes through the cluster tables.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+            mem.write_slice(&vec![0u8; data.len()], data_addr).unwrap();
+
+            simulate_queue_event(&mut block, Some(true));
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            let mut buf = vec![0u8; data.len()];
+            mem.read_slice(&mut buf, data_addr).unwrap();
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Sync,
+                IoEngineConfig::default(),
+                ImageFormat::Raw,
+                false,
+                num_queues,
+                BlockTopology::default(),
+                None,
+                None,
+                None,
+            )
+        };
+
+        // The number of queues must be between 1 and the maximum number of vCPUs.
+        assert!(matches!(new_block(0), Err(Error::InvalidNumQueues(0))));
+        assert!(matches!(
+            new_block(MAX_NUM_QUEUES + 1),
+            Err(Error::InvalidNumQueues(_))
+        ));
+
+        // A single queue device doesn't offer the feature.
+        let block = new_block(1).unwrap();
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_MQ)));
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+
+        let mut block = new_block(2).unwrap();
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_MQ)));
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_eq!(block.disk.file_engines().len(), 2);
+
+        // The number of queues is exposed in the config space.
+        let mut config_space = [0u8; MQ_CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
+        let mut config = ConfigSpace::default();
+        config.as_mut_slice()[..MQ_CONFIG_SPACE_SIZE].copy_from_slice(&config_space);
+        assert_eq!(config.capacity, 8);
+        assert_eq!(config.num_queues, 2);
+
+        let mem = default_mem();
+        let vq0 = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let vq1 = VirtQueue::new(GuestAddress(0x8000), &mem, 16);
+        set_queue(&mut block, 0, vq0.create_queue());
+        set_queue(&mut block, 1, vq1.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq0);
+        initialize_virtqueue(&vq1);
+
+        // A request on the second queue completes on that queue only.
+        let request_type_addr = GuestAddress(vq1.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq1.dtable[2].addr.get());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+        assert_eq!(vq1.used.idx.get(), 1);
+        assert_eq!(vq1.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(vq0.used.idx.get(), 0);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |image_format| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Direct,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Sync,
+                IoEngineConfig::default(),
+                image_format,
+                false,
+                1,
+                BlockTopology::default(),
+                None,
+                None,
+                None,
+            )
+        };
+
+        // The qcow2 metadata can't be accessed directly.
+        assert!(matches!(
+            new_block(ImageFormat::Qcow2),
+            Err(Error::UnsupportedDirectIo(ImageFormat::Qcow2))
+        ));
+
+        let mut block = match new_block(ImageFormat::Raw) {
+            Ok(block) => block,
+            // The filesystem doesn't support `O_DIRECT`.
+            Err(Error::BackingFile(err)) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{:?}", err),
+        };
+        assert_eq!(block.cache_type(), CacheType::Direct);
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_FLUSH)));
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_BLK_SIZE)));
+
+        // The logical block size is exposed in the config space.
+        let block_size = block.disk.logical_block_size().unwrap();
+        assert_eq!(block.config_space.len(), BLK_SIZE_CONFIG_SPACE_SIZE);
+        let mut config_space = [0u8; BLK_SIZE_CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
+        let mut config = ConfigSpace::default();
+        config.as_mut_slice()[..BLK_SIZE_CONFIG_SPACE_SIZE].copy_from_slice(&config_space);
+        assert_eq!(config.capacity, 8);
+        assert_eq!(u64::from(config.blk_size), block_size);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        // A write from a buffer which is not aligned in memory goes through a bounce buffer.
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() + 1);
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        vq.dtable[1].addr.set(data_addr.raw_value());
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(SECTOR_SIZE as u32);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(1, request_type_addr.unchecked_add(8))
+            .unwrap();
+        mem.write_slice(&[0xaa; SECTOR_SIZE as usize], data_addr)
+            .unwrap();
+
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        let mut buf = [0u8; 0x1000];
+        f.as_file().read_exact(&mut buf).unwrap();
+        assert!(buf[..SECTOR_SIZE as usize].iter().all(|&b| b == 0));
+        assert!(buf[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize]
+            .iter()
+            .all(|&b| b == 0xaa));
+        assert!(buf[2 * SECTOR_SIZE as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_topology() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x10000).unwrap();
+        let new_block = |topology| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                FileEngineType::Sync,
+                IoEngineConfig::default(),
+                ImageFormat::Raw,
+                false,
+                1,
+                topology,
+                None,
+                None,
+                None,
+            )
+        };
+
+        // The default topology is not advertised.
+        let block = new_block(BlockTopology::default()).unwrap();
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_BLK_SIZE)));
+        assert!(!block.has_feature(u64::from(VIRTIO_BLK_F_TOPOLOGY)));
+        assert_eq!(block.config_space.len(), CONFIG_SPACE_SIZE);
+
+        // Inconsistent topologies are rejected.
+        for topology in [
+            BlockTopology {
+                logical_block_size: Some(1000),
+                ..Default::default()
+            },
+            BlockTopology {
+                logical_block_size: Some(8192),
+                ..Default::default()
+            },
+            BlockTopology {
+                logical_block_size: Some(4096),
+                physical_block_size: Some(512),
+                ..Default::default()
+            },
+            BlockTopology {
+                logical_block_size: Some(4096),
+                min_io_size: Some(6144),
+                ..Default::default()
+            },
+            BlockTopology {
+                opt_io_size: Some(1000),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                new_block(topology),
+                Err(Error::InvalidTopology(_))
+            ));
+        }
+
+        let topology = BlockTopology {
+            logical_block_size: Some(1024),
+            physical_block_size: Some(4096),
+            min_io_size: Some(8192),
+            opt_io_size: Some(65536),
+        };
+        let mut block = new_block(topology).unwrap();
+        assert_eq!(block.topology(), topology);
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_BLK_SIZE)));
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_TOPOLOGY)));
+
+        // The IO size hints are counted in logical blocks.
+        assert_eq!(block.config_space.len(), TOPOLOGY_CONFIG_SPACE_SIZE);
+        let mut config_space = [0u8; TOPOLOGY_CONFIG_SPACE_SIZE];
+        block.read_config(0, &mut config_space);
+        let mut config = ConfigSpace::default();
+        config.as_mut_slice()[..TOPOLOGY_CONFIG_SPACE_SIZE].copy_from_slice(&config_space);
+        assert_eq!(config.capacity, 0x80);
+        assert_eq!(config.blk_size, 1024);
+        assert_eq!(config.physical_block_exp, 2);
+        assert_eq!(config.min_io_size, 8);
+        assert_eq!(config.opt_io_size, 64);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        // A request which is not aligned to the logical block size is rejected.
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(1024);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(1, request_type_addr.unchecked_add(8))
+            .unwrap();
+
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 0);
+        let mut buf = [0u8; 0x400];
+        f.as_file().read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_verity() {
+        let data: Vec<u8> = (0..16u8).flat_map(|i| vec![i; 0x1000]).collect();
+        let (root_hash, tree) = build_hash_tree(&data, &[], 0x1000, 0x1000);
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&data).unwrap();
+        f.as_file().write_all(&tree).unwrap();
+        let verity = VerityConfig {
+            root_hash,
+            hash_offset: data.len() as u64,
+            ..Default::default()
+        };
+        let new_block = |is_disk_read_only, image_format, topology| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                is_disk_read_only,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                IoEngineConfig::default(),
+                image_format,
+                false,
+                1,
+                topology,
+                Some(verity.clone()),
+                None,
+                None,
+            )
+        };
+
+        // The verified disk must be a read-only raw image.
+        assert!(matches!(
+            new_block(false, ImageFormat::Raw, BlockTopology::default()),
+            Err(Error::UnsupportedVerity)
+        ));
+        assert!(matches!(
+            new_block(true, ImageFormat::Qcow2, BlockTopology::default()),
+            Err(Error::UnsupportedVerity)
+        ));
+        // The logical block size is the data block size.
+        let topology = BlockTopology {
+            logical_block_size: Some(512),
+            ..Default::default()
+        };
+        assert!(matches!(
+            new_block(true, ImageFormat::Raw, topology),
+            Err(Error::InvalidTopology(_))
+        ));
+
+        let mut block = new_block(true, ImageFormat::Raw, BlockTopology::default()).unwrap();
+        assert_eq!(block.verity(), Some(verity));
+        // Only the data is exposed to the guest.
+        assert_eq!(block.disk.nsectors(), data.len() as u64 >> SECTOR_SHIFT);
+        assert_eq!(block.disk.logical_block_size(), Some(0x1000));
+        assert!(block.has_feature(u64::from(VIRTIO_BLK_F_BLK_SIZE)));
+
+        let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
         block.activate(mem.clone()).unwrap();
@@ -1308,7 +2738,531 @@ pub(crate) mod tests {
         let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
         let data_addr = GuestAddress(vq.dtable[1].addr.get());
         let status_addr = GuestAddress(vq.dtable[2].addr.get());
-        let blk_metadata = block.disk.file().metadata();
+
+        // The data read from the disk matches the hash tree.
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(8, request_type_addr.unchecked_add(8))
+            .unwrap();
+        check_metric_after_block!(
+            &METRICS.block.verity_fails,
+            0,
+            simulate_queue_and_async_completion_events(&mut block, true)
+        );
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut buf = [0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert!(buf.iter().all(|&b| b == 1));
+
+        // The data modified on the host is reported as an IO error.
+        f.as_file().seek(SeekFrom::Start(0x2000)).unwrap();
+        f.as_file().write_all(&[0xff]).unwrap();
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u64>(16, request_type_addr.unchecked_add(8))
+            .unwrap();
+        check_metric_after_block!(
+            &METRICS.block.verity_fails,
+            1,
+            simulate_queue_and_async_completion_events(&mut block, true)
+        );
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x4000).unwrap();
+        let encryption = EncryptionConfig {
+            key: ("11".repeat(16) + &"22".repeat(16)).into(),
+        };
+        let new_block = |cache_type, image_format, is_discard_enabled, verity, encryption| {
+            Block::new(
+                "test".to_string(),
+                None,
+                cache_type,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                IoEngineConfig::default(),
+                image_format,
+                is_discard_enabled,
+                1,
+                BlockTopology::default(),
+                verity,
+                Some(encryption),
+                None,
+            )
+        };
+
+        // Only plain raw images are encrypted.
+        for (cache_type, image_format, is_discard_enabled, verity) in [
+            (CacheType::Unsafe, ImageFormat::Qcow2, false, None),
+            (CacheType::Direct, ImageFormat::Raw, false, None),
+            (CacheType::Unsafe, ImageFormat::Raw, true, None),
+            (
+                CacheType::Unsafe,
+                ImageFormat::Raw,
+                false,
+                Some(VerityConfig::default()),
+            ),
+        ] {
+            assert!(matches!(
+                new_block(
+                    cache_type,
+                    image_format,
+                    is_discard_enabled,
+                    verity,
+                    encryption.clone()
+                ),
+                Err(Error::UnsupportedEncryption)
+            ));
+        }
+        assert!(matches!(
+            new_block(
+                CacheType::Unsafe,
+                ImageFormat::Raw,
+                false,
+                None,
+                EncryptionConfig::default()
+            ),
+            Err(Error::Crypt(crypt::Error::InvalidKey))
+        ));
+
+        let mut block = new_block(
+            CacheType::Unsafe,
+            ImageFormat::Raw,
+            false,
+            None,
+            encryption.clone(),
+        )
+        .unwrap();
+        assert!(block.is_encrypted());
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let data = vec![0xab; 0x1000];
+
+        // The data is encrypted on its way to the disk, leaving the guest memory untouched.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(8, request_type_addr.unchecked_add(8))
+            .unwrap();
+        mem.write_slice(&data, data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, data);
+
+        let mut expected = data.clone();
+        XtsCipher::new(&encryption)
+            .unwrap()
+            .encrypt(8, &mut expected);
+        f.as_file().seek(SeekFrom::Start(0x1000)).unwrap();
+        f.as_file().read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        // The data is decrypted once read.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        mem.write_slice(&[0u8; 0x1000], data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_nbd() {
+        let server = TestServer::new(TestServerConfig {
+            size: 0x4000,
+            ..Default::default()
+        });
+        let new_block = |cache_type, file_engine_type, image_format, verity| {
+            Block::new(
+                "test".to_string(),
+                None,
+                cache_type,
+                String::new(),
+                false,
+                false,
+                RateLimiter::default(),
+                file_engine_type,
+                IoEngineConfig::default(),
+                image_format,
+                true,
+                1,
+                BlockTopology::default(),
+                verity,
+                None,
+                Some(server.config()),
+            )
+        };
+
+        // The export is accessed as a raw disk, by the NBD engine.
+        for (cache_type, file_engine_type, image_format, verity) in [
+            (
+                CacheType::Unsafe,
+                FileEngineType::Sync,
+                ImageFormat::Qcow2,
+                None,
+            ),
+            (
+                CacheType::Direct,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                None,
+            ),
+            (
+                CacheType::Unsafe,
+                FileEngineType::Async,
+                ImageFormat::Raw,
+                None,
+            ),
+            (
+                CacheType::Unsafe,
+                FileEngineType::Sync,
+                ImageFormat::Raw,
+                Some(VerityConfig::default()),
+            ),
+        ] {
+            assert!(matches!(
+                new_block(cache_type, file_engine_type, image_format, verity),
+                Err(Error::UnsupportedNbd)
+            ));
+        }
+
+        let mut block = new_block(
+            CacheType::Writeback,
+            FileEngineType::Sync,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+        assert_eq!(block.nbd(), Some(&server.config()));
+        assert_eq!(block.file_path(), &server.config().uri());
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert!(block.is_discard_enabled());
+        // The export can't be replaced by a file.
+        assert!(matches!(
+            block.update_disk_image(String::new()),
+            Err(Error::UnsupportedNbd)
+        ));
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let data = vec![0xab; 0x1000];
+
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        mem.write_obj::<u64>(8, request_type_addr.unchecked_add(8))
+            .unwrap();
+        mem.write_slice(&data, data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert_eq!(server.contents()[0x1000..0x2000], data[..]);
+
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+            .unwrap();
+        mem.write_slice(&[0u8; 0x1000], data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut buf = vec![0u8; 0x1000];
+        mem.read_slice(&mut buf, data_addr).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let trace_file = TempFile::new().unwrap();
+        let trace_path = trace_file.as_path().to_str().unwrap().to_string();
+
+        assert!(matches!(
+            block.update_trace(&TraceConfig {
+                enabled: true,
+                path_on_host: None,
+            }),
+            Err(Error::Trace(trace::Error::MissingPath))
+        ));
+        assert!(!block.is_traced());
+        block
+            .update_trace(&TraceConfig {
+                enabled: true,
+                path_on_host: Some(trace_path.clone()),
+            })
+            .unwrap();
+        assert!(block.is_traced());
+
+        // Read the whole disk.
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(vq.used.idx.get(), 1);
+
+        // Requests which can't be parsed are not traced.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u64>(8, request_type_addr.unchecked_add(8))
+            .unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        vq.dtable[1].len.set(VIRTIO_BLK_ID_BYTES);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_GET_ID, request_type_addr)
+            .unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+
+        block.update_trace(&TraceConfig::default()).unwrap();
+        assert!(!block.is_traced());
+
+        // Requests processed once tracing stopped are not traced either.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(vq.used.idx.get(), 1);
+
+        let reader = trace::TraceReader::new(File::open(&trace_path).unwrap()).unwrap();
+        let records = reader.collect::<result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].request_type, VIRTIO_BLK_T_IN);
+        assert_eq!(records[0].sector, 0);
+        assert_eq!(records[0].len, 0x1000);
+        assert_eq!(records[0].status, VIRTIO_BLK_S_OK as u8);
+        assert!(records[0].submit_time_us <= records[0].complete_time_us);
+        assert_eq!(records[1].request_type, VIRTIO_BLK_T_GET_ID);
+        assert_eq!(records[1].len, VIRTIO_BLK_ID_BYTES);
+        assert_eq!(records[1].status, VIRTIO_BLK_S_OK as u8);
+        assert!(records[0].complete_time_us <= records[1].submit_time_us);
+
+        // Tracing stops when the trace can't be written.
+        block
+            .update_trace(&TraceConfig {
+                enabled: true,
+                path_on_host: Some("/dev/full".to_string()),
+            })
+            .unwrap();
+        // Fill the buffer of the writer, so that the records reach the file.
+        for _ in 0..256 {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            simulate_queue_event(&mut block, Some(true));
+            if !block.is_traced() {
+                break;
+            }
+        }
+        assert!(!block.is_traced());
+    }
+
+    // Adds read or write requests of one sector each, at the given sectors. Each request has its
+    // own data buffer, and the buffers are not contiguous in the guest memory.
+    fn add_rw_requests_batch(
+        block: &mut Block,
+        vq: &VirtQueue,
+        mem: &GuestMemoryMmap,
+        request_type: u32,
+        sectors: &[u64],
+    ) {
+        vq.avail.idx.set(0);
+        vq.used.idx.set(0);
+        set_queue(block, 0, vq.create_queue());
+
+        let data_flags = match request_type {
+            VIRTIO_BLK_T_IN => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            _ => VIRTQ_DESC_F_NEXT,
+        };
+        for (i, &sector) in sectors.iter().enumerate() {
+            let idx = i as u16 * 3;
+            let hdr_addr = 0x1000 + i as u64 * 0x10;
+            mem.write_obj(
+                RequestHeader::new(request_type, sector),
+                GuestAddress(hdr_addr),
+            )
+            .unwrap();
+            vq.dtable[idx as usize].set(hdr_addr, 0x10, VIRTQ_DESC_F_NEXT, idx + 1);
+            vq.dtable[idx as usize + 1].set(
+                0x2000 + i as u64 * 0x300,
+                SECTOR_SIZE as u32,
+                data_flags,
+                idx + 2,
+            );
+            vq.dtable[idx as usize + 2].set(0x3000 + i as u64 * 0x10, 1, VIRTQ_DESC_F_WRITE, 0);
+
+            vq.avail.ring[i].set(idx);
+            vq.avail.idx.set(i as u16 + 1);
+        }
+    }
+
+    #[test]
+    fn test_merged_requests() {
+        let mut block = default_block(default_engine_type_for_kv());
+        assert!(block.disk.can_merge_requests());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.activate(mem.clone()).unwrap();
+
+        // The first four requests are contiguous, and merged into a single transfer. The last one
+        // doesn't follow them.
+        let sectors = [0, 1, 2, 3, 5];
+        let data_addr = |i: usize| GuestAddress(0x2000 + i as u64 * 0x300);
+        let status_addr = |i: usize| GuestAddress(0x3000 + i as u64 * 0x10);
+        let check_status = |len: u32| {
+            assert_eq!(vq.used.idx.get(), sectors.len() as u16);
+            for i in 0..sectors.len() {
+                let used = vq.used.ring[i].get();
+                assert_eq!(used.len, len);
+                assert_eq!(
+                    mem.read_obj::<u8>(status_addr(used.id as usize / 3))
+                        .unwrap(),
+                    VIRTIO_BLK_S_OK as u8
+                );
+            }
+        };
+
+        for i in 0..sectors.len() {
+            mem.write_slice(&[i as u8 + 1; SECTOR_SIZE as usize], data_addr(i))
+                .unwrap();
+        }
+        add_rw_requests_batch(&mut block, &vq, &mem, VIRTIO_BLK_T_OUT, &sectors);
+        // The metrics account for every request.
+        check_metric_after_block!(
+            &METRICS.block.write_count,
+            sectors.len(),
+            simulate_queue_and_async_completion_events(&mut block, true)
+        );
+        check_status(1);
+
+        let mut contents = vec![0u8; 0x1000];
+        let mut file = block.disk.file().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut contents).unwrap();
+        for (sector, chunk) in contents.chunks(SECTOR_SIZE as usize).enumerate() {
+            let expected = match sectors.iter().position(|&s| s == sector as u64) {
+                Some(i) => i as u8 + 1,
+                None => 0,
+            };
+            assert!(chunk.iter().all(|&b| b == expected));
+        }
+
+        // Read the data back.
+        for i in 0..sectors.len() {
+            mem.write_slice(&[0u8; SECTOR_SIZE as usize], data_addr(i))
+                .unwrap();
+        }
+        add_rw_requests_batch(&mut block, &vq, &mem, VIRTIO_BLK_T_IN, &sectors);
+        check_metric_after_block!(
+            &METRICS.block.read_count,
+            sectors.len(),
+            simulate_queue_and_async_completion_events(&mut block, true)
+        );
+        check_status(SECTOR_SIZE as u32 + 1);
+        for i in 0..sectors.len() {
+            let mut buf = [0u8; SECTOR_SIZE as usize];
+            mem.read_slice(&mut buf, data_addr(i)).unwrap();
+            assert!(buf.iter().all(|&b| b == i as u8 + 1));
+        }
+
+        // The data of encrypted disks goes through host buffers, so the requests aren't merged.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            Some(EncryptionConfig {
+                key: "11".repeat(32).into(),
+            }),
+            None,
+        )
+        .unwrap();
+        assert!(!block.disk.can_merge_requests());
+    }
+
+    #[test]
+    fn test_get_device_id() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let blk_metadata = block.disk.file().unwrap().metadata();
 
         // Test that the driver receives the correct device id.
         {
@@ -1417,6 +3371,59 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_register_guest_memory() {
+        skip_if_io_uring_unsupported!();
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Async,
+            IoEngineConfig {
+                registered_buffers: true,
+                sqpoll_idle_ms: None,
+            },
+            ImageFormat::Raw,
+            false,
+            2,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let registered_buffers = |block: &Block| -> Vec<usize> {
+            block
+                .disk
+                .file_engines()
+                .iter()
+                .map(|file_engine| match file_engine {
+                    FileEngine::Async(engine) => engine.registered_buffers().len(),
+                    FileEngine::Sync(_) | FileEngine::Nbd(_) => unreachable!(),
+                })
+                .collect()
+        };
+
+        // The guest memory is only known once the device is activated.
+        block.register_guest_memory();
+        assert_eq!(registered_buffers(&block), vec![0, 0]);
+
+        let mem = default_mem();
+        block.activate(mem.clone()).unwrap();
+        block.register_guest_memory();
+        assert_eq!(
+            registered_buffers(&block),
+            vec![mem.num_regions(), mem.num_regions()]
+        );
+    }
+
     #[test]
     fn test_io_engine_throttling() {
         // skip this test if kernel < 5.10 since in this case the sync engine will be used.
@@ -1433,25 +3440,25 @@ pub(crate) mod tests {
             // Run scenario that doesn't trigger FullSq Error: Add sq_size flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
 
             // Run scenario that triggers FullSqError : Add sq_size + 10 flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES + 10);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             // When the async_completion_event is triggered:
             // 1. sq_size requests should be processed processed.
             // 2. is_io_engine_throttled should be set back to false.
             // 3. process_queue() should be called again.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
             // check that process_queue() was called again resulting in the processing of the
             // remaining 10 ops.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES + 10, &mem, &vq);
         }
 
@@ -1467,18 +3474,18 @@ pub(crate) mod tests {
             // completion. Then try to push another entry.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
 
             add_flush_requests_batch(&mut block, &mem, &vq, 1);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES * 2, &mem, &vq);
         }
     }
@@ -1664,7 +3671,7 @@ pub(crate) mod tests {
             .unwrap();
 
         assert_eq!(
-            block.disk.file().metadata().unwrap().st_ino(),
+            block.disk.file().unwrap().metadata().unwrap().st_ino(),
             mdata.st_ino()
         );
         assert_eq!(block.disk.image_id, id.as_slice());
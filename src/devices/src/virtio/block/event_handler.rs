@@ -12,14 +12,24 @@ use crate::virtio::VirtioDevice;
 
 impl Block {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[0], EventSet::IN)) {
-            error!("Failed to register queue event: {}", err);
+        for queue_evt in &self.queue_evts {
+            if let Err(err) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", err);
+            }
         }
         if let Err(err) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
             error!("Failed to register ratelimiter event: {}", err);
         }
-        if let FileEngine::Async(engine) = self.disk.file_engine() {
-            if let Err(err) = ops.add(Events::new(engine.completion_evt(), EventSet::IN)) {
+        for file_engine in self.disk.file_engines() {
+            let res = match file_engine {
+                FileEngine::Async(engine) => {
+                    ops.add(Events::new(engine.completion_evt(), EventSet::IN))
+                }
+                // The NBD engines wait for their connection and timer on their own epoll fd.
+                FileEngine::Nbd(engine) => ops.add(Events::new(engine, EventSet::IN)),
+                FileEngine::Sync(_) => continue,
+            };
+            if let Err(err) = res {
                 error!("Failed to register IO engine completion event: {}", err);
             }
         }
@@ -31,11 +41,12 @@ impl Block {
         }
     }
 
-    fn process_activate_event(&self, ops: &mut EventOps) {
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
         debug!("block: activate event");
         if let Err(err) = self.activate_evt.read() {
             error!("Failed to consume block activate event: {:?}", err);
         }
+        self.register_guest_memory();
         self.register_runtime_events(ops);
         if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to un-register activate event: {}", err);
@@ -61,20 +72,28 @@ impl MutEventSubscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
-            let maybe_completion_fd = match self.disk.file_engine() {
-                FileEngine::Async(engine) => Some(engine.completion_evt().as_raw_fd()),
-                FileEngine::Sync(_) => None,
-            };
+            let maybe_queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
+            let maybe_completion_index =
+                self.disk
+                    .file_engines()
+                    .iter()
+                    .position(|file_engine| match file_engine {
+                        FileEngine::Async(engine) => engine.completion_evt().as_raw_fd() == source,
+                        FileEngine::Nbd(engine) => engine.as_raw_fd() == source,
+                        FileEngine::Sync(_) => false,
+                    });
 
             // Looks better than C style if/else if/else.
-            match source {
-                _ if queue_evt == source => self.process_queue_event(),
+            match (maybe_queue_index, maybe_completion_index) {
+                (Some(queue_index), _) => self.process_queue_event(queue_index),
+                (_, Some(queue_index)) => self.process_async_completion_event(queue_index),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
-                _ if maybe_completion_fd == Some(source) => self.process_async_completion_event(),
                 _ => warn!("Block: Spurious event received: {:?}", source),
             }
         } else {
@@ -91,6 +110,7 @@ impl MutEventSubscriber for Block {
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
         if self.is_activated() {
+            self.register_guest_memory();
             self.register_runtime_events(ops);
         } else {
             self.register_activate_event(ops);
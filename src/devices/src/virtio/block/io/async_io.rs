@@ -1,20 +1,58 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::cmp;
 use std::fs::File;
 use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 
 use io_uring::operation::{Cqe, OpCode, Operation};
 use io_uring::restriction::Restriction;
-use io_uring::{Error as IoUringError, IoUring};
-use logger::log_dev_preview_warning;
+use io_uring::{probe, Error as IoUringError, IoUring};
+use logger::{log_dev_preview_warning, warn};
 use utils::eventfd::EventFd;
-use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
-use crate::virtio::block::io::UserDataError;
+use crate::virtio::block::device::IoEngineConfig;
+use crate::virtio::block::io::{sync_io, UserDataError};
 use crate::virtio::block::IO_URING_NUM_ENTRIES;
 
+// The kernel rejects registered buffers larger than 1GiB, so the guest memory regions are
+// registered in chunks of this size.
+const MAX_REGISTERED_BUFFER_SIZE: usize = 1 << 30;
+// The operations submitted by the engine.
+const OPCODES: [OpCode; 6] = [
+    OpCode::Read,
+    OpCode::Write,
+    OpCode::Fsync,
+    OpCode::Fallocate,
+    OpCode::Readv,
+    OpCode::Writev,
+];
+// The operations submitted by the engine when using registered buffers.
+const FIXED_OPCODES: [OpCode; 2] = [OpCode::ReadFixed, OpCode::WriteFixed];
+
+/// Checks whether the host kernel supports all the operations submitted by an engine with the
+/// given configuration, as well as the polling of its submission queue if configured.
+pub fn is_supported(config: IoEngineConfig) -> bool {
+    let mut opcodes = OPCODES.to_vec();
+    if config.registered_buffers {
+        opcodes.extend_from_slice(&FIXED_OPCODES);
+    }
+    if probe::is_supported(&opcodes, config.sqpoll_idle_ms) {
+        return true;
+    }
+
+    if config.sqpoll_idle_ms.is_some() && probe::is_supported(&opcodes, None) {
+        warn!(
+            "The host kernel doesn't allow polling the io_uring submission queue, which needs \
+             privileges on kernels older than 5.11; the Sync io_engine will be used."
+        );
+    }
+    false
+}
+
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
@@ -29,32 +67,58 @@ pub struct AsyncFileEngine<T> {
     file: File,
     ring: IoUring,
     completion_evt: EventFd,
+    use_registered_buffers: bool,
+    // Host address and length of the registered buffers, in the order of their index.
+    registered_buffers: Vec<(usize, usize)>,
+    // Keeps the guest memory mapped for as long as the ring may access it. Declared after the
+    // ring, so that it is dropped after it.
+    registered_mem: Option<GuestMemoryMmap>,
     phantom: PhantomData<T>,
 }
 
 pub struct WrappedUserData<T> {
-    addr: Option<GuestAddress>,
+    // The guest memory segments written by the operation, in order.
+    dirty_segments: Vec<(GuestAddress, u32)>,
+    // The `iovec` array of a vectored operation, which must outlive it.
+    iovecs: Option<Vec<libc::iovec>>,
     user_data: T,
 }
 
 impl<T> WrappedUserData<T> {
     fn new(user_data: T) -> Self {
         WrappedUserData {
-            addr: None,
+            dirty_segments: Vec::new(),
+            iovecs: None,
             user_data,
         }
     }
 
-    fn new_with_dirty_tracking(addr: GuestAddress, user_data: T) -> Self {
+    fn new_with_dirty_tracking(addr: GuestAddress, count: u32, user_data: T) -> Self {
         WrappedUserData {
-            addr: Some(addr),
+            dirty_segments: vec![(addr, count)],
+            iovecs: None,
+            user_data,
+        }
+    }
+
+    fn new_with_iovecs(
+        iovecs: Vec<libc::iovec>,
+        dirty_segments: Vec<(GuestAddress, u32)>,
+        user_data: T,
+    ) -> Self {
+        WrappedUserData {
+            dirty_segments,
+            iovecs: Some(iovecs),
             user_data,
         }
     }
 
     fn mark_dirty_mem_and_unwrap(self, mem: &GuestMemoryMmap, count: u32) -> T {
-        if let Some(addr) = self.addr {
-            mark_dirty_mem(mem, addr, count as usize)
+        let mut remaining = count;
+        for (addr, len) in self.dirty_segments {
+            let len = cmp::min(len, remaining);
+            mark_dirty_mem(mem, addr, len as usize);
+            remaining -= len;
         }
 
         self.user_data
@@ -62,22 +126,26 @@ impl<T> WrappedUserData<T> {
 }
 
 impl<T> AsyncFileEngine<T> {
-    pub fn from_file(file: File) -> Result<AsyncFileEngine<T>, Error> {
+    pub fn from_file(file: File, config: IoEngineConfig) -> Result<AsyncFileEngine<T>, Error> {
         log_dev_preview_warning("Async file IO", Option::None);
 
         let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        // Make sure we only allow operations on pre-registered fds.
+        let mut restrictions = vec![Restriction::RequireFixedFds];
+        // Allowlist of opcodes.
+        restrictions.extend(OPCODES.map(Restriction::AllowOpCode));
+        if config.registered_buffers {
+            // The guest memory is registered once the device is activated.
+            restrictions.push(Restriction::AllowBufferRegistration);
+            restrictions.extend(FIXED_OPCODES.map(Restriction::AllowOpCode));
+        }
+
         let ring = IoUring::new(
             u32::from(IO_URING_NUM_ENTRIES),
             vec![&file],
-            vec![
-                // Make sure we only allow operations on pre-registered fds.
-                Restriction::RequireFixedFds,
-                // Allowlist of opcodes.
-                Restriction::AllowOpCode(OpCode::Read),
-                Restriction::AllowOpCode(OpCode::Write),
-                Restriction::AllowOpCode(OpCode::Fsync),
-            ],
+            restrictions,
             Some(completion_evt.as_raw_fd()),
+            config.sqpoll_idle_ms,
         )
         .map_err(Error::IoUring)?;
 
@@ -85,15 +153,67 @@ impl<T> AsyncFileEngine<T> {
             file,
             ring,
             completion_evt,
+            use_registered_buffers: config.registered_buffers,
+            registered_buffers: Vec::new(),
+            registered_mem: None,
             phantom: PhantomData,
         })
     }
 
+    /// Registers the guest memory with the ring, if the engine is configured to use registered
+    /// buffers. Requests on the registered memory then skip pinning the guest pages.
+    pub fn register_memory(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        if !self.use_registered_buffers || self.registered_mem.is_some() {
+            return Ok(());
+        }
+
+        let buffers: Vec<(usize, usize)> = mem
+            .iter()
+            .flat_map(|region| {
+                let start = region.as_ptr() as usize;
+                let len = region.len() as usize;
+                (0..len)
+                    .step_by(MAX_REGISTERED_BUFFER_SIZE)
+                    .map(move |off| (start + off, cmp::min(len - off, MAX_REGISTERED_BUFFER_SIZE)))
+            })
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter()
+            .map(|&(addr, len)| libc::iovec {
+                iov_base: addr as *mut libc::c_void,
+                iov_len: len,
+            })
+            .collect();
+
+        // SAFETY: Safe because the engine keeps a handle of the guest memory, which is dropped
+        // after the ring.
+        unsafe { self.ring.register_buffers(&iovecs) }.map_err(Error::IoUring)?;
+        self.registered_buffers = buffers;
+        self.registered_mem = Some(mem.clone());
+
+        Ok(())
+    }
+
+    // Returns the index of the registered buffer containing `[addr, addr + count)`, if any.
+    fn registered_buffer_index(&self, addr: usize, count: u32) -> Option<u16> {
+        let end = addr.checked_add(count as usize)?;
+        self.registered_buffers
+            .iter()
+            .position(|&(start, len)| start <= addr && end <= start + len)
+            // The number of registered buffers fits in a u16.
+            .map(|index| index as u16)
+    }
+
     #[cfg(test)]
     pub fn file(&self) -> &File {
         &self.file
     }
 
+    #[cfg(test)]
+    pub fn registered_buffers(&self) -> &[(usize, usize)] {
+        &self.registered_buffers
+    }
+
     pub fn completion_evt(&self) -> &EventFd {
         &self.completion_evt
     }
@@ -116,20 +236,17 @@ impl<T> AsyncFileEngine<T> {
             }
         };
 
-        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, count, user_data);
+        let operation = match self.registered_buffer_index(buf as usize, count) {
+            Some(buf_index) => {
+                Operation::read_fixed(0, buf as usize, count, offset, buf_index, wrapped_user_data)
+            }
+            None => Operation::read(0, buf as usize, count, offset, wrapped_user_data),
+        };
 
         // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
         // with this same `user_data`, so that the value will not be leaked.
-        unsafe {
-            self.ring.push(Operation::read(
-                0,
-                buf as usize,
-                count,
-                offset,
-                wrapped_user_data,
-            ))
-        }
-        .map_err(|err_tuple| UserDataError {
+        unsafe { self.ring.push(operation) }.map_err(|err_tuple| UserDataError {
             user_data: err_tuple.1.user_data,
             error: Error::IoUring(err_tuple.0),
         })
@@ -153,16 +270,115 @@ impl<T> AsyncFileEngine<T> {
             }
         };
 
+        let wrapped_user_data = WrappedUserData::new(user_data);
+        let operation = match self.registered_buffer_index(buf as usize, count) {
+            Some(buf_index) => {
+                Operation::write_fixed(0, buf as usize, count, offset, buf_index, wrapped_user_data)
+            }
+            None => Operation::write(0, buf as usize, count, offset, wrapped_user_data),
+        };
+
+        // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
+        // with this same `user_data`, so that the value will not be leaked.
+        unsafe { self.ring.push(operation) }.map_err(|err_tuple| UserDataError {
+            user_data: err_tuple.1.user_data,
+            error: Error::IoUring(err_tuple.0),
+        })
+    }
+
+    /// Reads into the given guest memory segments, in order, with a single vectored operation.
+    pub fn push_readv(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let iovecs = match sync_io::iovecs(mem, segments) {
+            Ok(iovecs) => iovecs,
+            Err(err) => {
+                return Err(UserDataError {
+                    user_data,
+                    error: Error::GuestMemory(err),
+                });
+            }
+        };
+
+        // Moving the array into the user data doesn't move its contents.
+        let (addr, len) = (iovecs.as_ptr() as usize, iovecs.len() as u32);
+        let wrapped_user_data =
+            WrappedUserData::new_with_iovecs(iovecs, segments.to_vec(), user_data);
+
+        // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
+        // with this same `user_data`, so that the value will not be leaked.
+        unsafe {
+            self.ring
+                .push(Operation::readv(0, addr, len, offset, wrapped_user_data))
+        }
+        .map_err(|err_tuple| UserDataError {
+            user_data: err_tuple.1.user_data,
+            error: Error::IoUring(err_tuple.0),
+        })
+    }
+
+    /// Writes the given guest memory segments, in order, with a single vectored operation.
+    pub fn push_writev(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let iovecs = match sync_io::iovecs(mem, segments) {
+            Ok(iovecs) => iovecs,
+            Err(err) => {
+                return Err(UserDataError {
+                    user_data,
+                    error: Error::GuestMemory(err),
+                });
+            }
+        };
+
+        // Moving the array into the user data doesn't move its contents.
+        let (addr, len) = (iovecs.as_ptr() as usize, iovecs.len() as u32);
+        let wrapped_user_data = WrappedUserData::new_with_iovecs(iovecs, Vec::new(), user_data);
+
+        // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
+        // with this same `user_data`, so that the value will not be leaked.
+        unsafe {
+            self.ring
+                .push(Operation::writev(0, addr, len, offset, wrapped_user_data))
+        }
+        .map_err(|err_tuple| UserDataError {
+            user_data: err_tuple.1.user_data,
+            error: Error::IoUring(err_tuple.0),
+        })
+    }
+
+    /// Writes a host buffer right away, such as data encrypted on behalf of the guest, which
+    /// doesn't outlive the call.
+    pub fn write_buffer(&self, offset: u64, buf: &[u8]) -> Result<u32, Error> {
+        self.file.write_all_at(buf, offset).map_err(Error::IO)?;
+        Ok(buf.len() as u32)
+    }
+
+    pub fn push_fallocate(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mode: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
         let wrapped_user_data = WrappedUserData::new(user_data);
 
         // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
         // with this same `user_data`, so that the value will not be leaked.
         unsafe {
-            self.ring.push(Operation::write(
+            self.ring.push(Operation::fallocate(
                 0,
-                buf as usize,
-                count,
                 offset,
+                len,
+                mode,
                 wrapped_user_data,
             ))
         }
@@ -0,0 +1,478 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for disk images opened with `O_DIRECT`, bypassing the host page cache.
+//!
+//! The offset, the length and the memory address of every transfer to or from such a file must
+//! be aligned to the logical block size of the underlying storage. Requests which satisfy these
+//! constraints are passed on to the file engine. All the others are executed synchronously
+//! through a bounce buffer covering the enclosing aligned range of the file. Unaligned writes
+//! first read the partial blocks at both ends of the range, so that the bytes around the written
+//! range are preserved.
+
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::{cmp, io, slice};
+
+use utils::ioctl::{ioctl_with_mut_ref, _IOC_NONE};
+use utils::ioctl_ioc_nr;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+use super::{FileEngine, FileEngineOk, UserDataError, UserDataOk};
+
+// The logical block sizes which are tried on regular files, in increasing order.
+const PROBED_BLOCK_SIZES: [u64; 4] = [512, 1024, 2048, 4096];
+// The alignment used when probing fails. It satisfies the constraints of any smaller block size.
+const MAX_BLOCK_SIZE: u64 = 4096;
+
+ioctl_ioc_nr!(BLKSSZGET, _IOC_NONE, 0x12, 104, 0);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to get the logical block size of the file.
+    BlockSize(io::Error),
+    /// The logical block size is not a power of two.
+    InvalidBlockSize(u64),
+    /// Failed to access the file.
+    Io(io::Error),
+    /// Failed to transfer data from or to guest memory.
+    Transfer(GuestMemoryError),
+    /// The size of the file is not a multiple of the logical block size, so writes to its last
+    /// block would extend it.
+    UnalignedSize(u64),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A zeroed heap buffer, aligned to the logical block size.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize, align: u64) -> Result<Self> {
+        let layout = Layout::from_size_align(len, align as usize)
+            .map_err(|_| Error::InvalidBlockSize(align))?;
+        // SAFETY: Safe because the layout has a non-zero size, as requests which don't transfer
+        // any data are never bounced.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Ok(Self { ptr, layout })
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: Safe because the buffer was allocated with this size and is owned by `self`.
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: Safe because the buffer was allocated with this size and is owned by `self`.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: Safe because the buffer was allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Serves the requests to a disk image opened with `O_DIRECT` which don't meet its alignment
+/// constraints.
+pub struct DirectIo {
+    // A handle of the disk image, which shares the `O_DIRECT` flag with the engine handles.
+    file: File,
+    block_size: u64,
+}
+
+impl DirectIo {
+    pub fn new(file: File) -> Result<Self> {
+        let block_size = Self::probe_block_size(&file)?;
+        if !block_size.is_power_of_two() {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        // The bounce buffers are written back whole, as `O_DIRECT` doesn't allow shorter writes.
+        // Block devices report a zero length, and always have an aligned size.
+        let size = file.metadata().map_err(Error::Io)?.len();
+        if size & (block_size - 1) != 0 {
+            return Err(Error::UnalignedSize(size));
+        }
+        Ok(Self { file, block_size })
+    }
+
+    /// The logical block size of the disk image, which every transfer must be aligned to.
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn probe_block_size(file: &File) -> Result<u64> {
+        let metadata = file.metadata().map_err(Error::BlockSize)?;
+        if metadata.file_type().is_block_device() {
+            let mut block_size: libc::c_int = 0;
+            // SAFETY: Safe because the file descriptor is valid, the kernel only writes an int
+            // to the given reference, and we check the return value.
+            if unsafe { ioctl_with_mut_ref(file, BLKSSZGET(), &mut block_size) } < 0 {
+                return Err(Error::BlockSize(io::Error::last_os_error()));
+            }
+            return Ok(block_size as u64);
+        }
+
+        // The constraints of regular files come from the device backing the filesystem, which
+        // is not accessible from here. Find the smallest read length the file accepts instead.
+        let mut buf = AlignedBuffer::new(MAX_BLOCK_SIZE as usize, MAX_BLOCK_SIZE)?;
+        for block_size in PROBED_BLOCK_SIZES {
+            match file.read_at(&mut buf[..block_size as usize], 0) {
+                Ok(_) => return Ok(block_size),
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => continue,
+                Err(err) => return Err(Error::BlockSize(err)),
+            }
+        }
+        Ok(MAX_BLOCK_SIZE)
+    }
+
+    fn align_down(&self, offset: u64) -> u64 {
+        offset & !(self.block_size - 1)
+    }
+
+    fn align_up(&self, offset: u64) -> u64 {
+        self.align_down(offset + self.block_size - 1)
+    }
+
+    fn is_aligned(
+        &self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> bool {
+        // Requests which don't transfer any data don't need a bounce buffer.
+        if count == 0 {
+            return true;
+        }
+        match mem.get_host_address(addr) {
+            Ok(host_addr) => {
+                (offset | u64::from(count) | host_addr as u64) & (self.block_size - 1) == 0
+            }
+            // Let the file engine report the invalid address.
+            Err(_) => true,
+        }
+    }
+
+    /// Reads into `buf` from `offset`, until the buffer is full or the end of the file is
+    /// reached. Returns the number of bytes read.
+    fn read_full(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.file.read_at(&mut buf[done..], offset + done as u64) {
+                Ok(0) => break,
+                Ok(len) => {
+                    done += len;
+                    // Reads are only cut short at the end of the file, and the next read
+                    // wouldn't be aligned anyway.
+                    if len as u64 & (self.block_size - 1) != 0 {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(Error::Io(err)),
+            }
+        }
+        Ok(done)
+    }
+
+    fn bounce_read(
+        &self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let start = self.align_down(offset);
+        let end = self.align_up(offset + u64::from(count));
+        let mut buf = AlignedBuffer::new((end - start) as usize, self.block_size)?;
+
+        let len = self.read_full(&mut buf, start)?;
+        let head = (offset - start) as usize;
+        let count = cmp::min(count as usize, len.saturating_sub(head));
+        mem.write_slice(&buf[head..head + count], addr)
+            .map_err(Error::Transfer)?;
+        Ok(count as u32)
+    }
+
+    fn bounce_write(
+        &self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let start = self.align_down(offset);
+        let end = self.align_up(offset + u64::from(count));
+        let mut buf = AlignedBuffer::new((end - start) as usize, self.block_size)?;
+        let block_size = self.block_size as usize;
+        let head = (offset - start) as usize;
+        let tail = (end - offset) as usize - count as usize;
+
+        // Preserve the bytes of the partial blocks at both ends of the range.
+        if head != 0 {
+            self.read_full(&mut buf[..block_size], start)?;
+        }
+        if tail != 0 && (head == 0 || buf.len() > block_size) {
+            let last_block = buf.len() - block_size;
+            self.read_full(&mut buf[last_block..], end - self.block_size)?;
+        }
+
+        mem.read_slice(&mut buf[head..head + count as usize], addr)
+            .map_err(Error::Transfer)?;
+        self.file.write_all_at(&buf, start).map_err(Error::Io)?;
+        Ok(count)
+    }
+
+    pub fn read<T>(
+        &self,
+        engine: &mut FileEngine<T>,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        if self.is_aligned(offset, mem, addr, count) {
+            return engine.read(offset, mem, addr, count, user_data);
+        }
+        Self::executed(self.bounce_read(offset, mem, addr, count), user_data)
+    }
+
+    pub fn write<T>(
+        &self,
+        engine: &mut FileEngine<T>,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        if self.is_aligned(offset, mem, addr, count) {
+            return engine.write(offset, mem, addr, count, user_data);
+        }
+        Self::executed(self.bounce_write(offset, mem, addr, count), user_data)
+    }
+
+    fn executed<T>(
+        res: Result<u32>,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        match res {
+            Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+            Err(err) => Err(UserDataError {
+                user_data,
+                error: super::Error::DirectIo(err),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+    use utils::skip_if_io_uring_unsupported;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::virtio::block::device::{FileEngineType, IoEngineConfig};
+
+    const BLOCK_SIZE: u64 = 512;
+    const FILE_LEN: usize = 4 * BLOCK_SIZE as usize;
+    const MEM_LEN: usize = 0x1000;
+
+    // Builds the helper without `O_DIRECT`, which isn't supported by every filesystem, so that
+    // the bounce buffers can be checked everywhere.
+    fn direct_io(file: &File) -> DirectIo {
+        DirectIo {
+            file: file.try_clone().unwrap(),
+            block_size: BLOCK_SIZE,
+        }
+    }
+
+    fn create_file() -> (TempFile, Vec<u8>) {
+        let tmp = TempFile::new().unwrap();
+        let contents: Vec<u8> = (0..FILE_LEN).map(|i| (i % 251) as u8).collect();
+        tmp.as_file().write_all_at(&contents, 0).unwrap();
+        (tmp, contents)
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], false)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_is_aligned() {
+        let (tmp, _) = create_file();
+        let direct_io = direct_io(tmp.as_file());
+        let mem = create_mem();
+
+        assert!(direct_io.is_aligned(0, &mem, GuestAddress(0), 0));
+        assert!(direct_io.is_aligned(1, &mem, GuestAddress(0x10), 0));
+        assert!(direct_io.is_aligned(512, &mem, GuestAddress(0x200), 1024));
+        assert!(!direct_io.is_aligned(1, &mem, GuestAddress(0), 512));
+        assert!(!direct_io.is_aligned(0, &mem, GuestAddress(0), 511));
+        assert!(!direct_io.is_aligned(0, &mem, GuestAddress(0x10), 512));
+        // Invalid addresses are left to the file engine.
+        assert!(direct_io.is_aligned(1, &mem, GuestAddress(MEM_LEN as u64), 1));
+    }
+
+    #[test]
+    fn test_bounce_read() {
+        let (tmp, contents) = create_file();
+        let direct_io = direct_io(tmp.as_file());
+        let mem = create_mem();
+        let mut engine: FileEngine<()> = FileEngine::from_file(
+            tmp.as_file().try_clone().unwrap(),
+            FileEngineType::Sync,
+            IoEngineConfig::default(),
+        )
+        .unwrap();
+
+        // Unaligned offset and length, spanning three blocks.
+        let res = direct_io.read(&mut engine, 100, &mem, GuestAddress(0x10), 1000, ());
+        assert!(matches!(
+            res,
+            Ok(FileEngineOk::Executed(UserDataOk { count: 1000, .. }))
+        ));
+        let mut buf = vec![0u8; 1000];
+        mem.read_slice(&mut buf, GuestAddress(0x10)).unwrap();
+        assert_eq!(buf, contents[100..1100]);
+
+        // The read is cut short at the end of the file.
+        let res = direct_io.read(
+            &mut engine,
+            FILE_LEN as u64 - 10,
+            &mem,
+            GuestAddress(0),
+            20,
+            (),
+        );
+        assert!(matches!(
+            res,
+            Ok(FileEngineOk::Executed(UserDataOk { count: 10, .. }))
+        ));
+        let mut buf = vec![0u8; 10];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, contents[FILE_LEN - 10..]);
+    }
+
+    #[test]
+    fn test_bounce_write() {
+        let (tmp, mut contents) = create_file();
+        let direct_io = direct_io(tmp.as_file());
+        let mem = create_mem();
+        let mut engine: FileEngine<()> = FileEngine::from_file(
+            tmp.as_file().try_clone().unwrap(),
+            FileEngineType::Sync,
+            IoEngineConfig::default(),
+        )
+        .unwrap();
+        mem.write_slice(&[0xaa; MEM_LEN], GuestAddress(0)).unwrap();
+
+        // Within a single block.
+        let res = direct_io.write(&mut engine, 10, &mem, GuestAddress(0), 20, ());
+        assert!(matches!(
+            res,
+            Ok(FileEngineOk::Executed(UserDataOk { count: 20, .. }))
+        ));
+        contents[10..30].fill(0xaa);
+
+        // Spanning three blocks, with partial blocks at both ends.
+        let res = direct_io.write(&mut engine, 600, &mem, GuestAddress(0), 1000, ());
+        assert!(matches!(
+            res,
+            Ok(FileEngineOk::Executed(UserDataOk { count: 1000, .. }))
+        ));
+        contents[600..1600].fill(0xaa);
+
+        // Aligned in the file, but not in memory.
+        let res = direct_io.write(&mut engine, 1536, &mem, GuestAddress(0x1), 512, ());
+        assert!(matches!(
+            res,
+            Ok(FileEngineOk::Executed(UserDataOk { count: 512, .. }))
+        ));
+        contents[1536..2048].fill(0xaa);
+
+        let mut buf = vec![0u8; FILE_LEN];
+        tmp.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, contents);
+    }
+
+    #[test]
+    fn test_aligned_requests() {
+        skip_if_io_uring_unsupported!();
+
+        let (tmp, contents) = create_file();
+        let direct_io = direct_io(tmp.as_file());
+        let mem = create_mem();
+        let mut engine: FileEngine<()> = FileEngine::from_file(
+            tmp.as_file().try_clone().unwrap(),
+            FileEngineType::Async,
+            IoEngineConfig::default(),
+        )
+        .unwrap();
+
+        // Aligned requests are submitted to the engine, unaligned ones are executed.
+        let res = direct_io.read(&mut engine, 512, &mem, GuestAddress(0x200), 512, ());
+        assert!(matches!(res, Ok(FileEngineOk::Submitted)));
+        let res = direct_io.read(&mut engine, 1, &mem, GuestAddress(0x400), 512, ());
+        assert!(matches!(res, Ok(FileEngineOk::Executed(_))));
+        engine.drain(true).unwrap();
+
+        let mut buf = vec![0u8; 512];
+        mem.read_slice(&mut buf, GuestAddress(0x200)).unwrap();
+        assert_eq!(buf, contents[512..1024]);
+        mem.read_slice(&mut buf, GuestAddress(0x400)).unwrap();
+        assert_eq!(buf, contents[1..513]);
+    }
+
+    #[test]
+    fn test_probe_block_size() {
+        let (tmp, _) = create_file();
+        let file = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(tmp.as_path())
+        {
+            Ok(file) => file,
+            // The filesystem doesn't support `O_DIRECT`.
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{}", err),
+        };
+
+        let direct_io = DirectIo::new(file).unwrap();
+        assert!(PROBED_BLOCK_SIZES.contains(&direct_io.block_size()));
+    }
+
+    #[test]
+    fn test_unaligned_size() {
+        let (tmp, _) = create_file();
+        tmp.as_file().set_len(FILE_LEN as u64 + 1).unwrap();
+        // Without `O_DIRECT`, the smallest probed block size is accepted.
+        assert!(matches!(
+            DirectIo::new(tmp.as_file().try_clone().unwrap()),
+            Err(Error::UnalignedSize(size)) if size == FILE_LEN as u64 + 1
+        ));
+    }
+}
@@ -2,15 +2,30 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod direct_io;
+pub mod nbd;
+pub mod qcow2;
 pub mod sync_io;
 
+use std::cmp;
 use std::fs::File;
 
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
 
 pub use self::async_io::AsyncFileEngine;
+pub use self::direct_io::DirectIo;
+pub use self::nbd::{NbdConfig, NbdEngine};
+pub use self::qcow2::Qcow2Image;
 pub use self::sync_io::SyncFileEngine;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::crypt::{self, XtsCipher};
+use crate::virtio::block::device::{FileEngineType, IoEngineConfig};
+use crate::virtio::block::SECTOR_SHIFT;
+
+// `fallocate` mode used for deallocating a range of the backing file. Keeping the size makes
+// sure that the disk capacity seen by the guest doesn't change.
+const DISCARD_MODE: u32 = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+// `fallocate` mode used for zeroing a range of the backing file, without deallocating it.
+const WRITE_ZEROES_MODE: u32 = (libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) as u32;
 
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct UserDataOk<T> {
@@ -28,6 +43,10 @@ pub enum FileEngineOk<T> {
 pub enum Error {
     Sync(sync_io::Error),
     Async(async_io::Error),
+    DirectIo(direct_io::Error),
+    Qcow2(qcow2::Error),
+    Crypt(crypt::Error),
+    Nbd(nbd::Error),
     UnsupportedEngine(FileEngineType),
     GetKernelVersion(utils::kernel_version::Error),
 }
@@ -52,10 +71,18 @@ pub enum FileEngine<T> {
     #[allow(unused)]
     Async(AsyncFileEngine<T>),
     Sync(SyncFileEngine),
+    Nbd(NbdEngine<T>),
 }
 
 impl<T> FileEngine<T> {
-    pub fn from_file(file: File, engine_type: FileEngineType) -> Result<FileEngine<T>, Error> {
+    pub fn from_file(
+        file: File,
+        engine_type: FileEngineType,
+        config: IoEngineConfig,
+    ) -> Result<FileEngine<T>, Error> {
+        let engine_type = engine_type
+            .select(config)
+            .map_err(Error::GetKernelVersion)?;
         if !engine_type
             .is_supported()
             .map_err(Error::GetKernelVersion)?
@@ -64,17 +91,32 @@ impl<T> FileEngine<T> {
         }
         match engine_type {
             FileEngineType::Async => Ok(FileEngine::Async(
-                AsyncFileEngine::from_file(file).map_err(Error::Async)?,
+                AsyncFileEngine::from_file(file, config).map_err(Error::Async)?,
             )),
-            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            // The `Auto` type was already replaced by the selected engine.
+            FileEngineType::Sync | FileEngineType::Auto => {
+                Ok(FileEngine::Sync(SyncFileEngine::from_file(file)))
+            }
+        }
+    }
+
+    /// Registers the guest memory with the engine, if it is configured to use registered
+    /// buffers.
+    pub fn register_memory(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        match self {
+            FileEngine::Async(engine) => engine.register_memory(mem).map_err(Error::Async),
+            FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Nbd(_engine) => Ok(()),
         }
     }
 
+    /// The backing file of the engine, unless it serves an NBD export.
     #[cfg(test)]
-    pub fn file(&self) -> &File {
+    pub fn file(&self) -> Option<&File> {
         match self {
-            FileEngine::Async(engine) => engine.file(),
-            FileEngine::Sync(engine) => engine.file(),
+            FileEngine::Async(engine) => Some(engine.file()),
+            FileEngine::Sync(engine) => Some(engine.file()),
+            FileEngine::Nbd(_engine) => None,
         }
     }
 
@@ -103,6 +145,15 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Nbd(engine) => {
+                match engine.push_read(offset, mem, addr, count, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                }
+            }
         }
     }
 
@@ -131,6 +182,205 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Nbd(engine) => {
+                match engine.push_write(offset, mem, addr, count, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Reads into the given guest memory segments, in order, with a single vectored operation.
+    pub fn readv(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_readv(offset, mem, segments, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.readv(offset, mem, segments) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+            // Requests are not merged on NBD disks.
+            FileEngine::Nbd(_engine) => Err(UserDataError {
+                user_data,
+                error: Error::Nbd(nbd::Error::UnsupportedRequest),
+            }),
+        }
+    }
+
+    /// Writes the given guest memory segments, in order, with a single vectored operation.
+    pub fn writev(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_writev(offset, mem, segments, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.writev(offset, mem, segments) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+            // Requests are not merged on NBD disks.
+            FileEngine::Nbd(_engine) => Err(UserDataError {
+                user_data,
+                error: Error::Nbd(nbd::Error::UnsupportedRequest),
+            }),
+        }
+    }
+
+    /// Encrypts the data found in guest memory and writes it. Except on NBD disks, the data goes
+    /// through `buf`, a whole number of sectors long, one chunk at a time, and is written right
+    /// away.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_encrypted(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        cipher: &XtsCipher,
+        buf: &mut [u8],
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        let res = match self {
+            FileEngine::Async(engine) => {
+                write_encrypted_chunks(offset, mem, addr, count, cipher, buf, |offset, chunk| {
+                    engine.write_buffer(offset, chunk).map_err(Error::Async)
+                })
+            }
+            FileEngine::Sync(engine) => {
+                write_encrypted_chunks(offset, mem, addr, count, cipher, buf, |offset, chunk| {
+                    engine.write_buffer(offset, chunk).map_err(Error::Sync)
+                })
+            }
+            // The engine keeps the data of every write until it completes, to send it again after
+            // reconnecting, so the data is encrypted into a buffer of its own.
+            FileEngine::Nbd(engine) => {
+                return match engine.push_write_with(offset, mem, addr, count, user_data, |data| {
+                    cipher.encrypt(offset >> SECTOR_SHIFT, data)
+                }) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                };
+            }
+        };
+        match res {
+            Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+            Err(error) => Err(UserDataError { user_data, error }),
+        }
+    }
+
+    /// Deallocates the given range of the backing file, so that subsequent reads return zeroes.
+    pub fn discard(
+        &mut self,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Nbd(engine) => match engine.push_trim(offset, len, user_data) {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(err) => Err(UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Nbd(err.error),
+                }),
+            },
+            _ => self.fallocate(offset, len, DISCARD_MODE, user_data),
+        }
+    }
+
+    /// Zeroes the given range of the backing file. If `unmap` is set, the range may also be
+    /// deallocated.
+    pub fn write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u64,
+        unmap: bool,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        if let FileEngine::Nbd(engine) = self {
+            return match engine.push_write_zeroes(offset, len, unmap, user_data) {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(err) => Err(UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Nbd(err.error),
+                }),
+            };
+        }
+        let mode = match unmap {
+            true => DISCARD_MODE,
+            false => WRITE_ZEROES_MODE,
+        };
+        self.fallocate(offset, len, mode, user_data)
+    }
+
+    fn fallocate(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mode: u32,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_fallocate(offset, len, mode, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.fallocate(offset, len, mode) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+            // The NBD requests are sent by `discard` and `write_zeroes`.
+            FileEngine::Nbd(_engine) => Err(UserDataError {
+                user_data,
+                error: Error::Nbd(nbd::Error::UnsupportedRequest),
+            }),
         }
     }
 
@@ -153,6 +403,20 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            // Without flush requests, the server flushes the data at its own discretion.
+            FileEngine::Nbd(engine) if !engine.can_flush() => {
+                Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                }))
+            }
+            FileEngine::Nbd(engine) => match engine.push_flush(user_data) {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(err) => Err(UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Nbd(err.error),
+                }),
+            },
         }
     }
 
@@ -160,6 +424,7 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain(discard).map_err(Error::Async),
             FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Nbd(engine) => engine.drain(discard).map_err(Error::Nbd),
         }
     }
 
@@ -167,14 +432,44 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain_and_flush(discard).map_err(Error::Async),
             FileEngine::Sync(engine) => engine.flush().map_err(Error::Sync),
+            FileEngine::Nbd(engine) => engine.drain_and_flush(discard).map_err(Error::Nbd),
         }
     }
 }
 
+// Encrypts the `count` bytes found in guest memory at `addr` through `buf`, handing each chunk to
+// `write` along with its offset on the disk.
+fn write_encrypted_chunks(
+    offset: u64,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    count: u32,
+    cipher: &XtsCipher,
+    buf: &mut [u8],
+    mut write: impl FnMut(u64, &[u8]) -> Result<u32, Error>,
+) -> Result<u32, Error> {
+    let mut done = 0;
+    while done < count {
+        let chunk = &mut buf[..cmp::min(count - done, buf.len() as u32) as usize];
+        let chunk_offset = offset + u64::from(done);
+        cipher
+            .encrypt_from_mem(
+                mem,
+                addr.unchecked_add(u64::from(done)),
+                chunk_offset,
+                chunk,
+            )
+            .map_err(Error::Crypt)?;
+        done += write(chunk_offset, chunk)?;
+    }
+    Ok(done)
+}
+
 #[cfg(test)]
 pub mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::os::unix::io::FromRawFd;
 
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
@@ -183,8 +478,8 @@ pub mod tests {
     use vm_memory::{Bitmap, Bytes, GuestMemory};
 
     use super::*;
-    use crate::virtio::block::device::FileEngineType;
     use crate::virtio::block::request::PendingRequest;
+    use crate::virtio::block::SECTOR_SIZE;
 
     const FILE_LEN: u32 = 1024;
     // 2 pages of memory should be enough to test read/write ops and also dirty tracking.
@@ -259,7 +554,8 @@ pub mod tests {
         assert!(matches!(
             FileEngine::<PendingRequest>::from_file(
                 TempFile::new().unwrap().into_file(),
-                FileEngineType::Async
+                FileEngineType::Async,
+                IoEngineConfig::default()
             ),
             Err(Error::UnsupportedEngine(FileEngineType::Async))
         ));
@@ -270,7 +566,8 @@ pub mod tests {
         // Check invalid file
         let mem = create_mem();
         let file = unsafe { File::from_raw_fd(-2) };
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine =
+            FileEngine::from_file(file, FileEngineType::Sync, IoEngineConfig::default()).unwrap();
         let res = engine.read(0, &mem, GuestAddress(0), 0, ());
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.write(0, &mem, GuestAddress(0), 0, ());
@@ -280,7 +577,8 @@ pub mod tests {
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine =
+            FileEngine::from_file(file, FileEngineType::Sync, IoEngineConfig::default()).unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -339,6 +637,57 @@ pub mod tests {
         mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
         assert_eq!(buf, data.as_slice());
 
+        // Discard
+        let discard_len = u64::from(FILE_LEN / 2);
+        assert_sync_execution!(engine.discard(0, discard_len, ()), 0);
+        assert_eq!(
+            engine.file().unwrap().metadata().unwrap().len(),
+            u64::from(FILE_LEN)
+        );
+        // Write zeroes
+        assert_sync_execution!(engine.write_zeroes(discard_len, 10, true, ()), 0);
+        // Check data
+        let mem = create_mem();
+        assert_sync_execution!(
+            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            FILE_LEN
+        );
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        let zeroed_len = discard_len as usize + 10;
+        assert!(buf[..zeroed_len].iter().all(|&b| b == 0));
+        assert_eq!(buf[zeroed_len..], data[zeroed_len..]);
+
+        // Vectored write
+        let segments = [(GuestAddress(0x100), 100), (GuestAddress(0x1000), 50)];
+        mem.write(&data[..100], segments[0].0).unwrap();
+        mem.write(&data[100..150], segments[1].0).unwrap();
+        assert_sync_execution!(engine.writev(offset, &mem, &segments, ()), 150);
+        // Vectored read
+        let mem = create_mem();
+        assert_sync_execution!(engine.readv(offset, &mem, &segments, ()), 150);
+        // Check data
+        let mut buf = vec![0u8; 100];
+        mem.read_slice(&mut buf, segments[0].0).unwrap();
+        assert_eq!(buf, data[..100]);
+        let mut buf = vec![0u8; 50];
+        mem.read_slice(&mut buf, segments[1].0).unwrap();
+        assert_eq!(buf, data[100..150]);
+        // check dirty mem
+        check_dirty_mem(&mem, segments[0].0, 100);
+        check_dirty_mem(&mem, segments[1].0, 50);
+        // Partial vectored read
+        let mem = create_mem();
+        assert_sync_execution!(
+            engine.readv(u64::from(FILE_LEN) - 60, &mem, &segments, ()),
+            60
+        );
+        check_dirty_mem(&mem, segments[0].0, 60);
+        check_clean_mem(&mem, segments[1].0, 50);
+        // Invalid segment
+        let res = engine.readv(0, &mem, &[(GuestAddress(MEM_LEN as u64), 1)], ());
+        assert_err!(res, Error::Sync(sync_io::Error::Transfer(_e)));
+
         // Check other ops
         assert!(engine.flush(()).is_ok());
         assert!(engine.drain(true).is_ok());
@@ -351,11 +700,16 @@ pub mod tests {
 
         // Check invalid file
         let file = unsafe { File::from_raw_fd(-2) };
-        assert!(FileEngine::<()>::from_file(file, FileEngineType::Async).is_err());
+        assert!(
+            FileEngine::<()>::from_file(file, FileEngineType::Async, IoEngineConfig::default())
+                .is_err()
+        );
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async).unwrap();
+        let mut engine =
+            FileEngine::<()>::from_file(file, FileEngineType::Async, IoEngineConfig::default())
+                .unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -401,6 +755,57 @@ pub mod tests {
         check_dirty_mem(&mem, addr, FILE_LEN);
         check_clean_mem(&mem, GuestAddress(4096), 4096);
 
+        // Discard
+        let discard_len = u64::from(FILE_LEN / 2);
+        assert_queued!(engine.discard(0, discard_len, ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        assert_eq!(
+            engine.file().unwrap().metadata().unwrap().len(),
+            u64::from(FILE_LEN)
+        );
+        // Write zeroes
+        assert_queued!(engine.write_zeroes(discard_len, 10, true, ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        // Check data
+        let mem = create_mem();
+        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        let zeroed_len = discard_len as usize + 10;
+        assert!(buf[..zeroed_len].iter().all(|&b| b == 0));
+        assert_eq!(buf[zeroed_len..], data[zeroed_len..]);
+
+        // Vectored write
+        let segments = [(GuestAddress(0x100), 100), (GuestAddress(0x1000), 50)];
+        mem.write(&data[..100], segments[0].0).unwrap();
+        mem.write(&data[100..150], segments[1].0).unwrap();
+        assert_queued!(engine.writev(offset, &mem, &segments, ()));
+        assert_async_execution(&mem, &mut engine, 150);
+        // Vectored read
+        let mem = create_mem();
+        assert_queued!(engine.readv(offset, &mem, &segments, ()));
+        assert_async_execution(&mem, &mut engine, 150);
+        // Check data
+        let mut buf = vec![0u8; 100];
+        mem.read_slice(&mut buf, segments[0].0).unwrap();
+        assert_eq!(buf, data[..100]);
+        let mut buf = vec![0u8; 50];
+        mem.read_slice(&mut buf, segments[1].0).unwrap();
+        assert_eq!(buf, data[100..150]);
+        // check dirty mem
+        check_dirty_mem(&mem, segments[0].0, 100);
+        check_dirty_mem(&mem, segments[1].0, 50);
+        // Partial vectored read
+        let mem = create_mem();
+        assert_queued!(engine.readv(u64::from(FILE_LEN) - 60, &mem, &segments, ()));
+        assert_async_execution(&mem, &mut engine, 60);
+        check_dirty_mem(&mem, segments[0].0, 60);
+        check_clean_mem(&mem, segments[1].0, 50);
+        // Invalid segment
+        let res = engine.readv(0, &mem, &[(GuestAddress(MEM_LEN as u64), 1)], ());
+        assert_err!(res, Error::Async(async_io::Error::GuestMemory(_e)));
+
         // Check other ops
         assert_queued!(engine.flush(()));
         assert_async_execution(&mem, &mut engine, 0);
@@ -408,4 +813,132 @@ pub mod tests {
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
+
+    #[test]
+    fn test_async_registered_buffers() {
+        skip_if_io_uring_unsupported!();
+
+        let config = IoEngineConfig {
+            registered_buffers: true,
+            sqpoll_idle_ms: None,
+        };
+        let file = TempFile::new().unwrap().into_file();
+        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async, config).unwrap();
+
+        let mem = create_mem();
+        engine.register_memory(&mem).unwrap();
+        if let FileEngine::Async(ref engine) = engine {
+            let region_addr = mem.get_host_address(GuestAddress(0)).unwrap() as usize;
+            assert_eq!(engine.registered_buffers(), &[(region_addr, MEM_LEN)]);
+        }
+        // The memory is only registered once.
+        engine.register_memory(&create_mem()).unwrap();
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+
+        // Write and read back through the registered buffers.
+        let addr = GuestAddress(0);
+        mem.write(&data, addr).unwrap();
+        assert_queued!(engine.write(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        mem.write(&[0u8; FILE_LEN as usize], addr).unwrap();
+        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data.as_slice());
+        check_dirty_mem(&mem, addr, FILE_LEN);
+
+        // Requests on memory which is not registered are still served.
+        let other_mem = create_mem();
+        assert_queued!(engine.read(0, &other_mem, addr, FILE_LEN, ()));
+        assert_async_execution(&other_mem, &mut engine, FILE_LEN);
+        other_mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data.as_slice());
+
+        assert!(engine.drain_and_flush(true).is_ok());
+    }
+
+    #[test]
+    fn test_write_encrypted() {
+        let cipher = XtsCipher::new(&crypt::EncryptionConfig {
+            key: ("11".repeat(16) + &"22".repeat(16)).into(),
+        })
+        .unwrap();
+        let mem = create_mem();
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        mem.write(&data, GuestAddress(0)).unwrap();
+
+        let mut engine_types = vec![FileEngineType::Sync];
+        if FileEngineType::Async.is_supported().unwrap() {
+            engine_types.push(FileEngineType::Async);
+        }
+        for engine_type in engine_types {
+            let file = TempFile::new().unwrap().into_file();
+            let mut engine =
+                FileEngine::<()>::from_file(file, engine_type, IoEngineConfig::default()).unwrap();
+
+            // The data goes through a buffer shorter than the write, and is written right away.
+            let mut buf = [0u8; SECTOR_SIZE as usize];
+            assert_sync_execution!(
+                engine.write_encrypted(
+                    0x200,
+                    &mem,
+                    GuestAddress(0),
+                    FILE_LEN,
+                    &cipher,
+                    &mut buf,
+                    ()
+                ),
+                FILE_LEN
+            );
+            let mut expected = data.clone();
+            cipher.encrypt(1, &mut expected);
+            let mut written = vec![0u8; FILE_LEN as usize];
+            engine.file().unwrap().read_exact_at(&mut written, 0x200).unwrap();
+            assert_eq!(written, expected);
+
+            // The guest memory is left untouched.
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf, data);
+
+            let mut buf = [0u8; SECTOR_SIZE as usize];
+            let res = engine.write_encrypted(
+                0,
+                &mem,
+                GuestAddress(MEM_LEN as u64),
+                FILE_LEN,
+                &cipher,
+                &mut buf,
+                (),
+            );
+            assert_err!(res, Error::Crypt(crypt::Error::Transfer(_e)));
+        }
+    }
+
+    #[test]
+    fn test_nbd_unsupported_requests() {
+        let server = nbd::test_utils::TestServer::new(nbd::test_utils::TestServerConfig {
+            size: FILE_LEN as usize,
+            ..Default::default()
+        });
+        let mut engine =
+            FileEngine::Nbd(NbdEngine::<()>::connect(&server.config(), false, false).unwrap());
+        let mem = create_mem();
+        assert!(engine.file().is_none());
+
+        // Vectored requests and raw `fallocate` calls don't map to NBD commands.
+        let segments = [(GuestAddress(0), 0x200)];
+        let res = engine.readv(0, &mem, &segments, ());
+        assert_err!(res, Error::Nbd(nbd::Error::UnsupportedRequest));
+        let res = engine.writev(0, &mem, &segments, ());
+        assert_err!(res, Error::Nbd(nbd::Error::UnsupportedRequest));
+        let res = engine.fallocate(0, 0x200, DISCARD_MODE, ());
+        assert_err!(res, Error::Nbd(nbd::Error::UnsupportedRequest));
+    }
 }
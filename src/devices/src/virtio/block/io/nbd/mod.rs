@@ -0,0 +1,1606 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client side of the [NBD protocol](https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md),
+//! serving the requests of a block device from an export of a remote server.
+//!
+//! Only the fixed newstyle handshake and the simple replies are implemented. The requests are sent
+//! and their replies received without blocking, from the event loop of the device. So are the
+//! connection and the handshake when reconnecting, while the first connection, made when the drive
+//! is configured, waits for the handshake to complete.
+
+#[cfg(test)]
+pub(crate) mod test_utils;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+use std::{cmp, mem, result};
+
+use logger::{error, info, warn, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::virtio::block::io::{UserDataError, UserDataOk};
+
+// Magic numbers of the handshake.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_OPT_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+// Handshake flags sent by the server and by the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options and option replies.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+const NBD_INFO_EXPORT: u16 = 0;
+// Size of the reply of the servers to `NBD_OPT_EXPORT_NAME`, without the trailing zeroes.
+const EXPORT_NAME_REPLY_SIZE: usize = 10;
+// Number of zeroes trailing the reply to `NBD_OPT_EXPORT_NAME`, unless the client asks not to.
+const EXPORT_NAME_REPLY_ZEROES: usize = 124;
+
+// Transmission flags, describing the export.
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Requests and their replies.
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_REQUEST_SIZE: usize = 28;
+const NBD_SIMPLE_REPLY_SIZE: usize = 16;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+// Longest time the connection to the server and the handshake may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Default delay before the first reconnection attempt.
+const DEFAULT_RECONNECT_DELAY_MS: u64 = 1000;
+// The delay between two reconnection attempts doubles after every failure, up to this bound,
+// unless the configured delay is longer.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// Longest time the requests in flight may take to complete when draining the engine, which
+// blocks the VMM thread.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// Most bytes the server may send during the handshake.
+const MAX_HANDSHAKE_SIZE: usize = 64 << 10;
+// The events of the connection the engines wait for. Edge triggered, so that the epoll is only
+// ready when the connection changes state, rather than whenever it is writable.
+const SOCKET_EVENTS: EventSet = EventSet::IN
+    .union(EventSet::OUT)
+    .union(EventSet::READ_HANG_UP)
+    .union(EventSet::EDGE_TRIGGERED);
+
+// Tokens of the events of the internal epoll.
+const SOCKET_TOKEN: u64 = 0;
+const TIMER_TOKEN: u64 = 1;
+const COMPLETION_TOKEN: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the server.
+    Connect(io::Error),
+    /// The connection to the server is lost.
+    Disconnected,
+    /// Failed to set up the events of the engine.
+    Epoll(io::Error),
+    /// Failed to create the completion event.
+    EventFd(io::Error),
+    /// The export changed since the previous connection to the server.
+    ExportChanged,
+    /// The server refused the export, with the given option reply.
+    ExportRejected(u32),
+    /// Failed to access the guest memory.
+    GuestMemory(vm_memory::GuestMemoryError),
+    /// Failed to exchange a message with the server during the handshake.
+    Handshake(io::Error),
+    /// Exactly one of the Unix socket and the TCP address of the server must be specified.
+    InvalidAddress,
+    /// The server sent an unexpected message.
+    InvalidReply,
+    /// The requests in flight didn't complete in time while draining the engine.
+    DrainTimeout,
+    /// The export doesn't support being accessed through several connections, which multiple
+    /// queues require.
+    MultiConnUnsupported,
+    /// The export is read-only, while the drive is not.
+    ReadOnlyExport,
+    /// The server failed the request, with the given errno value.
+    Request(u32),
+    /// Failed to set up the reconnection timer.
+    Timer(io::Error),
+    /// The request can't be sent to the server as is, such as a vectored request.
+    UnsupportedRequest,
+    /// The server doesn't implement the fixed newstyle handshake.
+    UnsupportedServer,
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Location of the NBD export backing a drive, and how to recover from losing the connection to
+/// its server.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdConfig {
+    /// Path of the Unix socket the server listens on. Either this or `address` must be specified.
+    pub socket_path: Option<String>,
+    /// The `host:port` TCP address the server listens on.
+    pub address: Option<String>,
+    /// Name of the export. Defaults to the default export of the server.
+    #[serde(default)]
+    pub export_name: String,
+    /// How many times reconnecting is attempted once the connection to the server is lost,
+    /// before failing the requests. Defaults to 0, which fails them right away.
+    #[serde(default)]
+    pub reconnect_attempts: u32,
+    /// The delay before the first reconnection attempt, in milliseconds, which doubles after
+    /// every failed attempt, up to 30 seconds. Defaults to 1000.
+    pub reconnect_delay_ms: Option<u64>,
+}
+
+impl NbdConfig {
+    /// The URI of the export, following the NBD URI format.
+    pub fn uri(&self) -> String {
+        match (&self.socket_path, &self.address) {
+            (Some(socket_path), _) => {
+                format!("nbd+unix:///{}?socket={}", self.export_name, socket_path)
+            }
+            (None, Some(address)) => format!("nbd://{}/{}", address, self.export_name),
+            (None, None) => String::new(),
+        }
+    }
+
+    // The delay before the reconnection attempt following `failures` failed ones.
+    fn reconnect_delay(&self, failures: u32) -> Duration {
+        // A zero duration would disarm the timer.
+        let delay = Duration::from_millis(cmp::max(
+            self.reconnect_delay_ms
+                .unwrap_or(DEFAULT_RECONNECT_DELAY_MS),
+            1,
+        ));
+        cmp::min(
+            delay.saturating_mul(1 << cmp::min(failures, 16)),
+            cmp::max(delay, MAX_RECONNECT_DELAY),
+        )
+    }
+}
+
+// Where the server listens. The TCP address is resolved once, when the drive is configured, so
+// that reconnecting doesn't block on name resolution.
+#[derive(Clone, Debug)]
+enum ServerAddr {
+    Unix(String),
+    Tcp(SocketAddr),
+}
+
+impl ServerAddr {
+    fn resolve(config: &NbdConfig) -> Result<Self> {
+        match (&config.socket_path, &config.address) {
+            (Some(socket_path), None) => Ok(ServerAddr::Unix(socket_path.clone())),
+            (None, Some(address)) => address
+                .to_socket_addrs()
+                .map_err(Error::Connect)?
+                .next()
+                .map(ServerAddr::Tcp)
+                .ok_or(Error::InvalidAddress),
+            _ => Err(Error::InvalidAddress),
+        }
+    }
+}
+
+// Starts connecting to `addr` without blocking.
+fn connect_tcp(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: Safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            family,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: We just checked that the fd is valid.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let ret = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: `sockaddr_in` only contains integers, for which all 0 is a valid value.
+            let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            let addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            // SAFETY: Safe because the address outlives the call, and we check the return value.
+            unsafe { libc::connect(fd, (&sockaddr as *const libc::sockaddr_in).cast(), addr_len) }
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: `sockaddr_in6` only contains integers, for which all 0 is a valid value.
+            let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = addr.port().to_be();
+            sockaddr.sin6_flowinfo = addr.flowinfo();
+            sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+            sockaddr.sin6_scope_id = addr.scope_id();
+            let addr_len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            // SAFETY: Safe because the address outlives the call, and we check the return value.
+            unsafe {
+                libc::connect(
+                    fd,
+                    (&sockaddr as *const libc::sockaddr_in6).cast(),
+                    addr_len,
+                )
+            }
+        }
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    // Starts connecting to the server, returning a nonblocking stream.
+    fn connect(addr: &ServerAddr) -> Result<Self> {
+        match addr {
+            // Unix sockets are connected right away, as long as the server keeps up with
+            // accepting the connections.
+            ServerAddr::Unix(socket_path) => {
+                let stream = UnixStream::connect(socket_path).map_err(Error::Connect)?;
+                stream.set_nonblocking(true).map_err(Error::Connect)?;
+                Ok(Stream::Unix(stream))
+            }
+            ServerAddr::Tcp(addr) => {
+                let stream = connect_tcp(addr).map_err(Error::Connect)?;
+                // The requests are small and latency sensitive.
+                stream.set_nodelay(true).map_err(Error::Connect)?;
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Size and transmission flags of an export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Export {
+    size: u64,
+    flags: u16,
+}
+
+fn read_u16(stream: &mut impl Read) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    stream.read_exact(&mut bytes).map_err(Error::Handshake)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32(stream: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    stream.read_exact(&mut bytes).map_err(Error::Handshake)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes).map_err(Error::Handshake)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn send_option(stream: &mut impl Write, option: u32, data: &[u8]) -> Result<()> {
+    let mut msg = Vec::with_capacity(16 + data.len());
+    msg.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    msg.extend_from_slice(&option.to_be_bytes());
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    stream.write_all(&msg).map_err(Error::Handshake)
+}
+
+// Selects the export with `NBD_OPT_GO`. Returns `None` if the server doesn't implement the
+// option, in which case the connection can still be used for `NBD_OPT_EXPORT_NAME`.
+fn go(stream: &mut (impl Read + Write), export_name: &str) -> Result<Option<Export>> {
+    let mut data = Vec::with_capacity(6 + export_name.len());
+    data.extend_from_slice(&(export_name.len() as u32).to_be_bytes());
+    data.extend_from_slice(export_name.as_bytes());
+    // No information other than the mandatory `NBD_INFO_EXPORT` is requested.
+    data.extend_from_slice(&0u16.to_be_bytes());
+    send_option(stream, NBD_OPT_GO, &data)?;
+
+    let mut export = None;
+    loop {
+        if read_u64(stream)? != NBD_OPT_REPLY_MAGIC || read_u32(stream)? != NBD_OPT_GO {
+            return Err(Error::InvalidReply);
+        }
+        let reply = read_u32(stream)?;
+        let mut data = vec![0u8; read_u32(stream)? as usize];
+        stream.read_exact(&mut data).map_err(Error::Handshake)?;
+
+        match reply {
+            NBD_REP_ACK => return export.ok_or(Error::InvalidReply).map(Some),
+            NBD_REP_INFO => {
+                if data.len() >= 12 && u16::from_be_bytes([data[0], data[1]]) == NBD_INFO_EXPORT {
+                    export = Some(Export {
+                        size: u64::from_be_bytes(data[2..10].try_into().unwrap()),
+                        flags: u16::from_be_bytes([data[10], data[11]]),
+                    });
+                }
+            }
+            NBD_REP_ERR_UNSUP => return Ok(None),
+            reply if reply & NBD_REP_FLAG_ERROR != 0 => return Err(Error::ExportRejected(reply)),
+            // Other replies are informational.
+            _ => {}
+        }
+    }
+}
+
+// Selects the export with `NBD_OPT_EXPORT_NAME`, which ends the handshake on success and closes
+// the connection on failure.
+fn export_name(
+    stream: &mut (impl Read + Write),
+    export_name: &str,
+    no_zeroes: bool,
+) -> Result<Export> {
+    send_option(stream, NBD_OPT_EXPORT_NAME, export_name.as_bytes())?;
+
+    let mut reply = vec![0u8; EXPORT_NAME_REPLY_SIZE];
+    if !no_zeroes {
+        reply.resize(EXPORT_NAME_REPLY_SIZE + EXPORT_NAME_REPLY_ZEROES, 0);
+    }
+    stream
+        .read_exact(&mut reply)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::ExportRejected(NBD_REP_FLAG_ERROR),
+            _ => Error::Handshake(err),
+        })?;
+    Ok(Export {
+        size: u64::from_be_bytes(reply[0..8].try_into().unwrap()),
+        flags: u16::from_be_bytes([reply[8], reply[9]]),
+    })
+}
+
+fn handshake(stream: &mut (impl Read + Write), name: &str) -> Result<Export> {
+    if read_u64(stream)? != NBD_MAGIC || read_u64(stream)? != NBD_IHAVEOPT {
+        return Err(Error::UnsupportedServer);
+    }
+    let server_flags = read_u16(stream)?;
+    if server_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::UnsupportedServer);
+    }
+    let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream
+        .write_all(&client_flags.to_be_bytes())
+        .map_err(Error::Handshake)?;
+
+    match go(stream, name)? {
+        Some(export) => Ok(export),
+        None => export_name(stream, name, no_zeroes),
+    }
+}
+
+// Runs the handshake over the bytes received so far, collecting the messages of the client
+// rather than sending them. Reading past the received bytes fails with `WouldBlock`, unless the
+// server closed the connection.
+struct Replay<'a> {
+    received: &'a [u8],
+    closed: bool,
+    sent: Vec<u8>,
+}
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() && !self.closed {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        self.received.read(buf)
+    }
+}
+
+impl Write for Replay<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A connection whose handshake is in progress.
+//
+// Whenever bytes are received, the handshake is run again from the start over all the bytes
+// received so far, until it completes. The messages of the client only depend on these bytes, so
+// every run sends the same ones, and only those which were not sent yet are.
+struct Handshake {
+    stream: Stream,
+    received: Vec<u8>,
+    // Whether the server closed the connection.
+    closed: bool,
+    // Number of bytes of the messages of the client which were sent.
+    sent: usize,
+}
+
+impl Handshake {
+    fn new(stream: Stream) -> Self {
+        Handshake {
+            stream,
+            received: Vec::new(),
+            closed: false,
+            sent: 0,
+        }
+    }
+
+    // Makes progress without blocking. Returns the export once the handshake completes.
+    fn advance(&mut self, export_name: &str) -> Result<Option<Export>> {
+        let mut buf = [0u8; 4096];
+        while !self.closed {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.closed = true,
+                Ok(count) if self.received.len() + count > MAX_HANDSHAKE_SIZE => {
+                    return Err(Error::InvalidReply)
+                }
+                Ok(count) => self.received.extend_from_slice(&buf[..count]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // Also reports the failure to connect.
+                Err(err) => return Err(Error::Connect(err)),
+            }
+        }
+
+        let mut replay = Replay {
+            received: &self.received,
+            closed: self.closed,
+            sent: Vec::new(),
+        };
+        let result = handshake(&mut replay, export_name);
+        while self.sent < replay.sent.len() {
+            match self.stream.write(&replay.sent[self.sent..]) {
+                Ok(count) => self.sent += count,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::Handshake(err)),
+            }
+        }
+
+        match result {
+            Ok(export) => Ok(Some(export)),
+            Err(Error::Handshake(err)) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Connects to the server and selects the export, waiting for the handshake to complete.
+fn connect(addr: &ServerAddr, export_name: &str) -> Result<(Stream, Export)> {
+    let mut handshake = Handshake::new(Stream::connect(addr)?);
+    let epoll = Epoll::new().map_err(Error::Epoll)?;
+    epoll
+        .ctl(
+            ControlOperation::Add,
+            handshake.stream.as_raw_fd(),
+            EpollEvent::new(SOCKET_EVENTS, SOCKET_TOKEN),
+        )
+        .map_err(Error::Epoll)?;
+
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut events = [EpollEvent::default(); 1];
+    loop {
+        if let Some(export) = handshake.advance(export_name)? {
+            return Ok((handshake.stream, export));
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(Error::Handshake(io::Error::from(io::ErrorKind::TimedOut)));
+        }
+        match epoll.wait(timeout.as_millis() as i32, &mut events) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(Error::Epoll(err)),
+        }
+    }
+}
+
+// A request which was not completed by the server yet.
+struct InFlight<T> {
+    command: u16,
+    flags: u16,
+    offset: u64,
+    len: u32,
+    // The data of a write, or of a completed read.
+    data: Option<Vec<u8>>,
+    // Where the data of a read goes in the guest memory.
+    addr: Option<GuestAddress>,
+    // Requests without user data are issued by the engine itself.
+    user_data: Option<T>,
+}
+
+impl<T> InFlight<T> {
+    fn new(command: u16, offset: u64, len: u32, user_data: Option<T>) -> Self {
+        InFlight {
+            command,
+            flags: 0,
+            offset,
+            len,
+            data: None,
+            addr: None,
+            user_data,
+        }
+    }
+
+    fn append_to(&self, handle: u64, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.command.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+        if self.command == NBD_CMD_WRITE {
+            if let Some(data) = self.data.as_ref() {
+                buf.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// Sends the requests of a block device queue to an NBD server over a dedicated connection.
+///
+/// The engine is driven by the events of an internal epoll, which reports the readiness of the
+/// connection and the expiry of the reconnection timer. The requests which are in flight when
+/// the connection is lost are sent again once reconnected, and fail if reconnecting fails.
+pub struct NbdEngine<T> {
+    config: NbdConfig,
+    addr: ServerAddr,
+    export: Export,
+    // The connection to the server, if connected.
+    stream: Option<Stream>,
+    // The connection to the server, while reconnecting.
+    handshake: Option<Handshake>,
+    epoll: Epoll,
+    // Expires when the next reconnection attempt is due, or when the handshake in progress times
+    // out.
+    reconnect_timer: TimerFd,
+    // Signals the requests which fail once the connection is lost, outside of the processing of
+    // the events.
+    completion_evt: EventFd,
+    // Number of reconnection attempts since the connection was lost.
+    reconnect_attempts: u32,
+    // Set once reconnecting failed, after which all the requests fail.
+    failed: bool,
+    next_handle: u64,
+    in_flight: BTreeMap<u64, InFlight<T>>,
+    // The requests which were not entirely sent yet, starting at `sent`.
+    send_buf: Vec<u8>,
+    sent: usize,
+    // The replies which were not entirely received yet.
+    recv_buf: Vec<u8>,
+    completed: VecDeque<(InFlight<T>, Result<u32>)>,
+}
+
+impl<T> NbdEngine<T> {
+    /// Connects to the export. If the export is accessed through other connections as well,
+    /// `multi_conn` must be set, to check that the server keeps them consistent.
+    pub fn connect(config: &NbdConfig, read_only: bool, multi_conn: bool) -> Result<Self> {
+        let addr = ServerAddr::resolve(config)?;
+        let (stream, export) = connect(&addr, &config.export_name)?;
+        if !read_only && export.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(Error::ReadOnlyExport);
+        }
+        if multi_conn && export.flags & NBD_FLAG_CAN_MULTI_CONN == 0 {
+            return Err(Error::MultiConnUnsupported);
+        }
+
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        let reconnect_timer =
+            TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                reconnect_timer.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, TIMER_TOKEN),
+            )
+            .map_err(Error::Epoll)?;
+        let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                completion_evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, COMPLETION_TOKEN),
+            )
+            .map_err(Error::Epoll)?;
+
+        let mut engine = NbdEngine {
+            config: config.clone(),
+            addr,
+            export,
+            stream: None,
+            handshake: None,
+            epoll,
+            reconnect_timer,
+            completion_evt,
+            reconnect_attempts: 0,
+            failed: false,
+            next_handle: 0,
+            in_flight: BTreeMap::new(),
+            send_buf: Vec::new(),
+            sent: 0,
+            recv_buf: Vec::new(),
+            completed: VecDeque::new(),
+        };
+        engine.register(&stream)?;
+        engine.stream = Some(stream);
+        Ok(engine)
+    }
+
+    fn register(&self, stream: &Stream) -> Result<()> {
+        self.epoll
+            .ctl(
+                ControlOperation::Add,
+                stream.as_raw_fd(),
+                EpollEvent::new(SOCKET_EVENTS, SOCKET_TOKEN),
+            )
+            .map_err(Error::Epoll)
+    }
+
+    fn unregister(&self, stream: &Stream) {
+        if let Err(err) = self.epoll.ctl(
+            ControlOperation::Delete,
+            stream.as_raw_fd(),
+            EpollEvent::default(),
+        ) {
+            error!("Failed to unregister the NBD connection: {}", err);
+        }
+    }
+
+    /// The size of the export, in bytes.
+    pub fn size(&self) -> u64 {
+        self.export.size
+    }
+
+    fn has_flag(&self, flag: u16) -> bool {
+        self.export.flags & NBD_FLAG_HAS_FLAGS != 0 && self.export.flags & flag != 0
+    }
+
+    /// Whether the server accepts flush requests. Without them, the data is flushed at the
+    /// discretion of the server.
+    pub fn can_flush(&self) -> bool {
+        self.has_flag(NBD_FLAG_SEND_FLUSH)
+    }
+
+    /// Whether the server accepts both discard and write zeroes requests.
+    pub fn can_discard(&self) -> bool {
+        self.has_flag(NBD_FLAG_SEND_TRIM) && self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES)
+    }
+
+    fn push(
+        &mut self,
+        request: InFlight<T>,
+    ) -> result::Result<(), UserDataError<Option<T>, Error>> {
+        if self.failed {
+            return Err(UserDataError {
+                user_data: request.user_data,
+                error: Error::Disconnected,
+            });
+        }
+
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        // While reconnecting, the request is only sent once connected again.
+        if self.stream.is_some() {
+            request.append_to(handle, &mut self.send_buf);
+        }
+        self.in_flight.insert(handle, request);
+        Ok(())
+    }
+
+    fn push_user_request(
+        &mut self,
+        request: InFlight<T>,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        self.push(request).map_err(|err| UserDataError {
+            // The requests of the user always hold user data, which is given back.
+            user_data: err.user_data.unwrap(),
+            error: err.error,
+        })
+    }
+
+    pub fn push_read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        // The data is only copied to the guest memory once received, so check the range first.
+        if let Err(err) = mem.get_slice(addr, count as usize) {
+            return Err(UserDataError {
+                user_data,
+                error: Error::GuestMemory(err),
+            });
+        }
+        let mut request = InFlight::new(NBD_CMD_READ, offset, count, Some(user_data));
+        request.addr = Some(addr);
+        self.push_user_request(request)
+    }
+
+    pub fn push_write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        self.push_write_with(offset, mem, addr, count, user_data, |_| {})
+    }
+
+    /// Writes the data found in guest memory, once transformed by `transform`, such as when it
+    /// is encrypted.
+    pub fn push_write_with(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+        transform: impl FnOnce(&mut [u8]),
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        // The data is kept until the request completes, to send it again after reconnecting. The
+        // range is checked first, so that the buffer never exceeds the guest memory.
+        let mut buf = Vec::new();
+        if let Err(err) = mem.get_slice(addr, count as usize).and_then(|_| {
+            buf.resize(count as usize, 0);
+            mem.read_slice(&mut buf, addr)
+        }) {
+            return Err(UserDataError {
+                user_data,
+                error: Error::GuestMemory(err),
+            });
+        }
+        transform(&mut buf);
+        self.push_write_buffer(offset, buf, user_data)
+    }
+
+    pub fn push_write_buffer(
+        &mut self,
+        offset: u64,
+        buf: Vec<u8>,
+        user_data: T,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        let mut request = InFlight::new(NBD_CMD_WRITE, offset, buf.len() as u32, Some(user_data));
+        request.data = Some(buf);
+        self.push_user_request(request)
+    }
+
+    pub fn push_trim(
+        &mut self,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        // The length of discard requests always fits in a u32.
+        self.push_user_request(InFlight::new(
+            NBD_CMD_TRIM,
+            offset,
+            len as u32,
+            Some(user_data),
+        ))
+    }
+
+    pub fn push_write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u64,
+        unmap: bool,
+        user_data: T,
+    ) -> result::Result<(), UserDataError<T, Error>> {
+        let mut request = InFlight::new(NBD_CMD_WRITE_ZEROES, offset, len as u32, Some(user_data));
+        if !unmap {
+            request.flags = NBD_CMD_FLAG_NO_HOLE;
+        }
+        self.push_user_request(request)
+    }
+
+    pub fn push_flush(&mut self, user_data: T) -> result::Result<(), UserDataError<T, Error>> {
+        self.push_user_request(InFlight::new(NBD_CMD_FLUSH, 0, 0, Some(user_data)))
+    }
+
+    /// Sends the pushed requests, as far as the connection accepts them without blocking. The
+    /// rest is sent once the connection is writable again.
+    pub fn kick(&mut self) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+        while self.sent < self.send_buf.len() {
+            match stream.write(&self.send_buf[self.sent..]) {
+                Ok(0) => return self.disconnect(),
+                Ok(count) => self.sent += count,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error!("Failed to send the requests to the NBD server: {}", err);
+                    return self.disconnect();
+                }
+            }
+        }
+        self.send_buf.clear();
+        self.sent = 0;
+    }
+
+    /// Processes the pending events of the engine, without blocking.
+    pub fn process_events(&mut self) -> Result<()> {
+        self.wait_and_process_events(0)
+    }
+
+    fn wait_and_process_events(&mut self, timeout: i32) -> Result<()> {
+        let mut events = [EpollEvent::default(); 3];
+        let count = match self.epoll.wait(timeout, &mut events) {
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => 0,
+            Err(err) => return Err(Error::Epoll(err)),
+        };
+
+        for event in &events[..count] {
+            match event.data() {
+                SOCKET_TOKEN if self.handshake.is_some() => self.advance_handshake(),
+                SOCKET_TOKEN => {
+                    // Hang ups and errors are detected when reading.
+                    if !(event.event_set() - EventSet::OUT).is_empty() {
+                        self.receive();
+                    }
+                    if event.event_set().contains(EventSet::OUT) {
+                        self.kick();
+                    }
+                }
+                TIMER_TOKEN => {
+                    self.reconnect_timer.read();
+                    if self.handshake.is_some() {
+                        self.abort_handshake();
+                        self.reconnect_failed(Error::Handshake(io::Error::from(
+                            io::ErrorKind::TimedOut,
+                        )));
+                    } else {
+                        self.reconnect();
+                    }
+                }
+                COMPLETION_TOKEN => {
+                    if let Err(err) = self.completion_evt.read() {
+                        error!("Failed to read the NBD completion event: {}", err);
+                    }
+                }
+                _ => warn!("Unexpected NBD engine event: {:?}", event),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return self.disconnect(),
+                Ok(count) => self.recv_buf.extend_from_slice(&buf[..count]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error!("Failed to receive the replies of the NBD server: {}", err);
+                    return self.disconnect();
+                }
+            }
+        }
+
+        let mut consumed = 0;
+        while self.recv_buf.len() - consumed >= NBD_SIMPLE_REPLY_SIZE {
+            let reply = &self.recv_buf[consumed..];
+            let magic = u32::from_be_bytes(reply[0..4].try_into().unwrap());
+            let errno = u32::from_be_bytes(reply[4..8].try_into().unwrap());
+            let handle = u64::from_be_bytes(reply[8..16].try_into().unwrap());
+            let request = match self.in_flight.get(&handle) {
+                Some(request) if magic == NBD_SIMPLE_REPLY_MAGIC => request,
+                _ => {
+                    error!("Received an invalid reply from the NBD server");
+                    return self.disconnect();
+                }
+            };
+            // The data of a read follows its reply, unless it failed.
+            let data_len = match (request.command, errno) {
+                (NBD_CMD_READ, 0) => request.len as usize,
+                _ => 0,
+            };
+            if reply.len() < NBD_SIMPLE_REPLY_SIZE + data_len {
+                break;
+            }
+
+            // The request was found above.
+            let mut request = self.in_flight.remove(&handle).unwrap();
+            let result = match errno {
+                0 => {
+                    if data_len > 0 {
+                        request.data = Some(
+                            reply[NBD_SIMPLE_REPLY_SIZE..NBD_SIMPLE_REPLY_SIZE + data_len].to_vec(),
+                        );
+                    }
+                    match request.command {
+                        NBD_CMD_READ | NBD_CMD_WRITE => Ok(request.len),
+                        _ => Ok(0),
+                    }
+                }
+                errno => Err(Error::Request(errno)),
+            };
+            self.complete(request, result);
+            consumed += NBD_SIMPLE_REPLY_SIZE + data_len;
+        }
+        self.recv_buf.drain(..consumed);
+    }
+
+    fn complete(&mut self, request: InFlight<T>, result: Result<u32>) {
+        if request.user_data.is_some() {
+            self.completed.push_back((request, result));
+        } else if let Err(err) = result {
+            error!("Failed to flush the NBD export: {:?}", err);
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.unregister(&stream);
+        }
+        METRICS.block.nbd_disconnects.inc();
+        warn!(
+            "Lost the connection to the NBD server {}",
+            self.config.uri()
+        );
+
+        // The requests are entirely sent again once reconnected.
+        self.send_buf.clear();
+        self.sent = 0;
+        self.recv_buf.clear();
+        self.reconnect_attempts = 0;
+        self.schedule_reconnect();
+    }
+
+    fn schedule_reconnect(&mut self) {
+        if self.reconnect_attempts < self.config.reconnect_attempts {
+            self.reconnect_timer.set_state(
+                TimerState::Oneshot(self.config.reconnect_delay(self.reconnect_attempts)),
+                SetTimeFlags::Default,
+            );
+            return;
+        }
+
+        error!(
+            "Giving up on the NBD server {}, the requests will fail",
+            self.config.uri()
+        );
+        self.failed = true;
+        let in_flight = mem::take(&mut self.in_flight);
+        for (_, request) in in_flight {
+            self.complete(request, Err(Error::Disconnected));
+        }
+        if let Err(err) = self.completion_evt.write(1) {
+            error!("Failed to signal the failed NBD requests: {}", err);
+        }
+    }
+
+    // Starts a reconnection attempt, which completes as the connection becomes ready.
+    fn reconnect(&mut self) {
+        if self.stream.is_some() || self.handshake.is_some() || self.failed {
+            return;
+        }
+
+        self.reconnect_attempts += 1;
+        let stream = match Stream::connect(&self.addr) {
+            Ok(stream) => stream,
+            Err(err) => return self.reconnect_failed(err),
+        };
+        if let Err(err) = self.register(&stream) {
+            error!("Failed to register the NBD connection: {:?}", err);
+            return self.schedule_reconnect();
+        }
+        self.handshake = Some(Handshake::new(stream));
+        self.reconnect_timer.set_state(
+            TimerState::Oneshot(HANDSHAKE_TIMEOUT),
+            SetTimeFlags::Default,
+        );
+        self.advance_handshake();
+    }
+
+    fn advance_handshake(&mut self) {
+        let handshake = match self.handshake.as_mut() {
+            Some(handshake) => handshake,
+            None => return,
+        };
+        let export = match handshake.advance(&self.config.export_name) {
+            Ok(Some(export)) => export,
+            Ok(None) => return,
+            Err(err) => {
+                self.abort_handshake();
+                return self.reconnect_failed(err);
+            }
+        };
+        // The handshake was found above.
+        let stream = self.handshake.take().unwrap().stream;
+        self.reconnect_timer
+            .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+
+        // Resuming the requests on a different export would corrupt it.
+        if export != self.export {
+            self.unregister(&stream);
+            error!(
+                "The NBD export {} changed while reconnecting: {:?}",
+                self.config.uri(),
+                Error::ExportChanged
+            );
+            self.reconnect_attempts = self.config.reconnect_attempts;
+            return self.schedule_reconnect();
+        }
+        self.stream = Some(stream);
+
+        METRICS.block.nbd_reconnects.inc();
+        info!("Reconnected to the NBD server {}", self.config.uri());
+        for (handle, request) in self.in_flight.iter() {
+            request.append_to(*handle, &mut self.send_buf);
+        }
+        self.kick();
+    }
+
+    fn abort_handshake(&mut self) {
+        if let Some(handshake) = self.handshake.take() {
+            self.unregister(&handshake.stream);
+        }
+    }
+
+    fn reconnect_failed(&mut self, err: Error) {
+        warn!(
+            "Failed to reconnect to the NBD server {} (attempt {}/{}): {:?}",
+            self.config.uri(),
+            self.reconnect_attempts,
+            self.config.reconnect_attempts,
+            err
+        );
+        self.schedule_reconnect();
+    }
+
+    /// Returns the next completed request, after copying the data of a read to the guest memory.
+    pub fn pop(
+        &mut self,
+        mem: &GuestMemoryMmap,
+    ) -> Option<result::Result<UserDataOk<T>, UserDataError<T, Error>>> {
+        let (request, result) = self.completed.pop_front()?;
+        // Only the requests with user data are completed.
+        let user_data = request.user_data.unwrap();
+        let result = match (result, request.addr, request.data) {
+            (Ok(count), Some(addr), Some(data)) => mem
+                .write_slice(&data, addr)
+                .map(|_| count)
+                .map_err(Error::GuestMemory),
+            (result, _, _) => result,
+        };
+
+        Some(match result {
+            Ok(count) => Ok(UserDataOk { user_data, count }),
+            Err(error) => Err(UserDataError { user_data, error }),
+        })
+    }
+
+    /// Waits for all the requests in flight to complete. The completed requests are dropped if
+    /// `discard` is set. The requests which don't complete within `DRAIN_TIMEOUT` fail.
+    pub fn drain(&mut self, discard: bool) -> Result<()> {
+        self.drain_with_timeout(discard, DRAIN_TIMEOUT)
+    }
+
+    fn drain_with_timeout(&mut self, discard: bool, timeout: Duration) -> Result<()> {
+        self.kick();
+        let deadline = Instant::now() + timeout;
+        let mut res = Ok(());
+        while !self.in_flight.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.fail_in_flight();
+                res = Err(Error::DrainTimeout);
+                break;
+            }
+            self.wait_and_process_events(remaining.as_millis() as i32)?;
+        }
+        if discard {
+            self.completed.clear();
+        }
+        res
+    }
+
+    // Fails the requests in flight, which the server or the reconnection attempts are too slow
+    // to complete.
+    fn fail_in_flight(&mut self) {
+        error!(
+            "The requests to the NBD server {} didn't complete in time, they will fail",
+            self.config.uri()
+        );
+        let in_flight = mem::take(&mut self.in_flight);
+        for (_, request) in in_flight {
+            self.complete(request, Err(Error::DrainTimeout));
+        }
+        if let Err(err) = self.completion_evt.write(1) {
+            error!("Failed to signal the failed NBD requests: {}", err);
+        }
+        // The server would reply to requests the engine no longer knows about.
+        if self.stream.is_some() {
+            self.disconnect();
+        }
+    }
+
+    /// Waits for all the requests in flight to complete, then flushes the export.
+    pub fn drain_and_flush(&mut self, discard: bool) -> Result<()> {
+        if self.can_flush() {
+            // The flush is completed by the server after the requests in flight.
+            self.push(InFlight::new(NBD_CMD_FLUSH, 0, 0, None))
+                .map_err(|err| err.error)?;
+        }
+        self.drain(discard)
+    }
+}
+
+impl<T> AsRawFd for NbdEngine<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl<T> Drop for NbdEngine<T> {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            // Let the server know that the connection closes on purpose. The requests which are
+            // not entirely sent are dropped anyway.
+            if self.sent == 0 {
+                let request = InFlight::<T>::new(NBD_CMD_DISC, 0, 0, None);
+                let mut buf = Vec::with_capacity(NBD_REQUEST_SIZE);
+                request.append_to(self.next_handle, &mut buf);
+                let _ = stream.write(&buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::test_utils::{TestServer, TestServerConfig};
+    use super::*;
+
+    const EXPORT_SIZE: usize = 0x10000;
+
+    fn create_mem() -> GuestMemoryMmap {
+        create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap()
+    }
+
+    fn wait_for_completion(engine: &mut NbdEngine<u32>, mem: &GuestMemoryMmap) -> (u32, u32) {
+        engine.drain(false).unwrap();
+        match engine.pop(mem).unwrap() {
+            Ok(ok) => (ok.user_data, ok.count),
+            Err(err) => panic!("Request {} failed: {:?}", err.user_data, err.error),
+        }
+    }
+
+    #[test]
+    fn test_uri() {
+        let mut config = NbdConfig {
+            socket_path: Some("/tmp/nbd.sock".to_string()),
+            export_name: "disk".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.uri(), "nbd+unix:///disk?socket=/tmp/nbd.sock");
+        config.socket_path = None;
+        config.address = Some("10.0.0.1:10809".to_string());
+        assert_eq!(config.uri(), "nbd://10.0.0.1:10809/disk");
+
+        assert_eq!(config.reconnect_delay(0), Duration::from_millis(1000));
+        assert_eq!(config.reconnect_delay(2), Duration::from_millis(4000));
+        assert_eq!(config.reconnect_delay(5), MAX_RECONNECT_DELAY);
+        assert_eq!(config.reconnect_delay(u32::MAX), MAX_RECONNECT_DELAY);
+        config.reconnect_delay_ms = Some(0);
+        assert_eq!(config.reconnect_delay(0), Duration::from_millis(1));
+        assert_eq!(config.reconnect_delay(1), Duration::from_millis(2));
+        config.reconnect_delay_ms = Some(60_000);
+        assert_eq!(config.reconnect_delay(3), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(matches!(
+            NbdEngine::<()>::connect(&NbdConfig::default(), false, false),
+            Err(Error::InvalidAddress)
+        ));
+        let config = NbdConfig {
+            socket_path: Some("/nonexistent/nbd.sock".to_string()),
+            address: Some("127.0.0.1:10809".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            NbdEngine::<()>::connect(&config, false, false),
+            Err(Error::InvalidAddress)
+        ));
+        let config = NbdConfig {
+            socket_path: Some("/nonexistent/nbd.sock".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            NbdEngine::<()>::connect(&config, false, false),
+            Err(Error::Connect(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake() {
+        // Servers which don't implement `NBD_OPT_GO` are supported.
+        for opt_go_unsupported in [false, true] {
+            let server = TestServer::new(TestServerConfig {
+                size: EXPORT_SIZE,
+                opt_go_unsupported,
+                ..Default::default()
+            });
+            let engine = NbdEngine::<()>::connect(&server.config(), false, false).unwrap();
+            assert_eq!(engine.size(), EXPORT_SIZE as u64);
+            assert!(engine.can_flush());
+            assert!(engine.can_discard());
+        }
+
+        // The export name is checked by the server.
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut config = server.config();
+        config.export_name = "unknown".to_string();
+        assert!(matches!(
+            NbdEngine::<()>::connect(&config, false, false),
+            Err(Error::ExportRejected(_))
+        ));
+
+        // Read-only exports can only back read-only drives.
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            flags: Some(NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY),
+            ..Default::default()
+        });
+        assert!(matches!(
+            NbdEngine::<()>::connect(&server.config(), false, false),
+            Err(Error::ReadOnlyExport)
+        ));
+        let engine = NbdEngine::<()>::connect(&server.config(), true, false).unwrap();
+        assert!(!engine.can_flush());
+        assert!(!engine.can_discard());
+
+        // Multiple connections require the export to support them.
+        assert!(matches!(
+            NbdEngine::<()>::connect(&server.config(), true, true),
+            Err(Error::MultiConnUnsupported)
+        ));
+    }
+
+    #[test]
+    fn test_requests() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut engine = NbdEngine::<u32>::connect(&server.config(), false, false).unwrap();
+        let mem = create_mem();
+
+        // Write, then read back.
+        let data: Vec<u8> = (0..=255).cycle().take(0x3000).collect();
+        mem.write_slice(&data, GuestAddress(0x1000)).unwrap();
+        engine
+            .push_write(0x200, &mem, GuestAddress(0x1000), 0x3000, 1)
+            .unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (1, 0x3000));
+        assert_eq!(server.contents()[0x200..0x3200], data[..]);
+
+        engine
+            .push_read(0x200, &mem, GuestAddress(0x8000), 0x3000, 2)
+            .unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (2, 0x3000));
+        let mut buf = vec![0u8; 0x3000];
+        mem.read_slice(&mut buf, GuestAddress(0x8000)).unwrap();
+        assert_eq!(buf, data);
+
+        // Write a host buffer.
+        engine.push_write_buffer(0, vec![0xab; 0x200], 3).unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (3, 0x200));
+        assert!(server.contents()[..0x200].iter().all(|&b| b == 0xab));
+
+        // Discard and write zeroes.
+        engine.push_trim(0x200, 0x1000, 4).unwrap();
+        engine.push_write_zeroes(0x1200, 0x200, false, 5).unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (4, 0));
+        assert_eq!(engine.pop(&mem).unwrap().unwrap().user_data, 5);
+        assert!(server.contents()[0x200..0x1400].iter().all(|&b| b == 0));
+        assert_eq!(server.contents()[0x1400..0x3200], data[0x1200..]);
+
+        // Flush.
+        engine.push_flush(6).unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (6, 0));
+        assert!(engine.pop(&mem).is_none());
+
+        // The errors of the server are reported.
+        engine
+            .push_read(EXPORT_SIZE as u64, &mem, GuestAddress(0), 0x200, 7)
+            .unwrap();
+        engine.kick();
+        engine.drain(false).unwrap();
+        let err = engine.pop(&mem).unwrap().unwrap_err();
+        assert_eq!(err.user_data, 7);
+        assert!(matches!(err.error, Error::Request(errno) if errno == libc::EINVAL as u32));
+
+        // The guest memory is checked when the request is pushed.
+        let err = engine
+            .push_read(0, &mem, GuestAddress(0x10000), 0x200, 8)
+            .unwrap_err();
+        assert_eq!(err.user_data, 8);
+        assert!(matches!(err.error, Error::GuestMemory(_)));
+        let err = engine
+            .push_write(0, &mem, GuestAddress(0xff00), 0x200, 9)
+            .unwrap_err();
+        assert_eq!(err.user_data, 9);
+        assert!(matches!(err.error, Error::GuestMemory(_)));
+
+        // Many requests in flight at once.
+        let data = vec![0x5a; EXPORT_SIZE];
+        for index in 0..16u32 {
+            engine
+                .push_write_buffer(u64::from(index) * 0x1000, data[..0x1000].to_vec(), index)
+                .unwrap();
+        }
+        engine.kick();
+        engine.drain(false).unwrap();
+        for index in 0..16u32 {
+            assert_eq!(engine.pop(&mem).unwrap().unwrap().user_data, index);
+        }
+        assert_eq!(server.contents(), data);
+
+        // The server is told about the disconnection, which it processes on its own thread.
+        drop(engine);
+        for _ in 0..100 {
+            if server.disconnects() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.disconnects(), 1);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut config = server.config();
+        config.reconnect_attempts = 3;
+        config.reconnect_delay_ms = Some(10);
+        let mut engine = NbdEngine::<u32>::connect(&config, false, false).unwrap();
+        let mem = create_mem();
+
+        // The server drops the connection instead of replying to the next request, which is
+        // sent again once reconnected.
+        server.drop_next_requests(1);
+        engine.push_write_buffer(0, vec![0x11; 0x200], 1).unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (1, 0x200));
+        assert!(server.contents()[..0x200].iter().all(|&b| b == 0x11));
+        assert_eq!(server.connections(), 2);
+
+        // Without a server to reconnect to, the requests fail once the attempts are exhausted.
+        server.drop_next_requests(1);
+        server.stop();
+        engine.push_write_buffer(0, vec![0x22; 0x200], 2).unwrap();
+        engine.kick();
+        engine.drain(false).unwrap();
+        let err = engine.pop(&mem).unwrap().unwrap_err();
+        assert_eq!(err.user_data, 2);
+        assert!(matches!(err.error, Error::Disconnected));
+
+        // The next requests fail right away.
+        let err = engine.push_flush(3).unwrap_err();
+        assert_eq!(err.user_data, 3);
+        assert!(matches!(err.error, Error::Disconnected));
+    }
+
+    #[test]
+    fn test_tcp() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            tcp: true,
+            ..Default::default()
+        });
+        let mut config = server.config();
+        config.reconnect_attempts = 3;
+        config.reconnect_delay_ms = Some(10);
+        let mut engine = NbdEngine::<u32>::connect(&config, false, false).unwrap();
+        let mem = create_mem();
+
+        engine.push_write_buffer(0, vec![0x33; 0x200], 1).unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (1, 0x200));
+        assert!(server.contents()[..0x200].iter().all(|&b| b == 0x33));
+
+        // The handshake of the new connection is driven by the events of the engine.
+        server.drop_next_requests(1);
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 2)
+            .unwrap();
+        engine.kick();
+        assert_eq!(wait_for_completion(&mut engine, &mem), (2, 0x200));
+        let mut data = vec![0u8; 0x200];
+        mem.read_slice(&mut data, GuestAddress(0)).unwrap();
+        assert!(data.iter().all(|&b| b == 0x33));
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut config = server.config();
+        config.reconnect_attempts = 3;
+        config.reconnect_delay_ms = Some(60_000);
+        let mut engine = NbdEngine::<u32>::connect(&config, false, false).unwrap();
+        let mem = create_mem();
+
+        // The request is still waiting for the reconnection when the drain times out.
+        server.drop_next_requests(1);
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 1)
+            .unwrap();
+        assert!(matches!(
+            engine.drain_with_timeout(false, Duration::from_millis(100)),
+            Err(Error::DrainTimeout)
+        ));
+        assert!(matches!(
+            engine.pop(&mem).unwrap(),
+            Err(UserDataError {
+                user_data: 1,
+                error: Error::DrainTimeout
+            })
+        ));
+        assert!(engine.pop(&mem).is_none());
+    }
+
+    #[test]
+    fn test_no_reconnect() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut engine = NbdEngine::<u32>::connect(&server.config(), false, false).unwrap();
+        let mem = create_mem();
+
+        // The requests fail as soon as the connection is lost.
+        server.drop_next_requests(1);
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 1)
+            .unwrap();
+        engine.kick();
+        engine.drain(false).unwrap();
+        assert!(matches!(
+            engine.pop(&mem).unwrap(),
+            Err(UserDataError {
+                user_data: 1,
+                error: Error::Disconnected
+            })
+        ));
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn test_export_changed() {
+        let server = TestServer::new(TestServerConfig {
+            size: EXPORT_SIZE,
+            ..Default::default()
+        });
+        let mut config = server.config();
+        config.reconnect_attempts = 3;
+        config.reconnect_delay_ms = Some(10);
+        let mut engine = NbdEngine::<u32>::connect(&config, false, false).unwrap();
+        let mem = create_mem();
+
+        // Reconnecting to a different export fails the requests without further attempts.
+        server.drop_next_requests(1);
+        server.resize(EXPORT_SIZE / 2);
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 1)
+            .unwrap();
+        engine.kick();
+        engine.drain(false).unwrap();
+        assert!(matches!(
+            engine.pop(&mem).unwrap(),
+            Err(UserDataError {
+                user_data: 1,
+                error: Error::Disconnected
+            })
+        ));
+        assert_eq!(server.connections(), 2);
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use utils::tempdir::TempDir;
+
+use super::*;
+
+// Name of the single export of the test server.
+const TEST_EXPORT_NAME: &str = "test";
+// Option reply for an unknown export.
+const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+
+/// How the test server describes its export.
+#[derive(Clone, Default)]
+pub(crate) struct TestServerConfig {
+    pub size: usize,
+    /// The transmission flags of the export. Defaults to supporting flush, discard and write
+    /// zeroes requests.
+    pub flags: Option<u16>,
+    /// Behave like old servers, which only implement `NBD_OPT_EXPORT_NAME`.
+    pub opt_go_unsupported: bool,
+    /// Listen on a TCP port of the loopback interface, rather than on a Unix socket.
+    pub tcp: bool,
+}
+
+#[derive(Default)]
+struct ServerState {
+    contents: Vec<u8>,
+    // Number of requests the server drops the connection on, instead of replying.
+    drop_next_requests: u32,
+    connections: u32,
+    disconnects: u32,
+}
+
+/// An NBD server exporting an in-memory disk, serving each connection on a separate thread.
+pub(crate) struct TestServer {
+    // Removed on drop, along with the socket.
+    _dir: TempDir,
+    socket_path: PathBuf,
+    // The address listened on, when listening on a TCP port.
+    tcp_addr: Option<SocketAddr>,
+    config: Arc<Mutex<TestServerConfig>>,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl TestServer {
+    pub fn new(config: TestServerConfig) -> Self {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("nbd.sock");
+        let state = Arc::new(Mutex::new(ServerState {
+            contents: vec![0; config.size],
+            ..Default::default()
+        }));
+        let tcp = config.tcp;
+        let config = Arc::new(Mutex::new(config));
+
+        // Bind before returning, so that the engine can connect right away.
+        let tcp_addr = if tcp {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let tcp_addr = listener.local_addr().unwrap();
+            let (config, state) = (config.clone(), state.clone());
+            thread::spawn(move || accept(listener.incoming(), &config, &state));
+            Some(tcp_addr)
+        } else {
+            let listener = UnixListener::bind(&socket_path).unwrap();
+            let (config, state) = (config.clone(), state.clone());
+            thread::spawn(move || accept(listener.incoming(), &config, &state));
+            None
+        };
+
+        TestServer {
+            _dir: dir,
+            socket_path,
+            tcp_addr,
+            config,
+            state,
+        }
+    }
+
+    /// The configuration of a drive backed by the export.
+    pub fn config(&self) -> NbdConfig {
+        let (socket_path, address) = match self.tcp_addr {
+            Some(tcp_addr) => (None, Some(tcp_addr.to_string())),
+            None => (Some(self.socket_path.to_str().unwrap().to_string()), None),
+        };
+        NbdConfig {
+            socket_path,
+            address,
+            export_name: TEST_EXPORT_NAME.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.state.lock().unwrap().contents.clone()
+    }
+
+    /// Closes the connection when receiving the next `count` requests.
+    pub fn drop_next_requests(&self, count: u32) {
+        self.state.lock().unwrap().drop_next_requests = count;
+    }
+
+    /// Changes the size of the export for the next connections.
+    pub fn resize(&self, size: usize) {
+        self.config.lock().unwrap().size = size;
+        self.state.lock().unwrap().contents.resize(size, 0);
+    }
+
+    /// Makes the next connection attempts fail, by removing the socket.
+    pub fn stop(&self) {
+        std::fs::remove_file(&self.socket_path).unwrap();
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connections(&self) -> u32 {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Number of connections closed on purpose by the client.
+    pub fn disconnects(&self) -> u32 {
+        self.state.lock().unwrap().disconnects
+    }
+}
+
+// Serves each accepted connection on a separate thread.
+fn accept<S: Read + Write + Send + 'static>(
+    incoming: impl Iterator<Item = std::io::Result<S>>,
+    config: &Arc<Mutex<TestServerConfig>>,
+    state: &Arc<Mutex<ServerState>>,
+) {
+    for stream in incoming {
+        let stream = stream.unwrap();
+        state.lock().unwrap().connections += 1;
+        let config = config.lock().unwrap().clone();
+        let state = state.clone();
+        thread::spawn(move || serve(stream, &config, &state));
+    }
+}
+
+fn read_u32(stream: &mut impl Read) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    stream.read_exact(&mut bytes).ok()?;
+    Some(u32::from_be_bytes(bytes))
+}
+
+fn read_u64(stream: &mut impl Read) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes).ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn send_option_reply(stream: &mut impl Write, option: u32, reply: u32, data: &[u8]) -> Option<()> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&NBD_OPT_REPLY_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&option.to_be_bytes());
+    msg.extend_from_slice(&reply.to_be_bytes());
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    stream.write_all(&msg).ok()
+}
+
+// Runs the handshake, returning whether the export was selected.
+fn handshake(
+    stream: &mut (impl Read + Write),
+    config: &TestServerConfig,
+    flags: u16,
+) -> Option<()> {
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    msg.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&msg).ok()?;
+    let no_zeroes = read_u32(stream)? & NBD_FLAG_C_NO_ZEROES != 0;
+
+    loop {
+        if read_u64(stream)? != NBD_IHAVEOPT {
+            return None;
+        }
+        let option = read_u32(stream)?;
+        let mut data = vec![0u8; read_u32(stream)? as usize];
+        stream.read_exact(&mut data).ok()?;
+
+        match option {
+            NBD_OPT_GO if !config.opt_go_unsupported => {
+                let name_len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+                if data.get(4..4 + name_len) != Some(TEST_EXPORT_NAME.as_bytes()) {
+                    send_option_reply(stream, option, NBD_REP_ERR_UNKNOWN, &[])?;
+                    continue;
+                }
+                let mut info = Vec::new();
+                info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&(config.size as u64).to_be_bytes());
+                info.extend_from_slice(&flags.to_be_bytes());
+                send_option_reply(stream, option, NBD_REP_INFO, &info)?;
+                send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                return Some(());
+            }
+            NBD_OPT_EXPORT_NAME => {
+                // The server closes the connection when the export doesn't exist.
+                if data != TEST_EXPORT_NAME.as_bytes() {
+                    return None;
+                }
+                let mut reply = Vec::new();
+                reply.extend_from_slice(&(config.size as u64).to_be_bytes());
+                reply.extend_from_slice(&flags.to_be_bytes());
+                if !no_zeroes {
+                    reply.extend_from_slice(&[0u8; EXPORT_NAME_REPLY_ZEROES]);
+                }
+                stream.write_all(&reply).ok()?;
+                return Some(());
+            }
+            _ => send_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn serve(mut stream: impl Read + Write, config: &TestServerConfig, state: &Mutex<ServerState>) {
+    let flags = config.flags.unwrap_or(
+        NBD_FLAG_HAS_FLAGS
+            | NBD_FLAG_SEND_FLUSH
+            | NBD_FLAG_SEND_TRIM
+            | NBD_FLAG_SEND_WRITE_ZEROES
+            | NBD_FLAG_CAN_MULTI_CONN,
+    );
+    if handshake(&mut stream, config, flags).is_none() {
+        return;
+    }
+
+    loop {
+        let mut request = [0u8; NBD_REQUEST_SIZE];
+        if stream.read_exact(&mut request).is_err() {
+            return;
+        }
+        assert_eq!(
+            u32::from_be_bytes(request[0..4].try_into().unwrap()),
+            NBD_REQUEST_MAGIC
+        );
+        let command = u16::from_be_bytes([request[6], request[7]]);
+        let handle = &request[8..16];
+        let offset = u64::from_be_bytes(request[16..24].try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(request[24..28].try_into().unwrap()) as usize;
+        let mut data = vec![0u8; if command == NBD_CMD_WRITE { len } else { 0 }];
+        if stream.read_exact(&mut data).is_err() {
+            return;
+        }
+
+        let mut state = state.lock().unwrap();
+        if state.drop_next_requests > 0 {
+            state.drop_next_requests -= 1;
+            return;
+        }
+        let in_range = offset + len <= state.contents.len();
+        let (errno, reply_data) = match command {
+            NBD_CMD_DISC => {
+                state.disconnects += 1;
+                return;
+            }
+            _ if !in_range => (libc::EINVAL as u32, Vec::new()),
+            NBD_CMD_READ => (0, state.contents[offset..offset + len].to_vec()),
+            NBD_CMD_WRITE => {
+                state.contents[offset..offset + len].copy_from_slice(&data);
+                (0, Vec::new())
+            }
+            NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                state.contents[offset..offset + len].fill(0);
+                (0, Vec::new())
+            }
+            _ => (0, Vec::new()),
+        };
+        drop(state);
+
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        reply.extend_from_slice(&errno.to_be_bytes());
+        reply.extend_from_slice(handle);
+        reply.extend_from_slice(&reply_data);
+        if stream.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
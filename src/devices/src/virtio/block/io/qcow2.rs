@@ -0,0 +1,1316 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for disk images in the qcow2 format.
+//!
+//! Guest offsets are translated into offsets in the image file by walking the two level
+//! L1/L2 cluster tables. Clusters which are not allocated in the image are read from the backing
+//! file (which can be a raw or a qcow2 image itself), or as zeroes if there is none. Writes to
+//! such clusters allocate new clusters at the end of the image file and update the refcounts and
+//! the cluster tables accordingly.
+//!
+//! Requests which map to a single contiguous range of allocated clusters are passed on to the
+//! file engine, so they benefit from the `Async` engine if configured. All the others (i.e. the
+//! ones touching unallocated clusters or the backing file) are executed synchronously, which also
+//! guarantees that the data reaches the image file before the metadata pointing to it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::{cmp, io};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::{FileEngine, FileEngineOk, UserDataError, UserDataOk};
+
+// "QFI\xfb"
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+// The size of a version 2 header. Version 3 headers are at least `V3_HEADER_SIZE` long.
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+// The compression type is the only incompatible feature which doesn't change the image layout.
+// Compressed clusters are rejected on access, regardless of the compression method.
+const INCOMPATIBLE_FEATURE_COMPRESSION_TYPE: u64 = 1 << 3;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Refcount entries which are not byte aligned are not supported.
+const MIN_REFCOUNT_ORDER: u32 = 3;
+const MAX_REFCOUNT_ORDER: u32 = 6;
+const MAX_BACKING_FILE_NAME_SIZE: u32 = 1023;
+// Maximum length of a backing file chain, which also protects against loops.
+const MAX_BACKING_CHAIN_DEPTH: u32 = 16;
+// Maximum number of L2 tables kept in memory.
+const L2_CACHE_SIZE: usize = 64;
+// Maximum size of the L1 and refcount tables, which are kept in memory. QEMU has the same limit.
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The cluster is used by exactly one table (its refcount is 1), so it can be written in place.
+const CLUSTER_COPIED: u64 = 1 << 63;
+const CLUSTER_COMPRESSED: u64 = 1 << 62;
+// Version 3 only: the cluster reads as zeroes.
+const CLUSTER_ZERO: u64 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to open the backing file.
+    BackingFile(io::Error),
+    /// The backing file chain exceeds `MAX_BACKING_CHAIN_DEPTH`.
+    BackingChainTooLong,
+    /// The image contains a compressed cluster.
+    CompressedCluster,
+    /// The image is encrypted.
+    Encrypted,
+    /// The image has an invalid cluster size.
+    InvalidClusterBits(u32),
+    /// The L1 table is too small for the virtual disk size.
+    InvalidL1Size(u32),
+    /// The image doesn't start with the qcow2 magic.
+    InvalidMagic,
+    /// The name of the backing file is too long.
+    InvalidBackingFileName,
+    /// The image uses an unsupported refcount width.
+    InvalidRefcountOrder(u32),
+    /// An offset points past the virtual disk size.
+    InvalidOffset,
+    /// Failed to access the image file.
+    Io(io::Error),
+    /// The image has internal snapshots, which are only supported for read-only disks.
+    InternalSnapshots,
+    /// The refcount table needs to grow.
+    RefcountTableFull,
+    /// An L2 table is shared between multiple L1 tables.
+    SharedL2Table,
+    /// The L1 or refcount table, of the given number of entries, doesn't fit in the image file or
+    /// exceeds `MAX_TABLE_SIZE`.
+    TableTooLarge(u64),
+    /// Failed to transfer data from or to guest memory.
+    Transfer(GuestMemoryError),
+    /// The image uses incompatible features which are not supported.
+    UnsupportedFeatures(u64),
+    /// The image has an unsupported version.
+    UnsupportedVersion(u32),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// The fields of the qcow2 header used by this implementation.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+}
+
+impl Header {
+    // Offset of the autoclear features field in a version 3 header.
+    const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+    fn read(file: &File) -> Result<Self> {
+        let mut buf = [0u8; V3_HEADER_SIZE];
+        file.read_exact_at(&mut buf[..V2_HEADER_SIZE], 0)
+            .map_err(Error::Io)?;
+
+        if be_u32(&buf, 0) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let mut header = Header {
+            version: be_u32(&buf, 4),
+            backing_file_offset: be_u64(&buf, 8),
+            backing_file_size: be_u32(&buf, 16),
+            cluster_bits: be_u32(&buf, 20),
+            size: be_u64(&buf, 24),
+            crypt_method: be_u32(&buf, 32),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            nb_snapshots: be_u32(&buf, 60),
+            // Version 2 images always use 16 bit refcounts.
+            refcount_order: 4,
+            ..Default::default()
+        };
+
+        match header.version {
+            2 => {}
+            3 => {
+                file.read_exact_at(&mut buf[V2_HEADER_SIZE..], V2_HEADER_SIZE as u64)
+                    .map_err(Error::Io)?;
+                header.incompatible_features = be_u64(&buf, 72);
+                header.autoclear_features = be_u64(&buf, 88);
+                header.refcount_order = be_u32(&buf, 96);
+            }
+            version => return Err(Error::UnsupportedVersion(version)),
+        }
+
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&self.cluster_bits) {
+            return Err(Error::InvalidClusterBits(self.cluster_bits));
+        }
+        if !(MIN_REFCOUNT_ORDER..=MAX_REFCOUNT_ORDER).contains(&self.refcount_order) {
+            return Err(Error::InvalidRefcountOrder(self.refcount_order));
+        }
+        if self.crypt_method != 0 {
+            return Err(Error::Encrypted);
+        }
+        let unsupported_features =
+            self.incompatible_features & !INCOMPATIBLE_FEATURE_COMPRESSION_TYPE;
+        if unsupported_features != 0 {
+            return Err(Error::UnsupportedFeatures(unsupported_features));
+        }
+        if self.backing_file_size > MAX_BACKING_FILE_NAME_SIZE {
+            return Err(Error::InvalidBackingFileName);
+        }
+
+        // Each L1 entry maps one L2 table, which maps `cluster_size / 8` clusters.
+        let l1_entry_bits = 2 * self.cluster_bits - 3;
+        let l1_coverage = u64::from(self.l1_size)
+            .checked_shl(l1_entry_bits)
+            .filter(|coverage| coverage >> l1_entry_bits == u64::from(self.l1_size));
+        match l1_coverage {
+            Some(coverage) if coverage >= self.size => Ok(()),
+            _ => Err(Error::InvalidL1Size(self.l1_size)),
+        }
+    }
+}
+
+/// Where the data of a guest range can be found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Extent {
+    /// At the given offset in the image file.
+    Data(u64),
+    /// The range reads as zeroes.
+    Zero,
+    /// At the given offset in the backing file.
+    Backing(u64),
+}
+
+impl Extent {
+    // Returns the extent describing the range which starts `len` bytes after the start of this
+    // one, if it continues it.
+    fn advance(self, len: u64) -> Self {
+        match self {
+            Extent::Data(offset) => Extent::Data(offset + len),
+            Extent::Zero => Extent::Zero,
+            Extent::Backing(offset) => Extent::Backing(offset + len),
+        }
+    }
+}
+
+/// An L2 entry which has to be written once the data of a newly allocated cluster is in place.
+struct L2Update {
+    table_offset: u64,
+    index: u64,
+    entry: u64,
+    // Cluster whose reference is dropped by this update.
+    released_cluster: Option<u64>,
+}
+
+/// The file backing the unallocated clusters of an image.
+#[derive(Debug)]
+enum BackingFile {
+    Raw(File),
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl BackingFile {
+    fn open(path: &Path, depth: u32) -> Result<Self> {
+        let file = File::open(path).map_err(Error::BackingFile)?;
+
+        let mut magic = [0u8; 4];
+        let is_qcow2 = match file.read_exact_at(&mut magic, 0) {
+            Ok(()) => u32::from_be_bytes(magic) == QCOW_MAGIC,
+            // Files shorter than the magic can only be raw images.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(err) => return Err(Error::BackingFile(err)),
+        };
+
+        if is_qcow2 {
+            return Ok(BackingFile::Qcow2(Box::new(Qcow2Image::open_with_depth(
+                file, path, false, depth,
+            )?)));
+        }
+
+        Ok(BackingFile::Raw(file))
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self {
+            BackingFile::Raw(file) => file.metadata().map(|m| m.len()).map_err(Error::Io),
+            BackingFile::Qcow2(image) => Ok(image.virtual_size()),
+        }
+    }
+
+    /// Reads at `offset` into `buf`. The range past the end of the backing file reads as zeroes.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let available = cmp::min(self.size()?.saturating_sub(offset), buf.len() as u64) as usize;
+        let (data, zeroes) = buf.split_at_mut(available);
+        zeroes.fill(0);
+
+        match self {
+            BackingFile::Raw(file) => file.read_exact_at(data, offset).map_err(Error::Io),
+            BackingFile::Qcow2(image) => image.read_at(offset, data),
+        }
+    }
+}
+
+/// A qcow2 (version 2 or 3) disk image.
+#[derive(Debug)]
+pub struct Qcow2Image {
+    file: File,
+    header: Header,
+    cluster_size: u64,
+    // Number of entries in an L2 table.
+    l2_entries: u64,
+    // Number of entries in a refcount block.
+    refcount_block_entries: u64,
+    // Size in bytes of a refcount entry.
+    refcount_bytes: usize,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    backing_file: Option<BackingFile>,
+    // Offset in the image file where the next cluster gets allocated.
+    next_cluster_offset: u64,
+}
+
+impl Qcow2Image {
+    /// Opens the qcow2 image stored in `file`, found at `path`. The path is needed to locate the
+    /// backing file, if the image has one.
+    pub fn open(file: File, path: &Path, writable: bool) -> Result<Self> {
+        Self::open_with_depth(file, path, writable, 0)
+    }
+
+    fn open_with_depth(file: File, path: &Path, writable: bool, depth: u32) -> Result<Self> {
+        let mut header = Header::read(&file)?;
+        header.validate()?;
+
+        // Writing to clusters shared with internal snapshots would require copying the cluster
+        // tables as well.
+        if writable && header.nb_snapshots != 0 {
+            return Err(Error::InternalSnapshots);
+        }
+
+        // Autoclear features describe extensions (like bitmaps) which become stale as soon as
+        // the image is modified by software that doesn't know about them.
+        if writable && header.autoclear_features != 0 {
+            file.write_all_at(&0u64.to_be_bytes(), Header::AUTOCLEAR_FEATURES_OFFSET)
+                .map_err(Error::Io)?;
+            header.autoclear_features = 0;
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let refcount_bits = 1u64 << header.refcount_order;
+
+        let file_size = file.metadata().map_err(Error::Io)?.len();
+
+        let l1_table = Self::read_top_table(
+            &file,
+            file_size,
+            header.l1_table_offset,
+            u64::from(header.l1_size),
+        )?;
+        let refcount_table = Self::read_top_table(
+            &file,
+            file_size,
+            header.refcount_table_offset,
+            u64::from(header.refcount_table_clusters) * (cluster_size / 8),
+        )?;
+
+        let backing_file = match header.backing_file_offset {
+            0 => None,
+            offset => {
+                if depth >= MAX_BACKING_CHAIN_DEPTH {
+                    return Err(Error::BackingChainTooLong);
+                }
+                let mut name = vec![0u8; header.backing_file_size as usize];
+                file.read_exact_at(&mut name, offset).map_err(Error::Io)?;
+                let name = String::from_utf8(name).map_err(|_| Error::InvalidBackingFileName)?;
+
+                // Relative backing file names are relative to the directory of the image.
+                let backing_path = match path.parent() {
+                    Some(dir) => dir.join(name),
+                    None => PathBuf::from(name),
+                };
+                Some(BackingFile::open(&backing_path, depth + 1)?)
+            }
+        };
+
+        let next_cluster_offset = (file_size + cluster_size - 1) & !(cluster_size - 1);
+
+        Ok(Qcow2Image {
+            file,
+            cluster_size,
+            l2_entries: cluster_size / 8,
+            refcount_block_entries: cluster_size * 8 / refcount_bits,
+            refcount_bytes: (refcount_bits / 8) as usize,
+            l1_table,
+            refcount_table,
+            l2_cache: HashMap::new(),
+            backing_file,
+            next_cluster_offset,
+            header,
+        })
+    }
+
+    // Reads a table which stays in memory while the image is open, after checking that its size,
+    // which comes from the header, is reasonable.
+    fn read_top_table(file: &File, file_size: u64, offset: u64, entries: u64) -> Result<Vec<u64>> {
+        let end = entries
+            .checked_mul(8)
+            .filter(|&len| len <= MAX_TABLE_SIZE)
+            .and_then(|len| offset.checked_add(len));
+        match end {
+            Some(end) if end <= file_size => {}
+            _ => return Err(Error::TableTooLarge(entries)),
+        }
+        Self::read_table(file, offset, entries)
+    }
+
+    fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+        let mut buf = vec![0u8; (entries * 8) as usize];
+        file.read_exact_at(&mut buf, offset).map_err(Error::Io)?;
+        Ok(buf.chunks_exact(8).map(|entry| be_u64(entry, 0)).collect())
+    }
+
+    /// Size of the disk, as seen by the guest.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn cluster_offset(&self, offset: u64) -> u64 {
+        offset & !(self.cluster_size - 1)
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset >> (2 * self.header.cluster_bits - 3)) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> u64 {
+        (offset >> self.header.cluster_bits) & (self.l2_entries - 1)
+    }
+
+    fn read_l2_entry(&mut self, table_offset: u64, index: u64) -> Result<u64> {
+        if let Some(table) = self.l2_cache.get(&table_offset) {
+            return Ok(table[index as usize]);
+        }
+
+        let table = Self::read_table(&self.file, table_offset, self.l2_entries)?;
+        let entry = table[index as usize];
+        if self.l2_cache.len() >= L2_CACHE_SIZE {
+            // The cache is write-through, so any table can be evicted.
+            if let Some(&evicted) = self.l2_cache.keys().next() {
+                self.l2_cache.remove(&evicted);
+            }
+        }
+        self.l2_cache.insert(table_offset, table);
+        Ok(entry)
+    }
+
+    fn write_l2_entry(&mut self, table_offset: u64, index: u64, entry: u64) -> Result<()> {
+        self.file
+            .write_all_at(&entry.to_be_bytes(), table_offset + index * 8)
+            .map_err(Error::Io)?;
+        if let Some(table) = self.l2_cache.get_mut(&table_offset) {
+            table[index as usize] = entry;
+        }
+        Ok(())
+    }
+
+    fn write_l1_entry(&mut self, index: usize, entry: u64) -> Result<()> {
+        self.file
+            .write_all_at(
+                &entry.to_be_bytes(),
+                self.header.l1_table_offset + index as u64 * 8,
+            )
+            .map_err(Error::Io)?;
+        self.l1_table[index] = entry;
+        Ok(())
+    }
+
+    /// Returns where the data of the cluster containing `offset` can be found.
+    fn cluster_extent(&mut self, offset: u64) -> Result<Extent> {
+        let cluster_offset = self.cluster_offset(offset);
+        let unallocated = match self.backing_file {
+            Some(_) => Extent::Backing(cluster_offset),
+            None => Extent::Zero,
+        };
+
+        let l1_entry = *self
+            .l1_table
+            .get(self.l1_index(offset))
+            .ok_or(Error::InvalidOffset)?;
+        let table_offset = l1_entry & L1_OFFSET_MASK;
+        if table_offset == 0 {
+            return Ok(unallocated);
+        }
+
+        let l2_entry = self.read_l2_entry(table_offset, self.l2_index(offset))?;
+        if l2_entry & CLUSTER_COMPRESSED != 0 {
+            return Err(Error::CompressedCluster);
+        }
+        if self.header.version >= 3 && l2_entry & CLUSTER_ZERO != 0 {
+            return Ok(Extent::Zero);
+        }
+        match l2_entry & L2_OFFSET_MASK {
+            0 => Ok(unallocated),
+            host_offset => Ok(Extent::Data(host_offset)),
+        }
+    }
+
+    /// Splits the guest range `[offset, offset + len)` into extents, merging the contiguous ones.
+    fn extents(&mut self, offset: u64, len: u64) -> Result<Vec<(Extent, u64)>> {
+        let end = offset.checked_add(len).ok_or(Error::InvalidOffset)?;
+        if end > self.virtual_size() {
+            return Err(Error::InvalidOffset);
+        }
+
+        let mut extents: Vec<(Extent, u64)> = Vec::new();
+        let mut current = offset;
+        while current < end {
+            let in_cluster = current - self.cluster_offset(current);
+            let chunk_len = cmp::min(self.cluster_size - in_cluster, end - current);
+            let extent = self.cluster_extent(current)?.advance(in_cluster);
+
+            match extents.last_mut() {
+                Some((last, last_len)) if last.advance(*last_len) == extent => {
+                    *last_len += chunk_len
+                }
+                _ => extents.push((extent, chunk_len)),
+            }
+            current += chunk_len;
+        }
+
+        Ok(extents)
+    }
+
+    /// Reads the guest range starting at `offset` into `buf`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for (extent, len) in self.extents(offset, buf.len() as u64)? {
+            let chunk = &mut buf[done..done + len as usize];
+            match extent {
+                Extent::Data(host_offset) => {
+                    self.file
+                        .read_exact_at(chunk, host_offset)
+                        .map_err(Error::Io)?;
+                }
+                Extent::Zero => chunk.fill(0),
+                Extent::Backing(backing_offset) => {
+                    // `Backing` extents are only created when there is a backing file.
+                    if let Some(backing_file) = self.backing_file.as_mut() {
+                        backing_file.read_at(backing_offset, chunk)?;
+                    }
+                }
+            }
+            done += len as usize;
+        }
+        Ok(())
+    }
+
+    fn read_to_mem(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let mut addr = addr;
+        for (extent, len) in self.extents(offset, u64::from(count))? {
+            match extent {
+                Extent::Data(host_offset) => {
+                    self.file
+                        .seek(SeekFrom::Start(host_offset))
+                        .map_err(Error::Io)?;
+                    mem.read_exact_from(addr, &mut self.file, len as usize)
+                        .map_err(Error::Transfer)?;
+                }
+                Extent::Zero => {
+                    mem.read_exact_from(addr, &mut io::repeat(0), len as usize)
+                        .map_err(Error::Transfer)?;
+                }
+                Extent::Backing(backing_offset) => {
+                    let mut buf = vec![0u8; len as usize];
+                    if let Some(backing_file) = self.backing_file.as_mut() {
+                        backing_file.read_at(backing_offset, &mut buf)?;
+                    }
+                    mem.write_slice(&buf, addr).map_err(Error::Transfer)?;
+                }
+            }
+            addr = addr.unchecked_add(len);
+        }
+        Ok(count)
+    }
+
+    fn allocate_cluster(&mut self) -> Result<u64> {
+        let offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size;
+        // Extending the file makes sure the new cluster reads as zeroes.
+        self.file
+            .set_len(self.next_cluster_offset)
+            .map_err(Error::Io)?;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn refcount_entry_offset(&self, block_offset: u64, cluster_offset: u64) -> u64 {
+        let index = (cluster_offset >> self.header.cluster_bits) % self.refcount_block_entries;
+        block_offset + index * self.refcount_bytes as u64
+    }
+
+    fn read_refcount(&self, cluster_offset: u64) -> Result<u64> {
+        let block_index =
+            (cluster_offset >> self.header.cluster_bits) / self.refcount_block_entries;
+        let block_offset = match self.refcount_table.get(block_index as usize) {
+            Some(entry) => entry & REFCOUNT_TABLE_OFFSET_MASK,
+            None => 0,
+        };
+        if block_offset == 0 {
+            return Ok(0);
+        }
+
+        let mut buf = [0u8; 8];
+        self.file
+            .read_exact_at(
+                &mut buf[8 - self.refcount_bytes..],
+                self.refcount_entry_offset(block_offset, cluster_offset),
+            )
+            .map_err(Error::Io)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u64) -> Result<()> {
+        let block_index =
+            (cluster_offset >> self.header.cluster_bits) / self.refcount_block_entries;
+        let mut block_offset = self
+            .refcount_table
+            .get(block_index as usize)
+            .ok_or(Error::RefcountTableFull)?
+            & REFCOUNT_TABLE_OFFSET_MASK;
+
+        if block_offset == 0 {
+            block_offset = self.next_cluster_offset;
+            self.next_cluster_offset += self.cluster_size;
+            self.file
+                .set_len(self.next_cluster_offset)
+                .map_err(Error::Io)?;
+
+            // The new refcount block needs a reference itself, which it may have to hold.
+            let self_block_index =
+                (block_offset >> self.header.cluster_bits) / self.refcount_block_entries;
+            if self_block_index == block_index {
+                self.write_refcount(block_offset, block_offset, 1)?;
+            } else {
+                self.set_refcount(block_offset, 1)?;
+            }
+
+            self.file
+                .write_all_at(
+                    &block_offset.to_be_bytes(),
+                    self.header.refcount_table_offset + block_index * 8,
+                )
+                .map_err(Error::Io)?;
+            self.refcount_table[block_index as usize] = block_offset;
+        }
+
+        self.write_refcount(block_offset, cluster_offset, refcount)
+    }
+
+    fn write_refcount(&self, block_offset: u64, cluster_offset: u64, refcount: u64) -> Result<()> {
+        self.file
+            .write_all_at(
+                &refcount.to_be_bytes()[8 - self.refcount_bytes..],
+                self.refcount_entry_offset(block_offset, cluster_offset),
+            )
+            .map_err(Error::Io)
+    }
+
+    /// Returns the offset of the L2 table mapping `offset`, allocating it if needed.
+    fn l2_table_for_write(&mut self, offset: u64) -> Result<u64> {
+        let l1_index = self.l1_index(offset);
+        let l1_entry = *self.l1_table.get(l1_index).ok_or(Error::InvalidOffset)?;
+
+        match l1_entry & L1_OFFSET_MASK {
+            0 => {
+                let table_offset = self.allocate_cluster()?;
+                self.l2_cache
+                    .insert(table_offset, vec![0; self.l2_entries as usize]);
+                self.write_l1_entry(l1_index, table_offset | CLUSTER_COPIED)?;
+                Ok(table_offset)
+            }
+            _ if l1_entry & CLUSTER_COPIED == 0 => Err(Error::SharedL2Table),
+            table_offset => Ok(table_offset),
+        }
+    }
+
+    /// Prepares the cluster containing `offset` for writing `len` bytes starting at `offset`.
+    ///
+    /// Returns the offset of the cluster in the image file, and the L2 update that has to be
+    /// performed once the data is written, for newly allocated clusters.
+    fn cluster_for_write(&mut self, offset: u64, len: u64) -> Result<(u64, Option<L2Update>)> {
+        let cluster_offset = self.cluster_offset(offset);
+        let table_offset = self.l2_table_for_write(offset)?;
+        let index = self.l2_index(offset);
+        let l2_entry = self.read_l2_entry(table_offset, index)?;
+
+        if l2_entry & CLUSTER_COMPRESSED != 0 {
+            return Err(Error::CompressedCluster);
+        }
+        let is_zero = self.header.version >= 3 && l2_entry & CLUSTER_ZERO != 0;
+        let is_copied = l2_entry & CLUSTER_COPIED != 0;
+        let host_offset = l2_entry & L2_OFFSET_MASK;
+
+        if host_offset != 0 && is_copied && !is_zero {
+            return Ok((host_offset, None));
+        }
+
+        // Preallocated zero clusters can be reused, all the others need a new cluster.
+        let (new_offset, is_new) = if host_offset != 0 && is_copied {
+            (host_offset, false)
+        } else {
+            (self.allocate_cluster()?, true)
+        };
+
+        // Fill the parts of the cluster which are not covered by the write.
+        let in_cluster = offset - cluster_offset;
+        if in_cluster > 0 || len < self.cluster_size {
+            let mut buf = vec![0u8; self.cluster_size as usize];
+            if !is_zero {
+                if host_offset != 0 {
+                    self.file
+                        .read_exact_at(&mut buf, host_offset)
+                        .map_err(Error::Io)?;
+                } else if let Some(backing_file) = self.backing_file.as_mut() {
+                    backing_file.read_at(cluster_offset, &mut buf)?;
+                }
+            }
+
+            let tail = (in_cluster + len) as usize;
+            for (start, end) in [(0, in_cluster as usize), (tail, buf.len())] {
+                let chunk = &buf[start..end];
+                // New clusters already read as zeroes.
+                if is_new && chunk.iter().all(|&byte| byte == 0) {
+                    continue;
+                }
+                self.file
+                    .write_all_at(chunk, new_offset + start as u64)
+                    .map_err(Error::Io)?;
+            }
+        }
+
+        // A shared cluster loses the reference held by this L2 table.
+        let released_cluster = Some(host_offset).filter(|&offset| offset != 0 && !is_copied);
+        Ok((
+            new_offset,
+            Some(L2Update {
+                table_offset,
+                index,
+                entry: new_offset | CLUSTER_COPIED,
+                released_cluster,
+            }),
+        ))
+    }
+
+    fn commit(&mut self, update: L2Update) -> Result<()> {
+        self.write_l2_entry(update.table_offset, update.index, update.entry)?;
+        if let Some(cluster) = update.released_cluster {
+            let refcount = self.read_refcount(cluster)?;
+            self.set_refcount(cluster, refcount.saturating_sub(1))?;
+        }
+        Ok(())
+    }
+
+    fn write_from_mem(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let end = offset
+            .checked_add(u64::from(count))
+            .filter(|&end| end <= self.virtual_size())
+            .ok_or(Error::InvalidOffset)?;
+
+        let mut current = offset;
+        let mut addr = addr;
+        while current < end {
+            let in_cluster = current - self.cluster_offset(current);
+            let len = cmp::min(self.cluster_size - in_cluster, end - current);
+            let (host_cluster, update) = self.cluster_for_write(current, len)?;
+
+            self.file
+                .seek(SeekFrom::Start(host_cluster + in_cluster))
+                .map_err(Error::Io)?;
+            mem.write_all_to(addr, &mut self.file, len as usize)
+                .map_err(Error::Transfer)?;
+
+            // The data is in place, so the cluster can be linked in the L2 table.
+            if let Some(update) = update {
+                self.commit(update)?;
+            }
+
+            current += len;
+            addr = addr.unchecked_add(len);
+        }
+        Ok(count)
+    }
+
+    /// Returns the host offset of the guest range, if it is backed by a single allocated range
+    /// of the image file which can be written in place.
+    fn writable_host_range(&mut self, offset: u64, len: u64) -> Result<Option<u64>> {
+        let extents = self.extents(offset, len)?;
+        let host_offset = match extents.as_slice() {
+            [(Extent::Data(host_offset), _)] => *host_offset,
+            _ => return Ok(None),
+        };
+
+        // The extent may span multiple clusters, all of which must be owned by this image.
+        let mut current = offset;
+        while current < offset + len {
+            let l1_entry = self.l1_table[self.l1_index(current)];
+            let l2_entry = self.read_l2_entry(l1_entry & L1_OFFSET_MASK, self.l2_index(current))?;
+            if l1_entry & CLUSTER_COPIED == 0 || l2_entry & CLUSTER_COPIED == 0 {
+                return Ok(None);
+            }
+            current = self.cluster_offset(current) + self.cluster_size;
+        }
+        Ok(Some(host_offset))
+    }
+
+    pub fn read<T>(
+        &mut self,
+        engine: &mut FileEngine<T>,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        let res = match self.extents(offset, u64::from(count)) {
+            Ok(extents) => match extents.as_slice() {
+                [(Extent::Data(host_offset), _)] => {
+                    return engine.read(*host_offset, mem, addr, count, user_data);
+                }
+                _ => self.read_to_mem(offset, mem, addr, count),
+            },
+            Err(err) => Err(err),
+        };
+        Self::executed(res, user_data)
+    }
+
+    pub fn write<T>(
+        &mut self,
+        engine: &mut FileEngine<T>,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        let res = match self.writable_host_range(offset, u64::from(count)) {
+            Ok(Some(host_offset)) => {
+                return engine.write(host_offset, mem, addr, count, user_data);
+            }
+            Ok(None) => self.write_from_mem(offset, mem, addr, count),
+            Err(err) => Err(err),
+        };
+        Self::executed(res, user_data)
+    }
+
+    fn executed<T>(
+        res: Result<u32>,
+        user_data: T,
+    ) -> std::result::Result<FileEngineOk<T>, UserDataError<T, super::Error>> {
+        match res {
+            Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+            Err(err) => Err(UserDataError {
+                user_data,
+                error: super::Error::Qcow2(err),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+    use utils::skip_if_io_uring_unsupported;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::virtio::block::device::{FileEngineType, IoEngineConfig};
+
+    const CLUSTER_BITS: u32 = 12;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    // Two L2 tables worth of clusters.
+    const DISK_SIZE: u64 = 2 * CLUSTER_SIZE * (CLUSTER_SIZE / 8);
+    const MEM_LEN: usize = 4 * CLUSTER_SIZE as usize;
+    const BACKING_FILE_NAME_OFFSET: u64 = 512;
+
+    /// Writes an empty version 3 qcow2 image of `size` bytes to `file`.
+    ///
+    /// The header, the L1 table, the refcount table and the only refcount block are stored in
+    /// the first four clusters.
+    pub fn create_qcow2_image(file: &File, size: u64, backing_file: Option<&str>) {
+        let l2_coverage = CLUSTER_SIZE * CLUSTER_SIZE / 8;
+        let l1_size = (size + l2_coverage - 1) / l2_coverage;
+        let mut header = vec![0u8; V3_HEADER_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &QCOW_MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        if let Some(name) = backing_file {
+            put(8, &BACKING_FILE_NAME_OFFSET.to_be_bytes());
+            put(16, &(name.len() as u32).to_be_bytes());
+        }
+        put(20, &CLUSTER_BITS.to_be_bytes());
+        put(24, &size.to_be_bytes());
+        put(36, &(l1_size as u32).to_be_bytes());
+        put(40, &CLUSTER_SIZE.to_be_bytes());
+        put(48, &(2 * CLUSTER_SIZE).to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &(V3_HEADER_SIZE as u32).to_be_bytes());
+
+        file.set_len(4 * CLUSTER_SIZE).unwrap();
+        file.write_all_at(&header, 0).unwrap();
+        if let Some(name) = backing_file {
+            file.write_all_at(name.as_bytes(), BACKING_FILE_NAME_OFFSET)
+                .unwrap();
+        }
+        file.write_all_at(&(3 * CLUSTER_SIZE).to_be_bytes(), 2 * CLUSTER_SIZE)
+            .unwrap();
+        for cluster in 0..4 {
+            file.write_all_at(&1u16.to_be_bytes(), 3 * CLUSTER_SIZE + cluster * 2)
+                .unwrap();
+        }
+    }
+
+    fn create_mem() -> GuestMemoryMmap {
+        vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], true)
+            .unwrap()
+    }
+
+    fn open_image(tmp: &TempFile) -> Qcow2Image {
+        Qcow2Image::open(tmp.as_file().try_clone().unwrap(), tmp.as_path(), true).unwrap()
+    }
+
+    fn sync_engine(tmp: &TempFile) -> FileEngine<()> {
+        FileEngine::from_file(
+            tmp.as_file().try_clone().unwrap(),
+            FileEngineType::Sync,
+            IoEngineConfig::default(),
+        )
+        .unwrap()
+    }
+
+    fn write_guest(
+        image: &mut Qcow2Image,
+        engine: &mut FileEngine<()>,
+        offset: u64,
+        data: &[u8],
+    ) -> FileEngineOk<()> {
+        let mem = create_mem();
+        mem.write_slice(data, GuestAddress(0)).unwrap();
+        image
+            .write(engine, offset, &mem, GuestAddress(0), data.len() as u32, ())
+            .unwrap()
+    }
+
+    fn read_guest(
+        image: &mut Qcow2Image,
+        engine: &mut FileEngine<()>,
+        offset: u64,
+        len: usize,
+    ) -> Vec<u8> {
+        let mem = create_mem();
+        let res = image
+            .read(engine, offset, &mem, GuestAddress(0), len as u32, ())
+            .unwrap();
+        assert!(matches!(
+            res,
+            FileEngineOk::Executed(UserDataOk { count, .. }) if count == len as u32
+        ));
+        let mut buf = vec![0u8; len];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_open() {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        let image = open_image(&tmp);
+        assert_eq!(image.virtual_size(), DISK_SIZE);
+        assert_eq!(image.cluster_size, CLUSTER_SIZE);
+        assert_eq!(image.l1_table, vec![0, 0]);
+        assert_eq!(image.refcount_block_entries, CLUSTER_SIZE / 2);
+        assert_eq!(image.next_cluster_offset, 4 * CLUSTER_SIZE);
+        assert_eq!(image.read_refcount(3 * CLUSTER_SIZE).unwrap(), 1);
+        assert_eq!(image.read_refcount(4 * CLUSTER_SIZE).unwrap(), 0);
+
+        // Invalid magic.
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().set_len(CLUSTER_SIZE).unwrap();
+        assert!(matches!(
+            Qcow2Image::open(tmp.into_file(), Path::new(""), true),
+            Err(Error::InvalidMagic)
+        ));
+
+        let check_invalid_header = |offset: u64, bytes: &[u8], check: fn(Error) -> bool| {
+            let tmp = TempFile::new().unwrap();
+            create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+            tmp.as_file().write_all_at(bytes, offset).unwrap();
+            let err = Qcow2Image::open(tmp.as_file().try_clone().unwrap(), tmp.as_path(), true)
+                .unwrap_err();
+            assert!(check(err));
+        };
+        check_invalid_header(4, &1u32.to_be_bytes(), |err| {
+            matches!(err, Error::UnsupportedVersion(1))
+        });
+        check_invalid_header(20, &8u32.to_be_bytes(), |err| {
+            matches!(err, Error::InvalidClusterBits(8))
+        });
+        check_invalid_header(32, &1u32.to_be_bytes(), |err| {
+            matches!(err, Error::Encrypted)
+        });
+        check_invalid_header(36, &1u32.to_be_bytes(), |err| {
+            matches!(err, Error::InvalidL1Size(1))
+        });
+        check_invalid_header(60, &1u32.to_be_bytes(), |err| {
+            matches!(err, Error::InternalSnapshots)
+        });
+        // Tables larger than the image file, or too large to be kept in memory.
+        check_invalid_header(36, &0x1000u32.to_be_bytes(), |err| {
+            matches!(err, Error::TableTooLarge(0x1000))
+        });
+        check_invalid_header(36, &u32::MAX.to_be_bytes(), |err| {
+            matches!(err, Error::TableTooLarge(_))
+        });
+        check_invalid_header(56, &u32::MAX.to_be_bytes(), |err| {
+            matches!(err, Error::TableTooLarge(_))
+        });
+        // Dirty bit.
+        check_invalid_header(72, &1u64.to_be_bytes(), |err| {
+            matches!(err, Error::UnsupportedFeatures(1))
+        });
+        check_invalid_header(96, &1u32.to_be_bytes(), |err| {
+            matches!(err, Error::InvalidRefcountOrder(1))
+        });
+
+        // Autoclear features are cleared when opening the image for writing.
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        tmp.as_file().write_all_at(&1u64.to_be_bytes(), 88).unwrap();
+        let image =
+            Qcow2Image::open(tmp.as_file().try_clone().unwrap(), tmp.as_path(), false).unwrap();
+        assert_eq!(image.header.autoclear_features, 1);
+        let image = open_image(&tmp);
+        assert_eq!(image.header.autoclear_features, 0);
+        assert_eq!(Header::read(tmp.as_file()).unwrap().autoclear_features, 0);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        let mut image = open_image(&tmp);
+        let mut engine = sync_engine(&tmp);
+
+        // Unallocated clusters read as zeroes.
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, MEM_LEN),
+            vec![0u8; MEM_LEN]
+        );
+        assert_eq!(
+            image.extents(0, 2 * CLUSTER_SIZE).unwrap(),
+            vec![(Extent::Zero, 2 * CLUSTER_SIZE)]
+        );
+
+        // A write straddling two clusters allocates an L2 table and two data clusters.
+        let data = vec![0xaa; CLUSTER_SIZE as usize];
+        let offset = CLUSTER_SIZE / 2;
+        assert!(matches!(
+            write_guest(&mut image, &mut engine, offset, &data),
+            FileEngineOk::Executed(UserDataOk { count, .. }) if count == CLUSTER_SIZE as u32
+        ));
+        assert_eq!(image.l1_table[0], 4 * CLUSTER_SIZE | CLUSTER_COPIED);
+        for cluster in 4..7 {
+            assert_eq!(image.read_refcount(cluster * CLUSTER_SIZE).unwrap(), 1);
+        }
+        assert_eq!(tmp.as_file().metadata().unwrap().len(), 7 * CLUSTER_SIZE);
+        assert_eq!(
+            image.extents(0, 2 * CLUSTER_SIZE).unwrap(),
+            vec![(Extent::Data(5 * CLUSTER_SIZE), 2 * CLUSTER_SIZE)]
+        );
+
+        let mut expected = vec![0u8; 3 * CLUSTER_SIZE as usize];
+        expected[offset as usize..(offset + CLUSTER_SIZE) as usize].copy_from_slice(&data);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, expected.len()),
+            expected
+        );
+
+        // Writes to allocated clusters are passed on to the file engine and don't allocate.
+        let data = vec![0xbb; 16];
+        assert!(matches!(
+            write_guest(&mut image, &mut engine, CLUSTER_SIZE, &data),
+            FileEngineOk::Executed(UserDataOk { count: 16, .. })
+        ));
+        expected[CLUSTER_SIZE as usize..CLUSTER_SIZE as usize + 16].copy_from_slice(&data);
+        assert_eq!(tmp.as_file().metadata().unwrap().len(), 7 * CLUSTER_SIZE);
+
+        // A write in the second L2 table allocates a new L2 table.
+        let offset = DISK_SIZE - CLUSTER_SIZE;
+        write_guest(&mut image, &mut engine, offset, &data);
+        assert_eq!(image.l1_table[1], 7 * CLUSTER_SIZE | CLUSTER_COPIED);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, offset, CLUSTER_SIZE as usize)[..16],
+            data[..]
+        );
+
+        // The metadata is persisted.
+        let mut image = open_image(&tmp);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, expected.len()),
+            expected
+        );
+        assert_eq!(image.read_refcount(8 * CLUSTER_SIZE).unwrap(), 1);
+
+        // Out of bounds accesses fail.
+        let mem = create_mem();
+        assert!(matches!(
+            image.read(&mut engine, DISK_SIZE, &mem, GuestAddress(0), 1, ()),
+            Err(UserDataError {
+                error: super::super::Error::Qcow2(Error::InvalidOffset),
+                ..
+            })
+        ));
+        assert!(matches!(
+            image.write(&mut engine, DISK_SIZE - 1, &mem, GuestAddress(0), 2, ()),
+            Err(UserDataError {
+                error: super::super::Error::Qcow2(Error::InvalidOffset),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_zero_and_compressed_clusters() {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        let mut image = open_image(&tmp);
+        let mut engine = sync_engine(&tmp);
+
+        let data = vec![0xaa; 2 * CLUSTER_SIZE as usize];
+        write_guest(&mut image, &mut engine, 0, &data);
+        let l2_table = image.l1_table[0] & L1_OFFSET_MASK;
+
+        // Mark the first cluster as zero, keeping it preallocated.
+        let entry = image.read_l2_entry(l2_table, 0).unwrap();
+        image
+            .write_l2_entry(l2_table, 0, entry | CLUSTER_ZERO)
+            .unwrap();
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, CLUSTER_SIZE as usize),
+            vec![0u8; CLUSTER_SIZE as usize]
+        );
+
+        // A partial write reuses the preallocated cluster, and zeroes the rest of it.
+        write_guest(&mut image, &mut engine, 16, &data[..16]);
+        assert_eq!(image.read_l2_entry(l2_table, 0).unwrap(), entry);
+        let mut expected = vec![0u8; CLUSTER_SIZE as usize];
+        expected[16..32].copy_from_slice(&data[..16]);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, CLUSTER_SIZE as usize),
+            expected
+        );
+
+        // Compressed clusters are not supported.
+        let entry = image.read_l2_entry(l2_table, 1).unwrap();
+        image
+            .write_l2_entry(l2_table, 1, entry | CLUSTER_COMPRESSED)
+            .unwrap();
+        let mem = create_mem();
+        assert!(matches!(
+            image.read(&mut engine, CLUSTER_SIZE, &mem, GuestAddress(0), 1, ()),
+            Err(UserDataError {
+                error: super::super::Error::Qcow2(Error::CompressedCluster),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_backing_file() {
+        // Raw base image, with a qcow2 image on top of it, with another qcow2 image on top.
+        let raw = TempFile::new().unwrap();
+        let base_data = vec![0x11; CLUSTER_SIZE as usize + 100];
+        raw.as_file().write_all_at(&base_data, 0).unwrap();
+        let middle = TempFile::new().unwrap();
+        create_qcow2_image(
+            middle.as_file(),
+            DISK_SIZE,
+            Some(raw.as_path().file_name().unwrap().to_str().unwrap()),
+        );
+        let top = TempFile::new().unwrap();
+        create_qcow2_image(
+            top.as_file(),
+            DISK_SIZE,
+            Some(middle.as_path().to_str().unwrap()),
+        );
+
+        let mut middle_image = open_image(&middle);
+        let mut middle_engine = sync_engine(&middle);
+        let middle_data = vec![0x22; 16];
+        write_guest(&mut middle_image, &mut middle_engine, 16, &middle_data);
+
+        let mut image = open_image(&top);
+        let mut engine = sync_engine(&top);
+        assert!(matches!(image.backing_file, Some(BackingFile::Qcow2(_))));
+
+        // Data comes from the whole chain, and the range past the raw image reads as zeroes.
+        let mut expected = vec![0u8; 2 * CLUSTER_SIZE as usize];
+        expected[..base_data.len()].copy_from_slice(&base_data);
+        expected[16..32].copy_from_slice(&middle_data);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, expected.len()),
+            expected
+        );
+
+        // Partial writes copy the rest of the cluster from the backing file.
+        let data = vec![0x33; 8];
+        write_guest(&mut image, &mut engine, 8, &data);
+        write_guest(&mut image, &mut engine, CLUSTER_SIZE + 200, &data);
+        expected[8..16].copy_from_slice(&data);
+        expected[CLUSTER_SIZE as usize + 200..CLUSTER_SIZE as usize + 208].copy_from_slice(&data);
+        assert_eq!(
+            read_guest(&mut image, &mut engine, 0, expected.len()),
+            expected
+        );
+        assert!(matches!(
+            image.extents(0, 2 * CLUSTER_SIZE).unwrap().as_slice(),
+            [(Extent::Data(_), len)] if *len == 2 * CLUSTER_SIZE
+        ));
+
+        // The backing files are left untouched.
+        let mut buf = vec![0u8; base_data.len()];
+        raw.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, base_data);
+        let mut middle_image = open_image(&middle);
+        assert_eq!(
+            read_guest(&mut middle_image, &mut middle_engine, 8, 8),
+            vec![0x11; 8]
+        );
+
+        // Missing backing files are reported when opening the image.
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, Some("/invalid/path"));
+        assert!(matches!(
+            Qcow2Image::open(tmp.as_file().try_clone().unwrap(), tmp.as_path(), true),
+            Err(Error::BackingFile(_))
+        ));
+
+        // Loops in the backing chain are detected.
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(
+            tmp.as_file(),
+            DISK_SIZE,
+            Some(tmp.as_path().to_str().unwrap()),
+        );
+        assert!(matches!(
+            Qcow2Image::open(tmp.as_file().try_clone().unwrap(), tmp.as_path(), true),
+            Err(Error::BackingChainTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_refcount_block_allocation() {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        let mut image = open_image(&tmp);
+
+        // Setting the refcount of a cluster not covered by the first refcount block allocates a
+        // new one, which also accounts for itself.
+        let cluster = image.refcount_block_entries * CLUSTER_SIZE;
+        image.set_refcount(cluster, 1).unwrap();
+        assert_eq!(image.refcount_table[1], 4 * CLUSTER_SIZE);
+        assert_eq!(image.read_refcount(cluster).unwrap(), 1);
+        assert_eq!(image.read_refcount(4 * CLUSTER_SIZE).unwrap(), 1);
+        assert_eq!(image.next_cluster_offset, 5 * CLUSTER_SIZE);
+
+        // The refcount table doesn't grow.
+        let entries = image.refcount_table.len() as u64;
+        assert!(matches!(
+            image.set_refcount(entries * image.refcount_block_entries * CLUSTER_SIZE, 1),
+            Err(Error::RefcountTableFull)
+        ));
+    }
+
+    #[test]
+    fn test_async() {
+        skip_if_io_uring_unsupported!();
+
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), DISK_SIZE, None);
+        let mut image = open_image(&tmp);
+        let mut engine = FileEngine::from_file(
+            tmp.as_file().try_clone().unwrap(),
+            FileEngineType::Async,
+            IoEngineConfig::default(),
+        )
+        .unwrap();
+
+        // Allocating writes are executed synchronously.
+        let data = vec![0xaa; CLUSTER_SIZE as usize];
+        assert!(matches!(
+            write_guest(&mut image, &mut engine, 0, &data),
+            FileEngineOk::Executed(_)
+        ));
+
+        // Accesses to allocated clusters are submitted to the engine.
+        let mem = create_mem();
+        assert!(matches!(
+            image.write(&mut engine, 0, &mem, GuestAddress(0), 16, ()),
+            Ok(FileEngineOk::Submitted)
+        ));
+        assert!(matches!(
+            image.read(&mut engine, 0, &mem, GuestAddress(0), 16, ()),
+            Ok(FileEngineOk::Submitted)
+        ));
+        engine.drain(false).unwrap();
+    }
+}
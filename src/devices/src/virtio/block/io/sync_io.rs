@@ -3,16 +3,23 @@
 
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 
-use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use utils::syscall::SyscallReturnCode;
+use vm_memory::{
+    mark_dirty_mem, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+};
 
 #[derive(Debug)]
 pub enum Error {
+    Fallocate(std::io::Error),
     Flush(std::io::Error),
+    Read(std::io::Error),
     Seek(std::io::Error),
     SyncAll(std::io::Error),
     Transfer(GuestMemoryError),
+    Write(std::io::Error),
 }
 
 pub struct SyncFileEngine {
@@ -62,6 +69,83 @@ impl SyncFileEngine {
             .map_err(Error::Transfer)
     }
 
+    /// Reads into the given guest memory segments, in order, with a single system call.
+    pub fn readv(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+    ) -> Result<u32, Error> {
+        let iovecs = iovecs(mem, segments).map_err(Error::Transfer)?;
+        // SAFETY: Safe because the file descriptor is valid, the buffers are valid guest memory
+        // and we check the return value.
+        let count = SyscallReturnCode(unsafe {
+            libc::preadv(
+                self.file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        })
+        .into_result()
+        .map_err(Error::Read)? as u32;
+
+        // The memory was written behind the back of the dirty page tracking.
+        let mut remaining = count;
+        for &(addr, len) in segments {
+            let len = std::cmp::min(len, remaining);
+            mark_dirty_mem(mem, addr, len as usize);
+            remaining -= len;
+        }
+
+        Ok(count)
+    }
+
+    /// Writes the given guest memory segments, in order, with a single system call.
+    pub fn writev(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        segments: &[(GuestAddress, u32)],
+    ) -> Result<u32, Error> {
+        let iovecs = iovecs(mem, segments).map_err(Error::Transfer)?;
+        // SAFETY: Safe because the file descriptor is valid, the buffers are valid guest memory
+        // and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::pwritev(
+                self.file.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        })
+        .into_result()
+        .map(|count| count as u32)
+        .map_err(Error::Write)
+    }
+
+    pub fn write_buffer(&mut self, offset: u64, buf: &[u8]) -> Result<u32, Error> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        self.file.write_all(buf).map_err(Error::Write)?;
+        Ok(buf.len() as u32)
+    }
+
+    pub fn fallocate(&mut self, offset: u64, len: u64, mode: u32) -> Result<(), Error> {
+        // SAFETY: Safe because the file descriptor is valid and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode as libc::c_int,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        })
+        .into_empty_result()
+        .map_err(Error::Fallocate)
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
         // flush() first to force any cached data out of rust buffers.
         self.file.flush().map_err(Error::Flush)?;
@@ -69,3 +153,19 @@ impl SyncFileEngine {
         self.file.sync_all().map_err(Error::SyncAll)
     }
 }
+
+/// Describes the host buffers backing the given guest memory segments.
+pub(crate) fn iovecs(
+    mem: &GuestMemoryMmap,
+    segments: &[(GuestAddress, u32)],
+) -> Result<Vec<libc::iovec>, GuestMemoryError> {
+    segments
+        .iter()
+        .map(|&(addr, len)| {
+            mem.get_slice(addr, len as usize).map(|slice| libc::iovec {
+                iov_base: slice.as_ptr().cast(),
+                iov_len: len as usize,
+            })
+        })
+        .collect()
+}
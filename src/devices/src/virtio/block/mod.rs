@@ -1,25 +1,49 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod crypt;
 pub mod device;
 pub mod event_handler;
 mod io;
 pub mod persist;
 pub mod request;
 pub mod test_utils;
+pub mod trace;
+pub mod verity;
 
 use vm_memory::GuestMemoryError;
 
-pub use self::device::{Block, CacheType};
+pub use self::crypt::EncryptionConfig;
+pub use self::device::{Block, BlockTopology, CacheType, ImageFormat, IoEngineConfig};
 pub use self::event_handler::*;
+pub use self::io::NbdConfig;
 pub use self::request::*;
+pub use self::trace::TraceConfig;
+pub use self::verity::VerityConfig;
 
 pub const CONFIG_SPACE_SIZE: usize = 8;
+// Size of the config space, up to and including the block size field.
+pub const BLK_SIZE_CONFIG_SPACE_SIZE: usize = 24;
+// Size of the config space, up to and including the topology fields.
+pub const TOPOLOGY_CONFIG_SPACE_SIZE: usize = 32;
+// Size of the config space, up to and including the discard and write zeroes fields.
+pub const DISCARD_CONFIG_SPACE_SIZE: usize = 60;
+// Size of the config space, up to and including the number of queues field.
+pub const MQ_CONFIG_SPACE_SIZE: usize = 36;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
+// The largest logical block size, as guest kernels require a block to fit in a page.
+pub const MAX_LOGICAL_BLOCK_SIZE: u32 = 4096;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+// The maximum number of request queues, matching the maximum number of vCPUs.
+pub const MAX_NUM_QUEUES: usize = 32;
+// The maximum number of sectors of a discard or write zeroes request. Limiting it makes sure
+// that the length in bytes of a request always fits in a u32.
+pub const MAX_DISCARD_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+// The maximum number of contiguous read or write requests merged into a single transfer.
+pub const MAX_MERGED_REQUESTS: usize = 64;
+// The maximum length in bytes of a merged transfer.
+pub const MAX_MERGED_DATA_LEN: u32 = 1 << 20;
 // The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
 // So we can use 128 IO_URING entries without ever triggering a FullSq Error.
 pub const IO_URING_NUM_ENTRIES: u16 = 128;
@@ -36,14 +60,36 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// The data length is invalid.
     InvalidDataLength,
+    /// The number of queues is either zero or above the supported maximum.
+    InvalidNumQueues(usize),
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// The block sizes or the IO size hints are inconsistent.
+    InvalidTopology(BlockTopology),
+    /// The device state describes an encrypted disk, but no key was provided to restore it.
+    MissingEncryptionKey,
+    /// The requested operation doesn't start at a logical block boundary.
+    UnalignedOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
     UnexpectedReadOnlyDescriptor,
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
+    /// The `Direct` cache type is not supported for the image format.
+    UnsupportedDirectIo(ImageFormat),
+    /// Encryption is only supported for raw disk images without discard support, integrity
+    /// verification or the `Direct` cache type.
+    UnsupportedEncryption,
+    /// NBD exports are only supported as raw disks, without integrity verification, the `Async`
+    /// engine or the `Direct` cache type, and can't be replaced by a disk image file.
+    UnsupportedNbd,
+    /// Integrity verification is only supported for read-only raw disk images.
+    UnsupportedVerity,
     // Error coming from the IO engine.
     FileEngine(io::Error),
+    // Error setting up the direct access to the backing file.
+    DirectIo(io::direct_io::Error),
+    // Error opening the qcow2 image.
+    Qcow2(io::qcow2::Error),
     // Error manipulating the backing file.
     BackingFile(std::io::Error),
     // Error opening eventfd.
@@ -54,4 +100,10 @@ pub enum Error {
     RateLimiter(std::io::Error),
     // Persistence error.
     Persist(crate::virtio::persist::Error),
+    // Error opening the hash tree of the disk.
+    Verity(verity::Error),
+    // Error setting up the encryption of the disk.
+    Crypt(crypt::Error),
+    // Error starting or stopping the trace of the requests.
+    Trace(trace::Error),
 }
@@ -13,11 +13,13 @@ use snapshot::Persist;
 use utils::kernel_version::min_kernel_version_for_io_uring;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
-use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
+use virtio_gen::virtio_blk::{VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_RO};
 use vm_memory::GuestMemoryMmap;
 
 use super::*;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::crypt::EncryptionConfig;
+use crate::virtio::block::device::{BlockTopology, FileEngineType, ImageFormat, IoEngineConfig};
+use crate::virtio::block::verity::VerityConfig;
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
@@ -26,6 +28,7 @@ use crate::virtio::{DeviceState, TYPE_BLOCK};
 pub enum CacheTypeState {
     Unsafe,
     Writeback,
+    Direct,
 }
 
 impl From<CacheType> for CacheTypeState {
@@ -33,6 +36,7 @@ impl From<CacheType> for CacheTypeState {
         match cache_type {
             CacheType::Unsafe => CacheTypeState::Unsafe,
             CacheType::Writeback => CacheTypeState::Writeback,
+            CacheType::Direct => CacheTypeState::Direct,
         }
     }
 }
@@ -42,6 +46,7 @@ impl From<CacheTypeState> for CacheType {
         match cache_type_state {
             CacheTypeState::Unsafe => CacheType::Unsafe,
             CacheTypeState::Writeback => CacheType::Writeback,
+            CacheTypeState::Direct => CacheType::Direct,
         }
     }
 }
@@ -56,7 +61,8 @@ pub enum FileEngineTypeState {
 impl From<FileEngineType> for FileEngineTypeState {
     fn from(file_engine_type: FileEngineType) -> Self {
         match file_engine_type {
-            FileEngineType::Sync => FileEngineTypeState::Sync,
+            // Devices only report the engine selected for the `Auto` type.
+            FileEngineType::Sync | FileEngineType::Auto => FileEngineTypeState::Sync,
             FileEngineType::Async => FileEngineTypeState::Async,
         }
     }
@@ -79,6 +85,165 @@ impl Default for FileEngineTypeState {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ImageFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<ImageFormat> for ImageFormatState {
+    fn from(image_format: ImageFormat) -> Self {
+        match image_format {
+            ImageFormat::Raw => ImageFormatState::Raw,
+            ImageFormat::Qcow2 => ImageFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<ImageFormatState> for ImageFormat {
+    fn from(image_format_state: ImageFormatState) -> Self {
+        match image_format_state {
+            ImageFormatState::Raw => ImageFormat::Raw,
+            ImageFormatState::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+impl Default for ImageFormatState {
+    fn default() -> Self {
+        // Snapshots which don't contain the `ImageFormat` were created on VMs using raw images.
+        ImageFormatState::Raw
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct IoEngineConfigState {
+    registered_buffers: bool,
+    sqpoll_idle_ms: Option<u32>,
+}
+
+impl From<IoEngineConfig> for IoEngineConfigState {
+    fn from(config: IoEngineConfig) -> Self {
+        IoEngineConfigState {
+            registered_buffers: config.registered_buffers,
+            sqpoll_idle_ms: config.sqpoll_idle_ms,
+        }
+    }
+}
+
+impl From<IoEngineConfigState> for IoEngineConfig {
+    fn from(state: IoEngineConfigState) -> Self {
+        IoEngineConfig {
+            registered_buffers: state.registered_buffers,
+            sqpoll_idle_ms: state.sqpoll_idle_ms,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct BlockTopologyState {
+    logical_block_size: Option<u32>,
+    physical_block_size: Option<u32>,
+    min_io_size: Option<u32>,
+    opt_io_size: Option<u32>,
+}
+
+impl From<BlockTopology> for BlockTopologyState {
+    fn from(topology: BlockTopology) -> Self {
+        BlockTopologyState {
+            logical_block_size: topology.logical_block_size,
+            physical_block_size: topology.physical_block_size,
+            min_io_size: topology.min_io_size,
+            opt_io_size: topology.opt_io_size,
+        }
+    }
+}
+
+impl From<BlockTopologyState> for BlockTopology {
+    fn from(state: BlockTopologyState) -> Self {
+        BlockTopology {
+            logical_block_size: state.logical_block_size,
+            physical_block_size: state.physical_block_size,
+            min_io_size: state.min_io_size,
+            opt_io_size: state.opt_io_size,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VerityConfigState {
+    root_hash: String,
+    salt: Option<String>,
+    hash_path: Option<String>,
+    hash_offset: u64,
+    data_block_size: Option<u32>,
+    hash_block_size: Option<u32>,
+}
+
+impl From<VerityConfig> for VerityConfigState {
+    fn from(config: VerityConfig) -> Self {
+        VerityConfigState {
+            root_hash: config.root_hash,
+            salt: config.salt,
+            hash_path: config.hash_path,
+            hash_offset: config.hash_offset,
+            data_block_size: config.data_block_size,
+            hash_block_size: config.hash_block_size,
+        }
+    }
+}
+
+impl From<VerityConfigState> for VerityConfig {
+    fn from(state: VerityConfigState) -> Self {
+        VerityConfig {
+            root_hash: state.root_hash,
+            salt: state.salt,
+            hash_path: state.hash_path,
+            hash_offset: state.hash_offset,
+            data_block_size: state.data_block_size,
+            hash_block_size: state.hash_block_size,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct NbdConfigState {
+    socket_path: Option<String>,
+    address: Option<String>,
+    export_name: String,
+    reconnect_attempts: u32,
+    reconnect_delay_ms: Option<u64>,
+}
+
+impl From<NbdConfig> for NbdConfigState {
+    fn from(config: NbdConfig) -> Self {
+        NbdConfigState {
+            socket_path: config.socket_path,
+            address: config.address,
+            export_name: config.export_name,
+            reconnect_attempts: config.reconnect_attempts,
+            reconnect_delay_ms: config.reconnect_delay_ms,
+        }
+    }
+}
+
+impl From<NbdConfigState> for NbdConfig {
+    fn from(state: NbdConfigState) -> Self {
+        NbdConfig {
+            socket_path: state.socket_path,
+            address: state.address,
+            export_name: state.export_name,
+            reconnect_attempts: state.reconnect_attempts,
+            reconnect_delay_ms: state.reconnect_delay_ms,
+        }
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
@@ -99,6 +264,32 @@ pub struct BlockState {
     // v1.0 are incompatible with older FC versions (due to incompatible notification suppression
     // feature).
     file_engine_type: FileEngineTypeState,
+    // Older versions restore the engine which was selected when the snapshot was created.
+    #[version(start = 4)]
+    auto_engine: bool,
+    #[version(start = 4, ser_fn = "block_image_format_ser")]
+    image_format: ImageFormatState,
+    #[version(
+        start = 4,
+        ser_fn = "block_num_queues_ser",
+        default_fn = "default_num_queues"
+    )]
+    num_queues: u16,
+    // Older versions don't tune the engine, which only affects performance.
+    #[version(start = 4)]
+    io_engine_config: IoEngineConfigState,
+    // Older versions don't advertise a topology, which guest drivers only read when probing the
+    // device. They don't reject unaligned requests either.
+    #[version(start = 4)]
+    topology: BlockTopologyState,
+    #[version(start = 4, ser_fn = "block_verity_ser")]
+    verity: Option<VerityConfigState>,
+    // Only whether the disk is encrypted is saved, the key has to be provided again on restore.
+    #[version(start = 4, ser_fn = "block_encrypted_ser")]
+    encrypted: bool,
+    // The connection to the NBD server is established again on restore.
+    #[version(start = 4, ser_fn = "block_nbd_ser")]
+    nbd: Option<NbdConfigState>,
 }
 
 impl BlockState {
@@ -108,6 +299,13 @@ impl BlockState {
                 "Target version does not implement the current cache type. Defaulting to \
                  \"unsafe\" mode."
             );
+        } else if target_version < 4 && self.cache_type == CacheTypeState::Direct {
+            // The data is flushed the same way, only the host page cache is no longer bypassed.
+            warn!(
+                "Target version does not implement the \"Direct\" cache type. Defaulting to \
+                 \"Writeback\" mode."
+            );
+            self.cache_type = CacheTypeState::Writeback;
         }
 
         Ok(())
@@ -116,10 +314,79 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn block_image_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would treat the image as raw, exposing the qcow2 metadata to the guest.
+        if target_version < 4 && self.image_format != ImageFormatState::Raw {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a block device backed by a qcow2 image to target version {}",
+                target_version
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn block_num_queues_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.num_queues != 1 {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a block device with {} queues to target version {}",
+                self.num_queues, target_version
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queues(_source_version: u16) -> u16 {
+        1
+    }
+
+    fn block_verity_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would serve the data to the guest without verifying it.
+        if target_version < 4 && self.verity.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a block device with integrity verification to target version {}",
+                target_version
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn block_encrypted_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would expose the encrypted data to the guest.
+        if target_version < 4 && self.encrypted {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize an encrypted block device to target version {}",
+                target_version
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn block_nbd_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would try to open the URI of the export as a file.
+        if target_version < 4 && self.nbd.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a block device backed by an NBD export to target version {}",
+                target_version
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
 }
 
 pub struct BlockConstructorArgs {
     pub mem: GuestMemoryMmap,
+    /// The encryption key of the disk, which is not part of the device state.
+    pub encryption: Option<EncryptionConfig>,
 }
 
 impl Persist<'_> for Block {
@@ -138,6 +405,14 @@ impl Persist<'_> for Block {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            auto_engine: self.is_auto_engine(),
+            image_format: ImageFormatState::from(self.image_format()),
+            num_queues: self.num_queues() as u16,
+            io_engine_config: IoEngineConfigState::from(self.io_engine_config()),
+            topology: BlockTopologyState::from(self.topology()),
+            verity: self.verity().map(VerityConfigState::from),
+            encrypted: self.is_encrypted(),
+            nbd: self.nbd().cloned().map(NbdConfigState::from),
         }
     }
 
@@ -146,8 +421,16 @@ impl Persist<'_> for Block {
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
         let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
+        let is_discard_enabled =
+            state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD) != 0;
+        let num_queues = usize::from(state.num_queues);
         let rate_limiter =
             RateLimiter::restore((), &state.rate_limiter_state).map_err(Error::RateLimiter)?;
+        let encryption = match (state.encrypted, constructor_args.encryption) {
+            (true, None) => return Err(Error::MissingEncryptionKey),
+            (true, Some(encryption)) => Some(encryption),
+            (false, _) => None,
+        };
 
         let mut block = Block::new(
             state.id.clone(),
@@ -157,7 +440,20 @@ impl Persist<'_> for Block {
             is_disk_read_only,
             state.root_device,
             rate_limiter,
-            state.file_engine_type.into(),
+            // The engine is selected again, as the host may support different operations.
+            if state.auto_engine {
+                FileEngineType::Auto
+            } else {
+                state.file_engine_type.into()
+            },
+            state.io_engine_config.into(),
+            state.image_format.into(),
+            is_discard_enabled,
+            num_queues,
+            state.topology.into(),
+            state.verity.clone().map(VerityConfig::from),
+            encryption.clone(),
+            state.nbd.clone().map(NbdConfig::from),
         )
         .or_else(|err| match err {
             Error::FileEngine(io::Error::UnsupportedEngine(FileEngineType::Async)) => {
@@ -179,6 +475,14 @@ impl Persist<'_> for Block {
                     state.root_device,
                     rate_limiter,
                     FileEngineType::Sync,
+                    IoEngineConfig::default(),
+                    state.image_format.into(),
+                    is_discard_enabled,
+                    num_queues,
+                    state.topology.into(),
+                    state.verity.clone().map(VerityConfig::from),
+                    encryption,
+                    state.nbd.clone().map(NbdConfig::from),
                 )
             }
             other_err => Err(other_err),
@@ -186,7 +490,7 @@ impl Persist<'_> for Block {
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, num_queues, QUEUE_SIZE)
             .map_err(Error::Persist)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
@@ -203,11 +507,14 @@ impl Persist<'_> for Block {
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
     use std::sync::atomic::Ordering;
 
     use utils::tempfile::TempFile;
 
     use super::*;
+    use crate::virtio::block::test_utils::default_engine_type_for_kv;
+    use crate::virtio::block::verity::tests::build_hash_tree;
     use crate::virtio::device::VirtioDevice;
     use crate::virtio::test_utils::default_mem;
 
@@ -221,12 +528,17 @@ mod tests {
             CacheTypeState::Writeback,
             CacheTypeState::from(CacheType::Writeback)
         );
+        assert_eq!(
+            CacheTypeState::Direct,
+            CacheTypeState::from(CacheType::Direct)
+        );
     }
 
     #[test]
     fn test_cache_type_state_into() {
         assert_eq!(CacheType::Unsafe, CacheTypeState::Unsafe.into());
         assert_eq!(CacheType::Writeback, CacheTypeState::Writeback.into());
+        assert_eq!(CacheType::Direct, CacheTypeState::Direct.into());
     }
 
     #[test]
@@ -257,6 +569,14 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -273,6 +593,41 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_direct_cache_type_ser() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Writeback,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        // Older versions fall back to the `Writeback` cache type.
+        let mut block_state = <Block as Persist>::save(&block);
+        block_state.cache_type = CacheTypeState::Direct;
+        block_state.block_cache_type_ser(3).unwrap();
+        assert_eq!(block_state.cache_type, CacheTypeState::Writeback);
+
+        block_state.cache_type = CacheTypeState::Direct;
+        block_state.block_cache_type_ser(4).unwrap();
+        assert_eq!(block_state.cache_type, CacheTypeState::Direct);
+    }
+
     #[test]
     fn test_file_engine_type() {
         // Test conversions between FileEngineType and FileEngineTypeState.
@@ -311,6 +666,14 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 FileEngineType::Sync,
+                IoEngineConfig::default(),
+                ImageFormat::Raw,
+                false,
+                1,
+                BlockTopology::default(),
+                None,
+                None,
+                None,
             )
             .unwrap();
 
@@ -327,7 +690,10 @@ mod tests {
 
             // Restore the block device.
             let restored_block = Block::restore(
-                BlockConstructorArgs { mem: default_mem() },
+                BlockConstructorArgs {
+                    mem: default_mem(),
+                    encryption: None,
+                },
                 &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
             )
             .unwrap();
@@ -338,6 +704,460 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_auto_engine_state() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Auto,
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let selected = FileEngineType::Auto
+            .select(IoEngineConfig::default())
+            .unwrap();
+        assert!(block.is_auto_engine());
+        assert_eq!(block.file_engine_type(), selected);
+
+        let mut block_state = <Block as Persist>::save(&block);
+        assert!(block_state.auto_engine);
+        assert_eq!(block_state.file_engine_type, selected.into());
+        // Pretend that the snapshot was created on a host which selected the other engine.
+        block_state.file_engine_type = match selected {
+            FileEngineType::Async => FileEngineTypeState::Sync,
+            _ => FileEngineTypeState::Async,
+        };
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+
+        // The engine is selected again for the current host.
+        assert!(restored_block.is_auto_engine());
+        assert_eq!(restored_block.file_engine_type(), selected);
+    }
+
+    #[test]
+    fn test_image_format_state() {
+        assert_eq!(
+            ImageFormatState::Raw,
+            ImageFormatState::from(ImageFormat::Raw)
+        );
+        assert_eq!(
+            ImageFormatState::Qcow2,
+            ImageFormatState::from(ImageFormat::Qcow2)
+        );
+        assert_eq!(ImageFormat::Raw, ImageFormatState::Raw.into());
+        assert_eq!(ImageFormat::Qcow2, ImageFormatState::Qcow2.into());
+        assert_eq!(ImageFormatState::default(), ImageFormatState::Raw);
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Raw images can be serialized to any version.
+        let mut block_state = <Block as Persist>::save(&block);
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        // Qcow2 images can only be serialized to versions which know about the image format.
+        block_state.image_format = ImageFormatState::Qcow2;
+        assert!(block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(restored_state.image_format, ImageFormatState::Qcow2);
+    }
+
+    #[test]
+    fn test_num_queues_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            4,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Multiple queues can only be serialized to versions which know about them.
+        let block_state = <Block as Persist>::save(&block);
+        assert!(block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+
+        // Older snapshots default to a single queue.
+        assert_eq!(BlockState::default_num_queues(2), 1);
+    }
+
+    #[test]
+    fn test_io_engine_config_persistence() {
+        let config = IoEngineConfig {
+            registered_buffers: true,
+            sqpoll_idle_ms: None,
+        };
+        assert_eq!(
+            IoEngineConfig::from(IoEngineConfigState::from(config)),
+            config
+        );
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            default_engine_type_for_kv(),
+            config,
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        let block_state = <Block as Persist>::save(&block);
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.io_engine_config(), config);
+
+        // Older versions don't tune the engine.
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(
+            restored_state.io_engine_config,
+            IoEngineConfigState::default()
+        );
+    }
+
+    #[test]
+    fn test_topology_persistence() {
+        let topology = BlockTopology {
+            logical_block_size: Some(4096),
+            physical_block_size: None,
+            min_io_size: Some(8192),
+            opt_io_size: None,
+        };
+        assert_eq!(
+            BlockTopology::from(BlockTopologyState::from(topology)),
+            topology
+        );
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            topology,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        let block_state = <Block as Persist>::save(&block);
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.topology(), topology);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+
+        // Older versions don't advertise a topology.
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(restored_state.topology, BlockTopologyState::default());
+    }
+
+    #[test]
+    fn test_verity_persistence() {
+        let data = vec![0xaa; 0x4000];
+        let (root_hash, tree) = build_hash_tree(&data, &[], 4096, 4096);
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&data).unwrap();
+        f.as_file().write_all(&tree).unwrap();
+        let verity = VerityConfig {
+            root_hash,
+            hash_offset: data.len() as u64,
+            ..Default::default()
+        };
+        assert_eq!(
+            VerityConfig::from(VerityConfigState::from(verity.clone())),
+            verity
+        );
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            true,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            Some(verity.clone()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        let block_state = <Block as Persist>::save(&block);
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: None,
+            },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.verity(), Some(verity));
+        assert_eq!(restored_block.disk.nsectors(), block.disk.nsectors());
+
+        // Older versions would not verify the data.
+        assert!(block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_encryption_persistence() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let encryption = EncryptionConfig {
+            key: ("11".repeat(16) + &"22".repeat(16)).into(),
+        };
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            Some(encryption.clone()),
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        let block_state = <Block as Persist>::save(&block);
+        assert!(block_state.is_encrypted());
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        // The key is not part of the state.
+        assert!(!mem
+            .windows(encryption.key.len())
+            .any(|window| window == encryption.key.as_bytes()));
+
+        // The key has to be provided again.
+        let state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert!(matches!(
+            Block::restore(
+                BlockConstructorArgs {
+                    mem: default_mem(),
+                    encryption: None,
+                },
+                &state,
+            ),
+            Err(Error::MissingEncryptionKey)
+        ));
+        let restored_block = Block::restore(
+            BlockConstructorArgs {
+                mem: default_mem(),
+                encryption: Some(encryption),
+            },
+            &state,
+        )
+        .unwrap();
+        assert!(restored_block.is_encrypted());
+
+        // Older versions would expose the encrypted data.
+        assert!(block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+    }
+
     #[test]
     fn test_persistence() {
         // We create the backing file here so that it exists for the whole lifetime of the test.
@@ -354,6 +1174,14 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -368,7 +1196,10 @@ mod tests {
 
         // Restore the block device.
         let restored_block = Block::restore(
-            BlockConstructorArgs { mem: guest_mem },
+            BlockConstructorArgs {
+                mem: guest_mem,
+                encryption: None,
+            },
             &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
         )
         .unwrap();
@@ -10,22 +10,31 @@ use std::result;
 
 use logger::{error, IncMetric, METRICS};
 use rate_limiter::{RateLimiter, TokenType};
+use utils::time::{get_time_us, ClockType};
 pub use virtio_gen::virtio_blk::{
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
 };
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
-use super::{io as block_io, Error, SECTOR_SHIFT};
+use super::crypt::{self, XtsCipher};
+use super::trace::TraceRecord;
+use super::verity::{self, HashTree};
+use super::{
+    io as block_io, Error, MAX_DISCARD_SECTORS, MAX_MERGED_DATA_LEN, MAX_MERGED_REQUESTS,
+    SECTOR_SHIFT,
+};
 use crate::virtio::block::device::DiskProperties;
-use crate::virtio::SECTOR_SIZE;
 
 #[derive(Debug, derive_more::From)]
 pub enum IoErr {
     GetId(GuestMemoryError),
     PartialTransfer { completed: u32, expected: u32 },
     FileEngine(block_io::Error),
+    Verity(verity::Error),
+    Crypt(crypt::Error),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -34,6 +43,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -44,20 +55,40 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
 }
 
+impl From<RequestType> for u32 {
+    fn from(request_type: RequestType) -> u32 {
+        match request_type {
+            RequestType::In => VIRTIO_BLK_T_IN,
+            RequestType::Out => VIRTIO_BLK_T_OUT,
+            RequestType::Flush => VIRTIO_BLK_T_FLUSH,
+            RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+            RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+            RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
+            RequestType::Unsupported(id) => id,
+        }
+    }
+}
+
 pub enum ProcessingResult {
     Submitted,
     Throttled,
     Executed(FinishedRequest),
+    // The requests transferred by a single vectored operation, in order.
+    ExecutedMerged(Vec<FinishedRequest>),
 }
 
 pub struct FinishedRequest {
     pub num_bytes_to_mem: u32,
     pub desc_idx: u16,
+    // The trace of the request, when the device is traced.
+    pub trace: Option<TraceRecord>,
 }
 
 enum Status {
@@ -88,12 +119,40 @@ impl Status {
 
 pub struct PendingRequest {
     r#type: RequestType,
+    offset: u64,
+    data_addr: GuestAddress,
     data_len: u32,
     status_addr: GuestAddress,
     desc_idx: u16,
+    // The time at which the request was submitted, when the device is traced.
+    submit_time_us: Option<u64>,
+    // The requests following this one on the disk, which were transferred along with it.
+    merged: Vec<PendingRequest>,
 }
 
 impl PendingRequest {
+    /// Splits the result of the transfer between the request and the ones merged with it. The
+    /// transferred bytes are accounted to the requests in order, while an error is only reported
+    /// for the first request, the other ones completing with a partial transfer.
+    pub fn split(mut self, res: Result<u32, IoErr>) -> Vec<(PendingRequest, Result<u32, IoErr>)> {
+        let merged = std::mem::take(&mut self.merged);
+        let mut split = Vec::with_capacity(merged.len() + 1);
+        match res {
+            Ok(mut count) => {
+                for request in std::iter::once(self).chain(merged) {
+                    let request_count = std::cmp::min(count, request.data_len);
+                    count -= request_count;
+                    split.push((request, Ok(request_count)));
+                }
+            }
+            Err(err) => {
+                split.push((self, Err(err)));
+                split.extend(merged.into_iter().map(|request| (request, Ok(0))));
+            }
+        }
+        split
+    }
+
     fn write_status_and_finish(self, status: &Status, mem: &GuestMemoryMmap) -> FinishedRequest {
         let (num_bytes_to_mem, status_code) = match status {
             Status::Ok { num_bytes_to_mem } => (*num_bytes_to_mem, VIRTIO_BLK_S_OK),
@@ -127,16 +186,55 @@ impl PendingRequest {
                 0
             });
 
+        let trace = self.submit_time_us.map(|submit_time_us| TraceRecord {
+            request_type: u32::from(self.r#type),
+            sector: self.offset >> SECTOR_SHIFT,
+            len: self.data_len,
+            submit_time_us,
+            complete_time_us: get_time_us(ClockType::Monotonic),
+            status: status_code as u8,
+        });
+
         FinishedRequest {
             num_bytes_to_mem,
             desc_idx: self.desc_idx,
+            trace,
         }
     }
 
-    pub fn finish(self, mem: &GuestMemoryMmap, res: Result<u32, IoErr>) -> FinishedRequest {
+    /// Completes the request, checking the data read against the hash tree of the disk and
+    /// decrypting it, if needed.
+    pub fn finish(
+        self,
+        mem: &GuestMemoryMmap,
+        res: Result<u32, IoErr>,
+        hash_tree: Option<&mut HashTree>,
+        cipher: Option<&XtsCipher>,
+    ) -> FinishedRequest {
         let status = match (res, self.r#type) {
             (Ok(transferred_data_len), RequestType::In) => {
-                let status = Status::from_data(self.data_len, transferred_data_len, true);
+                let mut status = Status::from_data(self.data_len, transferred_data_len, true);
+                if let (Status::Ok { .. }, Some(hash_tree)) = (&status, hash_tree) {
+                    if let Err(err) =
+                        hash_tree.verify(mem, self.data_addr, self.offset, self.data_len)
+                    {
+                        METRICS.block.verity_fails.inc();
+                        status = Status::IoErr {
+                            num_bytes_to_mem: transferred_data_len,
+                            err: IoErr::Verity(err),
+                        };
+                    }
+                }
+                if let (Status::Ok { .. }, Some(cipher)) = (&status, cipher) {
+                    if let Err(err) =
+                        cipher.decrypt_mem(mem, self.data_addr, self.offset, self.data_len)
+                    {
+                        status = Status::IoErr {
+                            num_bytes_to_mem: transferred_data_len,
+                            err: IoErr::Crypt(err),
+                        };
+                    }
+                }
                 METRICS.block.read_bytes.add(transferred_data_len as usize);
                 if let Status::Ok { .. } = status {
                     METRICS.block.read_count.inc();
@@ -160,6 +258,20 @@ impl PendingRequest {
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
+            (Ok(_), RequestType::Discard) => {
+                METRICS.block.discard_bytes.add(self.data_len as usize);
+                METRICS.block.discard_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                METRICS.block.write_zeroes_bytes.add(self.data_len as usize);
+                METRICS.block.write_zeroes_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (_, RequestType::Unsupported(op)) => Status::Unsupported { op },
             (Err(err), _) => Status::IoErr {
                 num_bytes_to_mem: 0,
@@ -213,13 +325,38 @@ impl RequestHeader {
     }
 }
 
+/// A single range of sectors, as passed by the driver with discard and write zeroes requests.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct Request {
     pub r#type: RequestType,
+    // For discard and write zeroes requests, this is the length in bytes of the affected range.
     pub data_len: u32,
     pub status_addr: GuestAddress,
     sector: u64,
     data_addr: GuestAddress,
+    // Whether a write zeroes request allows the range to be deallocated.
+    unmap: bool,
 }
 
 impl Request {
@@ -227,6 +364,7 @@ impl Request {
         avail_desc: &DescriptorChain,
         mem: &GuestMemoryMmap,
         num_disk_sectors: u64,
+        alignment: u64,
     ) -> result::Result<Request, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -240,6 +378,7 @@ impl Request {
             data_addr: GuestAddress(0),
             data_len: 0,
             status_addr: GuestAddress(0),
+            unmap: false,
         };
 
         let data_desc;
@@ -260,7 +399,12 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.r#type == RequestType::Out {
+            if data_desc.is_write_only()
+                && matches!(
+                    req.r#type,
+                    RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+                )
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.r#type == RequestType::In {
@@ -277,11 +421,14 @@ impl Request {
         // check request validity
         match req.r#type {
             RequestType::In | RequestType::Out => {
-                // Check that the data length is a multiple of 512 as specified in the virtio
-                // standard.
-                if u64::from(req.data_len) % SECTOR_SIZE != 0 {
+                // Check that the data length is a multiple of the logical block size, which is
+                // itself a multiple of 512 as specified in the virtio standard.
+                if u64::from(req.data_len) % alignment != 0 {
                     return Err(Error::InvalidDataLength);
                 }
+                if req.sector % (alignment >> SECTOR_SHIFT) != 0 {
+                    return Err(Error::UnalignedOffset);
+                }
                 let top_sector = req
                     .sector
                     .checked_add(u64::from(req.data_len) >> SECTOR_SHIFT)
@@ -295,6 +442,46 @@ impl Request {
                     return Err(Error::InvalidDataLength);
                 }
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // We only advertise support for a single segment per request.
+                if req.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+                    return Err(Error::InvalidDataLength);
+                }
+                let segment: DiscardWriteZeroesSegment =
+                    mem.read_obj(req.data_addr).map_err(Error::GuestMemory)?;
+                if segment.num_sectors > MAX_DISCARD_SECTORS {
+                    return Err(Error::InvalidDataLength);
+                }
+                let top_sector = segment
+                    .sector
+                    .checked_add(u64::from(segment.num_sectors))
+                    .ok_or(Error::InvalidOffset)?;
+                if top_sector > num_disk_sectors {
+                    return Err(Error::InvalidOffset);
+                }
+
+                // The unmap flag is only valid for write zeroes requests. Any other flag must
+                // be reported back to the driver as unsupported.
+                let allowed_flags = match req.r#type {
+                    RequestType::WriteZeroes => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                    _ => 0,
+                };
+                if segment.flags & !allowed_flags != 0 {
+                    req.r#type = RequestType::Unsupported(u32::from(req.r#type));
+                }
+                // The range must cover whole logical blocks, which the driver is told about.
+                let block_sectors = alignment >> SECTOR_SHIFT;
+                if segment.sector % block_sectors != 0
+                    || u64::from(segment.num_sectors) % block_sectors != 0
+                {
+                    req.r#type = RequestType::Unsupported(u32::from(req.r#type));
+                }
+
+                req.sector = segment.sector;
+                // Can't overflow since `num_sectors` is at most `MAX_DISCARD_SECTORS`.
+                req.data_len = segment.num_sectors << SECTOR_SHIFT;
+                req.unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            }
             _ => {}
         }
 
@@ -318,8 +505,11 @@ impl Request {
         if !rate_limiter.consume(1, TokenType::Ops) {
             return true;
         }
-        // Exercise the rate limiter only if this request is of data transfer type.
-        if self.r#type == RequestType::In || self.r#type == RequestType::Out {
+        // Exercise the rate limiter only if this request covers a range of the disk.
+        if matches!(
+            self.r#type,
+            RequestType::In | RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+        ) {
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
             if !rate_limiter.consume(u64::from(self.data_len), TokenType::Bytes) {
@@ -336,68 +526,219 @@ impl Request {
         self.sector << SECTOR_SHIFT
     }
 
-    fn to_pending_request(&self, desc_idx: u16) -> PendingRequest {
+    fn to_pending_request(&self, desc_idx: u16, submit_time_us: Option<u64>) -> PendingRequest {
         PendingRequest {
             r#type: self.r#type,
+            offset: self.offset(),
+            data_addr: self.data_addr,
             data_len: self.data_len,
             status_addr: self.status_addr,
             desc_idx,
+            submit_time_us,
+            merged: Vec::new(),
         }
     }
 
+    // Whether the request can be transferred along with others, by a single vectored operation.
+    fn is_mergeable(&self, mem: &GuestMemoryMmap) -> bool {
+        matches!(self.r#type, RequestType::In | RequestType::Out)
+            // The data must be contiguous in the host memory.
+            && mem.get_slice(self.data_addr, self.data_len as usize).is_ok()
+    }
+
+    /// Executes the request, or submits it to the IO engine of the queue. The submission time
+    /// is only given when the request is traced.
     pub(crate) fn process(
-        self,
+        mut self,
         disk: &mut DiskProperties,
+        queue_index: usize,
         desc_idx: u16,
         mem: &GuestMemoryMmap,
+        submit_time_us: Option<u64>,
     ) -> ProcessingResult {
-        let pending = self.to_pending_request(desc_idx);
+        if matches!(self.r#type, RequestType::Discard | RequestType::WriteZeroes)
+            && !disk.is_discard_enabled()
+        {
+            // The driver sent a request for a feature that the device didn't offer.
+            self.r#type = RequestType::Unsupported(u32::from(self.r#type));
+        }
+
+        let pending = self.to_pending_request(desc_idx, submit_time_us);
         let res = match self.r#type {
-            RequestType::In => disk.file_engine_mut().read(
+            RequestType::In => disk.read(
+                queue_index,
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Out => disk.file_engine_mut().write(
+            RequestType::Out => disk.write(
+                queue_index,
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Flush => disk.file_engine_mut().flush(pending),
+            RequestType::Flush => disk.file_engine_mut(queue_index).flush(pending),
+            RequestType::Discard => disk.file_engine_mut(queue_index).discard(
+                self.offset(),
+                u64::from(self.data_len),
+                pending,
+            ),
+            RequestType::WriteZeroes => disk.file_engine_mut(queue_index).write_zeroes(
+                self.offset(),
+                u64::from(self.data_len),
+                self.unmap,
+                pending,
+            ),
             RequestType::GetDeviceID => {
                 let res = mem
                     .write_slice(disk.image_id(), self.data_addr)
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(IoErr::GetId);
-                return ProcessingResult::Executed(pending.finish(mem, res));
+                return ProcessingResult::Executed(pending.finish(mem, res, None, None));
             }
             RequestType::Unsupported(_) => {
-                return ProcessingResult::Executed(pending.finish(mem, Ok(0)));
+                return ProcessingResult::Executed(pending.finish(mem, Ok(0), None, None));
             }
         };
 
         match res {
             Ok(block_io::FileEngineOk::Submitted) => ProcessingResult::Submitted,
             Ok(block_io::FileEngineOk::Executed(res)) => {
-                ProcessingResult::Executed(res.user_data.finish(mem, Ok(res.count)))
+                let (hash_tree, cipher) = disk.hash_tree_and_cipher();
+                ProcessingResult::Executed(res.user_data.finish(
+                    mem,
+                    Ok(res.count),
+                    hash_tree,
+                    cipher,
+                ))
             }
             Err(err) => {
                 if err.error.is_throttling_err() {
                     ProcessingResult::Throttled
                 } else {
-                    ProcessingResult::Executed(
-                        err.user_data.finish(mem, Err(IoErr::FileEngine(err.error))),
-                    )
+                    ProcessingResult::Executed(err.user_data.finish(
+                        mem,
+                        Err(IoErr::FileEngine(err.error)),
+                        None,
+                        None,
+                    ))
                 }
             }
         }
     }
 }
 
+/// Read or write requests accessing contiguous sectors of the disk, which are transferred by a
+/// single vectored operation.
+pub(crate) struct MergedRequest {
+    // The requests, in the order of their sectors, along with the index of their descriptor chain
+    // and their submission time.
+    head: (Request, u16, Option<u64>),
+    tail: Vec<(Request, u16, Option<u64>)>,
+    // The sector following the last request.
+    end_sector: u64,
+    data_len: u32,
+}
+
+impl MergedRequest {
+    /// Starts merging requests after the given one, if it can be merged at all.
+    pub(crate) fn new(
+        request: Request,
+        desc_idx: u16,
+        submit_time_us: Option<u64>,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<Self, Request> {
+        if !request.is_mergeable(mem) {
+            return Err(request);
+        }
+        Ok(MergedRequest {
+            end_sector: request.sector + (u64::from(request.data_len) >> SECTOR_SHIFT),
+            data_len: request.data_len,
+            head: (request, desc_idx, submit_time_us),
+            tail: Vec::new(),
+        })
+    }
+
+    /// Appends the request, if it has the type of the merged requests and starts at the sector
+    /// following them.
+    pub(crate) fn merge(
+        &mut self,
+        request: Request,
+        desc_idx: u16,
+        submit_time_us: Option<u64>,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<(), Request> {
+        if request.r#type != self.head.0.r#type
+            || request.sector != self.end_sector
+            || self.tail.len() + 1 >= MAX_MERGED_REQUESTS
+            || self.data_len.saturating_add(request.data_len) > MAX_MERGED_DATA_LEN
+            || !request.is_mergeable(mem)
+        {
+            return Err(request);
+        }
+        self.end_sector += u64::from(request.data_len) >> SECTOR_SHIFT;
+        self.data_len += request.data_len;
+        self.tail.push((request, desc_idx, submit_time_us));
+        Ok(())
+    }
+
+    /// The number of merged requests.
+    pub(crate) fn len(&self) -> usize {
+        self.tail.len() + 1
+    }
+
+    /// Executes the merged requests, or submits them to the IO engine of the queue.
+    pub(crate) fn process(
+        self,
+        disk: &mut DiskProperties,
+        queue_index: usize,
+        mem: &GuestMemoryMmap,
+    ) -> ProcessingResult {
+        let (head, desc_idx, submit_time_us) = self.head;
+        if self.tail.is_empty() {
+            return head.process(disk, queue_index, desc_idx, mem, submit_time_us);
+        }
+
+        let segments: Vec<_> = std::iter::once(&head)
+            .chain(self.tail.iter().map(|(request, _, _)| request))
+            .map(|request| (request.data_addr, request.data_len))
+            .collect();
+        let mut pending = head.to_pending_request(desc_idx, submit_time_us);
+        pending.merged = self
+            .tail
+            .iter()
+            .map(|(request, desc_idx, submit_time_us)| {
+                request.to_pending_request(*desc_idx, *submit_time_us)
+            })
+            .collect();
+
+        let file_engine = disk.file_engine_mut(queue_index);
+        let res = match head.r#type {
+            RequestType::In => file_engine.readv(head.offset(), mem, &segments, pending),
+            _ => file_engine.writev(head.offset(), mem, &segments, pending),
+        };
+
+        let (pending, res) = match res {
+            Ok(block_io::FileEngineOk::Submitted) => return ProcessingResult::Submitted,
+            Ok(block_io::FileEngineOk::Executed(res)) => (res.user_data, Ok(res.count)),
+            Err(err) if err.error.is_throttling_err() => return ProcessingResult::Throttled,
+            Err(err) => (err.user_data, Err(IoErr::FileEngine(err.error))),
+        };
+        let (mut hash_tree, cipher) = disk.hash_tree_and_cipher();
+        ProcessingResult::ExecutedMerged(
+            pending
+                .split(res)
+                .into_iter()
+                .map(|(pending, res)| pending.finish(mem, res, hash_tree.as_deref_mut(), cipher))
+                .collect(),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
@@ -408,6 +749,7 @@ mod tests {
     use super::*;
     use crate::virtio::queue::tests::*;
     use crate::virtio::test_utils::{VirtQueue, VirtqDesc};
+    use crate::virtio::SECTOR_SIZE;
 
     const NUM_DISK_SECTORS: u64 = 1024;
 
@@ -423,6 +765,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -449,6 +793,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -519,15 +871,25 @@ mod tests {
         fn check_parse_err(&self, _e: Error) {
             let mut q = self.vq.create_queue();
             assert!(matches!(
-                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS),
+                Request::parse(
+                    &q.pop(self.mem).unwrap(),
+                    self.mem,
+                    NUM_DISK_SECTORS,
+                    SECTOR_SIZE
+                ),
                 Err(_e)
             ));
         }
 
         fn check_parse(&self, check_data: bool) {
             let mut q = self.vq.create_queue();
-            let request =
-                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS).unwrap();
+            let request = Request::parse(
+                &q.pop(self.mem).unwrap(),
+                self.mem,
+                NUM_DISK_SECTORS,
+                SECTOR_SIZE,
+            )
+            .unwrap();
             assert_eq!(request.r#type, RequestType::from(self.hdr().request_type));
             assert_eq!(request.sector, self.hdr().sector);
 
@@ -542,6 +904,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_merge_requests() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x20_0000)], false).unwrap();
+        let request = |r#type, sector, data_len| Request {
+            r#type,
+            data_len,
+            status_addr: GuestAddress(0x1000),
+            sector,
+            data_addr: GuestAddress(0x2000),
+            unmap: false,
+        };
+
+        // Only reads and writes of data in a single memory region are merged.
+        assert!(MergedRequest::new(request(RequestType::Flush, 0, 0), 0, None, mem).is_err());
+        let mut out_of_bounds = request(RequestType::In, 0, 0x1000);
+        out_of_bounds.data_addr = GuestAddress(0x20_0000 - 0x200);
+        assert!(MergedRequest::new(out_of_bounds, 0, None, mem).is_err());
+
+        let mut merged =
+            MergedRequest::new(request(RequestType::Out, 0, 0x200), 0, None, mem).unwrap();
+        assert_eq!(merged.len(), 1);
+        // The merged requests have the same type and follow each other.
+        assert!(merged
+            .merge(request(RequestType::Out, 2, 0x200), 1, None, mem)
+            .is_err());
+        assert!(merged
+            .merge(request(RequestType::In, 1, 0x200), 1, None, mem)
+            .is_err());
+        assert!(merged
+            .merge(request(RequestType::Out, 1, 0x400), 1, None, mem)
+            .is_ok());
+        assert!(merged
+            .merge(request(RequestType::Out, 3, 0x200), 2, None, mem)
+            .is_ok());
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged.end_sector, 4);
+
+        // The number of merged requests is limited.
+        for sector in 4..MAX_MERGED_REQUESTS as u64 + 1 {
+            assert!(merged
+                .merge(request(RequestType::Out, sector, 0x200), 0, None, mem)
+                .is_ok());
+        }
+        assert_eq!(merged.len(), MAX_MERGED_REQUESTS);
+        let sector = merged.end_sector;
+        assert!(merged
+            .merge(request(RequestType::Out, sector, 0x200), 0, None, mem)
+            .is_err());
+
+        // So is the length of the transfer.
+        let mut merged = MergedRequest::new(
+            request(RequestType::In, 0, MAX_MERGED_DATA_LEN - 0x200),
+            0,
+            None,
+            mem,
+        )
+        .unwrap();
+        let sector = merged.end_sector;
+        assert!(merged
+            .merge(request(RequestType::In, sector, 0x400), 1, None, mem)
+            .is_err());
+        assert!(merged
+            .merge(request(RequestType::In, sector, 0x200), 1, None, mem)
+            .is_ok());
+        assert_eq!(merged.data_len, MAX_MERGED_DATA_LEN);
+    }
+
+    #[test]
+    fn test_split_merged_result() {
+        let pending = |desc_idx, data_len| PendingRequest {
+            r#type: RequestType::In,
+            offset: 0,
+            data_addr: GuestAddress(0),
+            data_len,
+            status_addr: GuestAddress(0),
+            desc_idx,
+            submit_time_us: None,
+            merged: Vec::new(),
+        };
+        let split = |res| {
+            let mut head = pending(0, 0x200);
+            head.merged = vec![pending(1, 0x400), pending(2, 0x200)];
+            head.split(res)
+                .into_iter()
+                .map(|(pending, res)| (pending.desc_idx, res.ok()))
+                .collect::<Vec<_>>()
+        };
+
+        // A request which isn't merged gets the whole result.
+        let res = pending(0, 0x200).split(Ok(0x200));
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].1.as_ref().unwrap(), &0x200);
+
+        // The transferred bytes are accounted in order.
+        assert_eq!(
+            split(Ok(0x800)),
+            vec![(0, Some(0x200)), (1, Some(0x400)), (2, Some(0x200))]
+        );
+        assert_eq!(
+            split(Ok(0x400)),
+            vec![(0, Some(0x200)), (1, Some(0x200)), (2, Some(0))]
+        );
+        // Only the first request gets the error.
+        assert_eq!(
+            split(Err(IoErr::PartialTransfer {
+                completed: 0,
+                expected: 0x800
+            })),
+            vec![(0, None), (1, Some(0)), (2, Some(0))]
+        );
+    }
+
     #[test]
     fn test_parse_generic() {
         let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
@@ -623,6 +1097,37 @@ mod tests {
         queue.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_alignment() {
+        const ALIGNMENT: u64 = 4096;
+
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), mem);
+        let parse = |queue: &RequestVirtQueue| {
+            let mut q = queue.vq.create_queue();
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, ALIGNMENT)
+        };
+
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_OUT, 8);
+        queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+        queue.set_data_desc(0x2000, 0x1000, VIRTQ_DESC_F_NEXT);
+        queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+        assert!(parse(&queue).is_ok());
+
+        // The data length is a multiple of 512, but not of the logical block size.
+        queue.mut_data_desc().len.set(512);
+        assert!(matches!(parse(&queue), Err(Error::InvalidDataLength)));
+
+        // The first sector is not at a logical block boundary.
+        queue.mut_data_desc().len.set(0x1000);
+        queue.mut_hdr().sector = 9;
+        assert!(matches!(parse(&queue), Err(Error::UnalignedOffset)));
+
+        // Flush requests don't carry an offset.
+        queue.mut_hdr().request_type = VIRTIO_BLK_T_FLUSH;
+        assert!(parse(&queue).is_ok());
+    }
+
     #[test]
     fn test_parse_out() {
         let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
@@ -696,6 +1201,120 @@ mod tests {
         queue.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_discard_write_zeroes() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), mem);
+        let segment_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+        let segment_addr = GuestAddress(0x2000);
+
+        for request_type in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let request_header = RequestHeader::new(request_type, 0);
+            queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+            queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+
+            // Write only data descriptor.
+            queue.set_data_desc(
+                segment_addr.0,
+                segment_len,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            );
+            queue.check_parse_err(Error::UnexpectedWriteOnlyDescriptor);
+
+            // More than one segment.
+            queue.mut_data_desc().flags.set(VIRTQ_DESC_F_NEXT);
+            queue.mut_data_desc().len.set(2 * segment_len);
+            queue.check_parse_err(Error::InvalidDataLength);
+
+            // Range goes beyond the end of the disk.
+            queue.mut_data_desc().len.set(segment_len);
+            mem.write_obj(DiscardWriteZeroesSegment::new(1, 1024, 0), segment_addr)
+                .unwrap();
+            queue.check_parse_err(Error::InvalidOffset);
+
+            // Too many sectors.
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(0, MAX_DISCARD_SECTORS + 1, 0),
+                segment_addr,
+            )
+            .unwrap();
+            queue.check_parse_err(Error::InvalidDataLength);
+
+            // Valid segment.
+            mem.write_obj(DiscardWriteZeroesSegment::new(16, 8, 0), segment_addr)
+                .unwrap();
+            let mut q = queue.vq.create_queue();
+            let request =
+                Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, SECTOR_SIZE).unwrap();
+            assert_eq!(request.r#type, RequestType::from(request_type));
+            assert_eq!(request.sector, 16);
+            assert_eq!(request.data_len, 8 << SECTOR_SHIFT);
+            assert!(!request.unmap);
+        }
+
+        // The unmap flag is only supported for write zeroes requests.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(16, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            segment_addr,
+        )
+        .unwrap();
+        let mut q = queue.vq.create_queue();
+        let request =
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, SECTOR_SIZE).unwrap();
+        assert_eq!(request.r#type, RequestType::WriteZeroes);
+        assert!(request.unmap);
+
+        queue.mut_hdr().request_type = VIRTIO_BLK_T_DISCARD;
+        let mut q = queue.vq.create_queue();
+        let request =
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, SECTOR_SIZE).unwrap();
+        assert_eq!(
+            request.r#type,
+            RequestType::Unsupported(VIRTIO_BLK_T_DISCARD)
+        );
+
+        // The ranges must cover whole logical blocks.
+        let parse_aligned = |segment: DiscardWriteZeroesSegment| {
+            mem.write_obj(segment, segment_addr).unwrap();
+            let mut q = queue.vq.create_queue();
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, 4096).unwrap()
+        };
+        let request = parse_aligned(DiscardWriteZeroesSegment::new(16, 8, 0));
+        assert_eq!(request.r#type, RequestType::Discard);
+        for segment in [
+            DiscardWriteZeroesSegment::new(12, 8, 0),
+            DiscardWriteZeroesSegment::new(16, 4, 0),
+        ] {
+            assert_eq!(
+                parse_aligned(segment).r#type,
+                RequestType::Unsupported(VIRTIO_BLK_T_DISCARD)
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_discard_write_zeroes() {
+        for r#type in [RequestType::Discard, RequestType::WriteZeroes] {
+            let request = Request {
+                r#type,
+                data_len: 8 << SECTOR_SHIFT,
+                status_addr: GuestAddress(0),
+                sector: 0,
+                data_addr: GuestAddress(0),
+                unmap: false,
+            };
+            // The covered range consumes the bytes budget, like reads and writes.
+            let mut rate_limiter =
+                RateLimiter::new(8 << SECTOR_SHIFT, 0, 100_000, 0, 0, 0).unwrap();
+            assert!(!request.rate_limit(&mut rate_limiter));
+            assert!(request.rate_limit(&mut rate_limiter));
+
+            let mut rate_limiter = RateLimiter::new(0, 0, 0, 1, 0, 100_000).unwrap();
+            assert!(!request.rate_limit(&mut rate_limiter));
+            assert!(request.rate_limit(&mut rate_limiter));
+        }
+    }
+
     use std::convert::TryInto;
 
     /// -------------------------------------
@@ -738,27 +1357,15 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
         }
     }
 
-    impl From<RequestType> for u32 {
-        fn from(request_type: RequestType) -> u32 {
-            match request_type {
-                RequestType::In => VIRTIO_BLK_T_IN,
-                RequestType::Out => VIRTIO_BLK_T_OUT,
-                RequestType::Flush => VIRTIO_BLK_T_FLUSH,
-                RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
-                RequestType::Unsupported(id) => id,
-            }
-        }
-    }
-
     // Returns flags based on the request type.
     fn request_type_flags(request_type: RequestType) -> u16 {
         match request_type {
@@ -766,6 +1373,8 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard => VIRTQ_DESC_F_NEXT,
+            RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -859,6 +1468,7 @@ mod tests {
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
             data_addr,
+            unmap: false,
         };
         let request_header = RequestHeader::new(virtio_request_id, request.sector);
 
@@ -982,7 +1592,7 @@ mod tests {
     fn parse_random_requests() {
         let cfg = ProptestConfig::with_cases(1000);
         proptest!(cfg, |(mut request in random_request_parse())| {
-            let result = Request::parse(&request.2.pop(&request.1).unwrap(), &request.1, NUM_DISK_SECTORS);
+            let result = Request::parse(&request.2.pop(&request.1).unwrap(), &request.1, NUM_DISK_SECTORS, SECTOR_SIZE);
             match result {
                 Ok(r) => prop_assert!(r == request.0.unwrap()),
                 Err(err) => {
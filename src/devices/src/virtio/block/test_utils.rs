@@ -12,12 +12,12 @@ use rate_limiter::RateLimiter;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use utils::tempfile::TempFile;
 
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{BlockTopology, FileEngineType, IoEngineConfig};
 #[cfg(test)]
 use crate::virtio::block::io::FileEngine;
 #[cfg(test)]
 use crate::virtio::IrqType;
-use crate::virtio::{Block, CacheType, Queue};
+use crate::virtio::{Block, CacheType, ImageFormat, Queue};
 
 /// Create a default Block instance to be used in tests.
 pub fn default_block(file_engine_type: FileEngineType) -> Block {
@@ -53,6 +53,14 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
         false,
         rate_limiter,
         file_engine_type,
+        IoEngineConfig::default(),
+        ImageFormat::Raw,
+        false,
+        1,
+        BlockTopology::default(),
+        None,
+        None,
+        None,
     )
     .unwrap()
 }
@@ -74,7 +82,7 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -83,13 +91,21 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
 
 #[cfg(test)]
 pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
-    if let FileEngine::Async(engine) = b.disk.file_engine_mut() {
-        // Wait for all the async operations to complete.
-        engine.drain(false).unwrap();
-        // Wait for the async completion event to be sent.
-        thread::sleep(Duration::from_millis(150));
-        // Handle event.
-        b.process_async_completion_event();
+    match b.disk.file_engine_mut(0) {
+        FileEngine::Async(engine) => {
+            // Wait for all the async operations to complete.
+            engine.drain(false).unwrap();
+            // Wait for the async completion event to be sent.
+            thread::sleep(Duration::from_millis(150));
+            // Handle event.
+            b.process_async_completion_event(0);
+        }
+        FileEngine::Nbd(engine) => {
+            // Wait for the replies of the server.
+            engine.drain(false).unwrap();
+            b.process_async_completion_event(0);
+        }
+        FileEngine::Sync(_) => {}
     }
 
     // Validate if there are pending IRQs.
@@ -98,8 +114,8 @@ pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
 
 #[cfg(test)]
 pub fn simulate_queue_and_async_completion_events(b: &mut Block, expected_irq: bool) {
-    match b.disk.file_engine_mut() {
-        FileEngine::Async(_) => {
+    match b.disk.file_engine_mut(0) {
+        FileEngine::Async(_) | FileEngine::Nbd(_) => {
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
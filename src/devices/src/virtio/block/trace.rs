@@ -0,0 +1,291 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binary trace of the requests processed by a block device.
+//!
+//! A trace starts with a 16-byte header, made of the `TRACE_MAGIC` bytes followed by the format
+//! version and 4 reserved bytes. It is followed by one fixed size record per completed request,
+//! in completion order. All the integers are stored in little-endian.
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 8    | First sector accessed by the request    |
+//! | 8      | 8    | Submission time, in microseconds        |
+//! | 16     | 8    | Completion time, in microseconds        |
+//! | 24     | 4    | Virtio type of the request              |
+//! | 28     | 4    | Length of the data, in bytes            |
+//! | 32     | 1    | Virtio status of the request            |
+//! | 33     | 7    | Reserved                                |
+//!
+//! The times are read from the monotonic clock of the host, so only their differences are
+//! meaningful.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::result;
+
+use serde::Deserialize;
+
+// The bytes every trace starts with.
+pub const TRACE_MAGIC: [u8; 8] = *b"FCBTRACE";
+// The version of the format of the records.
+pub const TRACE_VERSION: u32 = 1;
+// The size of the header of a trace.
+pub const TRACE_HEADER_SIZE: usize = 16;
+// The size of a single record.
+pub const TRACE_RECORD_SIZE: usize = 40;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file doesn't start with a trace header.
+    InvalidHeader,
+    /// No path was given to write the trace to.
+    MissingPath,
+    /// The trace ends in the middle of a record.
+    TruncatedRecord,
+    /// The trace was written in an unsupported format version.
+    UnsupportedVersion(u32),
+    /// Failed to access the trace file.
+    Io(io::Error),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Configuration of the tracing of the requests processed by a drive.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceConfig {
+    /// Whether the requests processed by the drive are traced.
+    pub enabled: bool,
+    /// Path of the file the trace is written to, required when tracing is enabled. The file is
+    /// truncated when tracing starts.
+    pub path_on_host: Option<String>,
+}
+
+/// A single traced request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// The virtio type of the request.
+    pub request_type: u32,
+    /// The first sector accessed by the request.
+    pub sector: u64,
+    /// The length of the data of the request, in bytes.
+    pub len: u32,
+    /// The time at which the request was submitted, in microseconds.
+    pub submit_time_us: u64,
+    /// The time at which the request completed, in microseconds.
+    pub complete_time_us: u64,
+    /// The virtio status the request completed with.
+    pub status: u8,
+}
+
+impl TraceRecord {
+    pub fn to_bytes(&self) -> [u8; TRACE_RECORD_SIZE] {
+        let mut bytes = [0u8; TRACE_RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.sector.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.submit_time_us.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.complete_time_us.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.request_type.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.len.to_le_bytes());
+        bytes[32] = self.status;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; TRACE_RECORD_SIZE]) -> Self {
+        // The slices have the exact size of the integers, so the conversions can't fail.
+        TraceRecord {
+            sector: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            submit_time_us: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            complete_time_us: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            request_type: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            len: u32::from_le_bytes(bytes[28..32].try_into().unwrap()),
+            status: bytes[32],
+        }
+    }
+
+    /// The time it took to complete the request, in microseconds.
+    pub fn latency_us(&self) -> u64 {
+        self.complete_time_us.saturating_sub(self.submit_time_us)
+    }
+}
+
+fn header() -> [u8; TRACE_HEADER_SIZE] {
+    let mut header = [0u8; TRACE_HEADER_SIZE];
+    header[0..8].copy_from_slice(&TRACE_MAGIC);
+    header[8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+    header
+}
+
+/// Writes the records of a trace to a file. The records are buffered, and only reach the file
+/// once enough of them were collected, or when the writer is flushed or dropped.
+pub struct TraceWriter {
+    file: BufWriter<File>,
+}
+
+impl TraceWriter {
+    /// Creates the trace file at `path`, truncating it if it exists.
+    pub fn new(path: &str) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path).map_err(Error::Io)?);
+        file.write_all(&header()).map_err(Error::Io)?;
+        Ok(TraceWriter { file })
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> Result<()> {
+        self.file.write_all(&record.to_bytes()).map_err(Error::Io)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush().map_err(Error::Io)
+    }
+}
+
+/// Iterates over the records of a trace.
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the header of the trace read from `reader`.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; TRACE_HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => Error::InvalidHeader,
+                _ => Error::Io(err),
+            })?;
+        if header[0..8] != TRACE_MAGIC {
+            return Err(Error::InvalidHeader);
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != TRACE_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        Ok(TraceReader { reader })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = [0u8; TRACE_RECORD_SIZE];
+        let mut len = 0;
+        while len < TRACE_RECORD_SIZE {
+            match self.reader.read(&mut bytes[len..]) {
+                Ok(0) => break,
+                Ok(count) => len += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Some(Err(Error::Io(err))),
+            }
+        }
+
+        match len {
+            0 => None,
+            TRACE_RECORD_SIZE => Some(Ok(TraceRecord::from_bytes(&bytes))),
+            // A trace whose writer didn't get to flush it may end with a partial record.
+            _ => Some(Err(Error::TruncatedRecord)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    fn record(sector: u64) -> TraceRecord {
+        TraceRecord {
+            request_type: 1,
+            sector,
+            len: 4096,
+            submit_time_us: 1000 + sector,
+            complete_time_us: 1250 + sector,
+            status: 0,
+        }
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        let record = TraceRecord {
+            request_type: 0x0102_0304,
+            sector: 0x1122_3344_5566_7788,
+            len: 0x0a0b_0c0d,
+            submit_time_us: 10,
+            complete_time_us: 25,
+            status: 2,
+        };
+        let bytes = record.to_bytes();
+        assert_eq!(
+            bytes[0..8],
+            [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+        assert_eq!(bytes[24..28], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(bytes[32..], [2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(TraceRecord::from_bytes(&bytes), record);
+        assert_eq!(record.latency_us(), 15);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+        let mut writer = TraceWriter::new(path).unwrap();
+        for sector in 0..3 {
+            writer.record(&record(sector)).unwrap();
+        }
+        // The records are only written on flush.
+        assert_eq!(
+            file.as_file().metadata().unwrap().len(),
+            TRACE_HEADER_SIZE as u64
+        );
+        writer.flush().unwrap();
+        assert_eq!(
+            file.as_file().metadata().unwrap().len(),
+            (TRACE_HEADER_SIZE + 3 * TRACE_RECORD_SIZE) as u64
+        );
+
+        let reader = TraceReader::new(File::open(path).unwrap()).unwrap();
+        let records = reader.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records, vec![record(0), record(1), record(2)]);
+
+        // Starting a new trace truncates the file.
+        let mut writer = TraceWriter::new(path).unwrap();
+        writer.record(&record(7)).unwrap();
+        drop(writer);
+        let reader = TraceReader::new(File::open(path).unwrap()).unwrap();
+        let records = reader.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records, vec![record(7)]);
+    }
+
+    #[test]
+    fn test_invalid_trace() {
+        assert!(matches!(
+            TraceReader::new(Cursor::new(Vec::new())),
+            Err(Error::InvalidHeader)
+        ));
+        assert!(matches!(
+            TraceReader::new(Cursor::new(vec![0u8; TRACE_HEADER_SIZE])),
+            Err(Error::InvalidHeader)
+        ));
+
+        let mut trace = header().to_vec();
+        trace[8] = 2;
+        assert!(matches!(
+            TraceReader::new(Cursor::new(trace)),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        let mut trace = header().to_vec();
+        trace.extend_from_slice(&record(0).to_bytes());
+        trace.extend_from_slice(&record(1).to_bytes()[..10]);
+        let mut reader = TraceReader::new(Cursor::new(trace)).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), record(0));
+        assert!(matches!(reader.next(), Some(Err(Error::TruncatedRecord))));
+        assert!(reader.next().is_none());
+    }
+}
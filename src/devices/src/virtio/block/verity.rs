@@ -0,0 +1,550 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Integrity verification of read-only disks against a dm-verity hash tree.
+//!
+//! The disk is split into data blocks, whose SHA-256 digests are stored in the hash blocks of the
+//! lowest level of the tree. The digests of the hash blocks of each level are stored in turn in
+//! the level above it, up to a single hash block whose digest is the root hash. Every digest is
+//! computed over the salt followed by the contents of the block. The levels are stored from the
+//! top one down, as laid out by `veritysetup` (format version 1).
+//!
+//! The data read by the guest is checked once the read completes, reading the hash blocks on its
+//! path through the tree as needed. A hash block is only trusted, and cached, once its own digest
+//! was checked against the level above it, so that the root hash is the only value which has to
+//! come from a trusted source.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::{cmp, result};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::{MAX_LOGICAL_BLOCK_SIZE, SECTOR_SIZE};
+
+// The size of a SHA-256 digest.
+const DIGEST_SIZE: usize = 32;
+// The data and hash block size used when none is configured.
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+// The maximum number of verified hash blocks kept in memory. With the default block sizes, the
+// hash blocks of the lowest level cover 512 MiB of data.
+const MAX_CACHED_HASH_BLOCKS: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A data block doesn't match its digest.
+    DataMismatch(u64),
+    /// The hash tree extends past the end of the hash file.
+    HashFileTooSmall,
+    /// A hash block doesn't match its digest.
+    HashMismatch(u64),
+    /// The block size is not a power of two between 512 and 4096 bytes.
+    InvalidBlockSize(u32),
+    /// The hash offset is not a multiple of the hash block size.
+    InvalidHashOffset(u64),
+    /// The root hash is not a hex encoded SHA-256 digest.
+    InvalidRootHash,
+    /// The salt is not hex encoded.
+    InvalidSalt,
+    /// Failed to access the hash file.
+    Io(io::Error),
+    /// The data area doesn't contain a single data block.
+    NoDataBlocks,
+    /// Failed to read the data from guest memory.
+    Transfer(GuestMemoryError),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Configuration of the integrity verification of a read-only drive.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerityConfig {
+    /// The hex encoded digest of the top level hash block.
+    pub root_hash: String,
+    /// The hex encoded salt, which is hashed before the contents of every block.
+    pub salt: Option<String>,
+    /// Path of the file holding the hash tree. Defaults to the disk image itself.
+    pub hash_path: Option<String>,
+    /// Offset of the first hash block in the hash file, in bytes. When the hash tree is stored
+    /// in the disk image, the data exposed to the guest ends at this offset.
+    #[serde(default)]
+    pub hash_offset: u64,
+    /// The size of the data blocks, in bytes. It becomes the logical block size of the drive.
+    pub data_block_size: Option<u32>,
+    /// The size of the hash blocks, in bytes.
+    pub hash_block_size: Option<u32>,
+}
+
+/// A dm-verity hash tree, along with the hash blocks which were already verified.
+pub struct HashTree {
+    config: VerityConfig,
+    file: File,
+    root_hash: [u8; DIGEST_SIZE],
+    salt: Vec<u8>,
+    data_block_size: u64,
+    hash_block_size: u64,
+    data_blocks: u64,
+    // Each hash block holds 2^hash_per_block_bits digests.
+    hash_per_block_bits: u32,
+    // The index in the hash file of the first hash block of each level. Level 0 holds the
+    // digests of the data blocks.
+    level_start: Vec<u64>,
+    // The verified hash blocks by index in the hash file, and the order they were added in.
+    cache: HashMap<u64, Vec<u8>>,
+    cache_order: VecDeque<u64>,
+}
+
+impl HashTree {
+    /// Opens the hash tree described by `config`, for the disk image found at
+    /// `disk_image_path` and whose size is `disk_size`.
+    pub fn new(config: VerityConfig, disk_image_path: &str, disk_size: u64) -> Result<Self> {
+        let data_block_size = block_size(config.data_block_size)?;
+        let hash_block_size = block_size(config.hash_block_size)?;
+        let root_hash = parse_hex(&config.root_hash)
+            .and_then(|root_hash| root_hash.try_into().ok())
+            .ok_or(Error::InvalidRootHash)?;
+        let salt = match config.salt {
+            Some(ref salt) => parse_hex(salt).ok_or(Error::InvalidSalt)?,
+            None => Vec::new(),
+        };
+        if config.hash_offset % hash_block_size != 0 {
+            return Err(Error::InvalidHashOffset(config.hash_offset));
+        }
+
+        let (mut file, data_size) = match config.hash_path {
+            Some(ref hash_path) => (File::open(hash_path).map_err(Error::Io)?, disk_size),
+            // The hash tree follows the data in the disk image.
+            None => (
+                File::open(disk_image_path).map_err(Error::Io)?,
+                cmp::min(config.hash_offset, disk_size),
+            ),
+        };
+        let data_blocks = data_size / data_block_size;
+        if data_blocks == 0 {
+            return Err(Error::NoDataBlocks);
+        }
+
+        // Each level holds the digests of the blocks of the level below it, and the top level
+        // fits in a single hash block.
+        let hash_per_block_bits = (hash_block_size / DIGEST_SIZE as u64).trailing_zeros();
+        let mut level_sizes = Vec::new();
+        let mut num_blocks = data_blocks;
+        while num_blocks > 1 {
+            num_blocks = (num_blocks + (1 << hash_per_block_bits) - 1) >> hash_per_block_bits;
+            level_sizes.push(num_blocks);
+        }
+        let mut level_start = vec![0; level_sizes.len()];
+        let mut hash_block = config.hash_offset / hash_block_size;
+        for (level, num_blocks) in level_sizes.iter().enumerate().rev() {
+            level_start[level] = hash_block;
+            hash_block += num_blocks;
+        }
+
+        let hash_file_size = file.seek(SeekFrom::End(0)).map_err(Error::Io)?;
+        if hash_block
+            .checked_mul(hash_block_size)
+            .map_or(true, |hash_end| hash_end > hash_file_size)
+        {
+            return Err(Error::HashFileTooSmall);
+        }
+
+        Ok(Self {
+            config,
+            file,
+            root_hash,
+            salt,
+            data_block_size,
+            hash_block_size,
+            data_blocks,
+            hash_per_block_bits,
+            level_start,
+            cache: HashMap::new(),
+            cache_order: VecDeque::new(),
+        })
+    }
+
+    /// Returns the configuration of the hash tree.
+    pub fn config(&self) -> &VerityConfig {
+        &self.config
+    }
+
+    /// Returns the size of the data blocks.
+    pub fn data_block_size(&self) -> u64 {
+        self.data_block_size
+    }
+
+    /// Returns the size of the data covered by the hash tree.
+    pub fn data_size(&self) -> u64 {
+        self.data_blocks * self.data_block_size
+    }
+
+    /// Checks the `len` bytes read from the disk at `offset` into the guest memory at `addr`
+    /// against the hash tree. Both `offset` and `len` must be multiples of the data block size.
+    pub fn verify(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        offset: u64,
+        len: u32,
+    ) -> Result<()> {
+        let mut data = vec![0u8; self.data_block_size as usize];
+        let first_block = offset / self.data_block_size;
+        for i in 0..u64::from(len) / self.data_block_size {
+            mem.read_slice(&mut data, addr.unchecked_add(i * self.data_block_size))
+                .map_err(Error::Transfer)?;
+            let block = first_block + i;
+            if self.digest(&data) != self.expected_digest(0, block)? {
+                return Err(Error::DataMismatch(block));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the expected digest of the block found at `index` in the level below `level`, the
+    // data blocks being below level 0.
+    fn expected_digest(&mut self, level: usize, index: u64) -> Result<[u8; DIGEST_SIZE]> {
+        // The top level consists of a single hash block, whose digest is the root hash.
+        if level == self.level_start.len() {
+            return Ok(self.root_hash);
+        }
+
+        let position = index >> self.hash_per_block_bits;
+        let hash_block = self.level_start[level] + position;
+        if !self.cache.contains_key(&hash_block) {
+            let mut contents = vec![0u8; self.hash_block_size as usize];
+            self.file
+                .read_exact_at(&mut contents, hash_block * self.hash_block_size)
+                .map_err(Error::Io)?;
+            if self.digest(&contents) != self.expected_digest(level + 1, position)? {
+                return Err(Error::HashMismatch(hash_block));
+            }
+            self.insert(hash_block, contents);
+        }
+
+        let offset = (index & ((1 << self.hash_per_block_bits) - 1)) as usize * DIGEST_SIZE;
+        let mut digest = [0u8; DIGEST_SIZE];
+        digest.copy_from_slice(&self.cache[&hash_block][offset..offset + DIGEST_SIZE]);
+        Ok(digest)
+    }
+
+    fn digest(&self, contents: &[u8]) -> [u8; DIGEST_SIZE] {
+        Sha256::new()
+            .chain_update(&self.salt)
+            .chain_update(contents)
+            .finalize()
+            .into()
+    }
+
+    // Caches a verified hash block, evicting the oldest one if the cache is full.
+    fn insert(&mut self, hash_block: u64, contents: Vec<u8>) {
+        if self.cache_order.len() == MAX_CACHED_HASH_BLOCKS {
+            if let Some(oldest) = self.cache_order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+        self.cache_order.push_back(hash_block);
+        self.cache.insert(hash_block, contents);
+    }
+}
+
+fn block_size(block_size: Option<u32>) -> Result<u64> {
+    let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    if !block_size.is_power_of_two()
+        || !(SECTOR_SIZE as u32..=MAX_LOGICAL_BLOCK_SIZE).contains(&block_size)
+    {
+        return Err(Error::InvalidBlockSize(block_size));
+    }
+    Ok(u64::from(block_size))
+}
+
+pub(crate) fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Builds the hash tree of `data`, returning the hex encoded root hash and the hash blocks.
+    pub(crate) fn build_hash_tree(
+        data: &[u8],
+        salt: &[u8],
+        data_block_size: usize,
+        hash_block_size: usize,
+    ) -> (String, Vec<u8>) {
+        let digest = |block: &[u8]| -> Vec<u8> {
+            Sha256::new()
+                .chain_update(salt)
+                .chain_update(block)
+                .finalize()
+                .to_vec()
+        };
+
+        // Hash the data blocks, then each level in turn until a single block is left.
+        let mut levels: Vec<Vec<u8>> = Vec::new();
+        let mut blocks: Vec<Vec<u8>> = data.chunks(data_block_size).map(Vec::from).collect();
+        while blocks.len() > 1 {
+            let mut level: Vec<u8> = blocks.iter().flat_map(|block| digest(block)).collect();
+            let padded_len = (level.len() + hash_block_size - 1) / hash_block_size;
+            level.resize(padded_len * hash_block_size, 0);
+            blocks = level.chunks(hash_block_size).map(Vec::from).collect();
+            levels.push(level);
+        }
+        let root_hash = to_hex(&digest(&blocks[0]));
+
+        // The top level comes first.
+        (root_hash, levels.into_iter().rev().flatten().collect())
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex(""), Some(vec![]));
+        assert_eq!(parse_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("+f"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_build_hash_tree() {
+        // 40 data blocks, with 16 digests per hash block, need 3 hash blocks on the lowest level
+        // and a single one on the top level.
+        let data: Vec<u8> = (0..40).flat_map(|i| vec![i as u8; 512]).collect();
+        let (root_hash, tree) = build_hash_tree(&data, &[0x12, 0x34], 512, 512);
+        assert_eq!(tree.len(), 4 * 512);
+        // Computed independently, following the layout of `veritysetup format`.
+        assert_eq!(
+            root_hash,
+            "27b7825132f6dfe301468d7bed222847640808b40d7cd3ae67ca9227b1b2bbac"
+        );
+
+        // A single data block is hashed directly into the root hash.
+        let (root_hash, tree) = build_hash_tree(&data[..512], &[], 512, 512);
+        assert!(tree.is_empty());
+        assert_eq!(
+            root_hash,
+            to_hex(&Sha256::new().chain_update(&data[..512]).finalize())
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let data = vec![0u8; 0x4000];
+        let (root_hash, tree) = build_hash_tree(&data, &[], 4096, 4096);
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&data).unwrap();
+        f.as_file().write_all(&tree).unwrap();
+        let path = f.as_path().to_str().unwrap();
+        let config = VerityConfig {
+            root_hash,
+            hash_offset: data.len() as u64,
+            ..Default::default()
+        };
+        let disk_size = (data.len() + tree.len()) as u64;
+        let new_hash_tree = |config: &VerityConfig| HashTree::new(config.clone(), path, disk_size);
+
+        let hash_tree = new_hash_tree(&config).unwrap();
+        assert_eq!(hash_tree.data_size(), data.len() as u64);
+        assert_eq!(hash_tree.data_block_size(), 4096);
+
+        for block_size in [256, 1000, 8192] {
+            assert!(matches!(
+                new_hash_tree(&VerityConfig {
+                    data_block_size: Some(block_size),
+                    ..config.clone()
+                }),
+                Err(Error::InvalidBlockSize(size)) if size == block_size
+            ));
+            assert!(matches!(
+                new_hash_tree(&VerityConfig {
+                    hash_block_size: Some(block_size),
+                    ..config.clone()
+                }),
+                Err(Error::InvalidBlockSize(size)) if size == block_size
+            ));
+        }
+        for root_hash in [
+            String::new(),
+            "00".to_string(),
+            "0".repeat(66),
+            "g".repeat(64),
+        ] {
+            assert!(matches!(
+                new_hash_tree(&VerityConfig {
+                    root_hash,
+                    ..config.clone()
+                }),
+                Err(Error::InvalidRootHash)
+            ));
+        }
+        assert!(matches!(
+            new_hash_tree(&VerityConfig {
+                salt: Some("0".to_string()),
+                ..config.clone()
+            }),
+            Err(Error::InvalidSalt)
+        ));
+        assert!(matches!(
+            new_hash_tree(&VerityConfig {
+                hash_offset: 512,
+                ..config.clone()
+            }),
+            Err(Error::InvalidHashOffset(512))
+        ));
+        assert!(matches!(
+            new_hash_tree(&VerityConfig {
+                hash_offset: 0,
+                ..config.clone()
+            }),
+            Err(Error::NoDataBlocks)
+        ));
+        assert!(matches!(
+            new_hash_tree(&VerityConfig {
+                hash_path: Some("/nonexistent".to_string()),
+                ..config.clone()
+            }),
+            Err(Error::Io(_))
+        ));
+        // When the data spans the whole file, there is no room left for the hash tree.
+        assert!(matches!(
+            HashTree::new(
+                VerityConfig {
+                    hash_offset: 0x5000,
+                    ..config.clone()
+                },
+                path,
+                0x5000
+            ),
+            Err(Error::HashFileTooSmall)
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let data: Vec<u8> = (0..40u8).flat_map(|i| vec![i; 512]).collect();
+        let salt = [0xab; 32];
+        let (root_hash, tree) = build_hash_tree(&data, &salt, 512, 512);
+        let data_file = TempFile::new().unwrap();
+        data_file.as_file().write_all(&data).unwrap();
+        // The hash tree is stored in a separate file, after a block for the superblock.
+        let hash_file = TempFile::new().unwrap();
+        hash_file.as_file().write_all(&[0; 512]).unwrap();
+        hash_file.as_file().write_all(&tree).unwrap();
+        let config = VerityConfig {
+            root_hash,
+            salt: Some(to_hex(&salt)),
+            hash_path: Some(hash_file.as_path().to_str().unwrap().to_string()),
+            hash_offset: 512,
+            data_block_size: Some(512),
+            hash_block_size: Some(512),
+        };
+        let mut hash_tree = HashTree::new(
+            config.clone(),
+            data_file.as_path().to_str().unwrap(),
+            data.len() as u64,
+        )
+        .unwrap();
+        assert_eq!(hash_tree.config(), &config);
+        assert_eq!(hash_tree.data_size(), data.len() as u64);
+
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let addr = GuestAddress(0x1000);
+        mem.write_slice(&data, addr).unwrap();
+        hash_tree.verify(&mem, addr, 0, data.len() as u32).unwrap();
+        assert_eq!(hash_tree.cache.len(), 4);
+
+        // The modified data blocks are detected.
+        mem.write_obj(0xffu8, GuestAddress(0x1000 + 5 * 512 + 7))
+            .unwrap();
+        assert!(matches!(
+            hash_tree.verify(&mem, GuestAddress(0x1000 + 4 * 512), 4 * 512, 2 * 512),
+            Err(Error::DataMismatch(5))
+        ));
+        hash_tree.verify(&mem, addr, 0, 4 * 512).unwrap();
+
+        // The verified hash blocks are served from the cache.
+        hash_file.as_file().set_len(0).unwrap();
+        hash_tree
+            .verify(&mem, GuestAddress(0x1000 + 39 * 512), 39 * 512, 512)
+            .unwrap();
+
+        // The modified hash blocks are detected.
+        let mut tampered_tree = tree.clone();
+        tampered_tree[512 + 100] ^= 1;
+        hash_file.as_file().write_all_at(&[0; 512], 0).unwrap();
+        hash_file
+            .as_file()
+            .write_all_at(&tampered_tree, 512)
+            .unwrap();
+        let mut hash_tree = HashTree::new(
+            config,
+            data_file.as_path().to_str().unwrap(),
+            data.len() as u64,
+        )
+        .unwrap();
+        mem.write_slice(&data, addr).unwrap();
+        // The second hash block of the file is the first one of the lowest level.
+        assert!(matches!(
+            hash_tree.verify(&mem, addr, 0, 512),
+            Err(Error::HashMismatch(2))
+        ));
+        // The other hash blocks of the lowest level are still trusted.
+        hash_tree
+            .verify(&mem, GuestAddress(0x1000 + 16 * 512), 16 * 512, 24 * 512)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let data = vec![0u8; 4096];
+        let (root_hash, tree) = build_hash_tree(&data, &[], 512, 512);
+        let f = TempFile::new().unwrap();
+        f.as_file().write_all(&data).unwrap();
+        f.as_file().write_all(&tree).unwrap();
+        let config = VerityConfig {
+            root_hash,
+            hash_offset: data.len() as u64,
+            data_block_size: Some(512),
+            hash_block_size: Some(512),
+            ..Default::default()
+        };
+        let mut hash_tree = HashTree::new(
+            config,
+            f.as_path().to_str().unwrap(),
+            (data.len() + tree.len()) as u64,
+        )
+        .unwrap();
+
+        for hash_block in 0..MAX_CACHED_HASH_BLOCKS as u64 {
+            hash_tree.insert(1000 + hash_block, vec![]);
+        }
+        assert_eq!(hash_tree.cache.len(), MAX_CACHED_HASH_BLOCKS);
+
+        // The oldest hash block is evicted first.
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false).unwrap();
+        hash_tree.verify(&mem, GuestAddress(0), 0, 512).unwrap();
+        assert_eq!(hash_tree.cache.len(), MAX_CACHED_HASH_BLOCKS);
+        assert!(!hash_tree.cache.contains_key(&1000));
+        assert!(hash_tree.cache.contains_key(&(data.len() as u64 / 512)));
+    }
+}
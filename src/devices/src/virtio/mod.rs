@@ -17,6 +17,9 @@ pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_net;
+pub mod vhost_user;
+pub mod vhost_user_block;
 pub mod vsock;
 
 pub use self::balloon::*;
@@ -26,6 +29,8 @@ pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_net::*;
+pub use self::vhost_user_block::*;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
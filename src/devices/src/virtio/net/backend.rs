@@ -0,0 +1,21 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::virtio::net::Result;
+
+/// The host end of a queue pair of a network device.
+///
+/// Every read returns a single frame and every write sends a single frame, both prefixed by a
+/// VNET header. The file descriptor is non-blocking, and becomes readable when frames are
+/// available.
+pub trait NetBackend: Read + Write + AsRawFd + Debug + Send {
+    /// Starts or stops the exchange of frames on the queue pair, when the backend has several
+    /// queues. The queues are enabled when opened.
+    fn set_queue_enabled(&self, _enabled: bool) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Capture of the frames exchanged by a network device, in the pcap format.
+//!
+//! A capture starts with the pcap global header, announcing nanosecond timestamps and Ethernet
+//! frames, followed by one record per frame, in the order the device processed them. Both the
+//! frames sent by the guest and the frames delivered to it are captured, including the ones
+//! exchanged with MMDS. The timestamps are read from the real time clock of the host.
+//!
+//! The capture file is written in non-blocking mode, so that a named pipe whose reader falls
+//! behind doesn't stall the device. The records the file doesn't accept right away are kept in
+//! memory, and frames are dropped while too many bytes are pending.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::{cmp, result};
+
+use logger::{warn, IncMetric, METRICS};
+use serde::Deserialize;
+use utils::time::{get_time_ns, ClockType};
+
+// The magic number of pcap files with nanosecond timestamps.
+pub const PCAP_MAGIC: u32 = 0xa1b2_3c4d;
+// The version of the pcap format.
+pub const PCAP_VERSION: (u16, u16) = (2, 4);
+// The link type of Ethernet frames.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+// The size of the global header of a capture.
+pub const PCAP_HEADER_SIZE: usize = 24;
+// The size of the header of a single record.
+pub const PCAP_RECORD_HEADER_SIZE: usize = 16;
+// The snap length used when none is configured, which fits any frame.
+pub const DEFAULT_SNAP_LEN: u32 = 262_144;
+// The number of bytes of records waiting for the file to accept them, past which frames are
+// dropped.
+const MAX_PENDING_BYTES: usize = 1 << 20;
+
+/// List of errors starting a capture can throw.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No path was given to write the capture to
+    #[error("No path was given to write the capture to")]
+    MissingPath,
+    /// The snap length is 0
+    #[error("The snap length must be greater than 0")]
+    InvalidSnapLen,
+    /// The maximum size doesn't fit the capture header
+    #[error("The maximum size is smaller than the capture header: {0}")]
+    InvalidMaxSize(u64),
+    /// Failed to access the capture file
+    #[error("Failed to access the capture file: {0}")]
+    Io(io::Error),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Configuration of the capture of the frames exchanged by a network interface.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Whether the frames exchanged by the interface are captured.
+    pub enabled: bool,
+    /// Path of the file or named pipe the capture is written to, required when the capture is
+    /// enabled. A file is truncated when the capture starts.
+    pub path_on_host: Option<String>,
+    /// Size of the capture in bytes, headers included, past which frames are no longer captured.
+    pub max_size_bytes: Option<u64>,
+    /// Maximum number of bytes captured for each frame.
+    pub snap_len: Option<u32>,
+}
+
+fn header(snap_len: u32) -> [u8; PCAP_HEADER_SIZE] {
+    let mut header = [0u8; PCAP_HEADER_SIZE];
+    header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&PCAP_VERSION.0.to_le_bytes());
+    header[6..8].copy_from_slice(&PCAP_VERSION.1.to_le_bytes());
+    // The time zone offset and the accuracy of the timestamps are always 0.
+    header[16..20].copy_from_slice(&snap_len.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// Writes the frames exchanged by a device to a capture file.
+#[derive(Debug)]
+pub struct Capture {
+    file: File,
+    snap_len: usize,
+    max_size: u64,
+    // The size of the capture so far, pending records included.
+    size: u64,
+    // Whether a frame didn't fit in the maximum size, which ends the capture.
+    full: bool,
+    // Whole records which the file didn't accept yet.
+    pending: Vec<u8>,
+}
+
+impl Capture {
+    /// Starts the capture described by `config`, truncating the capture file if it exists.
+    pub fn new(config: &CaptureConfig) -> Result<Self> {
+        let path = config.path_on_host.as_ref().ok_or(Error::MissingPath)?;
+        let snap_len = config.snap_len.unwrap_or(DEFAULT_SNAP_LEN);
+        if snap_len == 0 {
+            return Err(Error::InvalidSnapLen);
+        }
+        let max_size = config.max_size_bytes.unwrap_or(u64::MAX);
+        if max_size < PCAP_HEADER_SIZE as u64 {
+            return Err(Error::InvalidMaxSize(max_size));
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .map_err(Error::Io)?;
+        let mut capture = Capture {
+            file,
+            snap_len: snap_len as usize,
+            max_size,
+            size: PCAP_HEADER_SIZE as u64,
+            full: false,
+            pending: header(snap_len).to_vec(),
+        };
+        capture.flush().map_err(Error::Io)?;
+
+        Ok(capture)
+    }
+
+    /// The maximum number of bytes captured for each frame.
+    pub fn snap_len(&self) -> usize {
+        self.snap_len
+    }
+
+    /// Appends the record of a frame of `frame_len` bytes, starting with `data`. Only the first
+    /// `snap_len()` bytes of `data` are captured.
+    pub fn record(&mut self, data: &[u8], frame_len: usize) -> io::Result<()> {
+        if self.full {
+            return Ok(());
+        }
+
+        let data = &data[..cmp::min(data.len(), self.snap_len)];
+        let record_len = PCAP_RECORD_HEADER_SIZE + data.len();
+        if self.size + record_len as u64 > self.max_size {
+            warn!("The network capture reached its maximum size, frames are no longer captured.");
+            self.full = true;
+            return Ok(());
+        }
+
+        self.flush()?;
+        if self.pending.len() + record_len > MAX_PENDING_BYTES {
+            METRICS.net.capture_dropped_frames.inc();
+            return Ok(());
+        }
+
+        let time_ns = get_time_ns(ClockType::Real);
+        self.pending
+            .extend_from_slice(&((time_ns / 1_000_000_000) as u32).to_le_bytes());
+        self.pending
+            .extend_from_slice(&((time_ns % 1_000_000_000) as u32).to_le_bytes());
+        self.pending
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.pending
+            .extend_from_slice(&(frame_len as u32).to_le_bytes());
+        self.pending.extend_from_slice(data);
+        self.size += record_len as u64;
+
+        self.flush()
+    }
+
+    /// Writes as many pending records as the capture file accepts without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.file.write(&self.pending) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(count) => {
+                    self.pending.drain(..count);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of bytes of records which the capture file didn't accept yet.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+
+    use std::fs;
+    use std::os::unix::io::FromRawFd;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::virtio::net::test_utils::capture_records;
+
+    fn config(path: &str) -> CaptureConfig {
+        CaptureConfig {
+            enabled: true,
+            path_on_host: Some(path.to_string()),
+            max_size_bytes: None,
+            snap_len: None,
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+
+        let mut cfg = config(path);
+        cfg.path_on_host = None;
+        assert!(matches!(Capture::new(&cfg), Err(Error::MissingPath)));
+
+        let mut cfg = config(path);
+        cfg.snap_len = Some(0);
+        assert!(matches!(Capture::new(&cfg), Err(Error::InvalidSnapLen)));
+
+        let mut cfg = config(path);
+        cfg.max_size_bytes = Some(PCAP_HEADER_SIZE as u64 - 1);
+        assert!(matches!(Capture::new(&cfg), Err(Error::InvalidMaxSize(_))));
+
+        let cfg = config("/invalid/path/to/capture");
+        assert!(matches!(Capture::new(&cfg), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_record() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+        let mut cfg = config(path);
+        cfg.snap_len = Some(4);
+        let mut capture = Capture::new(&cfg).unwrap();
+        assert_eq!(capture.snap_len(), 4);
+
+        capture.record(&[1, 2, 3, 4, 5, 6], 6).unwrap();
+        capture.record(&[7, 8], 10).unwrap();
+        assert_eq!(capture.pending_len(), 0);
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(&bytes[..PCAP_HEADER_SIZE], &header(4));
+        assert_eq!(
+            capture_records(&bytes),
+            vec![(4, 6, vec![1, 2, 3, 4]), (2, 10, vec![7, 8])]
+        );
+        // The timestamps hold nanoseconds.
+        let nsec = u32::from_le_bytes(bytes[28..32].try_into().unwrap());
+        assert!(nsec < 1_000_000_000);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap();
+        let mut cfg = config(path);
+        cfg.max_size_bytes = Some((PCAP_HEADER_SIZE + 2 * PCAP_RECORD_HEADER_SIZE + 10) as u64);
+        let mut capture = Capture::new(&cfg).unwrap();
+
+        capture.record(&[0; 8], 8).unwrap();
+        // The capture ends with the first frame which doesn't fit, even if later ones would.
+        capture.record(&[1; 8], 8).unwrap();
+        capture.record(&[2; 2], 2).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(capture_records(&bytes), vec![(8, 8, vec![0; 8])]);
+    }
+
+    #[test]
+    fn test_full_pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        let writer = unsafe { File::from_raw_fd(fds[1]) };
+        let path = format!("/proc/self/fd/{}", fds[1]);
+        let mut capture = Capture::new(&config(&path)).unwrap();
+        drop(writer);
+
+        // Fill the pipe, then the pending records, without blocking.
+        let frame = vec![0xabu8; 60_000];
+        let dropped = METRICS.net.capture_dropped_frames.count();
+        let mut recorded = 0;
+        while METRICS.net.capture_dropped_frames.count() == dropped {
+            capture.record(&frame, frame.len()).unwrap();
+            recorded += 1;
+        }
+        assert!(capture.pending_len() > 0);
+        assert!(capture.pending_len() <= MAX_PENDING_BYTES);
+
+        // The pending records are written once the reader catches up, and none is split.
+        let mut bytes = Vec::new();
+        let mut buf = vec![0u8; 1 << 16];
+        while capture.pending_len() > 0 {
+            let count = io::Read::read(&mut reader, &mut buf).unwrap();
+            bytes.extend_from_slice(&buf[..count]);
+            capture.flush().unwrap();
+        }
+        drop(capture);
+        io::Read::read_to_end(&mut reader, &mut bytes).unwrap();
+        let records = capture_records(&bytes);
+        assert_eq!(records.len(), recorded - 1);
+        assert!(records.iter().all(|(_, _, data)| *data == frame));
+    }
+}
@@ -11,38 +11,50 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem, result};
 
-use dumbo::pdu::arp::ETH_IPV4_FRAME_LEN;
-use dumbo::pdu::ethernet::{EthernetFrame, PAYLOAD_OFFSET};
+use dumbo::pdu::ethernet::EthernetFrame;
 use libc::EAGAIN;
 use logger::{error, warn, IncMetric, METRICS};
 use mmds::data_store::Mmds;
+use mmds::dns::DnsConfig;
 use mmds::ns::MmdsNetworkStack;
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 use utils::eventfd::EventFd;
-use utils::net::mac::MacAddr;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET,
+    VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, VIRTIO_NET_CTRL_RX_PROMISC,
+    VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR,
+    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_VLAN,
+    VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_LINK_UP,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{ByteValued, Bytes, GuestMemoryError, GuestMemoryMmap};
-
-const FRAME_HEADER_MAX_LEN: usize = PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::capture::{Capture, CaptureConfig};
+use crate::virtio::net::dhcp::{DhcpConfig, DhcpServer};
+use crate::virtio::net::filter::{TxFilterConfig, Verdict, FILTER_HEADER_LEN};
 use crate::virtio::net::iovec::IoVecBuffer;
+use crate::virtio::net::rx_filter::RxFilter;
 use crate::virtio::net::tap::Tap;
-use crate::virtio::net::{
-    Error, NetQueue, Result, MAX_BUFFER_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX,
-};
+#[cfg(test)]
+use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::unix_socket::UnixSocket;
+use crate::virtio::net::user_nat::{UserNat, UserNatConfig};
+use crate::virtio::net::{Error, Result, MAX_BUFFER_SIZE, MAX_QUEUE_PAIRS, QUEUE_SIZE};
 use crate::virtio::{
     ActivateResult, DescriptorChain, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
     TYPE_NET,
 };
 use crate::{report_net_event_fail, Error as DeviceError};
 
+#[derive(Debug)]
 enum FrontendError {
     AddUsed,
+    DescriptorChainTooLarge,
     DescriptorChainTooSmall,
     EmptyQueue,
     GuestMemory(GuestMemoryError),
@@ -53,11 +65,15 @@ pub(crate) const fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
 
+// The offset of the `num_buffers` field, which ends the VNET header.
+const NUM_BUFFERS_OFFSET: usize = vnet_hdr_len() - mem::size_of::<u16>();
+
 // This returns the maximum frame header length. This includes the VNET header plus
-// the maximum L2 frame header bytes which includes the ethernet frame header plus
-// the header IPv4 ARP header which is 28 bytes long.
+// the maximum frame header bytes looked at by the TX filter, which covers the ethernet
+// frame header, an IPv4 header with options and the ports of a TCP or UDP header. This
+// also covers the IPv4 ARP header which is 28 bytes long.
 const fn frame_hdr_len() -> usize {
-    vnet_hdr_len() + FRAME_HEADER_MAX_LEN
+    vnet_hdr_len() + FILTER_HEADER_LEN
 }
 
 // Frames being sent/received through the network device model have a VNET header. This
@@ -84,18 +100,150 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     buf[0..vnet_hdr_len()].fill(0);
 }
 
+// The index of the rx queue of a queue pair, in the queues/queue_evts vectors.
+pub(crate) const fn rx_queue_index(pair: usize) -> usize {
+    2 * pair
+}
+
+// The index of the tx queue of a queue pair, in the queues/queue_evts vectors.
+pub(crate) const fn tx_queue_index(pair: usize) -> usize {
+    2 * pair + 1
+}
+
+// The number of queues of a device, including the control queue.
+pub(crate) const fn num_queues(num_queue_pairs: usize) -> usize {
+    2 * num_queue_pairs + 1
+}
+
+// The features implemented through the control queue, besides the multi-queue support.
+pub(crate) const CTRL_FEATURES: u64 = 1 << VIRTIO_NET_F_CTRL_VQ
+    | 1 << VIRTIO_NET_F_CTRL_RX
+    | 1 << VIRTIO_NET_F_CTRL_VLAN
+    | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR;
+
+// The offload features of the device, which are implemented by the TAP interface.
+pub(crate) const TAP_OFFLOAD_FEATURES: u64 = 1 << VIRTIO_NET_F_GUEST_CSUM
+    | 1 << VIRTIO_NET_F_CSUM
+    | 1 << VIRTIO_NET_F_GUEST_TSO4
+    | 1 << VIRTIO_NET_F_GUEST_UFO
+    | 1 << VIRTIO_NET_F_HOST_TSO4
+    | 1 << VIRTIO_NET_F_HOST_UFO;
+
+// Sets up a TAP interface to exchange frames prefixed by a VNET header, with the offloads of
+// `TAP_OFFLOAD_FEATURES`.
+pub(crate) fn configure_tap(tap: &Tap) -> Result<()> {
+    // Set offload flags to match the virtio features.
+    tap.set_offload(
+        net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
+    )
+    .map_err(Error::TapSetOffload)?;
+
+    let vnet_hdr_size = vnet_hdr_len() as i32;
+    tap.set_vnet_hdr_size(vnet_hdr_size)
+        .map_err(Error::TapSetVnetHdrSize)
+}
+
 #[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: MacAddr,
+    // Only read by the driver when VIRTIO_NET_F_STATUS is negotiated.
+    pub status: u16,
+    // Only read by the driver when VIRTIO_NET_F_MQ is negotiated.
+    pub max_virtqueue_pairs: u16,
 }
 
 // SAFETY: `ConfigSpace` contains only PODs.
 unsafe impl ByteValued for ConfigSpace {}
 
+// A command sent by the driver through the control queue.
+struct CtrlCommand {
+    class: u8,
+    cmd: u8,
+    data: Vec<u8>,
+    // The address of the byte the status of the command is written to.
+    ack_addr: GuestAddress,
+}
+
+impl CtrlCommand {
+    // The size of the class and command header.
+    const HDR_LEN: usize = 2;
+
+    // Reads a command made of the header and the command specific data, followed by the
+    // writable status byte.
+    fn parse(
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+    ) -> std::result::Result<Self, FrontendError> {
+        let mut bytes = Vec::new();
+        let mut next_descriptor = Some(head);
+
+        while let Some(descriptor) = &next_descriptor {
+            if descriptor.is_write_only() {
+                if bytes.len() < Self::HDR_LEN || descriptor.len == 0 {
+                    return Err(FrontendError::DescriptorChainTooSmall);
+                }
+                return Ok(CtrlCommand {
+                    class: bytes[0],
+                    cmd: bytes[1],
+                    data: bytes.split_off(Self::HDR_LEN),
+                    ack_addr: descriptor.addr,
+                });
+            }
+
+            let start = bytes.len();
+            let end = start + descriptor.len as usize;
+            if end > MAX_BUFFER_SIZE {
+                return Err(FrontendError::DescriptorChainTooLarge);
+            }
+            bytes.resize(end, 0);
+            mem.read_slice(&mut bytes[start..], descriptor.addr)
+                .map_err(FrontendError::GuestMemory)?;
+
+            next_descriptor = descriptor.next_descriptor();
+        }
+
+        Err(FrontendError::DescriptorChainTooSmall)
+    }
+}
+
+/// A pair of rx and tx queues, backed by its own queue of the host backend.
+pub struct QueuePair {
+    pub backend: Box<dyn NetBackend>,
+
+    pub(crate) rx_deferred_frame: bool,
+
+    rx_bytes_read: usize,
+    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
+
+    #[cfg(test)]
+    pub(crate) mocks: Mocks,
+}
+
+impl QueuePair {
+    fn new(backend: Box<dyn NetBackend>) -> Self {
+        QueuePair {
+            backend,
+            rx_deferred_frame: false,
+            rx_bytes_read: 0,
+            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+
+            #[cfg(test)]
+            mocks: Mocks::default(),
+        }
+    }
+}
+
 pub struct Net {
     pub(crate) id: String,
+    // The name of the tap interface, empty for devices backed by a socket.
+    pub(crate) if_name: String,
+    pub(crate) host_socket_path: Option<String>,
+    pub(crate) user_nat: Option<UserNatConfig>,
 
-    pub tap: Tap,
+    pub(crate) queue_pairs: Vec<QueuePair>,
+    // The number of queue pairs used by the driver, which is set through the control queue.
+    pub(crate) active_queue_pairs: usize,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -106,11 +254,6 @@ pub struct Net {
     pub(crate) rx_rate_limiter: RateLimiter,
     pub(crate) tx_rate_limiter: RateLimiter,
 
-    pub(crate) rx_deferred_frame: bool,
-
-    rx_bytes_read: usize,
-    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
-
     tx_frame_headers: [u8; frame_hdr_len()],
 
     pub(crate) irq_trigger: IrqTrigger,
@@ -122,39 +265,126 @@ pub struct Net {
     pub(crate) activate_evt: EventFd,
 
     pub mmds_ns: Option<MmdsNetworkStack>,
+    dhcp_server: Option<DhcpServer>,
+
+    capture: Option<Capture>,
+    tx_filter: Option<TxFilterConfig>,
+    pub(crate) rx_filter: RxFilter,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
+    /// Create a new virtio network device with the given TAP interface. Devices with more than
+    /// one queue pair require a multi-queue TAP interface, with one TAP queue per queue pair.
     pub fn new_with_tap(
         id: String,
         tap_if_name: &str,
         guest_mac: Option<MacAddr>,
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
+        num_queue_pairs: usize,
     ) -> Result<Self> {
-        let tap = Tap::open_named(tap_if_name).map_err(Error::TapOpen)?;
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_QUEUE_PAIRS {
+            return Err(Error::InvalidQueuePairs(num_queue_pairs));
+        }
 
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let taps = if num_queue_pairs > 1 {
+            Tap::open_multi_queue(tap_if_name, num_queue_pairs)
+        } else {
+            Tap::open_named(tap_if_name).map(|tap| vec![tap])
+        }
+        .map_err(Error::TapOpen)?;
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+        for tap in &taps {
+            configure_tap(tap)?;
+        }
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_RING_F_EVENT_IDX;
+        // The name may have been assigned by the kernel.
+        let if_name = taps[0].if_name_as_str().to_string();
+        let backends = taps
+            .into_iter()
+            .map(|tap| Box::new(tap) as Box<dyn NetBackend>)
+            .collect();
+        let mut net = Self::new_with_backends(
+            id,
+            backends,
+            TAP_OFFLOAD_FEATURES,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )?;
+        net.if_name = if_name;
+
+        Ok(net)
+    }
+
+    /// Create a new virtio network device exchanging raw Ethernet frames with another process
+    /// over the Unix socket at `socket_path`. Such devices have a single queue pair, and don't
+    /// offer any offload to the driver since the frames carry no VNET header on the socket.
+    pub fn new_with_socket(
+        id: String,
+        socket_path: &str,
+        guest_mac: Option<MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Result<Self> {
+        let socket = UnixSocket::connect(socket_path).map_err(Error::UnixSocketConnect)?;
 
-        let mut config_space = ConfigSpace::default();
+        let mut net = Self::new_with_backends(
+            id,
+            vec![Box::new(socket)],
+            0,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )?;
+        net.host_socket_path = Some(socket_path.to_string());
+
+        Ok(net)
+    }
+
+    /// Create a new virtio network device whose traffic goes through a user-mode NAT, which
+    /// proxies it through ordinary host sockets. Such devices have a single queue pair, and
+    /// don't offer any offload to the driver.
+    pub fn new_with_user_nat(
+        id: String,
+        config: &UserNatConfig,
+        guest_mac: Option<MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Result<Self> {
+        let nat = UserNat::new(config, guest_mac).map_err(Error::UserNat)?;
+
+        let mut net = Self::new_with_backends(
+            id,
+            vec![Box::new(nat)],
+            0,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        )?;
+        net.user_nat = Some(config.clone());
+
+        Ok(net)
+    }
+
+    // Creates a device with one queue pair per backend, which offers the `offload_features`
+    // implemented by the backends.
+    fn new_with_backends(
+        id: String,
+        backends: Vec<Box<dyn NetBackend>>,
+        offload_features: u64,
+        guest_mac: Option<MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Result<Self> {
+        let num_queue_pairs = backends.len();
+        let mut avail_features =
+            offload_features | 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX;
+
+        let mut config_space = ConfigSpace {
+            status: VIRTIO_NET_S_LINK_UP as u16,
+            ..Default::default()
+        };
         if let Some(mac) = guest_mac {
             config_space.guest_mac = mac;
             // Enabling feature for MAC address configuration
@@ -162,25 +392,33 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
+        if num_queue_pairs > 1 {
+            // The driver selects the number of queue pairs it uses through the control queue.
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            config_space.max_virtqueue_pairs = num_queue_pairs as u16;
+        }
+
         let mut queue_evts = Vec::new();
         let mut queues = Vec::new();
-        for &size in QUEUE_SIZES {
+        for _ in 0..num_queues(num_queue_pairs) {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
-            queues.push(Queue::new(size));
+            queues.push(Queue::new(QUEUE_SIZE));
         }
 
-        Ok(Net {
+        let mut net = Net {
             id,
-            tap,
+            if_name: String::new(),
+            host_socket_path: None,
+            user_nat: None,
+            queue_pairs: backends.into_iter().map(QueuePair::new).collect(),
+            // The backend queues are enabled when opened.
+            active_queue_pairs: num_queue_pairs,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
             rx_rate_limiter,
             tx_rate_limiter,
-            rx_deferred_frame: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_headers: [0u8; frame_hdr_len()],
             irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
             config_space,
@@ -188,7 +426,15 @@ impl Net {
             device_state: DeviceState::Inactive,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             mmds_ns: None,
-        })
+            dhcp_server: None,
+            capture: None,
+            tx_filter: None,
+            rx_filter: RxFilter::default(),
+        };
+        // Drivers only use the first queue pair until they set the number of queue pairs.
+        net.set_active_queue_pairs(1)?;
+
+        Ok(net)
     }
 
     /// Provides the ID of this net device.
@@ -201,9 +447,145 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
-    /// Provides the host IFACE name of this net device.
+    /// Provides the host IFACE name of this net device, which is empty for devices backed by a
+    /// socket.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.if_name.clone()
+    }
+
+    /// Provides the path of the host socket of this net device, if it's backed by one.
+    pub fn host_socket_path(&self) -> Option<&String> {
+        self.host_socket_path.as_ref()
+    }
+
+    /// Provides the configuration of the user-mode NAT of the device, if it has one.
+    pub fn user_nat(&self) -> Option<&UserNatConfig> {
+        self.user_nat.as_ref()
+    }
+
+    /// Provides the number of rx/tx queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> usize {
+        self.queue_pairs.len()
+    }
+
+    /// Provides the number of queue pairs used by the guest driver.
+    pub fn active_queue_pairs(&self) -> usize {
+        self.active_queue_pairs
+    }
+
+    /// Whether mergeable receive buffers are offered to the driver.
+    pub fn has_mergeable_rx_buffers(&self) -> bool {
+        self.avail_features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0
+    }
+
+    /// Offers mergeable receive buffers to the driver, or stops offering them. Drivers using
+    /// them prevent saving the device to snapshots of older versions, so they are not offered by
+    /// default. Must be called before the driver negotiates the features.
+    pub fn set_mergeable_rx_buffers(&mut self, enabled: bool) {
+        if enabled {
+            self.avail_features |= 1 << VIRTIO_NET_F_MRG_RXBUF;
+        } else {
+            self.avail_features &= !(1 << VIRTIO_NET_F_MRG_RXBUF);
+        }
+    }
+
+    // Enables the backend queues of the first `count` queue pairs and disables the other ones,
+    // so that the host doesn't send frames on the queue pairs which the driver doesn't use.
+    pub(crate) fn set_active_queue_pairs(&mut self, count: usize) -> Result<()> {
+        if count == 0 || count > self.queue_pairs.len() {
+            return Err(Error::InvalidQueuePairs(count));
+        }
+
+        for (pair, queue_pair) in self.queue_pairs.iter().enumerate() {
+            let was_active = pair < self.active_queue_pairs;
+            let is_active = pair < count;
+            if was_active != is_active {
+                queue_pair.backend.set_queue_enabled(is_active)?;
+            }
+        }
+        self.active_queue_pairs = count;
+
+        Ok(())
+    }
+
+    // The index of the control queue, for devices which have one.
+    pub(crate) fn ctrl_queue_index(&self) -> Option<usize> {
+        let index = 2 * self.queue_pairs.len();
+        if self.queues.len() > index {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    // The number of queues exposed to the driver. The control queue of devices with a single
+    // queue pair is only exposed to drivers negotiating it, so that the other drivers can
+    // activate the device without setting it up.
+    fn num_exposed_queues(&self) -> usize {
+        match self.ctrl_queue_index() {
+            Some(index)
+                if self.queue_pairs.len() > 1
+                    || self.has_feature(u64::from(VIRTIO_NET_F_CTRL_VQ)) =>
+            {
+                index + 1
+            }
+            _ => 2 * self.queue_pairs.len(),
+        }
+    }
+
+    // Removes the control queue, and the features implemented through it, from a device with a
+    // single queue pair. Used when restoring devices saved without a control queue.
+    pub(crate) fn remove_ctrl_queue(&mut self) {
+        if self.queue_pairs.len() == 1 && self.ctrl_queue_index().is_some() {
+            self.queues.pop();
+            self.queue_evts.pop();
+            self.avail_features &= !CTRL_FEATURES;
+        }
+    }
+
+    /// Whether the control queue is offered to the driver along with its commands programming
+    /// the receive filter, the VLANs and the MAC address.
+    pub fn has_ctrl_queue(&self) -> bool {
+        self.avail_features & CTRL_FEATURES == CTRL_FEATURES
+    }
+
+    /// Offers the control queue and its commands to the driver, or stops offering them. Devices
+    /// with several queue pairs keep offering the control queue itself, to select the number of
+    /// queue pairs. Drivers using the control queue of a device with a single queue pair prevent
+    /// saving it to snapshots of older versions, so it is not offered by default. Must be called
+    /// before the driver negotiates the features.
+    pub fn set_ctrl_queue(&mut self, enabled: bool) {
+        if enabled {
+            self.avail_features |= CTRL_FEATURES;
+        } else {
+            self.avail_features &= !CTRL_FEATURES;
+            if self.queue_pairs.len() > 1 {
+                self.avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+            }
+        }
+    }
+
+    /// Provides the receive filter programmed by the driver.
+    pub fn rx_filter(&self) -> &RxFilter {
+        &self.rx_filter
+    }
+
+    // Whether the TX filter checks the frames against the MAC set by the host, which the driver
+    // then can't change.
+    fn is_mac_locked(&self) -> bool {
+        self.tx_filter
+            .as_ref()
+            .map_or(false, |filter| filter.check_source_mac)
+    }
+
+    // Whether the receive filter programmed by the driver lets a frame through.
+    fn is_rx_frame_accepted(&self, frame: &[u8]) -> bool {
+        self.rx_filter.accepts(
+            frame,
+            self.guest_mac,
+            self.has_feature(u64::from(VIRTIO_NET_F_CTRL_RX)),
+            self.has_feature(u64::from(VIRTIO_NET_F_CTRL_VLAN)),
+        )
     }
 
     /// Provides the MmdsNetworkStack of this net device.
@@ -226,6 +608,28 @@ impl Net {
         self.mmds_ns = None
     }
 
+    /// Enables the DNS responder of the `MmdsNetworkStack` with the given configuration, or
+    /// disables it. Does nothing if the device doesn't forward MMDS requests.
+    pub fn set_mmds_dns_config(&mut self, dns: Option<DnsConfig>) {
+        if let Some(mmds_ns) = self.mmds_ns.as_mut() {
+            mmds_ns.set_dns_config(dns);
+        }
+    }
+
+    /// Starts the DHCP server handing the `config` lease to the guest, or stops it.
+    pub fn set_dhcp(&mut self, config: Option<DhcpConfig>) -> Result<()> {
+        self.dhcp_server = config
+            .map(DhcpServer::new)
+            .transpose()
+            .map_err(Error::Dhcp)?;
+        Ok(())
+    }
+
+    /// Provides the lease handed to the guest by the DHCP server.
+    pub fn dhcp_config(&self) -> Option<&DhcpConfig> {
+        self.dhcp_server.as_ref().map(DhcpServer::config)
+    }
+
     /// Provides a reference to the configured RX rate limiter.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
         &self.rx_rate_limiter
@@ -236,14 +640,11 @@ impl Net {
         &self.tx_rate_limiter
     }
 
-    fn signal_used_queue(&mut self, queue_type: NetQueue) -> result::Result<(), DeviceError> {
+    fn signal_used_queue(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = match queue_type {
-            NetQueue::Rx => &mut self.queues[RX_INDEX],
-            NetQueue::Tx => &mut self.queues[TX_INDEX],
-        };
+        let queue = &mut self.queues[queue_index];
 
         if queue.prepare_kick(mem) {
             self.irq_trigger
@@ -280,19 +681,20 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
-        if !Self::rate_limiter_consume_op(&mut self.rx_rate_limiter, self.rx_bytes_read as u64) {
+    fn rate_limited_rx_single_frame(&mut self, pair: usize) -> bool {
+        let rx_bytes_read = self.queue_pairs[pair].rx_bytes_read as u64;
+        if !Self::rate_limiter_consume_op(&mut self.rx_rate_limiter, rx_bytes_read) {
             METRICS.net.rx_rate_limiter_throttled.inc();
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
             // revert the rate limiting budget consumption
-            Self::rate_limiter_replenish_op(&mut self.rx_rate_limiter, self.rx_bytes_read as u64);
+            Self::rate_limiter_replenish_op(&mut self.rx_rate_limiter, rx_bytes_read);
         }
 
         success
@@ -309,10 +711,37 @@ impl Net {
         data: &[u8],
         head: DescriptorChain,
     ) -> std::result::Result<(), FrontendError> {
+        if Self::write_slice_to_descriptor_chain(mem, data, head)? < data.len() {
+            warn!("Receiving buffer is too small to hold frame of current size");
+            return Err(FrontendError::DescriptorChainTooSmall);
+        }
+
+        METRICS.net.rx_bytes_count.add(data.len());
+        METRICS.net.rx_packets_count.inc();
+        Ok(())
+    }
+
+    /// Write as much of a slice as fits in a descriptor chain
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an inappropriate (read only) descriptor is found in the chain
+    fn write_slice_to_descriptor_chain(
+        mem: &GuestMemoryMmap,
+        data: &[u8],
+        head: DescriptorChain,
+    ) -> std::result::Result<usize, FrontendError> {
         let mut chunk = data;
         let mut next_descriptor = Some(head);
 
         while let Some(descriptor) = &next_descriptor {
+            // If chunk is empty we are done here.
+            if chunk.is_empty() {
+                break;
+            }
+
             if !descriptor.is_write_only() {
                 return Err(FrontendError::ReadOnlyDescriptor);
             }
@@ -332,26 +761,44 @@ impl Net {
                 }
             }
 
-            // If chunk is empty we are done here.
-            if chunk.is_empty() {
-                METRICS.net.rx_bytes_count.add(data.len());
-                METRICS.net.rx_packets_count.inc();
-                return Ok(());
-            }
+            next_descriptor = descriptor.next_descriptor();
+        }
 
+        Ok(data.len() - chunk.len())
+    }
+
+    // Returns the number of bytes which can be written in a descriptor chain.
+    fn descriptor_chain_capacity(
+        head: &DescriptorChain,
+    ) -> std::result::Result<usize, FrontendError> {
+        if !head.is_write_only() {
+            return Err(FrontendError::ReadOnlyDescriptor);
+        }
+
+        let mut capacity = head.len as usize;
+        let mut next_descriptor = head.next_descriptor();
+        while let Some(descriptor) = next_descriptor {
+            if !descriptor.is_write_only() {
+                return Err(FrontendError::ReadOnlyDescriptor);
+            }
+            capacity += descriptor.len as usize;
             next_descriptor = descriptor.next_descriptor();
         }
 
-        warn!("Receiving buffer is too small to hold frame of current size");
-        Err(FrontendError::DescriptorChainTooSmall)
+        Ok(capacity)
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest.
+    fn do_write_frame_to_guest(&mut self, pair: usize) -> std::result::Result<(), FrontendError> {
+        if self.has_feature(u64::from(VIRTIO_NET_F_MRG_RXBUF)) {
+            return self.do_write_merged_frame_to_guest(pair);
+        }
+
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue_pair = &self.queue_pairs[pair];
+        let queue = &mut self.queues[rx_queue_index(pair)];
         let head_descriptor = queue.pop_or_enable_notification(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
@@ -360,7 +807,7 @@ impl Net {
 
         let result = Self::write_to_descriptor_chain(
             mem,
-            &self.rx_frame_buf[..self.rx_bytes_read],
+            &queue_pair.rx_frame_buf[..queue_pair.rx_bytes_read],
             head_descriptor,
         );
         // Mark the descriptor chain as used. If an error occurred, skip the descriptor chain.
@@ -368,7 +815,7 @@ impl Net {
             METRICS.net.rx_fails.inc();
             0
         } else {
-            self.rx_bytes_read as u32
+            queue_pair.rx_bytes_read as u32
         };
         queue.add_used(mem, head_index, used_len).map_err(|err| {
             error!("Failed to add available descriptor {}: {}", head_index, err);
@@ -378,12 +825,105 @@ impl Net {
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest, spreading it
+    // over as many descriptor chains as needed. The number of chains is reported to the driver in
+    // the `num_buffers` field of the VNET header.
+    fn do_write_merged_frame_to_guest(
+        &mut self,
+        pair: usize,
+    ) -> std::result::Result<(), FrontendError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+
+        let queue_pair = &mut self.queue_pairs[pair];
+        let queue = &mut self.queues[rx_queue_index(pair)];
+        let frame_len = queue_pair.rx_bytes_read;
+
+        // Pop descriptor chains until they can hold the whole frame.
+        let mut heads = Vec::new();
+        let mut capacity = 0;
+        let mut result = Ok(());
+        while capacity < frame_len {
+            let head = match queue.pop_or_enable_notification(mem) {
+                Some(head) => head,
+                None => {
+                    // Keep the popped chains for when the driver provides enough of them.
+                    for _ in 0..heads.len() {
+                        queue.undo_pop();
+                    }
+                    METRICS.net.no_rx_avail_buffer.inc();
+                    return Err(FrontendError::EmptyQueue);
+                }
+            };
+            let chain_capacity = Self::descriptor_chain_capacity(&head);
+            heads.push(head);
+            match chain_capacity {
+                Ok(len) => capacity += len,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        let head_indexes: Vec<u16> = heads.iter().map(|head| head.index).collect();
+        let mut used_lens = vec![0u32; heads.len()];
+        if result.is_ok() {
+            // The number of chains is bounded by the queue size, so it fits in an u16.
+            let num_buffers = heads.len() as u16;
+            let frame = &mut queue_pair.rx_frame_buf[..frame_len];
+            frame[NUM_BUFFERS_OFFSET..vnet_hdr_len()].copy_from_slice(&num_buffers.to_le_bytes());
+
+            let mut written = 0;
+            for (head, used_len) in heads.into_iter().zip(used_lens.iter_mut()) {
+                match Self::write_slice_to_descriptor_chain(mem, &frame[written..], head) {
+                    Ok(len) => {
+                        written += len;
+                        *used_len = len as u32;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // If an error occurred, skip all the descriptor chains.
+        if result.is_err() {
+            METRICS.net.rx_fails.inc();
+            used_lens.fill(0);
+        } else {
+            METRICS.net.rx_bytes_count.add(frame_len);
+            METRICS.net.rx_packets_count.inc();
+        }
+
+        // The driver expects all the chains of a frame to be used once it sees the first one, so
+        // they are published at once.
+        for (offset, (head_index, used_len)) in head_indexes.iter().zip(used_lens).enumerate() {
+            queue
+                .write_used_element(mem, offset as u16, *head_index, used_len)
+                .map_err(|err| {
+                    error!("Failed to add available descriptor {}: {}", head_index, err);
+                    FrontendError::AddUsed
+                })?;
+        }
+        queue
+            .advance_used_ring(mem, head_indexes.len() as u16)
+            .map_err(|err| {
+                error!("Failed to advance the used ring: {}", err);
+                FrontendError::AddUsed
+            })?;
+
+        result
+    }
+
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest. In case of an
+    // error retries the operation if possible. Returns true if the operation was successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -398,16 +938,21 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to the DHCP server or MMDS and if neither accepts it, sends it on
+    // the host backend of the queue pair.
     //
-    // Returns whether MMDS consumed the frame.
+    // Returns whether the DHCP server or MMDS consumed the frame.
+    #[allow(clippy::too_many_arguments)]
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
+        dhcp_server: Option<&mut DhcpServer>,
         rate_limiter: &mut RateLimiter,
         headers: &mut [u8],
         frame_iovec: &IoVecBuffer,
-        tap: &mut Tap,
+        queue_pair: &mut QueuePair,
         guest_mac: Option<MacAddr>,
+        capture: &mut Option<Capture>,
+        tx_filter: Option<&TxFilterConfig>,
     ) -> Result<bool> {
         // Read the frame headers from the IoVecBuffer. This will return None
         // if the frame_iovec is empty.
@@ -423,6 +968,30 @@ impl Net {
             e
         })?;
 
+        if let Some(writer) = capture.as_ref() {
+            let frame_len = frame_iovec.len() - vnet_hdr_len();
+            let mut data = vec![0u8; cmp::min(frame_len, writer.snap_len())];
+            // The frame is at least as long as `data`, which is only empty for empty frames.
+            frame_iovec.read_at(&mut data, vnet_hdr_len());
+            Self::capture_frame(capture, &data, frame_len);
+        }
+
+        if let Some(server) = dhcp_server {
+            if server.is_dhcp_frame(headers) {
+                let mut frame = vec![0u8; frame_iovec.len() - vnet_hdr_len()];
+                // Ok to unwrap here, because we are passing a buffer that has the exact size
+                // of the `IoVecBuffer` minus the VNET headers.
+                frame_iovec.read_at(&mut frame, vnet_hdr_len()).unwrap();
+                let _ = server.detour_frame(&frame);
+
+                // DHCP frames are not accounted by the rate limiter.
+                Self::rate_limiter_replenish_op(rate_limiter, frame_iovec.len() as u64);
+
+                // The DHCP server consumed the frame.
+                return Ok(true);
+            }
+        }
+
         if let Some(ns) = mmds_ns {
             if ns.is_mmds_frame(headers) {
                 let mut frame = vec![0u8; frame_iovec.len() - vnet_hdr_len()];
@@ -451,7 +1020,21 @@ impl Net {
             });
         }
 
-        match Self::write_tap(tap, frame_iovec) {
+        if let Some(filter) = tx_filter {
+            let dropped = match filter.check(headers, guest_mac) {
+                Verdict::Allow => None,
+                Verdict::SpoofedMac => Some(&METRICS.net.tx_filter_mac_drops),
+                Verdict::SpoofedIp => Some(&METRICS.net.tx_filter_ip_drops),
+                Verdict::Denied => Some(&METRICS.net.tx_filter_rule_drops),
+                Verdict::Malformed => Some(&METRICS.net.tx_filter_malformed_drops),
+            };
+            if let Some(metric) = dropped {
+                metric.inc();
+                return Ok(false);
+            }
+        }
+
+        match Self::write_tap(queue_pair, frame_iovec) {
             Ok(_) => {
                 METRICS.net.tx_bytes_count.add(frame_iovec.len());
                 METRICS.net.tx_packets_count.inc();
@@ -465,32 +1048,65 @@ impl Net {
         Ok(false)
     }
 
-    // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
+    // Appends a frame to the capture, if the device is captured. Capturing stops on the first
+    // failure to write the capture.
+    fn capture_frame(capture: &mut Option<Capture>, data: &[u8], frame_len: usize) {
+        if let Some(writer) = capture.as_mut() {
+            if let Err(err) = writer.record(data, frame_len) {
+                error!(
+                    "Failed to write the network capture, capturing stops: {:?}",
+                    err
+                );
+                METRICS.net.capture_fails.inc();
+                *capture = None;
+            }
+        }
+    }
+
+    // We currently prioritize packets from the DHCP server, then from the MMDS, over regular
+    // network packets.
+    fn read_from_mmds_or_tap(&mut self, pair: usize) -> Result<usize> {
+        if let Some(server) = self.dhcp_server.as_mut() {
+            let rx_frame_buf = &mut self.queue_pairs[pair].rx_frame_buf;
+            if let Some(len) = server.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
+                init_vnet_hdr(rx_frame_buf);
+                return Ok(vnet_hdr_len() + len.get());
+            }
+        }
+
         if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
-            {
+            let rx_frame_buf = &mut self.queue_pairs[pair].rx_frame_buf;
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                init_vnet_hdr(rx_frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        self.read_tap(pair).map_err(Error::IO)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
+                    if let Some(frame) = self.queue_pairs[pair]
+                        .rx_frame_buf
+                        .get(vnet_hdr_len()..count)
+                    {
+                        if !self.is_rx_frame_accepted(frame) {
+                            METRICS.net.rx_filtered_frames.inc();
+                            continue;
+                        }
+                        Self::capture_frame(&mut self.capture, frame, frame.len());
+                    }
+                    self.queue_pairs[pair].rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    if !self.rate_limited_rx_single_frame(pair) {
+                        self.queue_pairs[pair].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -515,40 +1131,41 @@ impl Net {
 
         // At this point we processed as many Rx frames as possible.
         // We have to wake the guest if at least one descriptor chain has been used.
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(rx_queue_index(pair))
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair) {
+            self.queue_pairs[pair].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair);
         }
 
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(rx_queue_index(pair))
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[pair].rx_deferred_frame {
+            self.handle_deferred_frame(pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        // The MMDS network stack works like a state machine, based on synchronous calls, and
-        // without being added to any event loop. If any frame is accepted by the MMDS, we also
-        // trigger a process_rx() which checks if there are any new frames to be sent, starting
-        // with the MMDS network stack.
+        // The MMDS network stack and the DHCP server work like state machines, based on
+        // synchronous calls, and without being added to any event loop. If any frame is accepted
+        // by either, we also trigger a process_rx() which checks if there are any new frames to
+        // be sent, starting with theirs.
         let mut process_rx_for_mmds = false;
         let mut used_any = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(pair)];
+        let queue_pair = &mut self.queue_pairs[pair];
 
         while let Some(head) = tx_queue.pop_or_enable_notification(mem) {
             let head_index = head.index;
@@ -571,14 +1188,17 @@ impl Net {
 
             let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
+                self.dhcp_server.as_mut(),
                 &mut self.tx_rate_limiter,
                 &mut self.tx_frame_headers,
                 &buffer,
-                &mut self.tap,
+                queue_pair,
                 self.guest_mac,
+                &mut self.capture,
+                self.tx_filter.as_ref(),
             )
             .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
+            if frame_consumed_by_mmds && !queue_pair.rx_deferred_frame {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
             }
@@ -589,18 +1209,146 @@ impl Net {
             used_any = true;
         }
 
-        if !used_any {
-            METRICS.net.no_tx_avail_buffer.inc();
+        if !used_any {
+            METRICS.net.no_tx_avail_buffer.inc();
+        }
+
+        self.signal_used_queue(tx_queue_index(pair))?;
+
+        // An incoming frame for the MMDS may trigger the transmission of a new message.
+        if process_rx_for_mmds {
+            self.process_rx(pair)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn process_ctrl(&mut self) -> result::Result<(), DeviceError> {
+        // The control queue event is only registered for devices which have a control queue.
+        let ctrl_index = self.ctrl_queue_index().unwrap();
+
+        loop {
+            // This is safe since we checked in the event handler that the device is activated.
+            let mem = self.device_state.mem().unwrap();
+            let head = match self.queues[ctrl_index].pop_or_enable_notification(mem) {
+                Some(head) => head,
+                None => break,
+            };
+            let head_index = head.index;
+
+            let used_len = match CtrlCommand::parse(mem, head) {
+                Ok(command) => {
+                    let status = if self.handle_ctrl_command(&command) {
+                        VIRTIO_NET_OK as u8
+                    } else {
+                        METRICS.net.ctrl_fails.inc();
+                        VIRTIO_NET_ERR as u8
+                    };
+                    let mem = self.device_state.mem().unwrap();
+                    match mem.write_obj(status, command.ack_addr) {
+                        Ok(()) => 1,
+                        Err(err) => {
+                            error!("Failed to write the control command status: {:?}", err);
+                            METRICS.net.ctrl_fails.inc();
+                            0
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Invalid control command: {:?}", err);
+                    METRICS.net.ctrl_fails.inc();
+                    0
+                }
+            };
+
+            let mem = self.device_state.mem().unwrap();
+            self.queues[ctrl_index]
+                .add_used(mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+        }
+
+        self.signal_used_queue(ctrl_index)
+    }
+
+    // Applies a command received on the control queue. Returns whether it succeeded.
+    fn handle_ctrl_command(&mut self, command: &CtrlCommand) -> bool {
+        match (u32::from(command.class), u32::from(command.cmd)) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                self.ctrl_set_queue_pairs(&command.data)
+            }
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_RX)) =>
+            {
+                self.rx_filter.set_promisc(&command.data)
+            }
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_RX)) =>
+            {
+                self.rx_filter.set_allmulti(&command.data)
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_RX)) =>
+            {
+                self.rx_filter.set_mac_tables(&command.data)
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_MAC_ADDR)) =>
+            {
+                self.ctrl_set_mac(&command.data)
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_VLAN)) =>
+            {
+                self.rx_filter.add_vlan(&command.data)
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_DEL)
+                if self.has_feature(u64::from(VIRTIO_NET_F_CTRL_VLAN)) =>
+            {
+                self.rx_filter.remove_vlan(&command.data)
+            }
+            (class, cmd) => {
+                warn!(
+                    "Unsupported control command: class {}, command {}",
+                    class, cmd
+                );
+                false
+            }
+        }
+    }
+
+    fn ctrl_set_queue_pairs(&mut self, data: &[u8]) -> bool {
+        let count = match data {
+            [low, high] => usize::from(u16::from_le_bytes([*low, *high])),
+            _ => return false,
+        };
+        if !self.has_feature(u64::from(VIRTIO_NET_F_MQ)) {
+            warn!("The driver set the number of queue pairs without negotiating the feature.");
+            return false;
         }
 
-        self.signal_used_queue(NetQueue::Tx)?;
+        match self.set_active_queue_pairs(count) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Failed to set the number of queue pairs: {}", err);
+                false
+            }
+        }
+    }
 
-        // An incoming frame for the MMDS may trigger the transmission of a new message.
-        if process_rx_for_mmds {
-            self.process_rx()
-        } else {
-            Ok(())
+    fn ctrl_set_mac(&mut self, data: &[u8]) -> bool {
+        if data.len() != MAC_ADDR_LEN {
+            return false;
+        }
+        if self.is_mac_locked() {
+            warn!("The driver set the MAC address while it is locked by the TX filter.");
+            return false;
         }
+
+        let mac = MacAddr::from_bytes_unchecked(data);
+        self.config_space.guest_mac = mac;
+        self.guest_mac = Some(mac);
+        METRICS.net.mac_address_updates.inc();
+        true
     }
 
     /// Updates the parameters for the rate limiters
@@ -615,20 +1363,100 @@ impl Net {
         self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
     }
 
+    /// Starts or stops capturing the frames exchanged by the device. Starting a capture while
+    /// one is running replaces it.
+    pub fn update_capture(&mut self, config: &CaptureConfig) -> Result<()> {
+        if let Some(mut writer) = self.capture.take() {
+            // Records the file still doesn't accept are lost.
+            writer.flush().map_err(Error::IO)?;
+        }
+        if config.enabled {
+            self.capture = Some(Capture::new(config).map_err(Error::Capture)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the frames exchanged by the device are captured.
+    pub fn is_captured(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Sets or removes the filter of the frames sent by the guest.
+    pub fn set_tx_filter(&mut self, filter: Option<TxFilterConfig>) -> Result<()> {
+        if let Some(filter) = filter.as_ref() {
+            filter.validate(self.guest_mac).map_err(Error::TxFilter)?;
+        }
+        self.tx_filter = filter;
+        Ok(())
+    }
+
+    /// Provides the filter of the frames sent by the guest.
+    pub fn tx_filter(&self) -> Option<&TxFilterConfig> {
+        self.tx_filter.as_ref()
+    }
+
+    /// Whether the link is reported as up to the driver.
+    pub fn is_link_up(&self) -> bool {
+        self.config_space.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Whether the link state is reported to the driver.
+    pub fn has_link_state_reporting(&self) -> bool {
+        self.avail_features & (1 << VIRTIO_NET_F_STATUS) != 0
+    }
+
+    /// Reports the link state to the driver, or stops reporting it, in which case the driver
+    /// considers the link up. Drivers reading the link state prevent saving the device to
+    /// snapshots of older versions, so it is not reported by default. Must be called before the
+    /// driver negotiates the features.
+    pub fn set_link_state_reporting(&mut self, enabled: bool) {
+        if enabled {
+            self.avail_features |= 1 << VIRTIO_NET_F_STATUS;
+        } else {
+            self.avail_features &= !(1 << VIRTIO_NET_F_STATUS);
+        }
+    }
+
+    /// Sets the state of the link reported to the driver, which is notified of the change
+    /// once the device is activated. The frames are still exchanged while the link is down.
+    /// The link can only be brought down when its state is reported.
+    pub fn set_link_up(&mut self, link_up: bool) -> Result<()> {
+        if link_up == self.is_link_up() {
+            return Ok(());
+        }
+        if !self.has_link_state_reporting() {
+            return Err(Error::LinkStateUnsupported);
+        }
+        if link_up {
+            self.config_space.status |= VIRTIO_NET_S_LINK_UP as u16;
+        } else {
+            self.config_space.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+        METRICS.net.link_state_updates.inc();
+
+        if self.is_activated() {
+            self.irq_trigger
+                .trigger_irq(IrqType::Config)
+                .map_err(Error::EventFd)?;
+        }
+        Ok(())
+    }
+
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> std::io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair: usize) -> std::io::Result<usize> {
+        let queue_pair = &mut self.queue_pairs[pair];
+        queue_pair.backend.read(&mut queue_pair.rx_frame_buf)
     }
 
     #[cfg(not(test))]
-    fn write_tap(tap: &mut Tap, buf: &IoVecBuffer) -> std::io::Result<usize> {
-        tap.write_vectored(buf)
+    fn write_tap(queue_pair: &mut QueuePair, buf: &IoVecBuffer) -> std::io::Result<usize> {
+        queue_pair.backend.write_vectored(buf)
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    pub fn process_rx_queue_event(&mut self, pair: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(err) = self.queue_evts[RX_INDEX].read() {
+        if let Err(err) = self.queue_evts[rx_queue_index(pair)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
@@ -636,11 +1464,11 @@ impl Net {
             METRICS.net.rx_rate_limiter_throttled.inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            self.resume_rx().unwrap_or_else(report_net_event_fail);
+            self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.net.rx_tap_event_count.inc();
@@ -649,7 +1477,8 @@ impl Net {
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        let rx_deferred_frame = self.queue_pairs[pair].rx_deferred_frame;
+        if self.queues[rx_queue_index(pair)].is_empty(mem) && rx_deferred_frame {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
@@ -660,31 +1489,43 @@ impl Net {
             return;
         }
 
-        if self.rx_deferred_frame
+        if rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, pair: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(err) = self.queue_evts[TX_INDEX].read() {
+        if let Err(err) = self.queue_evts[tx_queue_index(pair)].read() {
             error!("Failed to get tx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
         } else if !self.tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair).unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
         }
     }
 
+    pub fn process_ctrl_queue_event(&mut self) {
+        METRICS.net.ctrl_queue_event_count.inc();
+        // The control queue event is only registered for devices which have a control queue.
+        let ctrl_index = self.ctrl_queue_index().unwrap();
+        if let Err(err) = self.queue_evts[ctrl_index].read() {
+            error!("Failed to get ctrl queue event: {:?}", err);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl().unwrap_or_else(report_net_event_fail);
+        }
+    }
+
     pub fn process_rx_rate_limiter_event(&mut self) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
@@ -692,8 +1533,10 @@ impl Net {
 
         match self.rx_rate_limiter.event_handler() {
             Ok(_) => {
-                // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to receive the frames.
+                for pair in 0..self.active_queue_pairs {
+                    self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
+                }
             }
             Err(err) => {
                 error!("Failed to get rx rate-limiter event: {:?}", err);
@@ -708,8 +1551,10 @@ impl Net {
         // and restart processing the queue.
         match self.tx_rate_limiter.event_handler() {
             Ok(_) => {
-                // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to send the frames.
+                for pair in 0..self.active_queue_pairs {
+                    self.process_tx(pair).unwrap_or_else(report_net_event_fail);
+                }
             }
             Err(err) => {
                 error!("Failed to get tx rate-limiter event: {:?}", err);
@@ -720,8 +1565,10 @@ impl Net {
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        for pair in 0..self.active_queue_pairs {
+            let _ = self.resume_rx(pair);
+            let _ = self.process_tx(pair);
+        }
     }
 }
 
@@ -743,11 +1590,12 @@ impl VirtioDevice for Net {
     }
 
     fn queues(&self) -> &[Queue] {
-        &self.queues
+        &self.queues[..self.num_exposed_queues()]
     }
 
     fn queues_mut(&mut self) -> &mut [Queue] {
-        &mut self.queues
+        let num_queues = self.num_exposed_queues();
+        &mut self.queues[..num_queues]
     }
 
     fn queue_events(&self) -> &[EventFd] {
@@ -781,13 +1629,19 @@ impl VirtioDevice for Net {
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
-        let config_space_bytes = self.config_space.as_mut_slice();
+        // Only the MAC address can be written by the driver.
+        let config_space_bytes = &mut self.config_space.as_mut_slice()[..MAC_ADDR_LEN];
         let config_len = config_space_bytes.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
             METRICS.net.cfg_fails.inc();
             return;
         }
+        if self.is_mac_locked() {
+            error!("Failed to write config space: the guest MAC is locked by the TX filter");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
 
         config_space_bytes[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
         self.guest_mac = Some(self.config_space.guest_mac);
@@ -819,18 +1673,21 @@ impl VirtioDevice for Net {
 #[macro_use]
 pub mod tests {
     use std::net::Ipv4Addr;
+    use std::os::unix::net::UnixDatagram;
     use std::time::Duration;
     use std::{io, mem, thread};
 
     use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+    use dumbo::pdu::dhcp::MessageType as DhcpMessageType;
     use dumbo::pdu::ethernet::ETHERTYPE_ARP;
     use logger::{IncMetric, METRICS};
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
     use utils::net::mac::MAC_ADDR_LEN;
+    use utils::tempfile::TempFile;
     use virtio_gen::virtio_net::{
         virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
         VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
-        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF,
     };
     use vm_memory::{Address, GuestMemory};
 
@@ -838,35 +1695,46 @@ pub mod tests {
     use crate::check_metric_after_block;
     use crate::virtio::net::device::{
         frame_bytes_from_buf, frame_bytes_from_buf_mut, init_vnet_hdr, vnet_hdr_len,
+        NUM_BUFFERS_OFFSET,
     };
+    use crate::virtio::net::dhcp::tests::{dhcp_config, dhcp_request_frame};
+    use crate::virtio::net::dhcp::DHCP_SERVER_MAC;
+    use crate::virtio::net::filter::{FilterAction, FilterProtocol, TxFilterRule};
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
+        capture_records, default_guest_mac, default_guest_memory, default_net, if_index,
+        inject_tap_tx_frame, set_mac, temp_socket_path, NetEvent, NetQueue, ReadTapMock,
         TapTrafficSimulator, WriteTapMock,
     };
-    use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::net::{CaptureError, DhcpError, TxFilterError, UserNatError, QUEUE_SIZES};
+    use crate::virtio::test_utils::VirtQueue;
     use crate::virtio::{
-        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_WRITE,
+        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
     };
 
     impl Net {
-        pub(crate) fn read_tap(&mut self) -> io::Result<usize> {
-            match &self.tap.mocks.read_tap {
+        pub(crate) fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
+            let queue_pair = &mut self.queue_pairs[pair];
+            match &queue_pair.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(frame);
+                    queue_pair.rx_frame_buf[..frame.len()].copy_from_slice(frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => queue_pair.backend.read(&mut queue_pair.rx_frame_buf),
             }
         }
 
-        pub(crate) fn write_tap(tap: &mut Tap, buf: &IoVecBuffer) -> io::Result<usize> {
-            match tap.mocks.write_tap {
-                WriteTapMock::Success => tap.write_vectored(buf),
+        pub(crate) fn write_tap(
+            queue_pair: &mut QueuePair,
+            buf: &IoVecBuffer,
+        ) -> io::Result<usize> {
+            match queue_pair.mocks.write_tap {
+                WriteTapMock::Success => queue_pair.backend.write_vectored(buf),
                 WriteTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Write tap mock failure.",
@@ -951,10 +1819,60 @@ pub mod tests {
 
         // Invalid read.
         config_mac = [0u8; MAC_ADDR_LEN];
-        net.read_config(MAC_ADDR_LEN as u64, &mut config_mac);
+        net.read_config(mem::size_of::<ConfigSpace>() as u64, &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
     }
 
+    #[test]
+    fn test_link_state() {
+        let mut th = TestHelper::get_default();
+        let status_offset = MAC_ADDR_LEN as u64;
+        let mut status = [0u8; 2];
+
+        // The link can't be brought down until its state is reported.
+        assert!(!th.net().has_link_state_reporting());
+        assert!(matches!(
+            th.net().set_link_up(false),
+            Err(Error::LinkStateUnsupported)
+        ));
+        th.net().set_link_up(true).unwrap();
+        th.net().set_link_state_reporting(true);
+        assert!(th.net().has_link_state_reporting());
+
+        // The link is up by default.
+        th.net().read_config(status_offset, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        assert!(th.net().is_link_up());
+
+        // The driver isn't notified before the device is activated.
+        th.net().set_link_up(false).unwrap();
+        assert!(!th.net().is_link_up());
+        assert!(!th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        th.activate_net();
+        th.net().set_link_up(true).unwrap();
+        th.net().read_config(status_offset, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        assert!(th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        // Setting the current state doesn't notify the driver again.
+        check_metric_after_block!(
+            &METRICS.net.link_state_updates,
+            0,
+            th.net().set_link_up(true).unwrap()
+        );
+        assert!(!th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        check_metric_after_block!(
+            &METRICS.net.link_state_updates,
+            1,
+            th.net().set_link_up(false).unwrap()
+        );
+        th.net().read_config(status_offset, &mut status);
+        assert_eq!(u16::from_le_bytes(status), 0);
+        assert!(th.net().irq_trigger.has_pending_irq(IrqType::Config));
+    }
+
     #[test]
     fn test_virtio_device_rewrite_config() {
         let mut net = default_net();
@@ -1063,7 +1981,9 @@ pub mod tests {
     fn test_rx_retry() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_read_tap(ReadTapMock::TapFrame);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
 
         // Add invalid descriptor chain - read only descriptor.
         th.add_desc_chain(
@@ -1103,7 +2023,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1113,7 +2033,9 @@ pub mod tests {
     fn test_rx_complex_desc_chain() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_read_tap(ReadTapMock::TapFrame);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
 
         // Create a valid Rx avail descriptor chain with multiple descriptors.
         th.add_desc_chain(
@@ -1136,7 +2058,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1151,7 +2073,9 @@ pub mod tests {
     fn test_rx_multiple_frames() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_read_tap(ReadTapMock::TapFrame);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
 
         // Create 2 valid Rx avail descriptor chains. Each one has enough space to fit the
         // following 2 frames. But only 1 frame has to be written to each chain.
@@ -1175,7 +2099,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1189,11 +2113,64 @@ pub mod tests {
         th.rxq.dtable[3].check_data(&[0; 500]);
     }
 
+    #[test]
+    fn test_rx_mergeable_buffers() {
+        let mut th = TestHelper::get_default();
+        // The feature is only offered when enabled.
+        assert!(!th.net().has_mergeable_rx_buffers());
+        th.net().set_mergeable_rx_buffers(true);
+        assert!(th.net().has_mergeable_rx_buffers());
+        th.net().set_acked_features(1 << VIRTIO_NET_F_MRG_RXBUF);
+        th.activate_net();
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
+
+        // Add 2 descriptor chains, which can't hold the whole frame.
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, 500, VIRTQ_DESC_F_WRITE)]);
+        th.add_desc_chain(
+            NetQueue::Rx,
+            500,
+            &[(1, 300, VIRTQ_DESC_F_WRITE), (2, 200, VIRTQ_DESC_F_WRITE)],
+        );
+        let mut frame = inject_tap_tx_frame(&th.net(), 1200);
+        check_metric_after_block!(
+            METRICS.net.no_rx_avail_buffer,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+
+        // Check that the frame was deferred and no descriptor chain was used.
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
+        assert_eq!(th.rxq.used.idx.get(), 0);
+
+        // Add a third descriptor chain and check that the frame is spread across all of them.
+        th.add_desc_chain(NetQueue::Rx, 1000, &[(3, 500, VIRTQ_DESC_F_WRITE)]);
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
+        assert_eq!(th.rxq.used.idx.get(), 3);
+        assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
+        th.rxq.check_used_elem(0, 0, 500);
+        th.rxq.check_used_elem(1, 1, 500);
+        th.rxq.check_used_elem(2, 3, 200);
+        // The number of used descriptor chains is reported in the VNET header.
+        frame[NUM_BUFFERS_OFFSET..vnet_hdr_len()].copy_from_slice(&3u16.to_le_bytes());
+        th.rxq.dtable[0].check_data(&frame[..500]);
+        th.rxq.dtable[1].check_data(&frame[500..800]);
+        th.rxq.dtable[2].check_data(&frame[800..1000]);
+        th.rxq.dtable[3].check_data(&frame[1000..]);
+    }
+
     #[test]
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1213,7 +2190,7 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1232,7 +2209,7 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1254,7 +2231,7 @@ pub mod tests {
     fn test_tx_empty_frame() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 0, 0)]);
@@ -1276,7 +2253,7 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1318,7 +2295,7 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1346,7 +2323,9 @@ pub mod tests {
     fn test_tx_tap_failure() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_write_tap(WriteTapMock::Failure);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_write_tap(WriteTapMock::Failure);
 
         let desc_list = [(0, 1000, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1364,11 +2343,71 @@ pub mod tests {
         th.txq.check_used_elem(0, 0, 0);
     }
 
+    #[test]
+    fn test_capture() {
+        let mut th = TestHelper::get_default();
+        th.activate_net();
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
+        let capture_file = TempFile::new().unwrap();
+        let capture_path = capture_file.as_path().to_str().unwrap().to_string();
+
+        // A capture needs a path.
+        let mut config = CaptureConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            th.net().update_capture(&config),
+            Err(Error::Capture(CaptureError::MissingPath))
+        ));
+        assert!(!th.net().is_captured());
+
+        config.path_on_host = Some(capture_path.clone());
+        config.snap_len = Some(100);
+        th.net().update_capture(&config).unwrap();
+        assert!(th.net().is_captured());
+
+        // Send a frame from the guest.
+        let desc_list = [(0, 50, 0), (1, 250, 0)];
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        let tx_frame = th.write_tx_frame(&desc_list, 300);
+        th.event_manager.run_with_timeout(100).unwrap();
+
+        // Deliver a frame to the guest.
+        th.add_desc_chain(NetQueue::Rx, 1000, &[(2, 500, VIRTQ_DESC_F_WRITE)]);
+        let rx_frame = inject_tap_tx_frame(&th.net(), 200);
+        th.event_manager.run_with_timeout(100).unwrap();
+
+        // Stopping the capture writes what is left of it.
+        th.net().update_capture(&CaptureConfig::default()).unwrap();
+        assert!(!th.net().is_captured());
+
+        // Only the first bytes of the frames are captured, without the VNET header.
+        let records = capture_records(&std::fs::read(&capture_path).unwrap());
+        assert_eq!(
+            records,
+            vec![
+                (
+                    100,
+                    (tx_frame.len() - vnet_hdr_len()) as u32,
+                    tx_frame[vnet_hdr_len()..vnet_hdr_len() + 100].to_vec()
+                ),
+                (
+                    100,
+                    (rx_frame.len() - vnet_hdr_len()) as u32,
+                    rx_frame[vnet_hdr_len()..vnet_hdr_len() + 100].to_vec()
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().iface_name()));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1435,6 +2474,14 @@ pub mod tests {
     #[test]
     fn test_mmds_detour_and_injection() {
         let mut net = default_net();
+        let capture_file = TempFile::new().unwrap();
+        let capture_path = capture_file.as_path().to_str().unwrap().to_string();
+        net.update_capture(&CaptureConfig {
+            enabled: true,
+            path_on_host: Some(capture_path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
 
         let src_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
         let src_ip = Ipv4Addr::new(10, 1, 2, 3);
@@ -1454,11 +2501,14 @@ pub mod tests {
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
+                None,
                 &mut net.tx_rate_limiter,
                 &mut headers,
                 &buffer,
-                &mut net.tap,
+                &mut net.queue_pairs[0],
                 Some(src_mac),
+                &mut net.capture,
+                None,
             )
             .unwrap())
         );
@@ -1467,8 +2517,85 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
+        );
+
+        // The frame detoured to MMDS is captured.
+        let records = capture_records(&std::fs::read(&capture_path).unwrap());
+        let request = frame_buf[vnet_hdr_len()..frame_len].to_vec();
+        assert_eq!(
+            records,
+            vec![(request.len() as u32, request.len() as u32, request)]
+        );
+    }
+
+    #[test]
+    fn test_dhcp_detour_and_injection() {
+        let mut net = default_net();
+        let mut config = dhcp_config();
+        config.gateway = None;
+        assert!(matches!(
+            net.set_dhcp(Some(config.clone())),
+            Err(Error::Dhcp(DhcpError::MissingServerAddress))
+        ));
+        assert!(net.dhcp_config().is_none());
+        config = dhcp_config();
+        net.set_dhcp(Some(config.clone())).unwrap();
+        assert_eq!(net.dhcp_config(), Some(&config));
+
+        let mut frame_buf = vec![0u8; vnet_hdr_len()];
+        frame_buf.extend(dhcp_request_frame(
+            DhcpMessageType::Discover,
+            Ipv4Addr::UNSPECIFIED,
+            &[],
+        ));
+        let buffer = IoVecBuffer::from(frame_buf.as_slice());
+        let mut headers = vec![0; frame_hdr_len()];
+
+        // The DHCP server consumes the frame, which doesn't reach the tap.
+        check_metric_after_block!(
+            &METRICS.net.dhcp_requests,
+            1,
+            assert!(Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                net.dhcp_server.as_mut(),
+                &mut net.tx_rate_limiter,
+                &mut headers,
+                &buffer,
+                &mut net.queue_pairs[0],
+                net.guest_mac,
+                &mut net.capture,
+                None,
+            )
+            .unwrap())
+        );
+
+        // The offer is delivered to the guest, after the VNET header.
+        let mut len = 0;
+        check_metric_after_block!(
+            &METRICS.net.dhcp_replies,
+            1,
+            len = net.read_from_mmds_or_tap(0).unwrap()
         );
+        let frame = &net.queue_pairs[0].rx_frame_buf[vnet_hdr_len()..len];
+        let eth = EthernetFrame::from_bytes(frame).unwrap();
+        assert_eq!(eth.src_mac().get_bytes(), &DHCP_SERVER_MAC);
+
+        // Once stopped, the DHCP server lets the frames through.
+        net.set_dhcp(None).unwrap();
+        assert!(net.dhcp_config().is_none());
+        assert!(!Net::write_to_mmds_or_tap(
+            net.mmds_ns.as_mut(),
+            net.dhcp_server.as_mut(),
+            &mut net.tx_rate_limiter,
+            &mut headers,
+            &buffer,
+            &mut net.queue_pairs[0],
+            net.guest_mac,
+            &mut net.capture,
+            None,
+        )
+        .unwrap());
     }
 
     #[test]
@@ -1491,11 +2618,14 @@ pub mod tests {
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
+                None,
                 &mut net.tx_rate_limiter,
                 &mut headers,
                 &buffer,
-                &mut net.tap,
+                &mut net.queue_pairs[0],
                 Some(guest_mac),
+                &mut None,
+                None,
             )
         );
 
@@ -1505,12 +2635,100 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
+                None,
                 &mut net.tx_rate_limiter,
                 &mut headers,
                 &buffer,
-                &mut net.tap,
+                &mut net.queue_pairs[0],
                 Some(not_guest_mac),
+                &mut None,
+                None,
+            )
+        );
+    }
+
+    #[test]
+    fn test_tx_filter() {
+        let mut net = default_net();
+        let guest_mac = *net.guest_mac().unwrap();
+        let not_guest_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+
+        // A port can only be matched for TCP and UDP.
+        let mut filter = TxFilterConfig {
+            check_source_mac: true,
+            allowed_ipv4_sources: Some(vec![guest_ip]),
+            rules: vec![TxFilterRule {
+                action: FilterAction::Deny,
+                protocol: FilterProtocol::Arp,
+                port: Some(22),
+            }],
+            default_action: FilterAction::Allow,
+        };
+        assert!(matches!(
+            net.set_tx_filter(Some(filter.clone())),
+            Err(Error::TxFilter(TxFilterError::PortWithoutTransport(0)))
+        ));
+        filter.rules.clear();
+        net.set_tx_filter(Some(filter.clone())).unwrap();
+        assert_eq!(net.tx_filter(), Some(&filter));
+
+        let mut headers = vec![0; frame_hdr_len()];
+        let mut send = |net: &mut Net, src_mac: MacAddr, spa: Ipv4Addr| {
+            let (frame_buf, frame_len) = create_arp_request(src_mac, spa, dst_mac, dst_ip);
+            let buffer = IoVecBuffer::from(&frame_buf[..frame_len]);
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                None,
+                &mut net.tx_rate_limiter,
+                &mut headers,
+                &buffer,
+                &mut net.queue_pairs[0],
+                net.guest_mac,
+                &mut None,
+                net.tx_filter.as_ref(),
             )
+            .unwrap();
+        };
+
+        check_metric_after_block!(
+            &METRICS.net.tx_packets_count,
+            1,
+            send(&mut net, guest_mac, guest_ip)
+        );
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_mac_drops,
+            1,
+            send(&mut net, not_guest_mac, guest_ip)
+        );
+        check_metric_after_block!(
+            &METRICS.net.tx_packets_count,
+            0,
+            send(&mut net, not_guest_mac, guest_ip)
+        );
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_ip_drops,
+            1,
+            send(&mut net, guest_mac, dst_ip)
+        );
+
+        // The guest can't change the MAC the frames are checked against.
+        check_metric_after_block!(
+            &METRICS.net.cfg_fails,
+            1,
+            net.write_config(0, not_guest_mac.get_bytes())
+        );
+        assert_eq!(net.guest_mac(), Some(&guest_mac));
+
+        // Without a filter, every frame is sent.
+        net.set_tx_filter(None).unwrap();
+        assert!(net.tx_filter().is_none());
+        check_metric_after_block!(
+            &METRICS.net.tx_packets_count,
+            1,
+            send(&mut net, not_guest_mac, dst_ip)
         );
     }
 
@@ -1543,10 +2761,12 @@ pub mod tests {
     fn test_read_tap_fail_event_handler() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_read_tap(ReadTapMock::Failure);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().queue_pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1556,7 +2776,7 @@ pub mod tests {
         // We need to set this here to false, otherwise the device will try to
         // handle a deferred frame, it will fail and will never try to read from
         // the tap.
-        th.net().rx_deferred_frame = false;
+        th.net().queue_pairs[0].rx_deferred_frame = false;
 
         // Fake an avail buffer; this time, tap reading should error out.
         th.rxq.avail.idx.set(1);
@@ -1571,7 +2791,9 @@ pub mod tests {
     fn test_deferred_frame() {
         let mut th = TestHelper::get_default();
         th.activate_net();
-        th.net().tap.mocks.set_read_tap(ReadTapMock::TapFrame);
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
 
         let rx_packets_count = METRICS.net.rx_packets_count.count();
         let _ = inject_tap_tx_frame(&th.net(), 1000);
@@ -1584,7 +2806,7 @@ pub mod tests {
         );
         // The frame we read from the tap should be deferred now and
         // no frames should have been transmitted
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count);
 
         // Let's add a second frame, which should really have the same
@@ -1601,7 +2823,7 @@ pub mod tests {
             th.simulate_event(NetEvent::Tap)
         );
         // We should still have a deferred frame
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         // However, we should have delivered the first frame
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count + 1);
 
@@ -1614,7 +2836,7 @@ pub mod tests {
         );
 
         // We should be done with any deferred frame
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
     }
 
     #[test]
@@ -1731,7 +2953,7 @@ pub mod tests {
             th.net().rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1742,7 +2964,7 @@ pub mod tests {
                 // assert that limiter is blocked
                 assert!(th.net().rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1763,7 +2985,7 @@ pub mod tests {
 
             // following RX procedure should succeed because bandwidth should now be available
             {
-                let frame = &th.net().tap.mocks.read_tap.mock_frame();
+                let frame = &th.net().queue_pairs[0].mocks.read_tap.mock_frame();
                 // no longer throttled
                 check_metric_after_block!(
                     &METRICS.net.rx_rate_limiter_throttled,
@@ -1844,7 +3066,7 @@ pub mod tests {
             th.net().rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1859,7 +3081,7 @@ pub mod tests {
                 // assert that limiter is blocked
                 assert!(th.net().rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1879,7 +3101,7 @@ pub mod tests {
 
             // following RX procedure should succeed because ops should now be available
             {
-                let frame = &th.net().tap.mocks.read_tap.mock_frame();
+                let frame = &th.net().queue_pairs[0].mocks.read_tap.mock_frame();
                 th.simulate_event(NetEvent::RxRateLimiter);
                 // make sure the virtio queue operation completed this time
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1944,8 +3166,9 @@ pub mod tests {
         assert_eq!(queues[RX_INDEX].size, th.rxq.size());
         assert_eq!(queues[TX_INDEX].size, th.txq.size());
 
-        // Test corresponding queues events.
-        assert_eq!(net.queue_events().len(), QUEUE_SIZES.len());
+        // Test corresponding queues events, which include the unused control queue.
+        assert_eq!(net.queue_events().len(), QUEUE_SIZES.len() + 1);
+        assert_eq!(net.ctrl_queue_index(), Some(QUEUE_SIZES.len()));
 
         // Test interrupts.
         assert!(!&net.irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1964,4 +3187,395 @@ pub mod tests {
         assert!(queues[RX_INDEX].uses_notif_suppression);
         assert!(queues[TX_INDEX].uses_notif_suppression);
     }
+
+    fn multi_queue_net(num_queue_pairs: usize) -> Net {
+        Net::new_with_tap(
+            "mq-net".to_string(),
+            "mqnet%d",
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            num_queue_pairs,
+        )
+        .unwrap()
+    }
+
+    // Adds a control command to `ctrlq`, returning the address of its status byte.
+    fn add_ctrl_command(
+        mem: &GuestMemoryMmap,
+        ctrlq: &VirtQueue,
+        index: u16,
+        class: u8,
+        cmd: u8,
+        data: &[u8],
+    ) -> u64 {
+        let addr = 0x2000 + u64::from(index) * 0x100;
+        mem.write_slice(&[class, cmd], GuestAddress(addr)).unwrap();
+        mem.write_slice(data, GuestAddress(addr + 0x10)).unwrap();
+        ctrlq.dtable[0].set(addr, 2, VIRTQ_DESC_F_NEXT, 1);
+        ctrlq.dtable[1].set(addr + 0x10, data.len() as u32, VIRTQ_DESC_F_NEXT, 2);
+        ctrlq.dtable[2].set(addr + 0x20, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.avail.ring[index as usize].set(0);
+        ctrlq.avail.idx.set(index + 1);
+
+        addr + 0x20
+    }
+
+    #[test]
+    fn test_multi_queue_device() {
+        for num_queue_pairs in [0, MAX_QUEUE_PAIRS + 1] {
+            assert!(matches!(
+                Net::new_with_tap(
+                    "mq-net".to_string(),
+                    "mqnet%d",
+                    None,
+                    RateLimiter::default(),
+                    RateLimiter::default(),
+                    num_queue_pairs,
+                ),
+                Err(Error::InvalidQueuePairs(count)) if count == num_queue_pairs
+            ));
+        }
+
+        let mut net = multi_queue_net(4);
+        assert_eq!(net.num_queue_pairs(), 4);
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert_ne!(net.iface_name(), "mqnet%d");
+        // 4 rx/tx queue pairs and the control queue.
+        assert_eq!(net.queues().len(), 9);
+        assert_eq!(net.queue_events().len(), 9);
+        assert_eq!(net.ctrl_queue_index(), Some(8));
+
+        let mq_features = 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+        assert_eq!(net.avail_features() & mq_features, mq_features);
+        assert_eq!(default_net().avail_features() & mq_features, 0);
+        // The control queue stays offered without its commands.
+        assert!(!net.has_ctrl_queue());
+        net.set_ctrl_queue(true);
+        net.set_ctrl_queue(false);
+        assert_eq!(net.avail_features() & mq_features, mq_features);
+        assert_eq!(
+            net.avail_features() & CTRL_FEATURES,
+            1 << VIRTIO_NET_F_CTRL_VQ
+        );
+
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 4);
+
+        // Only the TAP queue of the first queue pair is attached.
+        net.queue_pairs[0]
+            .backend
+            .set_queue_enabled(true)
+            .unwrap_err();
+        net.queue_pairs[1]
+            .backend
+            .set_queue_enabled(false)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_socket_device() {
+        let socket_path = temp_socket_path();
+        // Nothing listens on the socket yet.
+        assert!(matches!(
+            Net::new_with_socket(
+                "socket-net".to_string(),
+                &socket_path,
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+            ),
+            Err(Error::UnixSocketConnect(_))
+        ));
+
+        let _peer = UnixDatagram::bind(&socket_path).unwrap();
+        let net = Net::new_with_socket(
+            "socket-net".to_string(),
+            &socket_path,
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert_eq!(net.host_socket_path(), Some(&socket_path));
+        assert_eq!(net.iface_name(), "");
+        assert_eq!(net.num_queue_pairs(), 1);
+        assert_eq!(default_net().host_socket_path(), None);
+
+        // The frames carry no VNET header on the socket, so the offloads can't be offered.
+        assert_eq!(net.avail_features() & TAP_OFFLOAD_FEATURES, 0);
+        assert_eq!(
+            default_net().avail_features() & TAP_OFFLOAD_FEATURES,
+            TAP_OFFLOAD_FEATURES
+        );
+    }
+
+    #[test]
+    fn test_user_nat_device() {
+        let config = UserNatConfig {
+            prefix_len: 30,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Net::new_with_user_nat(
+                "nat-net".to_string(),
+                &config,
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+            ),
+            Err(Error::UserNat(UserNatError::InvalidPrefixLen(30)))
+        ));
+
+        let config = UserNatConfig::default();
+        let net = Net::new_with_user_nat(
+            "nat-net".to_string(),
+            &config,
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
+        assert_eq!(net.user_nat(), Some(&config));
+        assert_eq!(net.host_socket_path(), None);
+        assert_eq!(net.iface_name(), "");
+        assert_eq!(net.num_queue_pairs(), 1);
+        assert_eq!(net.avail_features() & TAP_OFFLOAD_FEATURES, 0);
+        assert_eq!(default_net().user_nat(), None);
+    }
+
+    #[test]
+    fn test_ctrl_queue() {
+        let mut net = multi_queue_net(4);
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[8] = ctrlq.create_queue();
+        net.set_acked_features(1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ);
+        net.activate(mem.clone()).unwrap();
+
+        // Use 3 queue pairs.
+        let ack_addr = add_ctrl_command(
+            &mem,
+            &ctrlq,
+            0,
+            VIRTIO_NET_CTRL_MQ as u8,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+            &3u16.to_le_bytes(),
+        );
+        net.queue_evts[8].write(1).unwrap();
+        net.process_ctrl_queue_event();
+        assert_eq!(ctrlq.used.idx.get(), 1);
+        ctrlq.check_used_elem(0, 0, 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap(),
+            VIRTIO_NET_OK as u8
+        );
+        assert_eq!(net.active_queue_pairs(), 3);
+        net.queue_pairs[2]
+            .backend
+            .set_queue_enabled(true)
+            .unwrap_err();
+        net.queue_pairs[3]
+            .backend
+            .set_queue_enabled(false)
+            .unwrap_err();
+
+        // The device doesn't have 5 queue pairs.
+        let ack_addr = add_ctrl_command(
+            &mem,
+            &ctrlq,
+            1,
+            VIRTIO_NET_CTRL_MQ as u8,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+            &5u16.to_le_bytes(),
+        );
+        net.queue_evts[8].write(1).unwrap();
+        check_metric_after_block!(METRICS.net.ctrl_fails, 1, net.process_ctrl_queue_event());
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+        assert_eq!(net.active_queue_pairs(), 3);
+
+        // Unsupported command.
+        let ack_addr = add_ctrl_command(&mem, &ctrlq, 2, VIRTIO_NET_CTRL_MQ as u8, 0xff, &[0]);
+        net.queue_evts[8].write(1).unwrap();
+        check_metric_after_block!(METRICS.net.ctrl_fails, 1, net.process_ctrl_queue_event());
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+
+        // Go back to a single queue pair.
+        add_ctrl_command(
+            &mem,
+            &ctrlq,
+            3,
+            VIRTIO_NET_CTRL_MQ as u8,
+            VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+            &1u16.to_le_bytes(),
+        );
+        net.queue_evts[8].write(1).unwrap();
+        net.process_ctrl_queue_event();
+        assert_eq!(ctrlq.used.idx.get(), 4);
+        assert_eq!(net.active_queue_pairs(), 1);
+        net.queue_pairs[1]
+            .backend
+            .set_queue_enabled(false)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_single_pair_ctrl_queue() {
+        let mut net = default_net();
+        // The control queue is only offered when enabled.
+        assert!(!net.has_ctrl_queue());
+        net.set_ctrl_queue(true);
+        assert!(net.has_ctrl_queue());
+        assert_eq!(net.avail_features() & CTRL_FEATURES, CTRL_FEATURES);
+        // The control queue is only exposed to drivers negotiating it.
+        assert_eq!(net.ctrl_queue_index(), Some(2));
+        assert_eq!(net.queues().len(), 2);
+        assert_eq!(net.queue_events().len(), 3);
+        net.set_acked_features(1 << VIRTIO_NET_F_CTRL_VQ);
+        assert_eq!(net.queues().len(), 3);
+
+        net.remove_ctrl_queue();
+        assert_eq!(net.ctrl_queue_index(), None);
+        assert_eq!(net.queues().len(), 2);
+        assert_eq!(net.queue_events().len(), 2);
+        assert_eq!(net.avail_features() & CTRL_FEATURES, 0);
+    }
+
+    #[test]
+    fn test_ctrl_rx_filter() {
+        let mut net = default_net();
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[2] = ctrlq.create_queue();
+        net.set_acked_features(CTRL_FEATURES);
+        net.activate(mem.clone()).unwrap();
+
+        let send_command = |net: &mut Net, index: u16, class: u32, cmd: u32, data: &[u8]| {
+            let ack_addr = add_ctrl_command(&mem, &ctrlq, index, class as u8, cmd as u8, data);
+            net.queue_evts[2].write(1).unwrap();
+            net.process_ctrl_queue_event();
+            mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap() == VIRTIO_NET_OK as u8
+        };
+        let frame_to = |mac: &str| {
+            let mut frame = [0u8; 60];
+            EthernetFrame::write_incomplete(
+                &mut frame[..],
+                MacAddr::parse_str(mac).unwrap(),
+                MacAddr::parse_str("02:00:00:00:00:01").unwrap(),
+                0x0800,
+            )
+            .unwrap();
+            frame
+        };
+
+        // The device is promiscuous until the driver programs it.
+        assert!(net.is_rx_frame_accepted(&frame_to("02:00:00:00:00:02")));
+        assert!(send_command(
+            &mut net,
+            0,
+            VIRTIO_NET_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_PROMISC,
+            &[0]
+        ));
+        assert!(!net.is_rx_frame_accepted(&frame_to("02:00:00:00:00:02")));
+
+        let mut tables = 1u32.to_le_bytes().to_vec();
+        tables.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        tables.extend_from_slice(&0u32.to_le_bytes());
+        assert!(send_command(
+            &mut net,
+            1,
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            &tables
+        ));
+        assert!(net.is_rx_frame_accepted(&frame_to("02:00:00:00:00:02")));
+
+        // The driver changes its MAC.
+        let new_mac = MacAddr::parse_str("02:00:00:00:00:03").unwrap();
+        check_metric_after_block!(
+            METRICS.net.mac_address_updates,
+            1,
+            assert!(send_command(
+                &mut net,
+                2,
+                VIRTIO_NET_CTRL_MAC,
+                VIRTIO_NET_CTRL_MAC_ADDR_SET,
+                new_mac.get_bytes()
+            ))
+        );
+        assert_eq!(net.guest_mac(), Some(&new_mac));
+        assert_eq!(net.config_space.guest_mac, new_mac);
+        assert!(net.is_rx_frame_accepted(&frame_to("02:00:00:00:00:03")));
+        assert!(!net.is_rx_frame_accepted(&frame_to("11:22:33:44:55:66")));
+
+        // Only the frames tagged with a VLAN of the table are accepted.
+        let mut tagged = frame_to("02:00:00:00:00:03");
+        tagged[12..16].copy_from_slice(&[0x81, 0x00, 0x00, 0x0a]);
+        assert!(!net.is_rx_frame_accepted(&tagged));
+        assert!(send_command(
+            &mut net,
+            3,
+            VIRTIO_NET_CTRL_VLAN,
+            VIRTIO_NET_CTRL_VLAN_ADD,
+            &10u16.to_le_bytes()
+        ));
+        assert!(net.is_rx_frame_accepted(&tagged));
+
+        // The TX filter locks the MAC.
+        net.set_tx_filter(Some(TxFilterConfig {
+            check_source_mac: true,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert!(!send_command(
+            &mut net,
+            4,
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_ADDR_SET,
+            default_guest_mac().get_bytes()
+        ));
+        assert_eq!(net.guest_mac(), Some(&new_mac));
+
+        // The commands require their feature.
+        net.set_acked_features(1 << VIRTIO_NET_F_CTRL_VQ);
+        assert!(!send_command(
+            &mut net,
+            5,
+            VIRTIO_NET_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_PROMISC,
+            &[1]
+        ));
+        assert!(!net.rx_filter().promisc);
+        // Without the features, every frame is accepted.
+        assert!(net.is_rx_frame_accepted(&frame_to("02:00:00:00:00:04")));
+    }
+
+    #[test]
+    fn test_rx_filtered_frames() {
+        let mut th = TestHelper::get_default();
+        th.net().set_acked_features(1 << VIRTIO_NET_F_CTRL_RX);
+        th.activate_net();
+        th.net().rx_filter.promisc = false;
+        th.net().queue_pairs[0]
+            .mocks
+            .set_read_tap(ReadTapMock::TapFrame);
+
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, 1000, VIRTQ_DESC_F_WRITE)]);
+        // The frame, sent to a random unicast or multicast address, isn't accepted.
+        inject_tap_tx_frame(&th.net(), 100);
+        check_metric_after_block!(
+            METRICS.net.rx_filtered_frames,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.rxq.used.idx.get(), 0);
+    }
 }
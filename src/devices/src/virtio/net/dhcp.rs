@@ -0,0 +1,711 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A DHCPv4 server handing a single, configured lease to the guest.
+//!
+//! The DHCP messages the guest sends to the server port are detoured from the TX path, the same
+//! way the MMDS traffic is, and never reach the host backend. The replies are injected on the RX
+//! path. Renewals are handled whatever their destination, so the server address doesn't need to
+//! be reachable from the guest.
+
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
+use std::result;
+
+use dumbo::pdu::dhcp::{
+    DhcpMessage, MessageType, CLIENT_PORT, FLAG_BROADCAST, HTYPE_ETHERNET, OPTION_DNS_SERVERS,
+    OPTION_INTERFACE_MTU, OPTION_LEASE_TIME, OPTION_MESSAGE_TYPE, OPTION_REBINDING_TIME,
+    OPTION_RENEWAL_TIME, OPTION_REQUESTED_ADDRESS, OPTION_ROUTER, OPTION_SERVER_ID,
+    OPTION_SUBNET_MASK, OP_BOOTREPLY, OP_BOOTREQUEST, SERVER_PORT,
+};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{IPv4Packet, IPV4_VERSION, PROTOCOL_UDP};
+use dumbo::pdu::udp::{UdpDatagram, UDP_HEADER_SIZE};
+use logger::{warn, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+/// The MAC address the DHCP server sends its replies from.
+pub const DHCP_SERVER_MAC: [u8; MAC_ADDR_LEN] = [0x06, 0x01, 0x23, 0x45, 0x67, 0x02];
+/// The lease time used when none is configured, of one day.
+pub const DEFAULT_LEASE_TIME_SECS: u32 = 86400;
+
+// The length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+// The smallest MTU every IPv4 host must accept.
+const MIN_MTU: u16 = 68;
+// The most DNS servers whose addresses fit in a DHCP option.
+const MAX_DNS_SERVERS: usize = 63;
+// Large enough for the replies, whose options take less than 300 bytes.
+const MAX_REPLY_LEN: usize = 576;
+
+/// List of errors an invalid DHCP configuration can throw.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The prefix length is longer than an IPv4 address
+    #[error("The DHCP prefix length {0} is longer than 32.")]
+    InvalidPrefixLen(u8),
+    /// Neither a gateway nor a server address is configured
+    #[error("The DHCP server address must be set when there is no gateway.")]
+    MissingServerAddress,
+    /// The lease time is zero
+    #[error("The DHCP lease time can't be 0.")]
+    InvalidLeaseTime,
+    /// The MTU is smaller than the IPv4 minimum
+    #[error("The DHCP MTU {0} is smaller than {MIN_MTU}.")]
+    InvalidMtu(u16),
+    /// Too many DNS servers are configured
+    #[error("The DHCP lease can't carry more than {MAX_DNS_SERVERS} DNS servers.")]
+    TooManyDnsServers,
+}
+
+type Result<T> = result::Result<T, Error>;
+
+fn default_lease_time() -> u32 {
+    DEFAULT_LEASE_TIME_SECS
+}
+
+/// Configuration of the lease handed to the guest by the DHCP server.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DhcpConfig {
+    /// The address leased to the guest.
+    pub address: Ipv4Addr,
+    /// The length of the prefix of the guest subnet.
+    pub prefix_len: u8,
+    /// The default gateway of the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<Ipv4Addr>,
+    /// The DNS servers of the guest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// The MTU of the guest interface.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+    /// The duration of the lease, in seconds.
+    #[serde(default = "default_lease_time")]
+    pub lease_time_secs: u32,
+    /// The address the server identifies itself with. Defaults to the gateway.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_address: Option<Ipv4Addr>,
+}
+
+impl DhcpConfig {
+    /// Checks that the lease can be handed to the guest.
+    pub fn validate(&self) -> Result<()> {
+        if self.prefix_len > 32 {
+            return Err(Error::InvalidPrefixLen(self.prefix_len));
+        }
+        if self.server_id().is_none() {
+            return Err(Error::MissingServerAddress);
+        }
+        if self.lease_time_secs == 0 {
+            return Err(Error::InvalidLeaseTime);
+        }
+        if let Some(mtu) = self.mtu.filter(|&mtu| mtu < MIN_MTU) {
+            return Err(Error::InvalidMtu(mtu));
+        }
+        if self.dns_servers.len() > MAX_DNS_SERVERS {
+            return Err(Error::TooManyDnsServers);
+        }
+        Ok(())
+    }
+
+    fn server_id(&self) -> Option<Ipv4Addr> {
+        self.server_address.or(self.gateway)
+    }
+
+    fn subnet_mask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - u32::from(self.prefix_len))
+                .unwrap_or(0),
+        )
+    }
+}
+
+// A reply the server owes the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PendingReply {
+    message_type: MessageType,
+    xid: u32,
+    chaddr: MacAddr,
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    // Whether the reply only carries the local configuration, without a lease.
+    inform: bool,
+}
+
+/// Answers the DHCP messages sent by the guest with the configured lease.
+#[derive(Debug)]
+pub struct DhcpServer {
+    config: DhcpConfig,
+    server_id: Ipv4Addr,
+    mac_addr: MacAddr,
+    pending_reply: Option<PendingReply>,
+}
+
+impl DhcpServer {
+    /// Creates a server handing the lease described by `config`.
+    pub fn new(config: DhcpConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(DhcpServer {
+            // Safe to unwrap because the configuration was validated.
+            server_id: config.server_id().unwrap(),
+            config,
+            mac_addr: MacAddr::from(DHCP_SERVER_MAC),
+            pending_reply: None,
+        })
+    }
+
+    /// Provides the configuration of the lease.
+    pub fn config(&self) -> &DhcpConfig {
+        &self.config
+    }
+
+    /// Checks whether the frame whose headers start `src` carries a UDP datagram sent to the
+    /// DHCP server port. `src` may be shorter than the frame, but must hold the UDP ports.
+    pub fn is_dhcp_frame(&self, src: &[u8]) -> bool {
+        let eth = match EthernetFrame::from_bytes(src) {
+            Ok(eth) if eth.ethertype() == ETHERTYPE_IPV4 => eth,
+            _ => return false,
+        };
+        let payload = eth.payload();
+        if payload.len() < IPV4_MIN_HEADER_LEN {
+            return false;
+        }
+        let packet = IPv4Packet::from_bytes_unchecked(payload);
+        let (version, header_len) = packet.version_and_header_len();
+        let (_, fragment_offset) = packet.flags_and_fragment_offset();
+        if version != IPV4_VERSION
+            || header_len < IPV4_MIN_HEADER_LEN
+            || packet.protocol() != PROTOCOL_UDP
+            || fragment_offset != 0
+        {
+            return false;
+        }
+        payload
+            .get(header_len + 2..header_len + 4)
+            .map_or(false, |port| {
+                u16::from_be_bytes([port[0], port[1]]) == SERVER_PORT
+            })
+    }
+
+    /// Handles a frame sent to the DHCP server, which the caller checks with `is_dhcp_frame`.
+    /// A reply is written by the next call to `write_next_frame`, if the message calls for one.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the frame held a valid DHCP message or `false` otherwise.
+    pub fn detour_frame(&mut self, src: &[u8]) -> bool {
+        METRICS.net.dhcp_requests.inc();
+        if self.handle_frame(src).is_none() {
+            METRICS.net.dhcp_bad_requests.inc();
+            return false;
+        }
+        true
+    }
+
+    fn handle_frame(&mut self, src: &[u8]) -> Option<()> {
+        let eth = EthernetFrame::from_bytes(src).ok()?;
+        let payload = eth.payload();
+        // The frame may be padded past the end of the packet.
+        let total_len = usize::from(
+            IPv4Packet::from_bytes_unchecked(payload.get(..IPV4_MIN_HEADER_LEN)?).total_len(),
+        );
+        let packet = IPv4Packet::from_bytes(payload.get(..total_len)?, true).ok()?;
+        // The UDP checksum isn't verified, as the guest may leave it to the device to compute.
+        let datagram = UdpDatagram::from_bytes(packet.payload(), None).ok()?;
+        let message_len = usize::from(datagram.len()).checked_sub(UDP_HEADER_SIZE)?;
+        let message = DhcpMessage::from_bytes(datagram.payload().get(..message_len)?).ok()?;
+        if message.op() != OP_BOOTREQUEST
+            || message.htype() != HTYPE_ETHERNET
+            || usize::from(message.hlen()) != MAC_ADDR_LEN
+        {
+            return None;
+        }
+
+        let mut reply = PendingReply {
+            message_type: MessageType::Ack,
+            xid: message.xid(),
+            chaddr: message.chaddr(),
+            flags: message.flags(),
+            ciaddr: message.ciaddr(),
+            inform: false,
+        };
+        match message.message_type()? {
+            MessageType::Discover => reply.message_type = MessageType::Offer,
+            MessageType::Request => {
+                // The guest picked the offer of another server.
+                if message
+                    .option_addr(OPTION_SERVER_ID)
+                    .map_or(false, |addr| addr != self.server_id)
+                {
+                    return Some(());
+                }
+                let requested = message
+                    .option_addr(OPTION_REQUESTED_ADDRESS)
+                    .unwrap_or_else(|| message.ciaddr());
+                if requested != self.config.address {
+                    reply.message_type = MessageType::Nak;
+                }
+            }
+            MessageType::Inform => reply.inform = true,
+            MessageType::Decline => {
+                warn!(
+                    "The guest declined the DHCP lease of {}, which is already in use.",
+                    self.config.address
+                );
+                return Some(());
+            }
+            MessageType::Release => return Some(()),
+            MessageType::Offer | MessageType::Ack | MessageType::Nak => return None,
+        }
+        // Only the reply to the latest message is sent, as the guest retries the others.
+        self.pending_reply = Some(reply);
+        Some(())
+    }
+
+    /// Writes the pending reply to the guest in `buf`, if any.
+    ///
+    /// # Returns
+    ///
+    /// The length of the frame written, or `None` if there was nothing to write.
+    pub fn write_next_frame(&mut self, buf: &mut [u8]) -> Option<NonZeroUsize> {
+        let reply = self.pending_reply.take()?;
+        let len = self.write_reply(buf, &reply);
+        if len.is_some() {
+            METRICS.net.dhcp_replies.inc();
+        }
+        len
+    }
+
+    fn write_reply(&self, buf: &mut [u8], reply: &PendingReply) -> Option<NonZeroUsize> {
+        let broadcast = MacAddr::from([0xff; MAC_ADDR_LEN]);
+        let yiaddr = match reply.message_type {
+            MessageType::Nak => Ipv4Addr::UNSPECIFIED,
+            _ if reply.inform => Ipv4Addr::UNSPECIFIED,
+            _ => self.config.address,
+        };
+        let (dst_mac, dst_addr) = if reply.message_type == MessageType::Nak {
+            (broadcast, Ipv4Addr::BROADCAST)
+        } else if !reply.ciaddr.is_unspecified() {
+            (reply.chaddr, reply.ciaddr)
+        } else if reply.flags & FLAG_BROADCAST != 0 {
+            (broadcast, Ipv4Addr::BROADCAST)
+        } else {
+            (reply.chaddr, yiaddr)
+        };
+
+        let mut message_buf = [0u8; MAX_REPLY_LEN];
+        let message_len = self.write_message(&mut message_buf, reply, yiaddr)?;
+
+        let mut eth =
+            EthernetFrame::write_incomplete(buf, dst_mac, self.mac_addr, ETHERTYPE_IPV4).ok()?;
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            self.server_id,
+            dst_addr,
+        )
+        .ok()?;
+        let datagram_len = UdpDatagram::write_incomplete_datagram(
+            packet.inner_mut().payload_mut(),
+            &message_buf[..message_len],
+        )
+        .ok()?
+        .finalize(SERVER_PORT, CLIENT_PORT, Some((self.server_id, dst_addr)))
+        .len();
+        let packet_len = packet
+            .with_payload_len_unchecked(usize::from(datagram_len), true)
+            .len();
+        NonZeroUsize::new(eth.with_payload_len_unchecked(packet_len).len())
+    }
+
+    fn write_message(
+        &self,
+        buf: &mut [u8],
+        reply: &PendingReply,
+        yiaddr: Ipv4Addr,
+    ) -> Option<usize> {
+        let server_id = self.server_id.octets();
+        let lease_time = self.config.lease_time_secs;
+        let renewal_time = (lease_time / 2).to_be_bytes();
+        let rebinding_time = (lease_time / 8 * 7).to_be_bytes();
+        let lease_time = lease_time.to_be_bytes();
+        let subnet_mask = self.config.subnet_mask().octets();
+        let router = self.config.gateway.map(|addr| addr.octets());
+        let dns_servers: Vec<u8> = self
+            .config
+            .dns_servers
+            .iter()
+            .flat_map(|addr| addr.octets())
+            .collect();
+        let mtu = self.config.mtu.map(u16::to_be_bytes);
+
+        let message_type = [reply.message_type as u8];
+        let mut options: Vec<(u8, &[u8])> = vec![
+            (OPTION_MESSAGE_TYPE, &message_type[..]),
+            (OPTION_SERVER_ID, &server_id[..]),
+        ];
+        if reply.message_type != MessageType::Nak {
+            if !reply.inform {
+                options.push((OPTION_LEASE_TIME, &lease_time));
+                options.push((OPTION_RENEWAL_TIME, &renewal_time));
+                options.push((OPTION_REBINDING_TIME, &rebinding_time));
+            }
+            options.push((OPTION_SUBNET_MASK, &subnet_mask));
+            if let Some(router) = router.as_ref() {
+                options.push((OPTION_ROUTER, router));
+            }
+            if !dns_servers.is_empty() {
+                options.push((OPTION_DNS_SERVERS, &dns_servers));
+            }
+            if let Some(mtu) = mtu.as_ref() {
+                options.push((OPTION_INTERFACE_MTU, mtu));
+            }
+        }
+
+        let mut message =
+            DhcpMessage::write_incomplete(buf, OP_BOOTREPLY, reply.xid, reply.chaddr).ok()?;
+        message
+            .inner_mut()
+            .set_flags(reply.flags)
+            .set_ciaddr(reply.ciaddr)
+            .set_yiaddr(yiaddr);
+        message.finalize(&options).ok().map(|message| message.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const GUEST_MAC: &str = "12:34:56:78:9a:bc";
+    const XID: u32 = 0x1234_5678;
+
+    pub(crate) fn dhcp_config() -> DhcpConfig {
+        DhcpConfig {
+            address: Ipv4Addr::new(10, 0, 0, 2),
+            prefix_len: 24,
+            gateway: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            dns_servers: vec![Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 1, 53)],
+            mtu: Some(1500),
+            lease_time_secs: 3600,
+            server_address: None,
+        }
+    }
+
+    // Writes a frame carrying a DHCP message sent by the guest, whose options follow the
+    // message type.
+    pub(crate) fn dhcp_request_frame(
+        message_type: MessageType,
+        ciaddr: Ipv4Addr,
+        options: &[(u8, &[u8])],
+    ) -> Vec<u8> {
+        let guest_mac = MacAddr::parse_str(GUEST_MAC).unwrap();
+        let mut message_buf = [0u8; MAX_REPLY_LEN];
+        let message_type = [message_type as u8];
+        let mut all_options: Vec<(u8, &[u8])> = vec![(OPTION_MESSAGE_TYPE, &message_type[..])];
+        all_options.extend_from_slice(options);
+        let mut message =
+            DhcpMessage::write_incomplete(message_buf.as_mut(), OP_BOOTREQUEST, XID, guest_mac)
+                .unwrap();
+        message.inner_mut().set_ciaddr(ciaddr);
+        let message_len = message.finalize(&all_options).unwrap().len();
+
+        let src_addr = ciaddr;
+        let dst_addr = Ipv4Addr::BROADCAST;
+        let mut buf = vec![0u8; 1000];
+        let mut eth = EthernetFrame::write_incomplete(
+            buf.as_mut_slice(),
+            MacAddr::from([0xff; MAC_ADDR_LEN]),
+            guest_mac,
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            src_addr,
+            dst_addr,
+        )
+        .unwrap();
+        let datagram_len = UdpDatagram::write_incomplete_datagram(
+            packet.inner_mut().payload_mut(),
+            &message_buf[..message_len],
+        )
+        .unwrap()
+        .finalize(CLIENT_PORT, SERVER_PORT, Some((src_addr, dst_addr)))
+        .len();
+        let packet_len = packet
+            .with_payload_len_unchecked(usize::from(datagram_len), true)
+            .len();
+        let len = eth.with_payload_len_unchecked(packet_len).len();
+        buf.truncate(len);
+        buf
+    }
+
+    // Parses a reply of the server, returning its destinations, message type and lease address.
+    fn parse_reply(buf: &[u8]) -> (MacAddr, Ipv4Addr, MessageType, Ipv4Addr) {
+        let eth = EthernetFrame::from_bytes(buf).unwrap();
+        assert_eq!(eth.src_mac(), MacAddr::from(DHCP_SERVER_MAC));
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), Ipv4Addr::new(10, 0, 0, 1));
+        let datagram = UdpDatagram::from_bytes(
+            packet.payload(),
+            Some((packet.source_address(), packet.destination_address())),
+        )
+        .unwrap();
+        assert_eq!(datagram.source_port(), SERVER_PORT);
+        assert_eq!(datagram.destination_port(), CLIENT_PORT);
+        let message = DhcpMessage::from_bytes(datagram.payload()).unwrap();
+        assert_eq!(message.op(), OP_BOOTREPLY);
+        assert_eq!(message.xid(), XID);
+        assert_eq!(message.chaddr(), MacAddr::parse_str(GUEST_MAC).unwrap());
+        (
+            eth.dst_mac(),
+            packet.destination_address(),
+            message.message_type().unwrap(),
+            message.yiaddr(),
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = dhcp_config();
+        config.validate().unwrap();
+        assert_eq!(config.subnet_mask(), Ipv4Addr::new(255, 255, 255, 0));
+
+        config.prefix_len = 33;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidPrefixLen(33))
+        ));
+        config.prefix_len = 0;
+        assert_eq!(config.subnet_mask(), Ipv4Addr::UNSPECIFIED);
+        config.prefix_len = 32;
+        assert_eq!(config.subnet_mask(), Ipv4Addr::BROADCAST);
+
+        config.gateway = None;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::MissingServerAddress)
+        ));
+        config.server_address = Some(Ipv4Addr::new(10, 0, 0, 254));
+        config.validate().unwrap();
+
+        config.lease_time_secs = 0;
+        assert!(matches!(config.validate(), Err(Error::InvalidLeaseTime)));
+        config.lease_time_secs = 1;
+        config.mtu = Some(67);
+        assert!(matches!(config.validate(), Err(Error::InvalidMtu(67))));
+        config.mtu = None;
+        config.dns_servers = vec![Ipv4Addr::LOCALHOST; MAX_DNS_SERVERS + 1];
+        assert!(matches!(config.validate(), Err(Error::TooManyDnsServers)));
+        config.dns_servers.pop();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_is_dhcp_frame() {
+        let server = DhcpServer::new(dhcp_config()).unwrap();
+        let frame = dhcp_request_frame(MessageType::Discover, Ipv4Addr::UNSPECIFIED, &[]);
+        assert!(server.is_dhcp_frame(&frame));
+        // Only the headers are needed.
+        assert!(server.is_dhcp_frame(&frame[..14 + 20 + 4]));
+        assert!(!server.is_dhcp_frame(&frame[..14 + 20 + 3]));
+
+        // Another destination port.
+        let mut other = frame.clone();
+        other[14 + 20 + 3] = 53;
+        assert!(!server.is_dhcp_frame(&other));
+        // Another transport protocol.
+        let mut other = frame.clone();
+        other[14 + 9] = 6;
+        assert!(!server.is_dhcp_frame(&other));
+        // A non-first fragment.
+        let mut other = frame;
+        other[14 + 7] = 1;
+        assert!(!server.is_dhcp_frame(&other));
+    }
+
+    #[test]
+    fn test_lease() {
+        let mut server = DhcpServer::new(dhcp_config()).unwrap();
+        let address = server.config().address;
+        let server_id = Ipv4Addr::new(10, 0, 0, 1);
+        let guest_mac = MacAddr::parse_str(GUEST_MAC).unwrap();
+        let mut buf = [0u8; 2000];
+        assert!(server.write_next_frame(&mut buf).is_none());
+
+        // DISCOVER is answered with an OFFER sent to the offered address.
+        let frame = dhcp_request_frame(MessageType::Discover, Ipv4Addr::UNSPECIFIED, &[]);
+        assert!(server.detour_frame(&frame));
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (guest_mac, address, MessageType::Offer, address)
+        );
+        assert!(server.write_next_frame(&mut buf).is_none());
+
+        // The OFFER carries the whole configuration.
+        let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), false).unwrap();
+        let message = DhcpMessage::from_bytes(&packet.payload()[8..]).unwrap();
+        assert_eq!(message.option_addr(OPTION_SERVER_ID), Some(server_id));
+        assert_eq!(
+            message.option_addr(OPTION_SUBNET_MASK),
+            Some(Ipv4Addr::new(255, 255, 255, 0))
+        );
+        assert_eq!(message.option_addr(OPTION_ROUTER), Some(server_id));
+        assert_eq!(
+            message.option(OPTION_DNS_SERVERS),
+            Some(&[10, 0, 0, 53, 10, 0, 1, 53][..])
+        );
+        assert_eq!(
+            message.option(OPTION_INTERFACE_MTU),
+            Some(&1500u16.to_be_bytes()[..])
+        );
+        assert_eq!(
+            message.option(OPTION_LEASE_TIME),
+            Some(&3600u32.to_be_bytes()[..])
+        );
+        assert_eq!(
+            message.option(OPTION_RENEWAL_TIME),
+            Some(&1800u32.to_be_bytes()[..])
+        );
+        assert_eq!(
+            message.option(OPTION_REBINDING_TIME),
+            Some(&3150u32.to_be_bytes()[..])
+        );
+
+        // REQUEST for the offered address is acknowledged.
+        let frame = dhcp_request_frame(
+            MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[
+                (OPTION_REQUESTED_ADDRESS, &address.octets()),
+                (OPTION_SERVER_ID, &server_id.octets()),
+            ],
+        );
+        assert!(server.detour_frame(&frame));
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (guest_mac, address, MessageType::Ack, address)
+        );
+
+        // Renewals are unicast to the client address.
+        let frame = dhcp_request_frame(MessageType::Request, address, &[]);
+        assert!(server.detour_frame(&frame));
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (guest_mac, address, MessageType::Ack, address)
+        );
+
+        // REQUEST for another address is refused, with a broadcast NAK.
+        let other = Ipv4Addr::new(10, 0, 0, 3);
+        let frame = dhcp_request_frame(
+            MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[(OPTION_REQUESTED_ADDRESS, &other.octets())],
+        );
+        assert!(server.detour_frame(&frame));
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (
+                MacAddr::from([0xff; MAC_ADDR_LEN]),
+                Ipv4Addr::BROADCAST,
+                MessageType::Nak,
+                Ipv4Addr::UNSPECIFIED
+            )
+        );
+
+        // REQUEST for the offer of another server is ignored.
+        let frame = dhcp_request_frame(
+            MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[
+                (OPTION_REQUESTED_ADDRESS, &address.octets()),
+                (OPTION_SERVER_ID, &other.octets()),
+            ],
+        );
+        assert!(server.detour_frame(&frame));
+        assert!(server.write_next_frame(&mut buf).is_none());
+
+        // INFORM is acknowledged without a lease.
+        let frame = dhcp_request_frame(MessageType::Inform, other, &[]);
+        assert!(server.detour_frame(&frame));
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (guest_mac, other, MessageType::Ack, Ipv4Addr::UNSPECIFIED)
+        );
+        let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), false).unwrap();
+        let message = DhcpMessage::from_bytes(&packet.payload()[8..]).unwrap();
+        assert!(message.option(OPTION_LEASE_TIME).is_none());
+
+        // RELEASE and DECLINE aren't answered.
+        for message_type in [MessageType::Release, MessageType::Decline] {
+            let frame = dhcp_request_frame(message_type, address, &[]);
+            assert!(server.detour_frame(&frame));
+            assert!(server.write_next_frame(&mut buf).is_none());
+        }
+    }
+
+    #[test]
+    fn test_broadcast_flag() {
+        let mut server = DhcpServer::new(dhcp_config()).unwrap();
+        let mut frame = dhcp_request_frame(MessageType::Discover, Ipv4Addr::UNSPECIFIED, &[]);
+        // Set the broadcast flag.
+        frame[14 + 20 + 8 + 10] = 0x80;
+        assert!(server.detour_frame(&frame));
+        let mut buf = [0u8; 2000];
+        let len = server.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            parse_reply(&buf[..len]),
+            (
+                MacAddr::from([0xff; MAC_ADDR_LEN]),
+                Ipv4Addr::BROADCAST,
+                MessageType::Offer,
+                server.config().address
+            )
+        );
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut server = DhcpServer::new(dhcp_config()).unwrap();
+        let frame = dhcp_request_frame(MessageType::Discover, Ipv4Addr::UNSPECIFIED, &[]);
+        let mut buf = [0u8; 2000];
+
+        // A truncated frame.
+        assert!(!server.detour_frame(&frame[..100]));
+        // A bad IPv4 header checksum.
+        let mut other = frame.clone();
+        other[14 + 8] ^= 0xff;
+        assert!(!server.detour_frame(&other));
+        // A UDP length shorter than the UDP header.
+        let mut other = frame.clone();
+        other[14 + 20 + 4..14 + 20 + 6].copy_from_slice(&4u16.to_be_bytes());
+        assert!(!server.detour_frame(&other));
+        // A reply sent by the guest.
+        let mut other = frame.clone();
+        other[14 + 20 + 8] = OP_BOOTREPLY;
+        assert!(!server.detour_frame(&other));
+        // A message type only servers send.
+        let other = dhcp_request_frame(MessageType::Offer, Ipv4Addr::UNSPECIFIED, &[]);
+        assert!(!server.detour_frame(&other));
+        assert!(server.write_next_frame(&mut buf).is_none());
+
+        // Padding past the end of the packet is ignored.
+        let mut padded = frame;
+        padded.extend_from_slice(&[0u8; 10]);
+        assert!(server.detour_frame(&padded));
+        assert!(server.write_next_frame(&mut buf).is_some());
+    }
+}
@@ -7,16 +7,25 @@ use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn, IncMetric, METRICS};
 use utils::epoll::EventSet;
 
-use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::net::device::{rx_queue_index, tx_queue_index, Net};
+use crate::virtio::VirtioDevice;
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
+        for pair in 0..self.queue_pairs.len() {
+            let rx_queue_evt = &self.queue_evts[rx_queue_index(pair)];
+            if let Err(err) = ops.add(Events::new(rx_queue_evt, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", err);
+            }
+            let tx_queue_evt = &self.queue_evts[tx_queue_index(pair)];
+            if let Err(err) = ops.add(Events::new(tx_queue_evt, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
+        if let Some(ctrl_index) = self.ctrl_queue_index() {
+            if let Err(err) = ops.add(Events::new(&self.queue_evts[ctrl_index], EventSet::IN)) {
+                error!("Failed to register ctrl queue event: {}", err);
+            }
         }
         if let Err(err) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
             error!("Failed to register rx queue event: {}", err);
@@ -24,11 +33,13 @@ impl Net {
         if let Err(err) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
             error!("Failed to register tx queue event: {}", err);
         }
-        if let Err(err) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", err);
+        for queue_pair in &self.queue_pairs {
+            if let Err(err) = ops.add(Events::new_raw(
+                queue_pair.backend.as_raw_fd(),
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to register tap event: {}", err);
+            }
         }
     }
 
@@ -67,18 +78,28 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
             let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
             let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let maybe_queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
+            let maybe_tap_index = self
+                .queue_pairs
+                .iter()
+                .position(|queue_pair| queue_pair.backend.as_raw_fd() == source);
 
             // Looks better than C style if/else if/else.
-            match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
+            match (maybe_queue_index, maybe_tap_index) {
+                (Some(queue_index), _) if Some(queue_index) == self.ctrl_queue_index() => {
+                    self.process_ctrl_queue_event()
+                }
+                (Some(queue_index), _) if queue_index % 2 == 0 => {
+                    self.process_rx_queue_event(queue_index / 2)
+                }
+                (Some(queue_index), _) => self.process_tx_queue_event(queue_index / 2),
+                (_, Some(pair)) => self.process_tap_rx_event(pair),
                 _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
                 _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
@@ -0,0 +1,530 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of the frames sent by the guest, before they reach the host backend.
+//!
+//! The filter drops the frames whose source MAC or ARP sender MAC isn't the guest MAC, and the
+//! IPv4 packets and ARP messages whose source or sender address isn't allowed. The remaining
+//! frames go through a list of rules, matched in order by protocol and destination port, the
+//! first matching rule deciding whether the frame is sent. The frames carrying VLAN tags are
+//! checked by the protocol following the tags, and RARP messages as ARP messages.
+
+use std::net::Ipv4Addr;
+use std::result;
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN, HTYPE_ETHERNET};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, PAYLOAD_OFFSET};
+use dumbo::pdu::ipv4::{IPv4Packet, IPV4_VERSION, PROTOCOL_ICMP, PROTOCOL_TCP, PROTOCOL_UDP};
+use serde::{Deserialize, Serialize};
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+// The length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+// The length of an IPv4 header with the longest options.
+const IPV4_MAX_HEADER_LEN: usize = 60;
+// The length of the source and destination ports starting TCP and UDP headers.
+const PORTS_LEN: usize = 4;
+// The ethertypes of the 802.1Q and 802.1ad VLAN tags, and of RARP messages.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_RARP: u16 = 0x8035;
+// The length of a VLAN tag, whose last two bytes are the ethertype of the rest of the frame.
+const VLAN_TAG_LEN: usize = 4;
+// The most VLAN tags a frame may carry, the frames with more tags being malformed.
+const MAX_VLAN_TAGS: usize = 2;
+
+/// The number of bytes at the start of a frame the filter looks at.
+pub const FILTER_HEADER_LEN: usize =
+    PAYLOAD_OFFSET + MAX_VLAN_TAGS * VLAN_TAG_LEN + IPV4_MAX_HEADER_LEN + PORTS_LEN;
+
+/// List of errors an invalid filter configuration can throw.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The source MAC can't be checked without a guest MAC
+    #[error("The source MAC of the frames can't be checked without a guest MAC.")]
+    MissingGuestMac,
+    /// A rule matches a port for a protocol without ports
+    #[error("Rule {0} matches a port, which only TCP and UDP rules can.")]
+    PortWithoutTransport(usize),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// What happens to the frames matched by a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FilterAction {
+    /// The frames are sent.
+    Allow,
+    /// The frames are dropped.
+    Deny,
+}
+
+impl Default for FilterAction {
+    fn default() -> FilterAction {
+        FilterAction::Allow
+    }
+}
+
+/// The frames matched by a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FilterProtocol {
+    /// All the frames.
+    Any,
+    /// ARP messages.
+    Arp,
+    /// IPv4 packets, whatever their protocol.
+    Ipv4,
+    /// ICMP messages over IPv4.
+    Icmp,
+    /// TCP segments over IPv4.
+    Tcp,
+    /// UDP datagrams over IPv4.
+    Udp,
+}
+
+/// A rule deciding what happens to the frames it matches.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TxFilterRule {
+    /// What happens to the matched frames.
+    pub action: FilterAction,
+    /// The protocol of the matched frames.
+    pub protocol: FilterProtocol,
+    /// The destination port of the matched TCP segments or UDP datagrams. All the ports match
+    /// when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+/// Configuration of the filtering of the frames sent by the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TxFilterConfig {
+    /// Whether the frames whose source MAC, or ARP sender MAC, isn't the guest MAC are dropped.
+    #[serde(default)]
+    pub check_source_mac: bool,
+    /// The addresses the guest may send IPv4 packets and ARP messages from. All the addresses
+    /// are allowed when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_ipv4_sources: Option<Vec<Ipv4Addr>>,
+    /// The rules the frames go through, in order.
+    #[serde(default)]
+    pub rules: Vec<TxFilterRule>,
+    /// What happens to the frames no rule matches.
+    #[serde(default)]
+    pub default_action: FilterAction,
+}
+
+/// The outcome of filtering a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The frame is sent.
+    Allow,
+    /// The source MAC or ARP sender MAC isn't the guest MAC.
+    SpoofedMac,
+    /// The source IPv4 address or ARP sender address isn't allowed.
+    SpoofedIp,
+    /// The frame was denied by a rule, or by the default action.
+    Denied,
+    /// The frame is too short for its headers, or has invalid ones.
+    Malformed,
+}
+
+// What the rules look at in a frame.
+struct Matched {
+    protocol: FilterProtocol,
+    port: Option<u16>,
+}
+
+impl TxFilterRule {
+    fn matches(&self, matched: &Matched) -> bool {
+        let protocol = match self.protocol {
+            FilterProtocol::Any => true,
+            FilterProtocol::Ipv4 => matches!(
+                matched.protocol,
+                FilterProtocol::Ipv4
+                    | FilterProtocol::Icmp
+                    | FilterProtocol::Tcp
+                    | FilterProtocol::Udp
+            ),
+            protocol => protocol == matched.protocol,
+        };
+        protocol && (self.port.is_none() || self.port == matched.port)
+    }
+}
+
+impl TxFilterConfig {
+    /// Checks that the filter can be applied to a device with the `guest_mac` MAC.
+    pub fn validate(&self, guest_mac: Option<MacAddr>) -> Result<()> {
+        if self.check_source_mac && guest_mac.is_none() {
+            return Err(Error::MissingGuestMac);
+        }
+        for (index, rule) in self.rules.iter().enumerate() {
+            let transport = matches!(rule.protocol, FilterProtocol::Tcp | FilterProtocol::Udp);
+            if rule.port.is_some() && !transport {
+                return Err(Error::PortWithoutTransport(index));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_allowed_source(&self, addr: Ipv4Addr) -> bool {
+        self.allowed_ipv4_sources
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(&addr))
+    }
+
+    /// Filters a frame sent by the guest, whose headers start `headers`, which is at least
+    /// `FILTER_HEADER_LEN` long unless the frame is shorter.
+    pub fn check(&self, headers: &[u8], guest_mac: Option<MacAddr>) -> Verdict {
+        let eth_frame = match EthernetFrame::from_bytes(headers) {
+            Ok(eth_frame) => eth_frame,
+            Err(_) => return Verdict::Malformed,
+        };
+        let guest_mac = guest_mac.filter(|_| self.check_source_mac);
+        if guest_mac.map_or(false, |mac| mac != eth_frame.src_mac()) {
+            return Verdict::SpoofedMac;
+        }
+
+        // The VLAN tags are skipped, the tagged frames going through the same checks.
+        let mut ethertype = eth_frame.ethertype();
+        let mut payload = eth_frame.payload();
+        let mut tags = 0;
+        while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+            if tags == MAX_VLAN_TAGS || payload.len() < VLAN_TAG_LEN {
+                return Verdict::Malformed;
+            }
+            ethertype = u16::from_be_bytes([payload[2], payload[3]]);
+            payload = &payload[VLAN_TAG_LEN..];
+            tags += 1;
+        }
+
+        let matched = match ethertype {
+            ETHERTYPE_ARP | ETHERTYPE_RARP => {
+                if payload.len() < ETH_IPV4_FRAME_LEN {
+                    return Verdict::Malformed;
+                }
+                let arp = EthIPv4ArpFrame::from_bytes_unchecked(&payload[..ETH_IPV4_FRAME_LEN]);
+                if arp.htype() != HTYPE_ETHERNET
+                    || arp.ptype() != ETHERTYPE_IPV4
+                    || usize::from(arp.hlen()) != MAC_ADDR_LEN
+                    || arp.plen() != 4
+                {
+                    return Verdict::Malformed;
+                }
+                if guest_mac.map_or(false, |mac| mac != arp.sha()) {
+                    return Verdict::SpoofedMac;
+                }
+                if !self.is_allowed_source(arp.spa()) {
+                    return Verdict::SpoofedIp;
+                }
+                Matched {
+                    protocol: FilterProtocol::Arp,
+                    port: None,
+                }
+            }
+            ETHERTYPE_IPV4 => {
+                // The packet may be longer than the headers, so its length isn't checked.
+                if payload.len() < IPV4_MIN_HEADER_LEN {
+                    return Verdict::Malformed;
+                }
+                let packet = IPv4Packet::from_bytes_unchecked(payload);
+                let (version, header_len) = packet.version_and_header_len();
+                if version != IPV4_VERSION || header_len < IPV4_MIN_HEADER_LEN {
+                    return Verdict::Malformed;
+                }
+                if !self.is_allowed_source(packet.source_address()) {
+                    return Verdict::SpoofedIp;
+                }
+                let protocol = match packet.protocol() {
+                    PROTOCOL_ICMP => FilterProtocol::Icmp,
+                    PROTOCOL_TCP => FilterProtocol::Tcp,
+                    PROTOCOL_UDP => FilterProtocol::Udp,
+                    _ => FilterProtocol::Ipv4,
+                };
+                // Only the first fragment of a packet holds the ports.
+                let (_, fragment_offset) = packet.flags_and_fragment_offset();
+                let port = match protocol {
+                    FilterProtocol::Tcp | FilterProtocol::Udp if fragment_offset == 0 => payload
+                        .get(header_len + 2..header_len + PORTS_LEN)
+                        .map(|port| u16::from_be_bytes([port[0], port[1]])),
+                    _ => None,
+                };
+                Matched { protocol, port }
+            }
+            _ => Matched {
+                protocol: FilterProtocol::Any,
+                port: None,
+            },
+        };
+
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(&matched))
+            .map_or(self.default_action, |rule| rule.action);
+        match action {
+            FilterAction::Allow => Verdict::Allow,
+            FilterAction::Deny => Verdict::Denied,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: &str = "12:34:56:78:9a:bc";
+    const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OTHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str(GUEST_MAC).unwrap()
+    }
+
+    fn eth_frame(buf: &mut [u8], src_mac: MacAddr, ethertype: u16) -> usize {
+        EthernetFrame::write_incomplete(
+            buf,
+            MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+            src_mac,
+            ethertype,
+        )
+        .unwrap();
+        PAYLOAD_OFFSET
+    }
+
+    fn arp_frame(src_mac: MacAddr, sha: MacAddr, spa: Ipv4Addr) -> Vec<u8> {
+        let mut buf = vec![0u8; 60];
+        let offset = eth_frame(&mut buf, src_mac, ETHERTYPE_ARP);
+        EthIPv4ArpFrame::write_request(
+            &mut buf[offset..offset + ETH_IPV4_FRAME_LEN],
+            sha,
+            spa,
+            MacAddr::parse_str("00:00:00:00:00:00").unwrap(),
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap();
+        buf
+    }
+
+    fn ipv4_frame(src_ip: Ipv4Addr, protocol: u8, dst_port: u16, fragment_offset: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; FILTER_HEADER_LEN];
+        let offset = eth_frame(&mut buf, guest_mac(), ETHERTYPE_IPV4);
+        let mut packet = IPv4Packet::from_bytes_unchecked(&mut buf[offset..]);
+        packet
+            .set_version_and_header_len(IPV4_VERSION, IPV4_MIN_HEADER_LEN)
+            .set_flags_and_fragment_offset(0, fragment_offset)
+            .set_protocol(protocol)
+            .set_source_address(src_ip)
+            .set_destination_address(Ipv4Addr::new(10, 0, 0, 1));
+        let ports = offset + IPV4_MIN_HEADER_LEN;
+        buf[ports..ports + 2].copy_from_slice(&1234u16.to_be_bytes());
+        buf[ports + 2..ports + 4].copy_from_slice(&dst_port.to_be_bytes());
+        buf
+    }
+
+    fn rule(action: FilterAction, protocol: FilterProtocol, port: Option<u16>) -> TxFilterRule {
+        TxFilterRule {
+            action,
+            protocol,
+            port,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut filter = TxFilterConfig {
+            check_source_mac: true,
+            ..Default::default()
+        };
+        assert!(matches!(filter.validate(None), Err(Error::MissingGuestMac)));
+        filter.validate(Some(guest_mac())).unwrap();
+
+        filter.rules = vec![
+            rule(FilterAction::Allow, FilterProtocol::Tcp, Some(22)),
+            rule(FilterAction::Allow, FilterProtocol::Icmp, Some(22)),
+        ];
+        assert!(matches!(
+            filter.validate(Some(guest_mac())),
+            Err(Error::PortWithoutTransport(1))
+        ));
+    }
+
+    #[test]
+    fn test_default_config() {
+        // The default filter lets everything through.
+        let filter = TxFilterConfig::default();
+        let other_mac = MacAddr::parse_str("02:00:00:00:00:01").unwrap();
+        let frame = arp_frame(other_mac, other_mac, OTHER_IP);
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Allow);
+        let frame = ipv4_frame(OTHER_IP, PROTOCOL_TCP, 22, 0);
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Allow);
+        // Frames too short for an Ethernet header are always dropped.
+        assert_eq!(filter.check(&frame[..10], None), Verdict::Malformed);
+    }
+
+    #[test]
+    fn test_spoofing() {
+        let filter = TxFilterConfig {
+            check_source_mac: true,
+            allowed_ipv4_sources: Some(vec![GUEST_IP]),
+            ..Default::default()
+        };
+        let mac = Some(guest_mac());
+        let other_mac = MacAddr::parse_str("02:00:00:00:00:01").unwrap();
+
+        assert_eq!(
+            filter.check(&arp_frame(guest_mac(), guest_mac(), GUEST_IP), mac),
+            Verdict::Allow
+        );
+        assert_eq!(
+            filter.check(&arp_frame(other_mac, guest_mac(), GUEST_IP), mac),
+            Verdict::SpoofedMac
+        );
+        assert_eq!(
+            filter.check(&arp_frame(guest_mac(), other_mac, GUEST_IP), mac),
+            Verdict::SpoofedMac
+        );
+        assert_eq!(
+            filter.check(&arp_frame(guest_mac(), guest_mac(), OTHER_IP), mac),
+            Verdict::SpoofedIp
+        );
+        // The ARP message must hold a whole IPv4 over Ethernet payload.
+        let frame = arp_frame(guest_mac(), guest_mac(), GUEST_IP);
+        assert_eq!(
+            filter.check(&frame[..PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN - 1], mac),
+            Verdict::Malformed
+        );
+
+        assert_eq!(
+            filter.check(&ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0), mac),
+            Verdict::Allow
+        );
+        assert_eq!(
+            filter.check(&ipv4_frame(OTHER_IP, PROTOCOL_UDP, 53, 0), mac),
+            Verdict::SpoofedIp
+        );
+        // The IPv4 header must be valid.
+        let mut frame = ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0);
+        frame[PAYLOAD_OFFSET] = 0x65;
+        assert_eq!(filter.check(&frame, mac), Verdict::Malformed);
+        let frame = ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0);
+        assert_eq!(
+            filter.check(&frame[..PAYLOAD_OFFSET + IPV4_MIN_HEADER_LEN - 1], mac),
+            Verdict::Malformed
+        );
+
+        // Frames of other protocols are only checked for their source MAC.
+        let mut buf = vec![0u8; 60];
+        eth_frame(&mut buf, guest_mac(), 0x86dd);
+        assert_eq!(filter.check(&buf, mac), Verdict::Allow);
+        eth_frame(&mut buf, other_mac, 0x86dd);
+        assert_eq!(filter.check(&buf, mac), Verdict::SpoofedMac);
+    }
+
+    #[test]
+    fn test_vlan_and_rarp() {
+        let filter = TxFilterConfig {
+            allowed_ipv4_sources: Some(vec![GUEST_IP]),
+            rules: vec![rule(FilterAction::Deny, FilterProtocol::Tcp, Some(25))],
+            ..Default::default()
+        };
+
+        // The packets carried by tagged frames are checked like untagged ones.
+        let tagged = |frame: &[u8], tags: &[u16]| {
+            let mut buf = frame[..PAYLOAD_OFFSET - 2].to_vec();
+            for tag in tags {
+                buf.extend_from_slice(&tag.to_be_bytes());
+                buf.extend_from_slice(&10u16.to_be_bytes());
+            }
+            buf.extend_from_slice(&frame[PAYLOAD_OFFSET - 2..]);
+            buf
+        };
+        let vlan = [ETHERTYPE_VLAN];
+        let qinq = [ETHERTYPE_QINQ, ETHERTYPE_VLAN];
+        let frame = ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0);
+        assert_eq!(filter.check(&tagged(&frame, &vlan), None), Verdict::Allow);
+        assert_eq!(filter.check(&tagged(&frame, &qinq), None), Verdict::Allow);
+        let frame = ipv4_frame(OTHER_IP, PROTOCOL_UDP, 53, 0);
+        assert_eq!(
+            filter.check(&tagged(&frame, &vlan), None),
+            Verdict::SpoofedIp
+        );
+        assert_eq!(
+            filter.check(&tagged(&frame, &qinq), None),
+            Verdict::SpoofedIp
+        );
+        let frame = ipv4_frame(GUEST_IP, PROTOCOL_TCP, 25, 0);
+        assert_eq!(filter.check(&tagged(&frame, &vlan), None), Verdict::Denied);
+        let frame = arp_frame(guest_mac(), guest_mac(), OTHER_IP);
+        assert_eq!(
+            filter.check(&tagged(&frame, &vlan), None),
+            Verdict::SpoofedIp
+        );
+
+        // Frames with too many tags, or truncated ones, are dropped.
+        let frame = ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0);
+        let three_tags = [ETHERTYPE_QINQ, ETHERTYPE_QINQ, ETHERTYPE_VLAN];
+        assert_eq!(
+            filter.check(&tagged(&frame, &three_tags), None),
+            Verdict::Malformed
+        );
+        let buf = tagged(&frame, &vlan);
+        assert_eq!(
+            filter.check(&buf[..PAYLOAD_OFFSET + 2], None),
+            Verdict::Malformed
+        );
+
+        // RARP messages are checked like ARP messages.
+        let mut frame = arp_frame(guest_mac(), guest_mac(), OTHER_IP);
+        frame[PAYLOAD_OFFSET - 2..PAYLOAD_OFFSET].copy_from_slice(&ETHERTYPE_RARP.to_be_bytes());
+        assert_eq!(filter.check(&frame, None), Verdict::SpoofedIp);
+    }
+
+    #[test]
+    fn test_rules() {
+        let filter = TxFilterConfig {
+            rules: vec![
+                rule(FilterAction::Deny, FilterProtocol::Tcp, Some(25)),
+                rule(FilterAction::Allow, FilterProtocol::Tcp, None),
+                rule(FilterAction::Allow, FilterProtocol::Udp, Some(53)),
+                rule(FilterAction::Allow, FilterProtocol::Arp, None),
+                rule(FilterAction::Allow, FilterProtocol::Icmp, None),
+            ],
+            default_action: FilterAction::Deny,
+            ..Default::default()
+        };
+
+        let cases = [
+            (ipv4_frame(GUEST_IP, PROTOCOL_TCP, 25, 0), Verdict::Denied),
+            (ipv4_frame(GUEST_IP, PROTOCOL_TCP, 80, 0), Verdict::Allow),
+            (ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0), Verdict::Allow),
+            (ipv4_frame(GUEST_IP, PROTOCOL_UDP, 54, 0), Verdict::Denied),
+            (ipv4_frame(GUEST_IP, PROTOCOL_ICMP, 0, 0), Verdict::Allow),
+            (ipv4_frame(GUEST_IP, 0x2f, 0, 0), Verdict::Denied),
+            (
+                arp_frame(guest_mac(), guest_mac(), GUEST_IP),
+                Verdict::Allow,
+            ),
+            // Later fragments don't hold the ports, so they only match rules without a port.
+            (ipv4_frame(GUEST_IP, PROTOCOL_TCP, 25, 8), Verdict::Allow),
+            (ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 8), Verdict::Denied),
+        ];
+        for (frame, verdict) in cases {
+            assert_eq!(filter.check(&frame, None), verdict);
+        }
+
+        let filter = TxFilterConfig {
+            rules: vec![
+                rule(FilterAction::Deny, FilterProtocol::Ipv4, None),
+                rule(FilterAction::Allow, FilterProtocol::Any, None),
+            ],
+            default_action: FilterAction::Deny,
+            ..Default::default()
+        };
+        let frame = ipv4_frame(GUEST_IP, PROTOCOL_UDP, 53, 0);
+        assert_eq!(filter.check(&frame, None), Verdict::Denied);
+        let frame = arp_frame(guest_mac(), guest_mac(), GUEST_IP);
+        assert_eq!(filter.check(&frame, None), Verdict::Allow);
+    }
+}
@@ -7,30 +7,41 @@ pub const MAX_BUFFER_SIZE: usize = 65562;
 pub const QUEUE_SIZE: u16 = 256;
 pub const NUM_QUEUES: usize = 2;
 pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+// The index of the rx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
+// The maximum number of rx/tx queue pairs of a Net device.
+pub const MAX_QUEUE_PAIRS: usize = 16;
 
+pub mod backend;
+pub mod capture;
 pub mod device;
+pub mod dhcp;
 pub mod event_handler;
+pub mod filter;
 mod iovec;
 pub mod persist;
-mod tap;
+pub mod rx_filter;
+pub(crate) mod tap;
 pub mod test_utils;
+pub(crate) mod unix_socket;
+pub mod user_nat;
 
+pub use capture::Error as CaptureError;
+pub use dhcp::Error as DhcpError;
+pub use filter::Error as TxFilterError;
 pub use tap::Error as TapError;
+pub use unix_socket::Error as UnixSocketError;
+pub use user_nat::Error as UserNatError;
 
+pub use self::capture::CaptureConfig;
 pub use self::device::Net;
+pub use self::dhcp::DhcpConfig;
 pub use self::event_handler::*;
-
-/// Enum representing the Net device queue types
-pub enum NetQueue {
-    /// The RX queue
-    Rx,
-    /// The TX queue
-    Tx,
-}
+pub use self::filter::TxFilterConfig;
+pub use self::rx_filter::RxFilter;
+pub use self::user_nat::UserNatConfig;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -43,12 +54,36 @@ pub enum Error {
     /// Setting vnet header size failed
     #[error("Setting vnet header size failed: {0}")]
     TapSetVnetHdrSize(TapError),
+    /// Attaching or detaching a tap queue failed
+    #[error("Attaching or detaching a tap queue failed: {0}")]
+    TapSetQueue(TapError),
+    /// Connecting to the host socket failed
+    #[error("Connecting to the host socket failed: {0}")]
+    UnixSocketConnect(UnixSocketError),
+    /// Setting up the user-mode NAT failed
+    #[error("Setting up the user-mode NAT failed: {0}")]
+    UserNat(UserNatError),
+    /// The number of queue pairs is not supported
+    #[error("The number of queue pairs is not supported: {0}")]
+    InvalidQueuePairs(usize),
     /// EventFd error
     #[error("EventFd error: {0}")]
     EventFd(io::Error),
     /// IO error
     #[error("IO error: {0}")]
     IO(io::Error),
+    /// Starting or stopping the capture of the frames failed
+    #[error("Starting or stopping the capture of the frames failed: {0}")]
+    Capture(CaptureError),
+    /// The filter of the frames sent by the guest is invalid
+    #[error("The filter of the frames sent by the guest is invalid: {0}")]
+    TxFilter(TxFilterError),
+    /// The lease handed to the guest by the DHCP server is invalid
+    #[error("The lease handed to the guest by the DHCP server is invalid: {0}")]
+    Dhcp(DhcpError),
+    /// The link state is not reported to the driver
+    #[error("The link state is not reported to the driver")]
+    LinkStateUnsupported,
     /// The VNET header is missing from the frame
     #[error("The VNET header is missing from the frame")]
     VnetHeaderMissing,
@@ -4,6 +4,7 @@
 //! Defines the structures needed for saving/restoring net devices.
 
 use std::io;
+use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
@@ -15,12 +16,18 @@ use rate_limiter::persist::RateLimiterState;
 use rate_limiter::RateLimiter;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_net::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_STATUS};
 use vm_memory::GuestMemoryMmap;
 
-use super::device::Net;
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::{Net, CTRL_FEATURES};
+use super::dhcp::DhcpConfig;
+use super::filter::{FilterAction, FilterProtocol, TxFilterConfig, TxFilterRule};
+use super::rx_filter::RxFilter;
+use super::user_nat::{ForwardProtocol, PortForward, UserNatConfig};
+use super::QUEUE_SIZE;
+use crate::virtio::device::VirtioDevice;
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
@@ -59,6 +66,305 @@ impl NetConfigSpaceState {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FilterActionState {
+    Allow,
+    Deny,
+}
+
+impl From<FilterAction> for FilterActionState {
+    fn from(action: FilterAction) -> Self {
+        match action {
+            FilterAction::Allow => FilterActionState::Allow,
+            FilterAction::Deny => FilterActionState::Deny,
+        }
+    }
+}
+
+impl From<FilterActionState> for FilterAction {
+    fn from(action_state: FilterActionState) -> Self {
+        match action_state {
+            FilterActionState::Allow => FilterAction::Allow,
+            FilterActionState::Deny => FilterAction::Deny,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum FilterProtocolState {
+    Any,
+    Arp,
+    Ipv4,
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+impl From<FilterProtocol> for FilterProtocolState {
+    fn from(protocol: FilterProtocol) -> Self {
+        match protocol {
+            FilterProtocol::Any => FilterProtocolState::Any,
+            FilterProtocol::Arp => FilterProtocolState::Arp,
+            FilterProtocol::Ipv4 => FilterProtocolState::Ipv4,
+            FilterProtocol::Icmp => FilterProtocolState::Icmp,
+            FilterProtocol::Tcp => FilterProtocolState::Tcp,
+            FilterProtocol::Udp => FilterProtocolState::Udp,
+        }
+    }
+}
+
+impl From<FilterProtocolState> for FilterProtocol {
+    fn from(protocol_state: FilterProtocolState) -> Self {
+        match protocol_state {
+            FilterProtocolState::Any => FilterProtocol::Any,
+            FilterProtocolState::Arp => FilterProtocol::Arp,
+            FilterProtocolState::Ipv4 => FilterProtocol::Ipv4,
+            FilterProtocolState::Icmp => FilterProtocol::Icmp,
+            FilterProtocolState::Tcp => FilterProtocol::Tcp,
+            FilterProtocolState::Udp => FilterProtocol::Udp,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct TxFilterRuleState {
+    action: FilterActionState,
+    protocol: FilterProtocolState,
+    port: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct TxFilterState {
+    check_source_mac: bool,
+    allowed_ipv4_sources: Option<Vec<u32>>,
+    rules: Vec<TxFilterRuleState>,
+    default_action: FilterActionState,
+}
+
+impl From<&TxFilterConfig> for TxFilterState {
+    fn from(filter: &TxFilterConfig) -> Self {
+        TxFilterState {
+            check_source_mac: filter.check_source_mac,
+            allowed_ipv4_sources: filter
+                .allowed_ipv4_sources
+                .as_ref()
+                .map(|sources| sources.iter().map(|addr| u32::from(*addr)).collect()),
+            rules: filter
+                .rules
+                .iter()
+                .map(|rule| TxFilterRuleState {
+                    action: rule.action.into(),
+                    protocol: rule.protocol.into(),
+                    port: rule.port,
+                })
+                .collect(),
+            default_action: filter.default_action.into(),
+        }
+    }
+}
+
+impl From<&TxFilterState> for TxFilterConfig {
+    fn from(state: &TxFilterState) -> Self {
+        TxFilterConfig {
+            check_source_mac: state.check_source_mac,
+            allowed_ipv4_sources: state
+                .allowed_ipv4_sources
+                .as_ref()
+                .map(|sources| sources.iter().map(|addr| Ipv4Addr::from(*addr)).collect()),
+            rules: state
+                .rules
+                .iter()
+                .map(|rule| TxFilterRule {
+                    action: rule.action.into(),
+                    protocol: rule.protocol.into(),
+                    port: rule.port,
+                })
+                .collect(),
+            default_action: state.default_action.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DhcpState {
+    address: u32,
+    prefix_len: u8,
+    gateway: Option<u32>,
+    dns_servers: Vec<u32>,
+    mtu: Option<u16>,
+    lease_time_secs: u32,
+    server_address: Option<u32>,
+}
+
+impl From<&DhcpConfig> for DhcpState {
+    fn from(config: &DhcpConfig) -> Self {
+        DhcpState {
+            address: u32::from(config.address),
+            prefix_len: config.prefix_len,
+            gateway: config.gateway.map(u32::from),
+            dns_servers: config
+                .dns_servers
+                .iter()
+                .map(|addr| u32::from(*addr))
+                .collect(),
+            mtu: config.mtu,
+            lease_time_secs: config.lease_time_secs,
+            server_address: config.server_address.map(u32::from),
+        }
+    }
+}
+
+impl From<&DhcpState> for DhcpConfig {
+    fn from(state: &DhcpState) -> Self {
+        DhcpConfig {
+            address: Ipv4Addr::from(state.address),
+            prefix_len: state.prefix_len,
+            gateway: state.gateway.map(Ipv4Addr::from),
+            dns_servers: state
+                .dns_servers
+                .iter()
+                .map(|addr| Ipv4Addr::from(*addr))
+                .collect(),
+            mtu: state.mtu,
+            lease_time_secs: state.lease_time_secs,
+            server_address: state.server_address.map(Ipv4Addr::from),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ForwardProtocolState {
+    Tcp,
+    Udp,
+}
+
+impl From<ForwardProtocol> for ForwardProtocolState {
+    fn from(protocol: ForwardProtocol) -> Self {
+        match protocol {
+            ForwardProtocol::Tcp => ForwardProtocolState::Tcp,
+            ForwardProtocol::Udp => ForwardProtocolState::Udp,
+        }
+    }
+}
+
+impl From<ForwardProtocolState> for ForwardProtocol {
+    fn from(protocol_state: ForwardProtocolState) -> Self {
+        match protocol_state {
+            ForwardProtocolState::Tcp => ForwardProtocol::Tcp,
+            ForwardProtocolState::Udp => ForwardProtocol::Udp,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct PortForwardState {
+    protocol: ForwardProtocolState,
+    host_address: u32,
+    host_port: u16,
+    guest_port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct UserNatState {
+    network: u32,
+    prefix_len: u8,
+    dns_servers: Vec<u32>,
+    allow_host_loopback: bool,
+    port_forwards: Vec<PortForwardState>,
+}
+
+impl From<&UserNatConfig> for UserNatState {
+    fn from(config: &UserNatConfig) -> Self {
+        UserNatState {
+            network: u32::from(config.network),
+            prefix_len: config.prefix_len,
+            dns_servers: config
+                .dns_servers
+                .iter()
+                .map(|addr| u32::from(*addr))
+                .collect(),
+            allow_host_loopback: config.allow_host_loopback,
+            port_forwards: config
+                .port_forwards
+                .iter()
+                .map(|forward| PortForwardState {
+                    protocol: forward.protocol.into(),
+                    host_address: u32::from(forward.host_address),
+                    host_port: forward.host_port,
+                    guest_port: forward.guest_port,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&UserNatState> for UserNatConfig {
+    fn from(state: &UserNatState) -> Self {
+        UserNatConfig {
+            network: Ipv4Addr::from(state.network),
+            prefix_len: state.prefix_len,
+            dns_servers: state
+                .dns_servers
+                .iter()
+                .map(|addr| Ipv4Addr::from(*addr))
+                .collect(),
+            allow_host_loopback: state.allow_host_loopback,
+            port_forwards: state
+                .port_forwards
+                .iter()
+                .map(|forward| PortForward {
+                    protocol: forward.protocol.into(),
+                    host_address: Ipv4Addr::from(forward.host_address),
+                    host_port: forward.host_port,
+                    guest_port: forward.guest_port,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct RxFilterState {
+    promisc: bool,
+    allmulti: bool,
+    unicast: Option<Vec<MacAddr>>,
+    multicast: Option<Vec<MacAddr>>,
+    vlans: Vec<u16>,
+}
+
+impl From<&RxFilter> for RxFilterState {
+    fn from(filter: &RxFilter) -> Self {
+        RxFilterState {
+            promisc: filter.promisc,
+            allmulti: filter.allmulti,
+            unicast: filter.unicast.clone(),
+            multicast: filter.multicast.clone(),
+            vlans: filter.vlans.clone(),
+        }
+    }
+}
+
+impl From<&RxFilterState> for RxFilter {
+    fn from(state: &RxFilterState) -> Self {
+        RxFilter {
+            promisc: state.promisc,
+            allmulti: state.allmulti,
+            unicast: state.unicast.clone(),
+            multicast: state.multicast.clone(),
+            vlans: state.vlans.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
@@ -69,6 +375,114 @@ pub struct NetState {
     pub mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(
+        start = 2,
+        ser_fn = "net_state_ser",
+        default_fn = "default_num_queue_pairs"
+    )]
+    num_queue_pairs: u16,
+    #[version(start = 2, default_fn = "default_num_queue_pairs")]
+    active_queue_pairs: u16,
+    #[version(start = 2)]
+    host_socket_path: Option<String>,
+    #[version(start = 2)]
+    tx_filter: Option<TxFilterState>,
+    #[version(start = 2, default_fn = "default_link_up")]
+    link_up: bool,
+    #[version(start = 2, default_fn = "default_rx_filter")]
+    rx_filter: RxFilterState,
+    #[version(start = 2)]
+    dhcp: Option<DhcpState>,
+    #[version(start = 2)]
+    user_nat: Option<UserNatState>,
+}
+
+impl NetState {
+    fn net_state_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version >= 2 {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.host_socket_path {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device backed by the socket {} to target version {}",
+                path, target_version
+            )));
+        }
+
+        if self.user_nat.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device backed by a user-mode NAT to target version {}",
+                target_version
+            )));
+        }
+
+        if self.tx_filter.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device with a TX filter to target version {}",
+                target_version
+            )));
+        }
+
+        if self.dhcp.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device with a DHCP server to target version {}",
+                target_version
+            )));
+        }
+
+        if self.num_queue_pairs != 1 {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device with {} queue pairs to target version {}",
+                self.num_queue_pairs, target_version
+            )));
+        }
+
+        // Older versions don't support mergeable receive buffers, which can only be dropped
+        // if the driver didn't acknowledge them.
+        let mrg_rxbuf = 1u64 << VIRTIO_NET_F_MRG_RXBUF;
+        if self.virtio_state.acked_features & mrg_rxbuf != 0 {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device using mergeable receive buffers to target version \
+                 {}",
+                target_version
+            )));
+        }
+        self.virtio_state.avail_features &= !mrg_rxbuf;
+
+        // Older versions don't report the link state either.
+        let status = 1u64 << VIRTIO_NET_F_STATUS;
+        if self.virtio_state.acked_features & status != 0 {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device reporting its link state to target version {}",
+                target_version
+            )));
+        }
+        self.virtio_state.avail_features &= !status;
+
+        // Nor do they have a control queue for devices with a single queue pair.
+        if self.virtio_state.acked_features & (1u64 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a net device using its control queue to target version {}",
+                target_version
+            )));
+        }
+        self.virtio_state.avail_features &= !CTRL_FEATURES;
+
+        Ok(())
+    }
+
+    fn default_rx_filter(_source_version: u16) -> RxFilterState {
+        RxFilterState::from(&RxFilter::default())
+    }
+
+    fn default_link_up(_source_version: u16) -> bool {
+        true
+    }
+
+    fn default_num_queue_pairs(_source_version: u16) -> u16 {
+        1
+    }
 }
 
 pub struct NetConstructorArgs {
@@ -101,6 +515,14 @@ impl Persist<'_> for Net {
                 guest_mac: Default::default(),
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            num_queue_pairs: self.num_queue_pairs() as u16,
+            active_queue_pairs: self.active_queue_pairs() as u16,
+            host_socket_path: self.host_socket_path().cloned(),
+            tx_filter: self.tx_filter().map(TxFilterState::from),
+            link_up: self.is_link_up(),
+            rx_filter: RxFilterState::from(self.rx_filter()),
+            dhcp: self.dhcp_config().map(DhcpState::from),
+            user_nat: self.user_nat().map(UserNatState::from),
         }
     }
 
@@ -111,13 +533,36 @@ impl Persist<'_> for Net {
         // RateLimiter::restore() can fail at creating a timerfd.
         let rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)?;
         let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)?;
-        let mut net = Net::new_with_tap(
-            state.id.clone(),
-            &state.tap_if_name,
-            state.config_space.guest_mac_v2,
-            rx_rate_limiter,
-            tx_rate_limiter,
-        )?;
+        let num_queue_pairs = usize::from(state.num_queue_pairs);
+        // The flows of a user-mode NAT don't survive the snapshot, unlike its configuration.
+        let mut net = match (&state.host_socket_path, &state.user_nat) {
+            (Some(path), _) => Net::new_with_socket(
+                state.id.clone(),
+                path,
+                state.config_space.guest_mac_v2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+            )?,
+            (None, Some(user_nat)) => Net::new_with_user_nat(
+                state.id.clone(),
+                &UserNatConfig::from(user_nat),
+                state.config_space.guest_mac_v2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+            )?,
+            (None, None) => Net::new_with_tap(
+                state.id.clone(),
+                &state.tap_if_name,
+                state.config_space.guest_mac_v2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                num_queue_pairs,
+            )?,
+        };
+        // The driver doesn't set the number of queue pairs again.
+        net.set_active_queue_pairs(usize::from(state.active_queue_pairs))?;
+        net.set_tx_filter(state.tx_filter.as_ref().map(TxFilterConfig::from))?;
+        net.set_dhcp(state.dhcp.as_ref().map(DhcpConfig::from))?;
 
         // We trust the MMIODeviceManager::restore to pass us an MMDS data store reference if
         // there is at least one net device having the MMDS NS present and/or the mmds version was
@@ -136,16 +581,28 @@ impl Persist<'_> for Net {
             );
         }
 
-        net.queues = state.virtio_state.build_queues_checked(
+        net.avail_features = state.virtio_state.avail_features;
+        net.acked_features = state.virtio_state.acked_features;
+        // Devices saved without a control queue keep running without one.
+        if net.avail_features & (1u64 << VIRTIO_NET_F_CTRL_VQ) == 0 {
+            net.remove_ctrl_queue();
+        }
+        // The control queue of a device with a single queue pair is only saved if the driver
+        // uses it, otherwise it is left as created.
+        let queues = state.virtio_state.build_queues_checked(
             &constructor_args.mem,
             TYPE_NET,
-            NUM_QUEUES,
+            net.queues().len(),
             QUEUE_SIZE,
         )?;
+        for (queue, restored_queue) in net.queues.iter_mut().zip(queues) {
+            *queue = restored_queue;
+        }
         net.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
-        net.avail_features = state.virtio_state.avail_features;
-        net.acked_features = state.virtio_state.acked_features;
+        net.rx_filter = RxFilter::from(&state.rx_filter);
+        // The driver already knows the link state, so it isn't notified.
+        net.set_link_up(state.link_up)?;
 
         if state.virtio_state.activated {
             net.device_state = DeviceState::Activated(constructor_args.mem);
@@ -157,16 +614,22 @@ impl Persist<'_> for Net {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::net::UnixDatagram;
     use std::sync::atomic::Ordering;
 
     use super::*;
-    use crate::virtio::device::VirtioDevice;
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net, default_net_no_mmds};
+    use crate::virtio::net::dhcp::tests::dhcp_config;
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_net, default_net_no_mmds, temp_socket_path,
+    };
 
     fn validate_save_and_restore(net: Net, mmds_ds: Option<Arc<Mutex<Mmds>>>) {
         let guest_mem = default_guest_memory();
         let mut mem = vec![0; 4096];
-        let version_map = VersionMap::new();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
 
         let id;
         let tap_if_name;
@@ -177,7 +640,7 @@ mod tests {
         // Create and save the net device.
         {
             <Net as Persist>::save(&net)
-                .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+                .serialize(&mut mem.as_mut_slice(), &version_map, 2)
                 .unwrap();
 
             // Save some fields that we want to check later.
@@ -198,7 +661,7 @@ mod tests {
                     mem: guest_mem,
                     mmds: mmds_ds,
                 },
-                &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+                &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
             ) {
                 Ok(restored_net) => {
                     // Test that virtio specific fields are the same.
@@ -239,4 +702,368 @@ mod tests {
         // data store. This will return an error.
         validate_save_and_restore(default_net(), None);
     }
+
+    #[test]
+    fn test_multi_queue_persistence() {
+        let mut net = Net::new_with_tap(
+            "mq-net".to_string(),
+            "mqnet%d",
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            2,
+        )
+        .unwrap();
+        net.set_active_queue_pairs(2).unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions don't support multiple queue pairs.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.num_queue_pairs(), 2);
+        assert_eq!(restored_net.active_queue_pairs(), 2);
+        assert_eq!(restored_net.queues().len(), 5);
+    }
+
+    #[test]
+    fn test_socket_persistence() {
+        let socket_path = temp_socket_path();
+        let _peer = UnixDatagram::bind(&socket_path).unwrap();
+        let net = Net::new_with_socket(
+            "socket-net".to_string(),
+            &socket_path,
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
+        let avail_features = net.avail_features();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions only support tap devices.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.host_socket_path(), Some(&socket_path));
+        assert_eq!(restored_net.iface_name(), "");
+        assert_eq!(restored_net.avail_features(), avail_features);
+    }
+
+    #[test]
+    fn test_user_nat_persistence() {
+        let config = UserNatConfig {
+            network: Ipv4Addr::new(192, 168, 100, 0),
+            dns_servers: vec![Ipv4Addr::new(192, 0, 2, 53)],
+            allow_host_loopback: true,
+            // Port 0 can't be forwarded, so pick a free port.
+            port_forwards: vec![PortForward {
+                protocol: ForwardProtocol::Udp,
+                host_address: Ipv4Addr::LOCALHOST,
+                host_port: std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port(),
+                guest_port: 53,
+            }],
+            ..Default::default()
+        };
+        let net = Net::new_with_user_nat(
+            "nat-net".to_string(),
+            &config,
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions only support tap devices.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        // Release the forwarded port.
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.user_nat(), Some(&config));
+        assert_eq!(restored_net.iface_name(), "");
+    }
+
+    #[test]
+    fn test_tx_filter_persistence() {
+        let mut net = default_net();
+        let filter = TxFilterConfig {
+            check_source_mac: true,
+            allowed_ipv4_sources: Some(vec![Ipv4Addr::new(10, 0, 0, 2)]),
+            rules: vec![TxFilterRule {
+                action: FilterAction::Deny,
+                protocol: FilterProtocol::Tcp,
+                port: Some(25),
+            }],
+            default_action: FilterAction::Allow,
+        };
+        net.set_tx_filter(Some(filter.clone())).unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions don't support filtering the frames.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: Some(Arc::new(Mutex::new(Mmds::default()))),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.tx_filter(), Some(&filter));
+    }
+
+    #[test]
+    fn test_dhcp_persistence() {
+        let mut net = default_net();
+        let config = dhcp_config();
+        net.set_dhcp(Some(config.clone())).unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions don't have a DHCP server.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: Some(Arc::new(Mutex::new(Mmds::default()))),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.dhcp_config(), Some(&config));
+    }
+
+    #[test]
+    fn test_default_net_persistence_v1() {
+        // Drivers acknowledge every feature offered by default, which must not prevent saving
+        // the device for the Firecracker versions before v1.3.
+        let mut net = default_net();
+        net.set_acked_features(net.avail_features());
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        assert_eq!(state.virtio_state.avail_features, net.avail_features());
+        assert_eq!(state.virtio_state.acked_features, net.acked_features());
+    }
+
+    #[test]
+    fn test_mergeable_rx_buffers_persistence() {
+        let mut net = default_net();
+        net.set_mergeable_rx_buffers(true);
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // The feature is dropped from the snapshots of older versions, unless it was acked.
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        assert_eq!(
+            state.virtio_state.avail_features,
+            net.avail_features()
+                & !(1 << VIRTIO_NET_F_MRG_RXBUF | 1 << VIRTIO_NET_F_STATUS | CTRL_FEATURES)
+        );
+
+        net.set_acked_features(1 << VIRTIO_NET_F_MRG_RXBUF);
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(state.virtio_state.avail_features, net.avail_features());
+        assert_eq!(state.virtio_state.acked_features, net.acked_features());
+    }
+
+    #[test]
+    fn test_link_state_persistence() {
+        let mut net = default_net();
+        net.set_link_state_reporting(true);
+        net.set_link_up(false).unwrap();
+        net.set_acked_features(1 << VIRTIO_NET_F_STATUS);
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions don't report the link state to the driver.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: Some(Arc::new(Mutex::new(Mmds::default()))),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert!(!restored_net.is_link_up());
+        assert_eq!(restored_net.config_space.status, 0);
+    }
+
+    #[test]
+    fn test_rx_filter_persistence() {
+        let mut net = default_net();
+        net.set_ctrl_queue(true);
+        net.set_acked_features(CTRL_FEATURES);
+        net.rx_filter.promisc = false;
+        net.rx_filter.multicast = None;
+        net.rx_filter.vlans = vec![10];
+        let rx_filter = net.rx_filter().clone();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+
+        // Older versions don't have a control queue for devices with a single queue pair.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: Some(Arc::new(Mutex::new(Mmds::default()))),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.rx_filter(), &rx_filter);
+        assert_eq!(restored_net.queues().len(), 3);
+        drop(restored_net);
+
+        // Devices restored from older versions keep running without a control queue.
+        let net = default_net();
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: Some(Arc::new(Mutex::new(Mmds::default()))),
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.avail_features() & CTRL_FEATURES, 0);
+        assert_eq!(restored_net.ctrl_queue_index(), None);
+        assert_eq!(restored_net.queue_events().len(), 2);
+        assert_eq!(restored_net.rx_filter(), &RxFilter::default());
+    }
 }
@@ -0,0 +1,325 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of the frames delivered to the guest, according to the receive mode, MAC tables
+//! and VLAN table programmed by the driver through the control queue.
+//!
+//! The device starts in promiscuous mode, until the driver programs it, and always accepts
+//! broadcast frames. Like in other implementations, a MAC table holding more addresses than
+//! the device supports accepts all the addresses of its kind.
+
+use dumbo::pdu::ethernet::EthernetFrame;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+/// The number of addresses each MAC table holds.
+pub const MAX_MAC_TABLE_ENTRIES: usize = 64;
+/// The largest VLAN ID.
+pub const MAX_VLAN_ID: u16 = 4095;
+
+// The ethertype of frames carrying an 802.1Q tag.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+// The mask of the VLAN ID in the tag control information.
+const VLAN_ID_MASK: u16 = 0x0fff;
+// The size of the entry count preceding each MAC table.
+const MAC_TABLE_HDR_LEN: usize = 4;
+
+/// The receive filter programmed by the driver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RxFilter {
+    /// Whether all the frames are accepted.
+    pub promisc: bool,
+    /// Whether all the multicast frames are accepted.
+    pub allmulti: bool,
+    /// The unicast addresses accepted besides the guest MAC, or `None` when the driver set
+    /// more addresses than the table holds.
+    pub unicast: Option<Vec<MacAddr>>,
+    /// The multicast addresses accepted, or `None` when the driver set more addresses than the
+    /// table holds.
+    pub multicast: Option<Vec<MacAddr>>,
+    /// The VLAN IDs of the tagged frames accepted.
+    pub vlans: Vec<u16>,
+}
+
+impl Default for RxFilter {
+    fn default() -> Self {
+        RxFilter {
+            promisc: true,
+            allmulti: false,
+            unicast: Some(Vec::new()),
+            multicast: Some(Vec::new()),
+            vlans: Vec::new(),
+        }
+    }
+}
+
+// Parses a MAC table made of its entry count and addresses, returning it with the bytes which
+// follow it.
+fn parse_mac_table(data: &[u8]) -> Option<(Option<Vec<MacAddr>>, &[u8])> {
+    let mut count = [0u8; MAC_TABLE_HDR_LEN];
+    count.copy_from_slice(data.get(..MAC_TABLE_HDR_LEN)?);
+    let entries = usize::try_from(u32::from_le_bytes(count)).ok()?;
+    let end = entries
+        .checked_mul(MAC_ADDR_LEN)?
+        .checked_add(MAC_TABLE_HDR_LEN)?;
+    let table = data.get(MAC_TABLE_HDR_LEN..end)?;
+
+    let addresses = if entries > MAX_MAC_TABLE_ENTRIES {
+        None
+    } else {
+        Some(
+            table
+                .chunks_exact(MAC_ADDR_LEN)
+                .map(MacAddr::from_bytes_unchecked)
+                .collect(),
+        )
+    };
+    Some((addresses, &data[end..]))
+}
+
+fn parse_vlan_id(data: &[u8]) -> Option<u16> {
+    match data {
+        [low, high] => Some(u16::from_le_bytes([*low, *high])).filter(|id| *id <= MAX_VLAN_ID),
+        _ => None,
+    }
+}
+
+impl RxFilter {
+    /// Applies the data of a `VIRTIO_NET_CTRL_RX_PROMISC` command. Returns whether it is valid.
+    pub fn set_promisc(&mut self, data: &[u8]) -> bool {
+        match data {
+            [on] => {
+                self.promisc = *on != 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the data of a `VIRTIO_NET_CTRL_RX_ALLMULTI` command. Returns whether it is valid.
+    pub fn set_allmulti(&mut self, data: &[u8]) -> bool {
+        match data {
+            [on] => {
+                self.allmulti = *on != 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the data of a `VIRTIO_NET_CTRL_MAC_TABLE_SET` command, made of the unicast table
+    /// followed by the multicast table. Returns whether it is valid.
+    pub fn set_mac_tables(&mut self, data: &[u8]) -> bool {
+        let tables = parse_mac_table(data).and_then(|(unicast, rest)| {
+            parse_mac_table(rest).map(|(multicast, rest)| (unicast, multicast, rest))
+        });
+        match tables {
+            Some((unicast, multicast, [])) => {
+                self.unicast = unicast;
+                self.multicast = multicast;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Applies the data of a `VIRTIO_NET_CTRL_VLAN_ADD` command. Returns whether it is valid.
+    pub fn add_vlan(&mut self, data: &[u8]) -> bool {
+        match parse_vlan_id(data) {
+            Some(id) => {
+                if !self.vlans.contains(&id) {
+                    self.vlans.push(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Applies the data of a `VIRTIO_NET_CTRL_VLAN_DEL` command. Returns whether it is valid.
+    pub fn remove_vlan(&mut self, data: &[u8]) -> bool {
+        match parse_vlan_id(data) {
+            Some(id) => {
+                self.vlans.retain(|vlan| *vlan != id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the frame `frame` is delivered to a driver using the `guest_mac` MAC, checking
+    /// its destination if `mac_filtering` and its VLAN tag if `vlan_filtering`.
+    pub fn accepts(
+        &self,
+        frame: &[u8],
+        guest_mac: Option<MacAddr>,
+        mac_filtering: bool,
+        vlan_filtering: bool,
+    ) -> bool {
+        if (mac_filtering && self.promisc) || (!mac_filtering && !vlan_filtering) {
+            return true;
+        }
+        let eth_frame = match EthernetFrame::from_bytes(frame) {
+            Ok(eth_frame) => eth_frame,
+            Err(_) => return false,
+        };
+
+        if vlan_filtering && eth_frame.ethertype() == ETHERTYPE_VLAN {
+            let vlan_id = match eth_frame.payload() {
+                [high, low, ..] => u16::from_be_bytes([*high, *low]) & VLAN_ID_MASK,
+                _ => return false,
+            };
+            if !self.vlans.contains(&vlan_id) {
+                return false;
+            }
+        }
+        if !mac_filtering {
+            return true;
+        }
+
+        let dst_mac = eth_frame.dst_mac();
+        let bytes = dst_mac.get_bytes();
+        if bytes.iter().all(|byte| *byte == 0xff) {
+            true
+        } else if bytes[0] & 0x01 != 0 {
+            self.allmulti
+                || self
+                    .multicast
+                    .as_ref()
+                    .map_or(true, |table| table.contains(&dst_mac))
+        } else {
+            guest_mac == Some(dst_mac)
+                || self
+                    .unicast
+                    .as_ref()
+                    .map_or(true, |table| table.contains(&dst_mac))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dumbo::pdu::ethernet::ETHERTYPE_IPV4;
+
+    use super::*;
+
+    const GUEST_MAC: &str = "12:34:56:78:9a:bc";
+
+    fn guest_mac() -> Option<MacAddr> {
+        Some(MacAddr::parse_str(GUEST_MAC).unwrap())
+    }
+
+    fn frame(dst_mac: &str, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 60];
+        EthernetFrame::write_incomplete(
+            buf.as_mut_slice(),
+            MacAddr::parse_str(dst_mac).unwrap(),
+            MacAddr::parse_str("02:00:00:00:00:01").unwrap(),
+            ethertype,
+        )
+        .unwrap();
+        buf[14..14 + payload.len()].copy_from_slice(payload);
+        buf
+    }
+
+    fn mac_tables(unicast: &[&str], multicast: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for table in [unicast, multicast] {
+            data.extend_from_slice(&(table.len() as u32).to_le_bytes());
+            for mac in table {
+                data.extend_from_slice(MacAddr::parse_str(mac).unwrap().get_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_rx_mode() {
+        let mut filter = RxFilter::default();
+        let other = frame("02:00:00:00:00:02", ETHERTYPE_IPV4, &[]);
+        let multicast = frame("01:00:5e:00:00:01", ETHERTYPE_IPV4, &[]);
+
+        // The device starts in promiscuous mode.
+        assert!(filter.accepts(&other, guest_mac(), true, false));
+        assert!(filter.accepts(&[0u8; 4], guest_mac(), true, false));
+
+        assert!(filter.set_promisc(&[0]));
+        assert!(!filter.promisc);
+        assert!(!filter.accepts(&other, guest_mac(), true, false));
+        assert!(!filter.accepts(&multicast, guest_mac(), true, false));
+        assert!(!filter.accepts(&[0u8; 4], guest_mac(), true, false));
+        // Without MAC filtering, every frame is accepted.
+        assert!(filter.accepts(&other, guest_mac(), false, false));
+
+        assert!(filter.set_allmulti(&[1]));
+        assert!(filter.accepts(&multicast, guest_mac(), true, false));
+        assert!(!filter.accepts(&other, guest_mac(), true, false));
+
+        // The commands hold a single byte.
+        assert!(!filter.set_promisc(&[]));
+        assert!(!filter.set_allmulti(&[1, 1]));
+    }
+
+    #[test]
+    fn test_mac_tables() {
+        let mut filter = RxFilter::default();
+        filter.set_promisc(&[0]);
+
+        let guest = frame(GUEST_MAC, ETHERTYPE_IPV4, &[]);
+        let broadcast = frame("ff:ff:ff:ff:ff:ff", ETHERTYPE_IPV4, &[]);
+        let unicast = frame("02:00:00:00:00:02", ETHERTYPE_IPV4, &[]);
+        let multicast = frame("33:33:00:00:00:01", ETHERTYPE_IPV4, &[]);
+        assert!(filter.accepts(&guest, guest_mac(), true, false));
+        assert!(filter.accepts(&broadcast, guest_mac(), true, false));
+        assert!(!filter.accepts(&unicast, guest_mac(), true, false));
+        assert!(!filter.accepts(&multicast, guest_mac(), true, false));
+
+        assert!(filter.set_mac_tables(&mac_tables(&["02:00:00:00:00:02"], &["33:33:00:00:00:01"])));
+        assert!(filter.accepts(&unicast, guest_mac(), true, false));
+        assert!(filter.accepts(&multicast, guest_mac(), true, false));
+        // Frames to the guest MAC are accepted whatever the tables hold.
+        assert!(filter.accepts(&guest, guest_mac(), true, false));
+        assert!(!filter.accepts(&guest, None, true, false));
+
+        // Tables holding too many addresses accept all the addresses of their kind.
+        let many = vec!["02:00:00:00:00:03"; MAX_MAC_TABLE_ENTRIES + 1];
+        assert!(filter.set_mac_tables(&mac_tables(&many, &[])));
+        assert_eq!(filter.unicast, None);
+        assert_eq!(filter.multicast, Some(Vec::new()));
+        assert!(filter.accepts(&unicast, guest_mac(), true, false));
+        assert!(!filter.accepts(&multicast, guest_mac(), true, false));
+
+        // The data must hold both tables and nothing else.
+        let before = filter.clone();
+        let data = mac_tables(&["02:00:00:00:00:02"], &[]);
+        assert!(!filter.set_mac_tables(&data[..data.len() - 1]));
+        assert!(!filter.set_mac_tables(&[data.as_slice(), &[0]].concat()));
+        assert!(!filter.set_mac_tables(&data[..MAC_TABLE_HDR_LEN + MAC_ADDR_LEN]));
+        assert!(!filter.set_mac_tables(&[0xff; 8]));
+        assert_eq!(filter, before);
+    }
+
+    #[test]
+    fn test_vlans() {
+        let mut filter = RxFilter::default();
+        let tagged = frame(GUEST_MAC, ETHERTYPE_VLAN, &[0x20, 0x0a]);
+        let untagged = frame(GUEST_MAC, ETHERTYPE_IPV4, &[]);
+
+        // Tagged frames are only checked with VLAN filtering.
+        assert!(filter.accepts(&tagged, guest_mac(), false, false));
+        assert!(!filter.accepts(&tagged, guest_mac(), false, true));
+        assert!(filter.accepts(&untagged, guest_mac(), false, true));
+
+        // The priority bits aren't part of the VLAN ID.
+        assert!(filter.add_vlan(&10u16.to_le_bytes()));
+        assert!(filter.add_vlan(&10u16.to_le_bytes()));
+        assert_eq!(filter.vlans, vec![10]);
+        assert!(filter.accepts(&tagged, guest_mac(), false, true));
+
+        assert!(filter.remove_vlan(&10u16.to_le_bytes()));
+        assert!(filter.vlans.is_empty());
+        assert!(!filter.accepts(&tagged, guest_mac(), false, true));
+
+        assert!(!filter.add_vlan(&(MAX_VLAN_ID + 1).to_le_bytes()));
+        assert!(!filter.remove_vlan(&[1]));
+    }
+}
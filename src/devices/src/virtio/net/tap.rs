@@ -14,8 +14,8 @@ use net_gen::ifreq;
 use utils::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 use utils::{ioctl_ioc_nr, ioctl_iow_nr};
 
-#[cfg(test)]
-use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::Error as NetError;
 
 // As defined in the Linux UAPI:
 // https://elixir.bootlin.com/linux/v4.17/source/include/uapi/linux/if.h#L33
@@ -42,6 +42,9 @@ pub enum Error {
     /// Error while setting size of the vnet header
     #[error("Error while setting size of the vnet header: {0}")]
     SetSizeOfVnetHdr(IoError),
+    /// Error while attaching or detaching a queue of the interface
+    #[error("Error while attaching or detaching a queue of the interface: {0}")]
+    SetQueue(IoError),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -50,6 +53,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -60,14 +64,11 @@ ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
 pub struct Tap {
     tap_file: File,
     pub(crate) if_name: [u8; IFACE_NAME_MAX_LEN],
-
-    #[cfg(test)]
-    pub(crate) mocks: Mocks,
 }
 
 // Returns a byte vector representing the contents of a null terminated C string which
 // contains if_name.
-fn build_terminated_if_name(if_name: &str) -> Result<[u8; IFACE_NAME_MAX_LEN]> {
+pub(crate) fn build_terminated_if_name(if_name: &str) -> Result<[u8; IFACE_NAME_MAX_LEN]> {
     // Convert the string slice to bytes, and shadow the variable,
     // since we no longer need the &str version.
     let if_name = if_name.as_bytes();
@@ -120,6 +121,28 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Self::open(if_name, 0)
+    }
+
+    /// Open `num_queues` queues of a multi-queue TUN/TAP device given the interface name. The
+    /// interface must have been created with multi-queue support, if it already exists.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_multi_queue(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        let first = Self::open(if_name, net_gen::IFF_MULTI_QUEUE)?;
+        // The name may have been assigned by the kernel, and the other queues must use it.
+        let if_name = first.if_name_as_str().to_string();
+        let mut taps = vec![first];
+        for _ in 1..num_queues {
+            taps.push(Self::open(&if_name, net_gen::IFF_MULTI_QUEUE)?);
+        }
+
+        Ok(taps)
+    }
+
+    fn open(if_name: &str, extra_flags: u32) -> Result<Tap> {
         // SAFETY: Open calls are safe because we give a constant null-terminated
         // string and verify the result.
         let fd = unsafe {
@@ -138,7 +161,10 @@ impl Tap {
         let terminated_if_name = build_terminated_if_name(if_name)?;
         let ifreq = IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .flags(
+                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | extra_flags)
+                    as i16,
+            )
             .execute(&tuntap, TUNSETIFF())
             .map_err(|io_error| Error::IfreqExecuteError(io_error, if_name.to_owned()))?;
 
@@ -146,9 +172,6 @@ impl Tap {
             tap_file: tuntap,
             // SAFETY: Safe since only the name is accessed, and it's cloned out.
             if_name: unsafe { ifreq.ifr_ifrn.ifrn_name },
-
-            #[cfg(test)]
-            mocks: Mocks::default(),
         })
     }
 
@@ -180,6 +203,22 @@ impl Tap {
 
         Ok(())
     }
+
+    /// Attach the queue to, or detach it from a multi-queue interface. A detached queue neither
+    /// receives nor sends frames. The queues are attached when opened.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())
+            .map_err(Error::SetQueue)?;
+
+        Ok(())
+    }
 }
 
 impl Read for Tap {
@@ -208,6 +247,12 @@ impl AsRawFd for Tap {
     }
 }
 
+impl NetBackend for Tap {
+    fn set_queue_enabled(&self, enabled: bool) -> crate::virtio::net::Result<()> {
+        Tap::set_queue_enabled(self, enabled).map_err(NetError::TapSetQueue)
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
@@ -275,7 +320,6 @@ pub mod tests {
         let faulty_tap = Tap {
             tap_file: unsafe { File::from_raw_fd(-2) },
             if_name: [0x01; 16],
-            mocks: Default::default(),
         };
         assert_eq!(
             faulty_tap.set_vnet_hdr_size(16).unwrap_err().to_string(),
@@ -285,6 +329,27 @@ pub mod tests {
             faulty_tap.set_offload(0).unwrap_err().to_string(),
             Error::SetOffloadFlags(IoError::from_raw_os_error(9)).to_string()
         );
+        assert_eq!(
+            faulty_tap.set_queue_enabled(false).unwrap_err().to_string(),
+            Error::SetQueue(IoError::from_raw_os_error(9)).to_string()
+        );
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let taps = Tap::open_multi_queue("mqtap%d", 3).unwrap();
+        assert_eq!(taps.len(), 3);
+        // All the queues belong to the interface named by the kernel.
+        assert_ne!(b"mqtap%d", &taps[0].if_name[..7]);
+        assert!(taps.iter().all(|tap| tap.if_name == taps[0].if_name));
+        // The interface can't be opened as a single queue one.
+        Tap::open_named(taps[0].if_name_as_str()).unwrap_err();
+
+        // The queues are attached when opened.
+        taps[1].set_queue_enabled(true).unwrap_err();
+        taps[1].set_queue_enabled(false).unwrap();
+        taps[1].set_queue_enabled(false).unwrap_err();
+        taps[1].set_queue_enabled(true).unwrap();
     }
 
     #[test]
@@ -296,8 +361,8 @@ pub mod tests {
     #[test]
     fn test_read() {
         let mut tap = Tap::open_named("").unwrap();
-        enable(&tap);
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&tap));
+        enable(tap.if_name_as_str());
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(tap.if_name_as_str()));
 
         let packet = utils::rand::rand_alphanumerics(PAYLOAD_SIZE);
         tap_traffic_simulator.push_tx_packet(packet.as_bytes());
@@ -313,8 +378,8 @@ pub mod tests {
     #[test]
     fn test_write() {
         let mut tap = Tap::open_named("").unwrap();
-        enable(&tap);
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&tap));
+        enable(tap.if_name_as_str());
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(tap.if_name_as_str()));
 
         let mut packet = [0u8; PACKET_SIZE];
         let payload = utils::rand::rand_alphanumerics(PAYLOAD_SIZE);
@@ -16,11 +16,13 @@ use mmds::data_store::Mmds;
 use mmds::ns::MmdsNetworkStack;
 use rate_limiter::RateLimiter;
 use utils::net::mac::MacAddr;
+use utils::tempfile::TempFile;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
+use crate::virtio::net::capture::{PCAP_HEADER_SIZE, PCAP_RECORD_HEADER_SIZE};
 #[cfg(test)]
 use crate::virtio::net::device::vnet_hdr_len;
-use crate::virtio::net::tap::{Error, IfReqBuilder, Tap};
+use crate::virtio::net::tap::{build_terminated_if_name, Error, IfReqBuilder};
 use crate::virtio::test_utils::VirtQueue;
 use crate::virtio::{Net, Queue, QueueError};
 use crate::Error as DeviceError;
@@ -47,13 +49,14 @@ pub fn default_net() -> Net {
         Some(guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        1,
     )
     .unwrap();
     net.configure_mmds_network_stack(
         MmdsNetworkStack::default_ipv4_addr(),
         Arc::new(Mutex::new(Mmds::default())),
     );
-    enable(&net.tap);
+    enable(&net.iface_name());
 
     net
 }
@@ -70,9 +73,10 @@ pub fn default_net_no_mmds() -> Net {
         Some(guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        1,
     )
     .unwrap();
-    enable(&net.tap);
+    enable(&net.iface_name());
 
     net
 }
@@ -244,10 +248,10 @@ pub fn virtqueues(mem: &GuestMemoryMmap) -> (VirtQueue, VirtQueue) {
     (rxq, txq)
 }
 
-pub fn if_index(tap: &Tap) -> i32 {
+pub fn if_index(if_name: &str) -> i32 {
     let sock = create_socket();
     let ifreq = IfReqBuilder::new()
-        .if_name(&tap.if_name)
+        .if_name(&build_terminated_if_name(if_name).unwrap())
         .execute(&sock, c_ulong::from(net_gen::sockios::SIOCGIFINDEX))
         .unwrap();
 
@@ -256,20 +260,20 @@ pub fn if_index(tap: &Tap) -> i32 {
 }
 
 /// Enable the tap interface.
-pub fn enable(tap: &Tap) {
+pub fn enable(if_name: &str) {
     // Disable IPv6 router advertisment requests
     Command::new("sh")
         .arg("-c")
         .arg(format!(
             "echo 0 > /proc/sys/net/ipv6/conf/{}/accept_ra",
-            tap.if_name_as_str()
+            if_name
         ))
         .output()
         .unwrap();
 
     let sock = create_socket();
     IfReqBuilder::new()
-        .if_name(&tap.if_name)
+        .if_name(&build_terminated_if_name(if_name).unwrap())
         .flags(
             (net_gen::net_device_flags_IFF_UP
                 | net_gen::net_device_flags_IFF_RUNNING
@@ -282,7 +286,7 @@ pub fn enable(tap: &Tap) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.iface_name()));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -315,6 +319,32 @@ pub fn default_guest_mac() -> MacAddr {
     MacAddr::parse_str("11:22:33:44:55:66").unwrap()
 }
 
+/// Returns an unused path for a Unix socket.
+pub fn temp_socket_path() -> String {
+    let mut temp_uds_path = TempFile::new().unwrap();
+    // Remove the file so the path can be used by the socket.
+    temp_uds_path.remove().unwrap();
+    String::from(temp_uds_path.as_path().to_str().unwrap())
+}
+
+/// Returns the (captured length, frame length, captured bytes) of the records of a capture.
+pub fn capture_records(capture: &[u8]) -> Vec<(u32, u32, Vec<u8>)> {
+    let mut records = Vec::new();
+    let mut bytes = &capture[PCAP_HEADER_SIZE..];
+    while !bytes.is_empty() {
+        let incl_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let orig_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let end = PCAP_RECORD_HEADER_SIZE + incl_len as usize;
+        records.push((
+            incl_len,
+            orig_len,
+            bytes[PCAP_RECORD_HEADER_SIZE..end].to_vec(),
+        ));
+        bytes = &bytes[end..];
+    }
+    records
+}
+
 pub fn default_guest_memory() -> GuestMemoryMmap {
     vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
         .expect("Cannot initialize memory")
@@ -412,10 +442,10 @@ pub mod test {
 
         pub fn simulate_event(&mut self, event: NetEvent) {
             match event {
-                NetEvent::RxQueue => self.net().process_rx_queue_event(),
+                NetEvent::RxQueue => self.net().process_rx_queue_event(0),
                 NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(),
-                NetEvent::Tap => self.net().process_tap_rx_event(),
-                NetEvent::TxQueue => self.net().process_tx_queue_event(),
+                NetEvent::Tap => self.net().process_tap_rx_event(0),
+                NetEvent::TxQueue => self.net().process_tx_queue_event(0),
                 NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(),
             };
         }
@@ -465,7 +495,9 @@ pub mod test {
 
         /// Generate a tap frame of `frame_len` and check that it is deferred
         pub fn check_rx_deferred_frame(&mut self, frame_len: usize) -> Vec<u8> {
-            self.net().tap.mocks.set_read_tap(ReadTapMock::TapFrame);
+            self.net().queue_pairs[0]
+                .mocks
+                .set_read_tap(ReadTapMock::TapFrame);
             let used_idx = self.rxq.used.idx.get();
 
             // Inject frame to tap and run epoll.
@@ -476,7 +508,7 @@ pub mod test {
                 self.event_manager.run_with_timeout(100).unwrap()
             );
             // Check that the frame has been deferred.
-            assert!(self.net().rx_deferred_frame);
+            assert!(self.net().queue_pairs[0].rx_deferred_frame);
             // Check that the descriptor chain has been discarded.
             assert_eq!(self.rxq.used.idx.get(), used_idx + 1);
             assert!(&self.net().irq_trigger.has_pending_irq(IrqType::Vring));
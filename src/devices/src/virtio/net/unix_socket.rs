@@ -0,0 +1,319 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, IoSlice, Read, Result as IoResult, Write};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::device::vnet_hdr_len;
+
+/// List of errors the Unix socket backend can throw.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The socket path is too long
+    #[error("The socket path is too long: {0}")]
+    PathTooLong(String),
+    /// Couldn't create the socket
+    #[error("Couldn't create the socket: {0}")]
+    CreateSocket(IoError),
+    /// Couldn't bind the socket to an address
+    #[error("Couldn't bind the socket to an address: {0}")]
+    Bind(IoError),
+    /// Couldn't connect to the socket
+    #[error("Couldn't connect to the socket {0}: {1}")]
+    Connect(String, IoError),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Handle for a Unix socket exchanging raw Ethernet frames with another process, one frame per
+/// message.
+///
+/// The frames of the device are prefixed by a VNET header, which is dropped when writing to the
+/// socket, and set to all 0 when reading from it. The socket is closed when `UnixSocket` goes out
+/// of scope.
+#[derive(Debug)]
+pub struct UnixSocket {
+    socket: File,
+}
+
+// Builds the address of the socket at `path`.
+fn socket_addr(path: &str) -> Result<libc::sockaddr_un> {
+    // SAFETY: `sockaddr_un` only contains integers, for which all 0 is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // The path must be null terminated.
+    let path_bytes = path.as_bytes();
+    if path_bytes.len() >= addr.sun_path.len() {
+        return Err(Error::PathTooLong(path.to_owned()));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path_bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    Ok(addr)
+}
+
+impl UnixSocket {
+    /// Connect to the `SOCK_SEQPACKET` or `SOCK_DGRAM` Unix socket at `path`.
+    /// # Arguments
+    ///
+    /// * `path` - the path of the socket.
+    pub fn connect(path: &str) -> Result<UnixSocket> {
+        let addr = socket_addr(path)?;
+        let socket = match Self::open(path, &addr, libc::SOCK_SEQPACKET) {
+            // The socket at `path` has another type.
+            Err(Error::Connect(_, err)) if err.raw_os_error() == Some(libc::EPROTOTYPE) => {
+                Self::open(path, &addr, libc::SOCK_DGRAM)?
+            }
+            result => result?,
+        };
+
+        Ok(UnixSocket { socket })
+    }
+
+    fn open(path: &str, addr: &libc::sockaddr_un, socket_type: c_int) -> Result<File> {
+        // SAFETY: Safe because we check the return value.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_UNIX,
+                socket_type | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::CreateSocket(IoError::last_os_error()));
+        }
+
+        // SAFETY: We just checked that the fd is valid.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        if socket_type == libc::SOCK_DGRAM {
+            // The peer can only reply to a datagram socket which has an address. Binding an
+            // address made of the family alone has the kernel pick an unused abstract one.
+            let family_len = mem::size_of::<libc::sa_family_t>() as libc::socklen_t;
+            // SAFETY: Safe because the address outlives the call, and we check the return value.
+            if unsafe { libc::bind(fd, (addr as *const libc::sockaddr_un).cast(), family_len) } < 0
+            {
+                return Err(Error::Bind(IoError::last_os_error()));
+            }
+        }
+
+        let addr_len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        // SAFETY: Safe because the address outlives the call, and we check the return value.
+        if unsafe { libc::connect(fd, (addr as *const libc::sockaddr_un).cast(), addr_len) } < 0 {
+            return Err(Error::Connect(path.to_owned(), IoError::last_os_error()));
+        }
+
+        Ok(socket)
+    }
+}
+
+impl Read for UnixSocket {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.len() < vnet_hdr_len() {
+            return Err(IoError::from(ErrorKind::InvalidInput));
+        }
+
+        let (vnet_hdr, frame) = buf.split_at_mut(vnet_hdr_len());
+        let len = self.socket.read(frame)?;
+        // An empty message is not a frame, and is what a `SOCK_SEQPACKET` socket reads once the
+        // peer closed its end.
+        if len == 0 {
+            return Err(IoError::from(ErrorKind::UnexpectedEof));
+        }
+        vnet_hdr.fill(0);
+
+        Ok(vnet_hdr_len() + len)
+    }
+}
+
+impl Write for UnixSocket {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> IoResult<usize> {
+        // Skip the VNET header, which may span several slices.
+        let mut hdr_left = vnet_hdr_len();
+        let mut frame = Vec::with_capacity(bufs.len());
+        for buf in bufs {
+            if hdr_left >= buf.len() {
+                hdr_left -= buf.len();
+            } else {
+                frame.push(IoSlice::new(&buf[hdr_left..]));
+                hdr_left = 0;
+            }
+        }
+        if hdr_left > 0 {
+            return Err(IoError::from(ErrorKind::InvalidInput));
+        }
+
+        Ok(vnet_hdr_len() + self.socket.write_vectored(&frame)?)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UnixSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl NetBackend for UnixSocket {}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+    use crate::virtio::net::test_utils::temp_socket_path;
+
+    const FRAME_SIZE: usize = 512;
+
+    #[test]
+    fn test_connect_errors() {
+        let path = "a".repeat(108);
+        assert_eq!(
+            UnixSocket::connect(&path).unwrap_err().to_string(),
+            Error::PathTooLong(path).to_string()
+        );
+
+        let path = temp_socket_path();
+        assert_eq!(
+            UnixSocket::connect(&path).unwrap_err().to_string(),
+            Error::Connect(path, IoError::from_raw_os_error(libc::ENOENT)).to_string()
+        );
+    }
+
+    #[test]
+    fn test_seqpacket() {
+        let path = temp_socket_path();
+        // The standard library has no SOCK_SEQPACKET listener.
+        let listener_fd =
+            unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        assert!(listener_fd >= 0);
+        let listener = unsafe { File::from_raw_fd(listener_fd) };
+        let addr = socket_addr(&path).unwrap();
+        let addr_len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        assert_eq!(
+            unsafe {
+                libc::bind(
+                    listener_fd,
+                    (&addr as *const libc::sockaddr_un).cast(),
+                    addr_len,
+                )
+            },
+            0
+        );
+        assert_eq!(unsafe { libc::listen(listener_fd, 1) }, 0);
+
+        let mut socket = UnixSocket::connect(&path).unwrap();
+        let peer_fd = unsafe {
+            libc::accept4(
+                listener.as_raw_fd(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        assert!(peer_fd >= 0);
+        let mut peer = unsafe { File::from_raw_fd(peer_fd) };
+
+        // Nothing to read yet.
+        let mut buf = [0xffu8; vnet_hdr_len() + FRAME_SIZE];
+        assert_eq!(
+            socket.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        // The frame is read after an empty VNET header.
+        let frame = utils::rand::rand_alphanumerics(FRAME_SIZE);
+        peer.write_all(frame.as_bytes()).unwrap();
+        assert_eq!(socket.read(&mut buf).unwrap(), vnet_hdr_len() + FRAME_SIZE);
+        assert_eq!(&buf[..vnet_hdr_len()], &[0u8; vnet_hdr_len()]);
+        assert_eq!(&buf[vnet_hdr_len()..], frame.as_bytes());
+
+        // The VNET header is dropped, even when split across slices.
+        let hdr = [0u8; vnet_hdr_len()];
+        let bufs = [
+            IoSlice::new(&hdr[..2]),
+            IoSlice::new(&hdr[2..]),
+            IoSlice::new(&frame.as_bytes()[..100]),
+            IoSlice::new(&frame.as_bytes()[100..]),
+        ];
+        assert_eq!(
+            socket.write_vectored(&bufs).unwrap(),
+            vnet_hdr_len() + FRAME_SIZE
+        );
+        let mut peer_buf = [0u8; 2 * FRAME_SIZE];
+        assert_eq!(peer.read(&mut peer_buf).unwrap(), FRAME_SIZE);
+        assert_eq!(&peer_buf[..FRAME_SIZE], frame.as_bytes());
+
+        // Frames without a VNET header are rejected.
+        assert_eq!(
+            socket.write(&hdr[1..]).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // Reading fails once the peer is gone.
+        drop(peer);
+        assert_eq!(
+            socket.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_dgram() {
+        let path = temp_socket_path();
+        let peer = UnixDatagram::bind(&path).unwrap();
+
+        let mut socket = UnixSocket::connect(&path).unwrap();
+        let frame = utils::rand::rand_alphanumerics(FRAME_SIZE);
+        let mut packet = vec![0u8; vnet_hdr_len()];
+        packet.extend_from_slice(frame.as_bytes());
+        assert_eq!(socket.write(&packet).unwrap(), packet.len());
+
+        // The peer replies to the address picked by the kernel.
+        let mut peer_buf = [0u8; 2 * FRAME_SIZE];
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        let len = unsafe {
+            libc::recvfrom(
+                peer.as_raw_fd(),
+                peer_buf.as_mut_ptr().cast(),
+                peer_buf.len(),
+                0,
+                (&mut addr as *mut libc::sockaddr_un).cast(),
+                &mut addr_len,
+            )
+        };
+        assert_eq!(len, FRAME_SIZE as isize);
+        assert_eq!(&peer_buf[..FRAME_SIZE], frame.as_bytes());
+        let len = unsafe {
+            libc::sendto(
+                peer.as_raw_fd(),
+                peer_buf.as_ptr().cast(),
+                FRAME_SIZE,
+                0,
+                (&addr as *const libc::sockaddr_un).cast(),
+                addr_len,
+            )
+        };
+        assert_eq!(len, FRAME_SIZE as isize);
+
+        let mut buf = [0xffu8; vnet_hdr_len() + FRAME_SIZE];
+        assert_eq!(socket.read(&mut buf).unwrap(), packet.len());
+        assert_eq!(&buf[..], &packet[..]);
+    }
+}
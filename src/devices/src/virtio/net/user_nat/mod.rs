@@ -0,0 +1,1741 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A user-mode NAT backend, which needs neither a tap device nor any privilege on the host.
+//!
+//! The backend impersonates the gateway of a private subnet. The TCP connections and the UDP
+//! flows the guest opens through it are terminated by the backend, and proxied through ordinary
+//! host sockets. The TCP and UDP ports configured as port forwards are listened on, and what
+//! they receive is proxied to the guest, appearing to come from the gateway. The guest gets its
+//! address from the DHCP server of the device. All the other traffic, such as ICMP, is dropped.
+
+mod tcp;
+mod udp;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Error as IoError, ErrorKind, IoSlice, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::num::NonZeroU16;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use std::{fmt, result};
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, PAYLOAD_OFFSET};
+use dumbo::pdu::ipv4::{IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP};
+use dumbo::pdu::tcp::{Flags as TcpFlags, TcpSegment};
+use dumbo::pdu::udp::{UdpDatagram, UDP_HEADER_SIZE};
+use logger::{error, warn, IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+use utils::rand::xor_pseudo_rng_u32;
+
+use self::tcp::{GuestSegment, Segment, TcpFlow};
+use self::udp::{UdpFlow, UdpSource};
+use crate::virtio::net::backend::NetBackend;
+use crate::virtio::net::device::vnet_hdr_len;
+use crate::virtio::net::dhcp::{DhcpConfig, DEFAULT_LEASE_TIME_SECS};
+use crate::virtio::net::MAX_BUFFER_SIZE;
+
+/// The MAC address of the gateway of the guest.
+pub const GATEWAY_MAC: [u8; MAC_ADDR_LEN] = [0x06, 0x01, 0x23, 0x45, 0x67, 0x03];
+
+// The default subnet, which is the one of the QEMU user networking.
+const DEFAULT_NETWORK: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 0);
+const DEFAULT_PREFIX_LEN: u8 = 24;
+// The host numbers of the gateway and of the guest in the subnet.
+const GATEWAY_HOST: u32 = 2;
+const GUEST_HOST: u32 = 15;
+// The shortest prefix allowed, and the longest one leaving room for the guest address.
+const MIN_PREFIX_LEN: u8 = 8;
+const MAX_PREFIX_LEN: u8 = 27;
+
+// The length of an IPv4 header without options.
+const IPV4_HEADER_LEN: usize = 20;
+// The length of a TCP header with the MSS option.
+const TCP_MAX_HEADER_LEN: usize = 24;
+// The TTL of the packets sent to the guest.
+const IPV4_TTL: u8 = 64;
+// The flag set on all the fragments of a packet but the last one.
+const IPV4_MORE_FRAGMENTS: u8 = 1;
+// The largest payload of a UDP datagram carried by an IPv4 packet.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+// The largest frame the device receives.
+const MAX_FRAME_LEN: usize = MAX_BUFFER_SIZE - vnet_hdr_len();
+
+// The most flows of each protocol.
+const MAX_TCP_FLOWS: usize = 1024;
+const MAX_UDP_FLOWS: usize = 1024;
+// The most frames queued for the guest before the host sockets stop being read.
+const MAX_QUEUED_FRAMES: usize = 1024;
+// The first port the connections and datagrams of the port forwards appear to come from.
+const FIRST_INBOUND_PORT: u16 = 49152;
+
+// How often the flows are checked for retransmissions and expiry, while there are some.
+const TIMER_INTERVAL: Duration = Duration::from_millis(500);
+// The most events handled at once, and the most rounds of events handled by a read.
+const MAX_EVENTS: usize = 32;
+const MAX_EVENT_ROUNDS: usize = 16;
+
+// Tokens of the events of the internal epoll, after which come the ones of the sockets.
+const FRAMES_TOKEN: u64 = 0;
+const TIMER_TOKEN: u64 = 1;
+const FIRST_SOCKET_TOKEN: u64 = 2;
+
+/// List of errors the user-mode NAT can throw.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The prefix length of the subnet is not supported
+    #[error(
+        "The prefix length of the subnet must be between {MIN_PREFIX_LEN} and {MAX_PREFIX_LEN}: \
+         {0}"
+    )]
+    InvalidPrefixLen(u8),
+    /// The address of the subnet has host bits set
+    #[error("The address of the subnet has host bits set: {0}/{1}")]
+    InvalidNetwork(Ipv4Addr, u8),
+    /// A port of a port forward is 0
+    #[error("The ports of a port forward can't be 0")]
+    InvalidPort,
+    /// Couldn't listen on the host address of a port forward
+    #[error("Couldn't listen on {0}: {1}")]
+    Listen(SocketAddrV4, IoError),
+    /// Couldn't set up the events of the backend
+    #[error("Couldn't set up the events of the backend: {0}")]
+    Epoll(IoError),
+    /// Couldn't create the event signaling the frames for the guest
+    #[error("Couldn't create the event signaling the frames for the guest: {0}")]
+    EventFd(IoError),
+    /// Couldn't create the timer of the flows
+    #[error("Couldn't create the timer of the flows: {0}")]
+    Timer(IoError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The transport protocol of a port forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ForwardProtocol {
+    /// Forward the TCP connections.
+    Tcp,
+    /// Forward the UDP datagrams.
+    Udp,
+}
+
+impl Default for ForwardProtocol {
+    fn default() -> Self {
+        ForwardProtocol::Tcp
+    }
+}
+
+fn default_host_address() -> Ipv4Addr {
+    Ipv4Addr::LOCALHOST
+}
+
+/// A host port whose traffic is forwarded to a port of the guest.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortForward {
+    /// The protocol of the forwarded traffic. Defaults to TCP.
+    #[serde(default)]
+    pub protocol: ForwardProtocol,
+    /// The host address listened on. Defaults to `127.0.0.1`.
+    #[serde(default = "default_host_address")]
+    pub host_address: Ipv4Addr,
+    /// The host port listened on.
+    pub host_port: u16,
+    /// The port of the guest the traffic is forwarded to.
+    pub guest_port: u16,
+}
+
+impl PortForward {
+    fn host_socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.host_address, self.host_port)
+    }
+}
+
+fn default_network() -> Ipv4Addr {
+    DEFAULT_NETWORK
+}
+
+fn default_prefix_len() -> u8 {
+    DEFAULT_PREFIX_LEN
+}
+
+/// The configuration of the user-mode NAT backend of a network device.
+///
+/// The gateway takes the second address of the subnet, and the guest the fifteenth one.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserNatConfig {
+    /// The address of the subnet of the guest. Defaults to `10.0.2.0`.
+    #[serde(default = "default_network")]
+    pub network: Ipv4Addr,
+    /// The length of the prefix of the subnet. Defaults to 24.
+    #[serde(default = "default_prefix_len")]
+    pub prefix_len: u8,
+    /// The DNS servers handed to the guest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// Whether the traffic of the guest to the gateway reaches the loopback interface of the
+    /// host. Defaults to false.
+    #[serde(default)]
+    pub allow_host_loopback: bool,
+    /// The host ports forwarded to the guest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_forwards: Vec<PortForward>,
+}
+
+impl Default for UserNatConfig {
+    fn default() -> Self {
+        UserNatConfig {
+            network: DEFAULT_NETWORK,
+            prefix_len: DEFAULT_PREFIX_LEN,
+            dns_servers: Vec::new(),
+            allow_host_loopback: false,
+            port_forwards: Vec::new(),
+        }
+    }
+}
+
+impl UserNatConfig {
+    /// Checks that the subnet and the port forwards can be used.
+    pub fn validate(&self) -> Result<()> {
+        if !(MIN_PREFIX_LEN..=MAX_PREFIX_LEN).contains(&self.prefix_len) {
+            return Err(Error::InvalidPrefixLen(self.prefix_len));
+        }
+        if u32::from(self.network) & !self.netmask() != 0 {
+            return Err(Error::InvalidNetwork(self.network, self.prefix_len));
+        }
+        if self
+            .port_forwards
+            .iter()
+            .any(|forward| forward.host_port == 0 || forward.guest_port == 0)
+        {
+            return Err(Error::InvalidPort);
+        }
+        Ok(())
+    }
+
+    /// The address of the gateway of the guest.
+    pub fn gateway_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + GATEWAY_HOST)
+    }
+
+    /// The address leased to the guest.
+    pub fn guest_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + GUEST_HOST)
+    }
+
+    /// The lease the DHCP server of the device hands to the guest, unless configured otherwise.
+    pub fn default_lease(&self) -> DhcpConfig {
+        DhcpConfig {
+            address: self.guest_address(),
+            prefix_len: self.prefix_len,
+            gateway: Some(self.gateway_address()),
+            dns_servers: self.dns_servers.clone(),
+            mtu: None,
+            lease_time_secs: DEFAULT_LEASE_TIME_SECS,
+            server_address: None,
+        }
+    }
+
+    fn netmask(&self) -> u32 {
+        u32::MAX << (32 - u32::from(self.prefix_len))
+    }
+
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.netmask() == u32::from(self.network)
+    }
+}
+
+// The endpoints of a flow, as seen by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FlowKey {
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+// Picks the port of the gateway a new inbound flow to `guest` comes from, among the ones not
+// used by `flows`.
+fn inbound_flow_key<V>(
+    next_port: &mut u16,
+    guest: SocketAddrV4,
+    gateway_addr: Ipv4Addr,
+    flows: &HashMap<FlowKey, V>,
+) -> FlowKey {
+    loop {
+        let port = *next_port;
+        *next_port = port.checked_add(1).unwrap_or(FIRST_INBOUND_PORT);
+        let key = FlowKey {
+            guest,
+            remote: SocketAddrV4::new(gateway_addr, port),
+        };
+        // There are more ports than flows.
+        if !flows.contains_key(&key) {
+            return key;
+        }
+    }
+}
+
+// What the events of a socket are about.
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    Forward(usize),
+    Tcp(FlowKey),
+    Udp(FlowKey),
+}
+
+#[derive(Debug)]
+enum Forward {
+    Tcp {
+        listener: TcpListener,
+        guest_port: u16,
+    },
+    Udp {
+        socket: UdpSocket,
+        guest_port: u16,
+    },
+}
+
+impl Forward {
+    fn open(config: &PortForward) -> io::Result<Self> {
+        let addr = config.host_socket_addr();
+        Ok(match config.protocol {
+            ForwardProtocol::Tcp => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Forward::Tcp {
+                    listener,
+                    guest_port: config.guest_port,
+                }
+            }
+            ForwardProtocol::Udp => {
+                let socket = UdpSocket::bind(addr)?;
+                socket.set_nonblocking(true)?;
+                Forward::Udp {
+                    socket,
+                    guest_port: config.guest_port,
+                }
+            }
+        })
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Forward::Tcp { listener, .. } => listener.as_raw_fd(),
+            Forward::Udp { socket, .. } => socket.as_raw_fd(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TcpEntry {
+    flow: TcpFlow,
+    token: u64,
+    // The events the host socket is registered for, none meaning it is not registered.
+    evset: EventSet,
+}
+
+#[derive(Debug)]
+struct UdpEntry {
+    flow: UdpFlow,
+    // The token of the host socket of the flows opened by the guest.
+    token: Option<u64>,
+}
+
+/// A user-mode NAT, exchanging the traffic of the guest with the host through ordinary sockets.
+///
+/// Its file descriptor is an internal epoll, which reports the events of the host sockets, of
+/// the timer of the flows, and of the frames queued for the guest while handling its frames.
+pub struct UserNat {
+    config: UserNatConfig,
+    gateway_addr: Ipv4Addr,
+    guest_addr: Ipv4Addr,
+    // The MAC address of the guest, which is learned from its frames.
+    guest_mac: Option<MacAddr>,
+    epoll: Epoll,
+    frames_evt: EventFd,
+    timer: TimerFd,
+    timer_armed: bool,
+    frames: VecDeque<Vec<u8>>,
+    forwards: Vec<Forward>,
+    sources: HashMap<u64, Source>,
+    next_token: u64,
+    tcp_flows: HashMap<FlowKey, TcpEntry>,
+    udp_flows: HashMap<FlowKey, UdpEntry>,
+    // The UDP flows of the port forwards, by forward and host peer.
+    udp_peers: HashMap<(usize, SocketAddrV4), FlowKey>,
+    next_inbound_port: u16,
+}
+
+impl fmt::Debug for UserNat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserNat")
+            .field("config", &self.config)
+            .field("tcp_flows", &self.tcp_flows.len())
+            .field("udp_flows", &self.udp_flows.len())
+            .finish()
+    }
+}
+
+impl UserNat {
+    /// Creates the NAT described by `config`, listening on the host ports forwarded to the
+    /// guest. Until the guest sends a frame, the frames for it are sent to `guest_mac`, or
+    /// broadcast if unknown.
+    pub fn new(config: &UserNatConfig, guest_mac: Option<MacAddr>) -> Result<Self> {
+        config.validate()?;
+
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        let frames_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let timer = TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?;
+        for (fd, token) in [
+            (frames_evt.as_raw_fd(), FRAMES_TOKEN),
+            (timer.as_raw_fd(), TIMER_TOKEN),
+        ] {
+            epoll
+                .ctl(
+                    ControlOperation::Add,
+                    fd,
+                    EpollEvent::new(EventSet::IN, token),
+                )
+                .map_err(Error::Epoll)?;
+        }
+
+        let mut nat = UserNat {
+            config: config.clone(),
+            gateway_addr: config.gateway_address(),
+            guest_addr: config.guest_address(),
+            guest_mac,
+            epoll,
+            frames_evt,
+            timer,
+            timer_armed: false,
+            frames: VecDeque::new(),
+            forwards: Vec::new(),
+            sources: HashMap::new(),
+            next_token: FIRST_SOCKET_TOKEN,
+            tcp_flows: HashMap::new(),
+            udp_flows: HashMap::new(),
+            udp_peers: HashMap::new(),
+            next_inbound_port: FIRST_INBOUND_PORT,
+        };
+
+        for forward_config in &config.port_forwards {
+            let forward = Forward::open(forward_config)
+                .map_err(|err| Error::Listen(forward_config.host_socket_addr(), err))?;
+            let token = nat.new_token();
+            nat.epoll
+                .ctl(
+                    ControlOperation::Add,
+                    forward.as_raw_fd(),
+                    EpollEvent::new(EventSet::IN, token),
+                )
+                .map_err(Error::Epoll)?;
+            nat.sources
+                .insert(token, Source::Forward(nat.forwards.len()));
+            nat.forwards.push(forward);
+        }
+
+        Ok(nat)
+    }
+
+    fn new_token(&mut self) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        token
+    }
+
+    fn guest_mac(&self) -> MacAddr {
+        self.guest_mac
+            .unwrap_or_else(|| MacAddr::from([0xff; MAC_ADDR_LEN]))
+    }
+
+    // Handles the pending events of the host sockets and of the timer, which may queue frames
+    // for the guest. Returns whether there were any.
+    fn process_events(&mut self) -> bool {
+        let mut events = [EpollEvent::default(); MAX_EVENTS];
+        let count = match self.epoll.wait(0, &mut events) {
+            Ok(count) => count,
+            Err(err) => {
+                if err.kind() != ErrorKind::Interrupted {
+                    error!("Failed to consume the user NAT events: {}", err);
+                    METRICS.net.event_fails.inc();
+                }
+                return false;
+            }
+        };
+
+        let now = Instant::now();
+        for event in &events[..count] {
+            match event.data() {
+                FRAMES_TOKEN => {
+                    if let Err(err) = self.frames_evt.read() {
+                        if err.kind() != ErrorKind::WouldBlock {
+                            error!("Failed to read the user NAT frames event: {}", err);
+                        }
+                    }
+                }
+                TIMER_TOKEN => {
+                    self.timer.read();
+                    self.on_timer(now);
+                }
+                token => match self.sources.get(&token).copied() {
+                    Some(Source::Forward(index)) => self.on_forward_readable(index, now),
+                    Some(Source::Tcp(key)) => self.on_tcp_event(key, event.event_set(), now),
+                    Some(Source::Udp(key)) => self.on_udp_readable(key, now),
+                    // The source was dropped while handling the previous events.
+                    None => (),
+                },
+            }
+        }
+        self.update_timer();
+
+        count > 0
+    }
+
+    // Arms the timer while some flow needs it.
+    fn update_timer(&mut self) {
+        let needed = !self.udp_flows.is_empty()
+            || self
+                .tcp_flows
+                .values()
+                .any(|entry| entry.flow.is_retransmitting());
+        if needed != self.timer_armed {
+            let state = if needed {
+                TimerState::Periodic {
+                    current: TIMER_INTERVAL,
+                    interval: TIMER_INTERVAL,
+                }
+            } else {
+                TimerState::Disarmed
+            };
+            self.timer.set_state(state, SetTimeFlags::Default);
+            self.timer_armed = needed;
+        }
+    }
+
+    fn on_timer(&mut self, now: Instant) {
+        let retransmitting: Vec<FlowKey> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, entry)| entry.flow.is_retransmitting())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in retransmitting {
+            let mut out = Vec::new();
+            if let Some(entry) = self.tcp_flows.get_mut(&key) {
+                entry.flow.on_timer(now, &mut out);
+            }
+            self.finish_tcp(key, out);
+        }
+
+        let expired: Vec<FlowKey> = self
+            .udp_flows
+            .iter()
+            .filter(|(_, entry)| entry.flow.is_expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            if let Some(entry) = self.udp_flows.remove(&key) {
+                if let Some(token) = entry.token {
+                    self.sources.remove(&token);
+                }
+                if let UdpSource::Forward(index, peer) = entry.flow.source() {
+                    self.udp_peers.remove(&(*index, *peer));
+                }
+            }
+        }
+    }
+
+    // The host address the traffic of the guest to `addr` is sent to, unless it must not leave
+    // the NAT.
+    fn host_address(&self, addr: Ipv4Addr) -> Option<Ipv4Addr> {
+        if addr == self.gateway_addr {
+            return if self.config.allow_host_loopback {
+                Some(Ipv4Addr::LOCALHOST)
+            } else {
+                None
+            };
+        }
+        if self.config.contains(addr)
+            || addr.is_loopback()
+            || addr.is_link_local()
+            || addr.is_multicast()
+            || addr.is_broadcast()
+            || addr.is_unspecified()
+        {
+            return None;
+        }
+        Some(addr)
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let eth = match EthernetFrame::from_bytes(frame) {
+            Ok(eth) => eth,
+            Err(_) => {
+                METRICS.net.nat_dropped_frames.inc();
+                return;
+            }
+        };
+        self.guest_mac = Some(eth.src_mac());
+
+        let handled = match eth.ethertype() {
+            ETHERTYPE_ARP => self.handle_arp(eth.payload()),
+            ETHERTYPE_IPV4 => self.handle_ipv4(eth.payload()),
+            _ => false,
+        };
+        if !handled {
+            METRICS.net.nat_dropped_frames.inc();
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8]) -> bool {
+        // The payload may be padded.
+        let request = match payload
+            .get(..ETH_IPV4_FRAME_LEN)
+            .and_then(|bytes| EthIPv4ArpFrame::request_from_bytes(bytes).ok())
+        {
+            Some(request) if request.tpa() == self.gateway_addr => request,
+            _ => return false,
+        };
+
+        let mut frame = vec![0u8; PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN];
+        let gateway_mac = MacAddr::from(GATEWAY_MAC);
+        let mut eth = match EthernetFrame::write_incomplete(
+            frame.as_mut_slice(),
+            request.sha(),
+            gateway_mac,
+            ETHERTYPE_ARP,
+        ) {
+            Ok(eth) => eth,
+            Err(_) => return false,
+        };
+        if EthIPv4ArpFrame::write_reply(
+            eth.inner_mut().payload_mut(),
+            gateway_mac,
+            self.gateway_addr,
+            request.sha(),
+            request.spa(),
+        )
+        .is_err()
+        {
+            return false;
+        }
+        self.frames.push_back(frame);
+        true
+    }
+
+    fn handle_ipv4(&mut self, payload: &[u8]) -> bool {
+        if payload.len() < IPV4_HEADER_LEN {
+            return false;
+        }
+        // The payload may be padded.
+        let total_len = usize::from(IPv4Packet::from_bytes_unchecked(payload).total_len());
+        let packet = match payload
+            .get(..total_len)
+            .and_then(|bytes| IPv4Packet::from_bytes(bytes, true).ok())
+        {
+            Some(packet) => packet,
+            None => return false,
+        };
+        // Fragments are not reassembled.
+        let (flags, fragment_offset) = packet.flags_and_fragment_offset();
+        if flags & IPV4_MORE_FRAGMENTS != 0 || fragment_offset != 0 {
+            return false;
+        }
+
+        let src = packet.source_address();
+        let dst = packet.destination_address();
+        match packet.protocol() {
+            PROTOCOL_TCP => self.handle_tcp(src, dst, packet.payload()),
+            PROTOCOL_UDP => self.handle_udp(src, dst, packet.payload()),
+            _ => false,
+        }
+    }
+
+    fn handle_tcp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> bool {
+        let segment = match TcpSegment::from_bytes(payload, Some((src, dst))) {
+            Ok(segment) => segment,
+            Err(_) => return false,
+        };
+        let mss = segment
+            .parse_mss_option_unchecked(segment.header_len())
+            .ok()
+            .flatten()
+            .map(NonZeroU16::get);
+        let guest_segment = GuestSegment {
+            seq: segment.sequence_number(),
+            ack: segment.ack_number(),
+            flags: segment.flags_after_ns(),
+            window: segment.window_size(),
+            mss,
+            payload: segment.payload(),
+        };
+        let key = FlowKey {
+            guest: SocketAddrV4::new(src, segment.source_port()),
+            remote: SocketAddrV4::new(dst, segment.destination_port()),
+        };
+
+        let now = Instant::now();
+        let mut out = Vec::new();
+        if let Some(entry) = self.tcp_flows.get_mut(&key) {
+            entry.flow.on_guest_segment(&guest_segment, now, &mut out);
+        } else if guest_segment.flags & (TcpFlags::SYN | TcpFlags::ACK | TcpFlags::RST)
+            == TcpFlags::SYN
+        {
+            if !self.open_tcp_flow(key, &guest_segment) {
+                // The guest sees the connection refused.
+                out.push(tcp::reset_for(&guest_segment));
+            }
+        } else if !guest_segment.flags.contains(TcpFlags::RST) {
+            out.push(tcp::reset_for(&guest_segment));
+        }
+        self.finish_tcp(key, out);
+        true
+    }
+
+    fn open_tcp_flow(&mut self, key: FlowKey, syn: &GuestSegment) -> bool {
+        let host_addr = match self.host_address(*key.remote.ip()) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        if self.tcp_flows.len() >= MAX_TCP_FLOWS {
+            METRICS.net.nat_flow_fails.inc();
+            return false;
+        }
+        let stream = match tcp::connect(SocketAddrV4::new(host_addr, key.remote.port())) {
+            Ok(stream) => stream,
+            Err(_) => {
+                METRICS.net.nat_flow_fails.inc();
+                return false;
+            }
+        };
+
+        let flow = TcpFlow::new_outbound(stream, syn, xor_pseudo_rng_u32());
+        self.insert_tcp_flow(key, flow);
+        true
+    }
+
+    fn insert_tcp_flow(&mut self, key: FlowKey, flow: TcpFlow) {
+        let token = self.new_token();
+        self.sources.insert(token, Source::Tcp(key));
+        self.tcp_flows.insert(
+            key,
+            TcpEntry {
+                flow,
+                token,
+                evset: EventSet::empty(),
+            },
+        );
+        METRICS.net.nat_flows_created.inc();
+    }
+
+    fn on_tcp_event(&mut self, key: FlowKey, evset: EventSet, now: Instant) {
+        let mut out = Vec::new();
+        if let Some(entry) = self.tcp_flows.get_mut(&key) {
+            // Errors and hang ups are noticed when writing or reading.
+            if evset.intersects(EventSet::OUT | EventSet::ERROR | EventSet::HANG_UP) {
+                entry.flow.on_host_writable(now, &mut out);
+            }
+            if !(evset - EventSet::OUT).is_empty() {
+                entry.flow.on_host_readable(now, &mut out);
+            }
+        }
+        self.finish_tcp(key, out);
+    }
+
+    // Queues the segments of the flow for the guest, and updates the events its host socket is
+    // polled for, dropping the flow once closed.
+    fn finish_tcp(&mut self, key: FlowKey, segments: Vec<Segment>) {
+        for segment in &segments {
+            self.queue_tcp_frame(&key, segment);
+        }
+
+        let entry = match self.tcp_flows.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.flow.is_closed() {
+            // Closing the socket removes it from the epoll.
+            self.sources.remove(&entry.token);
+            self.tcp_flows.remove(&key);
+            return;
+        }
+
+        let evset = entry.flow.polled_evset();
+        if evset == entry.evset {
+            return;
+        }
+        let fd = entry.flow.stream().as_raw_fd();
+        let result = if evset.is_empty() {
+            self.epoll
+                .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+        } else {
+            let operation = if entry.evset.is_empty() {
+                ControlOperation::Add
+            } else {
+                ControlOperation::Modify
+            };
+            self.epoll
+                .ctl(operation, fd, EpollEvent::new(evset, entry.token))
+        };
+        match result {
+            Ok(()) => entry.evset = evset,
+            Err(err) => {
+                error!("Failed to poll the socket of a user NAT flow: {}", err);
+                METRICS.net.event_fails.inc();
+                let mut out = Vec::new();
+                entry.flow.reset(&mut out);
+                self.finish_tcp(key, out);
+            }
+        }
+    }
+
+    fn on_forward_readable(&mut self, index: usize, now: Instant) {
+        match self.forwards[index] {
+            Forward::Tcp { .. } => self.accept_forwarded_connections(index, now),
+            Forward::Udp { .. } => self.receive_forwarded_datagrams(index, now),
+        }
+    }
+
+    fn accept_forwarded_connections(&mut self, index: usize, now: Instant) {
+        loop {
+            let (stream, guest_port) = match &self.forwards[index] {
+                Forward::Tcp {
+                    listener,
+                    guest_port,
+                } => match listener.accept() {
+                    Ok((stream, _)) => (stream, *guest_port),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                    Err(err) => {
+                        warn!("Failed to accept a forwarded connection: {}", err);
+                        METRICS.net.nat_flow_fails.inc();
+                        return;
+                    }
+                },
+                Forward::Udp { .. } => return,
+            };
+            // Dropping the stream refuses the connection.
+            if self.tcp_flows.len() >= MAX_TCP_FLOWS || stream.set_nonblocking(true).is_err() {
+                METRICS.net.nat_flow_fails.inc();
+                continue;
+            }
+
+            let key = inbound_flow_key(
+                &mut self.next_inbound_port,
+                SocketAddrV4::new(self.guest_addr, guest_port),
+                self.gateway_addr,
+                &self.tcp_flows,
+            );
+            let mut out = Vec::new();
+            let flow = TcpFlow::new_inbound(stream, xor_pseudo_rng_u32(), now, &mut out);
+            self.insert_tcp_flow(key, flow);
+            self.finish_tcp(key, out);
+        }
+    }
+
+    fn receive_forwarded_datagrams(&mut self, index: usize, now: Instant) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while self.frames.len() < MAX_QUEUED_FRAMES {
+            let (len, peer, guest_port) = match &self.forwards[index] {
+                Forward::Udp { socket, guest_port } => match socket.recv_from(&mut buf) {
+                    Ok((len, SocketAddr::V4(peer))) => (len, peer, *guest_port),
+                    // The socket is bound to an IPv4 address.
+                    Ok((_, SocketAddr::V6(_))) => continue,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => return,
+                },
+                Forward::Tcp { .. } => return,
+            };
+
+            let key = match self.udp_peers.get(&(index, peer)) {
+                Some(key) => *key,
+                None => {
+                    if self.udp_flows.len() >= MAX_UDP_FLOWS {
+                        METRICS.net.nat_flow_fails.inc();
+                        continue;
+                    }
+                    let key = inbound_flow_key(
+                        &mut self.next_inbound_port,
+                        SocketAddrV4::new(self.guest_addr, guest_port),
+                        self.gateway_addr,
+                        &self.udp_flows,
+                    );
+                    self.udp_flows.insert(
+                        key,
+                        UdpEntry {
+                            flow: UdpFlow::forwarded(index, peer, now),
+                            token: None,
+                        },
+                    );
+                    self.udp_peers.insert((index, peer), key);
+                    METRICS.net.nat_flows_created.inc();
+                    key
+                }
+            };
+            if let Some(entry) = self.udp_flows.get_mut(&key) {
+                entry.flow.touch(now);
+            }
+            self.queue_udp_frame(&key, &buf[..len]);
+        }
+    }
+
+    fn handle_udp(&mut self, src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> bool {
+        let datagram = match UdpDatagram::from_bytes(payload, Some((src, dst))) {
+            Ok(datagram) if usize::from(datagram.len()) == payload.len() => datagram,
+            _ => return false,
+        };
+        let key = FlowKey {
+            guest: SocketAddrV4::new(src, datagram.source_port()),
+            remote: SocketAddrV4::new(dst, datagram.destination_port()),
+        };
+        let now = Instant::now();
+        if !self.udp_flows.contains_key(&key) && !self.open_udp_flow(key, now) {
+            return false;
+        }
+        let entry = match self.udp_flows.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        entry.flow.touch(now);
+
+        let result = match entry.flow.source() {
+            UdpSource::Connected(socket) => socket.send(datagram.payload()),
+            UdpSource::Forward(index, peer) => match &self.forwards[*index] {
+                Forward::Udp { socket, .. } => socket.send_to(datagram.payload(), peer),
+                Forward::Tcp { .. } => Err(IoError::from(ErrorKind::InvalidInput)),
+            },
+        };
+        // Like a router, the NAT drops what it can't send right away.
+        result.is_ok()
+    }
+
+    fn open_udp_flow(&mut self, key: FlowKey, now: Instant) -> bool {
+        let host_addr = match self.host_address(*key.remote.ip()) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        if self.udp_flows.len() >= MAX_UDP_FLOWS {
+            METRICS.net.nat_flow_fails.inc();
+            return false;
+        }
+        let flow = match UdpFlow::connect(SocketAddrV4::new(host_addr, key.remote.port()), now) {
+            Ok(flow) => flow,
+            Err(_) => {
+                METRICS.net.nat_flow_fails.inc();
+                return false;
+            }
+        };
+        let token = self.new_token();
+        if let UdpSource::Connected(socket) = flow.source() {
+            if let Err(err) = self.epoll.ctl(
+                ControlOperation::Add,
+                socket.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, token),
+            ) {
+                error!("Failed to poll the socket of a user NAT flow: {}", err);
+                METRICS.net.event_fails.inc();
+                return false;
+            }
+        }
+
+        self.sources.insert(token, Source::Udp(key));
+        self.udp_flows.insert(
+            key,
+            UdpEntry {
+                flow,
+                token: Some(token),
+            },
+        );
+        METRICS.net.nat_flows_created.inc();
+        true
+    }
+
+    fn on_udp_readable(&mut self, key: FlowKey, now: Instant) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        while self.frames.len() < MAX_QUEUED_FRAMES {
+            let entry = match self.udp_flows.get_mut(&key) {
+                Some(entry) => entry,
+                None => return,
+            };
+            let len = match entry.flow.source() {
+                UdpSource::Connected(socket) => match socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    // The errors reported by the remote peer are dropped as well.
+                    Err(_) => return,
+                },
+                UdpSource::Forward(..) => return,
+            };
+            entry.flow.touch(now);
+            self.queue_udp_frame(&key, &buf[..len]);
+        }
+    }
+
+    // Queues a frame carrying an IPv4 packet from `src` to `dst` for the guest, with a payload of
+    // at most `max_payload_len` bytes written by `write_payload`.
+    fn queue_ipv4_frame<F>(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+        max_payload_len: usize,
+        write_payload: F,
+    ) where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        let max_len = PAYLOAD_OFFSET + IPV4_HEADER_LEN + max_payload_len;
+        if max_len > MAX_FRAME_LEN {
+            METRICS.net.nat_rx_dropped_frames.inc();
+            return;
+        }
+        let mut frame = vec![0u8; max_len];
+        match write_ipv4_frame(
+            &mut frame,
+            self.guest_mac(),
+            src,
+            dst,
+            protocol,
+            write_payload,
+        ) {
+            Some(len) => {
+                frame.truncate(len);
+                self.frames.push_back(frame);
+            }
+            None => {
+                error!("Failed to build a user NAT frame");
+                METRICS.net.event_fails.inc();
+            }
+        }
+    }
+
+    fn queue_tcp_frame(&mut self, key: &FlowKey, segment: &Segment) {
+        let (src, dst) = (key.remote, key.guest);
+        self.queue_ipv4_frame(
+            *src.ip(),
+            *dst.ip(),
+            PROTOCOL_TCP,
+            TCP_MAX_HEADER_LEN + segment.payload.len(),
+            |buf| {
+                let payload = if segment.payload.is_empty() {
+                    None
+                } else {
+                    Some((segment.payload.as_slice(), segment.payload.len()))
+                };
+                let len = TcpSegment::write_incomplete_segment::<[u8]>(
+                    buf,
+                    segment.seq,
+                    segment.ack,
+                    segment.flags,
+                    segment.window,
+                    segment.mss,
+                    u16::MAX,
+                    payload,
+                )
+                .ok()?
+                .finalize(src.port(), dst.port(), Some((*src.ip(), *dst.ip())))
+                .len();
+                Some(len)
+            },
+        );
+    }
+
+    fn queue_udp_frame(&mut self, key: &FlowKey, payload: &[u8]) {
+        let (src, dst) = (key.remote, key.guest);
+        self.queue_ipv4_frame(
+            *src.ip(),
+            *dst.ip(),
+            PROTOCOL_UDP,
+            UDP_HEADER_SIZE + payload.len(),
+            |buf| {
+                let len = UdpDatagram::write_incomplete_datagram(buf, payload)
+                    .ok()?
+                    .finalize(src.port(), dst.port(), Some((*src.ip(), *dst.ip())))
+                    .len();
+                Some(usize::from(len))
+            },
+        );
+    }
+}
+
+// Writes a frame from the gateway to `guest_mac` to `buf`, carrying an IPv4 packet whose payload
+// is written by `write_payload`. Returns the length of the frame.
+fn write_ipv4_frame<F>(
+    buf: &mut [u8],
+    guest_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    write_payload: F,
+) -> Option<usize>
+where
+    F: FnOnce(&mut [u8]) -> Option<usize>,
+{
+    let mut eth =
+        EthernetFrame::write_incomplete(buf, guest_mac, MacAddr::from(GATEWAY_MAC), ETHERTYPE_IPV4)
+            .ok()?;
+    let mut packet =
+        IPv4Packet::write_header(eth.inner_mut().payload_mut(), protocol, src, dst).ok()?;
+    packet.inner_mut().set_ttl(IPV4_TTL);
+    let payload_len = write_payload(packet.inner_mut().payload_mut())?;
+    let packet_len = packet.with_payload_len_unchecked(payload_len, true).len();
+    Some(eth.with_payload_len_unchecked(packet_len).len())
+}
+
+impl Read for UserNat {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() < vnet_hdr_len() {
+            return Err(IoError::from(ErrorKind::InvalidInput));
+        }
+
+        // Handling the events doesn't always produce frames.
+        let mut rounds = 0;
+        while self.frames.is_empty() && self.process_events() {
+            rounds += 1;
+            if rounds == MAX_EVENT_ROUNDS {
+                // Come back to the remaining events later, rather than starving the device.
+                self.frames_evt.write(1)?;
+                break;
+            }
+        }
+
+        let frame = self
+            .frames
+            .front()
+            .ok_or_else(|| IoError::from(ErrorKind::WouldBlock))?;
+        let (vnet_hdr, frame_buf) = buf.split_at_mut(vnet_hdr_len());
+        // The frame stays queued, to be read into a large enough buffer.
+        if frame.len() > frame_buf.len() {
+            return Err(IoError::from(ErrorKind::InvalidInput));
+        }
+        let len = frame.len();
+        vnet_hdr.fill(0);
+        frame_buf[..len].copy_from_slice(frame);
+        self.frames.pop_front();
+
+        Ok(vnet_hdr_len() + len)
+    }
+}
+
+impl Write for UserNat {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let frame = buf
+            .get(vnet_hdr_len()..)
+            .ok_or_else(|| IoError::from(ErrorKind::InvalidInput))?;
+
+        let queued = self.frames.len();
+        self.handle_frame(frame);
+        self.update_timer();
+        // The replies are read once the device is notified.
+        if self.frames.len() > queued {
+            self.frames_evt.write(1)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let buf: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.write(&buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserNat {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+impl NetBackend for UserNat {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    const GUEST_MAC: [u8; MAC_ADDR_LEN] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const GUEST_PORT: u16 = 40000;
+    const GUEST_ISS: u32 = 7000;
+
+    fn loopback_config() -> UserNatConfig {
+        UserNatConfig {
+            allow_host_loopback: true,
+            ..Default::default()
+        }
+    }
+
+    // Returns a local port nobody listens on.
+    fn free_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // Builds the frame the guest sends, with a VNET header, carrying a packet whose payload is
+    // written by `write_payload`.
+    fn guest_frame<F>(dst: Ipv4Addr, protocol: u8, write_payload: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut [u8], Ipv4Addr) -> usize,
+    {
+        let src = UserNatConfig::default().guest_address();
+        let mut buf = vec![0u8; vnet_hdr_len() + 2048];
+        let mut eth = EthernetFrame::write_incomplete(
+            &mut buf[vnet_hdr_len()..],
+            MacAddr::from(GATEWAY_MAC),
+            MacAddr::from(GUEST_MAC),
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let mut packet =
+            IPv4Packet::write_header(eth.inner_mut().payload_mut(), protocol, src, dst).unwrap();
+        let payload_len = write_payload(packet.inner_mut().payload_mut(), src);
+        let packet_len = packet.with_payload_len_unchecked(payload_len, true).len();
+        let len = eth.with_payload_len_unchecked(packet_len).len();
+        buf.truncate(vnet_hdr_len() + len);
+        buf
+    }
+
+    fn guest_tcp_frame(
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: TcpFlags,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        guest_frame(*dst.ip(), PROTOCOL_TCP, |buf, src| {
+            let payload = if payload.is_empty() {
+                None
+            } else {
+                Some((payload, payload.len()))
+            };
+            TcpSegment::write_incomplete_segment::<[u8]>(
+                buf,
+                seq,
+                ack,
+                flags,
+                8192,
+                Some(1460),
+                u16::MAX,
+                payload,
+            )
+            .unwrap()
+            .finalize(GUEST_PORT, dst.port(), Some((src, *dst.ip())))
+            .len()
+        })
+    }
+
+    fn guest_udp_frame(src_port: u16, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        guest_frame(*dst.ip(), PROTOCOL_UDP, |buf, src| {
+            usize::from(
+                UdpDatagram::write_incomplete_datagram(buf, payload)
+                    .unwrap()
+                    .finalize(src_port, dst.port(), Some((src, *dst.ip())))
+                    .len(),
+            )
+        })
+    }
+
+    // Reads the next frame for the guest, waiting for it if needed.
+    fn read_frame(nat: &mut UserNat) -> Vec<u8> {
+        let mut buf = vec![0u8; vnet_hdr_len() + 65536];
+        for _ in 0..50 {
+            match nat.read(&mut buf) {
+                Ok(len) => {
+                    assert_eq!(&buf[..vnet_hdr_len()], &[0u8; vnet_hdr_len()]);
+                    return buf[vnet_hdr_len()..len].to_vec();
+                }
+                Err(err) => assert_eq!(err.kind(), ErrorKind::WouldBlock),
+            }
+            let mut pollfd = libc::pollfd {
+                fd: nat.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: Safe because `pollfd` outlives the call.
+            unsafe { libc::poll(&mut pollfd, 1, 100) };
+        }
+        panic!("no frame for the guest");
+    }
+
+    fn assert_no_frame(nat: &mut UserNat) {
+        let mut buf = vec![0u8; vnet_hdr_len() + 65536];
+        assert_eq!(
+            nat.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+    }
+
+    struct TcpFrame {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: TcpFlags,
+        mss: Option<u16>,
+        payload: Vec<u8>,
+    }
+
+    fn parse_tcp_frame(frame: &[u8]) -> TcpFrame {
+        let eth = EthernetFrame::from_bytes(frame).unwrap();
+        assert_eq!(eth.src_mac(), MacAddr::from(GATEWAY_MAC));
+        assert_eq!(eth.dst_mac(), MacAddr::from(GUEST_MAC));
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.protocol(), PROTOCOL_TCP);
+        let (src, dst) = (packet.source_address(), packet.destination_address());
+        let segment = TcpSegment::from_bytes(packet.payload(), Some((src, dst))).unwrap();
+        TcpFrame {
+            src: SocketAddrV4::new(src, segment.source_port()),
+            dst: SocketAddrV4::new(dst, segment.destination_port()),
+            seq: segment.sequence_number(),
+            ack: segment.ack_number(),
+            flags: segment.flags_after_ns(),
+            mss: segment
+                .parse_mss_option_unchecked(segment.header_len())
+                .unwrap()
+                .map(NonZeroU16::get),
+            payload: segment.payload().to_vec(),
+        }
+    }
+
+    fn parse_udp_frame(frame: &[u8]) -> (SocketAddrV4, SocketAddrV4, Vec<u8>) {
+        let eth = EthernetFrame::from_bytes(frame).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.protocol(), PROTOCOL_UDP);
+        let (src, dst) = (packet.source_address(), packet.destination_address());
+        let datagram = UdpDatagram::from_bytes(packet.payload(), Some((src, dst))).unwrap();
+        (
+            SocketAddrV4::new(src, datagram.source_port()),
+            SocketAddrV4::new(dst, datagram.destination_port()),
+            datagram.payload().to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_config() {
+        let config = UserNatConfig::default();
+        config.validate().unwrap();
+        assert_eq!(config.gateway_address(), Ipv4Addr::new(10, 0, 2, 2));
+        assert_eq!(config.guest_address(), Ipv4Addr::new(10, 0, 2, 15));
+        assert!(config.contains(Ipv4Addr::new(10, 0, 2, 255)));
+        assert!(!config.contains(Ipv4Addr::new(10, 0, 3, 1)));
+
+        let lease = config.default_lease();
+        lease.validate().unwrap();
+        assert_eq!(lease.address, config.guest_address());
+        assert_eq!(lease.gateway, Some(config.gateway_address()));
+
+        let forward = PortForward {
+            protocol: ForwardProtocol::Tcp,
+            host_address: Ipv4Addr::LOCALHOST,
+            host_port: 8080,
+            guest_port: 80,
+        };
+        let invalid = |config: UserNatConfig| config.validate().unwrap_err().to_string();
+        assert_eq!(
+            invalid(UserNatConfig {
+                prefix_len: 28,
+                ..Default::default()
+            }),
+            Error::InvalidPrefixLen(28).to_string()
+        );
+        assert_eq!(
+            invalid(UserNatConfig {
+                network: Ipv4Addr::new(10, 0, 2, 1),
+                ..Default::default()
+            }),
+            Error::InvalidNetwork(Ipv4Addr::new(10, 0, 2, 1), 24).to_string()
+        );
+        assert_eq!(
+            invalid(UserNatConfig {
+                port_forwards: vec![PortForward {
+                    guest_port: 0,
+                    ..forward
+                }],
+                ..Default::default()
+            }),
+            Error::InvalidPort.to_string()
+        );
+    }
+
+    #[test]
+    fn test_host_address() {
+        let mut nat = UserNat::new(&UserNatConfig::default(), None).unwrap();
+        let public = Ipv4Addr::new(192, 0, 2, 1);
+        assert_eq!(nat.host_address(public), Some(public));
+        for addr in [
+            nat.gateway_addr,
+            Ipv4Addr::new(10, 0, 2, 3),
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(169, 254, 169, 254),
+            Ipv4Addr::new(224, 0, 0, 1),
+            Ipv4Addr::BROADCAST,
+            Ipv4Addr::UNSPECIFIED,
+        ] {
+            assert_eq!(nat.host_address(addr), None);
+        }
+
+        nat.config.allow_host_loopback = true;
+        assert_eq!(
+            nat.host_address(nat.gateway_addr),
+            Some(Ipv4Addr::LOCALHOST)
+        );
+    }
+
+    #[test]
+    fn test_arp() {
+        let mut nat = UserNat::new(&UserNatConfig::default(), None).unwrap();
+        let guest_addr = nat.guest_addr;
+        let arp_frame = |tpa: Ipv4Addr| {
+            let mut buf = vec![0u8; vnet_hdr_len() + PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN];
+            let mut eth = EthernetFrame::write_incomplete(
+                &mut buf[vnet_hdr_len()..],
+                MacAddr::from([0xff; MAC_ADDR_LEN]),
+                MacAddr::from(GUEST_MAC),
+                ETHERTYPE_ARP,
+            )
+            .unwrap();
+            EthIPv4ArpFrame::write_request(
+                eth.inner_mut().payload_mut(),
+                MacAddr::from(GUEST_MAC),
+                guest_addr,
+                MacAddr::from([0; MAC_ADDR_LEN]),
+                tpa,
+            )
+            .unwrap();
+            // Ethernet pads the short frames.
+            buf.extend_from_slice(&[0u8; 18]);
+            buf
+        };
+
+        // Only the requests for the gateway are answered.
+        nat.write_all(&arp_frame(Ipv4Addr::new(10, 0, 2, 3)))
+            .unwrap();
+        assert_no_frame(&mut nat);
+
+        nat.write_all(&arp_frame(nat.gateway_addr)).unwrap();
+        let frame = read_frame(&mut nat);
+        let eth = EthernetFrame::from_bytes(frame.as_slice()).unwrap();
+        assert_eq!(eth.dst_mac(), MacAddr::from(GUEST_MAC));
+        assert_eq!(eth.ethertype(), ETHERTYPE_ARP);
+        let reply = EthIPv4ArpFrame::from_bytes_unchecked(eth.payload());
+        assert_eq!(reply.sha(), MacAddr::from(GATEWAY_MAC));
+        assert_eq!(reply.spa(), nat.gateway_addr);
+        assert_eq!(reply.tha(), MacAddr::from(GUEST_MAC));
+        assert_eq!(reply.tpa(), guest_addr);
+        assert_no_frame(&mut nat);
+
+        // Other frames are dropped.
+        let mut frame = vec![0u8; vnet_hdr_len() + PAYLOAD_OFFSET + 64];
+        EthernetFrame::write_incomplete(
+            &mut frame[vnet_hdr_len()..],
+            MacAddr::from(GATEWAY_MAC),
+            MacAddr::from(GUEST_MAC),
+            0x86dd,
+        )
+        .unwrap();
+        nat.write_all(&frame).unwrap();
+        let ping = guest_frame(nat.gateway_addr, 1, |buf, _| {
+            buf[..8].copy_from_slice(&[8, 0, 0xf7, 0xff, 0, 0, 0, 0]);
+            8
+        });
+        nat.write_all(&ping).unwrap();
+        assert_no_frame(&mut nat);
+    }
+
+    #[test]
+    fn test_tcp_outbound() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut nat = UserNat::new(&loopback_config(), None).unwrap();
+        let remote = SocketAddrV4::new(nat.gateway_addr, port);
+
+        // The guest connects to the host through the gateway.
+        nat.write_all(&guest_tcp_frame(remote, GUEST_ISS, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let syn_ack = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(syn_ack.src, remote);
+        assert_eq!(syn_ack.dst, SocketAddrV4::new(nat.guest_addr, GUEST_PORT));
+        assert_eq!(syn_ack.flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack.ack, GUEST_ISS + 1);
+        assert_eq!(syn_ack.mss, Some(tcp::LOCAL_MSS));
+        let iss = syn_ack.seq;
+
+        // The data of the guest reaches the host peer.
+        nat.write_all(&guest_tcp_frame(
+            remote,
+            GUEST_ISS + 1,
+            iss + 1,
+            TcpFlags::ACK | TcpFlags::PSH,
+            b"ping",
+        ))
+        .unwrap();
+        let ack = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(ack.flags, TcpFlags::ACK);
+        assert_eq!(ack.ack, GUEST_ISS + 5);
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // And the data of the host peer reaches the guest.
+        peer.write_all(b"pong").unwrap();
+        let data = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(data.seq, iss + 1);
+        assert_eq!(data.payload, b"pong");
+
+        // Resetting the connection closes the host socket.
+        nat.write_all(&guest_tcp_frame(
+            remote,
+            GUEST_ISS + 5,
+            iss + 5,
+            TcpFlags::RST | TcpFlags::ACK,
+            &[],
+        ))
+        .unwrap();
+        assert!(nat.tcp_flows.is_empty());
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+
+        // The segments of unknown connections are reset.
+        nat.write_all(&guest_tcp_frame(
+            remote,
+            GUEST_ISS + 5,
+            iss + 5,
+            TcpFlags::ACK,
+            &[],
+        ))
+        .unwrap();
+        let rst = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(rst.flags, TcpFlags::RST);
+        assert_eq!(rst.seq, iss + 5);
+    }
+
+    #[test]
+    fn test_tcp_refused() {
+        // Without the host loopback, the gateway refuses the connections.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut nat = UserNat::new(&UserNatConfig::default(), None).unwrap();
+        let remote = SocketAddrV4::new(nat.gateway_addr, port);
+        nat.write_all(&guest_tcp_frame(remote, GUEST_ISS, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+        let rst = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(rst.flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(rst.ack, GUEST_ISS + 1);
+        assert!(nat.tcp_flows.is_empty());
+
+        // So does the host when nobody listens.
+        let mut nat = UserNat::new(&loopback_config(), None).unwrap();
+        let remote = SocketAddrV4::new(nat.gateway_addr, free_port());
+        nat.write_all(&guest_tcp_frame(remote, GUEST_ISS, 0, TcpFlags::SYN, &[]))
+            .unwrap();
+        let rst = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(rst.flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(rst.ack, GUEST_ISS + 1);
+        assert!(nat.tcp_flows.is_empty());
+    }
+
+    #[test]
+    fn test_udp_outbound() {
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let mut nat = UserNat::new(&loopback_config(), None).unwrap();
+        let remote = SocketAddrV4::new(nat.gateway_addr, port);
+
+        nat.write_all(&guest_udp_frame(GUEST_PORT, remote, b"ping"))
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (len, addr) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(nat.udp_flows.len(), 1);
+        assert!(nat.timer_armed);
+
+        // The replies come from the gateway.
+        peer.send_to(b"pong", addr).unwrap();
+        let (src, dst, payload) = parse_udp_frame(&read_frame(&mut nat));
+        assert_eq!(src, remote);
+        assert_eq!(dst, SocketAddrV4::new(nat.guest_addr, GUEST_PORT));
+        assert_eq!(payload, b"pong");
+
+        // The flow is reused by the following datagrams.
+        nat.write_all(&guest_udp_frame(GUEST_PORT, remote, b"again"))
+            .unwrap();
+        let (len, same_addr) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"again");
+        assert_eq!(same_addr, addr);
+
+        // Until it expires.
+        nat.on_timer(Instant::now() + Duration::from_secs(3600));
+        assert!(nat.udp_flows.is_empty());
+        assert!(nat.sources.is_empty());
+        nat.update_timer();
+        assert!(!nat.timer_armed);
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let mut nat = UserNat::new(&UserNatConfig::default(), None).unwrap();
+        let key = FlowKey {
+            guest: SocketAddrV4::new(nat.guest_addr, GUEST_PORT),
+            remote: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 53),
+        };
+
+        // The frames which don't fit the buffers of the device aren't queued.
+        let dropped = METRICS.net.nat_rx_dropped_frames.count();
+        nat.queue_udp_frame(&key, &vec![0u8; MAX_FRAME_LEN]);
+        assert!(nat.frames.is_empty());
+        assert_eq!(METRICS.net.nat_rx_dropped_frames.count(), dropped + 1);
+        nat.queue_udp_frame(&key, &vec![0u8; MAX_DATAGRAM_SIZE]);
+        assert_eq!(nat.frames.len(), 1);
+        let len = PAYLOAD_OFFSET + IPV4_HEADER_LEN + UDP_HEADER_SIZE + MAX_DATAGRAM_SIZE;
+        assert_eq!(nat.frames[0].len(), len);
+
+        // The frames are kept until they are read into a large enough buffer.
+        let mut buf = vec![0u8; vnet_hdr_len() + 1500];
+        assert_eq!(
+            nat.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        let (src, dst, payload) = parse_udp_frame(&read_frame(&mut nat));
+        assert_eq!((src, dst), (key.remote, key.guest));
+        assert_eq!(payload.len(), MAX_DATAGRAM_SIZE);
+        assert_no_frame(&mut nat);
+    }
+
+    #[test]
+    fn test_tcp_forward() {
+        let host_port = free_port();
+        let config = UserNatConfig {
+            port_forwards: vec![PortForward {
+                protocol: ForwardProtocol::Tcp,
+                host_address: Ipv4Addr::LOCALHOST,
+                host_port,
+                guest_port: 80,
+            }],
+            ..Default::default()
+        };
+        let mut nat = UserNat::new(&config, Some(MacAddr::from(GUEST_MAC))).unwrap();
+        assert_eq!(
+            UserNat::new(&config, None).unwrap_err().to_string(),
+            Error::Listen(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, host_port),
+                IoError::from_raw_os_error(libc::EADDRINUSE)
+            )
+            .to_string()
+        );
+
+        // The connection to the host port is forwarded to the guest.
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, host_port)).unwrap();
+        let syn = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(syn.flags, TcpFlags::SYN);
+        assert_eq!(syn.src.ip(), &nat.gateway_addr);
+        assert!(syn.src.port() >= FIRST_INBOUND_PORT);
+        assert_eq!(syn.dst, SocketAddrV4::new(nat.guest_addr, 80));
+
+        let guest_segment = |seq, ack, flags, payload: &[u8]| {
+            guest_frame(syn.src.ip().to_owned(), PROTOCOL_TCP, |buf, src| {
+                let payload = if payload.is_empty() {
+                    None
+                } else {
+                    Some((payload, payload.len()))
+                };
+                TcpSegment::write_incomplete_segment::<[u8]>(
+                    buf,
+                    seq,
+                    ack,
+                    flags,
+                    8192,
+                    None,
+                    u16::MAX,
+                    payload,
+                )
+                .unwrap()
+                .finalize(80, syn.src.port(), Some((src, *syn.src.ip())))
+                .len()
+            })
+        };
+        nat.write_all(&guest_segment(
+            GUEST_ISS,
+            syn.seq + 1,
+            TcpFlags::SYN | TcpFlags::ACK,
+            &[],
+        ))
+        .unwrap();
+        let ack = parse_tcp_frame(&read_frame(&mut nat));
+        assert_eq!(ack.flags, TcpFlags::ACK);
+        assert_eq!(ack.ack, GUEST_ISS + 1);
+
+        nat.write_all(&guest_segment(
+            GUEST_ISS + 1,
+            syn.seq + 1,
+            TcpFlags::ACK | TcpFlags::PSH,
+            b"hello",
+        ))
+        .unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.write_all(b"world").unwrap();
+        // Skip the acknowledgement of the data of the guest.
+        let mut data = parse_tcp_frame(&read_frame(&mut nat));
+        if data.payload.is_empty() {
+            data = parse_tcp_frame(&read_frame(&mut nat));
+        }
+        assert_eq!(data.payload, b"world");
+    }
+
+    #[test]
+    fn test_udp_forward() {
+        let host_port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = UserNatConfig {
+            port_forwards: vec![PortForward {
+                protocol: ForwardProtocol::Udp,
+                host_address: Ipv4Addr::LOCALHOST,
+                host_port,
+                guest_port: 53,
+            }],
+            ..Default::default()
+        };
+        let mut nat = UserNat::new(&config, Some(MacAddr::from(GUEST_MAC))).unwrap();
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        client
+            .send_to(b"query", (Ipv4Addr::LOCALHOST, host_port))
+            .unwrap();
+        let (src, dst, payload) = parse_udp_frame(&read_frame(&mut nat));
+        assert_eq!(src.ip(), &nat.gateway_addr);
+        assert_eq!(dst, SocketAddrV4::new(nat.guest_addr, 53));
+        assert_eq!(payload, b"query");
+
+        // The replies of the guest are sent from the forwarded port.
+        nat.write_all(&guest_udp_frame(53, src, b"answer")).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, addr) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"answer");
+        assert_eq!(addr.port(), host_port);
+
+        // The next datagrams of the host peer belong to the same flow.
+        client
+            .send_to(b"again", (Ipv4Addr::LOCALHOST, host_port))
+            .unwrap();
+        let (same_src, _, _) = parse_udp_frame(&read_frame(&mut nat));
+        assert_eq!(same_src, src);
+        assert_eq!(nat.udp_flows.len(), 1);
+    }
+}
@@ -0,0 +1,834 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The TCP connections of the user-mode NAT, terminated on the guest side and proxied through a
+//! host socket.
+//!
+//! The guest side of a connection is a minimal TCP endpoint: only the MSS option is negotiated,
+//! the segments received out of order are dropped, and the segments sent to the guest are
+//! retransmitted after a fixed timeout. The link with the guest is not expected to lose frames,
+//! so these shortcuts don't cost much.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::num::Wrapping;
+use std::os::unix::io::FromRawFd;
+use std::time::{Duration, Instant};
+
+use dumbo::pdu::tcp::Flags as TcpFlags;
+use dumbo::tcp::{seq_after, seq_at_or_after, MSS_DEFAULT};
+use utils::epoll::EventSet;
+
+/// The MSS advertised to the guest, for an Ethernet MTU.
+pub(crate) const LOCAL_MSS: u16 = 1460;
+// The largest payload fitting in an IPv4 packet, along with the headers of a segment without
+// options.
+const MAX_MSS: u16 = 65495;
+// The most bytes buffered for each direction of a connection. This is also the largest window
+// advertised to the guest, since window scaling is not negotiated.
+const BUFFER_SIZE: usize = 65535;
+// The fixed retransmission timeout.
+const RTO: Duration = Duration::from_secs(1);
+// The retransmissions after which the connection is reset.
+const MAX_RETRANSMISSIONS: u8 = 6;
+
+/// A segment to send to the guest.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+/// The fields of a segment received from the guest.
+#[derive(Debug)]
+pub(crate) struct GuestSegment<'a> {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+/// Builds the reset answering `segment`, which belongs to no connection.
+pub(crate) fn reset_for(segment: &GuestSegment) -> Segment {
+    let (seq, ack, flags) = if segment.flags.contains(TcpFlags::ACK) {
+        (segment.ack, 0, TcpFlags::RST)
+    } else {
+        // The SYN and FIN flags take a sequence number each.
+        let len = segment.payload.len()
+            + usize::from(segment.flags.contains(TcpFlags::SYN))
+            + usize::from(segment.flags.contains(TcpFlags::FIN));
+        (
+            0,
+            segment.seq.wrapping_add(len as u32),
+            TcpFlags::RST | TcpFlags::ACK,
+        )
+    };
+    Segment {
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        payload: Vec::new(),
+    }
+}
+
+/// Starts connecting to `addr` without blocking.
+pub(crate) fn connect(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // SAFETY: Safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: We just checked that the fd is valid.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    // SAFETY: `sockaddr_in` only contains integers, for which all 0 is a valid value.
+    let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+    sockaddr.sin_port = addr.port().to_be();
+    sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    let addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    // SAFETY: Safe because the address outlives the call, and we check the return value.
+    if unsafe { libc::connect(fd, (&sockaddr as *const libc::sockaddr_in).cast(), addr_len) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // The guest opened the connection, and the host socket is connecting.
+    Connecting,
+    // The SYN-ACK was sent to the guest, which didn't acknowledge it yet.
+    SynReceived,
+    // A host peer opened the connection, and the SYN was sent to the guest.
+    SynSent,
+    // The connection is established, and may be half closed in either direction.
+    Established,
+    // The connection is over.
+    Closed,
+}
+
+/// A TCP connection between the guest and a host socket.
+#[derive(Debug)]
+pub(crate) struct TcpFlow {
+    stream: TcpStream,
+    state: State,
+    // The sequence number of the SYN sent to the guest.
+    iss: Wrapping<u32>,
+    // The oldest sequence number the guest didn't acknowledge.
+    snd_una: Wrapping<u32>,
+    // The sequence number of the next segment sent to the guest.
+    snd_nxt: Wrapping<u32>,
+    // The sequence number expected next from the guest.
+    rcv_nxt: Wrapping<u32>,
+    // The window advertised by the guest.
+    snd_wnd: u16,
+    // The largest payload the guest accepts.
+    guest_mss: u16,
+    // The data sent to the guest which is not acknowledged yet, starting at `snd_una`.
+    unacked: VecDeque<u8>,
+    // The data received from the guest which is not written to the host socket yet.
+    to_host: Vec<u8>,
+    // Whether the host peer closed its side of the connection.
+    host_eof: bool,
+    // Whether the guest closed its side of the connection.
+    guest_fin: bool,
+    // Whether the host socket was shut down for writing, after the guest closed its side.
+    host_shutdown: bool,
+    // Whether the FIN was sent to the guest.
+    fin_sent: bool,
+    // When the oldest unacknowledged segment was last sent, if there is one.
+    rto_start: Option<Instant>,
+    retransmissions: u8,
+}
+
+impl TcpFlow {
+    fn new(stream: TcpStream, state: State, iss: u32) -> Self {
+        TcpFlow {
+            stream,
+            state,
+            iss: Wrapping(iss),
+            snd_una: Wrapping(iss),
+            snd_nxt: Wrapping(iss),
+            rcv_nxt: Wrapping(0),
+            snd_wnd: 0,
+            guest_mss: MSS_DEFAULT,
+            unacked: VecDeque::new(),
+            to_host: Vec::new(),
+            host_eof: false,
+            guest_fin: false,
+            host_shutdown: false,
+            fin_sent: false,
+            rto_start: None,
+            retransmissions: 0,
+        }
+    }
+
+    /// Creates the connection opened by the `syn` of the guest, whose host socket is connecting.
+    pub fn new_outbound(stream: TcpStream, syn: &GuestSegment, iss: u32) -> Self {
+        let mut flow = Self::new(stream, State::Connecting, iss);
+        flow.accept_syn(syn);
+        flow
+    }
+
+    /// Creates the connection opened by a host peer on a forwarded port, sending its SYN to
+    /// the guest.
+    pub fn new_inbound(stream: TcpStream, iss: u32, now: Instant, out: &mut Vec<Segment>) -> Self {
+        let mut flow = Self::new(stream, State::SynSent, iss);
+        flow.snd_nxt += Wrapping(1);
+        flow.rto_start = Some(now);
+        out.push(flow.syn());
+        flow
+    }
+
+    /// The host socket of the connection.
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Whether the connection is over, and can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Whether a segment sent to the guest waits for its acknowledgement.
+    pub fn is_retransmitting(&self) -> bool {
+        self.rto_start.is_some()
+    }
+
+    /// The events of the host socket the connection is waiting for.
+    pub fn polled_evset(&self) -> EventSet {
+        match self.state {
+            State::Connecting => EventSet::OUT,
+            State::Established => {
+                let mut evset = EventSet::empty();
+                if !self.host_eof && self.send_room() > 0 {
+                    evset |= EventSet::IN;
+                }
+                if !self.to_host.is_empty() {
+                    evset |= EventSet::OUT;
+                }
+                evset
+            }
+            // The data of the host peer waits in the socket until the guest is connected.
+            State::SynReceived | State::SynSent | State::Closed => EventSet::empty(),
+        }
+    }
+
+    /// Handles a segment received from the guest.
+    pub fn on_guest_segment(
+        &mut self,
+        segment: &GuestSegment,
+        now: Instant,
+        out: &mut Vec<Segment>,
+    ) {
+        if segment.flags.contains(TcpFlags::RST) {
+            self.state = State::Closed;
+            return;
+        }
+
+        match self.state {
+            // The guest sent its SYN again, which is answered once connected.
+            State::Connecting | State::Closed => return,
+            State::SynSent => {
+                if segment.flags.contains(TcpFlags::SYN | TcpFlags::ACK)
+                    && Wrapping(segment.ack) == self.snd_nxt
+                {
+                    self.accept_syn(segment);
+                    self.establish();
+                    out.push(self.segment(self.snd_nxt, TcpFlags::ACK, Vec::new()));
+                } else {
+                    out.push(reset_for(segment));
+                }
+                return;
+            }
+            State::SynReceived => {
+                if segment.flags.contains(TcpFlags::SYN) {
+                    // The SYN-ACK was lost.
+                    out.push(self.syn_ack());
+                    return;
+                }
+                if !segment.flags.contains(TcpFlags::ACK) {
+                    return;
+                }
+                if Wrapping(segment.ack) != self.snd_nxt {
+                    out.push(reset_for(segment));
+                    return;
+                }
+                self.establish();
+            }
+            State::Established => {
+                if segment.flags.contains(TcpFlags::SYN) {
+                    // Our SYN-ACK or ACK was lost.
+                    out.push(self.segment(self.snd_nxt, TcpFlags::ACK, Vec::new()));
+                    return;
+                }
+            }
+        }
+
+        if segment.flags.contains(TcpFlags::ACK) {
+            self.on_ack(Wrapping(segment.ack), now);
+            self.snd_wnd = segment.window;
+        }
+
+        if !segment.payload.is_empty() || segment.flags.contains(TcpFlags::FIN) {
+            self.receive(segment);
+            self.write_host(out);
+            if self.state == State::Closed {
+                return;
+            }
+            out.push(self.segment(self.snd_nxt, TcpFlags::ACK, Vec::new()));
+        }
+
+        self.check_closed();
+    }
+
+    /// Handles the host socket becoming readable, or reporting an error or a hang up.
+    pub fn on_host_readable(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        if self.state != State::Established {
+            return;
+        }
+
+        while !self.host_eof {
+            let room = self.send_room();
+            if room == 0 {
+                break;
+            }
+            let mut buf = vec![0u8; room];
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.host_eof = true,
+                Ok(len) => self.send_data(&buf[..len], now, out),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.reset(out);
+                    return;
+                }
+            }
+        }
+
+        if self.host_eof && !self.fin_sent {
+            out.push(self.segment(self.snd_nxt, TcpFlags::FIN | TcpFlags::ACK, Vec::new()));
+            self.snd_nxt += Wrapping(1);
+            self.fin_sent = true;
+            self.rto_start.get_or_insert(now);
+        }
+
+        self.check_closed();
+    }
+
+    /// Handles the host socket becoming writable, or reporting an error or a hang up.
+    pub fn on_host_writable(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        match self.state {
+            State::Connecting => match self.stream.take_error() {
+                Ok(None) => {
+                    self.state = State::SynReceived;
+                    self.snd_nxt += Wrapping(1);
+                    self.rto_start = Some(now);
+                    out.push(self.syn_ack());
+                }
+                _ => self.reset(out),
+            },
+            State::Established => {
+                let window = self.window();
+                self.write_host(out);
+                // Let the guest know once there's room for a full segment again, since it may
+                // be waiting for it.
+                if self.state == State::Established
+                    && window < self.guest_mss
+                    && self.window() >= self.guest_mss
+                {
+                    out.push(self.segment(self.snd_nxt, TcpFlags::ACK, Vec::new()));
+                }
+                self.check_closed();
+            }
+            State::SynReceived | State::SynSent | State::Closed => (),
+        }
+    }
+
+    /// Retransmits the oldest unacknowledged segment once it timed out, and resets the
+    /// connection after too many attempts.
+    pub fn on_timer(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        match self.rto_start {
+            Some(start) if now.duration_since(start) >= RTO => (),
+            _ => return,
+        }
+        if self.retransmissions >= MAX_RETRANSMISSIONS {
+            self.reset(out);
+            return;
+        }
+        self.retransmissions += 1;
+        self.rto_start = Some(now);
+
+        match self.state {
+            State::SynSent => out.push(self.syn()),
+            State::SynReceived => out.push(self.syn_ack()),
+            State::Established if !self.unacked.is_empty() => {
+                let len = cmp::min(self.unacked.len(), usize::from(self.guest_mss));
+                let payload = self.unacked.iter().take(len).copied().collect();
+                out.push(self.segment(self.snd_una, TcpFlags::ACK | TcpFlags::PSH, payload));
+            }
+            State::Established if self.fin_sent => {
+                out.push(self.segment(self.snd_una, TcpFlags::FIN | TcpFlags::ACK, Vec::new()));
+            }
+            _ => (),
+        }
+    }
+
+    /// Resets the connection, on both sides.
+    pub fn reset(&mut self, out: &mut Vec<Segment>) {
+        if self.state != State::Closed {
+            out.push(self.segment(self.snd_nxt, TcpFlags::RST | TcpFlags::ACK, Vec::new()));
+            self.state = State::Closed;
+        }
+        // Closing the socket with unread data resets the connection with the host peer.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    // The window advertised to the guest.
+    fn window(&self) -> u16 {
+        // This fits since the buffer is at most `BUFFER_SIZE` long.
+        (BUFFER_SIZE - self.to_host.len()) as u16
+    }
+
+    // How many more bytes the guest accepts.
+    fn send_room(&self) -> usize {
+        usize::from(self.snd_wnd).saturating_sub(self.unacked.len())
+    }
+
+    fn segment(&self, seq: Wrapping<u32>, flags: TcpFlags, payload: Vec<u8>) -> Segment {
+        Segment {
+            seq: seq.0,
+            ack: self.rcv_nxt.0,
+            flags,
+            window: self.window(),
+            mss: None,
+            payload,
+        }
+    }
+
+    fn syn(&self) -> Segment {
+        Segment {
+            seq: self.iss.0,
+            ack: 0,
+            flags: TcpFlags::SYN,
+            window: self.window(),
+            mss: Some(LOCAL_MSS),
+            payload: Vec::new(),
+        }
+    }
+
+    fn syn_ack(&self) -> Segment {
+        Segment {
+            mss: Some(LOCAL_MSS),
+            ..self.segment(self.iss, TcpFlags::SYN | TcpFlags::ACK, Vec::new())
+        }
+    }
+
+    fn accept_syn(&mut self, syn: &GuestSegment) {
+        self.rcv_nxt = Wrapping(syn.seq) + Wrapping(1);
+        self.snd_wnd = syn.window;
+        self.guest_mss = cmp::min(syn.mss.unwrap_or(MSS_DEFAULT), MAX_MSS);
+    }
+
+    fn establish(&mut self) {
+        self.state = State::Established;
+        self.snd_una = self.snd_nxt;
+        self.rto_start = None;
+        self.retransmissions = 0;
+    }
+
+    fn on_ack(&mut self, ack: Wrapping<u32>, now: Instant) {
+        if !seq_after(ack, self.snd_una) || seq_after(ack, self.snd_nxt) {
+            return;
+        }
+        // The FIN takes the sequence number following the data.
+        let acked = cmp::min((ack - self.snd_una).0 as usize, self.unacked.len());
+        self.unacked.drain(..acked);
+        self.snd_una = ack;
+        self.retransmissions = 0;
+        self.rto_start = if self.snd_una == self.snd_nxt {
+            None
+        } else {
+            Some(now)
+        };
+    }
+
+    // Buffers the data of `segment` for the host, along with its FIN, as long as it comes in
+    // order and fits in the buffer.
+    fn receive(&mut self, segment: &GuestSegment) {
+        let seq = Wrapping(segment.seq);
+        if self.guest_fin || !seq_at_or_after(self.rcv_nxt, seq) {
+            return;
+        }
+        let skipped = (self.rcv_nxt - seq).0 as usize;
+        if skipped > segment.payload.len() {
+            return;
+        }
+        let data = &segment.payload[skipped..];
+        let accepted = cmp::min(data.len(), BUFFER_SIZE - self.to_host.len());
+        self.to_host.extend_from_slice(&data[..accepted]);
+        self.rcv_nxt += Wrapping(accepted as u32);
+        if accepted == data.len() && segment.flags.contains(TcpFlags::FIN) {
+            self.rcv_nxt += Wrapping(1);
+            self.guest_fin = true;
+        }
+    }
+
+    fn send_data(&mut self, data: &[u8], now: Instant, out: &mut Vec<Segment>) {
+        for chunk in data.chunks(usize::from(self.guest_mss)) {
+            out.push(self.segment(self.snd_nxt, TcpFlags::ACK | TcpFlags::PSH, chunk.to_vec()));
+            self.snd_nxt += Wrapping(chunk.len() as u32);
+            self.unacked.extend(chunk);
+        }
+        self.rto_start.get_or_insert(now);
+    }
+
+    fn write_host(&mut self, out: &mut Vec<Segment>) {
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(0) => {
+                    self.reset(out);
+                    return;
+                }
+                Ok(len) => {
+                    self.to_host.drain(..len);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.reset(out);
+                    return;
+                }
+            }
+        }
+        if self.guest_fin && self.to_host.is_empty() && !self.host_shutdown {
+            // The host peer may be gone already, which is noticed when reading.
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+    }
+
+    fn check_closed(&mut self) {
+        if self.state == State::Established
+            && self.host_shutdown
+            && self.fin_sent
+            && self.snd_una == self.snd_nxt
+        {
+            self.state = State::Closed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    const ISS: u32 = 1000;
+    const GUEST_ISS: u32 = 5000;
+
+    fn guest_segment(seq: u32, ack: u32, flags: TcpFlags, payload: &[u8]) -> GuestSegment {
+        GuestSegment {
+            seq,
+            ack,
+            flags,
+            window: 8,
+            mss: Some(4),
+            payload,
+        }
+    }
+
+    // Connects the guest to `listener` through a new flow, returning the host peer.
+    fn established_flow(listener: &TcpListener, now: Instant) -> (TcpFlow, TcpStream) {
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            addr => panic!("unexpected address {}", addr),
+        };
+        let syn = guest_segment(GUEST_ISS, 0, TcpFlags::SYN, &[]);
+        let mut flow = TcpFlow::new_outbound(connect(addr).unwrap(), &syn, ISS);
+        assert_eq!(flow.polled_evset(), EventSet::OUT);
+        let (peer, _) = listener.accept().unwrap();
+
+        let mut out = Vec::new();
+        flow.on_host_writable(now, &mut out);
+        assert_eq!(
+            out,
+            vec![Segment {
+                seq: ISS,
+                ack: GUEST_ISS + 1,
+                flags: TcpFlags::SYN | TcpFlags::ACK,
+                window: BUFFER_SIZE as u16,
+                mss: Some(LOCAL_MSS),
+                payload: Vec::new(),
+            }]
+        );
+        out.clear();
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 1, ISS + 1, TcpFlags::ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert!(out.is_empty());
+        assert_eq!(flow.polled_evset(), EventSet::IN);
+        (flow, peer)
+    }
+
+    #[test]
+    fn test_reset_for() {
+        let syn = guest_segment(7, 0, TcpFlags::SYN, &[]);
+        let rst = reset_for(&syn);
+        assert_eq!((rst.seq, rst.ack), (0, 8));
+        assert_eq!(rst.flags, TcpFlags::RST | TcpFlags::ACK);
+
+        let ack = guest_segment(7, 9, TcpFlags::ACK, b"ab");
+        let rst = reset_for(&ack);
+        assert_eq!((rst.seq, rst.ack), (9, 0));
+        assert_eq!(rst.flags, TcpFlags::RST);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        // Find a port nobody listens on.
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, addr.port());
+        let syn = guest_segment(GUEST_ISS, 0, TcpFlags::SYN, &[]);
+        let stream = match connect(addr) {
+            Ok(stream) => stream,
+            // The refusal may be immediate.
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+                return;
+            }
+        };
+        let mut flow = TcpFlow::new_outbound(stream, &syn, ISS);
+
+        // Wait for the connection to fail.
+        let mut pollfd = libc::pollfd {
+            fd: flow.stream().as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        // SAFETY: Safe because `pollfd` outlives the call.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 1000) }, 1);
+
+        let mut out = Vec::new();
+        flow.on_host_writable(Instant::now(), &mut out);
+        assert!(flow.is_closed());
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(out[0].ack, GUEST_ISS + 1);
+    }
+
+    #[test]
+    fn test_data_transfer() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let now = Instant::now();
+        let (mut flow, mut peer) = established_flow(&listener, now);
+        let mut out = Vec::new();
+
+        // The data of the guest is acknowledged and written to the host peer.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 1, ISS + 1, TcpFlags::ACK, b"ping"),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].seq, out[0].ack), (ISS + 1, GUEST_ISS + 5));
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        out.clear();
+
+        // A retransmission is acknowledged, but not written again.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 1, ISS + 1, TcpFlags::ACK, b"ping"),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].ack, GUEST_ISS + 5);
+        out.clear();
+
+        // The data of the host peer is split in segments of the MSS of the guest, as long as it
+        // fits in its window.
+        peer.write_all(b"0123456789").unwrap();
+        peer.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        flow.on_host_readable(now, &mut out);
+        let payloads: Vec<_> = out.iter().map(|segment| segment.payload.clone()).collect();
+        assert_eq!(payloads, vec![b"0123".to_vec(), b"4567".to_vec()]);
+        assert_eq!(out[1].seq, ISS + 5);
+        assert_eq!(flow.polled_evset(), EventSet::empty());
+        out.clear();
+
+        // The first segment is retransmitted once the timeout expires.
+        flow.on_timer(now + RTO / 2, &mut out);
+        assert!(out.is_empty());
+        flow.on_timer(now + RTO, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].seq, &out[0].payload[..]), (ISS + 1, &b"0123"[..]));
+        out.clear();
+
+        // Acknowledging the data makes room for the rest.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 5, ISS + 9, TcpFlags::ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert!(out.is_empty());
+        assert!(!flow.is_retransmitting());
+        assert_eq!(flow.polled_evset(), EventSet::IN);
+        flow.on_host_readable(now, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].seq, &out[0].payload[..]), (ISS + 9, &b"89"[..]));
+    }
+
+    #[test]
+    fn test_close() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let now = Instant::now();
+        let (mut flow, mut peer) = established_flow(&listener, now);
+        let mut out = Vec::new();
+
+        // The FIN of the guest shuts the host socket down.
+        flow.on_guest_segment(
+            &guest_segment(
+                GUEST_ISS + 1,
+                ISS + 1,
+                TcpFlags::FIN | TcpFlags::ACK,
+                b"bye",
+            ),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].ack, GUEST_ISS + 5);
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"bye");
+        out.clear();
+
+        // Closing the host peer sends a FIN to the guest.
+        drop(peer);
+        flow.on_host_readable(now, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].flags, TcpFlags::FIN | TcpFlags::ACK);
+        assert_eq!(out[0].seq, ISS + 1);
+        assert!(!flow.is_closed());
+        out.clear();
+
+        // The connection is over once the guest acknowledges the FIN.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 5, ISS + 2, TcpFlags::ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert!(out.is_empty());
+        assert!(flow.is_closed());
+    }
+
+    #[test]
+    fn test_inbound() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let now = Instant::now();
+        let mut out = Vec::new();
+
+        let mut flow = TcpFlow::new_inbound(stream, ISS, now, &mut out);
+        assert_eq!(out, vec![flow.syn()]);
+        assert_eq!(flow.polled_evset(), EventSet::empty());
+        out.clear();
+
+        // The SYN is retransmitted, until the guest gives up.
+        flow.on_timer(now + RTO, &mut out);
+        assert_eq!(out, vec![flow.syn()]);
+        out.clear();
+
+        // An unexpected segment is reset.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS, ISS, TcpFlags::ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert_eq!(
+            out,
+            vec![reset_for(&guest_segment(
+                GUEST_ISS,
+                ISS,
+                TcpFlags::ACK,
+                &[]
+            ))]
+        );
+        out.clear();
+
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS, ISS + 1, TcpFlags::SYN | TcpFlags::ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].seq, out[0].ack), (ISS + 1, GUEST_ISS + 1));
+        assert_eq!(out[0].flags, TcpFlags::ACK);
+        assert_eq!(flow.polled_evset(), EventSet::IN);
+        assert!(!flow.is_retransmitting());
+
+        // A reset from the guest closes the connection.
+        flow.on_guest_segment(
+            &guest_segment(GUEST_ISS + 1, 0, TcpFlags::RST, &[]),
+            now,
+            &mut out,
+        );
+        assert!(flow.is_closed());
+        drop(client);
+    }
+
+    #[test]
+    fn test_retransmission_limit() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut now = Instant::now();
+        let mut out = Vec::new();
+
+        let mut flow = TcpFlow::new_inbound(stream, ISS, now, &mut out);
+        for _ in 0..MAX_RETRANSMISSIONS {
+            now += RTO;
+            flow.on_timer(now, &mut out);
+            assert!(!flow.is_closed());
+        }
+        out.clear();
+        now += RTO;
+        flow.on_timer(now, &mut out);
+        assert!(flow.is_closed());
+        assert_eq!(out.len(), 1);
+        assert!(out[0].flags.contains(TcpFlags::RST));
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The UDP flows of the user-mode NAT, proxied through host sockets.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+// How long a flow lasts without any datagram.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where the datagrams of a flow are exchanged with the host.
+#[derive(Debug)]
+pub(crate) enum UdpSource {
+    /// The flow was opened by the guest, and has its own host socket connected to the remote
+    /// peer.
+    Connected(UdpSocket),
+    /// The flow was opened by the given host peer, whose datagrams are exchanged on the socket of
+    /// the forwarded port with the given index.
+    Forward(usize, SocketAddrV4),
+}
+
+/// A UDP flow between the guest and a host peer.
+#[derive(Debug)]
+pub(crate) struct UdpFlow {
+    source: UdpSource,
+    last_active: Instant,
+}
+
+impl UdpFlow {
+    /// Opens a flow towards `addr`, on behalf of the guest.
+    pub fn connect(addr: SocketAddrV4, now: Instant) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        socket.connect(addr)?;
+        Ok(UdpFlow {
+            source: UdpSource::Connected(socket),
+            last_active: now,
+        })
+    }
+
+    /// Tracks the flow opened by `peer` on the forwarded port with index `forward`.
+    pub fn forwarded(forward: usize, peer: SocketAddrV4, now: Instant) -> Self {
+        UdpFlow {
+            source: UdpSource::Forward(forward, peer),
+            last_active: now,
+        }
+    }
+
+    pub fn source(&self) -> &UdpSource {
+        &self.source
+    }
+
+    /// Records a datagram going through the flow.
+    pub fn touch(&mut self, now: Instant) {
+        self.last_active = now;
+    }
+
+    /// Whether the flow saw no datagram for too long.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_active) >= IDLE_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_flow() {
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, peer.local_addr().unwrap().port());
+        let now = Instant::now();
+
+        let mut flow = UdpFlow::connect(addr, now).unwrap();
+        let socket = match flow.source() {
+            UdpSource::Connected(socket) => socket,
+            source => panic!("unexpected source {:?}", source),
+        };
+        assert_eq!(socket.peer_addr().unwrap(), addr.into());
+        assert_eq!(
+            socket.recv(&mut [0u8; 16]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        assert!(!flow.is_expired(now + IDLE_TIMEOUT / 2));
+        flow.touch(now + IDLE_TIMEOUT / 2);
+        assert!(!flow.is_expired(now + IDLE_TIMEOUT));
+        assert!(flow.is_expired(now + IDLE_TIMEOUT * 3 / 2));
+
+        let flow = UdpFlow::forwarded(3, addr, now);
+        assert!(matches!(flow.source(), UdpSource::Forward(3, peer) if *peer == addr));
+    }
+}
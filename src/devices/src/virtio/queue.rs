@@ -403,6 +403,20 @@ impl Queue {
         mem: &GuestMemoryMmap,
         desc_index: u16,
         len: u32,
+    ) -> Result<(), QueueError> {
+        self.write_used_element(mem, 0, desc_index, len)?;
+        self.advance_used_ring(mem, 1)
+    }
+
+    /// Writes a used ring element `offset` positions after the next used one, without making it
+    /// visible to the guest. The caller publishes the written elements with
+    /// `self.advance_used_ring()`.
+    pub fn write_used_element(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        offset: u16,
+        desc_index: u16,
+        len: u32,
     ) -> Result<(), QueueError> {
         if desc_index >= self.actual_size() {
             error!(
@@ -412,22 +426,31 @@ impl Queue {
             return Err(QueueError::DescIndexOutOfBounds(desc_index));
         }
 
-        let used_ring = self.used_ring;
-        let next_used = u64::from(self.next_used.0 % self.actual_size());
-        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+        let next_used = u64::from((self.next_used + Wrapping(offset)).0 % self.actual_size());
+        let used_elem = self.used_ring.unchecked_add(4 + next_used * 8);
 
         mem.write_obj(u32::from(desc_index), used_elem)?;
 
         let len_addr = used_elem.unchecked_add(4);
         mem.write_obj(len, len_addr)?;
 
-        self.num_added += Wrapping(1);
-        self.next_used += Wrapping(1);
+        Ok(())
+    }
+
+    /// Makes the next `count` used ring elements, written with `self.write_used_element()`,
+    /// visible to the guest at once.
+    pub fn advance_used_ring(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        count: u16,
+    ) -> Result<(), QueueError> {
+        self.num_added += Wrapping(count);
+        self.next_used += Wrapping(count);
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
 
-        let next_used_addr = used_ring.unchecked_add(2);
+        let next_used_addr = self.used_ring.unchecked_add(2);
         mem.write_obj(self.next_used.0, next_used_addr)
             .map_err(QueueError::UsedRing)
     }
@@ -896,6 +919,39 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_write_used_elements() {
+        let m = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.next_used = Wrapping(15);
+
+        // index too large
+        match q.write_used_element(m, 0, 16, 0x1000) {
+            Err(DescIndexOutOfBounds(16)) => (),
+            _ => unreachable!(),
+        }
+
+        // The elements wrap around the end of the ring and aren't visible before the
+        // used ring is advanced.
+        q.write_used_element(m, 0, 3, 0x1000).unwrap();
+        q.write_used_element(m, 1, 5, 0x200).unwrap();
+        assert_eq!(vq.used.idx.get(), 0);
+        assert_eq!(q.next_used, Wrapping(15));
+
+        q.advance_used_ring(m, 2).unwrap();
+        assert_eq!(vq.used.idx.get(), 17);
+        assert_eq!(q.next_used, Wrapping(17));
+        assert_eq!(q.num_added, Wrapping(2));
+        let x = vq.used.ring[15].get();
+        assert_eq!(x.id, 3);
+        assert_eq!(x.len, 0x1000);
+        let x = vq.used.ring[0].get();
+        assert_eq!(x.id, 5);
+        assert_eq!(x.len, 0x200);
+    }
+
     #[test]
     fn test_used_event() {
         let m = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
@@ -0,0 +1,398 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Write;
+use std::num::Wrapping;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::{cmp, result};
+
+use logger::{error, info, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+use virtio_gen::virtio_net::{VIRTIO_F_VERSION_1, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{ByteValued, GuestMemoryMmap};
+
+use super::vhost::VhostNetFd;
+use super::{Error, Result};
+use crate::virtio::net::device::{configure_tap, ConfigSpace, TAP_OFFLOAD_FEATURES};
+use crate::virtio::net::tap::Tap;
+use crate::virtio::net::{Error as NetError, NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, IrqTrigger, Queue, VirtioDevice, TYPE_NET,
+};
+
+// The features of the vhost-net driver which are exposed to the guest driver, on top of the
+// offloads of the TAP interface.
+const SUPPORTED_VHOST_FEATURES: u64 =
+    (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_RING_F_EVENT_IDX) | (1 << VIRTIO_NET_F_MRG_RXBUF);
+
+/// Virtio net device handing its rx/tx queue pair over to the vhost-net driver of the host
+/// kernel.
+///
+/// The driver exchanges the frames directly between the guest memory and the TAP interface.
+/// The device only forwards the queue notifications of the driver to the guest as interrupts.
+pub struct VhostNet {
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) config_space: ConfigSpace,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    // Registered as ioeventfds for the queue notifications of the guest and handed over to the
+    // driver as kick eventfds.
+    pub(crate) queue_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    pub(crate) guest_mac: Option<MacAddr>,
+    tap: Tap,
+    vhost: VhostNetFd,
+    // Signaled by the driver when it uses buffers of the corresponding queue.
+    pub(crate) call_evts: Vec<EventFd>,
+    // Whether the driver stopped processing the vrings for the device state to be saved.
+    vrings_stopped: bool,
+}
+
+impl VhostNet {
+    /// Create a new vhost-net device, exchanging frames with the TAP interface `tap_if_name`.
+    pub fn new(id: String, tap_if_name: &str, guest_mac: Option<MacAddr>) -> Result<VhostNet> {
+        let tap = Tap::open_named(tap_if_name).map_err(|err| Error::Tap(NetError::TapOpen(err)))?;
+        configure_tap(&tap).map_err(Error::Tap)?;
+
+        let vhost = VhostNetFd::open().map_err(Error::OpenVhostNet)?;
+        vhost
+            .set_owner()
+            .map_err(|err| Error::Vhost("set the owner", err))?;
+        let vhost_features = vhost
+            .get_features()
+            .map_err(|err| Error::Vhost("get the features", err))?;
+        if vhost_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::MissingFeature("VIRTIO_F_VERSION_1"));
+        }
+
+        let mut avail_features = TAP_OFFLOAD_FEATURES | (vhost_features & SUPPORTED_VHOST_FEATURES);
+        let mut config_space = ConfigSpace::default();
+        if let Some(mac) = guest_mac {
+            config_space.guest_mac = mac;
+            avail_features |= 1 << VIRTIO_NET_F_MAC;
+        }
+
+        let queue_evts = (0..NUM_QUEUES)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<_>, _>>()?;
+        let call_evts = (0..NUM_QUEUES)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<_>, _>>()?;
+
+        let queues = (0..NUM_QUEUES).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
+        Ok(VhostNet {
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queues,
+            queue_evts,
+            device_state: DeviceState::Inactive,
+            irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
+            id,
+            guest_mac,
+            tap,
+            vhost,
+            call_evts,
+            vrings_stopped: false,
+        })
+    }
+
+    /// Provides the ID of this net device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the MAC of this net device.
+    pub fn guest_mac(&self) -> Option<&MacAddr> {
+        self.guest_mac.as_ref()
+    }
+
+    /// Provides the host IFACE name of this net device.
+    pub fn iface_name(&self) -> String {
+        self.tap.if_name_as_str().to_string()
+    }
+
+    // Hands the guest memory and the queues over to the driver and starts the processing of
+    // the vrings. The driver resumes processing the available rings from the `next_avail`
+    // index of the queues, which is only different from zero for restored devices.
+    pub(crate) fn setup_vhost(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        self.vhost
+            .set_features(self.acked_features)
+            .map_err(|err| Error::Vhost("set the features", err))?;
+        self.vhost
+            .set_mem_table(mem)
+            .map_err(|err| Error::Vhost("set the memory table", err))?;
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            let index = queue_index as u32;
+            self.vhost
+                .set_vring_num(index, queue.actual_size())
+                .map_err(|err| Error::Vhost("set the vring size", err))?;
+            self.vhost
+                .set_vring_addr(
+                    mem,
+                    index,
+                    queue.desc_table,
+                    queue.avail_ring,
+                    queue.used_ring,
+                )
+                .map_err(|err| Error::Vhost("set the vring addresses", err))?;
+            self.vhost
+                .set_vring_base(index, queue.next_avail.0)
+                .map_err(|err| Error::Vhost("set the vring base", err))?;
+            self.vhost
+                .set_vring_call(index, self.call_evts[queue_index].as_raw_fd())
+                .map_err(|err| Error::Vhost("set the vring call eventfd", err))?;
+            self.vhost
+                .set_vring_kick(index, self.queue_evts[queue_index].as_raw_fd())
+                .map_err(|err| Error::Vhost("set the vring kick eventfd", err))?;
+        }
+        self.start_vrings()
+    }
+
+    fn start_vrings(&mut self) -> Result<()> {
+        for index in 0..self.queues.len() {
+            self.vhost
+                .set_backend(index as u32, Some(self.tap.as_raw_fd()))
+                .map_err(|err| Error::Vhost("set the backend", err))?;
+        }
+        self.vrings_stopped = false;
+        Ok(())
+    }
+
+    /// Stops the processing of the vrings by the driver and fetches back the index of the next
+    /// available descriptor of every queue, which is saved with the device state.
+    ///
+    /// The driver re-reads the index of the used ring from the guest memory when the vrings are
+    /// set up again, so the vrings have to stay stopped until the guest memory is saved too.
+    /// They are started again by `resume_vrings`.
+    pub fn prepare_save(&mut self) {
+        if !self.is_activated() || self.vrings_stopped {
+            return;
+        }
+
+        for (queue_index, queue) in self.queues.iter_mut().enumerate() {
+            let index = queue_index as u32;
+            let vring_base = self
+                .vhost
+                .set_backend(index, None)
+                .and_then(|()| self.vhost.get_vring_base(index));
+            match vring_base {
+                Ok(next_avail) => queue.next_avail = Wrapping(next_avail),
+                Err(err) => {
+                    error!(
+                        "Failed to stop vring {} of the vhost-net driver: {}",
+                        index, err
+                    );
+                    METRICS.net.event_fails.inc();
+                }
+            }
+        }
+        self.vrings_stopped = true;
+    }
+
+    /// Starts again the processing of the vrings stopped by `prepare_save`.
+    pub fn resume_vrings(&mut self) {
+        if !self.vrings_stopped {
+            return;
+        }
+
+        info!("Restarting the vrings of vhost-net device {}.", self.id);
+        if let Err(err) = self.start_vrings() {
+            error!(
+                "Failed to restart the vrings of the vhost-net driver: {}",
+                err
+            );
+            METRICS.net.event_fails.inc();
+        }
+    }
+}
+
+impl VirtioDevice for VhostNet {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space_bytes = self.config_space.as_slice();
+        let config_len = config_space_bytes.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(
+                &config_space_bytes[offset as usize..cmp::min(end, config_len) as usize],
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        // Only the MAC address can be written by the driver.
+        let config_space_bytes = &mut self.config_space.as_mut_slice()[..MAC_ADDR_LEN];
+        let config_len = config_space_bytes.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
+
+        config_space_bytes[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
+        self.guest_mac = Some(self.config_space.guest_mac);
+        METRICS.net.mac_address_updates.inc();
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if let Err(err) = self.setup_vhost(&mem) {
+            error!("Net: Cannot set up the vhost-net driver: {}", err);
+            METRICS.net.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Net: Cannot write to activate_evt");
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use virtio_gen::virtio_net::VIRTIO_NET_F_CSUM;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::*;
+    use crate::virtio::net::test_utils::{
+        default_guest_mac, default_guest_memory, enable, if_index, virtqueues, TapTrafficSimulator,
+    };
+
+    pub(crate) fn default_vhost_net(id: &str) -> VhostNet {
+        let tap_if_name = format!("vhost-{}", id);
+        let vhost_net =
+            VhostNet::new(id.to_string(), &tap_if_name, Some(default_guest_mac())).unwrap();
+        enable(&vhost_net.iface_name());
+        vhost_net
+    }
+
+    #[test]
+    fn test_new() {
+        let vhost_net = default_vhost_net("test_new");
+
+        assert_eq!(vhost_net.id(), "test_new");
+        assert_eq!(vhost_net.iface_name(), "vhost-test_new");
+        assert_eq!(vhost_net.guest_mac(), Some(&default_guest_mac()));
+        assert_eq!(vhost_net.device_type(), TYPE_NET);
+        assert_eq!(vhost_net.queues().len(), NUM_QUEUES);
+        assert!(vhost_net.has_feature(u64::from(VIRTIO_F_VERSION_1)));
+        assert!(vhost_net.has_feature(u64::from(VIRTIO_NET_F_MAC)));
+        assert!(vhost_net.has_feature(u64::from(VIRTIO_NET_F_CSUM)));
+
+        let mut mac = [0u8; MAC_ADDR_LEN];
+        vhost_net.read_config(0, &mut mac);
+        assert_eq!(&mac[..], default_guest_mac().get_bytes());
+    }
+
+    #[test]
+    fn test_tx_through_vhost() {
+        let mut vhost_net = default_vhost_net("test_tx");
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&vhost_net.iface_name()));
+        let mem = default_guest_memory();
+        let (rxq, txq) = virtqueues(&mem);
+        vhost_net.queues[0] = rxq.create_queue();
+        vhost_net.queues[1] = txq.create_queue();
+        vhost_net.set_acked_features(vhost_net.avail_features());
+        vhost_net.activate(mem.clone()).unwrap();
+
+        // The frame is made of a zeroed VNET header followed by the payload.
+        let frame = [0u8; 12]
+            .iter()
+            .chain(b"vhost-net frame payload")
+            .copied()
+            .collect::<Vec<u8>>();
+        txq.avail.ring[0].set(0);
+        txq.dtable[0].set(0x2000, frame.len() as u32, 0, 0);
+        mem.write_slice(&frame, GuestAddress(0x2000)).unwrap();
+        txq.avail.idx.set(1);
+        vhost_net.queue_evts[1].write(1).unwrap();
+
+        // The frame is sent by a kernel thread, asynchronously.
+        let mut buf = [0u8; 64];
+        let mut received = false;
+        for _ in 0..100 {
+            if tap_traffic_simulator.pop_rx_packet(&mut buf) {
+                received = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(received);
+        assert_eq!(&buf[..frame.len() - 12], b"vhost-net frame payload");
+
+        // Once stopped, the driver reports the index of the next available descriptor.
+        vhost_net.prepare_save();
+        assert_eq!(vhost_net.queues[1].next_avail.0, 1);
+        assert_eq!(vhost_net.queues[0].next_avail.0, 0);
+        vhost_net.resume_vrings();
+        assert!(!vhost_net.vrings_stopped);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn, IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+use crate::virtio::vhost_net::device::VhostNet;
+use crate::virtio::{IrqType, VirtioDevice};
+
+impl VhostNet {
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        for call_evt in &self.call_evts {
+            if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                error!("Failed to register call event: {}", err);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        debug!("vhost-net: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!("Failed to consume vhost-net activate event: {:?}", err);
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+
+    // The driver used buffers of a queue, so the guest has to be notified.
+    fn process_call_event(&self, queue_index: usize) {
+        if let Err(err) = self.call_evts[queue_index].read() {
+            error!("Failed to get call event: {:?}", err);
+            METRICS.net.event_fails.inc();
+        } else if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+            error!("Failed to signal used queue: {:?}", err);
+            METRICS.net.event_fails.inc();
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostNet {
+    // Handle an event for a call eventfd of the vhost-net driver.
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let supported_events = EventSet::IN;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "VhostNet: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let activate_fd = self.activate_evt.as_raw_fd();
+            let maybe_call_index = self
+                .call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source);
+
+            match maybe_call_index {
+                Some(queue_index) => self.process_call_event(queue_index),
+                None if activate_fd == source => self.process_activate_event(ops),
+                None => warn!("VhostNet: Spurious event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "VhostNet: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        // This function can be called during different points in the device lifetime:
+        //  - shortly after device creation,
+        //  - on device activation (is-activated already true at this point),
+        //  - on device restore from snapshot.
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+
+    use super::*;
+    use crate::virtio::net::test_utils::{default_guest_memory, virtqueues};
+    use crate::virtio::vhost_net::device::tests::default_vhost_net;
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let mut vhost_net = default_vhost_net("test_evh");
+        let mem = default_guest_memory();
+        let (rxq, txq) = virtqueues(&mem);
+        vhost_net.queues[0] = rxq.create_queue();
+        vhost_net.queues[1] = txq.create_queue();
+        let vhost_net = Arc::new(Mutex::new(vhost_net));
+        let _id = event_manager.add_subscriber(vhost_net.clone());
+
+        // The call eventfds are only monitored once the device is activated.
+        vhost_net.lock().unwrap().call_evts[0].write(1).unwrap();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+
+        vhost_net.lock().unwrap().activate(mem).unwrap();
+        // Process the activate event and the pending call event.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(vhost_net
+            .lock()
+            .unwrap()
+            .irq_trigger
+            .has_pending_irq(IrqType::Vring));
+    }
+}
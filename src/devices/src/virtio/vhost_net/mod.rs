@@ -0,0 +1,39 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio net device whose data path is offloaded to the vhost-net driver of the
+//! host kernel.
+
+pub mod device;
+pub mod event_handler;
+pub mod persist;
+mod vhost;
+
+use std::io;
+
+pub use self::device::VhostNet;
+use crate::virtio::net::Error as NetError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Setting up the tap device failed
+    #[error("Setting up the tap device failed: {0}")]
+    Tap(NetError),
+    /// Opening /dev/vhost-net failed
+    #[error("Opening /dev/vhost-net failed: {0}")]
+    OpenVhostNet(io::Error),
+    /// A request to the vhost-net driver failed
+    #[error("The vhost-net driver failed to {0}: {1}")]
+    Vhost(&'static str, io::Error),
+    /// The vhost-net driver doesn't implement a required feature
+    #[error("The vhost-net driver doesn't implement the {0} feature")]
+    MissingFeature(&'static str),
+    /// EventFd error
+    #[error("EventFd error: {0}")]
+    EventFd(io::Error),
+    /// Error creating an irqfd
+    #[error("Error creating an irqfd: {0}")]
+    IrqTrigger(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,132 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the structures needed for saving/restoring vhost-net devices.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use snapshot::Persist;
+use utils::net::mac::MacAddr;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_memory::GuestMemoryMmap;
+
+use super::device::VhostNet;
+use crate::virtio::net::{NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
+use crate::virtio::{DeviceState, TYPE_NET};
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VhostNetState {
+    id: String,
+    tap_if_name: String,
+    guest_mac: Option<MacAddr>,
+    // The queues hold the indexes of the next available descriptors fetched back from the
+    // vhost-net driver by `VhostNet::prepare_save`.
+    virtio_state: VirtioDeviceState,
+}
+
+pub struct VhostNetConstructorArgs {
+    pub mem: GuestMemoryMmap,
+}
+
+#[derive(Debug, derive_more::From)]
+pub enum Error {
+    CreateVhostNet(super::Error),
+    VirtioState(VirtioStateError),
+}
+
+impl Persist<'_> for VhostNet {
+    type State = VhostNetState;
+    type ConstructorArgs = VhostNetConstructorArgs;
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        VhostNetState {
+            id: self.id().clone(),
+            tap_if_name: self.iface_name(),
+            guest_mac: self.guest_mac,
+            virtio_state: VirtioDeviceState::from_device(self),
+        }
+    }
+
+    fn restore(
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        let mut vhost_net = VhostNet::new(state.id.clone(), &state.tap_if_name, state.guest_mac)?;
+
+        vhost_net.queues = state.virtio_state.build_queues_checked(
+            &constructor_args.mem,
+            TYPE_NET,
+            NUM_QUEUES,
+            QUEUE_SIZE,
+        )?;
+        vhost_net.irq_trigger.irq_status =
+            Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
+        vhost_net.avail_features = state.virtio_state.avail_features;
+        vhost_net.acked_features = state.virtio_state.acked_features;
+
+        if state.virtio_state.activated {
+            // The vhost-net driver of the new process resumes processing the vrings from the
+            // saved indexes.
+            vhost_net.setup_vhost(&constructor_args.mem)?;
+            vhost_net.device_state = DeviceState::Activated(constructor_args.mem);
+        }
+
+        Ok(vhost_net)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    use super::*;
+    use crate::virtio::device::VirtioDevice;
+    use crate::virtio::net::test_utils::{default_guest_memory, virtqueues};
+    use crate::virtio::vhost_net::device::tests::default_vhost_net;
+
+    #[test]
+    fn test_persistence() {
+        let mem = default_guest_memory();
+        let (rxq, txq) = virtqueues(&mem);
+        let mut vhost_net = default_vhost_net("test_save");
+        vhost_net.queues[0] = rxq.create_queue();
+        vhost_net.queues[1] = txq.create_queue();
+        vhost_net.set_acked_features(vhost_net.avail_features());
+        vhost_net.activate(mem.clone()).unwrap();
+
+        vhost_net.prepare_save();
+        // Pretend the driver consumed two frames from the tx queue before being stopped.
+        vhost_net.queues[1].next_avail = Wrapping(2);
+
+        let mut buf = vec![0; 4096];
+        let version_map = VersionMap::new();
+        <VhostNet as Persist>::save(&vhost_net)
+            .serialize(&mut buf.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let guest_mac = vhost_net.guest_mac;
+        let avail_features = vhost_net.avail_features();
+        drop(vhost_net);
+
+        let mut restored = VhostNet::restore(
+            VhostNetConstructorArgs { mem },
+            &VhostNetState::deserialize(&mut buf.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored.id(), "test_save");
+        assert_eq!(restored.iface_name(), "vhost-test_save");
+        assert_eq!(restored.guest_mac, guest_mac);
+        assert_eq!(restored.avail_features(), avail_features);
+        assert_eq!(restored.acked_features(), avail_features);
+        assert!(restored.is_activated());
+
+        // The restored driver resumes processing the vrings from the saved indexes.
+        restored.queues[1].next_avail = Wrapping(0);
+        restored.prepare_save();
+        assert_eq!(restored.queues[1].next_avail.0, 2);
+    }
+}
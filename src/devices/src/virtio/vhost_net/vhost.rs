@@ -0,0 +1,240 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Wraps the ioctls of the vhost-net driver of the host kernel.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use utils::{ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+use vm_memory::{
+    Address, ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+// The payloads of the vhost-user requests have the same layout as the ones of the ioctls.
+use crate::virtio::vhost_user::{MemoryHeader, MemoryRegion, VringAddr, VringState};
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+/// Maximum number of guest memory regions passed to the driver.
+const VHOST_NET_MAX_MEM_REGIONS: usize = 8;
+
+const VHOST_VIRTIO: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, MemoryHeader);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST_VIRTIO, 0x12, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, VringFile);
+
+/// Payload of the ioctls passing a file descriptor for a vring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+struct VringFile {
+    index: u32,
+    fd: c_int,
+}
+
+// SAFETY: Safe because VringFile only contains plain data.
+unsafe impl ByteValued for VringFile {}
+
+/// Payload of the `VHOST_SET_MEM_TABLE` ioctl. The driver only reads the first `num_regions`
+/// regions.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct MemoryTable {
+    header: MemoryHeader,
+    regions: [MemoryRegion; VHOST_NET_MAX_MEM_REGIONS],
+}
+
+/// Handle of a vhost-net device instance of the host kernel, which processes the vrings of a
+/// single rx/tx queue pair.
+#[derive(Debug)]
+pub struct VhostNetFd {
+    file: File,
+}
+
+impl VhostNetFd {
+    /// Opens a new instance of the vhost-net device.
+    pub fn open() -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(VHOST_NET_PATH)?;
+        Ok(Self { file })
+    }
+
+    /// Claims the device for the current process, whose address space the driver accesses the
+    /// guest memory through.
+    pub fn set_owner(&self) -> io::Result<()> {
+        // SAFETY: Safe because the ioctl doesn't take any argument.
+        Self::check(unsafe { ioctl(self, VHOST_SET_OWNER()) })
+    }
+
+    /// Returns the virtio features supported by the driver.
+    pub fn get_features(&self) -> io::Result<u64> {
+        let mut features = 0u64;
+        // SAFETY: Safe because the kernel writes to a valid u64.
+        Self::check(unsafe { ioctl_with_mut_ref(self, VHOST_GET_FEATURES(), &mut features) })?;
+        Ok(features)
+    }
+
+    /// Sets the virtio features negotiated with the guest driver.
+    pub fn set_features(&self, features: u64) -> io::Result<()> {
+        // SAFETY: Safe because the kernel only reads a valid u64.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_FEATURES(), &features) })
+    }
+
+    /// Describes the guest memory regions and the addresses they are mapped at in the current
+    /// process.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> io::Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > VHOST_NET_MAX_MEM_REGIONS {
+            return Err(io::Error::from_raw_os_error(libc::E2BIG));
+        }
+
+        let mut table = MemoryTable {
+            header: MemoryHeader {
+                num_regions: num_regions as u32,
+                padding: 0,
+            },
+            ..Default::default()
+        };
+        for (region, table_region) in mem.iter().zip(table.regions.iter_mut()) {
+            *table_region = MemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: region.as_ptr() as u64,
+                // Holds the flags of the region, none of which are defined.
+                mmap_offset: 0,
+            };
+        }
+        // SAFETY: Safe because the kernel only reads the header and the `num_regions` regions
+        // which follow it.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_MEM_TABLE(), &table) })
+    }
+
+    /// Sets the number of descriptors of a vring.
+    pub fn set_vring_num(&self, index: u32, num: u16) -> io::Result<()> {
+        let state = VringState {
+            index,
+            num: u32::from(num),
+        };
+        // SAFETY: Safe because the kernel only reads a valid VringState.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_VRING_NUM(), &state) })
+    }
+
+    /// Sets the location of a vring, translating the guest addresses of its areas to addresses
+    /// in the current process.
+    pub fn set_vring_addr(
+        &self,
+        mem: &GuestMemoryMmap,
+        index: u32,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+    ) -> io::Result<()> {
+        let host_addr = |addr| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EFAULT))
+        };
+        let addr = VringAddr {
+            index,
+            flags: 0,
+            descriptor: host_addr(desc_table)?,
+            used: host_addr(used_ring)?,
+            available: host_addr(avail_ring)?,
+            log: 0,
+        };
+        // SAFETY: Safe because the kernel only reads a valid VringAddr.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_VRING_ADDR(), &addr) })
+    }
+
+    /// Sets the index of the next available descriptor the driver processes.
+    pub fn set_vring_base(&self, index: u32, base: u16) -> io::Result<()> {
+        let state = VringState {
+            index,
+            num: u32::from(base),
+        };
+        // SAFETY: Safe because the kernel only reads a valid VringState.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_VRING_BASE(), &state) })
+    }
+
+    /// Returns the index of the next available descriptor the driver would process.
+    pub fn get_vring_base(&self, index: u32) -> io::Result<u16> {
+        let mut state = VringState { index, num: 0 };
+        // SAFETY: Safe because the kernel reads and writes a valid VringState.
+        Self::check(unsafe { ioctl_with_mut_ref(self, VHOST_GET_VRING_BASE(), &mut state) })?;
+        // The index of a split virtqueue is 16 bits wide.
+        Ok(state.num as u16)
+    }
+
+    /// Sets the eventfd the driver gets notified on when the guest makes buffers available.
+    pub fn set_vring_kick(&self, index: u32, fd: RawFd) -> io::Result<()> {
+        let file = VringFile { index, fd };
+        // SAFETY: Safe because the kernel only reads a valid VringFile.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_VRING_KICK(), &file) })
+    }
+
+    /// Sets the eventfd the driver signals when it uses buffers of the vring.
+    pub fn set_vring_call(&self, index: u32, fd: RawFd) -> io::Result<()> {
+        let file = VringFile { index, fd };
+        // SAFETY: Safe because the kernel only reads a valid VringFile.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_SET_VRING_CALL(), &file) })
+    }
+
+    /// Sets the TAP file the frames of the vring are exchanged with. A `None` file stops the
+    /// processing of the vring.
+    pub fn set_backend(&self, index: u32, fd: Option<RawFd>) -> io::Result<()> {
+        let file = VringFile {
+            index,
+            fd: fd.unwrap_or(-1),
+        };
+        // SAFETY: Safe because the kernel only reads a valid VringFile.
+        Self::check(unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &file) })
+    }
+
+    fn check(ret: c_int) -> io::Result<()> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for VhostNetFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_AF00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_AF00);
+        assert_eq!(VHOST_SET_OWNER(), 0xAF01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_AF03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_AF10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_AF11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_AF12);
+        assert_eq!(VHOST_GET_VRING_BASE(), 0xC008_AF12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_AF20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_AF21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_AF30);
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use utils::sock_ctrl_msg::ScmSocket;
+use vm_memory::{
+    Address, ByteValued, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use super::{
+    ConfigHeader, Error, MemoryHeader, MemoryRegion, MsgHeader, Request, Result, U64Payload,
+    VringAddr, VringState, VHOST_USER_MAX_CONFIG_SIZE, VHOST_USER_MAX_MEM_REGIONS,
+    VHOST_USER_REPLY_MASK, VHOST_USER_VERSION, VHOST_USER_VERSION_MASK,
+};
+
+/// How long the frontend waits for the backend to read a request or to reply to it. The requests
+/// are sent from the VMM thread, which must not hang on an unresponsive backend.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn socket_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+        _ => Error::Socket(err),
+    }
+}
+
+fn send_fds_error(err: utils::errno::Error) -> Error {
+    match err.errno() {
+        libc::EAGAIN => Error::Timeout,
+        _ => Error::SendFds(err),
+    }
+}
+
+/// The frontend end of a vhost-user connection.
+///
+/// All the requests are synchronous: the replies are read right after sending the requests which
+/// expect one. Each exchange fails with `Error::Timeout` if the backend stalls for longer than
+/// `SOCKET_TIMEOUT`.
+pub struct Frontend {
+    sock: UnixStream,
+}
+
+impl Frontend {
+    /// Connects to the backend listening on the Unix socket at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let sock = UnixStream::connect(path).map_err(Error::Socket)?;
+        Self::from_stream(sock)
+    }
+
+    /// Creates a frontend from an already connected socket.
+    pub fn from_stream(sock: UnixStream) -> Result<Self> {
+        sock.set_read_timeout(Some(SOCKET_TIMEOUT))
+            .map_err(Error::Socket)?;
+        sock.set_write_timeout(Some(SOCKET_TIMEOUT))
+            .map_err(Error::Socket)?;
+        Ok(Self { sock })
+    }
+
+    /// Claims the backend for this frontend. Must be the first request of the session.
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send_request(Request::SetOwner, &[], &[])
+    }
+
+    /// Returns the virtio features offered by the backend.
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.send_request(Request::GetFeatures, &[], &[])?;
+        Ok(self.recv_reply::<U64Payload>(Request::GetFeatures)?.value)
+    }
+
+    /// Sets the virtio features negotiated with the driver.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        let payload = U64Payload { value: features };
+        self.send_request(Request::SetFeatures, payload.as_slice(), &[])
+    }
+
+    /// Returns the vhost-user protocol features offered by the backend.
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.send_request(Request::GetProtocolFeatures, &[], &[])?;
+        Ok(self
+            .recv_reply::<U64Payload>(Request::GetProtocolFeatures)?
+            .value)
+    }
+
+    /// Sets the vhost-user protocol features used by this session.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        let payload = U64Payload { value: features };
+        self.send_request(Request::SetProtocolFeatures, payload.as_slice(), &[])
+    }
+
+    /// Returns the maximum number of queues supported by the backend.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.send_request(Request::GetQueueNum, &[], &[])?;
+        Ok(self.recv_reply::<U64Payload>(Request::GetQueueNum)?.value)
+    }
+
+    /// Reads `size` bytes of the device configuration space, starting at `offset`.
+    pub fn get_config(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        if size as usize > VHOST_USER_MAX_CONFIG_SIZE {
+            return Err(Error::InvalidReply);
+        }
+        let header = ConfigHeader {
+            offset,
+            size,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.resize(payload.len() + size as usize, 0);
+        self.send_request(Request::GetConfig, &payload, &[])?;
+
+        let reply = self.recv_reply_bytes(Request::GetConfig)?;
+        let header_len = std::mem::size_of::<ConfigHeader>();
+        if reply.len() != header_len + size as usize {
+            return Err(Error::InvalidReply);
+        }
+        let mut reply_header = ConfigHeader::default();
+        reply_header
+            .as_mut_slice()
+            .copy_from_slice(&reply[..header_len]);
+        if reply_header.offset != offset || reply_header.size != size {
+            return Err(Error::InvalidReply);
+        }
+        Ok(reply[header_len..].to_vec())
+    }
+
+    /// Shares the guest memory with the backend. Every region must be backed by a file, which the
+    /// backend maps in its own address space.
+    pub fn set_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > VHOST_USER_MAX_MEM_REGIONS {
+            return Err(Error::TooManyMemoryRegions(num_regions));
+        }
+
+        let header = MemoryHeader {
+            num_regions: num_regions as u32,
+            padding: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        let mut fds = Vec::with_capacity(num_regions);
+        for region in mem.iter() {
+            let file_offset = region.file_offset().ok_or(Error::NonSharedMemory)?;
+            // Writes to a private mapping are not visible to the backend, and the other way around.
+            if region.flags() & libc::MAP_SHARED == 0 {
+                return Err(Error::NonSharedMemory);
+            }
+            let memory_region = MemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: region.as_ptr() as u64,
+                mmap_offset: file_offset.start(),
+            };
+            payload.extend_from_slice(memory_region.as_slice());
+            fds.push(file_offset.file().as_raw_fd());
+        }
+
+        self.send_request(Request::SetMemTable, &payload, &fds)
+    }
+
+    /// Sets the number of descriptors of the vring.
+    pub fn set_vring_num(&mut self, index: u32, num: u16) -> Result<()> {
+        let payload = VringState {
+            index,
+            num: u32::from(num),
+        };
+        self.send_request(Request::SetVringNum, payload.as_slice(), &[])
+    }
+
+    /// Sets the location of the vring, translating the guest addresses of its areas to addresses
+    /// in the frontend address space.
+    pub fn set_vring_addr(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        index: u32,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+    ) -> Result<()> {
+        let host_addr = |addr| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(|_| Error::InvalidVringAddress)
+        };
+        let payload = VringAddr {
+            index,
+            flags: 0,
+            descriptor: host_addr(desc_table)?,
+            used: host_addr(used_ring)?,
+            available: host_addr(avail_ring)?,
+            log: 0,
+        };
+        self.send_request(Request::SetVringAddr, payload.as_slice(), &[])
+    }
+
+    /// Sets the index of the next available descriptor the backend should process.
+    pub fn set_vring_base(&mut self, index: u32, base: u16) -> Result<()> {
+        let payload = VringState {
+            index,
+            num: u32::from(base),
+        };
+        self.send_request(Request::SetVringBase, payload.as_slice(), &[])
+    }
+
+    /// Sets the eventfd the backend gets notified on when the driver makes buffers available.
+    pub fn set_vring_kick(&mut self, index: u32, fd: RawFd) -> Result<()> {
+        let payload = U64Payload {
+            value: u64::from(index),
+        };
+        self.send_request(Request::SetVringKick, payload.as_slice(), &[fd])
+    }
+
+    /// Sets the eventfd the backend signals when it uses buffers of the vring.
+    pub fn set_vring_call(&mut self, index: u32, fd: RawFd) -> Result<()> {
+        let payload = U64Payload {
+            value: u64::from(index),
+        };
+        self.send_request(Request::SetVringCall, payload.as_slice(), &[fd])
+    }
+
+    /// Enables or disables the processing of the vring.
+    pub fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()> {
+        let payload = VringState {
+            index,
+            num: u32::from(enable),
+        };
+        self.send_request(Request::SetVringEnable, payload.as_slice(), &[])
+    }
+
+    fn send_request(&mut self, request: Request, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let header = MsgHeader {
+            request: request as u32,
+            flags: VHOST_USER_VERSION,
+            size: payload.len() as u32,
+        };
+        let mut msg = header.as_slice().to_vec();
+        msg.extend_from_slice(payload);
+
+        if fds.is_empty() {
+            self.sock.write_all(&msg).map_err(socket_error)
+        } else {
+            // The file descriptors must arrive along with the first byte of the message, so the
+            // whole message is sent at once.
+            let sent = self
+                .sock
+                .send_with_fds(&[&msg[..]], fds)
+                .map_err(send_fds_error)?;
+            if sent != msg.len() {
+                return Err(Error::Socket(std::io::Error::from(
+                    std::io::ErrorKind::WriteZero,
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    fn recv_reply_bytes(&mut self, request: Request) -> Result<Vec<u8>> {
+        let mut header = MsgHeader::default();
+        self.sock
+            .read_exact(header.as_mut_slice())
+            .map_err(socket_error)?;
+        if header.request != request as u32 {
+            return Err(Error::UnexpectedReply(header.request));
+        }
+        if header.flags & VHOST_USER_VERSION_MASK != VHOST_USER_VERSION
+            || header.flags & VHOST_USER_REPLY_MASK == 0
+            || header.size as usize
+                > std::mem::size_of::<ConfigHeader>() + VHOST_USER_MAX_CONFIG_SIZE
+        {
+            return Err(Error::InvalidReply);
+        }
+
+        let mut payload = vec![0u8; header.size as usize];
+        self.sock.read_exact(&mut payload).map_err(socket_error)?;
+        Ok(payload)
+    }
+
+    fn recv_reply<T: ByteValued>(&mut self, request: Request) -> Result<T> {
+        let payload = self.recv_reply_bytes(request)?;
+        if payload.len() != std::mem::size_of::<T>() {
+            return Err(Error::InvalidReply);
+        }
+        // The payload buffer is not necessarily aligned for `T`, so copy it over.
+        let mut reply = T::default();
+        reply.as_mut_slice().copy_from_slice(&payload);
+        Ok(reply)
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the frontend side of the vhost-user protocol, used for handing the virtqueues of a
+//! device over to a backend running in a separate process.
+//!
+//! Only the subset of the protocol needed by Firecracker devices is implemented. The messages are
+//! defined in the [vhost-user specification](https://qemu-project.gitlab.io/qemu/interop/vhost-user.html).
+
+mod frontend;
+#[cfg(test)]
+pub(crate) mod test_utils;
+
+use vm_memory::ByteValued;
+
+pub use self::frontend::Frontend;
+
+/// Version of the protocol implemented by the frontend.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+/// Flag set by the backend on its replies.
+pub const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+/// Mask of the version bits of the message flags.
+pub const VHOST_USER_VERSION_MASK: u32 = 0x3;
+
+/// Feature bit offered by backends which support protocol features negotiation. It is never
+/// exposed to the guest driver.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+/// Protocol feature for backends supporting multiple queues.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+/// Protocol feature for backends exposing the device configuration space.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+
+/// Maximum number of guest memory regions that can be shared with a backend.
+pub const VHOST_USER_MAX_MEM_REGIONS: usize = 8;
+/// Maximum size of the device configuration space that can be read from a backend.
+pub const VHOST_USER_MAX_CONFIG_SIZE: usize = 256;
+
+/// Requests sent by the frontend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Request {
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    SetMemTable = 5,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    GetVringBase = 11,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    GetQueueNum = 17,
+    SetVringEnable = 18,
+    GetConfig = 24,
+}
+
+/// Errors associated with the vhost-user protocol.
+#[derive(Debug)]
+pub enum Error {
+    /// The backend replied with a malformed message.
+    InvalidReply,
+    /// The backend replied to a different request than the one sent.
+    UnexpectedReply(u32),
+    /// The backend doesn't implement a required feature.
+    MissingFeature(&'static str),
+    /// The guest memory region is not backed by a file which can be shared with the backend.
+    NonSharedMemory,
+    /// The guest memory has more regions than the backend can map.
+    TooManyMemoryRegions(usize),
+    /// The guest memory address of a vring is invalid.
+    InvalidVringAddress,
+    /// Error reading from or writing to the socket.
+    Socket(std::io::Error),
+    /// Error sending file descriptors over the socket.
+    SendFds(utils::errno::Error),
+    /// The backend didn't read a request or reply to it in time.
+    Timeout,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Header of every vhost-user message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MsgHeader {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+// SAFETY: Safe because MsgHeader only contains plain data.
+unsafe impl ByteValued for MsgHeader {}
+
+/// Payload of the requests carrying a single u64 value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct U64Payload {
+    pub value: u64,
+}
+
+// SAFETY: Safe because U64Payload only contains plain data.
+unsafe impl ByteValued for U64Payload {}
+
+/// Payload of the `SET_VRING_NUM`, `SET_VRING_BASE`, `GET_VRING_BASE` and `SET_VRING_ENABLE`
+/// requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+// SAFETY: Safe because VringState only contains plain data.
+unsafe impl ByteValued for VringState {}
+
+/// Payload of the `SET_VRING_ADDR` request. The addresses are virtual addresses of the frontend,
+/// which the backend translates using the regions of the memory table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub descriptor: u64,
+    pub used: u64,
+    pub available: u64,
+    pub log: u64,
+}
+
+// SAFETY: Safe because VringAddr only contains plain data and has no implicit padding.
+unsafe impl ByteValued for VringAddr {}
+
+/// Header of the `SET_MEM_TABLE` payload, followed by `num_regions` memory regions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryHeader {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+// SAFETY: Safe because MemoryHeader only contains plain data.
+unsafe impl ByteValued for MemoryHeader {}
+
+/// Guest memory region shared with the backend. The file descriptor backing the region is sent
+/// along with the message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+// SAFETY: Safe because MemoryRegion only contains plain data.
+unsafe impl ByteValued for MemoryRegion {}
+
+/// Header of the `GET_CONFIG` payload, followed by `size` bytes of configuration space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ConfigHeader {
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+// SAFETY: Safe because ConfigHeader only contains plain data.
+unsafe impl ByteValued for ConfigHeader {}
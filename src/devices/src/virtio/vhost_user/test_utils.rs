@@ -0,0 +1,173 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::tempdir::TempDir;
+use vm_memory::ByteValued;
+
+use super::{
+    ConfigHeader, MsgHeader, Request, U64Payload, VHOST_USER_REPLY_MASK, VHOST_USER_VERSION,
+};
+
+/// Message received by the test backend.
+pub(crate) struct ReceivedMsg {
+    pub request: u32,
+    pub payload: Vec<u8>,
+    /// The first file descriptor sent along with the message, if any.
+    pub file: Option<File>,
+}
+
+impl ReceivedMsg {
+    /// Interprets the payload as a `T`.
+    pub fn payload_as<T: ByteValued>(&self) -> T {
+        let mut value = T::default();
+        value
+            .as_mut_slice()
+            .copy_from_slice(&self.payload[..std::mem::size_of::<T>()]);
+        value
+    }
+}
+
+/// What the test backend replies to the frontend.
+#[derive(Clone, Default)]
+pub(crate) struct TestBackendConfig {
+    pub features: u64,
+    pub protocol_features: u64,
+    pub queue_num: u64,
+    pub config_space: Vec<u8>,
+}
+
+/// A vhost-user backend serving a single frontend connection on a separate thread. It replies to
+/// the requests which expect a reply and forwards all the messages it receives to the test.
+pub(crate) struct TestBackend {
+    // Removed on drop, along with the socket.
+    _dir: TempDir,
+    socket_path: PathBuf,
+    msg_rx: Receiver<ReceivedMsg>,
+}
+
+impl TestBackend {
+    pub fn new(config: TestBackendConfig) -> Self {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("vhost-user.sock");
+        // Bind before returning, so that the frontend can connect right away.
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let (msg_tx, msg_rx) = channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &config, &msg_tx);
+        });
+
+        TestBackend {
+            _dir: dir,
+            socket_path,
+            msg_rx,
+        }
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Returns the next message received from the frontend.
+    pub fn recv_msg(&self) -> ReceivedMsg {
+        self.msg_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No message received from the frontend")
+    }
+
+    /// Returns the messages received so far.
+    pub fn received_msgs(&self) -> Vec<ReceivedMsg> {
+        self.msg_rx.try_iter().collect()
+    }
+}
+
+fn serve(mut stream: UnixStream, config: &TestBackendConfig, msg_tx: &Sender<ReceivedMsg>) {
+    loop {
+        let mut header = MsgHeader::default();
+        let (len, file) = match stream.recv_with_fd(header.as_mut_slice()) {
+            Ok((0, _)) | Err(_) => return,
+            Ok(res) => res,
+        };
+        if stream
+            .read_exact(&mut header.as_mut_slice()[len..])
+            .is_err()
+        {
+            return;
+        }
+        let mut payload = vec![0u8; header.size as usize];
+        if stream.read_exact(&mut payload).is_err() {
+            return;
+        }
+
+        let reply = match header.request {
+            r if r == Request::GetFeatures as u32 => Some(
+                U64Payload {
+                    value: config.features,
+                }
+                .as_slice()
+                .to_vec(),
+            ),
+            r if r == Request::GetProtocolFeatures as u32 => Some(
+                U64Payload {
+                    value: config.protocol_features,
+                }
+                .as_slice()
+                .to_vec(),
+            ),
+            r if r == Request::GetQueueNum as u32 => Some(
+                U64Payload {
+                    value: config.queue_num,
+                }
+                .as_slice()
+                .to_vec(),
+            ),
+            r if r == Request::GetConfig as u32 => {
+                let mut config_header = ConfigHeader::default();
+                let header_len = std::mem::size_of::<ConfigHeader>();
+                config_header
+                    .as_mut_slice()
+                    .copy_from_slice(&payload[..header_len]);
+                let start = config_header.offset as usize;
+                let end = start + config_header.size as usize;
+                let mut reply = payload[..header_len].to_vec();
+                reply.extend_from_slice(&config.config_space[start..end]);
+                Some(reply)
+            }
+            _ => None,
+        };
+
+        // Forward the message before replying, so that it is available to the test as soon as
+        // the frontend gets the reply.
+        let received = ReceivedMsg {
+            request: header.request,
+            payload,
+            file,
+        };
+        if msg_tx.send(received).is_err() {
+            return;
+        }
+
+        if let Some(reply) = reply {
+            let reply_header = MsgHeader {
+                request: header.request,
+                flags: VHOST_USER_VERSION | VHOST_USER_REPLY_MASK,
+                size: reply.len() as u32,
+            };
+            let mut msg = reply_header.as_slice().to_vec();
+            msg.extend_from_slice(&reply);
+            if stream.write_all(&msg).is_err() {
+                return;
+            }
+        }
+    }
+}
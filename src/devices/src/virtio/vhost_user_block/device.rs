@@ -0,0 +1,604 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::{cmp, result};
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::{
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_TOPOLOGY,
+    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_F_VERSION_1,
+};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{ByteValued, GuestMemoryMmap};
+
+use super::Error;
+use crate::virtio::block::device::ConfigSpace;
+use crate::virtio::block::{DISCARD_CONFIG_SPACE_SIZE, MAX_NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::vhost_user::{
+    self, Frontend, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ,
+};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, IrqTrigger, Queue, VirtioDevice, TYPE_BLOCK,
+};
+
+// The features of the backend which are exposed to the guest driver.
+const SUPPORTED_FEATURES: u64 = (1 << VIRTIO_F_VERSION_1)
+    | (1 << VIRTIO_RING_F_EVENT_IDX)
+    | (1 << VIRTIO_BLK_F_SIZE_MAX)
+    | (1 << VIRTIO_BLK_F_SEG_MAX)
+    | (1 << VIRTIO_BLK_F_RO)
+    | (1 << VIRTIO_BLK_F_BLK_SIZE)
+    | (1 << VIRTIO_BLK_F_FLUSH)
+    | (1 << VIRTIO_BLK_F_TOPOLOGY)
+    | (1 << VIRTIO_BLK_F_MQ)
+    | (1 << VIRTIO_BLK_F_DISCARD)
+    | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+
+fn require_feature(features: u64, bit: u32, name: &'static str) -> result::Result<(), Error> {
+    if features & (1u64 << bit) == 0 {
+        return Err(Error::VhostUser(vhost_user::Error::MissingFeature(name)));
+    }
+    Ok(())
+}
+
+/// Virtio block device handing its queues over to a vhost-user backend.
+///
+/// The backend processes the requests directly from the guest memory. The device only forwards
+/// the queue notifications of the backend to the guest as interrupts.
+pub struct VhostUserBlock {
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    config_space: Vec<u8>,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    // Registered as ioeventfds for the queue notifications of the guest and handed over to the
+    // backend as kick eventfds.
+    pub(crate) queue_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    pub(crate) partuuid: Option<String>,
+    pub(crate) root_device: bool,
+    socket_path: String,
+    frontend: Frontend,
+    // Signaled by the backend when it uses buffers of the corresponding queue.
+    pub(crate) call_evts: Vec<EventFd>,
+}
+
+impl VhostUserBlock {
+    /// Create a new vhost-user block device, connected to the backend listening on the Unix
+    /// socket found at `socket_path`.
+    pub fn new(
+        id: String,
+        partuuid: Option<String>,
+        socket_path: String,
+        is_disk_read_only: bool,
+        is_disk_root: bool,
+        num_queues: usize,
+    ) -> result::Result<VhostUserBlock, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let mut frontend = Frontend::connect(&socket_path).map_err(Error::VhostUser)?;
+        frontend.set_owner().map_err(Error::VhostUser)?;
+
+        let backend_features = frontend.get_features().map_err(Error::VhostUser)?;
+        require_feature(backend_features, VIRTIO_F_VERSION_1, "VIRTIO_F_VERSION_1")?;
+        require_feature(
+            backend_features,
+            VHOST_USER_F_PROTOCOL_FEATURES,
+            "VHOST_USER_F_PROTOCOL_FEATURES",
+        )?;
+        if is_disk_read_only {
+            require_feature(backend_features, VIRTIO_BLK_F_RO, "VIRTIO_BLK_F_RO")?;
+        }
+
+        let backend_protocol_features =
+            frontend.get_protocol_features().map_err(Error::VhostUser)?;
+        require_feature(
+            backend_protocol_features,
+            VHOST_USER_PROTOCOL_F_CONFIG,
+            "VHOST_USER_PROTOCOL_F_CONFIG",
+        )?;
+        let mut protocol_features = 1u64 << VHOST_USER_PROTOCOL_F_CONFIG;
+
+        let mut avail_features = backend_features & SUPPORTED_FEATURES;
+        if num_queues > 1 {
+            require_feature(backend_features, VIRTIO_BLK_F_MQ, "VIRTIO_BLK_F_MQ")?;
+            require_feature(
+                backend_protocol_features,
+                VHOST_USER_PROTOCOL_F_MQ,
+                "VHOST_USER_PROTOCOL_F_MQ",
+            )?;
+            protocol_features |= 1u64 << VHOST_USER_PROTOCOL_F_MQ;
+        } else {
+            avail_features &= !(1u64 << VIRTIO_BLK_F_MQ);
+        }
+
+        frontend
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUser)?;
+
+        if num_queues > 1 {
+            let backend_num_queues = frontend.get_queue_num().map_err(Error::VhostUser)?;
+            if backend_num_queues < num_queues as u64 {
+                return Err(Error::InvalidNumQueues(num_queues));
+            }
+        }
+
+        // The backend exposes the properties of the disk through the configuration space, but
+        // the number of queues is decided by the frontend.
+        let config = frontend
+            .get_config(0, DISCARD_CONFIG_SPACE_SIZE as u32)
+            .map_err(Error::VhostUser)?;
+        let mut config_space = ConfigSpace::default();
+        config_space.as_mut_slice()[..DISCARD_CONFIG_SPACE_SIZE].copy_from_slice(&config);
+        config_space.num_queues = num_queues as u16;
+
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<_>, _>>()?;
+        let call_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<_>, _>>()?;
+
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
+        Ok(VhostUserBlock {
+            avail_features,
+            acked_features: 0u64,
+            config_space: config_space.as_slice()[..DISCARD_CONFIG_SPACE_SIZE].to_vec(),
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            queues,
+            queue_evts,
+            device_state: DeviceState::Inactive,
+            irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
+            id,
+            partuuid,
+            root_device: is_disk_root,
+            socket_path,
+            frontend,
+            call_evts,
+        })
+    }
+
+    /// Provides the ID of this block device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the path of the socket the backend listens on.
+    pub fn socket_path(&self) -> &String {
+        &self.socket_path
+    }
+
+    /// Provides the PARTUUID of this block device.
+    pub fn partuuid(&self) -> Option<&String> {
+        self.partuuid.as_ref()
+    }
+
+    /// Specifies if this block device is read only.
+    pub fn is_read_only(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
+    }
+
+    /// Specifies if this block device is the root device.
+    pub fn is_root_device(&self) -> bool {
+        self.root_device
+    }
+
+    /// Provides the number of request queues of this block device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    // Hands the guest memory and the queues over to the backend.
+    fn setup_backend(&mut self, mem: &GuestMemoryMmap) -> vhost_user::Result<()> {
+        // The protocol features bit is not exposed to the guest, but it has to be acked for the
+        // rings to be enabled by `SET_VRING_ENABLE`.
+        self.frontend
+            .set_features(self.acked_features | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES))?;
+        self.frontend.set_mem_table(mem)?;
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            let index = queue_index as u32;
+            self.frontend.set_vring_num(index, queue.actual_size())?;
+            self.frontend.set_vring_addr(
+                mem,
+                index,
+                queue.desc_table,
+                queue.avail_ring,
+                queue.used_ring,
+            )?;
+            self.frontend.set_vring_base(index, 0)?;
+            self.frontend
+                .set_vring_call(index, self.call_evts[queue_index].as_raw_fd())?;
+            self.frontend
+                .set_vring_kick(index, self.queue_evts[queue_index].as_raw_fd())?;
+            self.frontend.set_vring_enable(index, true)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        // None of the writable fields are offered to the driver.
+        error!("Failed to write config space");
+        METRICS.block.cfg_fails.inc();
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if let Err(err) = self.setup_backend(&mem) {
+            error!("Block: Cannot set up the vhost-user backend: {:?}", err);
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Block: Cannot write to activate_evt");
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixListener;
+
+    use utils::tempdir::TempDir;
+    use virtio_gen::virtio_blk::VIRTIO_BLK_F_SCSI;
+    use vm_memory::{Bytes, GuestAddress, GuestMemory};
+
+    use super::*;
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
+    use crate::virtio::vhost_user::test_utils::{TestBackend, TestBackendConfig};
+    use crate::virtio::vhost_user::{
+        MemoryHeader, MemoryRegion, Request, U64Payload, VringAddr, VringState,
+    };
+
+    pub(crate) fn default_backend_config() -> TestBackendConfig {
+        let mut config_space = ConfigSpace::default();
+        config_space.capacity = 0x100;
+        TestBackendConfig {
+            features: (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES)
+                | (1u64 << VIRTIO_BLK_F_FLUSH)
+                | (1u64 << VIRTIO_BLK_F_SCSI),
+            protocol_features: 1u64 << VHOST_USER_PROTOCOL_F_CONFIG,
+            queue_num: 1,
+            config_space: config_space.as_slice().to_vec(),
+        }
+    }
+
+    pub(crate) fn vhost_user_block(
+        backend: &TestBackend,
+        is_disk_read_only: bool,
+        num_queues: usize,
+    ) -> result::Result<VhostUserBlock, Error> {
+        VhostUserBlock::new(
+            String::from("test"),
+            None,
+            backend.socket_path().to_str().unwrap().to_string(),
+            is_disk_read_only,
+            false,
+            num_queues,
+        )
+    }
+
+    pub(crate) fn memfd_mem() -> GuestMemoryMmap {
+        vm_memory::create_memfd_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap()
+    }
+
+    fn assert_requests(backend: &TestBackend, requests: &[Request]) {
+        for request in requests {
+            assert_eq!(backend.recv_msg().request, *request as u32);
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let backend = TestBackend::new(default_backend_config());
+        let block = vhost_user_block(&backend, false, 1).unwrap();
+
+        assert_requests(
+            &backend,
+            &[
+                Request::SetOwner,
+                Request::GetFeatures,
+                Request::GetProtocolFeatures,
+            ],
+        );
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetProtocolFeatures as u32);
+        assert_eq!(
+            msg.payload_as::<U64Payload>().value,
+            1u64 << VHOST_USER_PROTOCOL_F_CONFIG
+        );
+        assert_requests(&backend, &[Request::GetConfig]);
+
+        // The features not supported by the device are not exposed to the driver.
+        assert_eq!(
+            block.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH)
+        );
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        assert!(!block.is_read_only());
+        assert_eq!(block.num_queues(), 1);
+        assert_eq!(block.socket_path(), backend.socket_path().to_str().unwrap());
+
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x100);
+    }
+
+    #[test]
+    fn test_new_multi_queue() {
+        let mut config = default_backend_config();
+        config.features |= 1u64 << VIRTIO_BLK_F_MQ;
+        config.protocol_features |= 1u64 << VHOST_USER_PROTOCOL_F_MQ;
+        config.queue_num = 4;
+
+        // The backend supports fewer queues than requested.
+        let backend = TestBackend::new(config.clone());
+        assert!(matches!(
+            vhost_user_block(&backend, false, 8),
+            Err(Error::InvalidNumQueues(8))
+        ));
+
+        let backend = TestBackend::new(config.clone());
+        let block = vhost_user_block(&backend, false, 2).unwrap();
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        let mut num_queues = [0u8; 2];
+        block.read_config(34, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        // The multi-queue feature is not exposed for a single queue.
+        let backend = TestBackend::new(config);
+        let block = vhost_user_block(&backend, false, 1).unwrap();
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+    }
+
+    #[test]
+    fn test_new_missing_features() {
+        let mut config = default_backend_config();
+        config.features &= !(1u64 << VHOST_USER_F_PROTOCOL_FEATURES);
+        let backend = TestBackend::new(config);
+        assert!(matches!(
+            vhost_user_block(&backend, false, 1),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "VHOST_USER_F_PROTOCOL_FEATURES"
+            )))
+        ));
+
+        let mut config = default_backend_config();
+        config.protocol_features = 0;
+        let backend = TestBackend::new(config);
+        assert!(matches!(
+            vhost_user_block(&backend, false, 1),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "VHOST_USER_PROTOCOL_F_CONFIG"
+            )))
+        ));
+
+        let backend = TestBackend::new(default_backend_config());
+        assert!(matches!(
+            vhost_user_block(&backend, true, 1),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "VIRTIO_BLK_F_RO"
+            )))
+        ));
+
+        let backend = TestBackend::new(default_backend_config());
+        assert!(matches!(
+            vhost_user_block(&backend, false, 2),
+            Err(Error::VhostUser(vhost_user::Error::MissingFeature(
+                "VIRTIO_BLK_F_MQ"
+            )))
+        ));
+
+        let backend = TestBackend::new(default_backend_config());
+        assert!(matches!(
+            vhost_user_block(&backend, false, 0),
+            Err(Error::InvalidNumQueues(0))
+        ));
+    }
+
+    #[test]
+    fn test_new_unresponsive_backend() {
+        // The connection is queued on the listener, but nobody ever reads the requests.
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("vhost-user.sock");
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+
+        assert!(matches!(
+            VhostUserBlock::new(
+                String::from("test"),
+                None,
+                socket_path.to_str().unwrap().to_string(),
+                false,
+                false,
+                1,
+            ),
+            Err(Error::VhostUser(vhost_user::Error::Timeout))
+        ));
+    }
+
+    #[test]
+    fn test_activate() {
+        let backend = TestBackend::new(default_backend_config());
+        let mut block = vhost_user_block(&backend, false, 1).unwrap();
+        backend.received_msgs();
+
+        let mem = memfd_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.set_acked_features(1u64 << VIRTIO_F_VERSION_1);
+        block.activate(mem.clone()).unwrap();
+        assert!(block.is_activated());
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetFeatures as u32);
+        assert_eq!(
+            msg.payload_as::<U64Payload>().value,
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+
+        // The guest memory is shared with the backend.
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetMemTable as u32);
+        assert_eq!(msg.payload_as::<MemoryHeader>().num_regions, 1);
+        let mut region = MemoryRegion::default();
+        region
+            .as_mut_slice()
+            .copy_from_slice(&msg.payload[std::mem::size_of::<MemoryHeader>()..]);
+        assert_eq!(region.guest_phys_addr, 0);
+        assert_eq!(region.memory_size, 0x10000);
+        assert_eq!(region.mmap_offset, 0);
+        assert_eq!(
+            region.userspace_addr,
+            mem.get_host_address(GuestAddress(0)).unwrap() as u64
+        );
+        mem.write_obj(0xABCDu16, GuestAddress(0x8000)).unwrap();
+        let mut buf = [0u8; 2];
+        msg.file.unwrap().read_exact_at(&mut buf, 0x8000).unwrap();
+        assert_eq!(u16::from_ne_bytes(buf), 0xABCD);
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringNum as u32);
+        assert_eq!(
+            msg.payload_as::<VringState>(),
+            VringState { index: 0, num: 16 }
+        );
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringAddr as u32);
+        let vring_addr = msg.payload_as::<VringAddr>();
+        assert_eq!(
+            vring_addr.descriptor,
+            mem.get_host_address(vq.dtable_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring_addr.available,
+            mem.get_host_address(vq.avail_start()).unwrap() as u64
+        );
+        assert_eq!(
+            vring_addr.used,
+            mem.get_host_address(vq.used_start()).unwrap() as u64
+        );
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringBase as u32);
+        assert_eq!(
+            msg.payload_as::<VringState>(),
+            VringState { index: 0, num: 0 }
+        );
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringCall as u32);
+        assert!(msg.file.is_some());
+
+        // The queue notifications of the guest reach the backend.
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringKick as u32);
+        block.queue_evts[0].write(1).unwrap();
+        let mut kick = [0u8; 8];
+        msg.file.unwrap().read_exact(&mut kick).unwrap();
+        assert_eq!(u64::from_ne_bytes(kick), 1);
+
+        let msg = backend.recv_msg();
+        assert_eq!(msg.request, Request::SetVringEnable as u32);
+        assert_eq!(
+            msg.payload_as::<VringState>(),
+            VringState { index: 0, num: 1 }
+        );
+    }
+
+    #[test]
+    fn test_activate_private_memory() {
+        let backend = TestBackend::new(default_backend_config());
+        let mut block = vhost_user_block(&backend, false, 1).unwrap();
+
+        // Anonymous private memory can't be shared with the backend.
+        let mem = default_mem();
+        assert!(matches!(
+            block.activate(mem),
+            Err(ActivateError::BadActivate)
+        ));
+        assert!(!block.is_activated());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn, IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+use crate::virtio::vhost_user_block::device::VhostUserBlock;
+use crate::virtio::{IrqType, VirtioDevice};
+
+impl VhostUserBlock {
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        for call_evt in &self.call_evts {
+            if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                error!("Failed to register call event: {}", err);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        debug!("vhost-user block: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!(
+                "Failed to consume vhost-user block activate event: {:?}",
+                err
+            );
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+
+    // The backend used buffers of a queue, so the driver has to be notified.
+    fn process_call_event(&self, queue_index: usize) {
+        if let Err(err) = self.call_evts[queue_index].read() {
+            error!("Failed to get call event: {:?}", err);
+            METRICS.block.event_fails.inc();
+        } else if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+            error!("Failed to signal used queue: {:?}", err);
+            METRICS.block.event_fails.inc();
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostUserBlock {
+    // Handle an event for a call eventfd of the backend.
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let supported_events = EventSet::IN;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "VhostUserBlock: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let activate_fd = self.activate_evt.as_raw_fd();
+            let maybe_call_index = self
+                .call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source);
+
+            match maybe_call_index {
+                Some(queue_index) => self.process_call_event(queue_index),
+                None if activate_fd == source => self.process_activate_event(ops),
+                None => warn!("VhostUserBlock: Spurious event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "VhostUserBlock: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        // The device is not persisted, so it is either created inactive, or this is called
+        // right after activation.
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::test_utils::TestBackend;
+    use crate::virtio::vhost_user::Request;
+    use crate::virtio::vhost_user_block::device::tests::{
+        default_backend_config, memfd_mem, vhost_user_block,
+    };
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let backend = TestBackend::new(default_backend_config());
+        let mut block = vhost_user_block(&backend, false, 1).unwrap();
+        let mem = memfd_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+
+        let block = Arc::new(Mutex::new(block));
+        let _id = event_manager.add_subscriber(block.clone());
+
+        // Only the activation event is registered so far.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+
+        block.lock().unwrap().activate(mem).unwrap();
+        // Process the activate event.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+
+        let mut call_file = None;
+        while call_file.is_none() {
+            let msg = backend.recv_msg();
+            if msg.request == Request::SetVringCall as u32 {
+                call_file = msg.file;
+            }
+        }
+
+        // The backend signals the call eventfd, which triggers an interrupt for the guest.
+        call_file.unwrap().write_all(&1u64.to_ne_bytes()).unwrap();
+        let ev_count = event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(block
+            .lock()
+            .unwrap()
+            .irq_trigger
+            .has_pending_irq(IrqType::Vring));
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio block device whose requests are processed by a vhost-user backend.
+
+pub mod device;
+pub mod event_handler;
+
+pub use self::device::VhostUserBlock;
+
+use crate::virtio::vhost_user;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The number of queues is either zero or above the supported maximum.
+    InvalidNumQueues(usize),
+    /// Error communicating with the vhost-user backend.
+    VhostUser(vhost_user::Error),
+    // Error opening eventfd.
+    EventFd(std::io::Error),
+    // Error creating an irqfd.
+    IrqTrigger(std::io::Error),
+}
@@ -0,0 +1,550 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing and writing Dynamic Host Configuration Protocol (DHCPv4)
+//! messages, carried by UDP datagrams.
+//!
+//! Only the fixed BOOTP fields and the options area are handled; the `sname` and `file` fields
+//! are left empty when writing, and the option overload mechanism is not supported.
+//!
+//! Details of the DHCP message format and options can be found at [1] [2].
+//!
+//! [1]: https://tools.ietf.org/html/rfc2131
+//! [2]: https://tools.ietf.org/html/rfc2132
+
+use std::net::Ipv4Addr;
+
+use super::bytes::{InnerBytes, NetworkBytes, NetworkBytesMut};
+use super::Incomplete;
+use crate::MacAddr;
+
+const OP_OFFSET: usize = 0;
+const HTYPE_OFFSET: usize = 1;
+const HLEN_OFFSET: usize = 2;
+const HOPS_OFFSET: usize = 3;
+const XID_OFFSET: usize = 4;
+const SECS_OFFSET: usize = 8;
+const FLAGS_OFFSET: usize = 10;
+const CIADDR_OFFSET: usize = 12;
+const YIADDR_OFFSET: usize = 16;
+const SIADDR_OFFSET: usize = 20;
+const GIADDR_OFFSET: usize = 24;
+const CHADDR_OFFSET: usize = 28;
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+
+// The length of the Ethernet hardware addresses carried by the messages.
+const ETHERNET_HLEN: u8 = 6;
+// Some BOOTP relays and clients drop messages shorter than this (RFC 1542).
+const MIN_MESSAGE_LEN: usize = 300;
+
+/// The UDP port DHCP servers listen on.
+pub const SERVER_PORT: u16 = 67;
+/// The UDP port DHCP clients listen on.
+pub const CLIENT_PORT: u16 = 68;
+
+/// The `op` of messages sent by clients.
+pub const OP_BOOTREQUEST: u8 = 1;
+/// The `op` of messages sent by servers.
+pub const OP_BOOTREPLY: u8 = 2;
+/// The `htype` of Ethernet hardware addresses.
+pub const HTYPE_ETHERNET: u8 = 1;
+/// The value identifying DHCP messages among BOOTP ones, right before the options.
+pub const MAGIC_COOKIE: u32 = 0x6382_5363;
+/// The bit of the `flags` field asking the server to broadcast its replies.
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+/// The length of the fixed part of a DHCP message, up to and including the magic cookie.
+pub const DHCP_HEADER_LEN: usize = OPTIONS_OFFSET;
+
+/// Padding option, without a length.
+pub const OPTION_PAD: u8 = 0;
+/// Subnet mask option.
+pub const OPTION_SUBNET_MASK: u8 = 1;
+/// Router (default gateway) option.
+pub const OPTION_ROUTER: u8 = 3;
+/// Domain name servers option.
+pub const OPTION_DNS_SERVERS: u8 = 6;
+/// Interface MTU option.
+pub const OPTION_INTERFACE_MTU: u8 = 26;
+/// Requested IP address option.
+pub const OPTION_REQUESTED_ADDRESS: u8 = 50;
+/// IP address lease time option.
+pub const OPTION_LEASE_TIME: u8 = 51;
+/// DHCP message type option.
+pub const OPTION_MESSAGE_TYPE: u8 = 53;
+/// Server identifier option.
+pub const OPTION_SERVER_ID: u8 = 54;
+/// Renewal (T1) time option.
+pub const OPTION_RENEWAL_TIME: u8 = 58;
+/// Rebinding (T2) time option.
+pub const OPTION_REBINDING_TIME: u8 = 59;
+/// End option, without a length, closing the options area.
+pub const OPTION_END: u8 = 255;
+
+/// Represents errors which may occur while parsing or writing a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The magic cookie does not identify a DHCP message.
+    MagicCookie,
+    /// The specified byte sequence is shorter than the fixed part of a DHCP message.
+    MessageTooShort,
+    /// An option runs past the end of the message.
+    InvalidOptions,
+    /// The options to be written do not fit in the buffer.
+    OptionsTooLong,
+}
+
+/// The type of a DHCP message, carried by the message type option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// Client broadcast to locate the available servers.
+    Discover = 1,
+    /// Server reply to a `Discover`, offering configuration parameters.
+    Offer = 2,
+    /// Client message requesting the offered parameters, or renewing a lease.
+    Request = 3,
+    /// Client message telling the server the address is already in use.
+    Decline = 4,
+    /// Server reply committing the configuration parameters.
+    Ack = 5,
+    /// Server reply refusing the client's notion of its address.
+    Nak = 6,
+    /// Client message relinquishing its address.
+    Release = 7,
+    /// Client message asking only for local configuration parameters.
+    Inform = 8,
+}
+
+impl MessageType {
+    /// Returns the message type with the `value` code, if any.
+    pub fn from_u8(value: u8) -> Option<MessageType> {
+        match value {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Decline),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            7 => Some(MessageType::Release),
+            8 => Some(MessageType::Inform),
+            _ => None,
+        }
+    }
+}
+
+/// Iterates over the `(code, value)` pairs of the options of a DHCP message, skipping the
+/// padding and stopping at the end option.
+pub struct DhcpOptions<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for DhcpOptions<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&code, rest) = self.bytes.split_first()?;
+            match code {
+                OPTION_PAD => self.bytes = rest,
+                OPTION_END => {
+                    self.bytes = &[];
+                    return None;
+                }
+                _ => {
+                    let (&len, rest) = rest.split_first()?;
+                    let len = usize::from(len);
+                    if rest.len() < len {
+                        self.bytes = &[];
+                        return None;
+                    }
+                    let (value, rest) = rest.split_at(len);
+                    self.bytes = rest;
+                    return Some((code, value));
+                }
+            }
+        }
+    }
+}
+
+/// Interprets the inner bytes as a DHCP message.
+pub struct DhcpMessage<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> DhcpMessage<'a, T> {
+    /// Interprets `bytes` as a DHCP message without any validity checks.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        DhcpMessage {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Interprets `bytes` as a DHCP message if possible or returns the reason for failing to do
+    /// so. The options must not run past the end of `bytes`.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        if bytes.len() < DHCP_HEADER_LEN {
+            return Err(Error::MessageTooShort);
+        }
+
+        let message = DhcpMessage::from_bytes_unchecked(bytes);
+        if message.magic_cookie() != MAGIC_COOKIE {
+            return Err(Error::MagicCookie);
+        }
+
+        let mut options = &message.bytes[OPTIONS_OFFSET..];
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPTION_PAD => options = rest,
+                OPTION_END => break,
+                _ => {
+                    let len = match rest.first() {
+                        Some(&len) => usize::from(len),
+                        None => return Err(Error::InvalidOptions),
+                    };
+                    options = rest.get(1 + len..).ok_or(Error::InvalidOptions)?;
+                }
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// Returns the `op` field of the message.
+    #[inline]
+    pub fn op(&self) -> u8 {
+        self.bytes[OP_OFFSET]
+    }
+
+    /// Returns the hardware address type of the message.
+    #[inline]
+    pub fn htype(&self) -> u8 {
+        self.bytes[HTYPE_OFFSET]
+    }
+
+    /// Returns the hardware address length of the message.
+    #[inline]
+    pub fn hlen(&self) -> u8 {
+        self.bytes[HLEN_OFFSET]
+    }
+
+    /// Returns the transaction ID of the message.
+    #[inline]
+    pub fn xid(&self) -> u32 {
+        self.bytes.ntohl_unchecked(XID_OFFSET)
+    }
+
+    /// Returns the `flags` field of the message.
+    #[inline]
+    pub fn flags(&self) -> u16 {
+        self.bytes.ntohs_unchecked(FLAGS_OFFSET)
+    }
+
+    /// Returns the client IP address of the message.
+    #[inline]
+    pub fn ciaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(CIADDR_OFFSET))
+    }
+
+    /// Returns the "your" (client) IP address of the message.
+    #[inline]
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(YIADDR_OFFSET))
+    }
+
+    /// Returns the next server IP address of the message.
+    #[inline]
+    pub fn siaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(SIADDR_OFFSET))
+    }
+
+    /// Returns the relay agent IP address of the message.
+    #[inline]
+    pub fn giaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(GIADDR_OFFSET))
+    }
+
+    /// Returns the client hardware address, interpreted as an Ethernet address.
+    #[inline]
+    pub fn chaddr(&self) -> MacAddr {
+        MacAddr::from_bytes_unchecked(
+            &self.bytes[CHADDR_OFFSET..CHADDR_OFFSET + usize::from(ETHERNET_HLEN)],
+        )
+    }
+
+    /// Returns the magic cookie of the message.
+    #[inline]
+    pub fn magic_cookie(&self) -> u32 {
+        self.bytes.ntohl_unchecked(MAGIC_COOKIE_OFFSET)
+    }
+
+    /// Returns an iterator over the options of the message.
+    #[inline]
+    pub fn options(&self) -> DhcpOptions {
+        DhcpOptions {
+            bytes: &self.bytes[OPTIONS_OFFSET..],
+        }
+    }
+
+    /// Returns the value of the first option with the `code` code, if any.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options()
+            .find(|&(option, _)| option == code)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the type carried by the message type option, if any.
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self.option(OPTION_MESSAGE_TYPE) {
+            Some(&[value]) => MessageType::from_u8(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the IPv4 address carried by the `code` option, if any.
+    pub fn option_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code) {
+            Some(&[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d)),
+            _ => None,
+        }
+    }
+
+    /// Returns the length of the message.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<'a, T: NetworkBytesMut> DhcpMessage<'a, T> {
+    /// Writes the fixed part of a DHCP message for an Ethernet client, whose options are still
+    /// missing. Every field not given here is zeroed.
+    pub fn write_incomplete(
+        buf: T,
+        op: u8,
+        xid: u32,
+        chaddr: MacAddr,
+    ) -> Result<Incomplete<Self>, Error> {
+        if buf.len() < DHCP_HEADER_LEN {
+            return Err(Error::MessageTooShort);
+        }
+
+        let mut message = DhcpMessage::from_bytes_unchecked(buf);
+        message.bytes[..DHCP_HEADER_LEN].fill(0);
+        message.bytes[OP_OFFSET] = op;
+        message.bytes[HTYPE_OFFSET] = HTYPE_ETHERNET;
+        message.bytes[HLEN_OFFSET] = ETHERNET_HLEN;
+        message.bytes[HOPS_OFFSET] = 0;
+        message.bytes.htonl_unchecked(XID_OFFSET, xid);
+        message.bytes.htons_unchecked(SECS_OFFSET, 0);
+        message.bytes[CHADDR_OFFSET..CHADDR_OFFSET + usize::from(ETHERNET_HLEN)]
+            .copy_from_slice(chaddr.get_bytes());
+        message
+            .bytes
+            .htonl_unchecked(MAGIC_COOKIE_OFFSET, MAGIC_COOKIE);
+
+        Ok(Incomplete::new(message))
+    }
+
+    /// Sets the `flags` field of the message.
+    #[inline]
+    pub fn set_flags(&mut self, flags: u16) -> &mut Self {
+        self.bytes.htons_unchecked(FLAGS_OFFSET, flags);
+        self
+    }
+
+    /// Sets the client IP address of the message.
+    #[inline]
+    pub fn set_ciaddr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.bytes.htonl_unchecked(CIADDR_OFFSET, u32::from(addr));
+        self
+    }
+
+    /// Sets the "your" (client) IP address of the message.
+    #[inline]
+    pub fn set_yiaddr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.bytes.htonl_unchecked(YIADDR_OFFSET, u32::from(addr));
+        self
+    }
+
+    /// Sets the next server IP address of the message.
+    #[inline]
+    pub fn set_siaddr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.bytes.htonl_unchecked(SIADDR_OFFSET, u32::from(addr));
+        self
+    }
+
+    /// Sets the relay agent IP address of the message.
+    #[inline]
+    pub fn set_giaddr(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.bytes.htonl_unchecked(GIADDR_OFFSET, u32::from(addr));
+        self
+    }
+}
+
+impl<'a, T: NetworkBytesMut> Incomplete<DhcpMessage<'a, T>> {
+    /// Transforms `self` into a `DhcpMessage<T>` by writing the `(code, value)` options followed
+    /// by the end option. The message is padded to the minimum BOOTP length, and the underlying
+    /// slice shrunk to the length of the message.
+    pub fn finalize(mut self, options: &[(u8, &[u8])]) -> Result<DhcpMessage<'a, T>, Error> {
+        let options_len = options
+            .iter()
+            .map(|(_, value)| 2 + value.len())
+            .sum::<usize>()
+            + 1;
+        let len = std::cmp::max(OPTIONS_OFFSET + options_len, MIN_MESSAGE_LEN);
+        if len > self.inner.bytes.len() || options.iter().any(|(_, value)| value.len() > 255) {
+            return Err(Error::OptionsTooLong);
+        }
+
+        let bytes = &mut self.inner.bytes[OPTIONS_OFFSET..len];
+        let mut offset = 0;
+        for (code, value) in options {
+            bytes[offset] = *code;
+            // Safe to cast because the length was checked above.
+            bytes[offset + 1] = value.len() as u8;
+            bytes[offset + 2..offset + 2 + value.len()].copy_from_slice(value);
+            offset += 2 + value.len();
+        }
+        bytes[offset] = OPTION_END;
+        bytes[offset + 1..].fill(OPTION_PAD);
+
+        self.inner.bytes.shrink_unchecked(len);
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    impl<'a, T: NetworkBytes> fmt::Debug for DhcpMessage<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(DHCP message)")
+        }
+    }
+
+    impl<'a, T: NetworkBytes> fmt::Debug for Incomplete<DhcpMessage<'a, T>> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(Incomplete DHCP message)")
+        }
+    }
+
+    #[test]
+    fn test_write_and_parse() {
+        let mut buf = [0xffu8; 1000];
+        let chaddr = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let addr = Ipv4Addr::new(10, 0, 0, 2);
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+
+        let mut incomplete =
+            DhcpMessage::write_incomplete(buf.as_mut(), OP_BOOTREPLY, 0x1234_5678, chaddr).unwrap();
+        incomplete
+            .inner_mut()
+            .set_flags(FLAG_BROADCAST)
+            .set_yiaddr(addr)
+            .set_siaddr(server);
+        let message = incomplete
+            .finalize(&[
+                (OPTION_MESSAGE_TYPE, &[MessageType::Offer as u8]),
+                (OPTION_SERVER_ID, &server.octets()),
+            ])
+            .unwrap();
+        assert_eq!(message.len(), MIN_MESSAGE_LEN);
+
+        let message = DhcpMessage::from_bytes(&buf[..MIN_MESSAGE_LEN]).unwrap();
+        assert_eq!(message.op(), OP_BOOTREPLY);
+        assert_eq!(message.htype(), HTYPE_ETHERNET);
+        assert_eq!(message.hlen(), ETHERNET_HLEN);
+        assert_eq!(message.xid(), 0x1234_5678);
+        assert_eq!(message.flags(), FLAG_BROADCAST);
+        assert_eq!(message.ciaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(message.yiaddr(), addr);
+        assert_eq!(message.siaddr(), server);
+        assert_eq!(message.giaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(message.chaddr(), chaddr);
+        assert_eq!(message.message_type(), Some(MessageType::Offer));
+        assert_eq!(message.option_addr(OPTION_SERVER_ID), Some(server));
+        assert_eq!(message.option_addr(OPTION_REQUESTED_ADDRESS), None);
+        assert_eq!(message.options().count(), 2);
+        // The bytes past the end option are padding.
+        assert!(buf[OPTIONS_OFFSET + 9..MIN_MESSAGE_LEN]
+            .iter()
+            .all(|&b| b == OPTION_PAD));
+        assert_eq!(buf[MIN_MESSAGE_LEN], 0xff);
+    }
+
+    #[test]
+    fn test_long_options() {
+        let mut buf = [0u8; 400];
+        let chaddr = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let value = [1u8; 100];
+        let message = DhcpMessage::write_incomplete(buf.as_mut(), OP_BOOTREQUEST, 1, chaddr)
+            .unwrap()
+            .finalize(&[(OPTION_DNS_SERVERS, &value), (OPTION_ROUTER, &value[..4])])
+            .unwrap();
+        assert_eq!(message.len(), OPTIONS_OFFSET + 102 + 6 + 1);
+        assert_eq!(message.option(OPTION_DNS_SERVERS), Some(&value[..]));
+
+        assert_eq!(
+            DhcpMessage::write_incomplete(buf.as_mut(), OP_BOOTREQUEST, 1, chaddr)
+                .unwrap()
+                .finalize(&[(OPTION_DNS_SERVERS, &value), (OPTION_ROUTER, &value)])
+                .unwrap_err(),
+            Error::OptionsTooLong
+        );
+        assert_eq!(
+            DhcpMessage::write_incomplete(&mut buf[..100], OP_BOOTREQUEST, 1, chaddr).unwrap_err(),
+            Error::MessageTooShort
+        );
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut buf = [0u8; DHCP_HEADER_LEN + 4];
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..DHCP_HEADER_LEN - 1]).unwrap_err(),
+            Error::MessageTooShort
+        );
+        assert_eq!(
+            DhcpMessage::from_bytes(buf.as_ref()).unwrap_err(),
+            Error::MagicCookie
+        );
+
+        buf[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        // Padding, then an option whose length runs past the end of the message.
+        buf[OPTIONS_OFFSET..].copy_from_slice(&[OPTION_PAD, OPTION_MESSAGE_TYPE, 3, 1]);
+        assert_eq!(
+            DhcpMessage::from_bytes(buf.as_ref()).unwrap_err(),
+            Error::InvalidOptions
+        );
+        // A message without options is valid.
+        buf[OPTIONS_OFFSET..].copy_from_slice(&[OPTION_END, 0, 0, 0]);
+        let message = DhcpMessage::from_bytes(buf.as_ref()).unwrap();
+        assert_eq!(message.message_type(), None);
+        // Message type options of the wrong length are ignored.
+        buf[OPTIONS_OFFSET..].copy_from_slice(&[OPTION_MESSAGE_TYPE, 2, 1, 1]);
+        let message = DhcpMessage::from_bytes(buf.as_ref()).unwrap();
+        assert_eq!(message.message_type(), None);
+        assert_eq!(message.option(OPTION_MESSAGE_TYPE), Some(&[1u8, 1][..]));
+    }
+
+    #[test]
+    fn test_message_type() {
+        for value in 1..=8 {
+            assert_eq!(MessageType::from_u8(value).unwrap() as u8, value);
+        }
+        assert_eq!(MessageType::from_u8(0), None);
+        assert_eq!(MessageType::from_u8(9), None);
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing Domain Name System (DNS) queries, and writing responses made of
+//! IPv4 address (A) records, carried by UDP datagrams.
+//!
+//! Only the first question of a query is parsed, and compressed names are not supported in
+//! questions, which is enough for the queries sent by stub resolvers.
+//!
+//! Details of the DNS message format can be found at [1].
+//!
+//! [1]: https://tools.ietf.org/html/rfc1035
+
+use std::net::Ipv4Addr;
+
+use super::bytes::{InnerBytes, NetworkBytes, NetworkBytesMut};
+
+const ID_OFFSET: usize = 0;
+const FLAGS_OFFSET: usize = 2;
+const QDCOUNT_OFFSET: usize = 4;
+const ANCOUNT_OFFSET: usize = 6;
+const NSCOUNT_OFFSET: usize = 8;
+const ARCOUNT_OFFSET: usize = 10;
+const QUESTION_OFFSET: usize = 12;
+
+// The longest name, in its wire format.
+const MAX_NAME_LEN: usize = 255;
+// The longest label of a name.
+const MAX_LABEL_LEN: usize = 63;
+// The length of an A record pointing to the name of the question.
+const A_RECORD_LEN: usize = 16;
+// A pointer to the name of the question, which starts right after the header.
+const QUESTION_NAME_POINTER: u16 = 0xc000 | QUESTION_OFFSET as u16;
+
+/// The UDP port DNS servers listen on.
+pub const DNS_PORT: u16 = 53;
+/// The length of the header of a DNS message.
+pub const DNS_HEADER_LEN: usize = QUESTION_OFFSET;
+
+/// The bit of the flags set in responses.
+pub const FLAG_QR: u16 = 0x8000;
+/// The bit of the flags set in authoritative answers.
+pub const FLAG_AA: u16 = 0x0400;
+/// The bit of the flags set in truncated messages.
+pub const FLAG_TC: u16 = 0x0200;
+/// The bit of the flags asking for a recursive resolution, copied to the responses.
+pub const FLAG_RD: u16 = 0x0100;
+const OPCODE_SHIFT: u16 = 11;
+const OPCODE_MASK: u16 = 0x0f;
+const RCODE_MASK: u16 = 0x0f;
+
+/// The opcode of standard queries.
+pub const OPCODE_QUERY: u8 = 0;
+
+/// The response code of successful responses.
+pub const RCODE_NO_ERROR: u8 = 0;
+/// The response code of responses to queries the server could not interpret.
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+/// The response code of responses to queries for names which don't exist.
+pub const RCODE_NAME_ERROR: u8 = 3;
+/// The response code of responses to queries of a kind the server doesn't support.
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+/// The response code of responses to queries the server refuses to answer.
+pub const RCODE_REFUSED: u8 = 5;
+
+/// The type of IPv4 address records.
+pub const TYPE_A: u16 = 1;
+/// The Internet class.
+pub const CLASS_IN: u16 = 1;
+
+/// Represents errors which may occur while parsing or writing a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The specified byte sequence is shorter than the DNS header length.
+    MessageTooShort,
+    /// The question runs past the end of the message.
+    QuestionTooShort,
+    /// The name of the question is too long, compressed, or not ASCII.
+    InvalidName,
+    /// The response to be written does not fit in the buffer.
+    ResponseTooLong,
+}
+
+/// The first question of a DNS message.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Question<'a> {
+    /// The name the question is about, in lowercase, with its labels separated by dots and
+    /// without the trailing dot.
+    pub name: String,
+    /// The type of the records asked for.
+    pub qtype: u16,
+    /// The class of the records asked for.
+    pub qclass: u16,
+    /// The question, in its wire format.
+    pub bytes: &'a [u8],
+}
+
+/// Interprets the inner bytes as a DNS message.
+pub struct DnsMessage<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> DnsMessage<'a, T> {
+    /// Interprets `bytes` as a DNS message without any validity checks.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        DnsMessage {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Interprets `bytes` as a DNS message if possible or returns the reason for failing to do
+    /// so. Only the length of the header is checked.
+    #[inline]
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        if bytes.len() < DNS_HEADER_LEN {
+            return Err(Error::MessageTooShort);
+        }
+        Ok(DnsMessage::from_bytes_unchecked(bytes))
+    }
+
+    /// Returns the ID of the message.
+    #[inline]
+    pub fn id(&self) -> u16 {
+        self.bytes.ntohs_unchecked(ID_OFFSET)
+    }
+
+    /// Returns the flags of the message, including its opcode and response code.
+    #[inline]
+    pub fn flags(&self) -> u16 {
+        self.bytes.ntohs_unchecked(FLAGS_OFFSET)
+    }
+
+    /// Returns the opcode of the message.
+    #[inline]
+    pub fn opcode(&self) -> u8 {
+        // Safe to cast because the opcode has 4 bits.
+        ((self.flags() >> OPCODE_SHIFT) & OPCODE_MASK) as u8
+    }
+
+    /// Returns the response code of the message.
+    #[inline]
+    pub fn rcode(&self) -> u8 {
+        // Safe to cast because the response code has 4 bits.
+        (self.flags() & RCODE_MASK) as u8
+    }
+
+    /// Returns the number of questions of the message.
+    #[inline]
+    pub fn qdcount(&self) -> u16 {
+        self.bytes.ntohs_unchecked(QDCOUNT_OFFSET)
+    }
+
+    /// Returns the number of answer records of the message.
+    #[inline]
+    pub fn ancount(&self) -> u16 {
+        self.bytes.ntohs_unchecked(ANCOUNT_OFFSET)
+    }
+
+    /// Returns the number of authority records of the message.
+    #[inline]
+    pub fn nscount(&self) -> u16 {
+        self.bytes.ntohs_unchecked(NSCOUNT_OFFSET)
+    }
+
+    /// Returns the number of additional records of the message.
+    #[inline]
+    pub fn arcount(&self) -> u16 {
+        self.bytes.ntohs_unchecked(ARCOUNT_OFFSET)
+    }
+
+    /// Parses the first question of the message, which must have one.
+    pub fn question(&self) -> Result<Question, Error> {
+        let bytes = &self.bytes[QUESTION_OFFSET..];
+        let mut labels = Vec::new();
+        let mut offset = 0;
+        loop {
+            let len = usize::from(*bytes.get(offset).ok_or(Error::QuestionTooShort)?);
+            offset += 1;
+            if len == 0 {
+                break;
+            }
+            // Compression pointers and extended label types have the top bits set.
+            if len > MAX_LABEL_LEN {
+                return Err(Error::InvalidName);
+            }
+            let label = bytes
+                .get(offset..offset + len)
+                .ok_or(Error::QuestionTooShort)?;
+            if !label.is_ascii() || label.contains(&b'.') {
+                return Err(Error::InvalidName);
+            }
+            labels.push(label);
+            offset += len;
+            if offset > MAX_NAME_LEN {
+                return Err(Error::InvalidName);
+            }
+        }
+
+        let fields = bytes
+            .get(offset..offset + 4)
+            .ok_or(Error::QuestionTooShort)?;
+        let name = labels
+            .iter()
+            // Safe to unwrap because the labels are ASCII.
+            .map(|label| std::str::from_utf8(label).unwrap().to_ascii_lowercase())
+            .collect::<Vec<_>>()
+            .join(".");
+        Ok(Question {
+            name,
+            qtype: u16::from_be_bytes([fields[0], fields[1]]),
+            qclass: u16::from_be_bytes([fields[2], fields[3]]),
+            bytes: &bytes[..offset + 4],
+        })
+    }
+
+    /// Returns the length of the message.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<'a, T: NetworkBytesMut> DnsMessage<'a, T> {
+    /// Writes a response echoing the `question` in its wire format, which may be empty, and
+    /// answering it with an A record of `ttl` seconds for each of `addrs`. The `flags` are
+    /// written as is, and the underlying slice is shrunk to the length of the response.
+    pub fn write_response(
+        buf: T,
+        id: u16,
+        flags: u16,
+        question: &[u8],
+        addrs: &[Ipv4Addr],
+        ttl: u32,
+    ) -> Result<Self, Error> {
+        let len = DNS_HEADER_LEN + question.len() + addrs.len() * A_RECORD_LEN;
+        if buf.len() < len || addrs.len() > usize::from(u16::MAX) {
+            return Err(Error::ResponseTooLong);
+        }
+
+        let mut message = DnsMessage::from_bytes_unchecked(buf);
+        message.bytes.htons_unchecked(ID_OFFSET, id);
+        message.bytes.htons_unchecked(FLAGS_OFFSET, flags);
+        message
+            .bytes
+            .htons_unchecked(QDCOUNT_OFFSET, u16::from(!question.is_empty()));
+        // Safe to cast because the number of addresses was checked above.
+        message
+            .bytes
+            .htons_unchecked(ANCOUNT_OFFSET, addrs.len() as u16);
+        message.bytes.htons_unchecked(NSCOUNT_OFFSET, 0);
+        message.bytes.htons_unchecked(ARCOUNT_OFFSET, 0);
+
+        let mut offset = QUESTION_OFFSET + question.len();
+        message.bytes[QUESTION_OFFSET..offset].copy_from_slice(question);
+        for addr in addrs {
+            message.bytes.htons_unchecked(offset, QUESTION_NAME_POINTER);
+            message.bytes.htons_unchecked(offset + 2, TYPE_A);
+            message.bytes.htons_unchecked(offset + 4, CLASS_IN);
+            message.bytes.htonl_unchecked(offset + 6, ttl);
+            message.bytes.htons_unchecked(offset + 10, 4);
+            message.bytes.htonl_unchecked(offset + 12, u32::from(*addr));
+            offset += A_RECORD_LEN;
+        }
+
+        message.bytes.shrink_unchecked(len);
+        Ok(message)
+    }
+}
+
+/// Returns the flags of a response to a query with the `query_flags` flags, holding the
+/// `rcode` response code.
+pub fn response_flags(query_flags: u16, rcode: u8, authoritative: bool) -> u16 {
+    let mut flags = FLAG_QR | (query_flags & ((OPCODE_MASK << OPCODE_SHIFT) | FLAG_RD));
+    if authoritative {
+        flags |= FLAG_AA;
+    }
+    flags | (u16::from(rcode) & RCODE_MASK)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    impl<'a, T: NetworkBytes> fmt::Debug for DnsMessage<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(DNS message)")
+        }
+    }
+
+    // A query for the A records of `Metadata.Internal`.
+    const QUERY: [u8; 34] = [
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 8, b'M', b'e',
+        b't', b'a', b'd', b'a', b't', b'a', 8, b'I', b'n', b't', b'e', b'r', b'n', b'a', b'l', 0,
+        0x00, 0x01, 0x00,
+    ];
+
+    fn query() -> Vec<u8> {
+        let mut query = QUERY.to_vec();
+        query.push(0x01);
+        query
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = query();
+        let message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+        assert_eq!(message.id(), 0x1234);
+        assert_eq!(message.flags(), FLAG_RD);
+        assert_eq!(message.opcode(), OPCODE_QUERY);
+        assert_eq!(message.rcode(), RCODE_NO_ERROR);
+        assert_eq!(message.qdcount(), 1);
+        assert_eq!(message.ancount(), 0);
+        assert_eq!(message.nscount(), 0);
+        assert_eq!(message.arcount(), 0);
+        assert_eq!(message.len(), query.len());
+        assert_eq!(
+            message.question().unwrap(),
+            Question {
+                name: String::from("metadata.internal"),
+                qtype: TYPE_A,
+                qclass: CLASS_IN,
+                bytes: &query[DNS_HEADER_LEN..],
+            }
+        );
+
+        assert_eq!(
+            DnsMessage::from_bytes(&query[..DNS_HEADER_LEN - 1]).unwrap_err(),
+            Error::MessageTooShort
+        );
+    }
+
+    #[test]
+    fn test_invalid_questions() {
+        // Truncated in a label, and in the type and class.
+        for len in [DNS_HEADER_LEN, 20, QUERY.len()] {
+            let message = DnsMessage::from_bytes(&QUERY[..len]).unwrap();
+            assert_eq!(message.question().unwrap_err(), Error::QuestionTooShort);
+        }
+
+        // A compressed name.
+        let mut query = query();
+        query[DNS_HEADER_LEN] = 0xc0;
+        let message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+        assert_eq!(message.question().unwrap_err(), Error::InvalidName);
+
+        // A label holding a dot, or a non-ASCII byte.
+        for byte in [b'.', 0x80] {
+            let mut query = query();
+            query[DNS_HEADER_LEN + 1] = byte;
+            let message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+            assert_eq!(message.question().unwrap_err(), Error::InvalidName);
+        }
+
+        // A name longer than 255 bytes.
+        let mut query = QUERY[..DNS_HEADER_LEN].to_vec();
+        for _ in 0..5 {
+            query.push(63);
+            query.extend_from_slice(&[b'a'; 63]);
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+        let message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+        assert_eq!(message.question().unwrap_err(), Error::InvalidName);
+
+        // The root name is valid.
+        let mut query = QUERY[..DNS_HEADER_LEN].to_vec();
+        query.extend_from_slice(&[0, 0, 2, 0, 1]);
+        let message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+        let question = message.question().unwrap();
+        assert_eq!(question.name, "");
+        assert_eq!(question.qtype, 2);
+    }
+
+    #[test]
+    fn test_write_response() {
+        let query = query();
+        let query_message = DnsMessage::from_bytes(query.as_slice()).unwrap();
+        let question = query_message.question().unwrap().bytes;
+        let addrs = [
+            Ipv4Addr::new(169, 254, 169, 254),
+            Ipv4Addr::new(10, 0, 0, 1),
+        ];
+        let flags = response_flags(FLAG_RD, RCODE_NO_ERROR, true);
+        assert_eq!(flags, FLAG_QR | FLAG_AA | FLAG_RD);
+
+        let mut buf = [0u8; 100];
+        let len = DnsMessage::write_response(buf.as_mut(), 0x1234, flags, question, &addrs, 60)
+            .unwrap()
+            .len();
+        assert_eq!(len, query.len() + 2 * A_RECORD_LEN);
+
+        let message = DnsMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(message.id(), 0x1234);
+        assert_eq!(message.flags(), flags);
+        assert_eq!(message.qdcount(), 1);
+        assert_eq!(message.ancount(), 2);
+        assert_eq!(message.question().unwrap().name, "metadata.internal");
+        assert_eq!(
+            &buf[query.len()..query.len() + A_RECORD_LEN],
+            &[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 169, 254, 169, 254]
+        );
+        assert_eq!(&buf[len - 4..len], &[10, 0, 0, 1]);
+
+        // A response without a question.
+        let flags = response_flags(0, RCODE_FORMAT_ERROR, false);
+        let message =
+            DnsMessage::write_response(buf.as_mut(), 0x1234, flags, &[], &[], 60).unwrap();
+        assert_eq!(message.len(), DNS_HEADER_LEN);
+        assert_eq!(message.qdcount(), 0);
+        assert_eq!(message.rcode(), RCODE_FORMAT_ERROR);
+
+        assert_eq!(
+            DnsMessage::write_response(&mut buf[..50], 0x1234, flags, question, &addrs, 60)
+                .unwrap_err(),
+            Error::ResponseTooLong
+        );
+    }
+}
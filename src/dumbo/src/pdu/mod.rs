@@ -14,6 +14,8 @@ use crate::pdu::ipv4::{PROTOCOL_TCP, PROTOCOL_UDP};
 
 pub mod arp;
 pub mod bytes;
+pub mod dhcp;
+pub mod dns;
 pub mod ethernet;
 pub mod ipv4;
 pub mod tcp;
@@ -9,7 +9,12 @@
 //!
 //! Aims to provide an easy-to-use interface, while making some Firecracker-specific simplifying
 //! assumptions. The crate does not currently aim at supporting all io_uring features and use
-//! cases. For example, it only works with pre-registered fds and read/write/fsync requests.
+//! cases. For example, it only works with pre-registered fds and a subset of the operations:
+//! (vectored) reads and writes, fsync, fdatasync, sync_file_range, fallocate, timeouts and polls.
+//! Read and write requests may also target pre-registered buffers, and operations may be linked
+//! so that they run in order. Operations other than read and write may not be supported by the
+//! host kernel, which can be checked with [`IoUring::is_supported`], or with
+//! [`probe::is_supported`] before setting up a ring.
 //!
 //! Requires at least kernel version 5.10.51.
 //! For more information on io_uring, refer to the man pages.
@@ -18,7 +23,7 @@
 #[allow(clippy::undocumented_unsafe_blocks)]
 mod bindings;
 pub mod operation;
-mod probe;
+pub mod probe;
 mod queue;
 pub mod restriction;
 
@@ -41,6 +46,8 @@ use utils::syscall::SyscallReturnCode;
 const REQUIRED_OPS: [OpCode; 2] = [OpCode::Read, OpCode::Write];
 // Taken from linux/fs/io_uring.c
 const IORING_MAX_FIXED_FILES: usize = 1 << 15;
+// Taken from linux/fs/io_uring.c
+const IORING_MAX_REG_BUFFERS: usize = 1 << 14;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -61,6 +68,10 @@ pub enum Error {
     NoRegisteredFds,
     /// Error probing the io_uring subsystem.
     Probe(IOError),
+    /// Attempted to register too many buffers.
+    RegisterBufferLimitExceeded,
+    /// Could not register buffers.
+    RegisterBuffers(IOError),
     /// Could not register eventfd.
     RegisterEventfd(IOError),
     /// Could not register file.
@@ -103,6 +114,9 @@ pub struct IoUring {
     // The total number of ops. These includes the ops on the submission queue, the in-flight ops
     // and the ops that are in the CQ, but haven't been popped yet.
     num_ops: u32,
+
+    // The opcodes supported by the host kernel, as reported by probing the ring.
+    supported_opcodes: HashSet<u8>,
 }
 
 impl IoUring {
@@ -115,11 +129,14 @@ impl IoUring {
     /// * `files` - Files to be registered for IO.
     /// * `restrictions` - Vector of [`Restriction`](restriction/enum.Restriction.html)s
     /// * `eventfd` - Optional eventfd for receiving completion notifications.
+    /// * `sqpoll_idle_ms` - If set, the submission queue is polled by a kernel thread, which goes
+    /// to sleep after being idle for the given number of milliseconds.
     pub fn new(
         num_entries: u32,
         files: Vec<&File>,
         restrictions: Vec<Restriction>,
         eventfd: Option<RawFd>,
+        sqpoll_idle_ms: Option<u32>,
     ) -> Result<Self> {
         let mut params = io_uring_params {
             // Create the ring as disabled, so that we may register restrictions.
@@ -128,6 +145,11 @@ impl IoUring {
             ..Default::default()
         };
 
+        if let Some(idle_ms) = sqpoll_idle_ms {
+            params.flags |= bindings::IORING_SETUP_SQPOLL;
+            params.sq_thread_idle = idle_ms;
+        }
+
         // SAFETY: Safe because values are valid and we check the return value.
         let fd = SyscallReturnCode(unsafe {
             libc::syscall(
@@ -153,6 +175,7 @@ impl IoUring {
             fd: file,
             registered_fds_count: 0,
             num_ops: 0,
+            supported_opcodes: HashSet::new(),
         };
 
         instance.check_operations()?;
@@ -296,6 +319,38 @@ impl IoUring {
         Ok(())
     }
 
+    /// Register buffers, which can then be referred to by their index in `buffers` from
+    /// [`read_fixed`](operation/struct.Operation.html#method.read_fixed) and
+    /// [`write_fixed`](operation/struct.Operation.html#method.write_fixed) operations.
+    ///
+    /// The kernel pins the memory backing the buffers until the ring is dropped. If the ring
+    /// has restrictions, they must include
+    /// [`AllowBufferRegistration`](restriction/enum.Restriction.html).
+    ///
+    /// # Safety
+    /// Unsafe because the kernel accesses the buffers for as long as the ring exists. It's up to
+    /// the caller to make sure that the memory outlives the ring.
+    pub unsafe fn register_buffers(&mut self, buffers: &[libc::iovec]) -> Result<()> {
+        if buffers.is_empty() {
+            // No-op.
+            return Ok(());
+        }
+
+        if buffers.len() > IORING_MAX_REG_BUFFERS {
+            return Err(Error::RegisterBufferLimitExceeded);
+        }
+
+        SyscallReturnCode(libc::syscall(
+            libc::SYS_io_uring_register,
+            self.fd.as_raw_fd(),
+            bindings::IORING_REGISTER_BUFFERS,
+            buffers.as_ptr(),
+            buffers.len(),
+        ) as libc::c_int)
+        .into_empty_result()
+        .map_err(Error::RegisterBuffers)
+    }
+
     fn register_eventfd(&self, fd: RawFd) -> Result<()> {
         // SAFETY: Safe because values are valid and we check the return value.
         SyscallReturnCode(unsafe {
@@ -349,7 +404,12 @@ impl IoUring {
         Ok(())
     }
 
-    fn check_operations(&self) -> Result<()> {
+    /// Check whether the host kernel supports an operation.
+    pub fn is_supported(&self, opcode: OpCode) -> bool {
+        self.supported_opcodes.contains(&(opcode as u8))
+    }
+
+    fn check_operations(&mut self) -> Result<()> {
         let mut probes = ProbeWrapper::new(PROBE_LEN).map_err(Error::Fam)?;
 
         // SAFETY: Safe because values are valid and we check the return value.
@@ -365,7 +425,7 @@ impl IoUring {
         .into_empty_result()
         .map_err(Error::Probe)?;
 
-        let supported_opcodes: HashSet<u8> = probes
+        self.supported_opcodes = probes
             .as_slice()
             .iter()
             .filter(|op| ((u32::from(op.flags)) & bindings::IO_URING_OP_SUPPORTED) != 0)
@@ -373,7 +433,7 @@ impl IoUring {
             .collect();
 
         for opcode in REQUIRED_OPS.iter() {
-            if !supported_opcodes.contains(&(*opcode as u8)) {
+            if !self.is_supported(*opcode) {
                 return Err(Error::UnsupportedOperation((*opcode).into()));
             }
         }
@@ -524,7 +584,7 @@ mod tests {
                 &proptest::collection::vec(arbitrary_rw_operation(FILE_LEN as u32), OPS_COUNT),
                 |set| {
                     let mut ring =
-                        IoUring::new(RING_SIZE, vec![&file_async], vec![], None).unwrap();
+                        IoUring::new(RING_SIZE, vec![&file_async], vec![], None, None).unwrap();
 
                     for mut operation in set {
                         // Perform the sync op.
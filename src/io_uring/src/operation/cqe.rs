@@ -5,6 +5,7 @@ use std::result::Result;
 
 use vm_memory::ByteValued;
 
+use super::OpData;
 use crate::bindings::io_uring_cqe;
 
 // SAFETY: Struct is POD and contains no references or niches.
@@ -13,7 +14,7 @@ unsafe impl ByteValued for io_uring_cqe {}
 /// Wrapper over a completed operation.
 pub struct Cqe<T> {
     res: i32,
-    user_data: Box<T>,
+    user_data: T,
 }
 
 impl<T> Cqe<T> {
@@ -21,12 +22,12 @@ impl<T> Cqe<T> {
     ///
     /// # Safety
     /// Unsafe because we assume full ownership of the inner.user_data address.
-    /// We assume that it points to a valid address created with a Box<T>, with the correct type T,
-    /// and that ownership of that address is passed to this function.
+    /// We assume that it points to a valid address created with a Box<OpData<T>>, with the
+    /// correct type T, and that ownership of that address is passed to this function.
     pub(crate) unsafe fn new(inner: io_uring_cqe) -> Self {
         Self {
             res: inner.res,
-            user_data: Box::from_raw(inner.user_data as *mut T),
+            user_data: Box::from_raw(inner.user_data as *mut OpData<T>).user_data,
         }
     }
 
@@ -50,13 +51,13 @@ impl<T> Cqe<T> {
     pub fn map_user_data<U, F: FnOnce(T) -> U>(self, op: F) -> Cqe<U> {
         Cqe {
             res: self.res,
-            user_data: Box::new(op(self.user_data())),
+            user_data: op(self.user_data),
         }
     }
 
     /// Consume the object and return the user_data.
     pub fn user_data(self) -> T {
-        *self.user_data
+        self.user_data
     }
 }
 
@@ -68,7 +69,7 @@ mod tests {
     fn test_result() {
         // Check that `result()` returns an `Error` when `res` is negative.
         {
-            let user_data = Box::new(10u8);
+            let user_data = Box::new(OpData::new(10u8));
 
             let cqe: Cqe<u8> = unsafe {
                 Cqe::new(io_uring_cqe {
@@ -86,7 +87,7 @@ mod tests {
 
         // Check that `result()` returns Ok() when `res` is positive.
         {
-            let user_data = Box::new(10u8);
+            let user_data = Box::new(OpData::new(10u8));
 
             let cqe: Cqe<u8> = unsafe {
                 Cqe::new(io_uring_cqe {
@@ -102,7 +103,7 @@ mod tests {
 
     #[test]
     fn test_user_data() {
-        let user_data = Box::new(10u8);
+        let user_data = Box::new(OpData::new(10u8));
 
         let cqe: Cqe<u8> = unsafe {
             Cqe::new(io_uring_cqe {
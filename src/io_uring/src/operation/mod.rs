@@ -9,6 +9,7 @@ mod sqe;
 #[cfg(test)]
 use core::fmt::{self, Debug, Formatter};
 use std::convert::From;
+use std::time::Duration;
 
 pub use cqe::Cqe;
 pub(crate) use sqe::Sqe;
@@ -29,6 +30,22 @@ pub enum OpCode {
     Write = bindings::IORING_OP_WRITE as u8,
     /// Fsync operation.
     Fsync = bindings::IORING_OP_FSYNC as u8,
+    /// Fallocate operation.
+    Fallocate = bindings::IORING_OP_FALLOCATE as u8,
+    /// Read operation into a registered buffer.
+    ReadFixed = bindings::IORING_OP_READ_FIXED as u8,
+    /// Write operation from a registered buffer.
+    WriteFixed = bindings::IORING_OP_WRITE_FIXED as u8,
+    /// Vectored read operation.
+    Readv = bindings::IORING_OP_READV as u8,
+    /// Vectored write operation.
+    Writev = bindings::IORING_OP_WRITEV as u8,
+    /// Sync file range operation.
+    SyncFileRange = bindings::IORING_OP_SYNC_FILE_RANGE as u8,
+    /// Timeout operation.
+    Timeout = bindings::IORING_OP_TIMEOUT as u8,
+    /// Poll operation.
+    PollAdd = bindings::IORING_OP_POLL_ADD as u8,
 }
 
 // Useful for outputting errors.
@@ -38,6 +55,34 @@ impl From<OpCode> for &'static str {
             OpCode::Read => "read",
             OpCode::Write => "write",
             OpCode::Fsync => "fsync",
+            OpCode::Fallocate => "fallocate",
+            OpCode::ReadFixed => "read_fixed",
+            OpCode::WriteFixed => "write_fixed",
+            OpCode::Readv => "readv",
+            OpCode::Writev => "writev",
+            OpCode::SyncFileRange => "sync_file_range",
+            OpCode::Timeout => "timeout",
+            OpCode::PollAdd => "poll_add",
+        }
+    }
+}
+
+/// The data passed to the kernel along with an operation, and handed back with its completion.
+///
+/// Besides the `user_data`, it holds the memory the kernel accesses by address for the operation,
+/// such as the `timespec` of a timeout, so that the memory stays valid until the operation
+/// completes. The kernel may only read it once the operation is submitted, which is done
+/// asynchronously when the ring polls the submission queue.
+pub(crate) struct OpData<T> {
+    pub(crate) user_data: T,
+    timespec: Option<libc::timespec>,
+}
+
+impl<T> OpData<T> {
+    pub(crate) fn new(user_data: T) -> Self {
+        Self {
+            user_data,
+            timespec: None,
         }
     }
 }
@@ -51,7 +96,10 @@ pub struct Operation<T> {
     pub(crate) len: Option<u32>,
     flags: u8,
     pub(crate) offset: Option<u64>,
-    user_data: Box<T>,
+    buf_index: Option<u16>,
+    // The flags specific to the opcode, such as the events of a poll operation.
+    op_flags: Option<u32>,
+    data: Box<OpData<T>>,
 }
 
 // Needed for proptesting.
@@ -84,7 +132,9 @@ impl<T> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
-            user_data: Box::new(user_data),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
         }
     }
 
@@ -97,7 +147,93 @@ impl<T> Operation<T> {
             len: Some(len),
             flags: 0,
             offset: Some(offset),
-            user_data: Box::new(user_data),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a read operation into the registered buffer with index `buf_index`.
+    ///
+    /// The `[addr, addr + len)` range must be contained in the registered buffer.
+    pub fn read_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: u16,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::ReadFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a write operation from the registered buffer with index `buf_index`.
+    ///
+    /// The `[addr, addr + len)` range must be contained in the registered buffer.
+    pub fn write_fixed(
+        fd: FixedFd,
+        addr: usize,
+        len: u32,
+        offset: u64,
+        buf_index: u16,
+        user_data: T,
+    ) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::WriteFixed,
+            addr: Some(addr),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: Some(buf_index),
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a vectored read operation into the `len` buffers described by the `iovec` array
+    /// at `iovecs`.
+    ///
+    /// The array must stay valid until the operation completes.
+    pub fn readv(fd: FixedFd, iovecs: usize, len: u32, offset: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Readv,
+            addr: Some(iovecs),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a vectored write operation from the `len` buffers described by the `iovec`
+    /// array at `iovecs`.
+    ///
+    /// The array must stay valid until the operation completes.
+    pub fn writev(fd: FixedFd, iovecs: usize, len: u32, offset: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Writev,
+            addr: Some(iovecs),
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
         }
     }
 
@@ -110,7 +246,107 @@ impl<T> Operation<T> {
             len: None,
             flags: 0,
             offset: None,
-            user_data: Box::new(user_data),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a fdatasync operation, which only flushes the metadata needed for reading back
+    /// the data.
+    pub fn fdatasync(fd: FixedFd, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Fsync,
+            addr: None,
+            len: None,
+            flags: 0,
+            offset: None,
+            buf_index: None,
+            op_flags: Some(bindings::IORING_FSYNC_DATASYNC),
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a sync file range operation.
+    ///
+    /// `flags` takes the same flags as the `sync_file_range` syscall (e.g.
+    /// `SYNC_FILE_RANGE_WRITE`). A `len` of 0 syncs up to the end of the file.
+    pub fn sync_file_range(fd: FixedFd, offset: u64, len: u32, flags: u32, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::SyncFileRange,
+            addr: None,
+            len: Some(len),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            op_flags: Some(flags),
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a timeout operation, which completes once the relative `timeout` expires, or
+    /// once `count` other operations complete if `count` isn't 0.
+    ///
+    /// The operation completes with `ETIME` when it expires. It doesn't access any file, but the
+    /// ring must have registered files, like for any other operation.
+    pub fn timeout(timeout: Duration, count: u32, user_data: T) -> Self {
+        // The kernel reads the `timespec` by address, possibly after the operation is pushed, so
+        // it is owned by the operation until it completes, along with the `user_data`.
+        let timespec = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        Self {
+            fd: 0,
+            opcode: OpCode::Timeout,
+            // Set to the address of the `timespec` once it is moved to the heap.
+            addr: None,
+            len: Some(1),
+            flags: 0,
+            offset: Some(u64::from(count)),
+            buf_index: None,
+            op_flags: Some(0),
+            data: Box::new(OpData {
+                user_data,
+                timespec: Some(timespec),
+            }),
+        }
+    }
+
+    /// Construct a poll operation, which completes once any of the `events` (e.g. `POLLIN`)
+    /// is signaled on the file. The result of the operation holds the signaled events.
+    pub fn poll_add(fd: FixedFd, events: u32, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::PollAdd,
+            addr: None,
+            len: None,
+            flags: 0,
+            offset: None,
+            buf_index: None,
+            op_flags: Some(events),
+            data: Box::new(OpData::new(user_data)),
+        }
+    }
+
+    /// Construct a fallocate operation.
+    ///
+    /// `mode` takes the same flags as the `fallocate` syscall (e.g. `FALLOC_FL_PUNCH_HOLE`).
+    pub fn fallocate(fd: FixedFd, offset: u64, len: u64, mode: u32, user_data: T) -> Self {
+        // The kernel expects the length of the range in the `addr` field and the mode in the
+        // `len` field of the sqe.
+        Self {
+            fd,
+            opcode: OpCode::Fallocate,
+            addr: Some(len as usize),
+            len: Some(mode),
+            flags: 0,
+            offset: Some(offset),
+            buf_index: None,
+            op_flags: None,
+            data: Box::new(OpData::new(user_data)),
         }
     }
 
@@ -120,20 +356,25 @@ impl<T> Operation<T> {
 
     /// Consumes the operation and returns the associated `user_data`.
     pub fn user_data(self) -> T {
-        *self.user_data
+        self.data.user_data
     }
 
-    // Needed for proptesting.
-    #[cfg(test)]
-    pub(crate) fn set_linked(&mut self) {
+    /// Link the operation to the next one pushed to the submission queue, which only starts once
+    /// this one completes successfully. If this one fails, the next one completes with
+    /// `ECANCELED`.
+    ///
+    /// If the ring has restrictions, they must include
+    /// [`AllowLinkedOps`](../restriction/enum.Restriction.html).
+    pub fn set_linked(&mut self) {
         self.flags |= 1 << bindings::IOSQE_IO_LINK_BIT;
     }
 
     /// Transform the operation into an `Sqe`.
     ///
     /// # Safety
-    /// Unsafe because we turn the Boxed user_data into a raw pointer contained in the sqe.
-    /// It's up to the caller to make sure that this value is freed (not leaked).
+    /// Unsafe because we turn the Boxed user_data, along with the memory the operation accesses
+    /// by address, into a raw pointer contained in the sqe. It's up to the caller to make sure
+    /// that this value is freed (not leaked), and only once the operation completes.
     pub(crate) unsafe fn into_sqe(self) -> Sqe {
         // Safe because all-zero value is valid. The sqe is made up of integers and raw pointers.
         let mut inner: io_uring_sqe = std::mem::zeroed();
@@ -154,7 +395,25 @@ impl<T> Operation<T> {
         if let Some(offset) = self.offset {
             inner.__bindgen_anon_1.off = offset;
         }
-        inner.user_data = Box::into_raw(self.user_data) as u64;
+
+        if let Some(buf_index) = self.buf_index {
+            inner.__bindgen_anon_4.__bindgen_anon_1.__bindgen_anon_1.buf_index = buf_index;
+        }
+
+        if let Some(op_flags) = self.op_flags {
+            match self.opcode {
+                OpCode::Fsync => inner.__bindgen_anon_3.fsync_flags = op_flags,
+                OpCode::SyncFileRange => inner.__bindgen_anon_3.sync_range_flags = op_flags,
+                OpCode::Timeout => inner.__bindgen_anon_3.timeout_flags = op_flags,
+                OpCode::PollAdd => inner.__bindgen_anon_3.poll32_events = op_flags,
+                _ => inner.__bindgen_anon_3.rw_flags = op_flags as bindings::__kernel_rwf_t,
+            }
+        }
+        let data = Box::into_raw(self.data);
+        if let Some(timespec) = (*data).timespec.as_ref() {
+            inner.__bindgen_anon_2.addr = timespec as *const libc::timespec as u64;
+        }
+        inner.user_data = data as u64;
 
         Sqe::new(inner)
     }
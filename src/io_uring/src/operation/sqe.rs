@@ -3,6 +3,7 @@
 
 use vm_memory::ByteValued;
 
+use super::OpData;
 use crate::bindings::io_uring_sqe;
 
 // SAFETY: Struct is POD and contains no references or niches.
@@ -21,9 +22,9 @@ impl Sqe {
     ///
     /// # Safety
     /// Safe only if you guarantee that this is a valid pointer to some memory where there is a
-    /// value of type T created from a Box<T>.
+    /// value of type OpData<T> created from a Box<OpData<T>>, which the kernel no longer accesses.
     pub(crate) unsafe fn user_data<T>(self) -> T {
-        *Box::from_raw(self.0.user_data as *mut T)
+        Box::from_raw(self.0.user_data as *mut OpData<T>).user_data
     }
 }
 
@@ -33,7 +34,7 @@ mod tests {
     use super::*;
     #[test]
     fn test_user_data() {
-        let user_data = Box::new(10u8);
+        let user_data = Box::new(OpData::new(10u8));
         let mut inner: io_uring_sqe = unsafe { std::mem::zeroed() };
         inner.user_data = Box::into_raw(user_data) as u64;
 
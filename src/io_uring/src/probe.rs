@@ -1,10 +1,14 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+//! Detection of the io_uring operations supported by the host kernel.
+
 use utils::fam::{FamStruct, FamStructWrapper};
 use utils::generate_fam_struct_impl;
 
 use crate::bindings::{io_uring_probe, io_uring_probe_op};
+use crate::operation::OpCode;
+use crate::IoUring;
 
 // There is no max for the number of operations returned by probing. So we fallback to using the
 // number of values representable in a u8;
@@ -20,3 +24,16 @@ generate_fam_struct_impl!(
 );
 
 pub(crate) type ProbeWrapper = FamStructWrapper<io_uring_probe>;
+
+/// Check whether the host kernel supports io_uring, along with all the `opcodes`, for rings whose
+/// submission queue is polled by a kernel thread if `sqpoll_idle_ms` is set.
+///
+/// Sets up a short-lived ring for probing the kernel, so it is best called once, before creating
+/// the rings which need the operations. The ring is set up like these rings, as setting up a
+/// polling thread may need privileges the process doesn't have.
+pub fn is_supported(opcodes: &[OpCode], sqpoll_idle_ms: Option<u32>) -> bool {
+    match IoUring::new(1, vec![], vec![], None, sqpoll_idle_ms) {
+        Ok(ring) => opcodes.iter().all(|opcode| ring.is_supported(*opcode)),
+        Err(_) => false,
+    }
+}
@@ -6,7 +6,7 @@ use std::mem;
 use std::num::Wrapping;
 use std::os::unix::io::RawFd;
 use std::result::Result;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{fence, Ordering};
 
 use utils::syscall::SyscallReturnCode;
 use vm_memory::{Bytes, MmapRegion, VolatileMemory, VolatileMemoryError};
@@ -34,11 +34,14 @@ pub(crate) struct SubmissionQueue {
     // Offsets.
     head_off: usize,
     tail_off: usize,
+    flags_off: usize,
 
     // Cached values.
     ring_mask: u32,
     count: u32,
     unmasked_tail: Wrapping<u32>,
+    // Whether the queue is polled by a kernel thread.
+    sqpoll: bool,
 
     // Mmap-ed ring.
     ring: MmapRegion,
@@ -70,10 +73,12 @@ impl SubmissionQueue {
             io_uring_fd,
             head_off: params.sq_off.head as usize,
             tail_off: params.sq_off.tail as usize,
+            flags_off: params.sq_off.flags as usize,
             ring_mask,
             count: params.sq_entries,
             // We can init this to 0 and cache it because we are the only ones modifying it.
             unmasked_tail: Wrapping(0),
+            sqpoll: params.flags & bindings::IORING_SETUP_SQPOLL != 0,
             ring,
             sqes,
             to_submit: 0,
@@ -131,6 +136,27 @@ impl SubmissionQueue {
         if min_complete > 0 {
             flags |= bindings::IORING_ENTER_GETEVENTS;
         }
+
+        if self.sqpoll {
+            // The kernel thread picks up the new entries on its own, unless it went to sleep.
+            // Order the tail update before reading the flags, as the thread sets the wakeup
+            // flag before checking the tail one last time.
+            fence(Ordering::SeqCst);
+            let sq_flags = self
+                .ring
+                .as_volatile_slice()
+                .load::<u32>(self.flags_off, Ordering::Acquire)?;
+            if sq_flags & bindings::IORING_SQ_NEED_WAKEUP != 0 {
+                flags |= bindings::IORING_ENTER_SQ_WAKEUP;
+            }
+
+            if flags == 0 {
+                let submitted = self.to_submit;
+                self.to_submit = 0;
+                return Ok(submitted);
+            }
+        }
+
         // SAFETY: Safe because values are valid and we check the return value.
         let submitted = SyscallReturnCode(unsafe {
             libc::syscall(
@@ -21,6 +21,10 @@ pub enum Restriction {
     AllowOpCode(OpCode),
     /// Only allow operations on pre-registered fds.
     RequireFixedFds,
+    /// Allow registering buffers once the ring is enabled.
+    AllowBufferRegistration,
+    /// Allow linking operations, so that they run in order.
+    AllowLinkedOps,
 }
 
 impl From<&Restriction> for bindings::io_uring_restriction {
@@ -39,6 +43,14 @@ impl From<&Restriction> for bindings::io_uring_restriction {
                 instance.opcode = bindings::IORING_RESTRICTION_SQE_FLAGS_REQUIRED as u16;
                 instance.__bindgen_anon_1.sqe_flags = 1 << bindings::IOSQE_FIXED_FILE_BIT;
             }
+            AllowBufferRegistration => {
+                instance.opcode = bindings::IORING_RESTRICTION_REGISTER_OP as u16;
+                instance.__bindgen_anon_1.register_op = bindings::IORING_REGISTER_BUFFERS as u8;
+            }
+            AllowLinkedOps => {
+                instance.opcode = bindings::IORING_RESTRICTION_SQE_FLAGS_ALLOWED as u16;
+                instance.__bindgen_anon_1.sqe_flags = 1 << bindings::IOSQE_IO_LINK_BIT;
+            }
         };
 
         instance
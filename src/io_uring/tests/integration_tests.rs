@@ -1,8 +1,9 @@
 // Copyright 2021 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs::File;
 use std::os::unix::fs::FileExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::thread;
 use std::time::Duration;
 
@@ -15,6 +16,7 @@ use vm_memory::{Bytes, MmapRegion, VolatileMemory};
 
 mod test_utils;
 use io_uring::operation::{OpCode, Operation};
+use io_uring::probe;
 use io_uring::restriction::Restriction;
 use io_uring::{Error, IoUring, SQueueError};
 
@@ -28,13 +30,13 @@ fn test_ring_new() {
 
     // Invalid entries count: 0.
     assert!(matches!(
-        IoUring::new(0, vec![], vec![], None),
+        IoUring::new(0, vec![], vec![], None, None),
         Err(Error::Setup(err)) if err.kind() == std::io::ErrorKind::InvalidInput
     ));
     // Try to register too many files.
     let dummy_file = TempFile::new().unwrap().into_file();
     assert!(matches!(
-        IoUring::new(10, vec![&dummy_file; 40000usize], vec![], None), // Max is 32768.
+        IoUring::new(10, vec![&dummy_file; 40000usize], vec![], None, None), // Max is 32768.
         Err(Error::RegisterFileLimitExceeded)
     ));
 }
@@ -47,7 +49,7 @@ fn test_eventfd() {
 
     let file = TempFile::new().unwrap().into_file();
     let mut ring =
-        IoUring::new(NUM_ENTRIES, vec![&file], vec![], Some(eventfd.as_raw_fd())).unwrap();
+        IoUring::new(NUM_ENTRIES, vec![&file], vec![], Some(eventfd.as_raw_fd()), None).unwrap();
     let user_data: u8 = 71;
     let buf = [0; 4];
     let epoll = Epoll::new().unwrap();
@@ -86,6 +88,7 @@ fn test_restrictions() {
                 Restriction::AllowOpCode(OpCode::Read),
             ],
             None,
+            None,
         )
         .unwrap();
         let buf = [0; 4];
@@ -106,6 +109,35 @@ fn test_restrictions() {
         assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
         assert!(unsafe { ring.pop::<u8>().unwrap().unwrap().result().is_err() });
     }
+
+    // Check that linking operations must be explicitly allowed.
+    {
+        let file = TempFile::new().unwrap().into_file();
+        let restrictions = || {
+            vec![
+                Restriction::RequireFixedFds,
+                Restriction::AllowOpCode(OpCode::Read),
+            ]
+        };
+        let buf = [0; 4];
+        let linked_read = || {
+            let mut op = Operation::read(0, buf.as_ptr() as usize, 4, 0, 71u8);
+            op.set_linked();
+            op
+        };
+
+        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], restrictions(), None, None).unwrap();
+        unsafe { ring.push(linked_read()).unwrap() };
+        assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+        assert!(unsafe { ring.pop::<u8>().unwrap().unwrap().result().is_err() });
+
+        let mut restrictions = restrictions();
+        restrictions.push(Restriction::AllowLinkedOps);
+        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], restrictions, None, None).unwrap();
+        unsafe { ring.push(linked_read()).unwrap() };
+        assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+        assert!(unsafe { ring.pop::<u8>().unwrap().unwrap().result().is_ok() });
+    }
 }
 
 #[test]
@@ -115,7 +147,7 @@ fn test_ring_push() {
     // Forgot to register file.
     {
         let buf = [0; 4];
-        let mut ring = IoUring::new(NUM_ENTRIES, vec![], vec![], None).unwrap();
+        let mut ring = IoUring::new(NUM_ENTRIES, vec![], vec![], None, None).unwrap();
 
         assert!(matches!(
             unsafe { ring.push(Operation::read(0, buf.as_ptr() as usize, 4, 0, 71)) },
@@ -127,7 +159,7 @@ fn test_ring_push() {
     // Now register file.
     {
         let file = TempFile::new().unwrap().into_file();
-        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
         let user_data: u8 = 71;
         let buf = [0; 4];
 
@@ -207,7 +239,7 @@ fn test_ring_submit() {
 
     {
         let file = TempFile::new().unwrap().into_file();
-        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+        let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
         let user_data: u8 = 71;
         let buf = [0; 4];
 
@@ -239,7 +271,7 @@ fn test_submit_and_wait_all() {
     skip_if_io_uring_unsupported!();
 
     let file = TempFile::new().unwrap().into_file();
-    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
     let user_data: u8 = 71;
     let buf = [0; 4];
 
@@ -296,7 +328,7 @@ fn test_write() {
     const NUM_BYTES: usize = 100;
     // Setup.
     let file = TempFile::new().unwrap().into_file();
-    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
 
     // Create & init a memory mapping for storing the write buffers.
     let mem_region: MmapRegion = MmapRegion::build(
@@ -337,7 +369,7 @@ fn test_read() {
     const NUM_BYTES: usize = 100;
     // Setup.
     let file = TempFile::new().unwrap().into_file();
-    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
 
     // Create & init a memory mapping for storing the read buffers.
     let mem_region: MmapRegion = MmapRegion::build(
@@ -363,3 +395,437 @@ fn test_read() {
     // Verify the result.
     assert_eq!(buf, &init_contents[..]);
 }
+
+#[test]
+fn test_fallocate() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that punching a hole in a file zeroes the range without changing the file size.
+
+    const NUM_BYTES: usize = 8192;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+
+    // Init the file with all ones.
+    file.write_all_at(&[1; NUM_BYTES], 0).unwrap();
+
+    // Punch a hole in the second half of the file.
+    let mode = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+    unsafe {
+        ring.push(Operation::fallocate(
+            0,
+            (NUM_BYTES / 2) as u64,
+            (NUM_BYTES / 2) as u64,
+            mode,
+            71u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), 0);
+    assert_eq!(cqe.user_data(), 71);
+
+    // Verify the result.
+    assert_eq!(file.metadata().unwrap().len(), NUM_BYTES as u64);
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf[..NUM_BYTES / 2], [1; NUM_BYTES / 2]);
+    assert_eq!(buf[NUM_BYTES / 2..], [0; NUM_BYTES / 2]);
+}
+
+#[test]
+fn test_probe() {
+    skip_if_io_uring_unsupported!();
+
+    let file = TempFile::new().unwrap().into_file();
+    let ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+
+    // All these operations are supported starting with kernel 5.6.
+    for opcode in [
+        OpCode::Read,
+        OpCode::Write,
+        OpCode::Readv,
+        OpCode::Writev,
+        OpCode::ReadFixed,
+        OpCode::WriteFixed,
+        OpCode::Fsync,
+        OpCode::SyncFileRange,
+        OpCode::Fallocate,
+        OpCode::Timeout,
+        OpCode::PollAdd,
+    ] {
+        assert!(ring.is_supported(opcode));
+        assert!(probe::is_supported(&[opcode], None));
+    }
+    assert!(probe::is_supported(&[], None));
+    assert!(probe::is_supported(&[OpCode::Read], Some(10)));
+}
+
+#[test]
+fn test_vectored_io() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that a vectored write gathers the buffers and that a vectored read scatters the data.
+
+    const NUM_BYTES: usize = 4096;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+    let mem_region: MmapRegion = MmapRegion::build(
+        None,
+        NUM_BYTES * 2,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+    )
+    .unwrap();
+    let iovecs = |offset: usize| {
+        [
+            libc::iovec {
+                iov_base: (mem_region.as_ptr() as usize + offset) as *mut libc::c_void,
+                iov_len: 512,
+            },
+            libc::iovec {
+                iov_base: (mem_region.as_ptr() as usize + offset + 512) as *mut libc::c_void,
+                iov_len: NUM_BYTES - 512,
+            },
+        ]
+    };
+
+    let init_contents: Vec<u8> = (0..NUM_BYTES).map(|i| i as u8).collect();
+    mem_region
+        .as_volatile_slice()
+        .write_slice(&init_contents, 0)
+        .unwrap();
+
+    // Write the first half of the region to the file.
+    let write_iovecs = iovecs(0);
+    unsafe {
+        ring.push(Operation::writev(
+            0,
+            write_iovecs.as_ptr() as usize,
+            write_iovecs.len() as u32,
+            0,
+            71u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    // Read it back into the second half.
+    let read_iovecs = iovecs(NUM_BYTES);
+    unsafe {
+        ring.push(Operation::readv(
+            0,
+            read_iovecs.as_ptr() as usize,
+            read_iovecs.len() as u32,
+            0,
+            72u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    // Verify the result.
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, &init_contents[..]);
+    mem_region
+        .as_volatile_slice()
+        .read_slice(&mut buf, NUM_BYTES)
+        .unwrap();
+    assert_eq!(buf, &init_contents[..]);
+}
+
+#[test]
+fn test_sync() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that the fdatasync and sync_file_range operations complete successfully.
+
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+    file.write_all_at(&[1; 4096], 0).unwrap();
+
+    unsafe { ring.push(Operation::fdatasync(0, 71u8)).unwrap() };
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    unsafe {
+        ring.push(Operation::sync_file_range(0, 0, 4096, flags, 72u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 2);
+    for _ in 0..2 {
+        let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+        assert_eq!(cqe.result().unwrap(), 0);
+    }
+
+    // Invalid flags are rejected.
+    unsafe {
+        ring.push(Operation::sync_file_range(0, 0, 4096, u32::MAX, 73u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap_err().raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+fn test_timeout() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that a timeout completes with ETIME once it expires.
+
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+
+    unsafe {
+        ring.push(Operation::timeout(Duration::from_millis(10), 0, 71u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.user_data(), 71);
+    assert_eq!(cqe.result().unwrap_err().raw_os_error(), Some(libc::ETIME));
+}
+
+#[test]
+fn test_poll() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that a poll operation completes once the file is ready.
+
+    let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    // The file owns a duplicate of the eventfd.
+    let file = unsafe { File::from_raw_fd(libc::dup(eventfd.as_raw_fd())) };
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+
+    unsafe {
+        ring.push(Operation::poll_add(0, libc::POLLIN as u32, 71u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit().unwrap(), 1);
+    // The eventfd isn't readable yet.
+    thread::sleep(Duration::from_millis(10));
+    assert!(unsafe { ring.pop::<u8>().unwrap().is_none() });
+
+    eventfd.write(1).unwrap();
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 0);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.user_data(), 71);
+    assert_ne!(cqe.result().unwrap() & libc::POLLIN as u32, 0);
+}
+
+#[test]
+fn test_linked_ops() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that linked operations run in order, and that the ones following a failed operation
+    // are cancelled.
+
+    const NUM_BYTES: usize = 4096;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, None).unwrap();
+    let init_contents: Vec<u8> = (0..NUM_BYTES).map(|i| i as u8).collect();
+    let mut buf = vec![0u8; NUM_BYTES];
+
+    // Write, then read back the same range.
+    let mut write = Operation::write(
+        0,
+        init_contents.as_ptr() as usize,
+        NUM_BYTES as u32,
+        0,
+        71u8,
+    );
+    write.set_linked();
+    unsafe {
+        ring.push(write).unwrap();
+        ring.push(Operation::read(
+            0,
+            buf.as_mut_ptr() as usize,
+            NUM_BYTES as u32,
+            0,
+            72u8,
+        ))
+        .unwrap();
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 2);
+    for user_data in [71, 72] {
+        let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+        assert_eq!(cqe.user_data(), user_data);
+        assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+    }
+    assert_eq!(buf, init_contents);
+
+    // Punching a hole without keeping the size isn't supported, so the linked fsync is
+    // cancelled.
+    let mut fallocate = Operation::fallocate(0, 0, 512, libc::FALLOC_FL_PUNCH_HOLE as u32, 73u8);
+    fallocate.set_linked();
+    unsafe {
+        ring.push(fallocate).unwrap();
+        ring.push(Operation::fsync(0, 74u8)).unwrap();
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 2);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.user_data(), 73);
+    assert!(cqe.result().is_err());
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.user_data(), 74);
+    assert_eq!(
+        cqe.result().unwrap_err().raw_os_error(),
+        Some(libc::ECANCELED)
+    );
+}
+
+#[test]
+fn test_fixed_buffers() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that writing from and reading into a registered buffer works correctly.
+
+    const NUM_BYTES: usize = 4096;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let restrictions = || {
+        vec![
+            Restriction::RequireFixedFds,
+            Restriction::AllowOpCode(OpCode::ReadFixed),
+            Restriction::AllowOpCode(OpCode::WriteFixed),
+        ]
+    };
+    let mem_region: MmapRegion = MmapRegion::build(
+        None,
+        NUM_BYTES * 2,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+    )
+    .unwrap();
+    let iovec = libc::iovec {
+        iov_base: mem_region.as_ptr().cast::<libc::c_void>(),
+        iov_len: mem_region.size(),
+    };
+
+    // Registering buffers on a restricted ring must be explicitly allowed.
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], restrictions(), None, None).unwrap();
+    assert!(matches!(
+        unsafe { ring.register_buffers(&[iovec]) },
+        Err(Error::RegisterBuffers(_))
+    ));
+
+    let mut restrictions = restrictions();
+    restrictions.push(Restriction::AllowBufferRegistration);
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], restrictions, None, None).unwrap();
+    unsafe { ring.register_buffers(&[iovec]) }.unwrap();
+
+    // Write the first half of the buffer to the file.
+    let init_contents: Vec<u8> = (0..NUM_BYTES).map(|i| i as u8).collect();
+    mem_region
+        .as_volatile_slice()
+        .write_slice(&init_contents, 0)
+        .unwrap();
+    unsafe {
+        ring.push(Operation::write_fixed(
+            0,
+            mem_region.as_ptr() as usize,
+            NUM_BYTES as u32,
+            0,
+            0,
+            71u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    // Read it back into the second half.
+    unsafe {
+        ring.push(Operation::read_fixed(
+            0,
+            mem_region.as_ptr() as usize + NUM_BYTES,
+            NUM_BYTES as u32,
+            0,
+            0,
+            72u8,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), NUM_BYTES as u32);
+
+    // Verify the result.
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, &init_contents[..]);
+    mem_region
+        .as_volatile_slice()
+        .read_slice(&mut buf, NUM_BYTES)
+        .unwrap();
+    assert_eq!(buf, &init_contents[..]);
+
+    // Operations outside of the registered buffer fail.
+    let buf = [0u8; 4];
+    unsafe {
+        ring.push(Operation::read_fixed(0, buf.as_ptr() as usize, 4, 0, 0, 73u8))
+            .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    assert!(unsafe { ring.pop::<u8>().unwrap().unwrap().result().is_err() });
+}
+
+#[test]
+fn test_sqpoll() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that the operations are submitted by the kernel polling thread, including after it
+    // went to sleep.
+
+    const NUM_BYTES: usize = 100;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = match IoUring::new(NUM_ENTRIES, vec![&file], vec![], None, Some(10)) {
+        Ok(ring) => ring,
+        // Polling the submission queue requires CAP_SYS_ADMIN on kernels older than 5.11.
+        Err(Error::Setup(err)) if err.raw_os_error() == Some(libc::EPERM) => return,
+        Err(err) => panic!("Unexpected error: {:?}", err),
+    };
+
+    let mem_region: MmapRegion = MmapRegion::build(
+        None,
+        NUM_BYTES as usize,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+    )
+    .unwrap();
+
+    let init_contents: Vec<u8> = (0..(NUM_BYTES as u8)).collect();
+    file.write_all_at(&init_contents, 0).unwrap();
+
+    // Perform the IO.
+    drive_submission_and_completion(&mut ring, &mem_region, OpCode::Read, NUM_BYTES);
+
+    // Let the polling thread go to sleep and check that it's woken up on submission.
+    thread::sleep(Duration::from_millis(100));
+    let buf = [0u8; 4];
+    unsafe { ring.push(Operation::read(0, buf.as_ptr() as usize, 4, 0, 71u8)) }.unwrap();
+    assert_eq!(ring.submit().unwrap(), 1);
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 0);
+    let cqe = unsafe { ring.pop::<u8>().unwrap().unwrap() };
+    assert_eq!(cqe.result().unwrap(), 4);
+
+    let mut buf = [0; NUM_BYTES];
+    mem_region
+        .as_volatile_slice()
+        .read_slice(&mut buf, 0)
+        .unwrap();
+    // Verify the result.
+    assert_eq!(buf, &init_contents[..]);
+}
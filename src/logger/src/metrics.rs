@@ -365,6 +365,8 @@ pub struct GetRequestsMetrics {
     pub machine_cfg_count: SharedIncMetric,
     /// Number of GETs for getting mmds.
     pub mmds_count: SharedIncMetric,
+    /// Number of GETs for getting the state of a network interface.
+    pub network_count: SharedIncMetric,
     /// Number of GETs for getting the VMM version.
     pub vmm_version_count: SharedIncMetric,
 }
@@ -490,11 +492,31 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedIncMetric,
     /// Number of successful write operations.
     pub write_count: SharedIncMetric,
+    /// Number of bytes discarded by this block device.
+    pub discard_bytes: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of bytes zeroed by this block device through write zeroes operations.
+    pub write_zeroes_bytes: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
     /// Number of virtio events throttled because of the IO engine.
     /// This happens when the io_uring submission queue is full.
     pub io_engine_throttled_events: SharedIncMetric,
+    /// Number of drives with the `Auto` IO engine which selected the `Async` engine.
+    pub io_engine_auto_async_count: SharedIncMetric,
+    /// Number of drives with the `Auto` IO engine which fell back to the `Sync` engine.
+    pub io_engine_auto_sync_count: SharedIncMetric,
+    /// Number of reads which failed the integrity verification against the hash tree.
+    pub verity_fails: SharedIncMetric,
+    /// Number of failures while writing the trace of the requests.
+    pub trace_fails: SharedIncMetric,
+    /// Number of times the connection to the NBD server was lost.
+    pub nbd_disconnects: SharedIncMetric,
+    /// Number of successful reconnections to the NBD server.
+    pub nbd_reconnects: SharedIncMetric,
 }
 
 /// Metrics specific to the i8042 device.
@@ -552,6 +574,10 @@ pub struct MmdsMetrics {
     pub connections_created: SharedIncMetric,
     /// The number of connections cleaned up by the MMDS TCP handler.
     pub connections_destroyed: SharedIncMetric,
+    /// The number of DNS queries answered by the MMDS.
+    pub dns_queries: SharedIncMetric,
+    /// The number of DNS messages dropped or answered with an error by the MMDS.
+    pub dns_bad_queries: SharedIncMetric,
 }
 
 /// Network-related metrics.
@@ -563,6 +589,40 @@ pub struct NetDeviceMetrics {
     pub cfg_fails: SharedIncMetric,
     //// Number of times the mac address was updated through the config space.
     pub mac_address_updates: SharedIncMetric,
+    /// Number of times the link state reported to the driver was updated.
+    pub link_state_updates: SharedIncMetric,
+    /// Number of events associated with the control queue.
+    pub ctrl_queue_event_count: SharedIncMetric,
+    /// Number of commands received on the control queue which failed or were rejected.
+    pub ctrl_fails: SharedIncMetric,
+    /// Number of failures while writing the capture of the frames.
+    pub capture_fails: SharedIncMetric,
+    /// Number of frames left out of the capture because the capture file fell behind.
+    pub capture_dropped_frames: SharedIncMetric,
+    /// Number of frames dropped by the TX filter because of their source MAC.
+    pub tx_filter_mac_drops: SharedIncMetric,
+    /// Number of frames dropped by the TX filter because of their source IPv4 address.
+    pub tx_filter_ip_drops: SharedIncMetric,
+    /// Number of frames dropped by the TX filter rules.
+    pub tx_filter_rule_drops: SharedIncMetric,
+    /// Number of frames dropped by the TX filter because of their invalid headers.
+    pub tx_filter_malformed_drops: SharedIncMetric,
+    /// Number of received frames dropped by the receive filter programmed by the driver.
+    pub rx_filtered_frames: SharedIncMetric,
+    /// Number of DHCP messages sent by the guest and handled by the built-in DHCP server.
+    pub dhcp_requests: SharedIncMetric,
+    /// Number of invalid DHCP messages sent by the guest and dropped by the DHCP server.
+    pub dhcp_bad_requests: SharedIncMetric,
+    /// Number of replies sent to the guest by the built-in DHCP server.
+    pub dhcp_replies: SharedIncMetric,
+    /// Number of connections and UDP flows opened by the user-mode NAT backend.
+    pub nat_flows_created: SharedIncMetric,
+    /// Number of host sockets the user-mode NAT backend couldn't open or use.
+    pub nat_flow_fails: SharedIncMetric,
+    /// Number of frames sent by the guest and dropped by the user-mode NAT backend.
+    pub nat_dropped_frames: SharedIncMetric,
+    /// Number of frames for the guest dropped by the user-mode NAT backend.
+    pub nat_rx_dropped_frames: SharedIncMetric,
     /// No available buffer for the net device rx queue.
     pub no_rx_avail_buffer: SharedIncMetric,
     /// No available buffer for the net device tx queue.
@@ -0,0 +1,359 @@
+// Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal DNS responder, answering the queries sent to the MMDS address for the IPv4 address
+//! of `metadata.internal`, and of the host names configured by the user.
+//!
+//! The responder is authoritative for those names only, and doesn't forward any query: the
+//! queries for other names get a name error (NXDOMAIN) response.
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use dumbo::pdu::dns::{
+    response_flags, DnsMessage, CLASS_IN, DNS_HEADER_LEN, FLAG_QR, OPCODE_QUERY,
+    RCODE_FORMAT_ERROR, RCODE_NAME_ERROR, RCODE_NOT_IMPLEMENTED, RCODE_NO_ERROR, RCODE_REFUSED,
+    TYPE_A,
+};
+use serde::{Deserialize, Serialize};
+
+/// The host name always resolving to the MMDS address.
+pub const METADATA_HOST_NAME: &str = "metadata.internal";
+/// The time to live of the records sent to the guest.
+pub const RECORD_TTL_SECS: u32 = 60;
+
+// The longest host name, in its dotted format.
+const MAX_NAME_LEN: usize = 253;
+// The longest label of a host name.
+const MAX_LABEL_LEN: usize = 63;
+// The type of the queries asking for all the records of a name.
+const TYPE_ANY: u16 = 255;
+// The length of an A record in a response.
+const A_RECORD_LEN: usize = 16;
+
+/// List of errors an invalid DNS configuration can throw.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    /// A host name is not a valid DNS name
+    #[error("Invalid DNS host name: {0}.")]
+    InvalidName(String),
+    /// A record is configured for the reserved host name
+    #[error("The {METADATA_HOST_NAME} host name always resolves to the MMDS address.")]
+    ReservedName,
+    /// The same host name is configured twice
+    #[error("Duplicate DNS host name: {0}.")]
+    DuplicateName(String),
+}
+
+/// Configuration of the DNS responder of the MMDS.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    /// The IPv4 addresses of the host names resolved besides `metadata.internal`.
+    #[serde(default)]
+    pub records: BTreeMap<String, Ipv4Addr>,
+}
+
+// Returns the lowercase, dotted form of `name` without its trailing dot, or `None` if it isn't a
+// valid host name.
+fn normalize_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return None;
+    }
+
+    let valid_labels = name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LEN
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    });
+
+    valid_labels.then(|| name.to_ascii_lowercase())
+}
+
+impl DnsConfig {
+    /// Checks that the configured host names are valid, distinct DNS names.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut names = Vec::with_capacity(self.records.len());
+        for name in self.records.keys() {
+            let normalized =
+                normalize_name(name).ok_or_else(|| Error::InvalidName(name.clone()))?;
+            if normalized == METADATA_HOST_NAME {
+                return Err(Error::ReservedName);
+            }
+            if names.contains(&normalized) {
+                return Err(Error::DuplicateName(name.clone()));
+            }
+            names.push(normalized);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the address `name` resolves to, given the `mmds_addr` MMDS address. The name is
+    /// expected in its lowercase, dotted form, without the trailing dot.
+    pub fn resolve(&self, name: &str, mmds_addr: Ipv4Addr) -> Option<Ipv4Addr> {
+        if name == METADATA_HOST_NAME {
+            return Some(mmds_addr);
+        }
+
+        self.records
+            .iter()
+            .find(|(record_name, _)| normalize_name(record_name).as_deref() == Some(name))
+            .map(|(_, addr)| *addr)
+    }
+}
+
+/// The outcome of a DNS query handled by the responder.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The payload of the response to send back.
+    Answer(Vec<u8>),
+    /// The payload of an error response to send back, for a query the responder couldn't
+    /// interpret.
+    Error(Vec<u8>),
+    /// The message must be dropped without any response.
+    Drop,
+}
+
+fn write_response(id: u16, flags: u16, question: &[u8], addrs: &[Ipv4Addr]) -> Vec<u8> {
+    let mut buf = vec![0; DNS_HEADER_LEN + question.len() + addrs.len() * A_RECORD_LEN];
+    // The unwrap() is safe because the buffer is large enough for the response.
+    let len = DnsMessage::write_response(
+        buf.as_mut_slice(),
+        id,
+        flags,
+        question,
+        addrs,
+        RECORD_TTL_SECS,
+    )
+    .unwrap()
+    .len();
+    buf.truncate(len);
+    buf
+}
+
+/// Builds the response to the `query` DNS message, given the `mmds_addr` MMDS address.
+pub fn respond(config: &DnsConfig, mmds_addr: Ipv4Addr, query: &[u8]) -> Response {
+    let message = match DnsMessage::from_bytes(query) {
+        Ok(message) => message,
+        Err(_) => return Response::Drop,
+    };
+    // Never answer a response, so that two responders can't keep answering each other.
+    if message.flags() & FLAG_QR != 0 {
+        return Response::Drop;
+    }
+
+    let id = message.id();
+    let query_flags = message.flags();
+    if message.opcode() != OPCODE_QUERY {
+        let flags = response_flags(query_flags, RCODE_NOT_IMPLEMENTED, false);
+        return Response::Error(write_response(id, flags, &[], &[]));
+    }
+
+    let question = match message.question() {
+        Ok(question) if message.qdcount() == 1 => question,
+        _ => {
+            let flags = response_flags(query_flags, RCODE_FORMAT_ERROR, false);
+            return Response::Error(write_response(id, flags, &[], &[]));
+        }
+    };
+
+    if question.qclass != CLASS_IN {
+        let flags = response_flags(query_flags, RCODE_REFUSED, false);
+        return Response::Answer(write_response(id, flags, question.bytes, &[]));
+    }
+
+    match config.resolve(&question.name, mmds_addr) {
+        Some(addr) => {
+            let flags = response_flags(query_flags, RCODE_NO_ERROR, true);
+            // Names with an address have no records of any other type.
+            let addrs = match question.qtype {
+                TYPE_A | TYPE_ANY => vec![addr],
+                _ => Vec::new(),
+            };
+            Response::Answer(write_response(id, flags, question.bytes, &addrs))
+        }
+        None => {
+            let flags = response_flags(query_flags, RCODE_NAME_ERROR, true);
+            Response::Answer(write_response(id, flags, question.bytes, &[]))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use dumbo::pdu::dns::{FLAG_AA, FLAG_RD};
+
+    use super::*;
+
+    const MMDS_ADDR: Ipv4Addr = Ipv4Addr::new(169, 254, 169, 254);
+
+    pub(crate) fn dns_query(id: u16, flags: u16, name: &str, qtype: u16, qclass: u16) -> Vec<u8> {
+        let mut query = Vec::new();
+        query.extend_from_slice(&id.to_be_bytes());
+        query.extend_from_slice(&flags.to_be_bytes());
+        query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(u8::try_from(label.len()).unwrap());
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&qclass.to_be_bytes());
+        query
+    }
+
+    fn dns_config() -> DnsConfig {
+        DnsConfig {
+            records: BTreeMap::from([
+                ("Host.Example".to_string(), Ipv4Addr::new(10, 0, 0, 1)),
+                ("other.example.".to_string(), Ipv4Addr::new(10, 0, 0, 2)),
+            ]),
+        }
+    }
+
+    fn answer(response: Response) -> Vec<u8> {
+        match response {
+            Response::Answer(payload) => payload,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        dns_config().validate().unwrap();
+        DnsConfig::default().validate().unwrap();
+
+        let addr = Ipv4Addr::new(10, 0, 0, 1);
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for name in [
+            "",
+            ".",
+            "a..b",
+            "white space",
+            "ünicode",
+            &long_label,
+            &long_name,
+        ] {
+            let config = DnsConfig {
+                records: BTreeMap::from([(name.to_string(), addr)]),
+            };
+            assert_eq!(config.validate(), Err(Error::InvalidName(name.to_string())));
+        }
+
+        let config = DnsConfig {
+            records: BTreeMap::from([("Metadata.Internal.".to_string(), addr)]),
+        };
+        assert_eq!(config.validate(), Err(Error::ReservedName));
+
+        let config = DnsConfig {
+            records: BTreeMap::from([
+                ("host.example".to_string(), addr),
+                ("host.example.".to_string(), addr),
+            ]),
+        };
+        assert_eq!(
+            config.validate(),
+            Err(Error::DuplicateName("host.example.".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config = dns_config();
+        assert_eq!(
+            config.resolve(METADATA_HOST_NAME, MMDS_ADDR),
+            Some(MMDS_ADDR)
+        );
+        assert_eq!(
+            config.resolve("host.example", MMDS_ADDR),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(
+            config.resolve("other.example", MMDS_ADDR),
+            Some(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        assert_eq!(config.resolve("example", MMDS_ADDR), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        let config = dns_config();
+
+        // A records of the metadata and configured names.
+        for (name, addr) in [
+            ("metadata.internal", MMDS_ADDR),
+            ("HOST.example", Ipv4Addr::new(10, 0, 0, 1)),
+        ] {
+            let query = dns_query(0x1234, FLAG_RD, name, TYPE_A, CLASS_IN);
+            let payload = answer(respond(&config, MMDS_ADDR, &query));
+            let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+            assert_eq!(response.id(), 0x1234);
+            assert_eq!(response.flags(), FLAG_QR | FLAG_AA | FLAG_RD);
+            assert_eq!(response.qdcount(), 1);
+            assert_eq!(response.ancount(), 1);
+            assert_eq!(
+                &payload[DNS_HEADER_LEN..query.len()],
+                &query[DNS_HEADER_LEN..]
+            );
+            assert_eq!(&payload[payload.len() - 4..], &addr.octets());
+        }
+
+        // Other record types of a known name.
+        let query = dns_query(1, 0, "metadata.internal", 28, CLASS_IN);
+        let payload = answer(respond(&config, MMDS_ADDR, &query));
+        let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+        assert_eq!(response.rcode(), RCODE_NO_ERROR);
+        assert_eq!(response.ancount(), 0);
+
+        // Unknown names.
+        let query = dns_query(2, 0, "example.com", TYPE_A, CLASS_IN);
+        let payload = answer(respond(&config, MMDS_ADDR, &query));
+        let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+        assert_eq!(response.rcode(), RCODE_NAME_ERROR);
+        assert_eq!(response.ancount(), 0);
+
+        // Other classes.
+        let query = dns_query(3, 0, "metadata.internal", TYPE_A, 3);
+        let payload = answer(respond(&config, MMDS_ADDR, &query));
+        let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+        assert_eq!(response.rcode(), RCODE_REFUSED);
+        assert_eq!(response.ancount(), 0);
+
+        // Other opcodes.
+        let query = dns_query(4, 2 << 11, "metadata.internal", TYPE_A, CLASS_IN);
+        match respond(&config, MMDS_ADDR, &query) {
+            Response::Error(payload) => {
+                let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+                assert_eq!(response.rcode(), RCODE_NOT_IMPLEMENTED);
+                assert_eq!(response.opcode(), 2);
+                assert_eq!(response.qdcount(), 0);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Malformed questions.
+        let mut query = dns_query(5, 0, "metadata.internal", TYPE_A, CLASS_IN);
+        query.truncate(query.len() - 1);
+        match respond(&config, MMDS_ADDR, &query) {
+            Response::Error(payload) => {
+                let response = DnsMessage::from_bytes(payload.as_slice()).unwrap();
+                assert_eq!(response.id(), 5);
+                assert_eq!(response.rcode(), RCODE_FORMAT_ERROR);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Responses and truncated headers are dropped.
+        let query = dns_query(6, FLAG_QR, "metadata.internal", TYPE_A, CLASS_IN);
+        assert_eq!(respond(&config, MMDS_ADDR, &query), Response::Drop);
+        assert_eq!(
+            respond(&config, MMDS_ADDR, &query[..DNS_HEADER_LEN - 1]),
+            Response::Drop
+        );
+    }
+}
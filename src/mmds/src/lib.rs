@@ -6,6 +6,7 @@
 #![warn(clippy::cast_lossless)]
 
 pub mod data_store;
+pub mod dns;
 pub mod ns;
 pub mod persist;
 mod token;
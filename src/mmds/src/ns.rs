@@ -4,6 +4,7 @@
 // TODO: get rid of this when splitting dumbo into public and internal parts.
 #![allow(missing_docs)]
 
+use std::collections::VecDeque;
 use std::convert::From;
 use std::net::Ipv4Addr;
 use std::num::NonZeroUsize;
@@ -13,13 +14,15 @@ use std::sync::{Arc, Mutex};
 use dumbo::pdu::arp::{
     test_speculative_tpa, Error as ArpFrameError, EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN,
 };
+use dumbo::pdu::dns::DNS_PORT;
 use dumbo::pdu::ethernet::{
     Error as EthernetFrameError, EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4,
 };
 use dumbo::pdu::ipv4::{
-    test_speculative_dst_addr, Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP,
+    test_speculative_dst_addr, Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP,
 };
 use dumbo::pdu::tcp::Error as TcpSegmentError;
+use dumbo::pdu::udp::{Error as UdpDatagramError, UdpDatagram, UDP_HEADER_SIZE};
 use dumbo::pdu::Incomplete;
 use dumbo::tcp::handler::{RecvEvent, TcpIPv4Handler, WriteEvent, WriteNextError};
 use dumbo::tcp::NextSegmentStatus;
@@ -27,6 +30,7 @@ use logger::{IncMetric, METRICS};
 use utils::net::mac::MacAddr;
 use utils::time::timestamp_cycles;
 
+use crate::dns::{self, DnsConfig};
 use crate::Mmds;
 
 const DEFAULT_MAC_ADDR: &str = "06:01:23:45:67:01";
@@ -34,6 +38,9 @@ const DEFAULT_IPV4_ADDR: [u8; 4] = [169, 254, 169, 254];
 const DEFAULT_TCP_PORT: u16 = 80;
 const DEFAULT_MAX_CONNECTIONS: usize = 30;
 const DEFAULT_MAX_PENDING_RESETS: usize = 100;
+// Stub resolvers usually send their A and AAAA queries at once, so a few responses may be pending
+// at the same time.
+const MAX_PENDING_DNS_RESPONSES: usize = 16;
 
 #[derive(derive_more::From)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
@@ -52,6 +59,21 @@ enum WritePacketError {
     WriteNext(WriteNextError),
 }
 
+#[derive(derive_more::From)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum WriteDnsResponseError {
+    IPv4Packet(IPv4PacketError),
+    Ethernet(EthernetFrameError),
+    UdpDatagram(UdpDatagramError),
+}
+
+// A DNS response waiting to be sent to the guest.
+struct PendingDnsResponse {
+    dst_addr: Ipv4Addr,
+    dst_port: u16,
+    payload: Vec<u8>,
+}
+
 pub struct MmdsNetworkStack {
     // Network interface MAC address used by frames/packets heading to MMDS server.
     remote_mac_addr: MacAddr,
@@ -67,6 +89,10 @@ pub struct MmdsNetworkStack {
     pub(crate) tcp_handler: TcpIPv4Handler,
     // Data store reference shared across all MmdsNetworkStack instances.
     pub mmds: Arc<Mutex<Mmds>>,
+    // Configuration of the DNS responder, which is disabled when `None`.
+    dns: Option<DnsConfig>,
+    // DNS responses waiting to be sent to the guest.
+    pending_dns_responses: VecDeque<PendingDnsResponse>,
 }
 
 impl MmdsNetworkStack {
@@ -90,6 +116,8 @@ impl MmdsNetworkStack {
                 max_pending_resets,
             ),
             mmds,
+            dns: None,
+            pending_dns_responses: VecDeque::new(),
         }
     }
 
@@ -122,6 +150,18 @@ impl MmdsNetworkStack {
         Ipv4Addr::from(DEFAULT_IPV4_ADDR)
     }
 
+    /// Enables the DNS responder with the given configuration, or disables it when `None`.
+    pub fn set_dns_config(&mut self, dns: Option<DnsConfig>) {
+        if dns.is_none() {
+            self.pending_dns_responses.clear();
+        }
+        self.dns = dns;
+    }
+
+    pub fn dns_config(&self) -> Option<&DnsConfig> {
+        self.dns.as_ref()
+    }
+
     /// Check if a frame is destined for `mmds`
     ///
     /// This returns `true` if the frame is an ARP or IPv4 frame destined for
@@ -203,6 +243,9 @@ impl MmdsNetworkStack {
                     }
                     Err(_) => METRICS.mmds.rx_accepted_err.inc(),
                 }
+            } else if ip.protocol() == PROTOCOL_UDP && self.dns.is_some() {
+                self.remote_mac_addr = eth.src_mac();
+                self.detour_dns(&ip);
             } else {
                 // A non-TCP IPv4 packet heading towards the MMDS; we consider it unusual.
                 METRICS.mmds.rx_accepted_unusual.inc();
@@ -213,6 +256,52 @@ impl MmdsNetworkStack {
         false
     }
 
+    fn detour_dns(&mut self, ip: &IPv4Packet<&[u8]>) {
+        let udp = match UdpDatagram::from_bytes(ip.payload(), None) {
+            Ok(udp) if udp.destination_port() == DNS_PORT => udp,
+            // A UDP datagram heading towards another port of the MMDS; we consider it unusual.
+            _ => {
+                METRICS.mmds.rx_accepted_unusual.inc();
+                return;
+            }
+        };
+        METRICS.mmds.rx_count.inc();
+
+        // The datagram may be shorter than the IPv4 payload.
+        let payload = usize::from(udp.len())
+            .checked_sub(UDP_HEADER_SIZE)
+            .and_then(|len| udp.payload().get(..len));
+        let response = match (self.dns.as_ref(), payload) {
+            (Some(config), Some(query)) => dns::respond(config, self.ipv4_addr, query),
+            _ => dns::Response::Drop,
+        };
+        let payload = match response {
+            dns::Response::Answer(payload) => {
+                METRICS.mmds.dns_queries.inc();
+                payload
+            }
+            dns::Response::Error(payload) => {
+                METRICS.mmds.dns_bad_queries.inc();
+                payload
+            }
+            dns::Response::Drop => {
+                METRICS.mmds.dns_bad_queries.inc();
+                return;
+            }
+        };
+
+        if self.pending_dns_responses.len() < MAX_PENDING_DNS_RESPONSES {
+            self.pending_dns_responses.push_back(PendingDnsResponse {
+                dst_addr: ip.source_address(),
+                dst_port: udp.source_port(),
+                payload,
+            });
+        } else {
+            // The guest will retry the query.
+            METRICS.mmds.rx_accepted_err.inc();
+        }
+    }
+
     // Allows the MMDS network stack to write a frame to the specified buffer. Will return:
     // - None, if the MMDS network stack has no frame to send at this point. The buffer can be
     // used for something else by the device model.
@@ -231,6 +320,18 @@ impl MmdsNetworkStack {
                     None
                 }
             };
+        } else if let Some(response) = self.pending_dns_responses.pop_front() {
+            // DNS responses go next, since they don't depend on any connection state.
+            return match self.write_dns_response(buf, &response) {
+                Ok(len) => {
+                    METRICS.mmds.tx_count.inc();
+                    Some(len)
+                }
+                Err(_) => {
+                    METRICS.mmds.tx_errors.inc();
+                    None
+                }
+            };
         } else {
             let call_write = match self.tcp_handler.next_segment_status() {
                 NextSegmentStatus::Available => true,
@@ -288,6 +389,38 @@ impl MmdsNetworkStack {
         ))
     }
 
+    fn write_dns_response(
+        &self,
+        buf: &mut [u8],
+        response: &PendingDnsResponse,
+    ) -> Result<NonZeroUsize, WriteDnsResponseError> {
+        let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV4)?;
+        let mut packet_unsized = IPv4Packet::write_header(
+            eth_unsized.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            self.ipv4_addr,
+            response.dst_addr,
+        )?;
+        let datagram_len = UdpDatagram::write_incomplete_datagram(
+            packet_unsized.inner_mut().payload_mut(),
+            &response.payload,
+        )?
+        .finalize(
+            DNS_PORT,
+            response.dst_port,
+            Some((self.ipv4_addr, response.dst_addr)),
+        )
+        .len();
+        let packet_len = packet_unsized
+            .with_payload_len_unchecked(usize::from(datagram_len), true)
+            .len();
+
+        Ok(
+            // The unwrap() is safe because packet_len > 0.
+            NonZeroUsize::new(eth_unsized.with_payload_len_unchecked(packet_len).len()).unwrap(),
+        )
+    }
+
     fn write_packet(&mut self, buf: &mut [u8]) -> Result<Option<NonZeroUsize>, WritePacketError> {
         let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV4)?;
 
@@ -319,9 +452,11 @@ impl MmdsNetworkStack {
 mod tests {
     use std::str::FromStr;
 
+    use dumbo::pdu::dns::{DnsMessage, CLASS_IN, TYPE_A};
     use dumbo::pdu::tcp::{Flags as TcpFlags, TcpSegment};
 
     use super::*;
+    use crate::dns::tests::dns_query;
 
     // We use LOCALHOST here because const new() is not stable yet, so just reuse this const, since
     // all we're interested in is having some address different from the MMDS one.
@@ -390,6 +525,31 @@ mod tests {
             eth_unsized.with_payload_len_unchecked(packet_len).len()
         }
 
+        fn write_incoming_dns_query(&self, buf: &mut [u8], dst_port: u16, query: &[u8]) -> usize {
+            let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV4).unwrap();
+            let packet_len = {
+                let mut packet = IPv4Packet::write_header(
+                    eth_unsized.inner_mut().payload_mut(),
+                    PROTOCOL_UDP,
+                    REMOTE_ADDR,
+                    self.ipv4_addr,
+                )
+                .unwrap();
+
+                let datagram_len =
+                    UdpDatagram::write_incomplete_datagram(packet.inner_mut().payload_mut(), query)
+                        .unwrap()
+                        .finalize(REMOTE_PORT, dst_port, Some((REMOTE_ADDR, self.ipv4_addr)))
+                        .len();
+
+                packet
+                    .with_payload_len_unchecked(usize::from(datagram_len), true)
+                    .len()
+            };
+
+            eth_unsized.with_payload_len_unchecked(packet_len).len()
+        }
+
         fn next_frame_as_ipv4_packet<'a>(&mut self, buf: &'a mut [u8]) -> IPv4Packet<&'a [u8]> {
             let len = self.write_next_frame(buf).unwrap().get();
             let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
@@ -646,4 +806,67 @@ mod tests {
         assert!(ns.detour_arp(EthernetFrame::from_bytes(&buf[..len]).unwrap()));
         assert!(!ns.detour_ipv4(EthernetFrame::from_bytes(&buf[..len]).unwrap()));
     }
+
+    #[test]
+    fn test_ns_dns() {
+        let mut ns =
+            MmdsNetworkStack::new_with_defaults(None, Arc::new(Mutex::new(Mmds::default())));
+        let mut buf = [0u8; 2000];
+        let query = dns_query(0x1234, 0, "metadata.internal", TYPE_A, CLASS_IN);
+
+        // The DNS responder is disabled by default.
+        let len = ns.write_incoming_dns_query(buf.as_mut(), DNS_PORT, &query);
+        let curr_unusual = METRICS.mmds.rx_accepted_unusual.count();
+        assert!(ns.is_mmds_frame(&buf[..len]));
+        assert!(ns.detour_frame(&buf[..len]));
+        assert_eq!(curr_unusual + 1, METRICS.mmds.rx_accepted_unusual.count());
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        ns.set_dns_config(Some(DnsConfig::default()));
+        assert_eq!(ns.dns_config(), Some(&DnsConfig::default()));
+
+        // Datagrams heading towards other ports are still unusual.
+        let len = ns.write_incoming_dns_query(buf.as_mut(), 54, &query);
+        assert!(ns.detour_frame(&buf[..len]));
+        assert_eq!(curr_unusual + 2, METRICS.mmds.rx_accepted_unusual.count());
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        let len = ns.write_incoming_dns_query(buf.as_mut(), DNS_PORT, &query);
+        let curr_queries = METRICS.mmds.dns_queries.count();
+        assert!(ns.detour_frame(&buf[..len]));
+        assert_eq!(curr_queries + 1, METRICS.mmds.dns_queries.count());
+
+        {
+            let mmds_addr = ns.ipv4_addr;
+            let packet = ns.next_frame_as_ipv4_packet(buf.as_mut());
+            assert_eq!(packet.protocol(), PROTOCOL_UDP);
+            assert_eq!(packet.source_address(), mmds_addr);
+            assert_eq!(packet.destination_address(), REMOTE_ADDR);
+
+            let udp =
+                UdpDatagram::from_bytes(packet.payload(), Some((mmds_addr, REMOTE_ADDR))).unwrap();
+            assert_eq!(udp.source_port(), DNS_PORT);
+            assert_eq!(udp.destination_port(), REMOTE_PORT);
+
+            let response = DnsMessage::from_bytes(udp.payload()).unwrap();
+            assert_eq!(response.id(), 0x1234);
+            assert_eq!(response.ancount(), 1);
+            assert_eq!(
+                &udp.payload()[udp.payload().len() - 4..],
+                &mmds_addr.octets()
+            );
+        }
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        // The number of pending responses is bounded.
+        for _ in 0..MAX_PENDING_DNS_RESPONSES + 1 {
+            let len = ns.write_incoming_dns_query(buf.as_mut(), DNS_PORT, &query);
+            assert!(ns.detour_frame(&buf[..len]));
+        }
+        assert_eq!(ns.pending_dns_responses.len(), MAX_PENDING_DNS_RESPONSES);
+
+        // Disabling the responder drops the pending responses.
+        ns.set_dns_config(None);
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+    }
 }
@@ -8,12 +8,55 @@ use std::sync::{Arc, Mutex};
 
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
 use super::ns::MmdsNetworkStack;
+use crate::dns::DnsConfig;
 use crate::Mmds;
 
+/// State of a host name record of the DNS responder.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DnsRecordState {
+    name: String,
+    address: u32,
+}
+
+/// State of the DNS responder of a MmdsNetworkStack.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DnsState {
+    records: Vec<DnsRecordState>,
+}
+
+impl From<&DnsConfig> for DnsState {
+    fn from(config: &DnsConfig) -> Self {
+        DnsState {
+            records: config
+                .records
+                .iter()
+                .map(|(name, addr)| DnsRecordState {
+                    name: name.clone(),
+                    address: u32::from(*addr),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&DnsState> for DnsConfig {
+    fn from(state: &DnsState) -> Self {
+        DnsConfig {
+            records: state
+                .records
+                .iter()
+                .map(|record| (record.name.clone(), Ipv4Addr::from(record.address)))
+                .collect(),
+        }
+    }
+}
+
 /// State of a MmdsNetworkStack.
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -23,6 +66,21 @@ pub struct MmdsNetworkStackState {
     tcp_port: u16,
     max_connections: usize,
     max_pending_resets: usize,
+    #[version(start = 2, ser_fn = "dns_ser")]
+    dns: Option<DnsState>,
+}
+
+impl MmdsNetworkStackState {
+    fn dns_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.dns.is_some() {
+            return Err(VersionizeError::Serialize(format!(
+                "Cannot serialize a MMDS network stack with a DNS responder to target version {}",
+                target_version
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl Persist<'_> for MmdsNetworkStack {
@@ -40,6 +98,7 @@ impl Persist<'_> for MmdsNetworkStack {
             tcp_port: self.tcp_handler.local_port(),
             max_connections: self.tcp_handler.max_connections(),
             max_pending_resets: self.tcp_handler.max_pending_resets(),
+            dns: self.dns_config().map(DnsState::from),
         }
     }
 
@@ -47,14 +106,16 @@ impl Persist<'_> for MmdsNetworkStack {
         mmds: Self::ConstructorArgs,
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
-        Ok(MmdsNetworkStack::new(
+        let mut ns = MmdsNetworkStack::new(
             MacAddr::from_bytes_unchecked(&state.mac_addr),
             Ipv4Addr::from(state.ipv4_addr),
             state.tcp_port,
             std::num::NonZeroUsize::new(state.max_connections).unwrap(),
             std::num::NonZeroUsize::new(state.max_pending_resets).unwrap(),
             mmds,
-        ))
+        );
+        ns.set_dns_config(state.dns.as_ref().map(DnsConfig::from));
+        Ok(ns)
     }
 }
 
@@ -94,4 +155,40 @@ mod tests {
             ns.tcp_handler.max_pending_resets()
         );
     }
+
+    #[test]
+    fn test_dns_persistence() {
+        let mut ns =
+            MmdsNetworkStack::new_with_defaults(None, Arc::new(Mutex::new(Mmds::default())));
+        let dns_config = DnsConfig {
+            records: [("host.example".to_string(), Ipv4Addr::new(10, 0, 0, 1))]
+                .into_iter()
+                .collect(),
+        };
+        ns.set_dns_config(Some(dns_config.clone()));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(MmdsNetworkStackState::type_id(), 2);
+
+        // The DNS responder can't be saved to the first version.
+        assert!(ns
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        ns.save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        let restored_ns = MmdsNetworkStack::restore(
+            Arc::new(Mutex::new(Mmds::default())),
+            &MmdsNetworkStackState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_ns.dns_config(), Some(&dns_config));
+    }
 }
@@ -11,8 +11,9 @@
 // of the `utils` crate.
 pub use vmm_sys_util::ioctl::ioctl_expr;
 pub use vmm_sys_util::{
-    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_ioc_nr, ioctl_iow_nr, rand,
-    seek_hole, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
+    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_io_nr, ioctl_ioc_nr,
+    ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr, rand, seek_hole, sock_ctrl_msg, syscall, tempdir,
+    tempfile, terminal,
 };
 
 pub mod arg_parser;
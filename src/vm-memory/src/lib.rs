@@ -6,8 +6,10 @@
 // found in the THIRD-PARTY file.
 #![warn(clippy::undocumented_unsafe_blocks)]
 
+use std::fs::File;
 use std::io::Error as IoError;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
 
 use vm_memory_upstream::bitmap::AtomicBitmap;
 pub use vm_memory_upstream::bitmap::Bitmap;
@@ -100,12 +102,17 @@ fn build_guarded_region(
         false => None,
     };
 
+    let mut builder = MmapRegionBuilder::new_with_bitmap(size, bitmap)
+        .with_mmap_prot(prot)
+        .with_mmap_flags(flags);
+    if let Some(file_offset) = maybe_file_offset {
+        builder = builder.with_file_offset(file_offset);
+    }
+
     // SAFETY: Safe because the parameters are valid.
     unsafe {
-        MmapRegionBuilder::new_with_bitmap(size, bitmap)
+        builder
             .with_raw_mmap_pointer(region_addr as *mut u8)
-            .with_mmap_prot(prot)
-            .with_mmap_flags(flags)
             .build()
     }
 }
@@ -134,6 +141,46 @@ pub fn create_guest_memory(
     GuestMemoryMmap::from_regions(mmap_regions)
 }
 
+/// Helper for creating the guest memory backed by a single memfd, which can be shared with other
+/// processes. The regions are mapped from consecutive ranges of the memfd.
+pub fn create_memfd_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_NORESERVE | libc::MAP_SHARED;
+    let mem_size = regions.iter().map(|region| region.1 as u64).sum();
+    let memfd = Arc::new(
+        create_memfd(mem_size).map_err(|err| Error::MmapRegion(MmapRegionError::Mmap(err)))?,
+    );
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+    let mut offset = 0;
+
+    for region in regions {
+        let file_offset = FileOffset::from_arc(memfd.clone(), offset);
+        let mmap_region =
+            build_guarded_region(Some(file_offset), region.1, prot, flags, track_dirty_pages)
+                .map_err(Error::MmapRegion)?;
+
+        mmap_regions.push(GuestRegionMmap::new(mmap_region, region.0)?);
+        offset += region.1 as u64;
+    }
+
+    GuestMemoryMmap::from_regions(mmap_regions)
+}
+
+fn create_memfd(size: u64) -> std::io::Result<File> {
+    // SAFETY: Safe because the name is a valid nul-terminated string.
+    let fd = unsafe { libc::memfd_create(b"guest_mem\0".as_ptr().cast(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+    // SAFETY: Safe because the file descriptor was just created and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size)?;
+    Ok(file)
+}
+
 pub fn mark_dirty_mem(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
     let _ = mem.try_access(len, addr, |_total, count, caddr, region| {
         if let Some(bitmap) = region.bitmap() {
@@ -194,6 +241,8 @@ pub mod test_utils {
 #[cfg(test)]
 mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
+    use std::os::unix::fs::FileExt;
+
     use utils::get_page_size;
     use utils::tempfile::TempFile;
 
@@ -336,7 +385,7 @@ mod tests {
 
             // Verify that the region was built correctly
             assert_eq!(region.size(), size);
-            // assert_eq!(region.file_offset().unwrap().start(), offset as u64);
+            assert_eq!(region.file_offset().unwrap().start(), offset as u64);
             assert_eq!(region.prot(), prot);
             assert_eq!(region.flags(), flags);
 
@@ -396,6 +445,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_create_memfd_guest_memory() {
+        let region_size = 0x10000;
+        let regions = vec![
+            (GuestAddress(0x0), region_size),
+            (GuestAddress(0x20000), region_size),
+        ];
+
+        let guest_memory = create_memfd_guest_memory(&regions, false).unwrap();
+        let memfd = guest_memory
+            .iter()
+            .next()
+            .unwrap()
+            .file_offset()
+            .unwrap()
+            .file();
+        guest_memory.iter().enumerate().for_each(|(idx, region)| {
+            let file_offset = region.file_offset().unwrap();
+            assert_eq!(file_offset.file().as_raw_fd(), memfd.as_raw_fd());
+            assert_eq!(file_offset.start(), (idx * region_size) as u64);
+            assert_eq!(region.flags(), libc::MAP_NORESERVE | libc::MAP_SHARED);
+            validate_guard_region(region);
+        });
+
+        // The guest memory is visible through the memfd.
+        guest_memory
+            .write_obj(0xAAu8, GuestAddress(0x20000 + 1))
+            .unwrap();
+        let mut buf = [0u8; 2];
+        memfd.read_exact_at(&mut buf, region_size as u64).unwrap();
+        assert_eq!(buf, [0, 0xAA]);
+    }
+
     #[test]
     fn test_mark_dirty_mem() {
         let page_size = utils::get_page_size().unwrap();
@@ -17,7 +17,10 @@ use devices::legacy::RTCDevice;
 use devices::legacy::{
     EventFdTrigger, ReadableFd, SerialDevice, SerialEventsWrapper, SerialWrapper,
 };
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostNet, VhostUserBlock, VirtioDevice, Vsock,
+    VsockUnixBackend,
+};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use libc::EFD_NONBLOCK;
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
@@ -49,6 +52,7 @@ use crate::resources::VmResources;
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::machine_config::{VmConfigError, VmUpdateConfig};
+use crate::vmm_config::snapshot::DriveEncryptionConfig;
 use crate::vstate::system::KvmContext;
 use crate::vstate::vcpu::{Vcpu, VcpuConfig};
 use crate::vstate::vm::Vm;
@@ -95,6 +99,8 @@ pub enum StartMicrovmError {
     RestoreMicrovmState(MicrovmStateError),
     /// Unable to set VmResources.
     SetVmResources(VmConfigError),
+    /// A block device has more queues than the microVM has vCPUs.
+    TooManyBlockDeviceQueues(String, u8),
 }
 impl std::error::Error for StartMicrovmError {}
 /// It's convenient to automatically convert `linux_loader::cmdline::Error`s
@@ -178,6 +184,11 @@ impl Display for StartMicrovmError {
             }
             RestoreMicrovmState(err) => write!(f, "Cannot restore microvm state. Error: {}", err),
             SetVmResources(err) => write!(f, "Cannot set vm resources. Error: {}", err),
+            TooManyBlockDeviceQueues(drive_id, vcpu_count) => write!(
+                f,
+                "The block device {} has more queues than the {} vCPUs of the microVM.",
+                drive_id, vcpu_count
+            ),
         }
     }
 }
@@ -331,10 +342,35 @@ pub fn build_microvm_for_boot(
         .boot_source_builder()
         .ok_or(MissingKernelConfig)?;
 
-    let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory =
-        create_guest_memory(vm_resources.vm_config().mem_size_mib, track_dirty_pages)?;
     let vcpu_config = vm_resources.vcpu_config();
+    // The guest driver sets up at most one queue per vCPU, and the device is only activated once
+    // all its queues are set up.
+    let block_queues = vm_resources
+        .block
+        .list
+        .iter()
+        .map(|block| {
+            let locked = block.lock().expect("Poisoned lock");
+            (locked.id().clone(), locked.num_queues())
+        })
+        .chain(vm_resources.block.vhost_user_list.iter().map(|block| {
+            let locked = block.lock().expect("Poisoned lock");
+            (locked.id().clone(), locked.num_queues())
+        }));
+    for (drive_id, num_queues) in block_queues {
+        if num_queues > usize::from(vcpu_config.vcpu_count) {
+            return Err(TooManyBlockDeviceQueues(drive_id, vcpu_config.vcpu_count));
+        }
+    }
+
+    let track_dirty_pages = vm_resources.track_dirty_pages();
+    // Vhost-user backends access the guest memory directly, so it has to be shared with them.
+    let shared_memory = !vm_resources.block.vhost_user_list.is_empty();
+    let guest_memory = create_guest_memory(
+        vm_resources.vm_config().mem_size_mib,
+        track_dirty_pages,
+        shared_memory,
+    )?;
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
     let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
     // Clone the command-line so that a failed boot doesn't pollute the original.
@@ -361,16 +397,44 @@ pub fn build_microvm_for_boot(
         attach_balloon_device(&mut vmm, &mut boot_cmdline, balloon, event_manager)?;
     }
 
-    attach_block_devices(
+    // The root block device has to be attached first, for the guest to see it as /dev/vda.
+    if vm_resources.block.has_vhost_user_root_device() {
+        attach_vhost_user_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.vhost_user_list.iter(),
+            event_manager,
+        )?;
+        attach_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.list.iter(),
+            event_manager,
+        )?;
+    } else {
+        attach_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.list.iter(),
+            event_manager,
+        )?;
+        attach_vhost_user_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.vhost_user_list.iter(),
+            event_manager,
+        )?;
+    }
+    attach_net_devices(
         &mut vmm,
         &mut boot_cmdline,
-        vm_resources.block.list.iter(),
+        vm_resources.net_builder.iter(),
         event_manager,
     )?;
-    attach_net_devices(
+    attach_vhost_net_devices(
         &mut vmm,
         &mut boot_cmdline,
-        vm_resources.net_builder.iter(),
+        vm_resources.net_builder.vhost_net_iter(),
         event_manager,
     )?;
     if let Some(unix_vsock) = vm_resources.vsock.get() {
@@ -494,6 +558,7 @@ pub fn build_microvm_from_snapshot(
     track_dirty_pages: bool,
     seccomp_filters: &BpfThreadMap,
     vm_resources: &mut VmResources,
+    drive_encryption: &[DriveEncryptionConfig],
 ) -> std::result::Result<Arc<Mutex<Vmm>>, BuildMicrovmFromSnapshotError> {
     let vcpu_count = u8::try_from(microvm_state.vcpu_states.len()).map_err(|_| {
         BuildMicrovmFromSnapshotError::TooManyVCPUs(microvm_state.vcpu_states.len())
@@ -559,6 +624,7 @@ pub fn build_microvm_from_snapshot(
         for_each_restored_device: VmResources::update_from_restored_device,
         vm_resources,
         instance_id: &instance_info.id,
+        drive_encryption,
     };
 
     vmm.mmio_device_manager =
@@ -593,13 +659,21 @@ pub fn build_microvm_from_snapshot(
 }
 
 /// Creates GuestMemory of `mem_size_mib` MiB in size.
+///
+/// Shared memory is backed by a memfd, which can be handed over to other processes.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    shared: bool,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = arch::arch_memory_regions(mem_size);
 
+    if shared {
+        return vm_memory::create_memfd_guest_memory(&arch_mem_regions, track_dirty_pages)
+            .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
+
     vm_memory::create_guest_memory(
         &arch_mem_regions
             .iter()
@@ -936,6 +1010,23 @@ pub(crate) fn attach_boot_timer_device(
     Ok(())
 }
 
+fn insert_root_device_args(
+    cmdline: &mut LoaderKernelCmdline,
+    partuuid: Option<&String>,
+    is_read_only: bool,
+) -> std::result::Result<(), StartMicrovmError> {
+    cmdline.insert_str(if let Some(partuuid) = partuuid {
+        format!("root=PARTUUID={}", partuuid)
+    } else {
+        // If no PARTUUID was specified for the root device, try with the /dev/vda.
+        "root=/dev/vda".to_string()
+    })?;
+
+    let flags = if is_read_only { "ro" } else { "rw" };
+    cmdline.insert_str(flags)?;
+    Ok(())
+}
+
 fn attach_block_devices<'a>(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -946,15 +1037,27 @@ fn attach_block_devices<'a>(
         let id = {
             let locked = block.lock().expect("Poisoned lock");
             if locked.is_root_device() {
-                cmdline.insert_str(if let Some(partuuid) = locked.partuuid() {
-                    format!("root=PARTUUID={}", partuuid)
-                } else {
-                    // If no PARTUUID was specified for the root device, try with the /dev/vda.
-                    "root=/dev/vda".to_string()
-                })?;
-
-                let flags = if locked.is_read_only() { "ro" } else { "rw" };
-                cmdline.insert_str(flags)?;
+                insert_root_device_args(cmdline, locked.partuuid(), locked.is_read_only())?;
+            }
+            locked.id().clone()
+        };
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, block.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
+fn attach_vhost_user_block_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    blocks: impl Iterator<Item = &'a Arc<Mutex<VhostUserBlock>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for block in blocks {
+        let id = {
+            let locked = block.lock().expect("Poisoned lock");
+            if locked.is_root_device() {
+                insert_root_device_args(cmdline, locked.partuuid(), locked.is_read_only())?;
             }
             locked.id().clone()
         };
@@ -978,6 +1081,20 @@ fn attach_net_devices<'a>(
     Ok(())
 }
 
+fn attach_vhost_net_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    net_devices: impl Iterator<Item = &'a Arc<Mutex<VhostNet>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for net_device in net_devices {
+        let id = net_device.lock().expect("Poisoned lock").id().clone();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, net_device.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
 fn attach_unixsock_vsock_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -1030,7 +1147,9 @@ pub mod tests {
     use super::*;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
+    use crate::vmm_config::drive::{
+        BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType, ImageFormat,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
@@ -1114,7 +1233,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1163,19 +1282,30 @@ pub mod tests {
             block_files.push(TempFile::new().unwrap());
             let block_device_config = BlockDeviceConfig {
                 drive_id: String::from(&custom_block_cfg.drive_id),
-                path_on_host: block_files
-                    .last()
-                    .unwrap()
-                    .as_path()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
+                path_on_host: Some(
+                    block_files
+                        .last()
+                        .unwrap()
+                        .as_path()
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                ),
                 is_root_device: custom_block_cfg.is_root_device,
                 partuuid: custom_block_cfg.partuuid.clone(),
                 is_read_only: custom_block_cfg.is_read_only,
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                io_engine_config: None,
+                format: ImageFormat::default(),
+                enable_discard: false,
+                num_queues: None,
+                topology: None,
+                verity: None,
+                encryption: None,
+                socket: None,
+                nbd: None,
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1329,21 +1459,29 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, false).unwrap();
             assert!(!is_dirty_tracking_enabled(&guest_memory));
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, false).unwrap();
             assert!(is_dirty_tracking_enabled(&guest_memory));
         }
+
+        // Case 3: create guest memory which can be shared with other processes
+        {
+            let guest_memory = create_guest_memory(mem_size, false, true).unwrap();
+            assert!(guest_memory
+                .iter()
+                .all(|region| region.file_offset().is_some()));
+        }
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1364,9 +1502,18 @@ pub mod tests {
         let network_interface = NetworkInterfaceConfig {
             iface_id: String::from("netif"),
             host_dev_name: String::from("hostname"),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1649,6 +1796,9 @@ pub mod tests {
 
         let err = OpenBlockDevice(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
+
+        let err = TooManyBlockDeviceQueues(String::from("root"), 1);
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -19,7 +19,7 @@ use devices::legacy::RTCDevice;
 use devices::legacy::SerialDevice;
 use devices::pseudo::BootTimer;
 use devices::virtio::{
-    Balloon, Block, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
+    Balloon, Block, MmioTransport, Net, VhostNet, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
     TYPE_VSOCK,
 };
 use devices::BusDevice;
@@ -421,25 +421,32 @@ impl MMIODeviceManager {
                     }
                 }
                 TYPE_BLOCK => {
-                    let block = virtio.as_mut_any().downcast_mut::<Block>().unwrap();
                     // If device is activated, kick the block queue(s) to make up for any
                     // pending or in-flight epoll events we may have not captured in snapshot.
                     // No need to kick Ratelimiters because they are restored 'unblocked' so
                     // any inflight `timer_fd` events can be safely discarded.
-                    if block.is_activated() {
-                        info!("kick block {}.", id);
-                        block.process_virtio_queues();
+                    // The queues of vhost-user block devices are processed by their backends.
+                    if let Some(block) = virtio.as_mut_any().downcast_mut::<Block>() {
+                        if block.is_activated() {
+                            info!("kick block {}.", id);
+                            block.process_virtio_queues();
+                        }
                     }
                 }
                 TYPE_NET => {
-                    let net = virtio.as_mut_any().downcast_mut::<Net>().unwrap();
                     // If device is activated, kick the net queue(s) to make up for any
                     // pending or in-flight epoll events we may have not captured in snapshot.
                     // No need to kick Ratelimiters because they are restored 'unblocked' so
                     // any inflight `timer_fd` events can be safely discarded.
-                    if net.is_activated() {
-                        info!("kick net {}.", id);
-                        net.process_virtio_queues();
+                    // The queues of vhost-net devices are processed by the host kernel, whose
+                    // processing was stopped when saving their state.
+                    if let Some(net) = virtio.as_mut_any().downcast_mut::<Net>() {
+                        if net.is_activated() {
+                            info!("kick net {}.", id);
+                            net.process_virtio_queues();
+                        }
+                    } else if let Some(vhost_net) = virtio.as_mut_any().downcast_mut::<VhostNet>() {
+                        vhost_net.resume_vrings();
                     }
                 }
                 TYPE_VSOCK => {
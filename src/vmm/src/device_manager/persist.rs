@@ -15,6 +15,10 @@ use devices::virtio::block::{Block, Error as BlockError};
 use devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetState};
 use devices::virtio::net::Net;
 use devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState};
+use devices::virtio::vhost_net::persist::{
+    Error as VhostNetError, VhostNetConstructorArgs, VhostNetState,
+};
+use devices::virtio::vhost_net::VhostNet;
 use devices::virtio::vsock::persist::{VsockConstructorArgs, VsockState, VsockUdsConstructorArgs};
 use devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
 use devices::virtio::{
@@ -33,6 +37,7 @@ use vm_memory::GuestMemoryMmap;
 use super::mmio::*;
 use crate::resources::VmResources;
 use crate::vmm_config::mmds::MmdsConfigError;
+use crate::vmm_config::snapshot::DriveEncryptionConfig;
 use crate::EventManager;
 
 /// Errors for (de)serialization of the MMIO device manager.
@@ -45,6 +50,7 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
     Net(NetError),
+    VhostNet(VhostNetError),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
     MmdsConfig(MmdsConfigError),
@@ -92,6 +98,20 @@ pub struct ConnectedNetState {
     pub device_info: MMIODeviceInfo,
 }
 
+/// Holds the state of a vhost-net device connected to the MMIO space.
+// NOTICE: Any changes to this structure require a snapshot version bump.
+#[derive(Clone, Versionize)]
+pub struct ConnectedVhostNetState {
+    /// Device identifier.
+    pub device_id: String,
+    /// Device state.
+    pub device_state: VhostNetState,
+    /// Mmio transport state.
+    pub transport_state: MmioTransportState,
+    /// VmmResources.
+    pub device_info: MMIODeviceInfo,
+}
+
 /// Holds the state of a vsock device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 #[derive(Clone, Versionize)]
@@ -161,6 +181,9 @@ pub struct DeviceStates {
     /// Mmds version.
     #[version(start = 3, ser_fn = "mmds_version_serialize")]
     pub mmds_version: Option<MmdsVersionState>,
+    /// Vhost-net device states.
+    #[version(start = 4, ser_fn = "vhost_net_serialize")]
+    pub vhost_net_devices: Vec<ConnectedVhostNetState>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -168,6 +191,7 @@ pub struct DeviceStates {
 pub enum SharedDeviceType {
     Block(Arc<Mutex<Block>>),
     Network(Arc<Mutex<Net>>),
+    VhostNet(Arc<Mutex<VhostNet>>),
     Balloon(Arc<Mutex<Balloon>>),
     Vsock(Arc<Mutex<Vsock<VsockUnixBackend>>>),
 }
@@ -193,6 +217,16 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn vhost_net_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && !self.vhost_net_devices.is_empty() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement vhost-net devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
@@ -202,6 +236,7 @@ pub struct MMIODevManagerConstructorArgs<'a> {
     pub for_each_restored_device: fn(&mut VmResources, SharedDeviceType),
     pub vm_resources: &'a mut VmResources,
     pub instance_id: &'a str,
+    pub drive_encryption: &'a [DriveEncryptionConfig],
 }
 
 impl<'a> Persist<'a> for MMIODeviceManager {
@@ -218,6 +253,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
             mmds_version: None,
+            vhost_net_devices: Vec::new(),
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, device_info, bus_dev| {
             if *devtype == arch::DeviceType::BootTimer {
@@ -271,6 +307,19 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     });
                 }
                 TYPE_NET => {
+                    // The vrings of vhost-net devices are processed by the host kernel, which
+                    // holds part of their state.
+                    if let Some(vhost_net) = locked_device.as_mut_any().downcast_mut::<VhostNet>() {
+                        vhost_net.prepare_save();
+                        states.vhost_net_devices.push(ConnectedVhostNetState {
+                            device_id: devid.clone(),
+                            device_state: vhost_net.save(),
+                            transport_state,
+                            device_info: device_info.clone(),
+                        });
+                        return Ok(());
+                    }
+
                     let net = locked_device.as_any().downcast_ref::<Net>().unwrap();
                     if let (Some(mmds_ns), None) =
                         (net.mmds_ns.as_ref(), states.mmds_version.as_ref())
@@ -435,8 +484,16 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         }
 
         for block_state in &state.block_devices {
+            let encryption = constructor_args
+                .drive_encryption
+                .iter()
+                .find(|config| config.drive_id == block_state.device_id)
+                .map(|config| config.encryption.clone());
             let device = Arc::new(Mutex::new(Block::restore(
-                BlockConstructorArgs { mem: mem.clone() },
+                BlockConstructorArgs {
+                    mem: mem.clone(),
+                    encryption,
+                },
                 &block_state.device_state,
             )?));
 
@@ -500,6 +557,27 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        for vhost_net_state in &state.vhost_net_devices {
+            let device = Arc::new(Mutex::new(VhostNet::restore(
+                VhostNetConstructorArgs { mem: mem.clone() },
+                &vhost_net_state.device_state,
+            )?));
+
+            (constructor_args.for_each_restored_device)(
+                constructor_args.vm_resources,
+                SharedDeviceType::VhostNet(device.clone()),
+            );
+
+            restore_helper(
+                device.clone(),
+                device,
+                &vhost_net_state.device_id,
+                &vhost_net_state.transport_state,
+                &vhost_net_state.device_info,
+                constructor_args.event_manager,
+            )?;
+        }
+
         if let Some(vsock_state) = &state.vsock_device {
             let ctor_args = VsockUdsConstructorArgs {
                 cid: vsock_state.device_state.frontend.cid,
@@ -704,9 +782,18 @@ mod tests {
             let network_interface = NetworkInterfaceConfig {
                 iface_id: String::from("netif"),
                 host_dev_name: String::from("hostname"),
+                host_socket_path: None,
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: None,
+                mergeable_rx_buffers: false,
+                ctrl_queue: false,
+                vhost_net: false,
+                tx_filter: None,
+                link_up: None,
+                dhcp: None,
+                user_nat: None,
             };
             insert_net_device_with_mmds(
                 &mut vmm,
@@ -779,6 +866,7 @@ mod tests {
             for_each_restored_device: VmResources::update_from_restored_device,
             vm_resources,
             instance_id: "microvm-id",
+            drive_encryption: &[],
         };
         let restored_dev_manager =
             MMIODeviceManager::restore(restore_args, &device_states).unwrap();
@@ -799,7 +887,15 @@ mod tests {
       "is_read_only": true,
       "cache_type": "Unsafe",
       "rate_limiter": null,
-      "io_engine": "Sync"
+      "io_engine": "Sync",
+      "io_engine_config": null,
+      "format": "Raw",
+      "enable_discard": false,
+      "num_queues": null,
+      "topology": null,
+      "verity": null,
+      "encryption": null,
+      "socket": null
     }}
   ],
   "boot-source": {{
@@ -825,9 +921,18 @@ mod tests {
     {{
       "iface_id": "netif",
       "host_dev_name": "hostname",
+      "host_socket_path": null,
       "guest_mac": null,
       "rx_rate_limiter": null,
-      "tx_rate_limiter": null
+      "tx_rate_limiter": null,
+      "num_queue_pairs": null,
+      "mergeable_rx_buffers": false,
+      "ctrl_queue": false,
+      "vhost_net": false,
+      "tx_filter": null,
+      "link_up": null,
+      "dhcp": null,
+      "user_nat": null
     }}
   ],
   "vsock": {{
@@ -43,9 +43,11 @@ use std::{fmt, io};
 use arch::DeviceType;
 use devices::legacy::{IER_RDA_BIT, IER_RDA_OFFSET};
 use devices::virtio::balloon::Error as BalloonError;
+use devices::virtio::block::TraceConfig;
+use devices::virtio::net::CaptureConfig;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, VhostUserBlock,
+    BALLOON_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
 };
 use devices::BusDevice;
 use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
@@ -470,7 +472,19 @@ impl Vmm {
         &mut self,
         vm_info: &VmInfo,
     ) -> std::result::Result<MicrovmState, MicrovmStateError> {
-        use self::MicrovmStateError::SaveVmState;
+        use self::MicrovmStateError::{NotAllowed, SaveVmState};
+        // The state of the queues of vhost-user devices is owned by their backends.
+        self.mmio_device_manager
+            .for_each_virtio_device(|virtio_type, id, _info, dev| {
+                let locked = dev.lock().expect("Poisoned lock");
+                if virtio_type == TYPE_BLOCK && locked.as_any().is::<VhostUserBlock>() {
+                    return Err(NotAllowed(format!(
+                        "The vhost-user block device {} cannot be snapshotted.",
+                        id
+                    )));
+                }
+                Ok(())
+            })?;
         let vcpu_states = self.save_vcpu_states()?;
         let vm_state = {
             #[cfg(target_arch = "x86_64")]
@@ -614,6 +628,17 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Starts or stops tracing the requests processed by the block device with `drive_id` id.
+    pub fn update_block_trace(&mut self, drive_id: &str, config: &TraceConfig) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block
+                    .update_trace(config)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Updates the rate limiter parameters for net device with `net_id` id.
     pub fn update_net_rate_limiters(
         &mut self,
@@ -631,6 +656,25 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Starts or stops capturing the frames exchanged by the net device with `net_id` id.
+    pub fn update_net_capture(&mut self, net_id: &str, config: &CaptureConfig) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.update_capture(config)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Brings the link of the net device with `net_id` id up or down.
+    pub fn update_net_link_state(&mut self, net_id: &str, link_up: bool) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_link_up(link_up).map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Returns a reference to the balloon device if present.
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
@@ -14,7 +14,7 @@ use std::sync::{Arc, Mutex};
 use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
-use devices::virtio::TYPE_NET;
+use devices::virtio::{VhostNet, TYPE_NET};
 use logger::{error, info, warn};
 use seccompiler::BpfThreadMap;
 use serde::Serialize;
@@ -175,6 +175,12 @@ pub enum MicrovmStateError {
 /// Errors associated with creating a snapshot.
 #[derive(Debug, thiserror::Error)]
 pub enum CreateSnapshotError {
+    /// Diff snapshots are not supported with vhost-net devices.
+    #[error(
+        "Diff snapshots are not supported with the vhost-net device {0}, whose writes to the \
+         guest memory are not tracked."
+    )]
+    DiffSnapshotWithVhostNet(String),
     /// Failed to get dirty bitmap.
     #[error("Cannot get dirty bitmap: {0}")]
     DirtyBitmap(VmmError),
@@ -224,6 +230,20 @@ pub fn create_snapshot(
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, vmm)?;
 
+    // The host kernel writes the frames received by vhost-net devices straight to the guest
+    // memory, without marking the pages dirty in the KVM dirty bitmap.
+    if params.snapshot_type == SnapshotType::Diff {
+        vmm.mmio_device_manager
+            .for_each_virtio_device(|virtio_type, id, _info, dev| {
+                if virtio_type == TYPE_NET
+                    && dev.lock().expect("Poisoned lock").as_any().is::<VhostNet>()
+                {
+                    return Err(CreateSnapshotError::DiffSnapshotWithVhostNet(id.clone()));
+                }
+                Ok(())
+            })?;
+    }
+
     let microvm_state = vmm
         .save_state(vm_info)
         .map_err(CreateSnapshotError::MicrovmState)?;
@@ -488,6 +508,9 @@ pub enum RestoreFromSnapshotError {
     /// Failed to build microVM from snapshot.
     #[error("Failed to build microVM from snapshot: {0}")]
     Build(#[from] BuildMicrovmFromSnapshotError),
+    /// An encryption key was provided for a drive which is not encrypted.
+    #[error("The snapshot has no encrypted drive with the id {0}.")]
+    UnencryptedDrive(String),
 }
 /// Sub-Error type for [`restore_from_snapshot`] to contain either [`GuestMemoryFromFileError`] or
 /// [`GuestMemoryFromUffdError`] within [`RestoreFromSnapshotError`].
@@ -514,6 +537,18 @@ pub fn restore_from_snapshot(
 
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
+    for config in &params.drive_encryption {
+        if !microvm_state
+            .device_states
+            .block_devices
+            .iter()
+            .any(|block| block.device_id == config.drive_id && block.device_state.is_encrypted())
+        {
+            return Err(RestoreFromSnapshotError::UnencryptedDrive(
+                config.drive_id.clone(),
+            ));
+        }
+    }
 
     let mem_backend_path = &params.mem_backend.backend_path;
     let mem_state = &microvm_state.memory_state;
@@ -544,6 +579,7 @@ pub fn restore_from_snapshot(
         track_dirty_pages,
         seccomp_filters,
         vm_resources,
+        &params.drive_encryption,
     )
     .map_err(RestoreFromSnapshotError::Build)
 }
@@ -770,9 +806,18 @@ mod tests {
         let network_interface = NetworkInterfaceConfig {
             iface_id: String::from("netif"),
             host_dev_name: String::from("hostname"),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         };
         insert_net_device(
             &mut vmm,
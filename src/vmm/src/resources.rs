@@ -207,6 +207,10 @@ impl VmResources {
                 self.net_builder.add_device(network);
             }
 
+            SharedDeviceType::VhostNet(network) => {
+                self.net_builder.add_vhost_net_device(network);
+            }
+
             SharedDeviceType::Balloon(balloon) => {
                 self.balloon.set_device(balloon);
             }
@@ -322,6 +326,7 @@ impl VmResources {
                 version: mmds.lock().expect("Poisoned lock").version(),
                 network_interfaces: vec![],
                 ipv4_address: None,
+                dns: None,
             };
 
             for net_dev in net_devs_with_mmds {
@@ -332,6 +337,7 @@ impl VmResources {
                     // Safe to unwrap the mmds_ns as the filter() explicitly checks for
                     // its existence.
                     inner_mmds_config.ipv4_address = Some(net.mmds_ns().unwrap().ipv4_addr());
+                    inner_mmds_config.dns = net.mmds_ns().unwrap().dns_config().cloned();
                 }
             }
 
@@ -399,6 +405,16 @@ impl VmResources {
         Ok(())
     }
 
+    /// Returns the current configuration of the network interface with `iface_id` id.
+    pub fn net_config(
+        &self,
+        iface_id: &str,
+    ) -> std::result::Result<NetworkInterfaceConfig, NetworkInterfaceError> {
+        self.net_builder
+            .config(iface_id)
+            .ok_or_else(|| NetworkInterfaceError::DeviceNotFound(iface_id.to_string()))
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<VsockConfigError> {
         self.vsock.insert(config)
@@ -441,12 +457,27 @@ impl VmResources {
             _ => Err(MmdsConfigError::InvalidIpv4Addr),
         }?;
 
+        if let Some(dns) = config.dns() {
+            dns.validate().map_err(MmdsConfigError::Dns)?;
+        }
+
         let network_interfaces = config.network_interfaces();
         // Ensure that at least one network ID is specified.
         if network_interfaces.is_empty() {
             return Err(MmdsConfigError::EmptyNetworkIfaceList);
         }
 
+        // The frames of vhost-net interfaces bypass Firecracker, so they can't reach the MMDS.
+        if let Some(net) = self
+            .net_builder
+            .vhost_net_iter()
+            .find(|net| network_interfaces.contains(net.lock().expect("Poisoned lock").id()))
+        {
+            return Err(MmdsConfigError::VhostNetInterface(
+                net.lock().expect("Poisoned lock").id().clone(),
+            ));
+        }
+
         // Ensure all interface IDs specified correspond to existing net devices.
         if !network_interfaces.iter().all(|id| {
             self.net_builder
@@ -467,6 +498,7 @@ impl VmResources {
             let mut net_device_lock = net_device.lock().expect("Poisoned lock");
             if network_interfaces.contains(net_device_lock.id()) {
                 net_device_lock.configure_mmds_network_stack(ipv4_addr, mmds.clone());
+                net_device_lock.set_mmds_dns_config(config.dns().cloned());
             } else {
                 net_device_lock.disable_mmds_network_stack();
             }
@@ -508,7 +540,7 @@ mod tests {
     use crate::vmm_config::boot_source::{
         BootConfig, BootSource, BootSourceConfig, DEFAULT_KERNEL_CMDLINE,
     };
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
+    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType, ImageFormat};
     use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
@@ -527,9 +559,18 @@ mod tests {
                 .to_str()
                 .unwrap()
                 .to_string(),
+            host_socket_path: None,
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         }
     }
 
@@ -545,13 +586,22 @@ mod tests {
         (
             BlockDeviceConfig {
                 drive_id: "block1".to_string(),
-                path_on_host: tmp_file.as_path().to_str().unwrap().to_string(),
+                path_on_host: Some(tmp_file.as_path().to_str().unwrap().to_string()),
                 is_root_device: false,
                 partuuid: Some("0eaa91a0-01".to_string()),
                 cache_type: CacheType::Unsafe,
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
                 file_engine_type: FileEngineType::default(),
+                io_engine_config: None,
+                format: ImageFormat::default(),
+                enable_discard: false,
+                num_queues: None,
+                topology: None,
+                verity: None,
+                encryption: None,
+                socket: None,
+                nbd: None,
             },
             tmp_file,
         )
@@ -1253,6 +1303,70 @@ mod tests {
             let vmm_config: VmmConfig = (&resources).into();
             assert_eq!(initial_vmm_config, vmm_config);
         }
+
+        // MMDS configured with a DNS responder.
+        {
+            let kernel_file = TempFile::new().unwrap();
+            let rootfs_file = TempFile::new().unwrap();
+            let json = format!(
+                r#"{{
+                    "balloon": {{
+                        "amount_mib": 0,
+                        "deflate_on_oom": false,
+                        "stats_polling_interval_s": 0
+                    }},
+                    "boot-source": {{
+                        "kernel_image_path": "{}",
+                        "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                    }},
+                    "drives": [
+                        {{
+                            "drive_id": "rootfs",
+                            "path_on_host": "{}",
+                            "is_root_device": true,
+                            "is_read_only": false
+                        }}
+                    ],
+                    "network-interfaces": [
+                        {{
+                            "iface_id": "netif1",
+                            "host_dev_name": "hostname9"
+                        }},
+                        {{
+                            "iface_id": "netif2",
+                            "host_dev_name": "hostname10"
+                        }}
+                    ],
+                    "machine-config": {{
+                        "vcpu_count": 2,
+                        "mem_size_mib": 1024,
+                        "smt": false
+                    }},
+                    "mmds-config": {{
+                        "network_interfaces": ["netif1", "netif2"],
+                        "ipv4_address": "169.254.1.1",
+                        "dns": {{
+                            "records": {{
+                                "host.internal": "10.0.0.1"
+                            }}
+                        }}
+                    }}
+            }}"#,
+                kernel_file.as_path().to_str().unwrap(),
+                rootfs_file.as_path().to_str().unwrap(),
+            );
+            let resources = VmResources::from_json(
+                json.as_str(),
+                &InstanceInfo::default(),
+                HTTP_MAX_PAYLOAD_SIZE,
+                None,
+            )
+            .unwrap();
+
+            let initial_vmm_config = serde_json::from_slice::<VmmConfig>(json.as_bytes()).unwrap();
+            let vmm_config: VmmConfig = (&resources).into();
+            assert_eq!(initial_vmm_config, vmm_config);
+        }
     }
 
     #[test]
@@ -1446,7 +1560,7 @@ mod tests {
         let (mut new_block_device_cfg, _file) = default_block_cfg();
         let tmp_file = TempFile::new().unwrap();
         new_block_device_cfg.drive_id = "block2".to_string();
-        new_block_device_cfg.path_on_host = tmp_file.as_path().to_str().unwrap().to_string();
+        new_block_device_cfg.path_on_host = Some(tmp_file.as_path().to_str().unwrap().to_string());
         assert_eq!(vm_resources.block.list.len(), 1);
         vm_resources.set_block_device(new_block_device_cfg).unwrap();
         assert_eq!(vm_resources.block.list.len(), 2);
@@ -1475,8 +1589,18 @@ mod tests {
         new_net_device_cfg.host_dev_name = "dummy_path2".to_string();
         assert_eq!(vm_resources.net_builder.len(), 1);
 
-        vm_resources.build_net_device(new_net_device_cfg).unwrap();
+        vm_resources
+            .build_net_device(new_net_device_cfg.clone())
+            .unwrap();
         assert_eq!(vm_resources.net_builder.len(), 2);
+        assert_eq!(
+            vm_resources.net_config("new_net_if").unwrap(),
+            new_net_device_cfg
+        );
+        assert_eq!(
+            vm_resources.net_config("missing").unwrap_err().to_string(),
+            NetworkInterfaceError::DeviceNotFound("missing".to_string()).to_string()
+        );
     }
 
     #[test]
@@ -68,6 +68,8 @@ pub enum VmmAction {
     GetFullVmConfig,
     /// Get MMDS contents.
     GetMMDS,
+    /// Get the current configuration of the network interface with the given ID.
+    GetNetworkInterface(String),
     /// Get the machine configuration of the microVM.
     GetVmMachineConfig,
     /// Get microVM instance information.
@@ -224,6 +226,8 @@ pub enum VmmData {
     MachineConfiguration(VmConfig),
     /// Mmds contents.
     MmdsValue(serde_json::Value),
+    /// The current configuration of a network interface.
+    NetworkInterface(NetworkInterfaceConfig),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
     /// The microVM version.
@@ -416,6 +420,11 @@ impl<'a> PrebootApiController<'a> {
                 Ok(VmmData::FullVmConfig((&*self.vm_resources).into()))
             }
             GetMMDS => self.get_mmds(),
+            GetNetworkInterface(iface_id) => self
+                .vm_resources
+                .net_config(&iface_id)
+                .map(VmmData::NetworkInterface)
+                .map_err(VmmActionError::NetworkConfig),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
@@ -627,6 +636,11 @@ impl RuntimeApiController {
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetMMDS => self.get_mmds(),
+            GetNetworkInterface(iface_id) => self
+                .vm_resources
+                .net_config(&iface_id)
+                .map(VmmData::NetworkInterface)
+                .map_err(VmmActionError::NetworkConfig),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
             )),
@@ -657,7 +671,7 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateNetworkInterface(netif_update) => self.update_net_device(netif_update),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
@@ -787,7 +801,8 @@ impl RuntimeApiController {
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device, update the disk image on the
     ///    device and its virtio configuration
-    ///  - rate limiter configuration.
+    ///  - rate limiter configuration
+    ///  - tracing of the requests processed by the device.
     fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> ActionResult {
         let mut vmm = self.vmm.lock().expect("Poisoned lock");
         if let Some(new_path) = new_cfg.path_on_host {
@@ -804,24 +819,35 @@ impl RuntimeApiController {
             .map(|()| VmmData::Empty)
             .map_err(DriveError::DeviceUpdate)?;
         }
+        if let Some(trace) = new_cfg.trace {
+            vmm.update_block_trace(&new_cfg.drive_id, &trace)
+                .map_err(DriveError::DeviceUpdate)?;
+        }
         Ok(VmmData::Empty)
     }
 
-    /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .update_net_rate_limiters(
-                &new_cfg.iface_id,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
-            )
-            .map(|()| VmmData::Empty)
-            .map_err(NetworkInterfaceError::DeviceUpdate)
-            .map_err(VmmActionError::NetworkConfig)
+    /// Updates net device properties:
+    ///  - rate limiters configuration
+    ///  - capture of the frames exchanged by the device.
+    fn update_net_device(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        vmm.update_net_rate_limiters(
+            &new_cfg.iface_id,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+        )
+        .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        if let Some(capture) = new_cfg.capture {
+            vmm.update_net_capture(&new_cfg.iface_id, &capture)
+                .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        }
+        if let Some(link_up) = new_cfg.link_up {
+            vmm.update_net_link_state(&new_cfg.iface_id, link_up)
+                .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        }
+        Ok(VmmData::Empty)
     }
 }
 
@@ -836,8 +862,9 @@ mod tests {
 
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::{CacheType, FileEngineType};
+    use crate::vmm_config::drive::{CacheType, FileEngineType, ImageFormat, TraceConfig};
     use crate::vmm_config::logger::LoggerLevel;
+    use crate::vmm_config::net::CaptureConfig;
     use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
     use crate::vmm_config::vsock::VsockBuilder;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
@@ -902,6 +929,16 @@ mod tests {
             Ok(BalloonConfig::default())
         }
 
+        pub fn net_config(
+            &self,
+            iface_id: &str,
+        ) -> Result<NetworkInterfaceConfig, NetworkInterfaceError> {
+            if self.force_errors {
+                return Err(NetworkInterfaceError::DeviceNotFound(iface_id.to_string()));
+            }
+            Ok(mock_net_config(iface_id))
+        }
+
         pub fn track_dirty_pages(&self) -> bool {
             self.vm_config().track_dirty_pages
         }
@@ -1015,6 +1052,25 @@ mod tests {
         }
     }
 
+    fn mock_net_config(iface_id: &str) -> NetworkInterfaceConfig {
+        NetworkInterfaceConfig {
+            iface_id: iface_id.to_string(),
+            host_dev_name: String::new(),
+            host_socket_path: None,
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
+        }
+    }
+
     impl From<&MockVmRes> for VmmConfig {
         fn from(_: &MockVmRes) -> Self {
             VmmConfig::default()
@@ -1033,7 +1089,10 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub update_block_trace_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_net_capture_called: bool,
+        pub update_net_link_state_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1117,6 +1176,16 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_block_trace(&mut self, _: &str, _: &TraceConfig) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_block_trace_called = true;
+            Ok(())
+        }
+
         pub fn update_net_rate_limiters(
             &mut self,
             _: &str,
@@ -1134,6 +1203,26 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_capture(&mut self, _: &str, _: &CaptureConfig) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_capture_called = true;
+            Ok(())
+        }
+
+        pub fn update_net_link_state(&mut self, _: &str, _: bool) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_link_state_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1265,6 +1354,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_get_net_config() {
+        let req = VmmAction::GetNetworkInterface(String::from("net0"));
+        check_preboot_request(req, |result, _| {
+            assert_eq!(
+                result,
+                Ok(VmmData::NetworkInterface(mock_net_config("net0")))
+            )
+        });
+
+        let req = VmmAction::GetNetworkInterface(String::from("net0"));
+        check_preboot_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceNotFound(String::from(
+                "net0",
+            ))),
+        );
+    }
+
     #[test]
     fn test_preboot_get_balloon_config() {
         let req = VmmAction::GetBalloonConfig;
@@ -1308,7 +1416,7 @@ mod tests {
     #[test]
     fn test_preboot_insert_block_dev() {
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -1316,6 +1424,15 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1323,7 +1440,7 @@ mod tests {
         });
 
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -1331,6 +1448,15 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         });
         check_preboot_request_err(
             req,
@@ -1343,9 +1469,18 @@ mod tests {
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1355,9 +1490,18 @@ mod tests {
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         });
         check_preboot_request_err(
             req,
@@ -1398,6 +1542,7 @@ mod tests {
             ipv4_address: None,
             version: MmdsVersion::V2,
             network_interfaces: Vec::new(),
+            dns: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1411,6 +1556,7 @@ mod tests {
             ipv4_address: None,
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
+            dns: None,
         });
         check_preboot_request_err(
             req,
@@ -1652,6 +1798,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            drive_encryption: Vec::new(),
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1668,6 +1815,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            drive_encryption: Vec::new(),
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1715,6 +1863,8 @@ mod tests {
                 iface_id: String::new(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                capture: None,
+                link_up: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1879,6 +2029,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_get_net_config() {
+        let req = VmmAction::GetNetworkInterface(String::from("net0"));
+        check_runtime_request(req, |result, _| {
+            assert_eq!(
+                result,
+                Ok(VmmData::NetworkInterface(mock_net_config("net0")))
+            )
+        });
+    }
+
     #[test]
     fn test_runtime_balloon_config() {
         let req = VmmAction::GetBalloonConfig;
@@ -1969,12 +2130,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_update_block_trace() {
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            trace: Some(TraceConfig::default()),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_block_trace_called);
+            assert!(!vmm.update_block_device_path_called);
+        });
+
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            trace: Some(TraceConfig::default()),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            capture: None,
+            link_up: None,
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1985,6 +2172,67 @@ mod tests {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            capture: None,
+            link_up: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_capture() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            capture: Some(CaptureConfig::default()),
+            link_up: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_capture_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            capture: Some(CaptureConfig::default()),
+            link_up: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_link_state() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            capture: None,
+            link_up: Some(false),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_link_state_called);
+            assert!(!vmm.update_net_capture_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            capture: None,
+            link_up: Some(true),
         });
         check_runtime_request_err(
             req,
@@ -2017,7 +2265,7 @@ mod tests {
         );
         check_runtime_request_err(
             VmmAction::InsertBlockDevice(BlockDeviceConfig {
-                path_on_host: String::new(),
+                path_on_host: Some(String::new()),
                 is_root_device: false,
                 partuuid: None,
                 cache_type: CacheType::Unsafe,
@@ -2025,6 +2273,15 @@ mod tests {
                 drive_id: String::new(),
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                io_engine_config: None,
+                format: ImageFormat::default(),
+                enable_discard: false,
+                num_queues: None,
+                topology: None,
+                verity: None,
+                encryption: None,
+                socket: None,
+                nbd: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2032,9 +2289,18 @@ mod tests {
             VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
                 iface_id: String::new(),
                 host_dev_name: String::new(),
+                host_socket_path: None,
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: None,
+                mergeable_rx_buffers: false,
+                ctrl_queue: false,
+                vhost_net: false,
+                tx_filter: None,
+                link_up: None,
+                dhcp: None,
+                user_nat: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2063,6 +2329,7 @@ mod tests {
                 ipv4_address: None,
                 version: MmdsVersion::default(),
                 network_interfaces: Vec::new(),
+                dns: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2079,6 +2346,7 @@ mod tests {
                 },
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                drive_encryption: Vec::new(),
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2101,6 +2369,7 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            drive_encryption: Vec::new(),
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -2120,7 +2389,7 @@ mod tests {
         verify_load_snap_disallowed_after_boot_resources(req, "ConfigureBootSource");
 
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -2128,15 +2397,33 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
         let req = VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
             iface_id: String::new(),
             host_dev_name: String::new(),
+            host_socket_path: None,
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -2157,6 +2444,7 @@ mod tests {
             ipv4_address: None,
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
+            dns: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetMmdsConfiguration");
     }
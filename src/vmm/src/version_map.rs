@@ -6,9 +6,10 @@
 use std::collections::HashMap;
 
 use devices::virtio::block::persist::BlockState;
-use devices::virtio::net::persist::NetConfigSpaceState;
+use devices::virtio::net::persist::{NetConfigSpaceState, NetState};
 use devices::virtio::QueueState;
 use lazy_static::lazy_static;
+use mmds::persist::MmdsNetworkStackState;
 use versionize::{VersionMap, Versionize};
 
 use crate::device_manager::persist::DeviceStates;
@@ -29,6 +30,8 @@ pub const FC_V1_0_SNAP_VERSION: u16 = 4;
 pub const FC_V1_1_SNAP_VERSION: u16 = 5;
 /// Snap version for Firecracker v1.2
 pub const FC_V1_2_SNAP_VERSION: u16 = 6;
+/// Snap version for Firecracker v1.3
+pub const FC_V1_3_SNAP_VERSION: u16 = 7;
 
 lazy_static! {
     // Note: until we have a better design, this needs to be updated when the version changes.
@@ -58,6 +61,12 @@ lazy_static! {
         #[cfg(target_arch = "x86_64")]
         version_map.set_type_version(VcpuState::type_id(), 3);
 
+        // v1.3 state change mappings.
+        version_map.new_version().set_type_version(BlockState::type_id(), 4);
+        version_map.set_type_version(NetState::type_id(), 2);
+        version_map.set_type_version(MmdsNetworkStackState::type_id(), 2);
+        version_map.set_type_version(DeviceStates::type_id(), 4);
+
         version_map
     };
 
@@ -82,6 +91,7 @@ lazy_static! {
         mapping.insert(String::from("1.0.0"), FC_V1_0_SNAP_VERSION);
         mapping.insert(String::from("1.1.0"), FC_V1_1_SNAP_VERSION);
         mapping.insert(String::from("1.2.0"), FC_V1_2_SNAP_VERSION);
+        mapping.insert(String::from("1.3.0"), FC_V1_3_SNAP_VERSION);
 
         mapping
     };
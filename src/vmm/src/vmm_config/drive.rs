@@ -9,10 +9,14 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::{io, result};
 
-pub use devices::virtio::block::device::FileEngineType;
+pub use devices::virtio::block::device::{
+    BlockTopology, FileEngineType, ImageFormat, IoEngineConfig,
+};
 use devices::virtio::block::Error as BlockError;
-use devices::virtio::Block;
+pub use devices::virtio::block::{EncryptionConfig, NbdConfig, TraceConfig, VerityConfig};
+use devices::virtio::vhost_user_block::Error as VhostUserBlockError;
 pub use devices::virtio::CacheType;
+use devices::virtio::{Block, VhostUserBlock};
 use serde::{Deserialize, Serialize};
 
 use super::RateLimiterConfig;
@@ -30,14 +34,22 @@ pub enum DriveError {
     CreateBlockDevice(BlockError),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Unable to connect to the vhost-user backend or to negotiate the device with it.
+    CreateVhostUserBlockDevice(VhostUserBlockError),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// Not exactly one of the backing file, the vhost-user socket and the NBD export was provided.
+    InvalidBlockDeviceBackend,
     /// The block device path is invalid.
     InvalidBlockDevicePath(String),
+    /// The IO engine options were specified for an engine other than `Async`.
+    InvalidIoEngineConfig,
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The option is handled by the vhost-user backend.
+    UnsupportedVhostUserOption(&'static str),
 }
 
 impl Display for DriveError {
@@ -47,14 +59,30 @@ impl Display for DriveError {
             CreateBlockDevice(err) => write!(f, "Unable to create the block device {:?}", err),
             BlockDeviceUpdateFailed(err) => write!(f, "The update operation failed: {}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateVhostUserBlockDevice(err) => {
+                write!(f, "Unable to create the vhost-user block device {:?}", err)
+            }
             DeviceUpdate(err) => write!(f, "Error during drive update (patch): {}", err),
+            InvalidBlockDeviceBackend => write!(
+                f,
+                "Exactly one of path_on_host, socket and nbd must be specified."
+            ),
             InvalidBlockDevicePath(path) => write!(f, "Invalid block device path: {}", path),
+            InvalidIoEngineConfig => write!(
+                f,
+                "The io_engine_config option is only supported for the Async and Auto io_engines."
+            ),
             OpenBlockDevice(err) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 err
             ),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            UnsupportedVhostUserOption(option) => write!(
+                f,
+                "The {} option is not supported for vhost-user block devices.",
+                option
+            ),
         }
     }
 }
@@ -65,8 +93,8 @@ impl Display for DriveError {
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
-    /// Path of the drive.
-    pub path_on_host: String,
+    /// Path of the drive. Exactly one of this, `socket` and `nbd` must be specified.
+    pub path_on_host: Option<String>,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
     /// guest under /dev/vda unless the partuuid is present.
@@ -87,6 +115,28 @@ pub struct BlockDeviceConfig {
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// Tuning options for the `Async` IO engine.
+    pub io_engine_config: Option<IoEngineConfig>,
+    /// The format of the disk image found at `path_on_host`.
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// If set to true, the drive will advertise discard and write zeroes support to the guest
+    /// driver. Discarded ranges are deallocated from the backing file.
+    #[serde(default)]
+    pub enable_discard: bool,
+    /// The number of request queues exposed to the guest driver. Defaults to a single queue.
+    pub num_queues: Option<u16>,
+    /// The block sizes and IO size hints advertised to the guest driver.
+    pub topology: Option<BlockTopology>,
+    /// The hash tree which the data read from the drive is verified against.
+    pub verity: Option<VerityConfig>,
+    /// The key which the drive is transparently encrypted with. The key is never reported back.
+    pub encryption: Option<EncryptionConfig>,
+    /// Path of the Unix socket of a vhost-user backend which processes the requests of the
+    /// drive, instead of a backing file.
+    pub socket: Option<String>,
+    /// The NBD export which backs the drive, instead of a backing file.
+    pub nbd: Option<NbdConfig>,
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -94,13 +144,62 @@ impl From<&Block> for BlockDeviceConfig {
         let rl: RateLimiterConfig = block.rate_limiter().into();
         BlockDeviceConfig {
             drive_id: block.id().clone(),
-            path_on_host: block.file_path().clone(),
+            // The path of an NBD drive is the URI of the export.
+            path_on_host: match block.nbd() {
+                Some(_) => None,
+                None => Some(block.file_path().clone()),
+            },
             is_root_device: block.is_root_device(),
             partuuid: block.partuuid().cloned(),
             is_read_only: block.is_read_only(),
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
             file_engine_type: block.file_engine_type(),
+            io_engine_config: match block.io_engine_config() {
+                config if config == IoEngineConfig::default() => None,
+                config => Some(config),
+            },
+            format: block.image_format(),
+            enable_discard: block.is_discard_enabled(),
+            num_queues: match block.num_queues() {
+                1 => None,
+                num_queues => Some(num_queues as u16),
+            },
+            topology: match block.topology() {
+                topology if topology == BlockTopology::default() => None,
+                topology => Some(topology),
+            },
+            verity: block.verity(),
+            encryption: block.is_encrypted().then(EncryptionConfig::default),
+            socket: None,
+            nbd: block.nbd().cloned(),
+        }
+    }
+}
+
+impl From<&VhostUserBlock> for BlockDeviceConfig {
+    fn from(block: &VhostUserBlock) -> Self {
+        BlockDeviceConfig {
+            drive_id: block.id().clone(),
+            path_on_host: None,
+            is_root_device: block.is_root_device(),
+            partuuid: block.partuuid().cloned(),
+            is_read_only: block.is_read_only(),
+            cache_type: CacheType::default(),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: match block.num_queues() {
+                1 => None,
+                num_queues => Some(num_queues as u16),
+            },
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: Some(block.socket_path().clone()),
+            nbd: None,
         }
     }
 }
@@ -116,6 +215,8 @@ pub struct BlockDeviceUpdateConfig {
     pub path_on_host: Option<String>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// Starts or stops tracing the requests processed by the drive.
+    pub trace: Option<TraceConfig>,
 }
 
 /// Wrapper for the collection that holds all the Block Devices
@@ -127,6 +228,9 @@ pub struct BlockBuilder {
     // specified in order to avoid bugs in case of switching from partuuid boot
     // scenarios to /dev/vda boot type.
     pub list: VecDeque<Arc<Mutex<Block>>>,
+    /// The list of vhost-user block devices.
+    /// If the root block device is a vhost-user one, it would be the first in this list.
+    pub vhost_user_list: VecDeque<Arc<Mutex<VhostUserBlock>>>,
 }
 
 impl BlockBuilder {
@@ -134,17 +238,38 @@ impl BlockBuilder {
     pub fn new() -> Self {
         Self {
             list: VecDeque::<Arc<Mutex<Block>>>::new(),
+            vhost_user_list: VecDeque::<Arc<Mutex<VhostUserBlock>>>::new(),
         }
     }
 
-    /// Specifies whether there is a root block device already present in the list.
-    fn has_root_device(&self) -> bool {
-        // If there is a root device, it would be at the top of the list.
+    /// Gets the ID of the root block device, if there is one.
+    fn root_device_id(&self) -> Option<String> {
+        // If there is a root device, it would be at the top of one of the lists.
         if let Some(block) = self.list.get(0) {
-            block.lock().expect("Poisoned lock").is_root_device()
-        } else {
-            false
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
         }
+        if let Some(block) = self.vhost_user_list.get(0) {
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
+        }
+        None
+    }
+
+    /// Specifies whether there is a root block device already present in the lists.
+    fn has_root_device(&self) -> bool {
+        self.root_device_id().is_some()
+    }
+
+    /// Specifies whether the root block device is a vhost-user one.
+    pub fn has_vhost_user_root_device(&self) -> bool {
+        self.vhost_user_list.get(0).map_or(false, |block| {
+            block.lock().expect("Poisoned lock").is_root_device()
+        })
     }
 
     /// Gets the index of the device with the specified `drive_id` if it exists in the list.
@@ -154,6 +279,14 @@ impl BlockBuilder {
             .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
     }
 
+    /// Gets the index of the vhost-user device with the specified `drive_id` if it exists in the
+    /// list.
+    fn get_vhost_user_index_of_drive_id(&self, drive_id: &str) -> Option<usize> {
+        self.vhost_user_list
+            .iter()
+            .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
+    }
+
     /// Inserts an existing block device.
     pub fn add_device(&mut self, block_device: Arc<Mutex<Block>>) {
         if block_device.lock().expect("Poisoned lock").is_root_device() {
@@ -163,55 +296,93 @@ impl BlockBuilder {
         }
     }
 
-    /// Inserts a `Block` in the block devices list using the specified configuration.
+    /// Inserts a `Block` or a `VhostUserBlock` in the block devices lists using the specified
+    /// configuration.
     /// If a block with the same id already exists, it will overwrite it.
     /// Inserting a secondary root block device will fail.
     pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
         let is_root_device = config.is_root_device;
         let position = self.get_index_of_drive_id(&config.drive_id);
-        let has_root_block = self.has_root_device();
+        let vhost_user_position = self.get_vhost_user_index_of_drive_id(&config.drive_id);
 
         // Don't allow adding a second root block device.
         // If the new device cfg is root and not an update to the existing root, fail fast.
-        if is_root_device && has_root_block && position != Some(0) {
-            return Err(DriveError::RootBlockDeviceAlreadyAdded);
+        if let Some(root_device_id) = self.root_device_id() {
+            if is_root_device && root_device_id != config.drive_id {
+                return Err(DriveError::RootBlockDeviceAlreadyAdded);
+            }
         }
 
-        let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+        // An update may switch the drive from one kind of backend to the other, in which case
+        // the previous device is removed from its list.
+        match (&config.path_on_host, &config.socket, &config.nbd) {
+            (Some(_), None, None) | (None, None, Some(_)) => {
+                let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+                if let Some(index) = vhost_user_position {
+                    self.vhost_user_list.remove(index);
+                }
+                Self::insert_into(&mut self.list, position, is_root_device, block_dev);
+            }
+            (None, Some(_), None) => {
+                let block_dev = Arc::new(Mutex::new(Self::create_vhost_user_block(config)?));
+                if let Some(index) = position {
+                    self.list.remove(index);
+                }
+                Self::insert_into(
+                    &mut self.vhost_user_list,
+                    vhost_user_position,
+                    is_root_device,
+                    block_dev,
+                );
+            }
+            _ => return Err(DriveError::InvalidBlockDeviceBackend),
+        }
+        Ok(())
+    }
+
+    fn insert_into<T>(
+        list: &mut VecDeque<Arc<Mutex<T>>>,
+        position: Option<usize>,
+        is_root_device: bool,
+        block_dev: Arc<Mutex<T>>,
+    ) {
         // If the id of the drive already exists in the list, the operation is update/overwrite.
         match position {
             // New block device.
             None => {
                 if is_root_device {
-                    self.list.push_front(block_dev);
+                    list.push_front(block_dev);
                 } else {
-                    self.list.push_back(block_dev);
+                    list.push_back(block_dev);
                 }
             }
             // Update existing block device.
             Some(index) => {
                 // Update the slot with the new block.
-                self.list[index] = block_dev;
+                list[index] = block_dev;
                 // Check if the root block device is being updated.
                 if index != 0 && is_root_device {
                     // Make sure the root device is on the first position.
-                    self.list.swap(0, index);
+                    list.swap(0, index);
                 }
             }
         }
-        Ok(())
     }
 
     /// Creates a Block device from a BlockDeviceConfig.
     pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
-        // check if the path exists
-        let path_on_host = PathBuf::from(&block_device_config.path_on_host);
-        if !path_on_host.exists() {
-            return Err(DriveError::InvalidBlockDevicePath(format!(
-                "{}",
-                path_on_host.display()
-            )));
-        }
+        let path_on_host = match (block_device_config.path_on_host, &block_device_config.nbd) {
+            (Some(path_on_host), None) => {
+                // check if the path exists
+                if !PathBuf::from(&path_on_host).exists() {
+                    return Err(DriveError::InvalidBlockDevicePath(path_on_host));
+                }
+                path_on_host
+            }
+            // The path is only used to describe the drive.
+            (None, Some(nbd)) => nbd.uri(),
+            _ => return Err(DriveError::InvalidBlockDeviceBackend),
+        };
 
         let rate_limiter = block_device_config
             .rate_limiter
@@ -219,26 +390,95 @@ impl BlockBuilder {
             .transpose()
             .map_err(DriveError::CreateRateLimiter)?;
 
+        if block_device_config.io_engine_config.is_some()
+            && block_device_config.file_engine_type == FileEngineType::Sync
+        {
+            return Err(DriveError::InvalidIoEngineConfig);
+        }
+
         // Create and return the Block device
         devices::virtio::Block::new(
             block_device_config.drive_id,
             block_device_config.partuuid,
             block_device_config.cache_type,
-            block_device_config.path_on_host,
+            path_on_host,
             block_device_config.is_read_only,
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
             block_device_config.file_engine_type,
+            block_device_config.io_engine_config.unwrap_or_default(),
+            block_device_config.format,
+            block_device_config.enable_discard,
+            usize::from(block_device_config.num_queues.unwrap_or(1)),
+            block_device_config.topology.unwrap_or_default(),
+            block_device_config.verity,
+            block_device_config.encryption,
+            block_device_config.nbd,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
 
+    /// Creates a VhostUserBlock device from a BlockDeviceConfig, connecting to its backend.
+    pub fn create_vhost_user_block(
+        block_device_config: BlockDeviceConfig,
+    ) -> Result<VhostUserBlock> {
+        let socket = block_device_config
+            .socket
+            .ok_or(DriveError::InvalidBlockDeviceBackend)?;
+
+        // The requests are processed by the backend, so the options of the I/O path in
+        // Firecracker don't apply.
+        if block_device_config.rate_limiter.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("rate_limiter"));
+        }
+        if block_device_config.enable_discard {
+            return Err(DriveError::UnsupportedVhostUserOption("enable_discard"));
+        }
+        if block_device_config.cache_type != CacheType::default() {
+            return Err(DriveError::UnsupportedVhostUserOption("cache_type"));
+        }
+        if block_device_config.file_engine_type != FileEngineType::default() {
+            return Err(DriveError::UnsupportedVhostUserOption("io_engine"));
+        }
+        if block_device_config.io_engine_config.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("io_engine_config"));
+        }
+        if block_device_config.format != ImageFormat::default() {
+            return Err(DriveError::UnsupportedVhostUserOption("format"));
+        }
+        if block_device_config.topology.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("topology"));
+        }
+        if block_device_config.verity.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("verity"));
+        }
+        if block_device_config.encryption.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("encryption"));
+        }
+        if block_device_config.nbd.is_some() {
+            return Err(DriveError::InvalidBlockDeviceBackend);
+        }
+
+        VhostUserBlock::new(
+            block_device_config.drive_id,
+            block_device_config.partuuid,
+            socket,
+            block_device_config.is_read_only,
+            block_device_config.is_root_device,
+            usize::from(block_device_config.num_queues.unwrap_or(1)),
+        )
+        .map_err(DriveError::CreateVhostUserBlockDevice)
+    }
+
     /// Returns a vec with the structures used to configure the devices.
     pub fn configs(&self) -> Vec<BlockDeviceConfig> {
         let mut ret = vec![];
         for block in &self.list {
             ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
         }
+        for block in &self.vhost_user_list {
+            ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
+        }
         ret
     }
 }
@@ -269,6 +509,15 @@ mod tests {
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                io_engine_config: None,
+                format: self.format,
+                enable_discard: self.enable_discard,
+                num_queues: self.num_queues,
+                topology: self.topology,
+                verity: self.verity.clone(),
+                encryption: self.encryption.clone(),
+                socket: self.socket.clone(),
+                nbd: self.nbd.clone(),
             }
         }
     }
@@ -285,7 +534,7 @@ mod tests {
         let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
         let dummy_id = String::from("1");
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path,
+            path_on_host: Some(dummy_path),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Writeback,
@@ -293,6 +542,15 @@ mod tests {
             drive_id: dummy_id.clone(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -316,7 +574,7 @@ mod tests {
         let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
 
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path,
+            path_on_host: Some(dummy_path),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -324,6 +582,15 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -344,7 +611,7 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device_1 = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -352,12 +619,21 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let root_block_device_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -365,6 +641,15 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -381,7 +666,7 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -389,12 +674,21 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -402,12 +696,21 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
         let dummy_path_3 = dummy_file_3.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_3 = BlockDeviceConfig {
-            path_on_host: dummy_path_3,
+            path_on_host: Some(dummy_path_3),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -415,6 +718,15 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -445,7 +757,7 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -453,12 +765,21 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -466,12 +787,21 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
         let dummy_path_3 = dummy_file_3.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_3 = BlockDeviceConfig {
-            path_on_host: dummy_path_3,
+            path_on_host: Some(dummy_path_3),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -479,6 +809,15 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -510,7 +849,7 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1.clone(),
+            path_on_host: Some(dummy_path_1.clone()),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -518,12 +857,21 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let mut dummy_block_device_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2.clone(),
+            path_on_host: Some(dummy_path_2.clone()),
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -531,6 +879,15 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -567,14 +924,14 @@ mod tests {
 
         // Update with invalid path.
         let dummy_path_3 = String::from("test_update_3");
-        dummy_block_device_2.path_on_host = dummy_path_3.clone();
+        dummy_block_device_2.path_on_host = Some(dummy_path_3.clone());
         assert_eq!(
             block_devs.insert(dummy_block_device_2.clone()),
             Err(DriveError::InvalidBlockDevicePath(dummy_path_3))
         );
 
         // Update with 2 root block devices.
-        dummy_block_device_2.path_on_host = dummy_path_2.clone();
+        dummy_block_device_2.path_on_host = Some(dummy_path_2.clone());
         dummy_block_device_2.is_root_device = true;
         assert_eq!(
             block_devs.insert(dummy_block_device_2),
@@ -582,7 +939,7 @@ mod tests {
         );
 
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -590,12 +947,21 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
         root_block_device_old.is_root_device = false;
         let root_block_device_new = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             cache_type: CacheType::Unsafe,
@@ -603,6 +969,15 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -617,7 +992,7 @@ mod tests {
         let dummy_file = TempFile::new().unwrap();
 
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -625,6 +1000,15 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -635,6 +1019,388 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
     }
 
+    #[test]
+    fn test_block_config_num_queues() {
+        let dummy_file = TempFile::new().unwrap();
+
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: Some(4),
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert_eq!(block_devs.list[0].lock().unwrap().num_queues(), 4);
+        assert_eq!(block_devs.configs().first().unwrap(), &dummy_block_device);
+
+        // Zero queues is not a valid configuration.
+        dummy_block_device.num_queues = Some(0);
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateBlockDevice(BlockError::InvalidNumQueues(
+                0
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_block_config_io_engine() {
+        let dummy_file = TempFile::new().unwrap();
+
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            io_engine_config: Some(IoEngineConfig {
+                registered_buffers: true,
+                sqpoll_idle_ms: Some(100),
+            }),
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
+        };
+
+        // The options only apply to the Async engine.
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidIoEngineConfig)
+        );
+        assert!(block_devs.list.is_empty());
+
+        // The Auto engine reports the selected engine, along with the options if it is Async.
+        let io_engine_config = IoEngineConfig {
+            registered_buffers: true,
+            sqpoll_idle_ms: None,
+        };
+        let selected = FileEngineType::Auto.select(io_engine_config).unwrap();
+        dummy_block_device.file_engine_type = FileEngineType::Auto;
+        dummy_block_device.io_engine_config = Some(io_engine_config);
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        let config = &block_devs.configs()[0];
+        assert_eq!(config.file_engine_type, selected);
+        match selected {
+            FileEngineType::Async => {
+                assert_eq!(config.io_engine_config, dummy_block_device.io_engine_config)
+            }
+            _ => assert_eq!(config.io_engine_config, None),
+        }
+    }
+
+    #[test]
+    fn test_block_config_topology() {
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(0x10000).unwrap();
+
+        let topology = BlockTopology {
+            logical_block_size: Some(4096),
+            physical_block_size: None,
+            min_io_size: None,
+            opt_io_size: Some(0x10000),
+        };
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: Some(topology),
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert_eq!(block_devs.configs()[0].topology, Some(topology));
+
+        // The IO size hints must be multiples of the logical block size.
+        dummy_block_device.topology = Some(BlockTopology {
+            min_io_size: Some(512),
+            ..topology
+        });
+        match block_devs.insert(dummy_block_device) {
+            Err(DriveError::CreateBlockDevice(BlockError::InvalidTopology(_))) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_block_config_verity() {
+        // A single zeroed data block, whose digest is the root hash.
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(4096).unwrap();
+        let verity = VerityConfig {
+            root_hash: "ad7facb2586fc6e966c004d7d1d16b024f5805ff7cb47c7a85dabd8b48892ca7"
+                .to_string(),
+            hash_offset: 4096,
+            ..Default::default()
+        };
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: Some(verity.clone()),
+            encryption: None,
+            socket: None,
+            nbd: None,
+        };
+
+        // The verified drive must be read-only.
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(dummy_block_device.clone()) {
+            Err(DriveError::CreateBlockDevice(BlockError::UnsupportedVerity)) => (),
+            _ => unreachable!(),
+        }
+
+        dummy_block_device.is_read_only = true;
+        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert_eq!(block_devs.configs()[0].verity, Some(verity));
+
+        // The root hash must be a hex encoded SHA-256 digest.
+        dummy_block_device.verity = Some(VerityConfig {
+            root_hash: "0".repeat(63),
+            hash_offset: 4096,
+            ..Default::default()
+        });
+        match block_devs.insert(dummy_block_device) {
+            Err(DriveError::CreateBlockDevice(BlockError::Verity(_))) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_block_config_encryption() {
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(4096).unwrap();
+        let key = "11".repeat(16) + &"22".repeat(16);
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: true,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: Some(EncryptionConfig {
+                key: key.clone().into(),
+            }),
+            socket: None,
+            nbd: None,
+        };
+
+        // Discarded sectors can't be decrypted.
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(dummy_block_device.clone()) {
+            Err(DriveError::CreateBlockDevice(BlockError::UnsupportedEncryption)) => (),
+            _ => unreachable!(),
+        }
+
+        dummy_block_device.enable_discard = false;
+        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        // The key is never reported back.
+        let config = &block_devs.configs()[0];
+        assert_eq!(config.encryption, Some(EncryptionConfig::default()));
+        let json = serde_json::to_string(config).unwrap();
+        assert!(json.contains(r#""encryption":{}"#));
+        assert!(!json.contains(&key));
+        assert!(!format!("{:?}", dummy_block_device).contains(&key));
+
+        // The key must be a hex encoded AES-XTS key.
+        dummy_block_device.encryption = Some(EncryptionConfig {
+            key: "0".repeat(63).into(),
+        });
+        match block_devs.insert(dummy_block_device) {
+            Err(DriveError::CreateBlockDevice(BlockError::Crypt(_))) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_vhost_user_block_config() {
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: None,
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: None,
+        };
+        let mut block_devs = BlockBuilder::new();
+
+        // Exactly one of the backing file and the socket must be specified.
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidBlockDeviceBackend)
+        );
+        dummy_block_device.path_on_host = Some(dummy_path.clone());
+        dummy_block_device.socket = Some(dummy_path);
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidBlockDeviceBackend)
+        );
+
+        // The options of the I/O path in Firecracker are rejected.
+        dummy_block_device.path_on_host = None;
+        dummy_block_device.enable_discard = true;
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("enable_discard"))
+        );
+        dummy_block_device.enable_discard = false;
+        dummy_block_device.format = ImageFormat::Qcow2;
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("format"))
+        );
+        dummy_block_device.format = ImageFormat::Raw;
+        dummy_block_device.topology = Some(BlockTopology {
+            logical_block_size: Some(4096),
+            ..Default::default()
+        });
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("topology"))
+        );
+        dummy_block_device.topology = None;
+        dummy_block_device.verity = Some(VerityConfig::default());
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("verity"))
+        );
+        dummy_block_device.verity = None;
+        dummy_block_device.encryption = Some(EncryptionConfig::default());
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("encryption"))
+        );
+        dummy_block_device.encryption = None;
+
+        // Nothing listens on the socket.
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateVhostUserBlockDevice(_))
+        ));
+        assert!(block_devs.list.is_empty());
+        assert!(block_devs.vhost_user_list.is_empty());
+    }
+
+    #[test]
+    fn test_nbd_block_config() {
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_path.clone()),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            io_engine_config: None,
+            format: ImageFormat::default(),
+            enable_discard: false,
+            num_queues: None,
+            topology: None,
+            verity: None,
+            encryption: None,
+            socket: None,
+            nbd: Some(NbdConfig {
+                socket_path: Some(dummy_path),
+                ..Default::default()
+            }),
+        };
+        let mut block_devs = BlockBuilder::new();
+
+        // The export replaces the backing file.
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidBlockDeviceBackend)
+        );
+
+        // The export is accessed as a raw disk.
+        dummy_block_device.path_on_host = None;
+        dummy_block_device.format = ImageFormat::Qcow2;
+        match block_devs.insert(dummy_block_device.clone()) {
+            Err(DriveError::CreateBlockDevice(BlockError::UnsupportedNbd)) => (),
+            _ => unreachable!(),
+        }
+
+        // Nothing listens on the socket.
+        dummy_block_device.format = ImageFormat::Raw;
+        match block_devs.insert(dummy_block_device) {
+            Err(DriveError::CreateBlockDevice(BlockError::FileEngine(_))) => (),
+            _ => unreachable!(),
+        }
+        assert!(block_devs.list.is_empty());
+    }
+
     #[test]
     fn test_add_device() {
         let mut block_devs = BlockBuilder::new();
@@ -649,6 +1415,14 @@ mod tests {
             true,
             RateLimiter::default(),
             FileEngineType::default(),
+            IoEngineConfig::default(),
+            ImageFormat::Raw,
+            false,
+            1,
+            BlockTopology::default(),
+            None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -6,6 +6,8 @@ use std::net::Ipv4Addr;
 
 use mmds::data_store;
 use mmds::data_store::MmdsVersion;
+pub use mmds::dns::DnsConfig;
+use mmds::dns::Error as DnsError;
 use serde::{Deserialize, Serialize};
 
 /// Keeps the MMDS configuration.
@@ -19,6 +21,9 @@ pub struct MmdsConfig {
     pub network_interfaces: Vec<String>,
     /// MMDS IPv4 configured address.
     pub ipv4_address: Option<Ipv4Addr>,
+    /// Configuration of the DNS responder, which is disabled when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsConfig>,
 }
 
 impl MmdsConfig {
@@ -37,6 +42,12 @@ impl MmdsConfig {
     pub fn ipv4_addr(&self) -> Option<Ipv4Addr> {
         self.ipv4_address
     }
+
+    /// Returns the configuration of the DNS responder if one was configured.
+    /// Otherwise returns None.
+    pub fn dns(&self) -> Option<&DnsConfig> {
+        self.dns.as_ref()
+    }
 }
 
 /// MMDS configuration related errors.
@@ -49,8 +60,13 @@ pub enum MmdsConfigError {
     /// The network interfaces list provided contains IDs that
     /// does not correspond to any existing network interface.
     InvalidNetworkInterfaceId,
+    /// The network interfaces list provided contains the ID of a vhost-net
+    /// interface, whose frames can't be detoured to the MMDS.
+    VhostNetInterface(String),
     /// MMDS version could not be configured.
     MmdsVersion(MmdsVersion, data_store::Error),
+    /// The DNS responder configuration is invalid.
+    Dns(DnsError),
 }
 
 impl Display for MmdsConfigError {
@@ -73,6 +89,14 @@ impl Display for MmdsConfigError {
                      does not correspond to any existing network interface."
                 )
             }
+            MmdsConfigError::VhostNetInterface(id) => {
+                write!(
+                    f,
+                    "The MMDS requests cannot be forwarded from the vhost-net network interface \
+                     {}.",
+                    id
+                )
+            }
             MmdsConfigError::MmdsVersion(version, err) => {
                 write!(
                     f,
@@ -80,6 +104,9 @@ impl Display for MmdsConfigError {
                     version, err
                 )
             }
+            MmdsConfigError::Dns(err) => {
+                write!(f, "The MMDS DNS responder could not be configured: {}", err)
+            }
         }
     }
 }
@@ -6,8 +6,12 @@ use std::ops::Deref;
 use std::result;
 use std::sync::{Arc, Mutex};
 
+pub use devices::virtio::net::filter::{FilterAction, FilterProtocol, TxFilterRule};
+pub use devices::virtio::net::user_nat::{ForwardProtocol, PortForward};
 use devices::virtio::net::TapError;
-use devices::virtio::Net;
+pub use devices::virtio::net::{CaptureConfig, DhcpConfig, TxFilterConfig, UserNatConfig};
+use devices::virtio::vhost_net::Error as VhostNetError;
+use devices::virtio::{Net, VhostNet};
 use serde::{Deserialize, Serialize};
 use utils::net::mac::MacAddr;
 
@@ -22,13 +26,47 @@ pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
     /// Host level path for the guest network interface.
+    #[serde(default)]
     pub host_dev_name: String,
+    /// Path of a Unix socket exchanging the frames of the interface with another process, used
+    /// instead of a tap device.
+    #[serde(default)]
+    pub host_socket_path: Option<String>,
     /// Guest MAC address.
     pub guest_mac: Option<MacAddr>,
     /// Rate Limiter for received packages.
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// The number of rx/tx queue pairs exposed to the guest driver. Defaults to a single queue
+    /// pair. Multiple queue pairs require a multi-queue tap device.
+    pub num_queue_pairs: Option<u16>,
+    /// Offers mergeable receive buffers to the guest driver. Snapshots of microVMs whose driver
+    /// uses them can't be restored by older Firecracker versions.
+    #[serde(default)]
+    pub mergeable_rx_buffers: bool,
+    /// Offers the control queue to the guest driver, through which it programs its receive
+    /// filter, its VLANs and its MAC address. Snapshots of microVMs whose driver uses it can't be
+    /// restored by older Firecracker versions, unless the interface has several queue pairs.
+    #[serde(default)]
+    pub ctrl_queue: bool,
+    /// Offloads the data path of the interface to the vhost-net driver of the host kernel.
+    #[serde(default)]
+    pub vhost_net: bool,
+    /// Filter of the frames sent by the guest, dropping spoofed and denied frames.
+    #[serde(default)]
+    pub tx_filter: Option<TxFilterConfig>,
+    /// Whether the link is reported as up to the guest when it boots. Defaults to up.
+    #[serde(default)]
+    pub link_up: Option<bool>,
+    /// Lease handed to the guest by a DHCP server built into the interface.
+    #[serde(default)]
+    pub dhcp: Option<DhcpConfig>,
+    /// User-mode NAT exchanging the traffic of the guest through host sockets, used instead of a
+    /// tap device. Unless configured otherwise, its DHCP server leases the guest address of the
+    /// NAT network.
+    #[serde(default)]
+    pub user_nat: Option<UserNatConfig>,
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -38,15 +76,57 @@ impl From<&Net> for NetworkInterfaceConfig {
         NetworkInterfaceConfig {
             iface_id: net.id().clone(),
             host_dev_name: net.iface_name(),
+            host_socket_path: net.host_socket_path().cloned(),
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            num_queue_pairs: match net.num_queue_pairs() {
+                1 => None,
+                num_queue_pairs => Some(num_queue_pairs as u16),
+            },
+            mergeable_rx_buffers: net.has_mergeable_rx_buffers(),
+            ctrl_queue: net.has_ctrl_queue(),
+            vhost_net: false,
+            tx_filter: net.tx_filter().cloned(),
+            link_up: match net.has_link_state_reporting() {
+                true => Some(net.is_link_up()),
+                false => None,
+            },
+            // The lease derived from the user-mode NAT is reported as the default.
+            dhcp: net
+                .dhcp_config()
+                .filter(|dhcp| {
+                    Some(*dhcp) != net.user_nat().map(UserNatConfig::default_lease).as_ref()
+                })
+                .cloned(),
+            user_nat: net.user_nat().cloned(),
         }
     }
 }
 
-/// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
+impl From<&VhostNet> for NetworkInterfaceConfig {
+    fn from(net: &VhostNet) -> Self {
+        NetworkInterfaceConfig {
+            iface_id: net.id().clone(),
+            host_dev_name: net.iface_name(),
+            host_socket_path: None,
+            guest_mac: net.guest_mac().copied(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: true,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
+        }
+    }
+}
+
+/// The data fed into a network iface update request. Currently, only the RX and TX rate limiters,
+/// the capture of the frames and the link state can be updated.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
@@ -58,6 +138,10 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// Starts or stops capturing the frames exchanged by the interface.
+    pub capture: Option<CaptureConfig>,
+    /// Brings the link reported to the guest up or down.
+    pub link_up: Option<bool>,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -78,6 +162,24 @@ pub enum NetworkInterfaceError {
     /// Cannot open/create tap device
     #[error("Cannot open/create tap device: {0}")]
     OpenTap(#[from] TapError),
+    /// Could not create vhost-net device
+    #[error("Could not create vhost-net device: {0}")]
+    CreateVhostNetDevice(#[from] VhostNetError),
+    /// The option is handled by the Firecracker data path, which vhost-net interfaces bypass
+    #[error("The {0} option is not supported for vhost-net interfaces.")]
+    UnsupportedVhostNetOption(&'static str),
+    /// The interface can't be backed by both a tap device and a socket
+    #[error("The host_dev_name and host_socket_path options are mutually exclusive.")]
+    HostDevAndSocket,
+    /// The option relies on a tap device
+    #[error("The {0} option is not supported for interfaces backed by a socket.")]
+    UnsupportedSocketOption(&'static str),
+    /// The option conflicts with the user-mode NAT backing the interface
+    #[error("The {0} option is not supported for interfaces backed by a user-mode NAT.")]
+    UnsupportedUserNatOption(&'static str),
+    /// The interface doesn't exist
+    #[error("The network interface {0} does not exist.")]
+    DeviceNotFound(String),
 }
 
 type Result<T> = result::Result<T, NetworkInterfaceError>;
@@ -86,6 +188,7 @@ type Result<T> = result::Result<T, NetworkInterfaceError>;
 #[derive(Default)]
 pub struct NetBuilder {
     net_devices: Vec<Arc<Mutex<Net>>>,
+    vhost_net_devices: Vec<Arc<Mutex<VhostNet>>>,
 }
 
 impl NetBuilder {
@@ -94,6 +197,8 @@ impl NetBuilder {
         NetBuilder {
             /// List of built network devices.
             net_devices: Vec::new(),
+            /// List of built vhost-net devices.
+            vhost_net_devices: Vec::new(),
         }
     }
 
@@ -107,48 +212,120 @@ impl NetBuilder {
         self.net_devices.iter_mut()
     }
 
+    /// Returns a immutable iterator over the vhost-net devices.
+    pub fn vhost_net_iter(&self) -> ::std::slice::Iter<Arc<Mutex<VhostNet>>> {
+        self.vhost_net_devices.iter()
+    }
+
     /// Adds an existing network device in the builder.
     pub fn add_device(&mut self, device: Arc<Mutex<Net>>) {
         self.net_devices.push(device);
     }
 
+    /// Adds an existing vhost-net device in the builder.
+    pub fn add_vhost_net_device(&mut self, device: Arc<Mutex<VhostNet>>) {
+        self.vhost_net_devices.push(device);
+    }
+
     /// Builds a network device based on a network interface config. Keeps a device reference
-    /// in the builder's internal list.
-    pub fn build(&mut self, netif_config: NetworkInterfaceConfig) -> Result<Arc<Mutex<Net>>> {
-        let mac_conflict = |net: &Arc<Mutex<Net>>| {
-            let net = net.lock().expect("Poisoned lock");
+    /// in the builder's internal lists.
+    pub fn build(&mut self, netif_config: NetworkInterfaceConfig) -> Result<()> {
+        let is_conflicting = |guest_mac: Option<&MacAddr>, id: &String| {
             // Check if another net dev has same MAC.
             netif_config.guest_mac.is_some()
-                && netif_config.guest_mac.as_ref() == net.guest_mac()
-                && &netif_config.iface_id != net.id()
+                && netif_config.guest_mac.as_ref() == guest_mac
+                && &netif_config.iface_id != id
         };
         // Validate there is no Mac conflict.
         // No need to validate host_dev_name conflict. In such a case,
         // an error will be thrown during device creation anyway.
-        if self.net_devices.iter().any(mac_conflict) {
+        let mac_conflict = self.net_devices.iter().any(|net| {
+            let net = net.lock().expect("Poisoned lock");
+            is_conflicting(net.guest_mac(), net.id())
+        }) || self.vhost_net_devices.iter().any(|net| {
+            let net = net.lock().expect("Poisoned lock");
+            is_conflicting(net.guest_mac(), net.id())
+        });
+        if mac_conflict {
             return Err(NetworkInterfaceError::GuestMacAddressInUse(
                 netif_config.guest_mac.unwrap().to_string(),
             ));
         }
 
-        // If this is an update, just remove the old one.
-        if let Some(index) = self
+        let position = self
             .net_devices
             .iter()
-            .position(|net| net.lock().expect("Poisoned lock").id() == &netif_config.iface_id)
-        {
-            self.net_devices.swap_remove(index);
-        }
+            .position(|net| net.lock().expect("Poisoned lock").id() == &netif_config.iface_id);
+        let vhost_net_position = self
+            .vhost_net_devices
+            .iter()
+            .position(|net| net.lock().expect("Poisoned lock").id() == &netif_config.iface_id);
 
-        // Add new device.
-        let net = Arc::new(Mutex::new(Self::create_net(netif_config)?));
-        self.net_devices.push(net.clone());
+        // An update may switch the interface from one data path to the other. The previous
+        // device is removed from its list, which also releases its tap device.
+        if netif_config.vhost_net {
+            // MMDS requests are detoured by the Firecracker data path, so an interface which
+            // was configured to allow them can't be switched to vhost-net.
+            if let Some(index) = position {
+                if self.net_devices[index]
+                    .lock()
+                    .expect("Poisoned lock")
+                    .mmds_ns()
+                    .is_some()
+                {
+                    return Err(NetworkInterfaceError::UnsupportedVhostNetOption("mmds"));
+                }
+                self.net_devices.swap_remove(index);
+            }
+            if let Some(index) = vhost_net_position {
+                self.vhost_net_devices.swap_remove(index);
+            }
+            let net = Arc::new(Mutex::new(Self::create_vhost_net(netif_config)?));
+            self.vhost_net_devices.push(net);
+        } else {
+            if let Some(index) = vhost_net_position {
+                self.vhost_net_devices.swap_remove(index);
+            }
+            if let Some(index) = position {
+                self.net_devices.swap_remove(index);
+            }
+            let net = Arc::new(Mutex::new(Self::create_net(netif_config)?));
+            self.net_devices.push(net);
+        }
 
-        Ok(net)
+        Ok(())
     }
 
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
+        if cfg.host_socket_path.is_some() {
+            if !cfg.host_dev_name.is_empty() {
+                return Err(NetworkInterfaceError::HostDevAndSocket);
+            }
+            if cfg.num_queue_pairs.unwrap_or(1) != 1 {
+                return Err(NetworkInterfaceError::UnsupportedSocketOption(
+                    "num_queue_pairs",
+                ));
+            }
+        }
+        if cfg.user_nat.is_some() {
+            if !cfg.host_dev_name.is_empty() {
+                return Err(NetworkInterfaceError::UnsupportedUserNatOption(
+                    "host_dev_name",
+                ));
+            }
+            if cfg.host_socket_path.is_some() {
+                return Err(NetworkInterfaceError::UnsupportedUserNatOption(
+                    "host_socket_path",
+                ));
+            }
+            if cfg.num_queue_pairs.unwrap_or(1) != 1 {
+                return Err(NetworkInterfaceError::UnsupportedUserNatOption(
+                    "num_queue_pairs",
+                ));
+            }
+        }
+
         let rx_rate_limiter = cfg
             .rx_rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -160,15 +337,113 @@ impl NetBuilder {
             .transpose()
             .map_err(NetworkInterfaceError::CreateRateLimiter)?;
 
+        let dhcp = match &cfg.user_nat {
+            Some(user_nat) => cfg.dhcp.or_else(|| Some(user_nat.default_lease())),
+            None => cfg.dhcp,
+        };
+
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
-            cfg.iface_id,
-            &cfg.host_dev_name,
-            cfg.guest_mac,
-            rx_rate_limiter.unwrap_or_default(),
-            tx_rate_limiter.unwrap_or_default(),
-        )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = match (cfg.host_socket_path, &cfg.user_nat) {
+            (Some(socket_path), _) => devices::virtio::net::Net::new_with_socket(
+                cfg.iface_id,
+                &socket_path,
+                cfg.guest_mac,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+            ),
+            (None, Some(user_nat)) => devices::virtio::net::Net::new_with_user_nat(
+                cfg.iface_id,
+                user_nat,
+                cfg.guest_mac,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+            ),
+            (None, None) => devices::virtio::net::Net::new_with_tap(
+                cfg.iface_id,
+                &cfg.host_dev_name,
+                cfg.guest_mac,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+                usize::from(cfg.num_queue_pairs.unwrap_or(1)),
+            ),
+        }
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_mergeable_rx_buffers(cfg.mergeable_rx_buffers);
+        net.set_ctrl_queue(cfg.ctrl_queue);
+        net.set_tx_filter(cfg.tx_filter)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        // The link state is only reported to the driver when configured.
+        net.set_link_state_reporting(cfg.link_up.is_some());
+        net.set_link_up(cfg.link_up.unwrap_or(true))
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_dhcp(dhcp)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        Ok(net)
+    }
+
+    /// Creates a VhostNet device from a NetworkInterfaceConfig.
+    pub fn create_vhost_net(cfg: NetworkInterfaceConfig) -> Result<VhostNet> {
+        // The frames are exchanged with the tap device by the host kernel, so the options of
+        // the Firecracker data path don't apply.
+        if cfg.rx_rate_limiter.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "rx_rate_limiter",
+            ));
+        }
+        if cfg.tx_rate_limiter.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "tx_rate_limiter",
+            ));
+        }
+        if cfg.num_queue_pairs.unwrap_or(1) != 1 {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "num_queue_pairs",
+            ));
+        }
+        if cfg.host_socket_path.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "host_socket_path",
+            ));
+        }
+        if cfg.ctrl_queue {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "ctrl_queue",
+            ));
+        }
+        if cfg.tx_filter.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption(
+                "tx_filter",
+            ));
+        }
+        if cfg.link_up.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption("link_up"));
+        }
+        if cfg.dhcp.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption("dhcp"));
+        }
+        if cfg.user_nat.is_some() {
+            return Err(NetworkInterfaceError::UnsupportedVhostNetOption("user_nat"));
+        }
+
+        VhostNet::new(cfg.iface_id, &cfg.host_dev_name, cfg.guest_mac)
+            .map_err(NetworkInterfaceError::CreateVhostNetDevice)
+    }
+
+    /// Returns the structure describing the current state of the net device with `iface_id` id,
+    /// including the MAC set by the guest driver.
+    pub fn config(&self, iface_id: &str) -> Option<NetworkInterfaceConfig> {
+        self.net_devices
+            .iter()
+            .map(|net| net.lock().unwrap())
+            .find(|net| net.id() == iface_id)
+            .map(|net| NetworkInterfaceConfig::from(net.deref()))
+            .or_else(|| {
+                self.vhost_net_devices
+                    .iter()
+                    .map(|net| net.lock().unwrap())
+                    .find(|net| net.id() == iface_id)
+                    .map(|net| NetworkInterfaceConfig::from(net.deref()))
+            })
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -177,15 +452,23 @@ impl NetBuilder {
         for net in &self.net_devices {
             ret.push(NetworkInterfaceConfig::from(net.lock().unwrap().deref()));
         }
+        for net in &self.vhost_net_devices {
+            ret.push(NetworkInterfaceConfig::from(net.lock().unwrap().deref()));
+        }
         ret
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::net::UnixDatagram;
     use std::str;
 
+    use devices::virtio::net::test_utils::set_mac;
+    use mmds::data_store::Mmds;
+    use mmds::ns::MmdsNetworkStack;
     use rate_limiter::RateLimiter;
+    use utils::tempfile::TempFile;
 
     use super::*;
 
@@ -203,9 +486,18 @@ mod tests {
         NetworkInterfaceConfig {
             iface_id: String::from(id),
             host_dev_name: String::from(name),
+            host_socket_path: None,
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            num_queue_pairs: None,
+            mergeable_rx_buffers: false,
+            ctrl_queue: false,
+            vhost_net: false,
+            tx_filter: None,
+            link_up: None,
+            dhcp: None,
+            user_nat: None,
         }
     }
 
@@ -214,9 +506,18 @@ mod tests {
             NetworkInterfaceConfig {
                 iface_id: self.iface_id.clone(),
                 host_dev_name: self.host_dev_name.clone(),
+                host_socket_path: self.host_socket_path.clone(),
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: self.num_queue_pairs,
+                mergeable_rx_buffers: self.mergeable_rx_buffers,
+                ctrl_queue: self.ctrl_queue,
+                vhost_net: self.vhost_net,
+                tx_filter: self.tx_filter.clone(),
+                link_up: self.link_up,
+                dhcp: self.dhcp.clone(),
+                user_nat: self.user_nat.clone(),
             }
         }
     }
@@ -336,6 +637,420 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
     }
 
+    #[test]
+    fn test_net_config_num_queue_pairs() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "mqdev", "01:23:45:67:89:0b");
+        net_if_cfg.num_queue_pairs = Some(2);
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().num_queue_pairs(), 2);
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        net_if_cfg.num_queue_pairs = Some(0);
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidQueuePairs(0)
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_net_config_mergeable_rx_buffers() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "mrgdev", "01:23:45:67:89:0b");
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(!net.lock().unwrap().has_mergeable_rx_buffers());
+
+        net_if_cfg.mergeable_rx_buffers = true;
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(net.lock().unwrap().has_mergeable_rx_buffers());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+    }
+
+    #[test]
+    fn test_net_config_ctrl_queue() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "ctrldev", "01:23:45:67:89:0b");
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(!net.lock().unwrap().has_ctrl_queue());
+
+        net_if_cfg.ctrl_queue = true;
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(net.lock().unwrap().has_ctrl_queue());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+    }
+
+    #[test]
+    fn test_vhost_net_unsupported_options() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "vhdev", "01:23:45:67:89:0b");
+        net_if_cfg.vhost_net = true;
+        net_if_cfg.rx_rate_limiter = Some(RateLimiterConfig::default());
+        net_if_cfg.tx_rate_limiter = Some(RateLimiterConfig::default());
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("rx_rate_limiter").to_string()
+        );
+
+        net_if_cfg.rx_rate_limiter = None;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("tx_rate_limiter").to_string()
+        );
+
+        net_if_cfg.tx_rate_limiter = None;
+        net_if_cfg.num_queue_pairs = Some(2);
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("num_queue_pairs").to_string()
+        );
+
+        net_if_cfg.num_queue_pairs = None;
+        net_if_cfg.ctrl_queue = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("ctrl_queue").to_string()
+        );
+
+        // An interface allowing MMDS requests can't be switched to vhost-net.
+        let net_if_cfg = create_netif("id", "vhdev", "01:23:45:67:89:0b");
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        net_builder
+            .iter()
+            .next()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .configure_mmds_network_stack(
+                MmdsNetworkStack::default_ipv4_addr(),
+                Arc::new(Mutex::new(Mmds::default())),
+            );
+        let mut net_if_cfg = net_if_cfg;
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("mmds").to_string()
+        );
+        assert_eq!(net_builder.net_devices.len(), 1);
+        assert!(net_builder.vhost_net_devices.is_empty());
+    }
+
+    #[test]
+    fn test_net_config_socket() {
+        let mut temp_uds_path = TempFile::new().unwrap();
+        // Remove the file so the path can be used by the socket.
+        temp_uds_path.remove().unwrap();
+        let socket_path = temp_uds_path.as_path().to_str().unwrap().to_string();
+        let _peer = UnixDatagram::bind(&socket_path).unwrap();
+
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "", "01:23:45:67:89:0b");
+        net_if_cfg.host_socket_path = Some(socket_path.clone());
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().host_socket_path(), Some(&socket_path));
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        net_if_cfg.host_dev_name = String::from("sockdev");
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::HostDevAndSocket.to_string()
+        );
+
+        net_if_cfg.host_dev_name = String::new();
+        net_if_cfg.num_queue_pairs = Some(2);
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedSocketOption("num_queue_pairs").to_string()
+        );
+
+        net_if_cfg.num_queue_pairs = None;
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("host_socket_path").to_string()
+        );
+    }
+
+    #[test]
+    fn test_net_config_tx_filter() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "filterdev", "01:23:45:67:89:0b");
+        net_if_cfg.tx_filter = Some(TxFilterConfig {
+            check_source_mac: true,
+            allowed_ipv4_sources: Some(vec!["192.168.0.2".parse().unwrap()]),
+            rules: vec![TxFilterRule {
+                action: FilterAction::Deny,
+                protocol: FilterProtocol::Udp,
+                port: Some(53),
+            }],
+            default_action: FilterAction::Allow,
+        });
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(
+            net.lock().unwrap().tx_filter(),
+            net_if_cfg.tx_filter.as_ref()
+        );
+        assert_eq!(
+            net_builder.configs().first().unwrap().tx_filter,
+            net_if_cfg.tx_filter
+        );
+
+        // The source MAC can't be checked without a guest MAC.
+        let mut net_if_cfg = create_netif("id2", "filterdev2", "01:23:45:67:89:0c");
+        net_if_cfg.guest_mac = None;
+        net_if_cfg.tx_filter = Some(TxFilterConfig {
+            check_source_mac: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            net_builder.build(net_if_cfg.clone()),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::TxFilter(_)
+            ))
+        ));
+
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("tx_filter").to_string()
+        );
+    }
+
+    #[test]
+    fn test_net_config_link_state() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "linkdev", "01:23:45:67:89:0b");
+        net_if_cfg.link_up = Some(false);
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(!net.lock().unwrap().is_link_up());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        net_if_cfg.link_up = Some(true);
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(net.lock().unwrap().is_link_up());
+        assert!(net.lock().unwrap().has_link_state_reporting());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // The link state is only reported when configured.
+        net_if_cfg.link_up = None;
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert!(!net.lock().unwrap().has_link_state_reporting());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        net_if_cfg.link_up = Some(true);
+
+        net_if_cfg.iface_id = String::from("id2");
+        net_if_cfg.host_dev_name = String::from("linkdev2");
+        net_if_cfg.guest_mac = None;
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("link_up").to_string()
+        );
+    }
+
+    #[test]
+    fn test_net_config_dhcp() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "dhcpdev", "01:23:45:67:89:0b");
+        net_if_cfg.dhcp = Some(
+            serde_json::from_str(
+                r#"{
+                    "address": "10.0.0.2",
+                    "prefix_len": 24,
+                    "gateway": "10.0.0.1",
+                    "dns_servers": ["10.0.0.53"]
+                }"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            net_if_cfg.dhcp.as_ref().unwrap().lease_time_secs,
+            devices::virtio::net::dhcp::DEFAULT_LEASE_TIME_SECS
+        );
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().dhcp_config(), net_if_cfg.dhcp.as_ref());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // The server needs an address to identify itself with.
+        net_if_cfg.dhcp.as_mut().unwrap().gateway = None;
+        assert!(matches!(
+            net_builder.build(net_if_cfg.clone()),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::Dhcp(_)
+            ))
+        ));
+
+        net_if_cfg.iface_id = String::from("id2");
+        net_if_cfg.host_dev_name = String::from("dhcpdev2");
+        net_if_cfg.guest_mac = None;
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("dhcp").to_string()
+        );
+    }
+
+    #[test]
+    fn test_net_config_user_nat() {
+        let mut net_builder = NetBuilder::new();
+        let mut net_if_cfg = create_netif("id", "", "01:23:45:67:89:0b");
+        net_if_cfg.user_nat = Some(
+            serde_json::from_str(
+                r#"{
+                    "network": "192.168.100.0",
+                    "dns_servers": ["192.0.2.53"]
+                }"#,
+            )
+            .unwrap(),
+        );
+        let user_nat = net_if_cfg.user_nat.clone().unwrap();
+        assert_eq!(user_nat.prefix_len, 24);
+        assert!(!user_nat.allow_host_loopback);
+        assert!(user_nat.port_forwards.is_empty());
+
+        // The DHCP server leases the guest address of the NAT network by default.
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().user_nat(), Some(&user_nat));
+        assert_eq!(
+            net.lock().unwrap().dhcp_config(),
+            Some(&user_nat.default_lease())
+        );
+        assert_eq!(net.lock().unwrap().iface_name(), "");
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // A configured lease takes precedence.
+        let mut lease = user_nat.default_lease();
+        lease.lease_time_secs = 600;
+        net_if_cfg.dhcp = Some(lease);
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        let net = net_builder.iter().next().unwrap();
+        assert_eq!(net.lock().unwrap().dhcp_config(), net_if_cfg.dhcp.as_ref());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        net_if_cfg.dhcp = None;
+        net_if_cfg.host_dev_name = String::from("natdev");
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedUserNatOption("host_dev_name").to_string()
+        );
+
+        net_if_cfg.host_dev_name = String::new();
+        net_if_cfg.num_queue_pairs = Some(2);
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::UnsupportedUserNatOption("num_queue_pairs").to_string()
+        );
+
+        // The NAT network must leave room for the gateway and the guest.
+        net_if_cfg.num_queue_pairs = None;
+        net_if_cfg.user_nat.as_mut().unwrap().prefix_len = 28;
+        assert!(matches!(
+            net_builder.build(net_if_cfg.clone()),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::UserNat(_)
+            ))
+        ));
+
+        net_if_cfg.user_nat = Some(user_nat);
+        net_if_cfg.vhost_net = true;
+        assert_eq!(
+            net_builder.build(net_if_cfg).err().unwrap().to_string(),
+            NetworkInterfaceError::UnsupportedVhostNetOption("user_nat").to_string()
+        );
+    }
+
+    #[test]
+    fn test_port_forward_config() {
+        let forward: PortForward =
+            serde_json::from_str(r#"{"host_port": 8080, "guest_port": 80}"#).unwrap();
+        assert_eq!(
+            forward,
+            PortForward {
+                protocol: ForwardProtocol::Tcp,
+                host_address: std::net::Ipv4Addr::LOCALHOST,
+                host_port: 8080,
+                guest_port: 80,
+            }
+        );
+
+        let forward: PortForward = serde_json::from_str(
+            r#"{
+                "protocol": "Udp",
+                "host_address": "0.0.0.0",
+                "host_port": 5353,
+                "guest_port": 53
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(forward.protocol, ForwardProtocol::Udp);
+        assert_eq!(forward.host_address, std::net::Ipv4Addr::UNSPECIFIED);
+
+        assert!(serde_json::from_str::<PortForward>(
+            r#"{"host_port": 8080, "guest_port": 80, "bogus": 1}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_net_config_by_id() {
+        let mut net_builder = NetBuilder::new();
+        let net_if_cfg = create_netif("id", "configdev", "01:23:45:67:89:0c");
+        net_builder.build(net_if_cfg.clone()).unwrap();
+        assert_eq!(net_builder.config("id"), Some(net_if_cfg));
+        assert_eq!(net_builder.config("other"), None);
+
+        // The MAC set by the guest driver is reported.
+        let guest_mac = MacAddr::parse_str("02:00:00:00:00:0c").unwrap();
+        set_mac(
+            &mut net_builder.iter().next().unwrap().lock().unwrap(),
+            guest_mac,
+        );
+        assert_eq!(net_builder.config("id").unwrap().guest_mac, Some(guest_mac));
+    }
+
     #[test]
     fn test_add_device() {
         let mut net_builder = NetBuilder::new();
@@ -349,6 +1064,7 @@ mod tests {
             Some(MacAddr::parse_str(guest_mac).unwrap()),
             RateLimiter::default(),
             RateLimiter::default(),
+            1,
         )
         .unwrap();
 
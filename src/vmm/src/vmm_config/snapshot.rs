@@ -7,6 +7,8 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::vmm_config::drive::EncryptionConfig;
+
 /// The snapshot type options that are available when
 /// creating a new snapshot.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -66,6 +68,8 @@ pub struct LoadSnapshotParams {
     /// When set to true, the vm is also resumed if the snapshot load
     /// is successful.
     pub resume_vm: bool,
+    /// The encryption keys of the encrypted drives, which are not part of the snapshot.
+    pub drive_encryption: Vec<DriveEncryptionConfig>,
 }
 
 /// Stores the configuration for loading a snapshot that is provided by the user.
@@ -88,6 +92,19 @@ pub struct LoadSnapshotConfig {
     /// Whether or not to resume the vm post snapshot load.
     #[serde(default)]
     pub resume_vm: bool,
+    /// The encryption keys of the encrypted drives.
+    #[serde(default)]
+    pub drive_encryption: Vec<DriveEncryptionConfig>,
+}
+
+/// The encryption key of a drive restored from a snapshot.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveEncryptionConfig {
+    /// The identifier of the drive.
+    pub drive_id: String,
+    /// The encryption key of the drive.
+    pub encryption: EncryptionConfig,
 }
 
 /// Stores the configuration used for managing snapshot memory.
@@ -242,6 +242,7 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
         false,
         &empty_seccomp_filters,
         vm_resources,
+        &[],
     )
     .unwrap();
     // For now we're happy we got this far, we don't test what the guest is actually doing.
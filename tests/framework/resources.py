@@ -643,6 +643,7 @@ class Network:
         rx_rate_limiter=None,
         tx_rate_limiter=None,
         allow_mmds_requests=None,
+        user_nat=None,
     ):
         """Create the json for the net specific API request."""
         datax = {"iface_id": iface_id}
@@ -663,6 +664,9 @@ class Network:
         if allow_mmds_requests is not None:
             datax["allow_mmds_requests"] = allow_mmds_requests
 
+        if user_nat is not None:
+            datax["user_nat"] = user_nat
+
         return datax
 
 
@@ -1258,8 +1258,17 @@ def test_get_full_config_after_restoring_snapshot(bin_cloner_path):
             "guest_mac": net_tools.mac_from_ip(net_iface.guest_ip),
             "iface_id": net_iface.dev_name,
             "host_dev_name": net_iface.tap_name,
+            "host_socket_path": None,
             "rx_rate_limiter": None,
             "tx_rate_limiter": tx_rl,
+            "num_queue_pairs": None,
+            "mergeable_rx_buffers": False,
+            "ctrl_queue": False,
+            "vhost_net": False,
+            "tx_filter": None,
+            "link_up": None,
+            "dhcp": None,
+            "user_nat": None,
         }
     ]
     # Create a snapshot builder from a microvm.
@@ -1367,9 +1376,18 @@ def test_get_full_config(test_microvm_with_api):
         {
             "iface_id": iface_id,
             "host_dev_name": tap1.name,
+            "host_socket_path": None,
             "guest_mac": "06:00:00:00:00:01",
             "rx_rate_limiter": None,
             "tx_rate_limiter": tx_rl,
+            "num_queue_pairs": None,
+            "mergeable_rx_buffers": False,
+            "ctrl_queue": False,
+            "vhost_net": False,
+            "tx_filter": None,
+            "link_up": None,
+            "dhcp": None,
+            "user_nat": None,
         }
     ]
 
@@ -0,0 +1,100 @@
+# Copyright 2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0
+"""Tests for the network interfaces backed by the user-mode NAT."""
+import socket
+import threading
+
+from nsenter import Namespace
+
+from framework import utils
+import host_tools.network as net_tools
+
+# The gateway and guest addresses of the default NAT subnet.
+GATEWAY_IP = "10.0.2.2"
+GUEST_IP = "10.0.2.15"
+# The host port forwarded to the SSH server of the guest.
+HOST_PORT = 2222
+
+
+def test_user_nat_with_seccomp(test_microvm_with_api, network_config):
+    """
+    Exchange TCP and UDP traffic through a user NAT interface.
+
+    The NAT opens and uses host sockets on the VMM thread while the microVM
+    runs, so this checks that the default seccomp filter allows it.
+
+    @type: functional
+    """
+    test_microvm = test_microvm_with_api
+    test_microvm.spawn()
+    test_microvm.basic_config()
+
+    # The guest is reached through the tap interface, while its traffic to
+    # the gateway goes through the NAT.
+    _tap, _, _ = test_microvm.ssh_network_config(network_config, "1")
+    response = test_microvm.network.put(
+        iface_id="2",
+        guest_mac=net_tools.mac_from_ip(GUEST_IP),
+        user_nat={
+            "allow_host_loopback": True,
+            "port_forwards": [{"host_port": HOST_PORT, "guest_port": 22}],
+        },
+    )
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    test_microvm.start()
+    utils.assert_seccomp_level(test_microvm.jailer_clone_pid, "2")
+
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    # The guest assigns itself a /30 address, which doesn't cover the gateway.
+    iface = net_tools.get_guest_net_if_name(ssh_connection, GUEST_IP)
+    exit_code, _, _ = ssh_connection.execute_command(
+        f"ip addr flush dev {iface} && ip addr add {GUEST_IP}/24 dev {iface}"
+    )
+    assert exit_code == 0
+
+    # The host peers live in the network namespace of Firecracker, whose
+    # loopback interface the gateway address leads to.
+    with Namespace(test_microvm.jailer.netns_file_path(), "net"):
+        tcp_server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        tcp_server.bind(("127.0.0.1", 0))
+        tcp_server.listen()
+        udp_server = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        udp_server.bind(("127.0.0.1", 0))
+        forwarded = socket.create_connection(("127.0.0.1", HOST_PORT), timeout=10)
+    tcp_server.settimeout(10)
+    udp_server.settimeout(10)
+
+    # A TCP connection opened and then closed by the guest.
+    def echo():
+        conn, _ = tcp_server.accept()
+        with conn:
+            conn.sendall(conn.recv(64).upper())
+
+    echo_thread = threading.Thread(target=echo)
+    echo_thread.start()
+    tcp_port = tcp_server.getsockname()[1]
+    _, stdout, _ = ssh_connection.execute_command(
+        f"bash -c 'exec 3<>/dev/tcp/{GATEWAY_IP}/{tcp_port}; echo ping >&3; cat <&3'"
+    )
+    assert stdout.read() == "PING\n"
+    echo_thread.join()
+
+    # A UDP flow opened by the guest.
+    udp_port = udp_server.getsockname()[1]
+    exit_code, _, _ = ssh_connection.execute_command(
+        f"bash -c 'echo ping > /dev/udp/{GATEWAY_IP}/{udp_port}'"
+    )
+    assert exit_code == 0
+    data, _ = udp_server.recvfrom(64)
+    assert data == b"ping\n"
+
+    # A connection to the forwarded port, reaching the SSH server of the guest.
+    with forwarded:
+        assert forwarded.recv(64).startswith(b"SSH-2.0")
+
+    # Firecracker would have been killed by a denied system call.
+    exit_code, _, _ = ssh_connection.execute_command("true")
+    assert exit_code == 0
+    tcp_server.close()
+    udp_server.close()
@@ -87,6 +87,10 @@ fc-bindgen \
 info "BINDGEN virtio_net.h"
 fc-bindgen \
     --allowlist-var "VIRTIO_NET_F_.*" \
+    --allowlist-var "VIRTIO_NET_S_.*" \
+    --allowlist-var "VIRTIO_NET_CTRL_.*" \
+    --allowlist-var "VIRTIO_NET_OK" \
+    --allowlist-var "VIRTIO_NET_ERR" \
     --allowlist-var "VIRTIO_F_.*" \
     --allowlist-type "virtio_net_hdr_v1" \
     "$KERNEL_HEADERS_HOME/include/linux/virtio_net.h" >src/virtio_gen/src/virtio_net.rs